@@ -4,6 +4,7 @@ use std::process::Command;
 
 use crate::instruction::{InstructionKind, Keyword};
 use crate::log::*;
+use crate::prompt;
 use anyhow::{Context, Result};
 
 fn unquote(str: String) -> String {
@@ -18,23 +19,19 @@ fn unquote(str: String) -> String {
 }
 
 pub fn dump(program: &crate::instruction::Program, opt: crate::cli::CfgOptions) -> Result<()> {
-    let dot_path = opt.output.unwrap_or_else(|| {
+    let dot_path = opt.output.clone().unwrap_or_else(|| {
         let mut path = program.base_path.join(&program.name);
         path.set_extension("dot");
         path
     });
 
     if dot_path.exists() {
-        dialoguer::console::set_colors_enabled(true);
-        let overwrite = dialoguer::Select::new()
-            .item("Yes")
-            .item("No")
-            .default(1)
-            .with_prompt(format!("File {:?} already exists, overwrite?", dot_path))
-            .report(true)
-            .clear(true)
-            .interact()?;
-        if overwrite == 0 {
+        let overwrite = opt.force
+            || prompt::confirm(
+                &format!("File {:?} already exists, overwrite?", dot_path),
+                false,
+            )?;
+        if overwrite {
             if dot_path.is_file() {
                 std::fs::remove_file(&dot_path)
                     .context(format!("Failed to remove file {:?}", &dot_path))?;
@@ -44,7 +41,7 @@ pub fn dump(program: &crate::instruction::Program, opt: crate::cli::CfgOptions)
             }
         } else {
             return Err(anyhow::anyhow!(
-                "Aborted by user: file {:?} already exists.",
+                "Aborted: file {:?} already exists, --force writes over it.",
                 &dot_path
             ))
             .context(format!("Could not write graphviz."));
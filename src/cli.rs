@@ -1,12 +1,91 @@
-use std::{fmt::Display, path::PathBuf};
+use std::{fmt::Display, io::IsTerminal, path::PathBuf, time::Duration};
 
 use clap::{Parser, ValueEnum};
 
+use crate::codegen::BSS_CAPACITY;
+use crate::lint::Lint;
+use crate::log::{self, LogFormat, LogLevel};
+
 #[derive(Debug, Parser)]
+#[clap(version)]
 pub struct Cli {
-    pub file: PathBuf,
+    /// The program, which can also go after the command. Every command but dap,
+    /// lsp and repl needs one. Build, run, simulate and cfg read it from stdin
+    /// when it's -.
+    pub file: Option<PathBuf>,
+    #[clap(
+        long,
+        global = true,
+        value_name = "NAME",
+        help = "What to call a program read from stdin, in messages and what it builds [default: stdin]"
+    )]
+    pub name: Option<String>,
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        value_name = "LEVEL",
+        help = "Only log messages at LEVEL or after it in debug, cmd, info, warn, and WORTHC_LOG can set it for each phase [default: cmd]"
+    )]
+    pub log_level: Option<LogLevel>,
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        default_value = "human",
+        help = "What to log messages as, json for a line of JSON each"
+    )]
+    pub log_format: LogFormat,
+    #[clap(
+        short,
+        long,
+        global = true,
+        conflicts_with = "log_level",
+        help = "Only log warnings, like --log-level warn"
+    )]
+    pub quiet: bool,
+    #[clap(
+        short,
+        long,
+        global = true,
+        conflicts_with_all = ["log_level", "quiet"],
+        help = "Log everything, like --log-level debug, and report code the optimizer removed as unreachable"
+    )]
+    pub verbose: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "Report how long parsing, preprocessing each include, typechecking, codegen, assembling and linking took"
+    )]
+    pub timings: bool,
+    #[clap(
+        short,
+        long,
+        global = true,
+        alias = "non-interactive",
+        help = "Never ask before doing anything, as if yes was the answer to every question"
+    )]
+    pub yes: bool,
     #[clap(short, long = "unsafe", help = "Disables typechecking")]
     pub unsafe_: bool,
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        default_value = "human",
+        help = "Format for error diagnostics. sarif prints a SARIF log to stdout, of lint's warnings or the error worthc failed with"
+    )]
+    pub message_format: MessageFormat,
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        default_value = "auto",
+        help = "Whether to color diagnostics, auto if stderr is a terminal and NO_COLOR isn't set"
+    )]
+    pub color: ColorChoice,
+    #[clap(flatten)]
+    pub typecheck: TypecheckOptions,
     #[clap(subcommand)]
     pub command: Option<Command>,
 }
@@ -21,39 +100,557 @@ pub enum Command {
     Simulate(SimulatorOptions),
     #[clap(alias = "G", alias = "g")]
     Cfg(CfgOptions),
+    /// Serve the Debug Adapter Protocol over stdio, simulating the launched program
+    Dap,
+    /// Serve the Language Server Protocol over stdio, checking programs as they're edited
+    Lsp,
+    /// Simulate a line at a time, keeping the stack, memory and what's declared between lines
+    Repl(ReplOptions),
+    /// Format programs in place
+    Fmt(FmtOptions),
+    /// Run the programs in a directory that have a .txt next to them, simulated and built
+    Test(TestOptions),
+    /// Simulate and build a program, and say where what they do first differs
+    Difftest(DifftestOptions),
+    /// Make a new project, with a worth.toml, a hello world and a test for it
+    Init(InitOptions),
+    /// Document the macros a program defines, from the comments before them
+    Doc(DocOptions),
+    /// Warn about code that's valid but probably not what was meant
+    Lint(LintOptions),
+    /// Count what a program is made of: its instructions, macros, strings and executable's size
+    Stats(StatsOptions),
+    /// Say more about the error with a code, with an example and how to fix it
+    Explain(ExplainOptions),
+    /// Print the tokens a program is lexed into, with where each one is
+    DumpTokens(DumpOptions),
+    /// Print the instructions a program is preprocessed into, as the typechecker and codegen see them
+    DumpIr(DumpOptions),
+}
+
+impl Cli {
+    /// The least a message needs to be logged.
+    pub fn log_level(&self) -> LogLevel {
+        match self.log_level {
+            Some(level) => level,
+            None if self.quiet => LogLevel::Warn,
+            None if self.verbose => LogLevel::Debug,
+            None => log::default_level(),
+        }
+    }
+
+    /// The program to load, whether it came before the command or after it.
+    /// Given before, what the command took as its file was really the first
+    /// of the program's arguments, as it used to be.
+    pub fn take_file(&mut self) -> Option<PathBuf> {
+        let (file, args) = match &mut self.command {
+            Some(Command::Build(opt)) => (opt.file.take(), None),
+            Some(Command::Run(opt)) => (opt.file.take(), Some(&mut opt.run_args)),
+            Some(Command::Simulate(opt)) => (opt.file.take(), Some(&mut opt.sim_args)),
+            Some(Command::Cfg(opt)) => (opt.file.take(), None),
+            Some(Command::Doc(opt)) => (opt.file.take(), None),
+            Some(Command::Lint(opt)) => (opt.file.take(), None),
+            Some(Command::Stats(opt)) => (opt.file.take(), None),
+            Some(Command::Difftest(opt)) => (opt.file.take(), Some(&mut opt.args)),
+            Some(Command::DumpTokens(opt) | Command::DumpIr(opt)) => (opt.file.take(), None),
+            Some(Command::Repl(opt)) => (opt.file.take(), None),
+            Some(Command::Fmt(opt)) => {
+                // Every file is formatted, so it's one more
+                if let Some(file) = self.file.take() {
+                    opt.files.insert(0, file);
+                }
+                (None, None)
+            }
+            Some(
+                Command::Dap
+                | Command::Lsp
+                | Command::Test(_)
+                | Command::Init(_)
+                | Command::Explain(_),
+            )
+            | None => (None, None),
+        };
+        match (self.file.clone(), file, args) {
+            (Some(program), Some(arg), Some(args)) => {
+                args.insert(0, arg.to_string_lossy().to_string());
+                Some(program)
+            }
+            (Some(program), _, _) => Some(program),
+            (None, file, _) => file,
+        }
+    }
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct TypecheckOptions {
+    #[clap(
+        long = "tc-trace",
+        help = "Write every typechecker step (ip, instruction, location, stack) to a file"
+    )]
+    pub trace: Option<PathBuf>,
+    #[clap(long, help = "Report the worst-case stack depth of the program")]
+    pub stack_depth: bool,
+    #[clap(
+        long,
+        value_name = "DEPTH",
+        help = "Fail typechecking if the stack can grow deeper than DEPTH"
+    )]
+    pub max_stack_depth: Option<usize>,
+    #[clap(skip)]
+    pub debugger: bool,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct FmtOptions {
+    #[clap(value_name = "FILE")]
+    pub files: Vec<PathBuf>,
+    #[clap(
+        long,
+        help = "Change nothing, and exit with 1 if any file isn't formatted already"
+    )]
+    pub check: bool,
+    #[clap(flatten)]
+    pub config: FmtConfig,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct TestOptions {
+    /// Searched for programs, along with the directories in it
+    #[clap(value_name = "DIR")]
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct DocOptions {
+    /// The program, unless it's given before the command
+    #[clap(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+    #[clap(
+        short,
+        long,
+        help = "Write the documentation here instead of to stdout"
+    )]
+    pub output: Option<PathBuf>,
+    #[clap(
+        long,
+        value_enum,
+        help = "What to write it as [default: html if -o ends in .html, markdown otherwise]"
+    )]
+    pub format: Option<DocFormat>,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct LintOptions {
+    /// The program, unless it's given before the command
+    #[clap(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+    #[clap(
+        short = 'A',
+        long,
+        value_enum,
+        value_name = "LINT",
+        help = "Don't warn about this lint anywhere"
+    )]
+    pub allow: Vec<Lint>,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct DifftestOptions {
+    /// The program, unless it's given before the command
+    #[clap(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+    #[clap(long, value_name = "FILE", help = "Give the program FILE as its stdin")]
+    pub stdin: Option<PathBuf>,
+    #[clap(
+        long_help = "Arguments to pass to the program, use -- to separate them from the compiler arguments.\nExample: ./worthc test.porth difftest -- arg1 arg2."
+    )]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct InitOptions {
+    /// The directory to make it in, which it's named after
+    #[clap(value_name = "NAME")]
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct ExplainOptions {
+    /// The code an error was given, like W0015
+    #[clap(value_name = "CODE")]
+    pub code: String,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct StatsOptions {
+    /// The program, unless it's given before the command
+    #[clap(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+    #[clap(
+        short = 'O',
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(0..=1),
+        help = "Optimization level the instructions and executable are counted at"
+    )]
+    pub opt_level: u8,
+    #[clap(long, help = "Print a JSON object instead, for tools")]
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DocFormat {
+    Markdown,
+    Html,
+}
+
+/// How the formatter lays programs out.
+#[derive(Debug, Parser, Clone)]
+pub struct FmtConfig {
+    #[clap(
+        long,
+        value_name = "COLUMNS",
+        default_value_t = 4,
+        help = "Columns each level of blocks is indented by"
+    )]
+    pub indent: usize,
+    #[clap(
+        long,
+        value_name = "COLUMNS",
+        default_value_t = 100,
+        help = "Wrap runs of pushed values onto new lines past this width, 0 to never wrap"
+    )]
+    pub max_width: usize,
+    #[clap(long, value_enum, default_value_t = Newline::Lf, help = "Line endings to write")]
+    pub newline: Newline,
+}
+
+impl Default for FmtConfig {
+    fn default() -> Self {
+        Self {
+            indent: 4,
+            max_width: 100,
+            newline: Newline::Lf,
+        }
+    }
 }
 
 #[derive(Debug, Parser, Clone)]
 pub struct CfgOptions {
+    /// The program, unless it's given before the command
+    #[clap(value_name = "FILE")]
+    pub file: Option<PathBuf>,
     #[clap(short, long)]
     pub output: Option<PathBuf>,
+    #[clap(
+        short,
+        long,
+        help = "Write over the output if it's there, without asking"
+    )]
+    pub force: bool,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct ReplOptions {
+    /// A program to run before the first line, unless it's given before the command
+    #[clap(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct DumpOptions {
+    /// The program, unless it's given before the command
+    #[clap(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+    #[clap(long, help = "Print a JSON object a line instead, for tools")]
+    pub json: bool,
 }
 
 #[derive(Debug, Parser, Clone)]
 pub struct CompilerOptions {
+    /// The program, unless it's given before the command or it's the entry of
+    /// the worth.toml here or in a directory above
+    #[clap(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Another program to load on its own and link in after this one, which can be given more than once"
+    )]
+    pub link: Vec<PathBuf>,
     #[clap(short, long)]
     pub output: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "DIR",
+        help = "Directory to build everything in, created if it's missing, with -o relative to it"
+    )]
+    pub out_dir: Option<PathBuf>,
     #[clap(short = 'k', long)]
     pub keep_asm: bool,
     #[clap(short = 'K', long)]
     pub keep_obj: bool,
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "TYPE",
+        help = "What to build, several separated by commas to keep each of them. Just one is written to -o as it's named [default: from the extension of -o]"
+    )]
+    pub emit: Vec<OutputType>,
     #[clap(short = 'd', long)]
     pub debug: bool,
+    #[clap(
+        short = 'g',
+        long,
+        help = "Write DWARF line info, so debuggers can step through the .porth source (x86_64 only)"
+    )]
+    pub debug_info: bool,
+    #[clap(
+        short = 'O',
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(0..=1),
+        help = "Optimization level, 0 to compile the program exactly as written"
+    )]
+    pub opt_level: u8,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "x86_64-linux",
+        help = "Platform to build the program for"
+    )]
+    pub target: Target,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "native",
+        help = "Code generator to build the program with"
+    )]
+    pub backend: Backend,
+    #[clap(
+        long,
+        value_enum,
+        help = "Assembler the x86 targets are built with [default: builtin for x86_64-linux, nasm otherwise]"
+    )]
+    pub assembler: Option<Assembler>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Linker the executable is made with [default: builtin with the built-in assembler and no --link-arg, ld otherwise]"
+    )]
+    pub linker: Option<Linker>,
+    #[clap(
+        long = "link-arg",
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        help = "Pass an argument to the linker, or to cc when it links, can be repeated"
+    )]
+    pub link_args: Vec<String>,
+    #[clap(
+        long,
+        help = "Build a position independent executable, for systems that require PIE (Linux targets)"
+    )]
+    pub pie: bool,
+    #[clap(
+        long,
+        help = "Link with the C library through cc, so extern functions can be called (x86_64-linux)"
+    )]
+    pub link_libc: bool,
+    #[clap(
+        long,
+        help = "Check the stack, the bounds of mem and divisors while running, and abort with where they broke (x86_64 only)"
+    )]
+    pub checked: bool,
+    #[clap(
+        long,
+        help = "Check every load and store is in mem or clear of it, and abort with the address and where it was (x86_64 only)"
+    )]
+    pub check_bounds: bool,
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value_t = BSS_CAPACITY,
+        help = "Size of the memory mem points to"
+    )]
+    pub mem_capacity: usize,
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Write a JSON map of the executable's labels, their address ranges and the source and macro each came from (native Linux targets)"
+    )]
+    pub emit_map: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Count how often each basic block runs, and write the counts with where each block is to <output>.profile on exit (x86_64-linux)"
+    )]
+    pub instrument: bool,
+    #[clap(
+        long,
+        help = "Build again whenever the program or a file it includes changes"
+    )]
+    pub watch: bool,
+    #[clap(
+        long,
+        help = "Don't build the program if worthc lint warns about it, unless it's read from stdin"
+    )]
+    pub strict: bool,
 }
 
 #[derive(Debug, Parser, Clone)]
 pub struct RunOptions {
+    /// The program, unless it's given before the command
+    #[clap(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Another program to load on its own and link in after this one, which can be given more than once"
+    )]
+    pub link: Vec<PathBuf>,
     #[clap(
         short,
-        help = "Output file name / type [ types: .asm, .o, .exe ]\nIf file extension is not specified, .exe is assumed."
+        help = "Output file name / type [ types: .asm, .ll, .c, .o, .exe ]\nIf file extension is not specified, .exe is assumed."
     )]
     pub output: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "DIR",
+        help = "Directory to build everything in, created if it's missing, with -o relative to it"
+    )]
+    pub out_dir: Option<PathBuf>,
     #[clap(short = 'k', help = "Keep the assembly file after compilation.")]
     pub keep_asm: bool,
     #[clap(short = 'K', help = "Keep the object file after compilation.")]
     pub keep_obj: bool,
+    #[clap(
+        long,
+        help = "Keep the executable after it's run, which it is anyway when -o says where"
+    )]
+    pub keep_exe: bool,
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "TYPE",
+        help = "What to keep besides the executable that's run, separated by commas"
+    )]
+    pub emit: Vec<OutputType>,
     #[clap(short = 'd', help = "Enable debug mode.")]
     pub debug: bool,
+    #[clap(
+        short = 'g',
+        long,
+        help = "Write DWARF line info, so debuggers can step through the .porth source (x86_64 only)"
+    )]
+    pub debug_info: bool,
+    #[clap(
+        short = 'O',
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(0..=1),
+        help = "Optimization level, 0 to compile the program exactly as written"
+    )]
+    pub opt_level: u8,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "x86_64-linux",
+        help = "Platform to build the program for"
+    )]
+    pub target: Target,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "native",
+        help = "Code generator to build the program with"
+    )]
+    pub backend: Backend,
+    #[clap(
+        long,
+        value_enum,
+        help = "Assembler the x86 targets are built with [default: builtin for x86_64-linux, nasm otherwise]"
+    )]
+    pub assembler: Option<Assembler>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Linker the executable is made with [default: builtin with the built-in assembler and no --link-arg, ld otherwise]"
+    )]
+    pub linker: Option<Linker>,
+    #[clap(
+        long = "link-arg",
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        help = "Pass an argument to the linker, or to cc when it links, can be repeated"
+    )]
+    pub link_args: Vec<String>,
+    #[clap(
+        long,
+        help = "Build a position independent executable, for systems that require PIE (Linux targets)"
+    )]
+    pub pie: bool,
+    #[clap(
+        long,
+        help = "Link with the C library through cc, so extern functions can be called (x86_64-linux)"
+    )]
+    pub link_libc: bool,
+    #[clap(
+        long,
+        help = "Check the stack, the bounds of mem and divisors while running, and abort with where they broke (x86_64 only)"
+    )]
+    pub checked: bool,
+    #[clap(
+        long,
+        help = "Check every load and store is in mem or clear of it, and abort with the address and where it was (x86_64 only)"
+    )]
+    pub check_bounds: bool,
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value_t = BSS_CAPACITY,
+        help = "Size of the memory mem points to"
+    )]
+    pub mem_capacity: usize,
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Write a JSON map of the executable's labels, their address ranges and the source and macro each came from (native Linux targets)"
+    )]
+    pub emit_map: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Count how often each basic block runs, and write the counts with where each block is to <output>.profile on exit (x86_64-linux)"
+    )]
+    pub instrument: bool,
+    #[clap(
+        long,
+        help = "Build and run again whenever the program or a file it includes changes"
+    )]
+    pub watch: bool,
+    #[clap(
+        long,
+        help = "Don't build the program if worthc lint warns about it, unless it's read from stdin"
+    )]
+    pub strict: bool,
+    #[clap(
+        long,
+        value_name = "SECS",
+        value_parser = parse_timeout,
+        help = "Kill the program if it runs longer than SECS seconds"
+    )]
+    pub timeout: Option<Duration>,
+    #[clap(
+        long,
+        value_name = "KEY=VAL",
+        value_parser = parse_env,
+        help = "Set an environment variable for the program, can be given more than once"
+    )]
+    pub env: Vec<(String, String)>,
+    #[clap(long, value_name = "DIR", help = "Run the program in DIR")]
+    pub cwd: Option<PathBuf>,
+    #[clap(long, value_name = "FILE", help = "Give the program FILE as its stdin")]
+    pub stdin: Option<PathBuf>,
     #[clap(
         long_help = "Arguments to pass to the program, use -- to separate them from the compiler arguments.\nExample: ./worthc test.porth run -d -- arg1 arg2."
     )]
@@ -62,33 +659,280 @@ pub struct RunOptions {
 
 impl From<RunOptions> for CompilerOptions {
     fn from(opt: RunOptions) -> Self {
+        let mut emit = opt.emit;
+        // There's nothing to run otherwise
+        if !emit.is_empty() && !emit.contains(&OutputType::Exe) {
+            emit.push(OutputType::Exe);
+        }
         Self {
+            file: opt.file,
+            link: opt.link,
             output: opt.output,
+            out_dir: opt.out_dir,
             keep_asm: opt.keep_asm,
             keep_obj: opt.keep_obj,
+            emit,
             debug: opt.debug,
+            debug_info: opt.debug_info,
+            opt_level: opt.opt_level,
+            target: opt.target,
+            backend: opt.backend,
+            assembler: opt.assembler,
+            linker: opt.linker,
+            link_args: opt.link_args,
+            pie: opt.pie,
+            link_libc: opt.link_libc,
+            checked: opt.checked,
+            check_bounds: opt.check_bounds,
+            mem_capacity: opt.mem_capacity,
+            emit_map: opt.emit_map,
+            instrument: opt.instrument,
+            watch: opt.watch,
+            strict: opt.strict,
         }
     }
 }
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Parser, Clone)]
 pub struct SimulatorOptions {
+    /// The program, unless it's given before the command
+    #[clap(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Another program to load on its own and link in after this one, which can be given more than once"
+    )]
+    pub link: Vec<PathBuf>,
     #[clap(short = 'd', long)]
     pub debug: bool,
     #[clap(long = "tc-debugger")]
     pub tc_debug: bool,
     #[clap(short = 's', long)]
     pub step: bool,
-    #[clap(short = 'b', long)]
-    pub breakpoint: Option<usize>,
+    #[clap(
+        short = 'b',
+        long = "break",
+        value_name = "IP|FILE:LINE",
+        help = "Stop at an instruction or the start of a line, can be given more than once"
+    )]
+    pub breakpoints: Vec<String>,
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 1000,
+        help = "While debugging, snapshot the state every N instructions so the debugger can step back, 0 to disable"
+    )]
+    pub snapshot_every: u64,
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Write every executed instruction (ip, instruction, location, stack depth) to a file, gzipped if it ends in .gz"
+    )]
+    pub trace: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        help = "Print the N (default 10) source lines that ran the most instructions"
+    )]
+    pub profile: Option<usize>,
+    #[clap(
+        long,
+        value_name = "SEED",
+        help = "Make getrandom return values from SEED and the clocks start at a fixed time, advancing 1ms per read"
+    )]
+    pub deterministic: Option<u64>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Fail if the program runs more than N instructions"
+    )]
+    pub max_steps: Option<u64>,
+    #[clap(
+        long,
+        value_name = "SECS",
+        value_parser = parse_timeout,
+        help = "Fail if the simulation takes longer than SECS seconds"
+    )]
+    pub timeout: Option<Duration>,
+    #[clap(long, help = "Warn when a load reads memory that was never written")]
+    pub uninit: bool,
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value_t = BSS_CAPACITY,
+        help = "Size of the memory mem points to, like when building"
+    )]
+    pub mem_capacity: usize,
+    #[clap(
+        long,
+        help = "Make syscalls that write files, use the network or start processes fail with EPERM"
+    )]
+    pub sandbox: bool,
+    #[clap(
+        long,
+        value_enum,
+        value_name = "PERMISSION",
+        requires = "sandbox",
+        help = "Let a sandboxed program do this anyway, can be repeated"
+    )]
+    pub allow: Vec<Permission>,
+    #[clap(
+        long,
+        value_name = "KEY=VAL",
+        value_parser = parse_env,
+        help = "Set an environment variable for the program, can be given more than once"
+    )]
+    pub env: Vec<(String, String)>,
+    #[clap(long, value_name = "DIR", help = "Run the program in DIR")]
+    pub cwd: Option<PathBuf>,
+    #[clap(long, value_name = "FILE", help = "Give the program FILE as its stdin")]
+    pub stdin: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Simulate again whenever the program or a file it includes changes"
+    )]
+    pub watch: bool,
     #[clap(
         long_help = "Arguments to pass to the program, use -- to separate them from the compiler arguments.\nExample: ./worthc test.porth run -d -- arg1 arg2."
     )]
     pub sim_args: Vec<String>,
 }
 
-#[derive(Debug, Parser, Clone, ValueEnum)]
+/// A `KEY=VAL` given to `--env`.
+fn parse_env(var: &str) -> Result<(String, String), String> {
+    var.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("{} isn't KEY=VAL", var))
+}
+
+/// A `SECS` given to `--timeout`, which can be fractional.
+fn parse_timeout(secs: &str) -> Result<Duration, String> {
+    let parsed = secs
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+    Duration::try_from_secs_f64(parsed).map_err(|_| format!("{} isn't a number of seconds", secs))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Newline {
+    Lf,
+    Crlf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to color what's written to stderr.
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+                !no_color && std::io::stderr().is_terminal()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MessageFormat {
+    Human,
+    Json,
+    Sarif,
+}
+
+/// What a sandboxed program can be allowed to do with `--allow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Permission {
+    /// Open files for writing, create them and unlink them
+    Write,
+    /// Use sockets
+    Net,
+    /// Fork, exec and wait for child processes
+    Process,
+}
+
+/// What `--target` can build for, by name or triple. What each one is and
+/// how it's built is in `codegen::target`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Target {
+    #[value(name = "x86_64-linux", alias = "x86_64-unknown-linux-gnu")]
+    X86_64Linux,
+    #[value(name = "aarch64-linux", alias = "aarch64-unknown-linux-gnu")]
+    Aarch64Linux,
+    #[value(name = "riscv64-linux", alias = "riscv64-unknown-linux-gnu")]
+    Riscv64Linux,
+    #[value(name = "x86_64-macos", alias = "x86_64-apple-darwin")]
+    X86_64Macos,
+    #[value(name = "x86_64-windows", alias = "x86_64-pc-windows-gnu")]
+    X86_64Windows,
+    #[value(alias = "wasm32-wasi")]
+    Wasm32,
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("no target is skipped");
+        write!(f, "{}", value.get_name())
+    }
+}
+
+/// What `--backend` writes the program as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Assembly for the target, built with its assembler
+    Native,
+    /// LLVM IR, optimized and built with clang
+    Llvm,
+    /// C, built with the C compiler for the target
+    C,
+}
+
+/// What `--assembler` builds x86 assembly with. The other targets are always
+/// built with GNU as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Assembler {
+    /// Encodes the instructions itself, and only writes ELF objects
+    Builtin,
+    Nasm,
+    /// GNU as, or anything else that reads AT&T syntax, for systems without nasm
+    Gas,
+}
+
+/// What `--linker` makes the executable with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Linker {
+    /// Writes a static ELF executable from the built-in assembler's object
+    Builtin,
+    /// The linker of the target, usually ld
+    Ld,
+    /// LLVM's linker, which links for every target without cross tools
+    Lld,
+    /// Only links ELF, so only for the Linux targets
+    Mold,
+}
+
+impl Display for Linker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("no linker is skipped");
+        write!(f, "{}", value.get_name())
+    }
+}
+
+/// In the order they're built in
+#[derive(Debug, Parser, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum OutputType {
+    /// The preprocessed program, which can be built from again, see `ir`
+    Ir,
     Asm,
     Obj,
     Exe,
@@ -97,6 +941,7 @@ pub enum OutputType {
 impl Display for OutputType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            OutputType::Ir => write!(f, "ir"),
             OutputType::Asm => write!(f, "asm"),
             OutputType::Obj => write!(f, "obj"),
             OutputType::Exe => write!(f, "exe"),
@@ -0,0 +1,107 @@
+use crate::codegen::intrinsics::Intrinsic;
+use crate::{asm, asm_line, comment, label};
+
+use super::{address, exit, pop, push, Builder};
+
+pub fn compile(asm: &mut Builder, intrinsic: &Intrinsic) {
+    match intrinsic {
+        Intrinsic::Print => {
+            pop(asm, "x0");
+            asm!(asm, ("bl", "intrinsic_print"));
+        }
+        Intrinsic::Panic => exit(asm, 1),
+        Intrinsic::Dup => {
+            pop(asm, "x0");
+            push(asm, "x0");
+            push(asm, "x0");
+        }
+        Intrinsic::Dup2 => {
+            pop(asm, "x1");
+            pop(asm, "x0");
+            for reg in ["x0", "x1", "x0", "x1"] {
+                push(asm, reg);
+            }
+        }
+        Intrinsic::Swap => {
+            pop(asm, "x1");
+            pop(asm, "x0");
+            push(asm, "x1");
+            push(asm, "x0");
+        }
+        Intrinsic::Over => {
+            pop(asm, "x1");
+            pop(asm, "x0");
+            for reg in ["x0", "x1", "x0"] {
+                push(asm, reg);
+            }
+        }
+        Intrinsic::Drop => asm!(asm, ("add", "x28, x28, #8")),
+        Intrinsic::Drop2 => asm!(asm, ("add", "x28, x28, #16")),
+        Intrinsic::Mem => {
+            address(asm, "x0", "mem");
+            push(asm, "x0");
+        }
+        Intrinsic::Argc => {
+            address(asm, "x0", "args_ptr");
+            asm!(asm, ("ldr", "x0, [x0]"), ("ldr", "x0, [x0]"));
+            push(asm, "x0");
+        }
+        Intrinsic::Argv => {
+            address(asm, "x0", "args_ptr");
+            asm!(asm, ("ldr", "x0, [x0]"), ("add", "x0, x0, #8"));
+            push(asm, "x0");
+        }
+        Intrinsic::CastPtr => {
+            comment!(asm, "-- Cast to Pointer --");
+        }
+        Intrinsic::CastInt => {
+            comment!(asm, "-- Cast to Int --");
+        }
+        Intrinsic::Align => {
+            pop(asm, "x1");
+            pop(asm, "x0");
+            asm!(
+                asm,
+                ("add", "x0, x0, x1"),
+                ("sub", "x0, x0, #1"),
+                ("neg", "x1, x1"),
+                ("and", "x0, x0, x1")
+            );
+            push(asm, "x0");
+        }
+        Intrinsic::Here => {
+            comment!(asm, "-- {} --", asm.tmp_here);
+        }
+    }
+}
+
+/// `intrinsic_print`, which prints `x0` as an unsigned number and a newline.
+pub fn gen_print(asm: &mut Builder) {
+    label!(asm, "intrinsic_print");
+    address(asm, "x9", "print_buf");
+    asm!(
+        asm,
+        /// Digits are written backwards from the end of the buffer
+        ("add", "x9, x9, #32"),
+        ("mov", "x10, #10"),
+        ("strb", "w10, [x9, #-1]!"),
+        ("mov", "x2, #1")
+    );
+    label!(asm, ".Lintrinsic_print_body");
+    asm!(
+        asm,
+        ("udiv", "x11, x0, x10"),
+        ("msub", "x12, x11, x10, x0"),
+        ("add", "x12, x12, #48"),
+        ("strb", "w12, [x9, #-1]!"),
+        ("add", "x2, x2, #1"),
+        ("mov", "x0, x11"),
+        ("cbnz", "x0, .Lintrinsic_print_body"),
+        ("mov", "x1, x9"),
+        ("mov", "x0, #1"),
+        /// write
+        ("mov", "x8, #64"),
+        ("svc", "#0"),
+        ("ret")
+    );
+}
@@ -0,0 +1,371 @@
+//! Code generation for 64 bit ARM Linux, written for GNU as.
+//!
+//! The porth stack lives in its own region of the bss, with `x28` pointing at
+//! the top value, since `sp` has to stay 16 byte aligned. Programs use x86-64
+//! syscall numbers like the ones in std.porth, so every syscall goes through
+//! `do_syscall`, which looks the number up in a table. Calls that only exist as
+//! their `*at` version here, like `open`, get `AT_FDCWD` as the first argument,
+//! and `fork` becomes `clone`. Anything else without an equivalent returns
+//! `-ENOSYS`. Structs are passed through as they are, and some of them, like
+//! `struct stat`, are laid out differently.
+
+mod intrinsics;
+mod ops;
+
+use anyhow::{Context, Result};
+
+use super::builder::Builder;
+use super::labels::Labels;
+use super::source::Source;
+use crate::{
+    asm, asm_line, comment, err,
+    error::{CompileError::*, Error::CompileError},
+    instruction::*,
+    label, segment,
+};
+
+/// Bytes set aside for the porth stack.
+pub const STACK_CAPACITY: usize = 8 * 1024 * 1024;
+
+pub(super) const AT_FDCWD: u16 = 0x4000;
+pub(super) const FORK: u16 = 0x2000;
+pub(super) const UNSUPPORTED: u16 = 0xFFFF;
+
+/// x86-64 syscall numbers and what they become, with `AT_FDCWD` or `FORK` set
+/// for the calls that need their arguments changed. These are the generic
+/// numbers every newer Linux port uses, not only arm.
+#[rustfmt::skip]
+const SYSCALLS: &[(usize, u16)] = &[
+    (0, 63), (1, 64), (2, 56 | AT_FDCWD), (3, 57), (4, 79 | AT_FDCWD), (5, 80), (8, 62),
+    (9, 222), (10, 226), (11, 215), (12, 214), (13, 134), (14, 135), (16, 29), (17, 67),
+    (18, 68), (19, 65), (20, 66), (21, 48 | AT_FDCWD), (22, 59), (24, 124), (28, 233),
+    (32, 23), (33, 24), (35, 101), (39, 172), (41, 198), (42, 203), (43, 202), (44, 206),
+    (45, 207), (46, 211), (47, 212), (48, 210), (49, 200), (50, 201), (51, 204), (52, 205),
+    (53, 199), (54, 208), (55, 209), (56, 220), (57, 220 | FORK), (58, 220 | FORK), (59, 221),
+    (60, 93), (61, 260), (62, 129), (63, 160), (72, 25), (74, 82), (75, 83), (76, 45),
+    (77, 46), (79, 17), (80, 49), (81, 50), (83, 34 | AT_FDCWD), (87, 35 | AT_FDCWD),
+    (89, 78 | AT_FDCWD), (90, 53 | AT_FDCWD), (91, 52), (95, 166), (96, 169), (97, 163),
+    (98, 165), (99, 179), (102, 174), (104, 176), (107, 175), (108, 177), (110, 173),
+    (186, 178), (202, 98), (217, 61), (228, 113), (229, 114), (230, 115), (231, 94),
+    (257, 56), (258, 34), (262, 79), (263, 35), (292, 24), (293, 59), (302, 261), (318, 278),
+];
+
+pub fn push(asm: &mut Builder, reg: &str) {
+    asm!(asm, ("str", "{}, [x28, #-8]!", reg));
+}
+
+pub fn pop(asm: &mut Builder, reg: &str) {
+    asm!(asm, ("ldr", "{}, [x28], #8", reg));
+}
+
+/// Load the address of `label` into `reg`.
+pub fn address(asm: &mut Builder, reg: &str, label: &str) {
+    asm!(
+        asm,
+        ("adrp", "{}", format!("{}, {}", reg, label)),
+        ("add", "{}", format!("{0}, {0}, :lo12:{1}", reg, label))
+    );
+}
+
+/// Load any 64 bit value into `reg`, 16 bits at a time.
+pub fn immediate(asm: &mut Builder, reg: &str, value: i64) {
+    let value = value as u64;
+    asm!(asm, ("movz", "{}", format!("{}, #{}", reg, value & 0xFFFF)));
+    for shift in [16, 32, 48] {
+        let part = (value >> shift) & 0xFFFF;
+        if part != 0 {
+            asm!(
+                asm,
+                ("movk", "{}", format!("{}, #{}, lsl #{}", reg, part, shift))
+            );
+        }
+    }
+}
+
+pub fn exit(asm: &mut Builder, code: i64) {
+    comment!(asm, "-- exit {} --", code);
+    asm!(
+        asm,
+        ("mov", "x0, #{}", code),
+        ("mov", "x8, #93"),
+        ("svc", "#0")
+    );
+}
+
+pub fn generate(
+    program: &Program,
+    mem_capacity: usize,
+    mut source: Option<Source>,
+) -> Result<Builder> {
+    let mut asm = Builder::gas("//");
+    comment!(asm, "-- generated by the worth compiler --");
+
+    segment!(asm, "bss");
+    asm!(asm, (".balign", "{}", MEM_ALIGN));
+    label!(asm, "mem");
+    asm!(asm, (".skip", "{}", mem_capacity));
+    asm!(asm, (".balign", "{}", MEM_ALIGN));
+    for memory in &program.memories {
+        label!(asm, "{}", memory.label());
+        asm!(asm, (".skip", "{}", memory.reserved()));
+    }
+    label!(asm, "args_ptr");
+    asm!(asm, (".skip", "8"));
+    label!(asm, "print_buf");
+    asm!(asm, (".skip", "32"));
+    label!(asm, "stack");
+    asm!(asm, (".skip", "{}", STACK_CAPACITY));
+    label!(asm, "stack_end");
+
+    segment!(asm, "text");
+    asm!(asm, (".globl", "_start"));
+    label!(asm, "_start");
+    asm!(
+        asm,
+        /// Save the stack pointer for argc and argv intrinsics
+        ("mov", "x9, sp")
+    );
+    address(&mut asm, "x10", "args_ptr");
+    asm!(asm, ("str", "x9, [x10]"));
+    address(&mut asm, "x28", "stack_end");
+
+    let labels = Labels::new(program);
+    for (ip, inst) in program.instructions.iter().enumerate() {
+        asm.tmp_here =
+            inst.loc.0.clone() + ":" + &inst.loc.1.to_string() + ":" + &inst.loc.2.to_string();
+        if let Some(line) = source.as_mut().and_then(|source| source.line(&inst.loc)) {
+            comment!(asm, line);
+        }
+        match &inst.kind {
+            InstructionKind::Push(val) => {
+                let value = match val {
+                    Value::Int(i) => *i,
+                    Value::Bool(b) => *b as i64,
+                    Value::Char(c) => *c as i64,
+                    Value::Ptr(name) => err!(
+                        program,
+                        CompileError(UnexpectedToken(name.clone())),
+                        format!("Pointer to {} should be resolved before codegen", name),
+                        ip
+                    ),
+                    Value::Str(s) => {
+                        let s_id = asm.new_const_str(s);
+                        immediate(&mut asm, "x0", s.len() as i64);
+                        push(&mut asm, "x0");
+                        address(&mut asm, "x0", &format!("const_str_{}", s_id));
+                        push(&mut asm, "x0");
+                        continue;
+                    }
+                };
+                immediate(&mut asm, "x0", value);
+                push(&mut asm, "x0");
+            }
+            InstructionKind::Intrinsic(intrinsic) => {
+                comment!(
+                    asm,
+                    &format!("-- intrinsic: {} --", intrinsic.to_string().to_lowercase())
+                );
+                intrinsics::compile(&mut asm, intrinsic);
+                comment!(asm, "-- end intrinsic --");
+            }
+            InstructionKind::Keyword(Keyword::While { self_ip, .. }) => {
+                comment!(asm, "-- while --");
+                label!(asm, labels.get(*self_ip));
+            }
+            InstructionKind::Keyword(Keyword::Do { end_ip }) => {
+                pop(&mut asm, "x0");
+                asm!(
+                    asm,
+                    /// Jump to the end of the block
+                    ("cbz", "x0, {}", labels.get(*end_ip))
+                );
+                comment!(asm, "-- do --");
+            }
+            InstructionKind::Keyword(Keyword::If) => {
+                comment!(asm, "-- if --");
+            }
+            InstructionKind::Keyword(Keyword::Unsafe) => {
+                comment!(asm, "-- unsafe --");
+            }
+            InstructionKind::Keyword(
+                keyword @ (Keyword::Elif { self_ip, end_ip } | Keyword::Else { self_ip, end_ip }),
+            ) => {
+                comment!(asm, "-- {} --", keyword);
+                asm!(
+                    asm,
+                    /// Jump to the end of the if statement
+                    ("b", "{}", labels.get(*end_ip))
+                );
+                label!(asm, labels.get(*self_ip));
+            }
+            InstructionKind::Keyword(Keyword::End { self_ip, while_ip }) => {
+                comment!(asm, "-- end --");
+                if let Some(while_ip) = while_ip {
+                    asm!(
+                        asm,
+                        /// Jump to while statement
+                        ("b", "{}", labels.get(*while_ip))
+                    );
+                }
+                label!(asm, labels.get(*self_ip));
+            }
+            InstructionKind::Op(op) => match op {
+                Op::Add => ops::add(&mut asm),
+                Op::Sub => ops::sub(&mut asm),
+                Op::Mul => ops::mul(&mut asm),
+                Op::Div => ops::div(&mut asm),
+                Op::Mod => ops::mod_(&mut asm),
+                Op::DivMod => ops::divmod(&mut asm),
+                Op::BitwiseAnd => ops::band(&mut asm),
+                Op::BitwiseOr => ops::bor(&mut asm),
+                Op::BitwiseXor => ops::xor(&mut asm),
+                Op::BitwiseNot => ops::not(&mut asm),
+                Op::Shl => ops::shl(&mut asm),
+                Op::Shr => ops::shr(&mut asm),
+                Op::Eq => ops::eq(&mut asm),
+                Op::Neq => ops::neq(&mut asm),
+                Op::Lt => ops::lt(&mut asm),
+                Op::Gt => ops::gt(&mut asm),
+                Op::Lte => ops::lte(&mut asm),
+                Op::Gte => ops::gte(&mut asm),
+                Op::Load => ops::load(&mut asm),
+                Op::Store => ops::store(&mut asm),
+                Op::Load64 => ops::load64(&mut asm),
+                Op::Store64 => ops::store64(&mut asm),
+            },
+            InstructionKind::Syscall(kind) => ops::syscall(&mut asm, syscall_args(kind)),
+            InstructionKind::Keyword(Keyword::Include) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("include".into())),
+                    "Include should be expanded before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Macro) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("macro".into())),
+                    "Macro should be expanded before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Extern) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("extern".into())),
+                    "Extern should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Memory) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("memory".into())),
+                    "Memory should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Memory(memory) => {
+                address(&mut asm, "x0", &memory.label());
+                push(&mut asm, "x0");
+            }
+            InstructionKind::Extern(_) => {
+                unreachable!("compile only lets externs through for native x86_64-linux")
+            }
+            InstructionKind::Name(name) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken(name.clone())),
+                    format!("Name {} should be resolved before codegen", name),
+                    ip
+                )
+            }
+        }
+    }
+
+    exit(&mut asm, 0);
+
+    intrinsics::gen_print(&mut asm);
+    gen_syscall(&mut asm);
+
+    Ok(asm)
+}
+
+fn syscall_args(kind: &SyscallKind) -> usize {
+    match kind {
+        SyscallKind::Syscall0 => 0,
+        SyscallKind::Syscall1 => 1,
+        SyscallKind::Syscall2 => 2,
+        SyscallKind::Syscall3 => 3,
+        SyscallKind::Syscall4 => 4,
+        SyscallKind::Syscall5 => 5,
+        SyscallKind::Syscall6 => 6,
+    }
+}
+
+/// [`SYSCALLS`] indexed by x86-64 number, with gaps set to `UNSUPPORTED`.
+pub(super) fn syscall_table() -> Vec<u16> {
+    let len = SYSCALLS.iter().map(|&(x86, _)| x86 + 1).max().unwrap_or(0);
+    let mut table = vec![UNSUPPORTED; len];
+    for &(x86, generic) in SYSCALLS {
+        table[x86] = generic;
+    }
+    table
+}
+
+/// `do_syscall` and the table it translates numbers with.
+fn gen_syscall(asm: &mut Builder) {
+    let table = syscall_table();
+    let len = table.len();
+
+    label!(asm, "do_syscall");
+    asm!(
+        asm,
+        ("cmp", "x8, #{}", len),
+        ("b.hs", ".Lsyscall_unsupported")
+    );
+    address(asm, "x9", "syscall_table");
+    asm!(
+        asm,
+        ("ldrh", "w9, [x9, x8, lsl #1]"),
+        ("mov", "x10, #{}", UNSUPPORTED),
+        ("cmp", "x9, x10"),
+        ("b.eq", ".Lsyscall_unsupported"),
+        ("tbz", "x9, #{}, .Lsyscall_fork", AT_FDCWD.trailing_zeros()),
+        ("mov", "x3, x2"),
+        ("mov", "x2, x1"),
+        ("mov", "x1, x0"),
+        /// AT_FDCWD
+        ("mov", "x0, #-100")
+    );
+    label!(asm, ".Lsyscall_fork");
+    asm!(
+        asm,
+        ("tbz", "x9, #{}, .Lsyscall_call", FORK.trailing_zeros()),
+        /// SIGCHLD, which is what fork sends the parent
+        ("mov", "x0, #17")
+    );
+    label!(asm, ".Lsyscall_call");
+    asm!(
+        asm,
+        ("and", "x8, x9, #{}", FORK - 1),
+        ("svc", "#0"),
+        ("ret")
+    );
+    label!(asm, ".Lsyscall_unsupported");
+    asm!(
+        asm,
+        /// -ENOSYS
+        ("mov", "x0, #-38"),
+        ("ret")
+    );
+
+    segment!(asm, "rodata");
+    asm!(asm, (".balign", "2"));
+    label!(asm, "syscall_table");
+    for chunk in table.chunks(16) {
+        let entries: Vec<String> = chunk.iter().map(u16::to_string).collect();
+        asm!(asm, (".hword", "{}", entries.join(", ")));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{asm, asm_line, comment};
+
+use super::{pop, push, Builder};
+
+/// Pop `b` then `a`, and push `a op b`.
+fn binary(asm: &mut Builder, name: &str, op: &str) {
+    comment!(asm, "-- {} --", name);
+    pop(asm, "x1");
+    pop(asm, "x0");
+    asm!(asm, (op, "x0, x0, x1"));
+    push(asm, "x0");
+}
+
+/// Pop `b` then `a`, and push whether `a cond b`, signed.
+fn compare(asm: &mut Builder, name: &str, cond: &str) {
+    comment!(asm, "-- {} --", name);
+    pop(asm, "x1");
+    pop(asm, "x0");
+    asm!(asm, ("cmp", "x0, x1"), ("cset", "x0, {}", cond));
+    push(asm, "x0");
+}
+
+pub fn add(asm: &mut Builder) {
+    binary(asm, "add", "add");
+}
+
+pub fn sub(asm: &mut Builder) {
+    binary(asm, "sub", "sub");
+}
+
+pub fn mul(asm: &mut Builder) {
+    binary(asm, "mul", "mul");
+}
+
+pub fn div(asm: &mut Builder) {
+    binary(asm, "div", "udiv");
+}
+
+pub fn mod_(asm: &mut Builder) {
+    comment!(asm, "-- mod --");
+    pop(asm, "x1");
+    pop(asm, "x0");
+    asm!(
+        asm,
+        ("udiv", "x2, x0, x1"),
+        /// Remainder is a - (a / b) * b
+        ("msub", "x0, x2, x1, x0")
+    );
+    push(asm, "x0");
+}
+
+pub fn divmod(asm: &mut Builder) {
+    comment!(asm, "-- divmod --");
+    pop(asm, "x1");
+    pop(asm, "x0");
+    asm!(asm, ("udiv", "x2, x0, x1"), ("msub", "x3, x2, x1, x0"));
+    push(asm, "x2");
+    push(asm, "x3");
+}
+
+pub fn not(asm: &mut Builder) {
+    comment!(asm, "-- not --");
+    pop(asm, "x0");
+    asm!(asm, ("mvn", "x0, x0"));
+    push(asm, "x0");
+}
+
+pub fn band(asm: &mut Builder) {
+    binary(asm, "and", "and");
+}
+
+pub fn bor(asm: &mut Builder) {
+    binary(asm, "or", "orr");
+}
+
+pub fn xor(asm: &mut Builder) {
+    binary(asm, "xor", "eor");
+}
+
+pub fn shl(asm: &mut Builder) {
+    binary(asm, "shl", "lsl");
+}
+
+pub fn shr(asm: &mut Builder) {
+    binary(asm, "shr", "lsr");
+}
+
+pub fn eq(asm: &mut Builder) {
+    compare(asm, "eq", "eq");
+}
+
+pub fn neq(asm: &mut Builder) {
+    compare(asm, "neq", "ne");
+}
+
+pub fn lt(asm: &mut Builder) {
+    compare(asm, "lt", "lt");
+}
+
+pub fn gt(asm: &mut Builder) {
+    compare(asm, "gt", "gt");
+}
+
+pub fn lte(asm: &mut Builder) {
+    compare(asm, "lte", "le");
+}
+
+pub fn gte(asm: &mut Builder) {
+    compare(asm, "gte", "ge");
+}
+
+pub fn load(asm: &mut Builder) {
+    comment!(asm, "-- load --");
+    pop(asm, "x0");
+    asm!(asm, ("ldrb", "w0, [x0]"));
+    push(asm, "x0");
+}
+
+pub fn store(asm: &mut Builder) {
+    comment!(asm, "-- store --");
+    pop(asm, "x1");
+    pop(asm, "x0");
+    asm!(asm, ("strb", "w1, [x0]"));
+}
+
+pub fn load64(asm: &mut Builder) {
+    comment!(asm, "-- load64 --");
+    pop(asm, "x0");
+    asm!(asm, ("ldr", "x0, [x0]"));
+    push(asm, "x0");
+}
+
+pub fn store64(asm: &mut Builder) {
+    comment!(asm, "-- store64 --");
+    pop(asm, "x1");
+    pop(asm, "x0");
+    asm!(asm, ("str", "x1, [x0]"));
+}
+
+/// Pop the x86-64 syscall number and `args` arguments, and push the result.
+/// Arguments that weren't given are zeroed, so calls that gain one here, like
+/// `pipe` becoming `pipe2`, pass 0 for it.
+pub fn syscall(asm: &mut Builder, args: usize) {
+    comment!(asm, "-- syscall{} --", args);
+    pop(asm, "x8");
+    for arg in 0..6 {
+        if arg < args {
+            pop(asm, &format!("x{}", arg));
+        } else {
+            asm!(asm, ("mov", "x{}, #0", arg));
+        }
+    }
+    asm!(asm, ("bl", "do_syscall"));
+    push(asm, "x0");
+}
@@ -0,0 +1,192 @@
+//! DWARF 4 debug info for the text section: a line table mapping addresses to
+//! the lines of the .porth files they were compiled from, and the compile unit
+//! debuggers find it through.
+//!
+//! The program is always linked on its own, so the offsets of the line table
+//! and abbreviations are their offsets in the object, and only the addresses of
+//! the code are relocated.
+
+use super::{Reloc, Relocation, Section, SectionKind};
+
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_LANGUAGE: u8 = 0x13;
+const DW_AT_COMP_DIR: u8 = 0x1b;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA2: u8 = 0x05;
+const DW_FORM_DATA8: u8 = 0x07;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_SEC_OFFSET: u8 = 0x17;
+/// What GNU as calls its own output, there being nothing for porth
+const DW_LANG_MIPS_ASSEMBLER: u16 = 0x8001;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+const VERSION: u16 = 4;
+/// Special opcodes aren't used, but the header still describes them
+const LINE_BASE: i8 = -5;
+const LINE_RANGE: u8 = 14;
+const OPCODE_BASE: u8 = 13;
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+/// The start of the code for a line of source.
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub offset: usize,
+    pub file: String,
+    pub line: usize,
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn string(out: &mut Vec<u8>, s: &str) {
+    out.extend(s.as_bytes());
+    out.push(0);
+}
+
+fn section(name: &'static str, bytes: Vec<u8>, relocations: Vec<Relocation>) -> Section {
+    Section {
+        kind: SectionKind::Debug(name),
+        size: bytes.len(),
+        bytes,
+        relocations,
+    }
+}
+
+/// The debug sections for a text section of `size` bytes, laid out as `rows`
+/// says. `text` is a symbol at its start.
+pub fn sections(rows: &[Row], size: usize, text: usize) -> Vec<Section> {
+    let mut files: Vec<&str> = Vec::new();
+    for row in rows {
+        if !files.contains(&row.file.as_str()) {
+            files.push(&row.file);
+        }
+    }
+    let address = |offset| Relocation {
+        offset,
+        symbol: text,
+        reloc: Reloc::Abs64,
+        addend: 0,
+    };
+
+    let mut header = vec![1, 1, 1, LINE_BASE as u8, LINE_RANGE, OPCODE_BASE];
+    header.extend(STANDARD_OPCODE_LENGTHS);
+    // No include directories, so every file is relative to the compile unit
+    header.push(0);
+    for file in &files {
+        string(&mut header, file);
+        header.extend([0, 0, 0]);
+    }
+    header.push(0);
+
+    let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+    let set_address = program.len();
+    program.extend([0; 8]);
+    let (mut file, mut line, mut offset) = (1, 1, 0);
+    for row in rows {
+        let id = files
+            .iter()
+            .position(|&f| f == row.file)
+            .unwrap_or_default()
+            + 1;
+        if id != file {
+            program.push(DW_LNS_SET_FILE);
+            uleb(&mut program, id as u64);
+            file = id;
+        }
+        if row.line != line {
+            program.push(DW_LNS_ADVANCE_LINE);
+            sleb(&mut program, row.line as i64 - line as i64);
+            line = row.line;
+        }
+        if row.offset != offset {
+            program.push(DW_LNS_ADVANCE_PC);
+            uleb(&mut program, (row.offset - offset) as u64);
+            offset = row.offset;
+        }
+        program.push(DW_LNS_COPY);
+    }
+    if size != offset {
+        program.push(DW_LNS_ADVANCE_PC);
+        uleb(&mut program, (size - offset) as u64);
+    }
+    program.extend([0, 1, DW_LNE_END_SEQUENCE]);
+
+    let mut debug_line = Vec::new();
+    let length = 2 + 4 + header.len() + program.len();
+    debug_line.extend((length as u32).to_le_bytes());
+    debug_line.extend(VERSION.to_le_bytes());
+    debug_line.extend((header.len() as u32).to_le_bytes());
+    debug_line.extend(header);
+    let set_address = debug_line.len() + set_address;
+    debug_line.extend(program);
+
+    let mut debug_abbrev = vec![1, DW_TAG_COMPILE_UNIT, 0];
+    for (attribute, form) in [
+        (DW_AT_PRODUCER, DW_FORM_STRING),
+        (DW_AT_LANGUAGE, DW_FORM_DATA2),
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_COMP_DIR, DW_FORM_STRING),
+        (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        (DW_AT_LOW_PC, DW_FORM_ADDR),
+        (DW_AT_HIGH_PC, DW_FORM_DATA8),
+    ] {
+        debug_abbrev.extend([attribute, form]);
+    }
+    debug_abbrev.extend([0, 0, 0]);
+
+    let mut unit = vec![1];
+    string(&mut unit, "worthc");
+    unit.extend(DW_LANG_MIPS_ASSEMBLER.to_le_bytes());
+    string(&mut unit, files.first().copied().unwrap_or_default());
+    let directory = std::env::current_dir().unwrap_or_default();
+    string(&mut unit, &directory.to_string_lossy());
+    unit.extend(0u32.to_le_bytes());
+    let low_pc = unit.len();
+    unit.extend(0u64.to_le_bytes());
+    unit.extend((size as u64).to_le_bytes());
+    let mut debug_info = Vec::new();
+    debug_info.extend(((2 + 4 + 1 + unit.len()) as u32).to_le_bytes());
+    debug_info.extend(VERSION.to_le_bytes());
+    debug_info.extend(0u32.to_le_bytes());
+    debug_info.push(8);
+    let low_pc = debug_info.len() + low_pc;
+    debug_info.extend(unit);
+
+    vec![
+        section(".debug_info", debug_info, vec![address(low_pc)]),
+        section(".debug_abbrev", debug_abbrev, Vec::new()),
+        section(".debug_line", debug_line, vec![address(set_address)]),
+    ]
+}
@@ -0,0 +1,441 @@
+//! Writing an `Object` as an ELF64 file for x86_64, either relocatable for a
+//! linker or as a static executable.
+
+use super::{Object, Reloc, Section, SectionKind};
+use crate::codegen::builder::SegmentKind;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+/// Position independent executables are shared objects that can be run
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+/// Where executables are loaded, like ld's default.
+const BASE_ADDRESS: u64 = 0x400000;
+const PAGE_SIZE: usize = 0x1000;
+
+/// A string table, with the offset of each string added to it.
+struct Strings(Vec<u8>);
+
+impl Strings {
+    fn new() -> Self {
+        Self(vec![0])
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend(s.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+impl SectionHeader {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend(self.name.to_le_bytes());
+        out.extend(self.kind.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend(self.address.to_le_bytes());
+        out.extend(self.offset.to_le_bytes());
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.link.to_le_bytes());
+        out.extend(self.info.to_le_bytes());
+        out.extend(self.align.to_le_bytes());
+        out.extend(self.entry_size.to_le_bytes());
+    }
+}
+
+/// A loadable segment of an executable.
+struct ProgramHeader {
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+impl ProgramHeader {
+    fn write(&self, out: &mut [u8]) {
+        let mut phdr = Vec::with_capacity(PHDR_SIZE);
+        phdr.extend(PT_LOAD.to_le_bytes());
+        phdr.extend(self.flags.to_le_bytes());
+        phdr.extend(self.offset.to_le_bytes());
+        // Virtual, then physical
+        phdr.extend(self.address.to_le_bytes());
+        phdr.extend(self.address.to_le_bytes());
+        phdr.extend(self.file_size.to_le_bytes());
+        phdr.extend(self.memory_size.to_le_bytes());
+        phdr.extend((PAGE_SIZE as u64).to_le_bytes());
+        out.copy_from_slice(&phdr);
+    }
+}
+
+/// A file being written, with space at the start for the headers, which are
+/// filled in once the rest is laid out. There's room for up to as many program
+/// headers as it's made with.
+struct Writer {
+    out: Vec<u8>,
+    names: Strings,
+    headers: Vec<SectionHeader>,
+}
+
+impl Writer {
+    fn new(program_headers: usize) -> Self {
+        Self {
+            out: vec![0; EHDR_SIZE + program_headers * PHDR_SIZE],
+            names: Strings::new(),
+            headers: vec![SectionHeader::default()],
+        }
+    }
+
+    /// Add a section with `contents` at `offset`, or at the end of the file if
+    /// it's `None`. Returns its index.
+    fn section(
+        &mut self,
+        name: &str,
+        mut header: SectionHeader,
+        offset: Option<usize>,
+        contents: &[u8],
+    ) -> u32 {
+        let offset =
+            offset.unwrap_or_else(|| self.out.len().next_multiple_of(header.align as usize));
+        header.name = self.names.add(name);
+        header.offset = offset as u64;
+        if header.kind != SHT_NOBITS {
+            header.size = contents.len() as u64;
+            let end = offset + contents.len();
+            if self.out.len() < end {
+                self.out.resize(end, 0);
+            }
+            self.out[offset..end].copy_from_slice(contents);
+        }
+        self.headers.push(header);
+        self.headers.len() as u32 - 1
+    }
+
+    fn finish(mut self, kind: u16, entry: u64, program_headers: &[ProgramHeader]) -> Vec<u8> {
+        let name = self.names.add(".shstrtab");
+        let names = std::mem::take(&mut self.names.0);
+        let shstrtab = self.headers.len();
+        self.headers.push(SectionHeader {
+            name,
+            kind: SHT_STRTAB,
+            offset: self.out.len() as u64,
+            size: names.len() as u64,
+            align: 1,
+            ..SectionHeader::default()
+        });
+        self.out.extend(names);
+
+        self.out.resize(self.out.len().next_multiple_of(8), 0);
+        let section_headers = self.out.len() as u64;
+        for header in &self.headers {
+            header.write(&mut self.out);
+        }
+
+        let mut ehdr = Vec::with_capacity(EHDR_SIZE);
+        ehdr.extend([0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        ehdr.extend([0; 8]);
+        ehdr.extend(kind.to_le_bytes());
+        ehdr.extend(EM_X86_64.to_le_bytes());
+        ehdr.extend(1u32.to_le_bytes());
+        ehdr.extend(entry.to_le_bytes());
+        let (phoff, phentsize) = match program_headers.len() {
+            0 => (0, 0),
+            _ => (EHDR_SIZE as u64, PHDR_SIZE as u16),
+        };
+        ehdr.extend(phoff.to_le_bytes());
+        ehdr.extend(section_headers.to_le_bytes());
+        ehdr.extend(0u32.to_le_bytes());
+        ehdr.extend((EHDR_SIZE as u16).to_le_bytes());
+        ehdr.extend(phentsize.to_le_bytes());
+        ehdr.extend((program_headers.len() as u16).to_le_bytes());
+        ehdr.extend((SHDR_SIZE as u16).to_le_bytes());
+        ehdr.extend((self.headers.len() as u16).to_le_bytes());
+        ehdr.extend((shstrtab as u16).to_le_bytes());
+        self.out[..EHDR_SIZE].copy_from_slice(&ehdr);
+
+        for (i, phdr) in program_headers.iter().enumerate() {
+            let start = EHDR_SIZE + i * PHDR_SIZE;
+            phdr.write(&mut self.out[start..start + PHDR_SIZE]);
+        }
+        self.out
+    }
+}
+
+fn name(section: &Section) -> &'static str {
+    match section.kind {
+        SectionKind::Segment(SegmentKind::Bss) => ".bss",
+        SectionKind::Segment(SegmentKind::Text) => ".text",
+        SectionKind::Segment(SegmentKind::Data) => ".data",
+        SectionKind::Segment(SegmentKind::Rodata) => ".rodata",
+        SectionKind::Debug(name) => name,
+    }
+}
+
+fn header(section: &Section, address: u64) -> SectionHeader {
+    let (kind, flags, align) = match section.kind {
+        SectionKind::Segment(SegmentKind::Bss) => (SHT_NOBITS, SHF_ALLOC | SHF_WRITE, 16),
+        SectionKind::Segment(SegmentKind::Text) => (SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, 16),
+        SectionKind::Segment(SegmentKind::Data) => (SHT_PROGBITS, SHF_ALLOC | SHF_WRITE, 16),
+        SectionKind::Segment(SegmentKind::Rodata) => (SHT_PROGBITS, SHF_ALLOC, 16),
+        SectionKind::Debug(_) => (SHT_PROGBITS, 0, 1),
+    };
+    SectionHeader {
+        kind,
+        flags,
+        address,
+        size: section.size as u64,
+        align,
+        ..SectionHeader::default()
+    }
+}
+
+fn relocation_type(reloc: Reloc) -> u32 {
+    match reloc {
+        Reloc::Abs64 => 1,
+        Reloc::Pc32 => 2,
+        Reloc::Plt32 => 4,
+        Reloc::Abs32S => 11,
+    }
+}
+
+/// Write the symbol table and its strings, with each symbol's value its offset
+/// plus the address of its section. The object's sections have to be the first
+/// ones after the null section. Returns the position of each symbol in the
+/// table, and the index of the table.
+fn symbols(writer: &mut Writer, object: &Object, addresses: &[u64]) -> (Vec<u32>, u32) {
+    // Locals come first, so the globals can be found
+    let mut order: Vec<usize> = (0..object.symbols.len()).collect();
+    order.sort_by_key(|&id| object.symbols[id].global);
+    let mut strings = Strings::new();
+    let mut index = vec![0; object.symbols.len()];
+    let mut symtab = vec![0; SYM_SIZE];
+    for (i, &id) in order.iter().enumerate() {
+        let symbol = &object.symbols[id];
+        index[id] = i as u32 + 1;
+        let (section, value) = match symbol.definition {
+            Some((section, offset)) => (section as u16 + 1, addresses[section] + offset as u64),
+            None => (0, 0),
+        };
+        let bind = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+        symtab.extend(strings.add(&symbol.name).to_le_bytes());
+        symtab.push(bind << 4);
+        symtab.push(0);
+        symtab.extend(section.to_le_bytes());
+        symtab.extend(value.to_le_bytes());
+        symtab.extend(0u64.to_le_bytes());
+    }
+    let first_global = order
+        .iter()
+        .position(|&id| object.symbols[id].global)
+        .unwrap_or(order.len())
+        + 1;
+
+    let symtab_index = writer.headers.len() as u32;
+    let header = SectionHeader {
+        kind: SHT_SYMTAB,
+        link: symtab_index + 1,
+        info: first_global as u32,
+        align: 8,
+        entry_size: SYM_SIZE as u64,
+        ..SectionHeader::default()
+    };
+    writer.section(".symtab", header, None, &symtab);
+    let header = SectionHeader {
+        kind: SHT_STRTAB,
+        align: 1,
+        ..SectionHeader::default()
+    };
+    writer.section(".strtab", header, None, &strings.0);
+    (index, symtab_index)
+}
+
+/// An ELF relocatable object file, for a linker.
+pub fn relocatable(object: &Object) -> Vec<u8> {
+    let mut writer = Writer::new(0);
+    for section in &object.sections {
+        writer.section(name(section), header(section, 0), None, &section.bytes);
+    }
+    let (index, symtab) = symbols(&mut writer, object, &vec![0; object.sections.len()]);
+
+    for (i, section) in object.sections.iter().enumerate() {
+        if section.relocations.is_empty() {
+            continue;
+        }
+        let mut rela = Vec::with_capacity(section.relocations.len() * RELA_SIZE);
+        for relocation in &section.relocations {
+            let info =
+                (index[relocation.symbol] as u64) << 32 | relocation_type(relocation.reloc) as u64;
+            rela.extend((relocation.offset as u64).to_le_bytes());
+            rela.extend(info.to_le_bytes());
+            rela.extend(relocation.addend.to_le_bytes());
+        }
+        let header = SectionHeader {
+            kind: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            link: symtab,
+            info: i as u32 + 1,
+            align: 8,
+            entry_size: RELA_SIZE as u64,
+            ..SectionHeader::default()
+        };
+        writer.section(&format!(".rela{}", name(section)), header, None, &rela);
+    }
+    writer.finish(ET_REL, 0, &[])
+}
+
+/// A static executable starting at the global `entry`. Text, read-only data
+/// and writable data are each loaded on their own pages, with bss after the
+/// data, and the text shares the first page with the headers. If it's `pie`,
+/// it's laid out from zero for the kernel to load wherever it likes, and can't
+/// have any absolute addresses in it.
+pub fn executable(object: &Object, entry: &str, pie: bool) -> Result<Vec<u8>, String> {
+    let base = if pie { 0 } else { BASE_ADDRESS };
+    let segments = [
+        (SegmentKind::Text, PF_R | PF_X),
+        (SegmentKind::Rodata, PF_R),
+        (SegmentKind::Data, PF_R | PF_W),
+    ];
+    let ids = |kind: SegmentKind| {
+        let sections = object.sections.iter().enumerate();
+        sections
+            .filter(move |(_, section)| section.kind == SectionKind::Segment(kind))
+            .map(|(id, _)| id)
+    };
+
+    // Everything is laid out first, so relocations know where symbols end up
+    let mut offsets = vec![0; object.sections.len()];
+    let mut addresses = vec![0; object.sections.len()];
+    let mut program_headers = Vec::new();
+    let mut end = EHDR_SIZE + segments.len() * PHDR_SIZE;
+    for (kind, flags) in segments {
+        let start = match kind {
+            SegmentKind::Text => 0,
+            _ => end.next_multiple_of(PAGE_SIZE),
+        };
+        end = end.max(start);
+        for id in ids(kind) {
+            end = end.next_multiple_of(16);
+            offsets[id] = end;
+            addresses[id] = base + end as u64;
+            end += object.sections[id].size;
+        }
+        let file_end = end;
+        let mut memory_end = end;
+        if kind == SegmentKind::Data {
+            for id in ids(SegmentKind::Bss) {
+                memory_end = memory_end.next_multiple_of(16);
+                offsets[id] = file_end;
+                addresses[id] = base + memory_end as u64;
+                memory_end += object.sections[id].size;
+            }
+        }
+        // Segments with nothing in them aren't loaded at all
+        if memory_end == start {
+            continue;
+        }
+        program_headers.push(ProgramHeader {
+            flags,
+            offset: start as u64,
+            address: base + start as u64,
+            file_size: (file_end - start) as u64,
+            memory_size: (memory_end - start) as u64,
+        });
+    }
+
+    let address = |id: usize| match object.symbols[id].definition {
+        Some((section, offset)) => Ok(addresses[section] + offset as u64),
+        None => Err(format!("`{}` is never defined", object.symbols[id].name)),
+    };
+    let mut contents: Vec<Vec<u8>> = object.sections.iter().map(|s| s.bytes.clone()).collect();
+    for (id, section) in object.sections.iter().enumerate() {
+        for relocation in &section.relocations {
+            let target = address(relocation.symbol)? as i64 + relocation.addend;
+            let place = addresses[id] as i64 + relocation.offset as i64;
+            let out_of_range = |_| {
+                let symbol = &object.symbols[relocation.symbol].name;
+                format!("`{}` is too far away to reference", symbol)
+            };
+            let loaded = matches!(section.kind, SectionKind::Segment(_));
+            if pie && loaded && matches!(relocation.reloc, Reloc::Abs64 | Reloc::Abs32S) {
+                let symbol = &object.symbols[relocation.symbol].name;
+                return Err(format!(
+                    "`{}` is an absolute address, which a position independent executable can't have",
+                    symbol
+                ));
+            }
+            let field = &mut contents[id][relocation.offset..];
+            match relocation.reloc {
+                Reloc::Abs64 => field[..8].copy_from_slice(&target.to_le_bytes()),
+                Reloc::Abs32S => {
+                    let value = i32::try_from(target).map_err(out_of_range)?;
+                    field[..4].copy_from_slice(&value.to_le_bytes());
+                }
+                Reloc::Pc32 | Reloc::Plt32 => {
+                    let value = i32::try_from(target - place).map_err(out_of_range)?;
+                    field[..4].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+    }
+    let entry = object
+        .symbols
+        .iter()
+        .position(|symbol| symbol.name == entry && symbol.global)
+        .ok_or_else(|| format!("there's no global `{}` to start at", entry))?;
+    let entry = address(entry)?;
+
+    let mut writer = Writer::new(segments.len());
+    for (id, section) in object.sections.iter().enumerate() {
+        let header = header(section, addresses[id]);
+        // Debug info goes after everything that's loaded
+        let offset = match section.kind {
+            SectionKind::Segment(_) => Some(offsets[id]),
+            SectionKind::Debug(_) => None,
+        };
+        writer.section(name(section), header, offset, &contents[id]);
+    }
+    symbols(&mut writer, object, &addresses);
+    let kind = if pie { ET_DYN } else { ET_EXEC };
+    Ok(writer.finish(kind, entry, &program_headers))
+}
@@ -0,0 +1,725 @@
+//! Encoding single x86_64 instructions, written as nasm writes them.
+
+/// What a field in an instruction has to be filled in with once the symbols
+/// have addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reloc {
+    /// The 64 bit address
+    Abs64,
+    /// The address, sign extended from 32 bits
+    Abs32S,
+    /// The distance from the field, as data references across sections are
+    Pc32,
+    /// Like `Pc32`, through the PLT for symbols in other objects
+    Plt32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fixup {
+    /// Of the field, from the start of the instruction
+    pub offset: usize,
+    pub symbol: String,
+    pub reloc: Reloc,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Inst {
+    pub bytes: Vec<u8>,
+    pub fixup: Option<Fixup>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Reg {
+    num: u8,
+    /// In bytes
+    size: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Mem {
+    size: Option<u8>,
+    base: Option<u8>,
+    /// The register and its scale
+    index: Option<(u8, u8)>,
+    disp: i64,
+    symbol: Option<String>,
+    rip: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Reg(Reg),
+    Imm(i64),
+    /// A symbol's address as an immediate
+    Symbol(String),
+    Mem(Mem),
+}
+
+const REGISTERS: [[&str; 16]; 4] = [
+    [
+        "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+        "r13b", "r14b", "r15b",
+    ],
+    [
+        "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w",
+        "r13w", "r14w", "r15w",
+    ],
+    [
+        "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d",
+        "r12d", "r13d", "r14d", "r15d",
+    ],
+    [
+        "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12",
+        "r13", "r14", "r15",
+    ],
+];
+
+/// The condition codes of `jcc`, `cmovcc` and `setcc`.
+const CONDITIONS: [(&str, u8); 30] = [
+    ("o", 0),
+    ("no", 1),
+    ("b", 2),
+    ("c", 2),
+    ("nae", 2),
+    ("ae", 3),
+    ("nb", 3),
+    ("nc", 3),
+    ("e", 4),
+    ("z", 4),
+    ("ne", 5),
+    ("nz", 5),
+    ("be", 6),
+    ("na", 6),
+    ("a", 7),
+    ("nbe", 7),
+    ("s", 8),
+    ("ns", 9),
+    ("p", 10),
+    ("pe", 10),
+    ("np", 11),
+    ("po", 11),
+    ("l", 12),
+    ("nge", 12),
+    ("ge", 13),
+    ("nl", 13),
+    ("le", 14),
+    ("ng", 14),
+    ("g", 15),
+    ("nle", 15),
+];
+
+/// The ops that share the encodings of `add`, by the opcode extension they use.
+const ARITHMETIC: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+
+/// The ops of the `f7` group, by opcode extension.
+const UNARY: [(&str, u8); 6] = [
+    ("not", 2),
+    ("neg", 3),
+    ("mul", 4),
+    ("imul", 5),
+    ("div", 6),
+    ("idiv", 7),
+];
+
+const SHIFTS: [(&str, u8); 6] = [
+    ("rol", 0),
+    ("ror", 1),
+    ("shl", 4),
+    ("sal", 4),
+    ("shr", 5),
+    ("sar", 7),
+];
+
+fn register(name: &str) -> Option<Reg> {
+    REGISTERS
+        .iter()
+        .zip([1, 2, 4, 8])
+        .find_map(|(names, size)| {
+            let num = names.iter().position(|reg| *reg == name)?;
+            Some(Reg {
+                num: num as u8,
+                size,
+            })
+        })
+}
+
+pub fn number(text: &str) -> Option<i64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok().map(|n| n as i64),
+        None => text.parse().ok(),
+    }
+}
+
+fn condition(suffix: &str) -> Option<u8> {
+    CONDITIONS
+        .iter()
+        .find(|(name, _)| *name == suffix)
+        .map(|(_, code)| *code)
+}
+
+fn operand(arg: &str, scope: &str, default_rel: bool) -> Result<Operand, String> {
+    let (size, arg) = match arg.split_once(' ') {
+        Some(("byte", rest)) => (Some(1), rest.trim()),
+        Some(("word", rest)) => (Some(2), rest.trim()),
+        Some(("dword", rest)) => (Some(4), rest.trim()),
+        Some(("qword", rest)) => (Some(8), rest.trim()),
+        _ => (None, arg),
+    };
+    if let Some(address) = arg.strip_prefix('[').and_then(|arg| arg.strip_suffix(']')) {
+        return memory(address, size, scope, default_rel).map(Operand::Mem);
+    }
+    if let Some(reg) = register(arg) {
+        return Ok(Operand::Reg(reg));
+    }
+    if let Some(n) = number(arg) {
+        return Ok(Operand::Imm(n));
+    }
+    Ok(Operand::Symbol(symbol(arg, scope)))
+}
+
+/// The name nasm gives a label, with local ones belonging to `scope`.
+pub fn symbol(label: &str, scope: &str) -> String {
+    match label.starts_with('.') {
+        true => format!("{}{}", scope, label),
+        false => label.to_string(),
+    }
+}
+
+/// A memory operand, from the inside of nasm's brackets. Under `default rel`,
+/// like nasm, a symbol without registers is relative to rip.
+fn memory(address: &str, size: Option<u8>, scope: &str, default_rel: bool) -> Result<Mem, String> {
+    let (rip, address) = match address.strip_prefix("rel ") {
+        Some(address) => (true, address.trim()),
+        None => (false, address),
+    };
+    let mut mem = Mem {
+        size,
+        rip,
+        ..Mem::default()
+    };
+    let mut negative = false;
+    for token in address.split_inclusive(['+', '-']) {
+        let term = token.trim_end_matches(['+', '-']).trim();
+        if !term.is_empty() {
+            let reg = |name: &str| match register(name.trim()) {
+                Some(Reg { num, size: 8 }) => Ok(num),
+                _ => Err(format!("`{}` can't address memory", name.trim())),
+            };
+            if let Some((index, scale)) = term.split_once('*') {
+                let scale = number(scale.trim()).filter(|s| [1, 2, 4, 8].contains(s));
+                let scale = scale.ok_or(format!("`{}` isn't a scale", term))?;
+                mem.index = Some((reg(index)?, scale as u8));
+            } else if register(term).is_some() {
+                match mem.base {
+                    None => mem.base = Some(reg(term)?),
+                    Some(_) => mem.index = Some((reg(term)?, 1)),
+                }
+            } else if let Some(n) = number(term) {
+                mem.disp += if negative { -n } else { n };
+            } else if !negative && mem.symbol.is_none() {
+                mem.symbol = Some(symbol(term, scope));
+            } else {
+                return Err(format!("`{}` can't be part of an address", term));
+            }
+        }
+        negative = token.ends_with('-');
+    }
+    if mem.index.is_some_and(|(index, _)| index == 4) {
+        return Err("rsp can't be an index".into());
+    }
+    let registers = mem.base.is_some() || mem.index.is_some();
+    if rip && registers {
+        return Err("rip relative addresses can't have registers".into());
+    }
+    mem.rip |= default_rel && !registers && mem.symbol.is_some();
+    Ok(mem)
+}
+
+/// An instruction being put together, prefixes first.
+#[derive(Default)]
+struct Encoder {
+    rex: u8,
+    /// Forces a rex prefix, for `spl`, `bpl`, `sil` and `dil`
+    force_rex: bool,
+    operand_size_prefix: bool,
+    opcode: Vec<u8>,
+    modrm: Vec<u8>,
+    disp_fixup: Option<Fixup>,
+    imm: Vec<u8>,
+    imm_fixup: Option<Fixup>,
+}
+
+impl Encoder {
+    fn new(size: u8, opcode: &[u8]) -> Self {
+        let mut enc = Self {
+            opcode: opcode.to_vec(),
+            ..Self::default()
+        };
+        enc.size(size);
+        enc
+    }
+
+    fn size(&mut self, size: u8) {
+        match size {
+            2 => self.operand_size_prefix = true,
+            8 => self.rex |= 0x48,
+            _ => {}
+        }
+    }
+
+    /// Mark a register as needing the rex prefix if it's a new 8 bit one.
+    fn byte_reg(&mut self, reg: Reg) {
+        if reg.size == 1 && (4..8).contains(&reg.num) {
+            self.force_rex = true;
+        }
+    }
+
+    /// The register in the opcode itself, like `push` and `mov reg, imm`.
+    fn opcode_reg(&mut self, reg: Reg) {
+        self.byte_reg(reg);
+        if reg.num >= 8 {
+            self.rex |= 0x41;
+        }
+        *self.opcode.last_mut().expect("there's an opcode") += reg.num & 7;
+    }
+
+    /// The modrm byte, with `reg` as the register or opcode extension and
+    /// `rm` as the other operand.
+    fn modrm(&mut self, reg: u8, rm: &Operand) -> Result<(), String> {
+        if reg >= 8 {
+            self.rex |= 0x44;
+        }
+        let reg = (reg & 7) << 3;
+        let mem = match rm {
+            Operand::Reg(r) => {
+                self.byte_reg(*r);
+                if r.num >= 8 {
+                    self.rex |= 0x41;
+                }
+                self.modrm.push(0xc0 | reg | (r.num & 7));
+                return Ok(());
+            }
+            Operand::Mem(mem) => mem,
+            _ => return Err("expected a register or memory".into()),
+        };
+        let fixup = |reloc| {
+            mem.symbol.clone().map(|symbol| Fixup {
+                offset: 0,
+                symbol,
+                reloc,
+                addend: mem.disp,
+            })
+        };
+        // Left for the linker to fill in when there's a symbol
+        let disp32 = match mem.symbol {
+            Some(_) => [0; 4],
+            None => (mem.disp as i32).to_le_bytes(),
+        };
+        if i32::try_from(mem.disp).is_err() {
+            return Err("the displacement doesn't fit in 32 bits".into());
+        }
+        if mem.rip {
+            self.modrm.push(reg | 0b101);
+            self.modrm.extend(disp32);
+            self.disp_fixup = fixup(Reloc::Pc32);
+            return Ok(());
+        }
+        if let Some((index, _)) = mem.index {
+            if index >= 8 {
+                self.rex |= 0x42;
+            }
+        }
+        let scale = |(index, scale): (u8, u8)| {
+            let bits = match scale {
+                1 => 0,
+                2 => 1,
+                4 => 2,
+                _ => 3,
+            };
+            (bits << 6) | ((index & 7) << 3)
+        };
+        let Some(base) = mem.base else {
+            // Absolute, or only scaled, with a sib byte that has no base
+            let sib = mem.index.map_or(0b100 << 3, scale) | 0b101;
+            self.modrm.extend([reg | 0b100, sib]);
+            self.modrm.extend(disp32);
+            self.disp_fixup = fixup(Reloc::Abs32S);
+            return Ok(());
+        };
+        if base >= 8 {
+            self.rex |= 0x41;
+        }
+        let (mode, disp): (u8, Vec<u8>) = match mem.disp {
+            _ if mem.symbol.is_some() => (0b10, disp32.to_vec()),
+            // rbp and r13 as a base with mode 0 mean something else
+            0 if base & 7 != 5 => (0b00, Vec::new()),
+            disp if i8::try_from(disp).is_ok() => (0b01, vec![disp as i8 as u8]),
+            _ => (0b10, disp32.to_vec()),
+        };
+        match mem.index {
+            Some(index) => {
+                self.modrm
+                    .extend([(mode << 6) | reg | 0b100, scale(index) | (base & 7)]);
+            }
+            // rsp and r12 as a base need a sib byte
+            None if base & 7 == 4 => self.modrm.extend([(mode << 6) | reg | 0b100, 0x24]),
+            None => self.modrm.push((mode << 6) | reg | (base & 7)),
+        }
+        self.modrm.extend(disp);
+        self.disp_fixup = fixup(Reloc::Abs32S);
+        Ok(())
+    }
+
+    fn imm(&mut self, value: i64, size: u8) {
+        self.imm.extend(&value.to_le_bytes()[..size as usize]);
+    }
+
+    fn imm_symbol(&mut self, symbol: &str, size: u8, reloc: Reloc) {
+        self.imm.extend(vec![0; size as usize]);
+        self.imm_fixup = Some(Fixup {
+            offset: 0,
+            symbol: symbol.to_string(),
+            reloc,
+            addend: 0,
+        });
+    }
+
+    fn finish(self) -> Inst {
+        let mut bytes = Vec::new();
+        if self.operand_size_prefix {
+            bytes.push(0x66);
+        }
+        if self.rex != 0 || self.force_rex {
+            bytes.push(self.rex | 0x40);
+        }
+        bytes.extend(&self.opcode);
+        let modrm_start = bytes.len();
+        bytes.extend(&self.modrm);
+        let imm_start = bytes.len();
+        bytes.extend(&self.imm);
+        // The displacement is the last 4 bytes of the modrm part
+        let fixup = match (self.disp_fixup, self.imm_fixup) {
+            (Some(mut fixup), _) => {
+                fixup.offset = modrm_start + self.modrm.len() - 4;
+                // Relative to the end of the instruction, not of the field
+                if fixup.reloc == Reloc::Pc32 {
+                    fixup.addend -= 4 + self.imm.len() as i64;
+                }
+                Some(fixup)
+            }
+            (None, Some(mut fixup)) => {
+                fixup.offset = imm_start;
+                Some(fixup)
+            }
+            (None, None) => None,
+        };
+        Inst { bytes, fixup }
+    }
+}
+
+/// The size of the operation, from its registers or the size given for memory.
+fn operation_size(operands: &[Operand]) -> Result<u8, String> {
+    operands
+        .iter()
+        .find_map(|operand| match operand {
+            Operand::Reg(reg) => Some(reg.size),
+            Operand::Mem(mem) => mem.size,
+            _ => None,
+        })
+        .ok_or_else(|| "the operation size isn't given".to_string())
+}
+
+fn fits_i8(n: i64) -> bool {
+    i8::try_from(n).is_ok()
+}
+
+fn fits_i32(n: i64) -> bool {
+    i32::try_from(n).is_ok()
+}
+
+/// An immediate of an operation of `size`, which for 32 bit ones can be
+/// written unsigned.
+fn immediate(n: i64, size: u8) -> Result<i64, String> {
+    match size {
+        4 if (0..=u32::MAX as i64).contains(&n) => Ok(n as u32 as i32 as i64),
+        1 if (-128..=255).contains(&n) => Ok(n as u8 as i8 as i64),
+        2 if (-32768..=65535).contains(&n) => Ok(n as u16 as i16 as i64),
+        4 | 8 if fits_i32(n) => Ok(n),
+        _ => Err(format!("{} doesn't fit in the operand", n)),
+    }
+}
+
+/// Encode `op` with the nasm arguments `args`, with local labels belonging to
+/// `scope`, and symbols in memory operands relative to rip if `default_rel`.
+pub fn encode(op: &str, args: &[String], scope: &str, default_rel: bool) -> Result<Inst, String> {
+    let operands = args
+        .iter()
+        .map(|arg| operand(arg, scope, default_rel))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sized = |wide: u8, byte: u8, size: u8| if size == 1 { byte } else { wide };
+
+    let inst = match (op, &operands[..]) {
+        ("syscall", []) => Encoder::new(0, &[0x0f, 0x05]).finish(),
+        ("ret", []) => Encoder::new(0, &[0xc3]).finish(),
+        ("nop", []) => Encoder::new(0, &[0x90]).finish(),
+        ("cqo", []) => Encoder::new(8, &[0x99]).finish(),
+        ("leave", []) => Encoder::new(0, &[0xc9]).finish(),
+
+        (_, [rm, src]) if ARITHMETIC.contains(&op) => {
+            let ext = ARITHMETIC.iter().position(|name| *name == op).unwrap() as u8;
+            let size = operation_size(&operands)?;
+            match (rm, src) {
+                (rm, Operand::Reg(reg)) => {
+                    let mut enc = Encoder::new(size, &[(ext << 3) | sized(1, 0, size)]);
+                    enc.byte_reg(*reg);
+                    enc.modrm(reg.num, rm)?;
+                    enc.finish()
+                }
+                (Operand::Reg(reg), rm @ Operand::Mem(_)) => {
+                    let mut enc = Encoder::new(size, &[(ext << 3) | sized(3, 2, size)]);
+                    enc.byte_reg(*reg);
+                    enc.modrm(reg.num, rm)?;
+                    enc.finish()
+                }
+                (rm, Operand::Imm(n)) => {
+                    let n = immediate(*n, size)?;
+                    let (opcode, imm_size) = match size {
+                        1 => (0x80, 1),
+                        _ if fits_i8(n) => (0x83, 1),
+                        2 => (0x81, 2),
+                        _ => (0x81, 4),
+                    };
+                    let mut enc = Encoder::new(size, &[opcode]);
+                    enc.modrm(ext, rm)?;
+                    enc.imm(n, imm_size);
+                    enc.finish()
+                }
+                (rm, Operand::Symbol(symbol)) if size == 8 => {
+                    let mut enc = Encoder::new(size, &[0x81]);
+                    enc.modrm(ext, rm)?;
+                    enc.imm_symbol(symbol, 4, Reloc::Abs32S);
+                    enc.finish()
+                }
+                _ => return Err("invalid operands".into()),
+            }
+        }
+
+        ("test", [rm, src]) => {
+            let size = operation_size(&operands)?;
+            match src {
+                Operand::Reg(reg) => {
+                    let mut enc = Encoder::new(size, &[sized(0x85, 0x84, size)]);
+                    enc.byte_reg(*reg);
+                    enc.modrm(reg.num, rm)?;
+                    enc.finish()
+                }
+                Operand::Imm(n) => {
+                    let n = immediate(*n, size)?;
+                    let mut enc = Encoder::new(size, &[sized(0xf7, 0xf6, size)]);
+                    enc.modrm(0, rm)?;
+                    enc.imm(n, size.min(4));
+                    enc.finish()
+                }
+                _ => return Err("invalid operands".into()),
+            }
+        }
+
+        ("mov", [dst, src]) => {
+            let size = operation_size(&operands)?;
+            match (dst, src) {
+                (rm, Operand::Reg(reg)) => {
+                    let mut enc = Encoder::new(size, &[sized(0x89, 0x88, size)]);
+                    enc.byte_reg(*reg);
+                    enc.modrm(reg.num, rm)?;
+                    enc.finish()
+                }
+                (Operand::Reg(reg), rm @ Operand::Mem(_)) => {
+                    let mut enc = Encoder::new(size, &[sized(0x8b, 0x8a, size)]);
+                    enc.byte_reg(*reg);
+                    enc.modrm(reg.num, rm)?;
+                    enc.finish()
+                }
+                // Writing the low half zeroes the rest, like nasm does for small constants
+                (Operand::Reg(reg), Operand::Imm(n))
+                    if size == 8 && (0..=u32::MAX as i64).contains(n) =>
+                {
+                    let mut enc = Encoder::new(4, &[0xb8]);
+                    enc.opcode_reg(*reg);
+                    enc.imm(*n, 4);
+                    enc.finish()
+                }
+                (Operand::Reg(_), Operand::Imm(n)) if size == 8 && fits_i32(*n) => {
+                    let mut enc = Encoder::new(8, &[0xc7]);
+                    enc.modrm(0, dst)?;
+                    enc.imm(*n, 4);
+                    enc.finish()
+                }
+                (Operand::Reg(reg), Operand::Imm(n)) => {
+                    let n = match size {
+                        8 => *n,
+                        _ => immediate(*n, size)?,
+                    };
+                    let mut enc = Encoder::new(size, &[sized(0xb8, 0xb0, size)]);
+                    enc.opcode_reg(*reg);
+                    enc.imm(n, size);
+                    enc.finish()
+                }
+                (Operand::Reg(reg), Operand::Symbol(symbol)) if size == 8 => {
+                    let mut enc = Encoder::new(8, &[0xb8]);
+                    enc.opcode_reg(*reg);
+                    enc.imm_symbol(symbol, 8, Reloc::Abs64);
+                    enc.finish()
+                }
+                (Operand::Mem(_), Operand::Imm(n)) => {
+                    let n = immediate(*n, size)?;
+                    let mut enc = Encoder::new(size, &[sized(0xc7, 0xc6, size)]);
+                    enc.modrm(0, dst)?;
+                    enc.imm(n, size.min(4));
+                    enc.finish()
+                }
+                (Operand::Mem(_), Operand::Symbol(symbol)) if size == 8 => {
+                    let mut enc = Encoder::new(8, &[0xc7]);
+                    enc.modrm(0, dst)?;
+                    enc.imm_symbol(symbol, 4, Reloc::Abs32S);
+                    enc.finish()
+                }
+                _ => return Err("invalid operands".into()),
+            }
+        }
+
+        ("lea", [Operand::Reg(reg), mem @ Operand::Mem(_)]) if reg.size > 1 => {
+            let mut enc = Encoder::new(reg.size, &[0x8d]);
+            enc.modrm(reg.num, mem)?;
+            enc.finish()
+        }
+
+        ("movzx" | "movsx", [Operand::Reg(reg), src]) => {
+            let from = operation_size(&operands[1..])?;
+            let opcode = match (op, from) {
+                ("movzx", 1) => 0xb6,
+                ("movzx", 2) => 0xb7,
+                ("movsx", 1) => 0xbe,
+                ("movsx", 2) => 0xbf,
+                _ => return Err("it extends bytes and words".into()),
+            };
+            let mut enc = Encoder::new(reg.size, &[0x0f, opcode]);
+            enc.modrm(reg.num, src)?;
+            enc.finish()
+        }
+
+        ("push", [Operand::Reg(reg)]) if reg.size == 8 => {
+            let mut enc = Encoder::new(0, &[0x50]);
+            enc.opcode_reg(*reg);
+            enc.finish()
+        }
+        ("push", [Operand::Imm(n)]) if fits_i32(*n) => {
+            let mut enc = Encoder::new(0, &[if fits_i8(*n) { 0x6a } else { 0x68 }]);
+            enc.imm(*n, if fits_i8(*n) { 1 } else { 4 });
+            enc.finish()
+        }
+        ("push", [Operand::Symbol(symbol)]) => {
+            let mut enc = Encoder::new(0, &[0x68]);
+            enc.imm_symbol(symbol, 4, Reloc::Abs32S);
+            enc.finish()
+        }
+        ("push", [mem @ Operand::Mem(_)]) => {
+            let mut enc = Encoder::new(0, &[0xff]);
+            enc.modrm(6, mem)?;
+            enc.finish()
+        }
+        ("pop", [Operand::Reg(reg)]) if reg.size == 8 => {
+            let mut enc = Encoder::new(0, &[0x58]);
+            enc.opcode_reg(*reg);
+            enc.finish()
+        }
+        ("pop", [mem @ Operand::Mem(_)]) => {
+            let mut enc = Encoder::new(0, &[0x8f]);
+            enc.modrm(0, mem)?;
+            enc.finish()
+        }
+
+        ("inc" | "dec", [rm]) => {
+            let size = operation_size(&operands)?;
+            let mut enc = Encoder::new(size, &[sized(0xff, 0xfe, size)]);
+            enc.modrm((op == "dec") as u8, rm)?;
+            enc.finish()
+        }
+        (_, [rm]) if UNARY.iter().any(|(name, _)| *name == op) => {
+            let (_, ext) = UNARY.iter().find(|(name, _)| *name == op).unwrap();
+            let size = operation_size(&operands)?;
+            let mut enc = Encoder::new(size, &[sized(0xf7, 0xf6, size)]);
+            enc.modrm(*ext, rm)?;
+            enc.finish()
+        }
+        ("imul", [Operand::Reg(reg), rm]) if reg.size > 1 => {
+            let mut enc = Encoder::new(reg.size, &[0x0f, 0xaf]);
+            enc.modrm(reg.num, rm)?;
+            enc.finish()
+        }
+
+        (_, [rm, count]) if SHIFTS.iter().any(|(name, _)| *name == op) => {
+            let (_, ext) = SHIFTS.iter().find(|(name, _)| *name == op).unwrap();
+            let size = operation_size(&operands[..1])?;
+            match count {
+                Operand::Reg(Reg { num: 1, size: 1 }) => {
+                    let mut enc = Encoder::new(size, &[sized(0xd3, 0xd2, size)]);
+                    enc.modrm(*ext, rm)?;
+                    enc.finish()
+                }
+                Operand::Imm(n) if (0..64).contains(n) => {
+                    let mut enc = Encoder::new(size, &[sized(0xc1, 0xc0, size)]);
+                    enc.modrm(*ext, rm)?;
+                    enc.imm(*n, 1);
+                    enc.finish()
+                }
+                _ => return Err("shifts count by cl or a constant".into()),
+            }
+        }
+
+        ("jmp" | "call", [Operand::Symbol(symbol)]) => {
+            let (opcode, reloc) = match op {
+                "jmp" => (0xe9, Reloc::Pc32),
+                _ => (0xe8, Reloc::Plt32),
+            };
+            let mut enc = Encoder::new(0, &[opcode]);
+            enc.imm_symbol(symbol, 4, reloc);
+            jump(enc)
+        }
+        ("jmp" | "call", [rm]) => {
+            let mut enc = Encoder::new(0, &[0xff]);
+            enc.modrm(if op == "jmp" { 4 } else { 2 }, rm)?;
+            enc.finish()
+        }
+        (_, [Operand::Symbol(symbol)]) if op.starts_with('j') => {
+            let cc = condition(&op[1..]).ok_or("unknown condition")?;
+            let mut enc = Encoder::new(0, &[0x0f, 0x80 | cc]);
+            enc.imm_symbol(symbol, 4, Reloc::Pc32);
+            jump(enc)
+        }
+        (_, [Operand::Reg(reg), rm]) if op.starts_with("cmov") && reg.size > 1 => {
+            let cc = condition(&op[4..]).ok_or("unknown condition")?;
+            let mut enc = Encoder::new(reg.size, &[0x0f, 0x40 | cc]);
+            enc.modrm(reg.num, rm)?;
+            enc.finish()
+        }
+        (_, [rm]) if op.starts_with("set") => {
+            let cc = condition(&op[3..]).ok_or("unknown condition")?;
+            let mut enc = Encoder::new(0, &[0x0f, 0x90 | cc]);
+            enc.modrm(0, rm)?;
+            enc.finish()
+        }
+
+        _ => return Err("unknown instruction".into()),
+    };
+    Ok(inst)
+}
+
+/// A relative jump, whose distance is from the end of the instruction.
+fn jump(enc: Encoder) -> Inst {
+    let mut inst = enc.finish();
+    if let Some(fixup) = &mut inst.fixup {
+        fixup.addend = -4;
+    }
+    inst
+}
@@ -0,0 +1,309 @@
+//! The built-in assembler, which turns the nasm the x86 codegen writes into an
+//! ELF object without running nasm, and can link it into an executable itself.
+//!
+//! Only what the codegen generates is understood: labels, `global` and `extern`,
+//! the data directives, and the instructions it uses with nasm's operand syntax.
+//! Every jump is encoded near, so instructions never change size and labels in
+//! the text segment can be resolved as soon as it's encoded. References to
+//! other segments are left to the linker as relocations.
+
+mod dwarf;
+mod elf;
+mod encode;
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+pub use encode::Reloc;
+
+use super::builder::{Builder, SegmentKind};
+use super::peephole::Line;
+use crate::error::{
+    CompileError::{AssembleError, LinkError},
+    Error::CompileError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Segment(SegmentKind),
+    /// Debug info with its section name, which isn't loaded
+    Debug(&'static str),
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub kind: SectionKind,
+    /// Empty for bss, which is only `size` bytes of zeroes
+    pub bytes: Vec<u8>,
+    pub size: usize,
+    pub relocations: Vec<Relocation>,
+}
+
+/// A field in a section that the linker fills in.
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    pub offset: usize,
+    /// Index into `Object::symbols`
+    pub symbol: usize,
+    pub reloc: Reloc,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    /// The index into `Object::sections` and offset in it, or `None` if the
+    /// symbol is external
+    pub definition: Option<(usize, usize)>,
+    pub global: bool,
+}
+
+/// The assembled program, before it's written out in a file format.
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+}
+
+impl Object {
+    /// The object as an ELF relocatable file.
+    pub fn elf(&self) -> Vec<u8> {
+        elf::relocatable(self)
+    }
+
+    /// The object as a static ELF executable, starting at the global `entry`,
+    /// and loaded anywhere if it's `pie`.
+    pub fn executable(&self, entry: &str, pie: bool) -> Result<Vec<u8>> {
+        match elf::executable(self, entry, pie) {
+            Ok(executable) => Ok(executable),
+            Err(why) => Err(CompileError(LinkError)).with_context(|| why),
+        }
+    }
+}
+
+/// A reference to a symbol, waiting for all of them to be defined.
+struct Pending {
+    section: usize,
+    offset: usize,
+    fixup: encode::Fixup,
+}
+
+#[derive(Default)]
+struct Assembler {
+    sections: Vec<Section>,
+    symbols: Vec<Symbol>,
+    by_name: HashMap<String, usize>,
+    pending: Vec<Pending>,
+    /// The last label that wasn't a local one
+    scope: String,
+    /// Where each line of source starts in the text section, if the builder
+    /// marked them
+    rows: Vec<dwarf::Row>,
+    /// Whether symbols in memory operands are relative to rip, like they are
+    /// under nasm's `default rel`
+    default_rel: bool,
+}
+
+/// Assemble the segments of `asm`, which has to be written for nasm.
+pub fn assemble(asm: &Builder) -> Result<Object> {
+    let mut assembler = Assembler {
+        default_rel: asm.rip_relative,
+        ..Assembler::default()
+    };
+    for (kind, lines) in asm.segments() {
+        let section = assembler.sections.len();
+        assembler.sections.push(Section {
+            kind: SectionKind::Segment(kind),
+            bytes: Vec::new(),
+            size: 0,
+            relocations: Vec::new(),
+        });
+        for line in lines {
+            if let Err(why) = assembler.line(section, line) {
+                return Err(CompileError(AssembleError(line.trim().to_string())))
+                    .with_context(|| why);
+            }
+        }
+    }
+    assembler.link()
+}
+
+impl Assembler {
+    fn symbol(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.by_name.get(name) {
+            return id;
+        }
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            definition: None,
+            global: false,
+        });
+        self.by_name
+            .insert(name.to_string(), self.symbols.len() - 1);
+        self.symbols.len() - 1
+    }
+
+    fn line(&mut self, section: usize, line: &str) -> Result<(), String> {
+        match Line::parse(line.to_string()) {
+            Line::Comment(_) => Ok(()),
+            line @ Line::Loc(_) => {
+                let (file, line) = line.loc().ok_or("expected a line and a file")?;
+                let offset = self.sections[section].size;
+                // Lines that didn't generate any code aren't anywhere
+                if self.rows.last().is_some_and(|last| last.offset == offset) {
+                    self.rows.pop();
+                }
+                if let Some(last) = self.rows.last() {
+                    if last.file == file && last.line == line {
+                        return Ok(());
+                    }
+                }
+                self.rows.push(dwarf::Row {
+                    offset,
+                    file: file.to_string(),
+                    line,
+                });
+                Ok(())
+            }
+            Line::Other(text) => {
+                let text = text.split(";;").next().unwrap_or_default().trim();
+                match text.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["global" | "extern", name] => {
+                        let id = self.symbol(name);
+                        self.symbols[id].global = true;
+                    }
+                    [label] if label.ends_with(':') => {
+                        let label = label.trim_end_matches(':');
+                        if !label.starts_with('.') {
+                            self.scope = label.to_string();
+                        }
+                        let name = encode::symbol(label, &self.scope);
+                        let id = self.symbol(&name);
+                        if self.symbols[id].definition.is_some() {
+                            return Err(format!("`{}` is defined twice", name));
+                        }
+                        self.symbols[id].definition = Some((section, self.sections[section].size));
+                    }
+                    _ => return Err("unknown directive".into()),
+                }
+                Ok(())
+            }
+            Line::Inst { op, args, .. } => self.inst(section, &op, &args),
+        }
+    }
+
+    fn inst(&mut self, section: usize, op: &str, args: &[String]) -> Result<(), String> {
+        let data = |size: usize| -> Result<Vec<u8>, String> {
+            let mut bytes = Vec::new();
+            for arg in args {
+                let value = encode::number(arg).ok_or(format!("`{}` isn't a number", arg))?;
+                bytes.extend(&value.to_le_bytes()[..size]);
+            }
+            Ok(bytes)
+        };
+        let SectionKind::Segment(section_kind) = self.sections[section].kind else {
+            unreachable!("only segments are assembled")
+        };
+        let bytes = match op {
+            "resb" | "resq" => {
+                let count: usize = args
+                    .first()
+                    .and_then(|count| count.parse().ok())
+                    .ok_or("expected a count")?;
+                let size = count * if op == "resq" { 8 } else { 1 };
+                match section_kind {
+                    SegmentKind::Bss => {
+                        self.sections[section].size += size;
+                        return Ok(());
+                    }
+                    _ => vec![0; size],
+                }
+            }
+            "alignb" => {
+                let align: usize = args
+                    .first()
+                    .and_then(|align| align.parse().ok())
+                    .filter(|align: &usize| align.is_power_of_two())
+                    .ok_or("expected a power of two")?;
+                let size = self.sections[section].size.next_multiple_of(align)
+                    - self.sections[section].size;
+                match section_kind {
+                    SegmentKind::Bss => {
+                        self.sections[section].size += size;
+                        return Ok(());
+                    }
+                    _ => vec![0; size],
+                }
+            }
+            "db" => data(1)?,
+            "dw" => data(2)?,
+            "dd" => data(4)?,
+            "dq" => data(8)?,
+            _ => {
+                let inst = encode::encode(op, args, &self.scope, self.default_rel)?;
+                if let Some(fixup) = inst.fixup {
+                    self.pending.push(Pending {
+                        section,
+                        offset: self.sections[section].size + fixup.offset,
+                        fixup,
+                    });
+                }
+                inst.bytes
+            }
+        };
+        if matches!(section_kind, SegmentKind::Bss) {
+            return Err("bss can only reserve space".into());
+        }
+        let section = &mut self.sections[section];
+        section.size += bytes.len();
+        section.bytes.extend(bytes);
+        Ok(())
+    }
+
+    /// Resolve the references within a section, and leave the rest as
+    /// relocations. Debug info is added after, if there are any lines for it.
+    fn link(mut self) -> Result<Object> {
+        for Pending {
+            section,
+            offset,
+            fixup,
+        } in std::mem::take(&mut self.pending)
+        {
+            let id = self.symbol(&fixup.symbol);
+            let symbol = &self.symbols[id];
+            match (symbol.definition, fixup.reloc) {
+                (Some((target, at)), Reloc::Pc32 | Reloc::Plt32) if target == section => {
+                    let distance = at as i64 + fixup.addend - offset as i64;
+                    let field = &mut self.sections[section].bytes[offset..offset + 4];
+                    field.copy_from_slice(&(distance as i32).to_le_bytes());
+                }
+                (None, _) if !symbol.global => {
+                    return Err(CompileError(AssembleError(fixup.symbol.clone())))
+                        .with_context(|| format!("`{}` is never defined", fixup.symbol));
+                }
+                _ => self.sections[section].relocations.push(Relocation {
+                    offset,
+                    symbol: id,
+                    reloc: fixup.reloc,
+                    addend: fixup.addend,
+                }),
+            }
+        }
+        let text = self
+            .sections
+            .iter()
+            .position(|section| section.kind == SectionKind::Segment(SegmentKind::Text));
+        if let (Some(text), false) = (text, self.rows.is_empty()) {
+            let start = self.symbol(".Ltext");
+            self.symbols[start].definition = Some((text, 0));
+            let size = self.sections[text].size;
+            let debug = dwarf::sections(&self.rows, size, start);
+            self.sections.extend(debug);
+        }
+        Ok(Object {
+            sections: self.sections,
+            symbols: self.symbols,
+        })
+    }
+}
@@ -0,0 +1,211 @@
+//! Writing the x86 codegen's nasm lines for GNU as, in AT&T syntax.
+//!
+//! Everything up to the end is generated as nasm, so the peephole optimizer and
+//! the stack cache only ever see one syntax, and each line is translated on its
+//! way out: operands are reversed and marked as registers or immediates, sizes
+//! move from the operand to the op, and nasm's local labels, which belong to the
+//! label before them, get that label as a prefix to keep them unique.
+
+use super::peephole::{family, Line};
+
+pub(super) struct Att {
+    rip_relative: bool,
+    /// The last label that wasn't a local one
+    scope: String,
+    /// The source files named so far, numbered from one
+    files: Vec<String>,
+    /// The file and line of the last `.loc`
+    loc: Option<(usize, usize)>,
+}
+
+impl Att {
+    pub(super) fn new(rip_relative: bool) -> Self {
+        Self {
+            rip_relative,
+            scope: String::new(),
+            files: Vec::new(),
+            loc: None,
+        }
+    }
+
+    /// `line` for GNU as, or `None` if it has nothing to say there.
+    pub(super) fn line(&mut self, line: &str) -> Option<String> {
+        match Line::parse(line.to_string()) {
+            Line::Comment(text) => Some(text.replacen(";;", "#", 1)),
+            Line::Other(text) => self.directive(&text),
+            line @ Line::Loc(_) => {
+                let (file, number) = line.loc()?;
+                // Files are declared the first time a location is in them
+                let (id, declare) = match self.files.iter().position(|f| f == file) {
+                    Some(id) => (id + 1, String::new()),
+                    None => {
+                        self.files.push(file.to_string());
+                        let id = self.files.len();
+                        (id, format!(".file {} \"{}\"\n", id, file))
+                    }
+                };
+                if self.loc == Some((id, number)) {
+                    return None;
+                }
+                self.loc = Some((id, number));
+                Some(format!("{}.loc {} {}", declare, id, number))
+            }
+            Line::Inst {
+                op, args, comment, ..
+            } => {
+                let (op, args) = self.inst(&op, &args);
+                let text = match comment {
+                    Some(comment) => format!("{0:4}{1:8}{2:28}# {3}", " ", op, args, comment),
+                    None => format!("{0:4}{1:8}{2}", " ", op, args),
+                };
+                Some(text.trim_end().to_string())
+            }
+        }
+    }
+
+    /// Symbols and labels.
+    fn directive(&mut self, text: &str) -> Option<String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let line = match words[..] {
+            ["global", symbol] => format!(".globl {}", symbol),
+            // Undefined symbols are external, and rip relative addressing is explicit
+            ["extern", _] | ["default", "rel"] => return None,
+            [label] if label.ends_with(':') => {
+                let label = label.trim_end_matches(':');
+                if !label.starts_with('.') {
+                    self.scope = label.to_string();
+                }
+                format!("{}:", self.label(label))
+            }
+            _ => text.replacen(";;", "#", 1),
+        };
+        Some(line)
+    }
+
+    /// A label as GNU as sees it.
+    fn label(&self, label: &str) -> String {
+        match label.starts_with('.') {
+            true => format!("{}{}", self.scope, label),
+            false => label.to_string(),
+        }
+    }
+
+    /// The op and operands of an instruction or data directive.
+    fn inst(&self, op: &str, args: &[String]) -> (String, String) {
+        match op {
+            "resb" => return (".skip".into(), args.join(", ")),
+            "alignb" => return (".balign".into(), args.join(", ")),
+            "resq" => {
+                let count: usize = args[0].parse().expect("resq takes a count");
+                return (".skip".into(), (count * 8).to_string());
+            }
+            "db" => return (".byte".into(), args.join(", ")),
+            "dw" => return (".short".into(), args.join(", ")),
+            "dq" => return (".quad".into(), args.join(", ")),
+            _ if op == "call" || op.starts_with('j') => {
+                return (op.into(), self.label(&args[0]));
+            }
+            _ => {}
+        }
+
+        let mut size = None;
+        let mut operands: Vec<String> = Vec::new();
+        for arg in args {
+            let (arg_size, arg) = match arg.split_once(' ') {
+                Some((word @ ("byte" | "word" | "dword" | "qword"), rest))
+                    if rest.starts_with('[') =>
+                {
+                    (Some(word), rest.trim())
+                }
+                _ => (None, arg.as_str()),
+            };
+            size = size.or(arg_size);
+            operands.push(self.operand(arg));
+        }
+        let suffix = |size| match size {
+            "byte" => "b",
+            "word" => "w",
+            "dword" => "l",
+            _ => "q",
+        };
+
+        let op = match (op, size) {
+            // Zero extending moves name the size they extend from and to
+            ("movzx", Some(from)) => {
+                let to = match args[0].starts_with('r') && !args[0].ends_with('d') {
+                    true => "q",
+                    false => "l",
+                };
+                format!("movz{}{}", suffix(from), to)
+            }
+            // A label loaded into a register is its full 64 bit address
+            ("mov", None) if operands[1].starts_with("$") && is_symbol(&args[1]) => {
+                "movabs".to_string()
+            }
+            (op, Some(size)) => format!("{}{}", op, suffix(size)),
+            (op, None) => op.to_string(),
+        };
+        operands.reverse();
+        (op, operands.join(", "))
+    }
+
+    fn operand(&self, arg: &str) -> String {
+        if let Some(address) = arg.strip_prefix('[').and_then(|arg| arg.strip_suffix(']')) {
+            return self.memory(address);
+        }
+        if family(arg).is_some() {
+            return format!("%{}", arg);
+        }
+        format!("${}", self.label(arg))
+    }
+
+    /// A memory operand, from the inside of nasm's brackets.
+    fn memory(&self, address: &str) -> String {
+        let (relative, address) = match address.strip_prefix("rel ") {
+            Some(address) => (true, address),
+            None => (self.rip_relative, address),
+        };
+        let mut base = None;
+        let mut index = None;
+        let mut displacement = String::new();
+        let mut sign = '+';
+        for token in address.split_inclusive(['+', '-']) {
+            let term = token.trim_end_matches(['+', '-']).trim();
+            if !term.is_empty() {
+                match term.split_once('*') {
+                    Some((reg, scale)) => index = Some(format!("%{},{}", reg.trim(), scale.trim())),
+                    None if family(term).is_some() => match base {
+                        None => base = Some(format!("%{}", term)),
+                        Some(_) => index = Some(format!("%{}", term)),
+                    },
+                    None => {
+                        if sign == '-' || !displacement.is_empty() {
+                            displacement.push(sign);
+                        }
+                        displacement += &self.label(term);
+                    }
+                }
+            }
+            sign = token
+                .chars()
+                .last()
+                .filter(|c| "+-".contains(*c))
+                .unwrap_or('+');
+        }
+        match (base, index) {
+            (None, None) if relative && is_symbol(&displacement) => {
+                format!("{}(%rip)", displacement)
+            }
+            (None, None) => displacement,
+            (Some(base), None) => format!("{}({})", displacement, base),
+            (base, Some(index)) => {
+                format!("{}({},{})", displacement, base.unwrap_or_default(), index)
+            }
+        }
+    }
+}
+
+/// Whether `arg` names a symbol, rather than being a number or register.
+fn is_symbol(arg: &str) -> bool {
+    family(arg).is_none() && arg.parse::<i64>().is_err() && !arg.starts_with("0x")
+}
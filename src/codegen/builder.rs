@@ -1,3 +1,9 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use super::att::Att;
+use super::cache::StackCache;
+use super::peephole::Line;
 use crate::{asm, asm_line, label};
 
 #[derive(Debug, Clone)]
@@ -5,16 +11,53 @@ pub struct Builder {
     bss: Segment,
     text: Segment,
     data: Segment,
+    /// Read-only, so writes to string literals fault
+    rodata: Segment,
     const_str_counter: usize,
+    /// Ids of the strings already in the data segment, by contents
+    const_strs: HashMap<String, usize>,
     pub insert_segment: SegmentKind,
-    pub insert_point: InsertPoint,
     pub tmp_here: String,
+    /// Rewrites pushes and pops at the end of the text segment while set
+    cache: Option<StackCache>,
+    syntax: Syntax,
+    /// Take the addresses of labels relative to rip, for formats that can't
+    /// relocate absolute ones
+    pub rip_relative: bool,
+}
+
+/// The assembler the output is written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    Nasm,
+    /// GNU as, with the comment marker of the target
+    Gas {
+        comment: &'static str,
+    },
+    /// GNU as in AT&T syntax, translated from nasm when it's finalized, with
+    /// the directive for read-only data of the object format
+    Att {
+        rodata: &'static str,
+    },
+    /// A WebAssembly text module, written to the text segment alone
+    Wat,
+    /// LLVM IR, with globals in the data segment ahead of the functions in
+    /// the text segment
+    Llvm,
+    /// C, laid out like LLVM IR
+    C,
 }
 
+/// The lines of a segment, kept until the whole program is generated:
+/// `peephole`, `route_syscalls` and headers all change lines that were added
+/// long before. Only writing them out is streamed, see `Builder::write`.
 #[derive(Debug, Clone)]
 pub struct Segment {
     pub lines: Vec<String>,
     pub has_header: bool,
+    /// Lines to write before the line at each index, in the order they go, for
+    /// what's only known once the code after them is generated
+    headers: Vec<(usize, String)>,
 }
 
 impl Segment {
@@ -22,26 +65,51 @@ impl Segment {
         Self {
             lines: Vec::new(),
             has_header: false,
+            headers: Vec::new(),
         }
     }
-    pub fn insert(&mut self, idx: usize, line: String) {
-        self.lines.insert(idx, line);
-    }
 
     pub fn push(&mut self, line: String) {
         self.lines.push(line);
     }
 
-    pub fn join(&self, sep: &str) -> String {
-        self.lines.join(sep)
+    /// Write out every line as `line` translates it, with the headers where
+    /// they go.
+    fn write(
+        &self,
+        out: &mut impl Write,
+        mut line: impl FnMut(&str) -> Option<String>,
+    ) -> io::Result<()> {
+        let mut headers = self.headers.iter().peekable();
+        for (idx, text) in self.lines.iter().enumerate() {
+            while let Some((_, header)) = headers.next_if(|(at, _)| *at == idx) {
+                writeln!(out, "{}", header)?;
+            }
+            if let Some(text) = line(text) {
+                writeln!(out, "{}", text)?;
+            }
+        }
+        for (_, header) in headers {
+            writeln!(out, "{}", header)?;
+        }
+        Ok(())
     }
 }
 
+/// A place in a segment that lines can still be added at once the ones after
+/// it have been, with `Builder::insert_header`.
 #[derive(Debug, Clone, Copy)]
+pub struct Header {
+    segment: SegmentKind,
+    at: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SegmentKind {
     Bss,
     Text,
     Data,
+    Rodata,
 }
 
 impl Builder {
@@ -50,17 +118,61 @@ impl Builder {
             bss: Segment::new(),
             text: Segment::new(),
             data: Segment::new(),
+            rodata: Segment::new(),
             insert_segment: SegmentKind::Bss,
-            insert_point: InsertPoint::End,
             const_str_counter: 0,
+            const_strs: HashMap::new(),
             tmp_here: String::new(),
+            cache: None,
+            syntax: Syntax::Nasm,
+            rip_relative: false,
         };
         tmp
     }
 
+    /// A builder for GNU as. Lines are still written with `;;` comments, which
+    /// become `comment` when it's finalized.
+    pub fn gas(comment: &'static str) -> Self {
+        Self {
+            syntax: Syntax::Gas { comment },
+            ..Self::new()
+        }
+    }
+
+    /// A builder for x86 that writes GNU as. Lines are written for nasm like
+    /// any other x86 builder, and translated when it's finalized.
+    pub fn att(rodata: &'static str) -> Self {
+        Self {
+            syntax: Syntax::Att { rodata },
+            ..Self::new()
+        }
+    }
+
+    pub fn wat() -> Self {
+        Self {
+            syntax: Syntax::Wat,
+            insert_segment: SegmentKind::Text,
+            ..Self::new()
+        }
+    }
+
+    pub fn llvm() -> Self {
+        Self {
+            syntax: Syntax::Llvm,
+            insert_segment: SegmentKind::Text,
+            ..Self::new()
+        }
+    }
+
+    pub fn c() -> Self {
+        Self {
+            syntax: Syntax::C,
+            ..Self::llvm()
+        }
+    }
+
     pub fn set_insert_segment(&mut self, segment: SegmentKind) {
         self.insert_segment = segment;
-        self.insert_point = InsertPoint::End;
     }
 
     pub fn get_insert_segment(&self) -> &Segment {
@@ -68,38 +180,68 @@ impl Builder {
             SegmentKind::Bss => &self.bss,
             SegmentKind::Text => &self.text,
             SegmentKind::Data => &self.data,
+            SegmentKind::Rodata => &self.rodata,
+        }
+    }
+
+    fn segment_mut(&mut self, segment: SegmentKind) -> &mut Segment {
+        match segment {
+            SegmentKind::Bss => &mut self.bss,
+            SegmentKind::Text => &mut self.text,
+            SegmentKind::Data => &mut self.data,
+            SegmentKind::Rodata => &mut self.rodata,
+        }
+    }
+
+    /// Where the next line of the insert segment goes.
+    pub fn header(&self) -> Header {
+        Header {
+            segment: self.insert_segment,
+            at: self.get_insert_segment().lines.len(),
         }
     }
 
-    pub fn set_insert_point(&mut self, ins_pt: InsertPoint) {
-        self.insert_point = ins_pt;
+    /// Write `line` at `header`, after any lines already added there.
+    pub fn insert_header(&mut self, header: Header, line: String) {
+        let segment = self.segment_mut(header.segment);
+        let idx = segment.headers.partition_point(|(at, _)| *at <= header.at);
+        segment.headers.insert(idx, (header.at, line));
+    }
+
+    /// Keep the top of the stack in registers from here on.
+    pub fn start_caching(&mut self) {
+        self.cache = Some(StackCache::default());
+    }
+
+    /// Spill whatever is cached and go back to plain pushes and pops.
+    pub fn stop_caching(&mut self) {
+        if let Some(mut cache) = self.cache.take() {
+            for line in cache.flush() {
+                self.insert(line);
+            }
+        }
     }
 
     pub fn insert(&mut self, line: String) {
-        match self.insert_point {
-            InsertPoint::Start => match self.insert_segment {
-                SegmentKind::Bss => self.bss.insert(0, line),
-                SegmentKind::Text => self.text.insert(0, line),
-                SegmentKind::Data => self.data.insert(0, line),
-            },
-            InsertPoint::End => match self.insert_segment {
-                SegmentKind::Bss => self.bss.push(line),
-                SegmentKind::Text => self.text.push(line),
-                SegmentKind::Data => self.data.push(line),
-            },
-            InsertPoint::Line(line_no) => match self.insert_segment {
-                SegmentKind::Bss => self.bss.insert(line_no, line),
-                SegmentKind::Text => self.text.insert(line_no, line),
-                SegmentKind::Data => self.data.insert(line_no, line),
-            },
+        if let (Some(cache), SegmentKind::Text) = (&mut self.cache, self.insert_segment) {
+            for line in cache.rewrite(line) {
+                self.text.push(line);
+            }
+            return;
         }
+        self.segment_mut(self.insert_segment).push(line);
     }
 
+    /// The id of a `const_str_N` holding `value`, shared by every string with
+    /// the same contents.
     pub fn new_const_str(&mut self, value: &str) -> usize {
-        let prev_ins_pt = self.insert_point;
+        if let Some(&id) = self.const_strs.get(value) {
+            return id;
+        }
+        self.const_strs
+            .insert(value.to_string(), self.const_str_counter);
         let prev_ins_seg = self.insert_segment;
-        self.set_insert_segment(SegmentKind::Data);
-        self.set_insert_point(InsertPoint::End);
+        self.set_insert_segment(SegmentKind::Rodata);
         let label = format!("const_str_{}", self.const_str_counter);
         label!(self, "{}", label);
         let bytes_str = value
@@ -108,36 +250,130 @@ impl Builder {
             .map(|x| x.to_string())
             .collect::<Vec<String>>()
             .join(", ");
-        asm!(self, ("db", "{}", bytes_str));
+        let directive = match self.syntax {
+            Syntax::Nasm | Syntax::Att { .. } => "db",
+            Syntax::Gas { .. } => ".byte",
+            Syntax::Wat => unreachable!("wasm keeps its strings in data segments"),
+            Syntax::Llvm | Syntax::C => unreachable!("LLVM and C strings are globals"),
+        };
+        asm!(self, (directive, "{}", bytes_str));
         self.const_str_counter += 1;
         self.set_insert_segment(prev_ins_seg);
-        self.set_insert_point(prev_ins_pt);
         self.const_str_counter - 1
     }
 
     pub fn count_lines(&self) -> usize {
-        // + 3 for segment headers
-        self.bss.lines.len() + self.text.lines.len() + self.data.lines.len() + 3
-    }
-
-    pub fn finalize(self) -> String {
-        let mut output = String::new();
-        output += "segment .bss\n";
-        output += &self.bss.join("\n");
-        output += "\n\n";
-        output += "segment .text\n";
-        output += &self.text.join("\n");
-        output += "\n\n";
-        output += "segment .data\n";
-        output += &self.data.join("\n");
-        output += "\n\n";
-        output
+        match self.syntax {
+            Syntax::Wat => return self.text.lines.len(),
+            Syntax::Llvm | Syntax::C => return self.data.lines.len() + self.text.lines.len(),
+            _ => {}
+        }
+        // + 4 for segment headers
+        self.bss.lines.len()
+            + self.text.lines.len()
+            + self.data.lines.len()
+            + self.rodata.lines.len()
+            + 4
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-pub enum InsertPoint {
-    Start,
-    End,
-    Line(usize),
+    /// Clean up the text segment once everything is in it.
+    pub fn peephole(&mut self) {
+        super::peephole::optimize(&mut self.text.lines);
+    }
+
+    /// Send every syscall in the text segment through `routine`, for targets
+    /// that don't take Linux syscalls and builds that watch for exits.
+    pub fn route_syscalls(&mut self, routine: &str) {
+        for line in self.text.lines.iter_mut() {
+            let parsed = Line::parse(line.clone());
+            if let Some(("syscall", args)) = parsed.op() {
+                if args.is_empty() {
+                    *line = Line::inst("call", &[routine], parsed.comment()).text();
+                }
+            }
+        }
+    }
+
+    /// Mark the lines after this as the code for `line` of `file`, for debug
+    /// info. It's written as nasm's `%line`, and translated for the others.
+    pub fn loc(&mut self, file: &str, line: usize) {
+        self.insert(format!("%line {}+0 {}", line, file));
+    }
+
+    /// Load the address of `label` into `reg`.
+    pub fn load_address(&mut self, reg: &str, label: &str) {
+        if self.rip_relative {
+            asm!(self, ("lea", "{}", format!("{}, [rel {}]", reg, label)));
+        } else {
+            asm!(self, ("mov", "{}", format!("{}, {}", reg, label)));
+        }
+    }
+
+    /// Push the address of `label`, through rax if it has to be computed.
+    pub fn push_address(&mut self, label: &str) {
+        if self.rip_relative {
+            self.load_address("rax", label);
+            asm!(self, ("push", "rax"));
+        } else {
+            asm!(self, ("push", "{}", label));
+        }
+    }
+
+    /// The lines of each segment, in the order they're written out.
+    pub fn segments(&self) -> [(SegmentKind, &[String]); 4] {
+        [
+            (SegmentKind::Bss, &self.bss.lines),
+            (SegmentKind::Text, &self.text.lines),
+            (SegmentKind::Data, &self.data.lines),
+            (SegmentKind::Rodata, &self.rodata.lines),
+        ]
+    }
+
+    /// Write the program to `out` a line at a time, so it's never all in one
+    /// string. The lines themselves are all in memory until then.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        let as_is = |line: &str| Some(line.to_string());
+        let (headers, comment) = match self.syntax {
+            Syntax::Nasm => (
+                [
+                    "segment .bss",
+                    "segment .text",
+                    "segment .data",
+                    "segment .rodata",
+                ],
+                None,
+            ),
+            Syntax::Gas { comment } => (
+                [".bss", ".text", ".data", ".section .rodata"],
+                Some(comment),
+            ),
+            Syntax::Att { rodata } => ([".bss", ".text", ".data", rodata], None),
+            Syntax::Wat => return self.text.write(out, as_is),
+            Syntax::Llvm | Syntax::C => {
+                self.data.write(out, as_is)?;
+                return self.text.write(out, as_is);
+            }
+        };
+        let mut att = match self.syntax {
+            Syntax::Att { .. } => Some(Att::new(self.rip_relative)),
+            _ => None,
+        };
+        if self.rip_relative && self.syntax == Syntax::Nasm {
+            writeln!(out, "default rel")?;
+        }
+        for (header, segment) in
+            headers
+                .iter()
+                .zip([&self.bss, &self.text, &self.data, &self.rodata])
+        {
+            writeln!(out, "{}", header)?;
+            segment.write(out, |line| match (&mut att, comment) {
+                (Some(att), _) => att.line(line),
+                (None, Some(comment)) => Some(line.replacen(";;", comment, 1)),
+                (None, None) => Some(line.to_string()),
+            })?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
 }
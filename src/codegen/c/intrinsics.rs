@@ -0,0 +1,43 @@
+use crate::codegen::intrinsics::Intrinsic;
+
+use super::C;
+
+pub fn compile(c: &mut C, intrinsic: &Intrinsic) {
+    match intrinsic {
+        Intrinsic::Print => c.line("worth_print(POP());"),
+        Intrinsic::Panic => c.line("worth_syscall(60, 1, 0, 0, 0, 0, 0);"),
+        Intrinsic::Dup => c.line("a = POP(); PUSH(a); PUSH(a);"),
+        Intrinsic::Dup2 => c.line("b = POP(); a = POP(); PUSH(a); PUSH(b); PUSH(a); PUSH(b);"),
+        Intrinsic::Swap => c.line("b = POP(); a = POP(); PUSH(b); PUSH(a);"),
+        Intrinsic::Over => c.line("b = POP(); a = POP(); PUSH(a); PUSH(b); PUSH(a);"),
+        Intrinsic::Drop => c.line("sp -= 1;"),
+        Intrinsic::Drop2 => c.line("sp -= 2;"),
+        Intrinsic::Mem => c.line("PUSH((uintptr_t)mem);"),
+        Intrinsic::Argc => c.line("PUSH(argc);"),
+        Intrinsic::Argv => c.line("PUSH((uintptr_t)argv);"),
+        Intrinsic::CastPtr => c.comment("-- Cast to Pointer --"),
+        Intrinsic::CastInt => c.comment("-- Cast to Int --"),
+        Intrinsic::Align => c.line("b = POP(); a = POP(); PUSH((a + b - 1) & -b);"),
+        Intrinsic::Here => {
+            let here = c.asm.tmp_here.clone();
+            c.comment(format!("-- {} --", here));
+        }
+    }
+}
+
+/// `worth_print`, which prints a number as unsigned and a newline.
+pub fn gen_print(c: &mut C) {
+    c.top(&["", "static void worth_print(uint64_t value) {"]);
+    // Digits are written backwards from the end of the buffer
+    c.lines(&[
+        "char buf[32];",
+        "char *p = buf + sizeof(buf);",
+        "*--p = '\\n';",
+        "do {",
+        "    *--p = '0' + value % 10;",
+        "    value /= 10;",
+        "} while (value);",
+        "worth_syscall(1, 1, (uintptr_t)p, buf + sizeof(buf) - p, 0, 0, 0);",
+    ]);
+    c.top(&["}"]);
+}
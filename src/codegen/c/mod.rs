@@ -0,0 +1,382 @@
+//! Code generation for C, so programs build anywhere there's a C compiler.
+//!
+//! The porth stack is an array with `sp` pointing past the top value, and every
+//! instruction is a line of `main`, with labels where the x86 backend puts them
+//! and jumps as `goto`s. Syscalls go straight to the kernel on x86-64 Linux, and
+//! through the same table as the arm backend on the other Linux ports. Anywhere
+//! else only the common calls work, on top of POSIX, and errors are the host's
+//! errno values. Pointers are stored as they are, so the host has to be 64 bit.
+
+mod intrinsics;
+mod ops;
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use super::aarch64::{self, AT_FDCWD, FORK, STACK_CAPACITY, UNSUPPORTED};
+use super::builder::{Builder, SegmentKind};
+use super::labels::Labels;
+use super::source::Source;
+use crate::{
+    cli::Target,
+    err,
+    error::{CompileError::*, Error::CompileError},
+    instruction::*,
+};
+
+/// The program being written.
+pub struct C {
+    asm: Builder,
+    /// Ids of the string constants, by contents
+    strings: HashMap<String, usize>,
+    labels: Labels,
+}
+
+impl C {
+    fn line(&mut self, text: impl AsRef<str>) {
+        self.asm.insert(format!("    {}", text.as_ref()));
+    }
+
+    fn lines(&mut self, lines: &[&str]) {
+        for line in lines {
+            self.line(line);
+        }
+    }
+
+    fn comment(&mut self, text: impl AsRef<str>) {
+        self.line(format!("/* {} */", text.as_ref()))
+    }
+
+    fn label(&mut self, ip: usize) {
+        let label = format!("{}:;", self.labels.get(ip));
+        self.asm.insert(label);
+    }
+
+    /// Write lines outside of any function.
+    fn top(&mut self, lines: &[&str]) {
+        for line in lines {
+            self.asm.insert(line.to_string());
+        }
+    }
+
+    /// The name of the array holding `s`.
+    fn string(&mut self, s: &str) -> String {
+        if let Some(id) = self.strings.get(s) {
+            return format!("str_{}", id);
+        }
+        let id = self.strings.len();
+        self.strings.insert(s.to_string(), id);
+        self.asm.set_insert_segment(SegmentKind::Data);
+        self.asm.insert(format!(
+            "static const char str_{}[] = \"{}\";",
+            id,
+            escape(s.as_bytes())
+        ));
+        self.asm.set_insert_segment(SegmentKind::Text);
+        format!("str_{}", id)
+    }
+}
+
+pub fn generate(
+    program: &Program,
+    target: Target,
+    mem_capacity: usize,
+    mut source: Option<Source>,
+) -> Result<Builder> {
+    if target == Target::Wasm32 {
+        return Err(CompileError(UnsupportedTarget(target.to_string())))
+            .with_context(|| "The C backend needs a 64 bit target with a C library");
+    }
+
+    let mut c = C {
+        asm: Builder::c(),
+        strings: HashMap::new(),
+        labels: Labels::new(program),
+    };
+    c.asm.set_insert_segment(SegmentKind::Data);
+    c.top(&[
+        "/* -- generated by the worth compiler -- */",
+        "#define _GNU_SOURCE",
+        "#if defined(__linux__) && defined(__x86_64__)",
+        "#define LINUX_SYSCALLS 1",
+        "#elif defined(__linux__) && (defined(__aarch64__) || defined(__riscv))",
+        "#define GENERIC_SYSCALLS 1",
+        "#endif",
+        "#include <errno.h>",
+        "#include <fcntl.h>",
+        "#include <stdint.h>",
+        "#include <string.h>",
+        "#include <unistd.h>",
+        "#if !LINUX_SYSCALLS && !GENERIC_SYSCALLS && !defined(_WIN32)",
+        "#include <sys/stat.h>",
+        "#include <sys/wait.h>",
+        "#endif",
+        "",
+        "#define PUSH(x) (*sp++ = (uint64_t)(x))",
+        "#define POP() (*--sp)",
+        "",
+        &format!(
+            "static _Alignas({}) uint8_t mem[{}];",
+            MEM_ALIGN, mem_capacity
+        ),
+        &format!("static uint64_t stack[{}];", STACK_CAPACITY / 8),
+    ]);
+    for memory in &program.memories {
+        c.top(&[&format!(
+            "static _Alignas({}) uint8_t {}[{}];",
+            MEM_ALIGN,
+            memory.label(),
+            memory.reserved()
+        )]);
+    }
+    c.asm.set_insert_segment(SegmentKind::Text);
+
+    gen_syscall(&mut c);
+    intrinsics::gen_print(&mut c);
+
+    c.top(&["", "int main(int argc, char **argv) {"]);
+    c.lines(&[
+        "uint64_t *sp = stack;",
+        "uint64_t n, a, b, c, d, e, f;",
+        "(void)argc, (void)argv, (void)mem, (void)worth_print;",
+        "(void)n, (void)a, (void)b, (void)c, (void)d, (void)e, (void)f;",
+    ]);
+    for (ip, inst) in program.instructions.iter().enumerate() {
+        c.asm.tmp_here =
+            inst.loc.0.clone() + ":" + &inst.loc.1.to_string() + ":" + &inst.loc.2.to_string();
+        if let Some(line) = source.as_mut().and_then(|source| source.line(&inst.loc)) {
+            // The source could close the comment
+            c.comment(line.replace("*/", "* /"));
+        }
+        match &inst.kind {
+            InstructionKind::Push(val) => match val {
+                Value::Int(i) => c.line(format!("PUSH({});", literal(*i))),
+                Value::Bool(b) => c.line(format!("PUSH({});", *b as i64)),
+                Value::Char(ch) => c.line(format!("PUSH({});", *ch as i64)),
+                Value::Ptr(name) => err!(
+                    program,
+                    CompileError(UnexpectedToken(name.clone())),
+                    format!("Pointer to {} should be resolved before codegen", name),
+                    ip
+                ),
+                Value::Str(s) => {
+                    let name = c.string(s);
+                    c.line(format!("PUSH({});", s.len()));
+                    c.line(format!("PUSH((uintptr_t){});", name));
+                }
+            },
+            InstructionKind::Intrinsic(intrinsic) => {
+                c.comment(format!(
+                    "-- intrinsic: {} --",
+                    intrinsic.to_string().to_lowercase()
+                ));
+                intrinsics::compile(&mut c, intrinsic);
+            }
+            InstructionKind::Keyword(Keyword::While { self_ip, .. }) => {
+                c.comment("-- while --");
+                c.label(*self_ip);
+            }
+            InstructionKind::Keyword(Keyword::Do { end_ip }) => {
+                c.comment("-- do --");
+                c.line(format!("if (!POP()) goto {};", c.labels.get(*end_ip)));
+            }
+            InstructionKind::Keyword(Keyword::If) => c.comment("-- if --"),
+            InstructionKind::Keyword(Keyword::Unsafe) => c.comment("-- unsafe --"),
+            InstructionKind::Keyword(Keyword::Elif {
+                self_ip,
+                end_ip: else_ip,
+            }) => {
+                c.comment("-- elif --");
+                c.line(format!("goto {};", c.labels.get(*else_ip)));
+                c.label(*self_ip);
+            }
+            InstructionKind::Keyword(Keyword::Else {
+                self_ip: else_ip,
+                end_ip,
+            }) => {
+                c.comment("-- else --");
+                c.line(format!("goto {};", c.labels.get(*end_ip)));
+                c.label(*else_ip);
+            }
+            InstructionKind::Keyword(Keyword::End { self_ip, while_ip }) => {
+                c.comment("-- end --");
+                if let Some(while_ip) = while_ip {
+                    c.line(format!("goto {};", c.labels.get(*while_ip)));
+                }
+                c.label(*self_ip);
+            }
+            InstructionKind::Op(op) => ops::compile(&mut c, op),
+            InstructionKind::Syscall(kind) => ops::syscall(&mut c, syscall_args(kind)),
+            InstructionKind::Keyword(Keyword::Include) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("include".into())),
+                    "Include should be expanded before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Macro) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("macro".into())),
+                    "Macro should be expanded before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Extern) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("extern".into())),
+                    "Extern should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Memory) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("memory".into())),
+                    "Memory should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Memory(memory) => {
+                c.line(format!("PUSH((uintptr_t){});", memory.label()));
+            }
+            InstructionKind::Extern(_) => {
+                unreachable!("compile only lets externs through for native x86_64-linux")
+            }
+            InstructionKind::Name(name) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken(name.clone())),
+                    format!("Name {} should be resolved before codegen", name),
+                    ip
+                )
+            }
+        }
+    }
+    c.lines(&["worth_syscall(60, 0, 0, 0, 0, 0, 0);", "return 0;"]);
+    c.top(&["}"]);
+
+    Ok(c.asm)
+}
+
+/// `i` as a C constant, which can't be negative.
+fn literal(i: i64) -> String {
+    if i < 0 {
+        format!("-{}ULL", i.unsigned_abs())
+    } else {
+        i.to_string()
+    }
+}
+
+/// `bytes` as the inside of a C string. Everything but plain characters is an
+/// octal escape, which, unlike hex ones, can't run into the next character.
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'"' | b'\\' | b'?' => format!("\\{:03o}", b),
+            b' '..=b'~' => (b as char).to_string(),
+            _ => format!("\\{:03o}", b),
+        })
+        .collect()
+}
+
+fn syscall_args(kind: &SyscallKind) -> usize {
+    match kind {
+        SyscallKind::Syscall0 => 0,
+        SyscallKind::Syscall1 => 1,
+        SyscallKind::Syscall2 => 2,
+        SyscallKind::Syscall3 => 3,
+        SyscallKind::Syscall4 => 4,
+        SyscallKind::Syscall5 => 5,
+        SyscallKind::Syscall6 => 6,
+    }
+}
+
+/// `worth_syscall`, which takes an x86-64 Linux syscall number and six
+/// arguments, and returns the result or a negated errno like the kernel does.
+fn gen_syscall(c: &mut C) {
+    let table = aarch64::syscall_table();
+    let entries: Vec<String> = table.iter().map(u16::to_string).collect();
+    c.top(&[
+        "",
+        "#if GENERIC_SYSCALLS",
+        "static const uint16_t syscall_table[] = {",
+    ]);
+    for chunk in entries.chunks(16) {
+        c.line(format!("{},", chunk.join(", ")));
+    }
+    c.top(&[
+        "};",
+        "#elif !LINUX_SYSCALLS",
+        "/* Linux open flags as the host's */",
+    ]);
+    c.top(&["static int open_flags(uint64_t flags) {"]);
+    c.lines(&[
+        "int result = (flags & 3) == 1 ? O_WRONLY : (flags & 3) == 2 ? O_RDWR : O_RDONLY;",
+        "if (flags & 0100) result |= O_CREAT;",
+        "if (flags & 0200) result |= O_EXCL;",
+        "if (flags & 01000) result |= O_TRUNC;",
+        "if (flags & 02000) result |= O_APPEND;",
+        "#ifdef _WIN32",
+        "result |= O_BINARY;",
+        "#endif",
+        "return result;",
+    ]);
+    c.top(&["}", "#endif", ""]);
+
+    c.top(&["static uint64_t worth_syscall(uint64_t n, uint64_t a, uint64_t b, uint64_t c, uint64_t d, uint64_t e, uint64_t f) {"]);
+    c.lines(&[
+        "long result;",
+        "#if LINUX_SYSCALLS",
+        "result = syscall(n, a, b, c, d, e, f);",
+        "#elif GENERIC_SYSCALLS",
+        &format!(
+            "uint16_t entry = n < sizeof(syscall_table) / sizeof(*syscall_table) ? syscall_table[n] : {};",
+            UNSUPPORTED
+        ),
+        &format!("if (entry == {}) return -ENOSYS;", UNSUPPORTED),
+        &format!("if (entry & {}) {{", AT_FDCWD),
+        "    f = e, e = d, d = c, c = b, b = a, a = AT_FDCWD;",
+        "}",
+        "/* SIGCHLD, which is what fork sends the parent */",
+        &format!("if (entry & {}) a = 17;", FORK),
+        &format!("result = syscall(entry & {}, a, b, c, d, e, f);", FORK - 1),
+        "#else",
+        "(void)d, (void)e, (void)f;",
+        "switch (n) {",
+        "case 0: result = read(a, (void *)(uintptr_t)b, c); break;",
+        "case 1: result = write(a, (const void *)(uintptr_t)b, c); break;",
+        "case 2: result = open((const char *)(uintptr_t)a, open_flags(b), (int)c); break;",
+        "case 3: result = close(a); break;",
+        "case 8: result = lseek(a, b, c); break;",
+        "case 32: result = dup(a); break;",
+        "case 33: result = dup2(a, b); break;",
+        "case 39: result = getpid(); break;",
+        "case 60: case 231: _exit(a);",
+        "case 84: result = rmdir((const char *)(uintptr_t)a); break;",
+        "case 87: result = unlink((const char *)(uintptr_t)a); break;",
+        "#ifndef _WIN32",
+        "case 257:",
+        "    result = openat((int)a == -100 ? AT_FDCWD : (int)a, (const char *)(uintptr_t)b, open_flags(c), (int)d);",
+        "    break;",
+        "case 22: result = pipe((int *)(uintptr_t)a); break;",
+        "case 57: result = fork(); break;",
+        "case 59: result = execve((const char *)(uintptr_t)a, (char *const *)(uintptr_t)b, (char *const *)(uintptr_t)c); break;",
+        "case 61: result = waitpid(a, (int *)(uintptr_t)b, c); break;",
+        "case 83: result = mkdir((const char *)(uintptr_t)a, b); break;",
+        "case 102: result = getuid(); break;",
+        "case 104: result = getgid(); break;",
+        "case 107: result = geteuid(); break;",
+        "case 110: result = getppid(); break;",
+        "#endif",
+        "default: return -ENOSYS;",
+        "}",
+        "#endif",
+        "return result == -1 ? -(uint64_t)errno : (uint64_t)result;",
+    ]);
+    c.top(&["}"]);
+}
@@ -0,0 +1,81 @@
+use crate::instruction::Op;
+
+use super::C;
+
+/// Pop `b` then `a`, and push `expr`, which uses them.
+fn binary(c: &mut C, expr: &str) {
+    c.line(format!("b = POP(); a = POP(); PUSH({});", expr));
+}
+
+pub fn compile(c: &mut C, op: &Op) {
+    c.comment(format!("-- {} --", name(op)));
+    match op {
+        Op::Add => binary(c, "a + b"),
+        Op::Sub => binary(c, "a - b"),
+        Op::Mul => binary(c, "a * b"),
+        Op::Div => binary(c, "a / b"),
+        Op::Mod => binary(c, "a % b"),
+        Op::DivMod => c.line("b = POP(); a = POP(); PUSH(a / b); PUSH(a % b);"),
+        Op::BitwiseAnd => binary(c, "a & b"),
+        Op::BitwiseOr => binary(c, "a | b"),
+        Op::BitwiseXor => binary(c, "a ^ b"),
+        Op::BitwiseNot => c.line("a = POP(); PUSH(~a);"),
+        // The count is masked to 6 bits like on x86, since C leaves bigger shifts undefined
+        Op::Shl => binary(c, "a << (b & 63)"),
+        Op::Shr => binary(c, "a >> (b & 63)"),
+        Op::Eq => binary(c, "a == b"),
+        Op::Neq => binary(c, "a != b"),
+        Op::Lt => binary(c, "(int64_t)a < (int64_t)b"),
+        Op::Gt => binary(c, "(int64_t)a > (int64_t)b"),
+        Op::Lte => binary(c, "(int64_t)a <= (int64_t)b"),
+        Op::Gte => binary(c, "(int64_t)a >= (int64_t)b"),
+        Op::Load => c.line("a = POP(); PUSH(*(uint8_t *)(uintptr_t)a);"),
+        Op::Load64 => c.line("a = POP(); memcpy(&b, (void *)(uintptr_t)a, 8); PUSH(b);"),
+        Op::Store => c.line("b = POP(); a = POP(); *(uint8_t *)(uintptr_t)a = (uint8_t)b;"),
+        Op::Store64 => c.line("b = POP(); a = POP(); memcpy((void *)(uintptr_t)a, &b, 8);"),
+    }
+}
+
+fn name(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "add",
+        Op::Sub => "sub",
+        Op::Mul => "mul",
+        Op::Div => "div",
+        Op::Mod => "mod",
+        Op::DivMod => "divmod",
+        Op::BitwiseAnd => "and",
+        Op::BitwiseOr => "or",
+        Op::BitwiseXor => "xor",
+        Op::BitwiseNot => "not",
+        Op::Shl => "shl",
+        Op::Shr => "shr",
+        Op::Eq => "eq",
+        Op::Neq => "neq",
+        Op::Lt => "lt",
+        Op::Gt => "gt",
+        Op::Lte => "lte",
+        Op::Gte => "gte",
+        Op::Load => "load",
+        Op::Store => "store",
+        Op::Load64 => "load64",
+        Op::Store64 => "store64",
+    }
+}
+
+/// Pop the syscall number and `args` arguments, and push the result.
+pub fn syscall(c: &mut C, args: usize) {
+    c.comment(format!("-- syscall{} --", args));
+    let mut line = "n = POP();".to_string();
+    let mut values = vec!["n".to_string()];
+    for (arg, name) in ["a", "b", "c", "d", "e", "f"].iter().enumerate() {
+        if arg < args {
+            line += &format!(" {} = POP();", name);
+            values.push(name.to_string());
+        } else {
+            values.push("0".to_string());
+        }
+    }
+    line += &format!(" PUSH(worth_syscall({}));", values.join(", "));
+    c.line(line);
+}
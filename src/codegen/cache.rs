@@ -0,0 +1,104 @@
+//! Keeping the top of the stack in registers.
+//!
+//! Ops are still generated as pushes and pops, and the cache rewrites them as
+//! they're inserted: a push puts its value in `r12` or `r13`, or just remembers
+//! it if it's a constant, and a pop takes the newest one back out. Only the
+//! oldest slot goes to the real stack when a third is pushed. Everything is
+//! spilled before labels and jumps so the stack looks the same however code is
+//! reached.
+
+use super::peephole::{family, Line};
+
+/// Registers the rest of the codegen never touches.
+const REGISTERS: [&str; 2] = ["r12", "r13"];
+
+#[derive(Debug, Clone)]
+enum Slot {
+    /// A constant or label, not in any register yet
+    Const(String),
+    Reg(&'static str),
+}
+
+impl Slot {
+    fn arg(&self) -> &str {
+        match self {
+            Slot::Const(value) => value,
+            Slot::Reg(reg) => reg,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StackCache {
+    /// Oldest first
+    slots: Vec<Slot>,
+}
+
+impl StackCache {
+    /// The lines to emit in place of `line`.
+    pub fn rewrite(&mut self, line: String) -> Vec<String> {
+        let parsed = Line::parse(line.clone());
+        let mut out = Vec::new();
+        let Some((op, args)) = parsed.op() else {
+            // Labels are where jumps land
+            if matches!(parsed, Line::Other(_)) {
+                out.extend(self.flush());
+            }
+            out.push(line);
+            return out;
+        };
+        match (op, &args[..]) {
+            ("push", &[arg]) => {
+                if self.slots.len() == REGISTERS.len() {
+                    let oldest = self.slots.remove(0);
+                    out.push(Line::inst("push", &[oldest.arg()], None).text());
+                }
+                // Constants stay constants if they can still be pushed when spilled
+                let constant = match arg.parse::<i64>() {
+                    Ok(n) => i32::try_from(n).is_ok(),
+                    Err(_) => family(arg).is_none() && !arg.contains('['),
+                };
+                if constant {
+                    self.slots.push(Slot::Const(arg.to_string()));
+                } else {
+                    let reg = self.free();
+                    out.push(Line::inst("mov", &[reg, arg], parsed.comment()).text());
+                    self.slots.push(Slot::Reg(reg));
+                }
+            }
+            ("pop", &[reg]) if family(reg).is_some() && !self.slots.is_empty() => {
+                let slot = self.slots.pop().unwrap();
+                out.push(Line::inst("mov", &[reg, slot.arg()], parsed.comment()).text());
+            }
+            _ if op.starts_with('j')
+                || matches!(op, "ret" | "pop")
+                || args.iter().any(|arg| arg.contains("rsp")) =>
+            {
+                out.extend(self.flush());
+                out.push(line);
+            }
+            _ => out.push(line),
+        }
+        out
+    }
+
+    /// Push everything still in registers.
+    pub fn flush(&mut self) -> Vec<String> {
+        self.slots
+            .drain(..)
+            .map(|slot| Line::inst("push", &[slot.arg()], None).text())
+            .collect()
+    }
+
+    fn free(&self) -> &'static str {
+        REGISTERS
+            .into_iter()
+            .find(|reg| {
+                !self
+                    .slots
+                    .iter()
+                    .any(|slot| matches!(slot, Slot::Reg(used) if used == reg))
+            })
+            .expect("a register is free after spilling")
+    }
+}
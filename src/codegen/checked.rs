@@ -0,0 +1,317 @@
+//! The checks `--checked` builds make while they run.
+//!
+//! Before each instruction the data stack is checked for the values it pops and
+//! room for the ones it pushes, against `stack_base`, where rsp started, and
+//! `stack_limit` below it. mem and the memories after it are fenced in by
+//! `mem_guard_lo` and `mem_guard_hi`, which hold a canary that's checked after
+//! every instruction that can write to memory, and divisors are checked for zero
+//! before they're divided by. A failed check prints where the instruction came
+//! from to stderr and exits with 1.
+//!
+//! `--check-bounds` builds call `bounds_check` before every load and store
+//! instead, which like the simulator rejects null pointers and accesses that are
+//! only partly in mem, and also anything that touches what the compiler keeps in
+//! bss after it, up to `bss_end`.
+
+use super::builder::Builder;
+use super::intrinsics::Intrinsic;
+use crate::{
+    asm, asm_line,
+    cli::Target,
+    comment,
+    error::err_loc,
+    instruction::{Instruction, InstructionKind, Keyword, Op, SyscallKind, Value},
+    label, sys_exit, syscall,
+};
+
+/// What the guards around mem hold while nothing has written over them
+const CANARY: u64 = 0x5741_5243_4845_434b;
+
+/// How many bytes the data stack can hold, well inside the stack the main
+/// thread gets: 8MiB on Linux and macOS, 1MiB on Windows.
+pub fn stack_capacity(target: Target) -> usize {
+    match target {
+        Target::X86_64Windows => 512 * 1024,
+        _ => 4 * 1024 * 1024,
+    }
+}
+
+/// How many values `kind` pops, and how many it pushes after.
+fn effect(kind: &InstructionKind) -> (usize, usize) {
+    match kind {
+        InstructionKind::Push(Value::Str(_)) => (0, 2),
+        InstructionKind::Push(_) | InstructionKind::Memory(_) => (0, 1),
+        InstructionKind::Intrinsic(intrinsic) => match intrinsic {
+            Intrinsic::Print | Intrinsic::Drop => (1, 0),
+            Intrinsic::Drop2 => (2, 0),
+            Intrinsic::Dup => (1, 2),
+            Intrinsic::Dup2 => (2, 4),
+            Intrinsic::Swap => (2, 2),
+            Intrinsic::Align => (2, 1),
+            Intrinsic::Over => (2, 3),
+            Intrinsic::CastPtr | Intrinsic::CastInt => (1, 1),
+            Intrinsic::Mem | Intrinsic::Argc | Intrinsic::Argv => (0, 1),
+            Intrinsic::Panic | Intrinsic::Here => (0, 0),
+        },
+        InstructionKind::Op(Op::Load | Op::Load64 | Op::BitwiseNot) => (1, 1),
+        InstructionKind::Op(Op::Store | Op::Store64) => (2, 0),
+        InstructionKind::Op(Op::DivMod) => (2, 2),
+        InstructionKind::Op(_) => (2, 1),
+        InstructionKind::Syscall(kind) => {
+            let args = match kind {
+                SyscallKind::Syscall0 => 0,
+                SyscallKind::Syscall1 => 1,
+                SyscallKind::Syscall2 => 2,
+                SyscallKind::Syscall3 => 3,
+                SyscallKind::Syscall4 => 4,
+                SyscallKind::Syscall5 => 5,
+                SyscallKind::Syscall6 => 6,
+            };
+            // The syscall number, then its arguments
+            (args + 1, 1)
+        }
+        InstructionKind::Extern(declaration) => (declaration.args, declaration.returns as usize),
+        InstructionKind::Keyword(Keyword::Do { .. }) => (1, 0),
+        _ => (0, 0),
+    }
+}
+
+/// Whether `kind` can write to memory, so the guards are checked after it.
+fn writes(kind: &InstructionKind) -> bool {
+    matches!(
+        kind,
+        InstructionKind::Op(Op::Store | Op::Store64)
+            | InstructionKind::Syscall(_)
+            | InstructionKind::Extern(_)
+    )
+}
+
+/// The smallest address anything is mapped at
+const NULL_PAGE: usize = 4096;
+
+/// The width and kind of the access `kind` makes, and which of the values on
+/// the stack is its address.
+fn access(kind: &InstructionKind) -> Option<(usize, &'static str, usize)> {
+    match kind {
+        InstructionKind::Op(Op::Load) => Some((1, "read", 0)),
+        InstructionKind::Op(Op::Load64) => Some((8, "read", 0)),
+        // Under the value
+        InstructionKind::Op(Op::Store) => Some((1, "write", 1)),
+        InstructionKind::Op(Op::Store64) => Some((8, "write", 1)),
+        _ => None,
+    }
+}
+
+/// Check the address `inst` is about to load from or store to.
+pub fn bounds(asm: &mut Builder, inst: &Instruction) {
+    let Some((width, access, depth)) = access(&inst.kind) else {
+        return;
+    };
+    let message = format!(
+        "{}: Invalid memory {} of {} bytes at ",
+        err_loc(&inst.loc),
+        access,
+        width
+    );
+    let s_id = asm.new_const_str(&message);
+    asm!(asm, ("mov", "rdi, [rsp + {}]", depth * 8));
+    asm!(asm, ("mov", "rsi, {}", width));
+    asm.load_address("rdx", &format!("const_str_{}", s_id));
+    asm!(
+        asm,
+        ("mov", "rcx, {}", message.len()),
+        ("call", "bounds_check")
+    );
+}
+
+/// `bounds_check`, which takes the address in rdi, the width in rsi, and what
+/// to print before the address if it's invalid in rdx, with its length in rcx.
+///
+/// An access of width `w` at `s` overlaps `[a, b)` when `s - (a - w + 1)` is
+/// below `b - (a - w + 1)`, unsigned, so each check is one comparison. The
+/// peephole takes registers to be dead at jumps, so nothing is set for
+/// `bounds_fail` on the way there: it uses what the caller passed.
+pub fn gen_bounds_check(asm: &mut Builder) {
+    label!(asm, "bounds_check");
+    asm!(
+        asm,
+        ("cmp", "rdi, {}", NULL_PAGE),
+        ("jb", "bounds_fail"),
+        /// w - 1
+        ("lea", "r8, [rsi - 1]")
+    );
+    // Partly in mem from before it, then partly in what comes after it
+    for (start, len) in [("mem", "r8"), ("mem_end", "r10")] {
+        asm.load_address("rax", start);
+        asm!(asm, ("sub", "rax, r8"));
+        if start == "mem_end" {
+            asm.load_address("r10", "bss_end");
+            asm!(asm, ("sub", "r10, rax"));
+        }
+        asm!(
+            asm,
+            ("mov", "r9, rdi"),
+            ("sub", "r9, rax"),
+            ("cmp", "r9, {}", len),
+            ("jb", "bounds_fail")
+        );
+    }
+    asm!(asm, ("ret"));
+
+    label!(asm, "bounds_fail");
+    asm!(
+        asm,
+        /// Syscalls leave rbx alone
+        ("mov", "rbx, rdi"),
+        ("mov", "rsi, rdx"),
+        ("mov", "rdx, rcx"),
+        ("mov", "edi, 2"),
+        ("mov", "rax, 1"),
+        ("syscall"),
+        /// "0x", 16 digits and a newline
+        ("sub", "rsp, 32"),
+        ("mov", "byte [rsp], 48"),
+        ("mov", "byte [rsp + 1], 120")
+    );
+    for digit in 0..16 {
+        asm!(
+            asm,
+            ("mov", "rax, rbx"),
+            ("shr", "rax, {}", 60 - digit * 4),
+            ("and", "rax, 15"),
+            ("lea", "rcx, [rax + 48]"),
+            /// 'a' - 10
+            ("lea", "rdx, [rax + 87]"),
+            ("cmp", "rax, 10"),
+            ("cmovae", "rcx, rdx"),
+            ("mov", "byte [rsp + {}], cl", digit + 2)
+        );
+    }
+    asm!(
+        asm,
+        ("mov", "byte [rsp + 18], 10"),
+        ("mov", "rsi, rsp"),
+        ("mov", "rdx, 19"),
+        ("mov", "edi, 2"),
+        ("mov", "rax, 1"),
+        ("syscall")
+    );
+    sys_exit!(asm, 1);
+}
+
+#[derive(Debug, Default)]
+pub struct Checks {
+    /// What each `checked_fail_{n}` prints
+    failures: Vec<String>,
+}
+
+impl Checks {
+    /// Where the stack starts and the canaries, at the very start of `_start`.
+    pub fn gen_start(&self, asm: &mut Builder, target: Target) {
+        comment!(asm, "-- checked --");
+        asm!(
+            asm,
+            ("mov", "[stack_base], rsp"),
+            ("lea", "rax, [rsp - {}]", stack_capacity(target)),
+            ("mov", "[stack_limit], rax"),
+            ("mov", "rax, {}", CANARY),
+            ("mov", "[mem_guard_lo], rax"),
+            ("mov", "[mem_guard_hi], rax")
+        );
+    }
+
+    /// Check the stack has what `inst` needs before it runs.
+    pub fn before(&mut self, asm: &mut Builder, inst: &Instruction, target: Target) {
+        let (pops, pushes) = effect(&inst.kind);
+        if pops > 0 {
+            let fail = self.failure(format!(
+                "{}: Stack underflow, {} pops {} but there are fewer on the stack",
+                err_loc(&inst.loc),
+                inst.kind,
+                pops
+            ));
+            asm!(
+                asm,
+                ("lea", "rax, [rsp + {}]", pops * 8),
+                ("cmp", "rax, [stack_base]"),
+                ("ja", "{}", fail)
+            );
+        }
+        if pushes > pops {
+            let fail = self.failure(format!(
+                "{}: Stack overflow, {} pushes past the {} bytes of the stack",
+                err_loc(&inst.loc),
+                inst.kind,
+                stack_capacity(target)
+            ));
+            asm!(
+                asm,
+                ("lea", "rax, [rsp - {}]", (pushes - pops) * 8),
+                ("cmp", "rax, [stack_limit]"),
+                ("jb", "{}", fail)
+            );
+        }
+        if let InstructionKind::Op(Op::Div | Op::Mod | Op::DivMod) = inst.kind {
+            let fail = self.failure(format!(
+                "{}: Division by zero, {} divides by 0",
+                err_loc(&inst.loc),
+                inst.kind
+            ));
+            asm!(
+                asm,
+                /// The divisor is on top
+                ("cmp", "qword [rsp], 0"),
+                ("je", "{}", fail)
+            );
+        }
+    }
+
+    /// Check `inst` didn't write past either end of mem once it has run.
+    pub fn after(&mut self, asm: &mut Builder, inst: &Instruction) {
+        if !writes(&inst.kind) {
+            return;
+        }
+        let fail = self.failure(format!(
+            "{}: {} wrote outside of mem",
+            err_loc(&inst.loc),
+            inst.kind
+        ));
+        asm!(
+            asm,
+            ("mov", "rax, {}", CANARY),
+            ("cmp", "[mem_guard_lo], rax"),
+            ("jne", "{}", fail),
+            ("cmp", "[mem_guard_hi], rax"),
+            ("jne", "{}", fail)
+        );
+    }
+
+    fn failure(&mut self, message: String) -> String {
+        self.failures.push(message + "\n");
+        format!("checked_fail_{}", self.failures.len() - 1)
+    }
+
+    /// Where the failed checks jump, each printing its message and exiting.
+    pub fn gen_failures(&self, asm: &mut Builder) {
+        for (id, message) in self.failures.iter().enumerate() {
+            label!(asm, "checked_fail_{}", id);
+            let s_id = asm.new_const_str(message);
+            asm.load_address("rsi", &format!("const_str_{}", s_id));
+            asm!(
+                asm,
+                ("mov", "rdx, {}", message.len()),
+                /// Called, since nothing is live across a jump
+                ("call", "checked_abort")
+            );
+        }
+        label!(asm, "checked_abort");
+        asm!(
+            asm,
+            /// Write the message to stderr
+            ("mov", "edi, 2"),
+            ("mov", "rax, 1"),
+            ("syscall")
+        );
+        sys_exit!(asm, 1);
+    }
+}
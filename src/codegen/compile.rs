@@ -1,10 +1,26 @@
-use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
+use super::aarch64;
+use super::assembler::{self as builtin, SectionKind};
+use super::builder::SegmentKind;
+use super::c;
+use super::checked::{self, Checks};
+use super::darwin;
+use super::instrument::Instrument;
 use super::intrinsics::gen_intrinsics;
+use super::labels::Labels;
+use super::llvm;
+use super::map;
 use super::ops;
+use super::riscv64;
+use super::source::Source;
+use super::target::{Arch, Os};
+use super::wasm32;
+use super::windows;
 use crate::{
     asm, asm_line,
-    cli::{CompilerOptions, OutputType},
+    cli::{Assembler, Backend, CompilerOptions, Linker, OutputType, Target},
     codegen::builder::Builder,
     comment, err,
     error::{
@@ -14,27 +30,537 @@ use crate::{
     instruction::*,
     label,
     log::{self, LogLevel},
-    segment, syscall,
+    segment, syscall, timings,
 };
 
 use anyhow::{Context, Result};
 
+/// How many bytes `mem` has unless `--mem-capacity` says otherwise
 pub const BSS_CAPACITY: usize = 640_000;
 
+/// How big a program's x86_64 Linux executable is, as the built-in assembler
+/// and linker make it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Size {
+    pub text: usize,
+    pub data: usize,
+    pub rodata: usize,
+    /// Only reserved when it's loaded, so not in the file
+    pub bss: usize,
+    pub file: usize,
+}
+
+/// The size of `program` built with `opt`, without writing anything.
+pub fn size(program: &Program, opt: &CompilerOptions) -> Result<Size> {
+    if opt.target != Target::X86_64Linux {
+        return Err(CompileError(UnsupportedTarget(opt.target.to_string())))
+            .with_context(|| "Sizes are only worked out for x86_64-linux");
+    }
+    let asm = x86_64(program, opt, Assembler::Builtin, None)?;
+    let object = builtin::assemble(&asm)?;
+    let mut size = Size {
+        file: object.executable("_start", opt.pie)?.len(),
+        ..Size::default()
+    };
+    for section in &object.sections {
+        match section.kind {
+            SectionKind::Segment(SegmentKind::Text) => size.text += section.size,
+            SectionKind::Segment(SegmentKind::Data) => size.data += section.size,
+            SectionKind::Segment(SegmentKind::Rodata) => size.rodata += section.size,
+            SectionKind::Segment(SegmentKind::Bss) => size.bss += section.size,
+            SectionKind::Debug(_) => {}
+        }
+    }
+    Ok(size)
+}
+
 pub fn compile(program: &Program, opt: CompilerOptions) -> Result<PathBuf> {
-    let mut asm = Builder::new();
+    let assembler = opt.assembler.unwrap_or(opt.target.default_assembler());
+    let x86 = opt.target.arch() == Arch::X86_64;
+    let linux = opt.target.os() == Os::Linux;
+    if opt.pie && !linux {
+        return Err(CompileError(UnsupportedTarget(opt.target.to_string()))).with_context(|| {
+            "Position independent executables are only built for the Linux targets"
+        });
+    }
+    if opt.link_libc && !(opt.target == Target::X86_64Linux && opt.backend == Backend::Native) {
+        return Err(CompileError(UnsupportedTarget(opt.target.to_string()))).with_context(|| {
+            "The C library is only linked for x86_64-linux with the native backend"
+        });
+    }
+    if opt.checked && !(x86 && opt.backend == Backend::Native) {
+        return Err(CompileError(UnsupportedTarget(opt.target.to_string())))
+            .with_context(|| "Checked builds are only made by the native backend for x86_64");
+    }
+    if opt.emit_map.is_some() && !(linux && opt.backend == Backend::Native) {
+        return Err(CompileError(UnsupportedTarget(opt.target.to_string())))
+            .with_context(|| "Maps are only written for the native backend's Linux executables");
+    }
+    if opt.instrument && !(opt.target == Target::X86_64Linux && opt.backend == Backend::Native) {
+        return Err(CompileError(UnsupportedTarget(opt.target.to_string()))).with_context(|| {
+            "Instrumented builds are only made by the native backend for x86_64-linux"
+        });
+    }
+    if let Some(linker) = opt.linker.filter(|&linker| !opt.target.links_with(linker)) {
+        return Err(CompileError(UnsupportedTarget(opt.target.to_string())))
+            .with_context(|| format!("{} only links ELF, for the Linux targets", linker));
+    }
+    if opt.debug_info && !(x86 && opt.backend == Backend::Native) {
+        log::log(
+            LogLevel::Warn,
+            format!(
+                "Debug info is only written by the native backend for x86_64, not for {} with {:?}",
+                opt.target, opt.backend
+            ),
+            opt.debug,
+        );
+    }
+    let out_path = out_path(program, &opt)?;
+    // What was asked for with --emit, or what the extension says. The last
+    // one built is what's returned, and any before it are kept.
+    let output_type = match out_path.extension() {
+        _ if !opt.emit.is_empty() => *opt.emit.iter().max().expect("emit isn't empty"),
+        Some(ext) => match ext
+            .to_str()
+            .ok_or(IOError(NoFileExtension))
+            .with_context(|| format!("Invalid filename: {}", out_path.to_string_lossy()))?
+        {
+            "ir" => OutputType::Ir,
+            "asm" | "wat" | "ll" | "c" => OutputType::Asm,
+            "o" => OutputType::Obj,
+            "exe" | "wasm" => OutputType::Exe,
+            _ => {
+                log::log(
+                    LogLevel::Warn,
+                    format!(
+                        "Unknown output type {}. Building an executable.",
+                        ext.to_str()
+                            .ok_or(IOError(NoFileExtension))
+                            .with_context(|| {
+                                format!("Invalid filename: {}", out_path.to_string_lossy())
+                            })?
+                    ),
+                    opt.debug,
+                );
+                OutputType::Exe
+            }
+        },
+        None => OutputType::Exe,
+    };
+    // One artifact named with --emit goes to -o as it's given, and only what's
+    // built on the way to it is named after it
+    let exact = match opt.emit.as_slice() {
+        [only] if opt.output.is_some() => Some(*only),
+        _ => None,
+    };
+    let path_for = |kind: OutputType, extension: &str| match exact {
+        Some(exact) if exact == kind => out_path.clone(),
+        _ => out_path.with_extension(extension),
+    };
+    if output_type == OutputType::Ir || opt.emit.contains(&OutputType::Ir) {
+        let ir_path = path_for(OutputType::Ir, "ir");
+        crate::ir::write(program, &ir_path)?;
+        if output_type == OutputType::Ir {
+            return Ok(ir_path);
+        }
+    }
+    // C functions are only called with the SysV ABI, from native x86_64-linux
+    // code, so every other backend and target can leave them out
+    if !(opt.target == Target::X86_64Linux && opt.backend == Backend::Native) {
+        for (ip, inst) in program.instructions.iter().enumerate() {
+            if let InstructionKind::Extern(declaration) = &inst.kind {
+                err!(
+                    program,
+                    CompileError(UnsupportedTarget(opt.target.to_string())),
+                    format!(
+                        "C functions like {} are only called from native x86_64-linux code",
+                        declaration.name
+                    ),
+                    ip
+                )
+            }
+        }
+    }
+    let keep_asm = opt.keep_asm || opt.emit.contains(&OutputType::Asm);
+    let keep_obj = opt.keep_obj || opt.emit.contains(&OutputType::Obj);
+    // Only worth reading the source for if anyone will see the assembly
+    let source = keep_asm.then(|| Source::new(program));
+    let asm = timings::time("codegen", || -> Result<Builder> {
+        Ok(match (opt.backend, opt.target.arch()) {
+            (Backend::Llvm, _) => llvm::generate(program, opt.target, opt.mem_capacity, source)?,
+            (Backend::C, _) => c::generate(program, opt.target, opt.mem_capacity, source)?,
+            (Backend::Native, Arch::X86_64) => x86_64(program, &opt, assembler, source)?,
+            (Backend::Native, Arch::Aarch64) => {
+                aarch64::generate(program, opt.mem_capacity, source)?
+            }
+            (Backend::Native, Arch::Riscv64) => {
+                riscv64::generate(program, opt.mem_capacity, source)?
+            }
+            (Backend::Native, Arch::Wasm32) => wasm32::generate(program, opt.mem_capacity, source)?,
+        })
+    })?;
+
+    if opt.emit_map.is_some() && !matches!(output_type, OutputType::Exe) {
+        log::log(
+            LogLevel::Warn,
+            "Only executables have a map, so --emit-map is ignored".to_string(),
+            opt.debug,
+        );
+    }
+    let asm_extension = match opt.backend {
+        Backend::Native => opt.target.asm_extension(),
+        Backend::Llvm => "ll",
+        Backend::C => "c",
+    };
+    let asm_out_path = path_for(OutputType::Asm, asm_extension);
+    let asm_out_path_str = asm_out_path.to_string_lossy().to_string();
+    let obj_out_path_str = path_for(OutputType::Obj, opt.target.obj_extension())
+        .to_string_lossy()
+        .to_string();
+    let exe_out_path_str = path_for(OutputType::Exe, opt.target.exe_extension())
+        .to_string_lossy()
+        .to_string();
+
+    // The built-in assembler reads the builder, so there's no need to read back the file
+    let object = match (opt.backend, opt.target, &output_type) {
+        (_, _, OutputType::Asm) => None,
+        (Backend::Native, Target::X86_64Linux, _) if assembler == Assembler::Builtin => {
+            Some(timings::time("assemble (builtin)", || {
+                builtin::assemble(&asm)
+            })?)
+        }
+        _ => None,
+    };
+    let linker = match (opt.linker, &object) {
+        (Some(Linker::Builtin), None) if matches!(output_type, OutputType::Exe) => {
+            return Err(CompileError(UnsupportedTarget(opt.target.to_string()))).with_context(
+                || "The built-in linker only links what the built-in assembler made",
+            );
+        }
+        (Some(Linker::Builtin), _) if opt.link_libc => {
+            return Err(CompileError(UnsupportedTarget(opt.target.to_string())))
+                .with_context(|| "The built-in linker can't link the C library, use --linker ld");
+        }
+        (Some(Linker::Builtin), _) if !opt.link_args.is_empty() => {
+            return Err(CompileError(UnsupportedTarget(opt.target.to_string())))
+                .with_context(|| "The built-in linker takes no --link-arg, use --linker ld");
+        }
+        (None, _) if opt.link_libc || !opt.link_args.is_empty() => Linker::Ld,
+        (Some(linker), _) => linker,
+        (None, Some(_)) => Linker::Builtin,
+        (None, None) => Linker::Ld,
+    };
+
+    let count_lines = asm.count_lines();
+    timings::time(format!("write {}", asm_extension), || {
+        std::fs::File::create(&asm_out_path).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            asm.write(&mut out)?;
+            out.flush()
+        })
+    })
+    .with_context(|| format!("Could not write asm to {}", asm_out_path.to_string_lossy()))?;
+    log::log(
+        LogLevel::Info,
+        format!("Wrote {} lines to {}", count_lines, asm_out_path_str),
+        opt.debug,
+    );
+
+    if matches!(output_type, OutputType::Asm) {
+        return Ok(asm_out_path);
+    }
+
+    // Call nasm, or the assembler of the target, or clang for LLVM IR, unless
+    // the built-in assembler already made the object
+    if let Some(object) = &object {
+        // The built-in linker takes the object as it is, so it's only written
+        // if it's wanted
+        if matches!(output_type, OutputType::Obj) || keep_obj || linker != Linker::Builtin {
+            std::fs::write(&obj_out_path_str, object.elf())
+                .with_context(|| format!("Could not write object file {}", obj_out_path_str))?;
+            log::log(
+                LogLevel::Info,
+                format!("Assembled {}", obj_out_path_str),
+                opt.debug,
+            );
+        }
+    } else {
+        let mut nasm_cmd = match opt.backend {
+            Backend::Native => opt.target.assembler(
+                &asm_out_path_str,
+                &obj_out_path_str,
+                assembler,
+                opt.debug_info,
+            ),
+            Backend::Llvm => {
+                opt.target
+                    .clang(&asm_out_path_str, &obj_out_path_str, opt.opt_level, opt.pie)
+            }
+            Backend::C => {
+                opt.target
+                    .cc(&asm_out_path_str, &obj_out_path_str, opt.opt_level, opt.pie)
+            }
+        };
+        let assembler = nasm_cmd.get_program().to_string_lossy().to_string();
+        log::log(
+            LogLevel::Cmd,
+            format!("{:?}", nasm_cmd).replace("\"", ""),
+            opt.debug,
+        );
+
+        let nasm = timings::time(format!("assemble ({})", assembler), || {
+            nasm_cmd
+                .spawn()
+                .map_err(|e| CompileError(NasmInvokeError(e)))
+                .with_context(|| format!("Failed to spawn {} process", assembler))?
+                .wait_with_output()
+                .map_err(|e| CompileError(NasmInvokeError(e)))
+                .with_context(|| format!("Failed to wait for {} process to complete", assembler))
+        })?;
+
+        nasm.status
+            .success()
+            .to_err()
+            .map_err(|_| CompileError(NasmCompileError))
+            .with_context(|| {
+                format!(
+                    "{} failed to compile {}:\n{}\n",
+                    assembler,
+                    asm_out_path_str,
+                    String::from_utf8_lossy(&nasm.stderr)
+                )
+            })?;
+    }
+
+    if !keep_asm {
+        if let Err(e) = std::fs::remove_file(&asm_out_path_str) {
+            log::log(
+                LogLevel::Warn,
+                format!("Could not remove asm file {}: {}", asm_out_path_str, e),
+                opt.debug,
+            );
+        };
+    }
+
+    if matches!(output_type, OutputType::Obj) {
+        return Ok(obj_out_path_str.into());
+    }
+
+    if let (Some(object), Linker::Builtin) = (&object, linker) {
+        timings::time("link (builtin)", || -> Result<()> {
+            std::fs::write(&exe_out_path_str, object.executable("_start", opt.pie)?)
+                .with_context(|| format!("Could not write executable {}", exe_out_path_str))
+        })?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&exe_out_path_str, std::fs::Permissions::from_mode(0o755))
+                .with_context(|| format!("Could not make {} executable", exe_out_path_str))?;
+        }
+        log::log(
+            LogLevel::Info,
+            format!("Linked {}", exe_out_path_str),
+            opt.debug,
+        );
+        emit_map(
+            program,
+            opt.emit_map.as_deref(),
+            &exe_out_path_str,
+            opt.debug,
+        )?;
+        return Ok(exe_out_path_str.into());
+    }
+
+    // Call ld, unless what the assembler made already runs
+    let linker = match opt.backend {
+        // cc knows where the C library is
+        Backend::Native if opt.link_libc => {
+            Some(
+                opt.target
+                    .libc_linker(&obj_out_path_str, &exe_out_path_str, opt.pie, linker),
+            )
+        }
+        Backend::C => {
+            Some(
+                opt.target
+                    .cc_linker(&obj_out_path_str, &exe_out_path_str, opt.pie, linker),
+            )
+        }
+        Backend::Native | Backend::Llvm => {
+            opt.target
+                .linker(&obj_out_path_str, &exe_out_path_str, opt.pie, linker)
+        }
+    };
+    let Some(mut ld_cmd) = linker else {
+        if !opt.link_args.is_empty() {
+            log::log(
+                LogLevel::Warn,
+                format!("Nothing links for {}, so --link-arg is ignored", opt.target),
+                opt.debug,
+            );
+        }
+        return Ok(obj_out_path_str.into());
+    };
+    ld_cmd.args(&opt.link_args);
+    let linker = ld_cmd.get_program().to_string_lossy().to_string();
+    log::log(
+        LogLevel::Cmd,
+        format!("{:?}", ld_cmd).replace("\"", ""),
+        opt.debug,
+    );
+    let ld = timings::time(format!("link ({})", linker), || {
+        ld_cmd
+            .spawn()
+            .map_err(|e| CompileError(LdInvokeError(e)))
+            .with_context(|| format!("Failed to spawn {} process", linker))?
+            .wait_with_output()
+            .map_err(|e| CompileError(LdInvokeError(e)))
+            .with_context(|| format!("Failed to wait for {} process to complete", linker))
+    })?;
+
+    ld.status
+        .success()
+        .to_err()
+        .map_err(|_| CompileError(LdLinkError))
+        .with_context(|| {
+            format!(
+                "{} failed to link {}:\n{}\n",
+                linker,
+                obj_out_path_str,
+                String::from_utf8_lossy(&ld.stderr)
+            )
+        })?;
+
+    if !keep_obj {
+        if let Err(e) = std::fs::remove_file(&obj_out_path_str) {
+            log::log(
+                LogLevel::Warn,
+                format!("Could not remove object file {}: {}", obj_out_path_str, e),
+                opt.debug,
+            );
+        };
+    }
+
+    emit_map(
+        program,
+        opt.emit_map.as_deref(),
+        &exe_out_path_str,
+        opt.debug,
+    )?;
+    Ok(exe_out_path_str.into())
+}
+
+/// Write the map of `exe` to `path`, if one was asked for.
+fn emit_map(program: &Program, path: Option<&Path>, exe: &str, debug: bool) -> Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    map::write(program, exe.as_ref(), path)?;
+    log::log(
+        LogLevel::Info,
+        format!("Wrote the map of {} to {}", exe, path.to_string_lossy()),
+        debug,
+    );
+    Ok(())
+}
+
+/// What everything built is named for, with the extension of what -o asked
+/// for if it did. With --out-dir it's in there, which is made if it's missing.
+fn out_path(program: &Program, opt: &CompilerOptions) -> Result<PathBuf> {
+    let out_path = opt
+        .output
+        .clone()
+        .unwrap_or_else(|| program.name.clone().into());
+    let Some(out_dir) = &opt.out_dir else {
+        return Ok(out_path);
+    };
+    let out_path = out_dir.join(out_path);
+    if let Some(dir) = out_path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create {}", dir.to_string_lossy()))?;
+    }
+    Ok(out_path)
+}
+
+/// Where the counts of an instrumented build are written, next to the
+/// executable. It's absolute so running the program from elsewhere still finds it.
+fn profile_path(program: &Program, opt: &CompilerOptions) -> Result<String> {
+    let path = std::env::current_dir()
+        .with_context(|| "Could not find where the profile will be written")?
+        .join(out_path(program, opt)?.with_extension("profile"));
+    Ok(path.to_string_lossy().to_string())
+}
+
+fn x86_64(
+    program: &Program,
+    opt: &CompilerOptions,
+    assembler: Assembler,
+    mut source: Option<Source>,
+) -> Result<Builder> {
+    let macos = opt.target.os() == Os::Macos;
+    let windows = opt.target.os() == Os::Windows;
+    if assembler == Assembler::Builtin && (macos || windows) {
+        return Err(CompileError(UnsupportedTarget(opt.target.to_string()))).with_context(|| {
+            "The built-in assembler only writes ELF, use --assembler nasm or gas"
+        });
+    }
+    let mut asm = match assembler {
+        Assembler::Builtin | Assembler::Nasm => Builder::new(),
+        Assembler::Gas if macos => Builder::att(".section __TEXT,__const"),
+        Assembler::Gas if windows => Builder::att(".section .rdata,\"dr\""),
+        Assembler::Gas => Builder::att(".section .rodata"),
+    };
+    // Mach-O and PE can't relocate 32 bit absolute addresses, and PIE can't
+    // relocate any
+    asm.rip_relative = macos || windows || opt.pie;
     comment!(asm, "-- generated by the worth compiler --");
 
     segment!(asm, "bss");
 
+    asm!(asm, ("alignb", "{}", MEM_ALIGN));
+    if opt.checked {
+        // Padded so the guard still ends where mem starts
+        asm!(asm, ("resb", "{}", MEM_ALIGN - 8));
+        label!(asm, "mem_guard_lo");
+        asm!(asm, ("resq", "1"));
+    }
     label!(asm, "mem");
-    asm!(asm, ("resb", "{}", BSS_CAPACITY));
+    asm!(asm, ("resb", "{}", opt.mem_capacity));
+    asm!(asm, ("alignb", "{}", MEM_ALIGN));
+    for memory in &program.memories {
+        label!(asm, "{}", memory.label());
+        asm!(asm, ("resb", "{}", memory.reserved()));
+    }
+    if opt.check_bounds {
+        label!(asm, "mem_end");
+    }
+    if opt.checked {
+        label!(asm, "mem_guard_hi");
+        asm!(asm, ("resq", "1"));
+        label!(asm, "stack_base");
+        asm!(asm, ("resq", "1"));
+        label!(asm, "stack_limit");
+        asm!(asm, ("resq", "1"));
+    }
 
     label!(asm, "args_ptr");
     asm!(asm, ("resq", "1"));
 
     segment!(asm, "text");
     global!(asm, "_start");
+    if opt.link_libc {
+        let mut externs = vec!["exit"];
+        for inst in &program.instructions {
+            if let InstructionKind::Extern(declaration) = &inst.kind {
+                if !externs.contains(&declaration.name.as_str()) {
+                    externs.push(&declaration.name);
+                }
+            }
+        }
+        for name in externs {
+            asm.insert(format!("extern {}", name));
+        }
+    }
     label!(asm, "_start");
 
     asm!(
@@ -42,14 +568,53 @@ pub fn compile(program: &Program, opt: CompilerOptions) -> Result<PathBuf> {
         /// Save the stack pointer for argc and argv intrinsics
         ("mov", "[args_ptr], rsp")
     );
+    if windows {
+        windows::gen_args(&mut asm);
+    }
+    if opt.check_bounds {
+        // Everything in bss has been reserved by now
+        segment!(asm, "bss");
+        label!(asm, "bss_end");
+        segment!(asm, "text");
+    }
+    let mut checks = Checks::default();
+    let mut instrument = Instrument::default();
+    if opt.checked {
+        checks.gen_start(&mut asm, opt.target);
+    }
 
-    let program_name = program.name.clone();
+    if opt.opt_level >= 1 {
+        asm.start_caching();
+    }
 
+    // Locations only name the file, so debuggers need to be told where it is
+    let source_dir = program
+        .base_path
+        .canonicalize()
+        .unwrap_or_else(|_| program.base_path.clone());
+    let labels = Labels::new(program);
     for (ip, inst) in program.instructions.iter().enumerate() {
         asm.tmp_here +=
             &(inst.loc.0.clone() + ":" + &inst.loc.1.to_string() + ":" + &inst.loc.2.to_string());
+        if opt.debug_info {
+            let file = source_dir.join(&inst.loc.0);
+            asm.loc(&file.to_string_lossy(), inst.loc.1);
+        }
+        if let Some(line) = source.as_mut().and_then(|source| source.line(&inst.loc)) {
+            comment!(asm, line);
+        }
+        if opt.checked {
+            checks.before(&mut asm, inst, opt.target);
+        }
+        if opt.check_bounds {
+            checked::bounds(&mut asm, inst);
+        }
         match &inst.kind {
             InstructionKind::Push(val) => match val {
+                // push sign extends 32 bit immediates, which nasm truncates to and as rejects
+                Value::Int(i) if i32::try_from(*i).is_err() => {
+                    asm!(asm, ("mov", "rax, {}", i), ("push", "rax"));
+                }
                 Value::Int(i) => {
                     asm!(asm, ("push", "{}", i))
                 }
@@ -59,16 +624,17 @@ pub fn compile(program: &Program, opt: CompilerOptions) -> Result<PathBuf> {
                 Value::Char(c) => {
                     asm!(asm, ("push", "{}", c))
                 }
-                Value::Ptr(_) => todo!(),
+                Value::Ptr(name) => err!(
+                    program,
+                    CompileError(UnexpectedToken(name.clone())),
+                    format!("Pointer to {} should be resolved before codegen", name),
+                    ip
+                ),
                 Value::Str(s) => {
                     let s_id = asm.new_const_str(s);
-                    asm!(
-                        asm,
-                        ("mov", "rax, {}", s.as_bytes().len()),
-                        ("push", "rax"),
-                        ("mov", "rax, const_str_{}", s_id),
-                        ("push", "rax")
-                    );
+                    asm!(asm, ("mov", "rax, {}", s.len()), ("push", "rax"));
+                    asm.load_address("rax", &format!("const_str_{}", s_id));
+                    asm!(asm, ("push", "rax"));
                 }
             },
             InstructionKind::Intrinsic(intrinsic) => {
@@ -81,7 +647,7 @@ pub fn compile(program: &Program, opt: CompilerOptions) -> Result<PathBuf> {
             }
             InstructionKind::Keyword(Keyword::While { self_ip, .. }) => {
                 comment!(asm, "-- while --");
-                label!(asm, "addr_{}", self_ip);
+                label!(asm, labels.get(*self_ip));
             }
             InstructionKind::Keyword(Keyword::Do { end_ip }) => {
                 asm!(
@@ -90,13 +656,16 @@ pub fn compile(program: &Program, opt: CompilerOptions) -> Result<PathBuf> {
                     /// While loop condition
                     ("test", "rax, rax"),
                     /// Jump to end of while loop
-                    ("jz", "addr_{}", end_ip)
+                    ("jz", "{}", labels.get(*end_ip))
                 );
                 comment!(asm, "-- do --");
             }
             InstructionKind::Keyword(Keyword::If { .. }) => {
                 comment!(asm, "-- if --");
             }
+            InstructionKind::Keyword(Keyword::Unsafe) => {
+                comment!(asm, "-- unsafe --");
+            }
             InstructionKind::Keyword(Keyword::Elif {
                 self_ip,
                 end_ip: else_ip,
@@ -105,9 +674,9 @@ pub fn compile(program: &Program, opt: CompilerOptions) -> Result<PathBuf> {
                 asm!(
                     asm,
                     /// Jump to post-elif statement
-                    ("jmp", "addr_{}", else_ip)
+                    ("jmp", "{}", labels.get(*else_ip))
                 );
-                label!(asm, "addr_{}", self_ip);
+                label!(asm, labels.get(*self_ip));
             }
             InstructionKind::Keyword(Keyword::Else {
                 self_ip: else_ip,
@@ -117,9 +686,9 @@ pub fn compile(program: &Program, opt: CompilerOptions) -> Result<PathBuf> {
                 asm!(
                     asm,
                     /// Jump to end of if statement
-                    ("jmp", "addr_{}", end_ip)
+                    ("jmp", "{}", labels.get(*end_ip))
                 );
-                label!(asm, "addr_{}", else_ip);
+                label!(asm, labels.get(*else_ip));
             }
             InstructionKind::Keyword(Keyword::End { self_ip, while_ip }) => {
                 comment!(asm, "-- end --");
@@ -127,10 +696,10 @@ pub fn compile(program: &Program, opt: CompilerOptions) -> Result<PathBuf> {
                     asm!(
                         asm,
                         /// Jump to while statement
-                        ("jmp", "addr_{}", while_ip)
+                        ("jmp", "{}", labels.get(*while_ip))
                     )
                 }
-                label!(asm, "addr_{}", self_ip);
+                label!(asm, labels.get(*self_ip));
             }
             InstructionKind::Op(Op::Add) => ops::add(&mut asm),
             InstructionKind::Op(Op::Sub) => ops::sub(&mut asm),
@@ -177,6 +746,40 @@ pub fn compile(program: &Program, opt: CompilerOptions) -> Result<PathBuf> {
                     ip
                 )
             }
+            InstructionKind::Keyword(Keyword::Extern) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("extern".into())),
+                    "Extern should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Memory) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("memory".into())),
+                    "Memory should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Memory(memory) => {
+                comment!(asm, &format!("-- memory: {} --", memory.name));
+                asm.push_address(&memory.label());
+            }
+            InstructionKind::Extern(declaration) if opt.link_libc => {
+                ops::call_extern(&mut asm, declaration)
+            }
+            InstructionKind::Extern(declaration) => {
+                err!(
+                    program,
+                    CompileError(LibcNotLinked(declaration.name.clone())),
+                    format!(
+                        "Extern {} is a C function, compile with --link-libc to call it",
+                        declaration.name
+                    ),
+                    ip
+                )
+            }
             InstructionKind::Name(name) => {
                 err!(
                     program,
@@ -186,149 +789,53 @@ pub fn compile(program: &Program, opt: CompilerOptions) -> Result<PathBuf> {
                 )
             }
         }
+        if opt.checked {
+            checks.after(&mut asm, inst);
+        }
+        if opt.instrument {
+            instrument.block(&mut asm, inst);
+        }
     }
 
-    syscall!(asm, 60, 0);
+    asm.stop_caching();
+    if opt.link_libc {
+        if opt.instrument {
+            // exit isn't a syscall the counts can be written before
+            asm!(asm, ("call", "instrument_dump"));
+        }
+        // exit flushes what the C library buffered, where the syscall wouldn't
+        asm!(
+            asm,
+            ("xor", "edi, edi"),
+            ("and", "rsp, -16"),
+            ("call", "exit")
+        );
+    } else {
+        syscall!(asm, 60, 0);
+    }
 
     gen_intrinsics(&mut asm);
-
-    // Write asm to out.asm
-    let out_path = opt.output.unwrap_or_else(|| program_name.into());
-    let output_type = match out_path.extension() {
-        Some(ext) => match ext
-            .to_str()
-            .ok_or(IOError(NoFileExtension))
-            .with_context(|| format!("Invalid filename: {}", out_path.to_string_lossy()))?
-        {
-            "asm" => OutputType::Asm,
-            "o" => OutputType::Obj,
-            "exe" => OutputType::Exe,
-            _ => {
-                log::log(
-                    LogLevel::Warn,
-                    format!(
-                        "Unknown output type {}. Building elf64 executable.",
-                        ext.to_str()
-                            .ok_or(IOError(NoFileExtension))
-                            .with_context(|| {
-                                format!("Invalid filename: {}", out_path.to_string_lossy())
-                            })?
-                    ),
-                    opt.debug,
-                );
-                OutputType::Exe
-            }
-        },
-        None => OutputType::Exe,
-    };
-    let asm_out_path = out_path.with_extension("asm");
-    let asm_out_path_str = asm_out_path
-        .with_extension("asm")
-        .to_string_lossy()
-        .to_string();
-    let obj_out_path_str = asm_out_path
-        .with_extension("o")
-        .to_string_lossy()
-        .to_string();
-    let exe_out_path_str = asm_out_path
-        .with_extension("")
-        .to_string_lossy()
-        .to_string();
-
-    let count_lines = asm.count_lines();
-    let asm = asm.finalize();
-    std::fs::write(&asm_out_path, asm)
-        .with_context(|| format!("Could not write asm to {}", asm_out_path.to_string_lossy()))?;
-    log::log(
-        LogLevel::Info,
-        format!("Wrote {} lines to {}", count_lines, asm_out_path_str),
-        opt.debug,
-    );
-
-    if matches!(output_type, OutputType::Asm) {
-        return Ok(asm_out_path);
+    if opt.checked {
+        checks.gen_failures(&mut asm);
     }
-
-    // Call nasm
-    let mut nasm_cmd = std::process::Command::new("nasm");
-    nasm_cmd.args(&[&asm_out_path_str, "-f", "elf64", "-o", &obj_out_path_str]);
-    log::log(
-        LogLevel::Cmd,
-        format!("{:?}", nasm_cmd).replace("\"", ""),
-        opt.debug,
-    );
-
-    let nasm = nasm_cmd
-        .spawn()
-        .map_err(|e| CompileError(NasmInvokeError(e)))
-        .with_context(|| format!("Failed to spawn nasm process"))?
-        .wait_with_output()
-        .map_err(|e| CompileError(NasmInvokeError(e)))
-        .with_context(|| format!("Failed to wait for nasm process to complete"))?;
-
-    nasm.status
-        .success()
-        .to_err()
-        .map_err(|_| CompileError(NasmCompileError))
-        .with_context(|| {
-            format!(
-                "Nasm failed to compile {}:\n{}\n",
-                asm_out_path_str,
-                String::from_utf8_lossy(&nasm.stderr)
-            )
-        })?;
-
-    if !opt.keep_asm {
-        if let Err(e) = std::fs::remove_file(&asm_out_path_str) {
-            log::log(
-                LogLevel::Warn,
-                format!("Could not remove asm file {}: {}", asm_out_path_str, e),
-                opt.debug,
-            );
-        };
+    if opt.check_bounds {
+        checked::gen_bounds_check(&mut asm);
     }
 
-    if matches!(output_type, OutputType::Obj) {
-        return Ok(obj_out_path_str.into());
+    if opt.opt_level >= 1 {
+        asm.peephole();
     }
-
-    // Call ld
-    let mut ld_cmd = std::process::Command::new("ld");
-    ld_cmd.args(&[&obj_out_path_str, "-o", &exe_out_path_str]);
-    log::log(
-        LogLevel::Cmd,
-        format!("{:?}", ld_cmd).replace("\"", ""),
-        opt.debug,
-    );
-    let ld = ld_cmd
-        .spawn()
-        .map_err(|e| CompileError(LdInvokeError(e)))
-        .with_context(|| format!("Failed to spawn ld process"))?
-        .wait_with_output()
-        .map_err(|e| CompileError(LdInvokeError(e)))
-        .with_context(|| format!("Failed to wait for ld process to complete"))?;
-
-    ld.status
-        .success()
-        .to_err()
-        .map_err(|_| CompileError(LdLinkError))
-        .with_context(|| {
-            format!(
-                "Ld failed to link {}:\n{}\n",
-                obj_out_path_str,
-                String::from_utf8_lossy(&ld.stderr)
-            )
-        })?;
-
-    if !opt.keep_obj {
-        if let Err(e) = std::fs::remove_file(&obj_out_path_str) {
-            log::log(
-                LogLevel::Warn,
-                format!("Could not remove object file {}: {}", obj_out_path_str, e),
-                opt.debug,
-            );
-        };
+    if opt.instrument {
+        asm.route_syscalls("instrument_syscall");
+        instrument.gen(&mut asm, &profile_path(program, opt)?);
     }
-
-    Ok(exe_out_path_str.into())
+    if macos {
+        asm.route_syscalls("do_syscall");
+        darwin::gen_syscall(&mut asm);
+    }
+    if windows {
+        asm.route_syscalls("do_syscall");
+        windows::gen_syscall(&mut asm);
+    }
+    Ok(asm)
 }
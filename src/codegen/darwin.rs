@@ -0,0 +1,101 @@
+//! Running the x86-64 codegen on macOS.
+//!
+//! The instructions are the same, but Darwin numbers its syscalls after BSD,
+//! offset by `0x2000000`, and reports errors with the carry flag and a positive
+//! errno instead of a negative result. Programs are written against the Linux
+//! numbers in std.porth, so every `syscall` becomes a call to `do_syscall`,
+//! which translates the number and the result. `pipe` returns its descriptors
+//! in registers there, and the child side of `fork` is told apart by `rdx`, so
+//! those two get patched up after the call. Flags and structs are passed
+//! through as they are, and errno values past 34 don't match Linux.
+
+use super::builder::Builder;
+use crate::{asm, asm_line, label, segment};
+
+const PIPE: u16 = 0x4000;
+const FORK: u16 = 0x2000;
+const UNSUPPORTED: u16 = 0xFFFF;
+
+/// Darwin's `ENOSYS`
+const ENOSYS: i64 = 78;
+
+/// Linux x86-64 syscall numbers and their Darwin ones, with `PIPE` or `FORK`
+/// set for the calls that return differently.
+#[rustfmt::skip]
+const SYSCALLS: &[(usize, u16)] = &[
+    (0, 3), (1, 4), (2, 5), (3, 6), (4, 338), (5, 339), (6, 340), (7, 230), (8, 199),
+    (9, 197), (10, 74), (11, 73), (16, 54), (17, 153), (18, 154), (19, 120), (20, 121),
+    (21, 33), (22, 42 | PIPE), (23, 93), (28, 75), (32, 41), (33, 90), (39, 20), (41, 97),
+    (42, 98), (43, 30), (44, 133), (45, 29), (46, 28), (47, 27), (48, 134), (49, 104),
+    (50, 106), (51, 32), (52, 31), (53, 135), (54, 105), (55, 118), (57, 2 | FORK),
+    (58, 66 | FORK), (59, 59), (60, 1), (61, 7), (62, 37), (72, 92), (74, 95), (76, 200),
+    (77, 201), (80, 12), (81, 13), (82, 128), (83, 136), (84, 137), (86, 9), (87, 10),
+    (88, 57), (89, 58), (90, 15), (91, 124), (92, 16), (95, 60), (96, 116), (97, 194),
+    (98, 117), (102, 24), (104, 47), (105, 23), (106, 181), (107, 25), (108, 43), (109, 82),
+    (110, 39), (111, 81), (112, 147), (231, 1), (257, 463), (258, 475), (262, 469),
+    (263, 472), (264, 465), (267, 473), (269, 466),
+];
+
+/// `do_syscall` and the table it translates numbers with.
+pub fn gen_syscall(asm: &mut Builder) {
+    let len = SYSCALLS
+        .iter()
+        .map(|&(linux, _)| linux + 1)
+        .max()
+        .unwrap_or(0);
+    let mut table = vec![UNSUPPORTED; len];
+    for &(linux, darwin) in SYSCALLS {
+        table[linux] = darwin;
+    }
+
+    label!(asm, "do_syscall");
+    asm!(
+        asm,
+        ("cmp", "rax, {}", len),
+        ("jae", ".unsupported"),
+        ("lea", "r11, [rel syscall_table]"),
+        ("movzx", "eax, word [r11 + rax * 2]"),
+        ("cmp", "eax, {}", UNSUPPORTED),
+        ("je", ".unsupported"),
+        /// Keep the flags for after the call
+        ("push", "rax"),
+        ("and", "eax, {}", FORK - 1),
+        ("add", "rax, 0x2000000"),
+        ("syscall"),
+        /// Doesn't touch the carry flag
+        ("pop", "r11"),
+        ("jnc", ".pipe"),
+        ("neg", "rax"),
+        ("ret")
+    );
+    label!(asm, ".pipe");
+    asm!(
+        asm,
+        ("test", "r11, {}", PIPE),
+        ("jz", ".fork"),
+        ("mov", "[rdi], eax"),
+        ("mov", "[rdi + 4], edx"),
+        ("xor", "eax, eax")
+    );
+    label!(asm, ".fork");
+    asm!(
+        asm,
+        ("test", "r11, {}", FORK),
+        ("jz", ".done"),
+        /// Set in the child, which gets the parent's pid in rax
+        ("test", "edx, edx"),
+        ("jz", ".done"),
+        ("xor", "eax, eax")
+    );
+    label!(asm, ".done");
+    asm!(asm, ("ret"));
+    label!(asm, ".unsupported");
+    asm!(asm, ("mov", "rax, {}", -ENOSYS), ("ret"));
+
+    segment!(asm, "rodata");
+    label!(asm, "syscall_table");
+    for chunk in table.chunks(16) {
+        let entries: Vec<String> = chunk.iter().map(u16::to_string).collect();
+        asm!(asm, ("dw", "{}", entries.join(", ")));
+    }
+}
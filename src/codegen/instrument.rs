@@ -0,0 +1,171 @@
+//! The counters `--instrument` builds keep, so the hot parts of a native run can
+//! be traced back to the source without perf or debug info.
+//!
+//! Each basic block starts by bumping its counter in `instrument_counts`: the
+//! one at the start of the program, the ones after each control flow label and
+//! the body after each `do`. Blocks from the same keyword share a counter, so a
+//! macro expanded in many places is counted once for where it's written. The
+//! exit syscalls go through `instrument_syscall`, which writes every count with
+//! the location of its block to the profile first, a line each like
+//! `1204 rule110.porth:22:4`.
+
+use std::collections::HashMap;
+
+use super::builder::Builder;
+use crate::{
+    asm, asm_line, comment,
+    error::err_loc,
+    instruction::{Instruction, InstructionKind, Keyword},
+    label, segment,
+};
+
+const SYS_WRITE: usize = 1;
+const SYS_OPEN: usize = 2;
+const SYS_CLOSE: usize = 3;
+const SYS_EXIT: usize = 60;
+const SYS_EXIT_GROUP: usize = 231;
+/// O_WRONLY | O_CREAT | O_TRUNC
+const OPEN_FLAGS: usize = 0o1101;
+
+#[derive(Debug, Default)]
+pub struct Instrument {
+    /// The location each counter is for, by counter
+    blocks: Vec<(String, usize, usize)>,
+    counters: HashMap<(String, usize, usize), usize>,
+}
+
+impl Instrument {
+    /// Count the block `inst` starts, if it starts one, once the code for it
+    /// has been generated.
+    pub fn block(&mut self, asm: &mut Builder, inst: &Instruction) {
+        let starts = inst.ip == 0
+            || matches!(
+                inst.kind,
+                InstructionKind::Keyword(
+                    Keyword::While { .. }
+                        | Keyword::Do { .. }
+                        | Keyword::Elif { .. }
+                        | Keyword::Else { .. }
+                        | Keyword::End { .. }
+                )
+            );
+        if !starts {
+            return;
+        }
+        let counter = *self.counters.entry(inst.loc.clone()).or_insert_with(|| {
+            self.blocks.push(inst.loc.clone());
+            self.blocks.len() - 1
+        });
+        asm!(
+            asm,
+            /// Count the block
+            ("inc", "qword [instrument_counts + {}]", counter * 8)
+        );
+    }
+
+    /// Where the counters are kept, and the routines that write them to the
+    /// file at `path`. Generated after the peephole optimizer and anything
+    /// rewriting syscalls, since their registers are live across labels and
+    /// their own syscalls are left alone.
+    pub fn gen(&self, asm: &mut Builder, path: &str) {
+        segment!(asm, "bss");
+        label!(asm, "instrument_counts");
+        asm!(asm, ("resq", "{}", self.blocks.len().max(1)));
+        label!(asm, "instrument_fd");
+        asm!(asm, ("resq", "1"));
+        segment!(asm, "text");
+
+        comment!(asm, "-- instrument --");
+        label!(asm, "instrument_syscall");
+        asm!(
+            asm,
+            ("cmp", "rax, {}", SYS_EXIT),
+            ("je", "instrument_exit"),
+            ("cmp", "rax, {}", SYS_EXIT_GROUP),
+            ("je", "instrument_exit"),
+            ("syscall"),
+            ("ret")
+        );
+        label!(asm, "instrument_exit");
+        asm!(
+            asm,
+            ("push", "rax"),
+            ("push", "rdi"),
+            ("call", "instrument_dump"),
+            ("pop", "rdi"),
+            ("pop", "rax"),
+            ("syscall")
+        );
+
+        // Not written at all if the file can't be opened, the program's exit
+        // code still matters more
+        label!(asm, "instrument_dump");
+        let path_id = asm.new_const_str(&format!("{}\0", path));
+        asm.load_address("rdi", &format!("const_str_{}", path_id));
+        asm!(
+            asm,
+            ("mov", "rsi, {}", OPEN_FLAGS),
+            ("mov", "rdx, 420"),
+            ("mov", "rax, {}", SYS_OPEN),
+            ("syscall"),
+            ("test", "rax, rax"),
+            ("js", "instrument_dump_done"),
+            ("mov", "[instrument_fd], rax")
+        );
+        for (counter, loc) in self.blocks.iter().enumerate() {
+            let loc = format!("{}\n", err_loc(loc));
+            let loc_id = asm.new_const_str(&loc);
+            asm.load_address("rsi", &format!("const_str_{}", loc_id));
+            asm!(
+                asm,
+                ("mov", "rdx, {}", loc.len()),
+                ("mov", "rax, [instrument_counts + {}]", counter * 8),
+                ("call", "instrument_line")
+            );
+        }
+        asm!(
+            asm,
+            ("mov", "rdi, [instrument_fd]"),
+            ("mov", "rax, {}", SYS_CLOSE),
+            ("syscall")
+        );
+        label!(asm, "instrument_dump_done");
+        asm!(asm, ("ret"));
+
+        // Writes the count in rax and a space, then the rdx bytes at rsi
+        label!(asm, "instrument_line");
+        asm!(
+            asm,
+            ("push", "rsi"),
+            ("push", "rdx"),
+            ("sub", "rsp, 32"),
+            ("lea", "rcx, [rsp + 31]"),
+            ("mov", "byte [rcx], 32"),
+            ("mov", "r8, 10")
+        );
+        label!(asm, "instrument_digit");
+        asm!(
+            asm,
+            ("xor", "edx, edx"),
+            ("div", "r8"),
+            ("add", "dl, 48"),
+            ("dec", "rcx"),
+            ("mov", "[rcx], dl"),
+            ("test", "rax, rax"),
+            ("jnz", "instrument_digit"),
+            ("mov", "rsi, rcx"),
+            ("lea", "rdx, [rsp + 32]"),
+            ("sub", "rdx, rcx"),
+            ("mov", "rdi, [instrument_fd]"),
+            ("mov", "rax, {}", SYS_WRITE),
+            ("syscall"),
+            ("add", "rsp, 32"),
+            ("pop", "rdx"),
+            ("pop", "rsi"),
+            ("mov", "rdi, [instrument_fd]"),
+            ("mov", "rax, {}", SYS_WRITE),
+            ("syscall"),
+            ("ret")
+        );
+    }
+}
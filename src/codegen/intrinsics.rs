@@ -18,6 +18,7 @@ intrinsics!(
     Argv,
     CastPtr = "cast(ptr)",
     CastInt = "cast(int)",
+    Align,
     Here
 );
 
@@ -40,6 +41,19 @@ pub fn castint(asm: &mut Builder) {
     comment!(asm, "-- Cast to Int --");
 }
 
+/// Round the pointer under the top up to the power of two on top.
+pub fn align(asm: &mut Builder) {
+    asm!(
+        asm,
+        ("pop", "rbx"),
+        ("pop", "rax"),
+        ("lea", "rax, [rax + rbx - 1]"),
+        ("neg", "rbx"),
+        ("and", "rax, rbx"),
+        ("push", "rax")
+    );
+}
+
 pub fn argv(asm: &mut Builder) {
     asm!(
         asm,
@@ -118,7 +132,7 @@ pub fn swap(asm: &mut Builder) {
 }
 
 pub fn mem(asm: &mut Builder) {
-    asm!(asm, ("push", "mem"));
+    asm.push_address("mem");
 }
 
 pub fn gen_intrinsics(asm: &mut Builder) {
@@ -0,0 +1,56 @@
+//! Names for the labels control flow jumps to, from the keyword each is at and
+//! where it is in the source, like `while_17_3` or `if_else_42_8`, so the
+//! assembly and anything reading its symbols says which loop or branch it is.
+
+use std::collections::HashMap;
+
+use crate::instruction::{InstructionKind, Keyword, Program};
+
+pub struct Labels {
+    /// The name of the label at each ip that has one
+    names: HashMap<usize, String>,
+}
+
+impl Labels {
+    pub fn new(program: &Program) -> Self {
+        let mut names = HashMap::new();
+        // How many labels have each name so far. Code from the same macro, or
+        // from different files, can have keywords in the same place.
+        let mut counts = HashMap::new();
+        for inst in &program.instructions {
+            let InstructionKind::Keyword(keyword) = &inst.kind else {
+                continue;
+            };
+            let kind = match keyword {
+                Keyword::While { .. } => "while",
+                Keyword::Do { .. } => "do",
+                Keyword::Elif { .. } => "if_elif",
+                Keyword::Else { .. } => "if_else",
+                Keyword::End {
+                    while_ip: Some(_), ..
+                } => "while_end",
+                Keyword::End { .. } => "if_end",
+                _ => continue,
+            };
+            let name = format!("{}_{}_{}", kind, inst.loc.1, inst.loc.2);
+            let count = counts.entry(name.clone()).or_insert(0);
+            *count += 1;
+            let name = match *count {
+                1 => name,
+                count => format!("{}_{}", name, count),
+            };
+            names.insert(inst.ip, name);
+        }
+        Self { names }
+    }
+
+    /// Every label, with the ip it's at.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.names.iter().map(|(&ip, name)| (ip, name.as_str()))
+    }
+
+    /// The label at `ip`, which has to be a control flow keyword.
+    pub fn get(&self, ip: usize) -> &str {
+        &self.names[&ip]
+    }
+}
@@ -0,0 +1,107 @@
+use crate::codegen::intrinsics::Intrinsic;
+
+use super::Ir;
+
+pub fn compile(ir: &mut Ir, intrinsic: &Intrinsic) {
+    match intrinsic {
+        Intrinsic::Print => {
+            let value = ir.pop();
+            ir.line(format!("call void @print(i64 {})", value));
+        }
+        Intrinsic::Panic => ir.lines(&[
+            "call i64 @syscall(i64 60, i64 1, i64 0, i64 0, i64 0, i64 0, i64 0)",
+            "unreachable",
+        ]),
+        Intrinsic::Dup => {
+            let a = ir.pop();
+            ir.push(&a);
+            ir.push(&a);
+        }
+        Intrinsic::Dup2 => {
+            let b = ir.pop();
+            let a = ir.pop();
+            for value in [&a, &b, &a, &b] {
+                ir.push(value);
+            }
+        }
+        Intrinsic::Swap => {
+            let b = ir.pop();
+            let a = ir.pop();
+            ir.push(b);
+            ir.push(a);
+        }
+        Intrinsic::Over => {
+            let b = ir.pop();
+            let a = ir.pop();
+            ir.push(&a);
+            ir.push(b);
+            ir.push(a);
+        }
+        Intrinsic::Drop => ir.depth -= 1,
+        Intrinsic::Drop2 => ir.depth -= 2,
+        Intrinsic::Mem => {
+            let mem = ir.address(&format!("[{} x i8]", ir.mem_capacity), "mem");
+            ir.push(mem);
+        }
+        Intrinsic::Argc => {
+            let ptr = ir.value("inttoptr i64 %args to i64*");
+            let argc = ir.value(format!("load i64, i64* {}", ptr));
+            ir.push(argc);
+        }
+        Intrinsic::Argv => {
+            let argv = ir.value("add i64 %args, 8");
+            ir.push(argv);
+        }
+        Intrinsic::CastPtr => ir.comment("-- Cast to Pointer --"),
+        Intrinsic::CastInt => ir.comment("-- Cast to Int --"),
+        Intrinsic::Align => {
+            let n = ir.pop();
+            let ptr = ir.pop();
+            let end = ir.value(format!("add i64 {}, {}", ptr, n));
+            let end = ir.value(format!("sub i64 {}, 1", end));
+            let mask = ir.value(format!("sub i64 0, {}", n));
+            let aligned = ir.value(format!("and i64 {}, {}", end, mask));
+            ir.push(aligned);
+        }
+        Intrinsic::Here => {
+            let here = ir.asm.tmp_here.clone();
+            ir.comment(format!("-- {} --", here));
+        }
+    }
+}
+
+/// `@print`, which prints a number as unsigned and a newline.
+pub fn gen_print(ir: &mut Ir) {
+    ir.asm
+        .insert("define internal void @print(i64 %value) #0 {".to_string());
+    ir.label("entry");
+    // Digits are written backwards from the end of the buffer
+    ir.lines(&[
+        "%buf = alloca [32 x i8]",
+        "%last = getelementptr [32 x i8], [32 x i8]* %buf, i64 0, i64 31",
+        "store i8 10, i8* %last",
+        "br label %digits",
+    ]);
+    ir.label("digits");
+    ir.lines(&[
+        "%v = phi i64 [ %value, %entry ], [ %next, %digits ]",
+        "%i = phi i64 [ 31, %entry ], [ %j, %digits ]",
+        "%j = sub i64 %i, 1",
+        "%digit = urem i64 %v, 10",
+        "%char = add i64 %digit, 48",
+        "%byte = trunc i64 %char to i8",
+        "%p = getelementptr [32 x i8], [32 x i8]* %buf, i64 0, i64 %j",
+        "store i8 %byte, i8* %p",
+        "%next = udiv i64 %v, 10",
+        "%more = icmp ne i64 %next, 0",
+        "br i1 %more, label %digits, label %write",
+    ]);
+    ir.label("write");
+    ir.lines(&[
+        "%addr = ptrtoint i8* %p to i64",
+        "%len = sub i64 32, %j",
+        "call i64 @syscall(i64 1, i64 1, i64 %addr, i64 %len, i64 0, i64 0, i64 0)",
+        "ret void",
+    ]);
+    ir.asm.insert("}".to_string());
+}
@@ -0,0 +1,517 @@
+//! Code generation for LLVM, written as textual IR for clang to optimize and
+//! build for the target.
+//!
+//! Porth values never need their stack to be addressable, and after
+//! typechecking its depth at every instruction is known, so each stack slot
+//! becomes an `alloca` that LLVM promotes to a register. Labels are where the
+//! x86 backend puts them, each starting a basic block. Only the syscalls are
+//! written for the target, as inline assembly, and like the other backends
+//! every Linux port but x86-64 translates their numbers with a table. Code
+//! after a program exits is left out, since it can leave anything on the stack.
+
+mod intrinsics;
+mod ops;
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use anyhow::{Context, Result};
+
+use super::aarch64::{self, AT_FDCWD, FORK, UNSUPPORTED};
+use super::builder::{Builder, SegmentKind};
+use super::intrinsics::Intrinsic;
+use super::labels::Labels;
+use super::source::Source;
+use crate::{
+    cli::Target,
+    err,
+    error::{CompileError::*, Error::CompileError},
+    instruction::*,
+};
+
+/// The function being written, and what's on the porth stack at this point.
+pub struct Ir {
+    asm: Builder,
+    depth: usize,
+    /// Deepest the stack gets, which is how many slots there are
+    slots: usize,
+    temps: usize,
+    /// Whether anything runs the code being written
+    reachable: bool,
+    /// The depth of the stack jumps to each label arrive with
+    targets: HashMap<usize, usize>,
+    /// Ids of the string constants, by contents
+    strings: HashMap<String, usize>,
+    /// The size of `@mem`, which is part of its type
+    mem_capacity: usize,
+    labels: Labels,
+}
+
+impl Ir {
+    fn line(&mut self, text: impl AsRef<str>) {
+        self.asm.insert(format!("  {}", text.as_ref()));
+    }
+
+    fn lines(&mut self, lines: &[&str]) {
+        for line in lines {
+            self.line(line);
+        }
+    }
+
+    fn comment(&mut self, text: impl AsRef<str>) {
+        self.line(format!("; {}", text.as_ref()))
+    }
+
+    fn label(&mut self, name: impl Display) {
+        self.asm.insert(format!("{}:", name));
+    }
+
+    /// Compute `expr` into a new value, and return its name.
+    fn value(&mut self, expr: impl Display) -> String {
+        self.temps += 1;
+        let name = format!("%t{}", self.temps);
+        self.line(format!("{} = {}", name, expr));
+        name
+    }
+
+    fn push(&mut self, value: impl Display) {
+        self.line(format!("store i64 {}, i64* %s{}", value, self.depth));
+        self.depth += 1;
+        self.slots = self.slots.max(self.depth);
+    }
+
+    fn pop(&mut self) -> String {
+        self.depth -= 1;
+        self.value(format!("load i64, i64* %s{}", self.depth))
+    }
+
+    /// Note a jump to `label` from here. Returns false if the stack there
+    /// would be a different size than from somewhere else.
+    fn jump(&mut self, label: usize) -> bool {
+        *self.targets.entry(label).or_insert(self.depth) == self.depth
+    }
+
+    /// Jump to `label`, ending the block.
+    fn branch(&mut self, label: usize) -> bool {
+        if !self.reachable {
+            return true;
+        }
+        let branch = format!("br label %{}", self.labels.get(label));
+        self.line(branch);
+        self.reachable = false;
+        self.jump(label)
+    }
+
+    /// Start the block for `label`, if anything jumps there.
+    fn place(&mut self, label: usize) {
+        if let Some(&depth) = self.targets.get(&label) {
+            self.label(self.labels.get(label).to_string());
+            self.depth = depth;
+            self.reachable = true;
+        }
+    }
+
+    /// A value holding the address of the global `name` of type `ty`.
+    fn address(&mut self, ty: &str, name: &str) -> String {
+        self.value(format!("ptrtoint {}* @{} to i64", ty, name))
+    }
+
+    /// The global holding `s`, and its type.
+    fn string(&mut self, s: &str) -> (String, String) {
+        let ty = format!("[{} x i8]", s.len());
+        if let Some(id) = self.strings.get(s) {
+            return (format!("str_{}", id), ty);
+        }
+        let id = self.strings.len();
+        self.strings.insert(s.to_string(), id);
+        self.asm.set_insert_segment(SegmentKind::Data);
+        self.asm.insert(format!(
+            "@str_{} = private unnamed_addr constant {} c\"{}\"",
+            id,
+            ty,
+            escape(s.as_bytes())
+        ));
+        self.asm.set_insert_segment(SegmentKind::Text);
+        (format!("str_{}", id), ty)
+    }
+}
+
+/// How many values `kind` takes off the stack.
+fn pops(kind: &InstructionKind) -> usize {
+    match kind {
+        InstructionKind::Op(Op::BitwiseNot | Op::Load | Op::Load64) => 1,
+        InstructionKind::Op(_) => 2,
+        InstructionKind::Intrinsic(intrinsic) => match intrinsic {
+            Intrinsic::Print | Intrinsic::Drop | Intrinsic::Dup => 1,
+            Intrinsic::CastPtr | Intrinsic::CastInt => 1,
+            Intrinsic::Dup2 | Intrinsic::Swap | Intrinsic::Over | Intrinsic::Drop2 => 2,
+            Intrinsic::Align => 2,
+            _ => 0,
+        },
+        InstructionKind::Syscall(kind) => syscall_args(kind) + 1,
+        InstructionKind::Keyword(Keyword::Do { .. }) => 1,
+        _ => 0,
+    }
+}
+
+fn syscall_args(kind: &SyscallKind) -> usize {
+    match kind {
+        SyscallKind::Syscall0 => 0,
+        SyscallKind::Syscall1 => 1,
+        SyscallKind::Syscall2 => 2,
+        SyscallKind::Syscall3 => 3,
+        SyscallKind::Syscall4 => 4,
+        SyscallKind::Syscall5 => 5,
+        SyscallKind::Syscall6 => 6,
+    }
+}
+
+pub fn generate(
+    program: &Program,
+    target: Target,
+    mem_capacity: usize,
+    mut source: Option<Source>,
+) -> Result<Builder> {
+    let triple = target
+        .llvm_triple()
+        .ok_or(CompileError(UnsupportedTarget(target.to_string())))
+        .with_context(|| {
+            "The LLVM backend only builds for Linux, use the native backend for other targets"
+        })?;
+
+    let mut ir = Ir {
+        asm: Builder::llvm(),
+        depth: 0,
+        slots: 0,
+        temps: 0,
+        reachable: true,
+        targets: HashMap::new(),
+        strings: HashMap::new(),
+        mem_capacity,
+        labels: Labels::new(program),
+    };
+    ir.asm.set_insert_segment(SegmentKind::Data);
+    ir.asm
+        .insert("; -- generated by the worth compiler --".to_string());
+    ir.asm.insert(format!("target triple = \"{}\"", triple));
+    ir.asm.insert(format!(
+        "@mem = internal global [{} x i8] zeroinitializer, align {}",
+        ir.mem_capacity, MEM_ALIGN
+    ));
+    for memory in &program.memories {
+        ir.asm.insert(format!(
+            "@{} = internal global [{} x i8] zeroinitializer, align {}",
+            memory.label(),
+            memory.reserved(),
+            MEM_ALIGN
+        ));
+    }
+    ir.asm.set_insert_segment(SegmentKind::Text);
+
+    gen_start(&mut ir, target);
+    intrinsics::gen_print(&mut ir);
+    gen_syscall(&mut ir, target);
+
+    ir.asm
+        .insert("define void @worth_main(i64 %args) #0 {".to_string());
+    ir.label("entry");
+    let slots_at = ir.asm.header();
+
+    for (ip, inst) in program.instructions.iter().enumerate() {
+        ir.asm.tmp_here =
+            inst.loc.0.clone() + ":" + &inst.loc.1.to_string() + ":" + &inst.loc.2.to_string();
+        if let Some(line) = source.as_mut().and_then(|source| source.line(&inst.loc)) {
+            ir.comment(line);
+        }
+        if !ir.reachable && !matches!(inst.kind, InstructionKind::Keyword(_)) {
+            continue;
+        }
+        if ir.reachable && ir.depth < pops(&inst.kind) {
+            err!(
+                program,
+                CompileError(UnknownStackDepth),
+                "Not enough values on the stack, which the LLVM backend can't build",
+                ip
+            )
+        }
+        match &inst.kind {
+            InstructionKind::Push(val) => match val {
+                Value::Int(i) => ir.push(i),
+                Value::Bool(b) => ir.push(*b as i64),
+                Value::Char(c) => ir.push(*c as i64),
+                Value::Ptr(name) => err!(
+                    program,
+                    CompileError(UnexpectedToken(name.clone())),
+                    format!("Pointer to {} should be resolved before codegen", name),
+                    ip
+                ),
+                Value::Str(s) => {
+                    ir.push(s.len());
+                    let (name, ty) = ir.string(s);
+                    let address = ir.address(&ty, &name);
+                    ir.push(address);
+                }
+            },
+            InstructionKind::Intrinsic(intrinsic) => {
+                ir.comment(format!(
+                    "-- intrinsic: {} --",
+                    intrinsic.to_string().to_lowercase()
+                ));
+                intrinsics::compile(&mut ir, intrinsic);
+                if let Intrinsic::Panic = intrinsic {
+                    ir.reachable = false;
+                }
+            }
+            InstructionKind::Keyword(Keyword::While { self_ip, .. }) => {
+                ir.comment("-- while --");
+                if !ir.branch(*self_ip) {
+                    return mismatch(program, ip);
+                }
+                ir.place(*self_ip);
+            }
+            InstructionKind::Keyword(Keyword::Do { end_ip }) => {
+                if !ir.reachable {
+                    continue;
+                }
+                ir.comment("-- do --");
+                let condition = ir.pop();
+                let condition = ir.value(format!("icmp ne i64 {}, 0", condition));
+                if !ir.jump(*end_ip) {
+                    return mismatch(program, ip);
+                }
+                let body = ir.labels.get(ip).to_string();
+                let end = ir.labels.get(*end_ip).to_string();
+                ir.line(format!(
+                    "br i1 {}, label %{}, label %{}",
+                    condition, body, end
+                ));
+                ir.label(body);
+            }
+            InstructionKind::Keyword(Keyword::If) => ir.comment("-- if --"),
+            InstructionKind::Keyword(Keyword::Unsafe) => ir.comment("-- unsafe --"),
+            InstructionKind::Keyword(Keyword::Elif {
+                self_ip,
+                end_ip: else_ip,
+            }) => {
+                ir.comment("-- elif --");
+                if !ir.branch(*else_ip) {
+                    return mismatch(program, ip);
+                }
+                ir.place(*self_ip);
+            }
+            InstructionKind::Keyword(Keyword::Else {
+                self_ip: else_ip,
+                end_ip,
+            }) => {
+                ir.comment("-- else --");
+                if !ir.branch(*end_ip) {
+                    return mismatch(program, ip);
+                }
+                ir.place(*else_ip);
+            }
+            InstructionKind::Keyword(Keyword::End { self_ip, while_ip }) => {
+                ir.comment("-- end --");
+                if !ir.branch(while_ip.unwrap_or(*self_ip)) {
+                    return mismatch(program, ip);
+                }
+                ir.place(*self_ip);
+            }
+            InstructionKind::Op(op) => ops::compile(&mut ir, op),
+            InstructionKind::Syscall(kind) => {
+                ops::syscall(&mut ir, syscall_args(kind));
+                let exit = ip
+                    .checked_sub(1)
+                    .map(|prev| &program.instructions[prev].kind);
+                if matches!(exit, Some(InstructionKind::Push(Value::Int(60 | 231)))) {
+                    ir.line("unreachable");
+                    ir.reachable = false;
+                }
+            }
+            InstructionKind::Keyword(Keyword::Include) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("include".into())),
+                    "Include should be expanded before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Macro) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("macro".into())),
+                    "Macro should be expanded before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Extern) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("extern".into())),
+                    "Extern should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Memory) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("memory".into())),
+                    "Memory should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Memory(memory) => {
+                let address = ir.address(&format!("[{} x i8]", memory.reserved()), &memory.label());
+                ir.push(address);
+            }
+            InstructionKind::Extern(_) => {
+                unreachable!("compile only lets externs through for native x86_64-linux")
+            }
+            InstructionKind::Name(name) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken(name.clone())),
+                    format!("Name {} should be resolved before codegen", name),
+                    ip
+                )
+            }
+        }
+    }
+    if ir.reachable {
+        ir.lines(&[
+            "call i64 @syscall(i64 60, i64 0, i64 0, i64 0, i64 0, i64 0, i64 0)",
+            "unreachable",
+        ]);
+    }
+    ir.asm.insert("}".to_string());
+
+    // Now that it's known how many slots there are, they go in the entry block
+    for slot in 0..ir.slots {
+        ir.asm
+            .insert_header(slots_at, format!("  %s{} = alloca i64", slot));
+    }
+
+    let features = match target {
+        Target::Riscv64Linux => " \"target-features\"=\"+m\"",
+        _ => "",
+    };
+    ir.asm
+        .insert(format!("attributes #0 = {{ nounwind{} }}", features));
+    Ok(ir.asm)
+}
+
+fn mismatch(program: &Program, ip: usize) -> Result<Builder> {
+    err!(
+        program,
+        CompileError(UnknownStackDepth),
+        "The stack is a different size on each path here, which the LLVM backend can't build",
+        ip
+    )
+}
+
+/// `bytes` as the inside of an LLVM string.
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'"' | b'\\' => format!("\\{:02X}", b),
+            b' '..=b'~' => (b as char).to_string(),
+            _ => format!("\\{:02X}", b),
+        })
+        .collect()
+}
+
+/// `_start`, which passes the address of argc to `worth_main`. LLVM functions
+/// all start by setting up a frame, so this one is assembly.
+fn gen_start(ir: &mut Ir, target: Target) {
+    let start: &[&str] = match target {
+        Target::Aarch64Linux => &["mov x0, sp", "bl worth_main"],
+        Target::Riscv64Linux => &["mv a0, sp", "call worth_main"],
+        _ => &["mov %rsp, %rdi", "call worth_main"],
+    };
+    for line in [".text", ".globl _start", "_start:"].iter().chain(start) {
+        ir.asm.insert(format!("module asm \"{}\"", line));
+    }
+}
+
+/// `@syscall`, which takes an x86-64 syscall number and six arguments, and
+/// returns the result.
+fn gen_syscall(ir: &mut Ir, target: Target) {
+    let (inst, constraints) = match target {
+        Target::Aarch64Linux => ("svc #0", "={x0},{x8},{x0},{x1},{x2},{x3},{x4},{x5}"),
+        Target::Riscv64Linux => ("ecall", "={x10},{x17},{x10},{x11},{x12},{x13},{x14},{x15}"),
+        _ => (
+            "syscall",
+            "={rax},{rax},{rdi},{rsi},{rdx},{r10},{r8},{r9},~{rcx},~{r11}",
+        ),
+    };
+    let call = |n: &str, args: [&str; 6]| {
+        format!(
+            "%result = call i64 asm sideeffect \"{}\", \"{},~{{memory}}\"(i64 {}, i64 {})",
+            inst,
+            constraints,
+            n,
+            args.join(", i64 ")
+        )
+    };
+    ir.asm.insert(
+        "define internal i64 @syscall(i64 %n, i64 %a, i64 %b, i64 %c, i64 %d, i64 %e, i64 %f) #0 {"
+            .to_string(),
+    );
+    if target == Target::X86_64Linux {
+        ir.line(call("%n", ["%a", "%b", "%c", "%d", "%e", "%f"]));
+        ir.line("ret i64 %result");
+        ir.asm.insert("}".to_string());
+        return;
+    }
+
+    let table = aarch64::syscall_table();
+    let ty = format!("[{} x i16]", table.len());
+    let entries: Vec<String> = table.iter().map(|entry| format!("i16 {}", entry)).collect();
+    ir.asm.set_insert_segment(SegmentKind::Data);
+    ir.asm.insert(format!(
+        "@syscall_table = private constant {} [{}]",
+        ty,
+        entries.join(", ")
+    ));
+    ir.asm.set_insert_segment(SegmentKind::Text);
+
+    ir.lines(&[
+        &format!("%known = icmp ult i64 %n, {}", table.len()),
+        "br i1 %known, label %lookup, label %unsupported",
+    ]);
+    ir.label("lookup");
+    ir.lines(&[
+        &format!(
+            "%slot = getelementptr {0}, {0}* @syscall_table, i64 0, i64 %n",
+            ty
+        ),
+        "%entry = load i16, i16* %slot",
+        "%number = zext i16 %entry to i64",
+        &format!("%missing = icmp eq i64 %number, {}", UNSUPPORTED),
+        "br i1 %missing, label %unsupported, label %translate",
+    ]);
+    ir.label("translate");
+    ir.lines(&[
+        &format!("%at_flag = and i64 %number, {}", AT_FDCWD),
+        "%at = icmp ne i64 %at_flag, 0",
+        // AT_FDCWD
+        "%at_a = select i1 %at, i64 -100, i64 %a",
+        "%at_b = select i1 %at, i64 %a, i64 %b",
+        "%at_c = select i1 %at, i64 %b, i64 %c",
+        "%at_d = select i1 %at, i64 %c, i64 %d",
+        &format!("%fork_flag = and i64 %number, {}", FORK),
+        "%fork = icmp ne i64 %fork_flag, 0",
+        // SIGCHLD, which is what fork sends the parent
+        "%fork_a = select i1 %fork, i64 17, i64 %at_a",
+        &format!("%generic = and i64 %number, {}", FORK - 1),
+        &call(
+            "%generic",
+            ["%fork_a", "%at_b", "%at_c", "%at_d", "%e", "%f"],
+        ),
+        "ret i64 %result",
+    ]);
+    ir.label("unsupported");
+    // -ENOSYS
+    ir.line("ret i64 -38");
+    ir.asm.insert("}".to_string());
+}
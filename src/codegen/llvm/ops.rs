@@ -0,0 +1,139 @@
+use crate::instruction::Op;
+
+use super::Ir;
+
+/// Pop `b` then `a`, and push `inst a, b`.
+fn binary(ir: &mut Ir, inst: &str) {
+    let b = ir.pop();
+    let a = ir.pop();
+    let result = ir.value(format!("{} i64 {}, {}", inst, a, b));
+    ir.push(result);
+}
+
+/// Like `binary`, for the comparisons, which give an i1.
+fn compare(ir: &mut Ir, condition: &str) {
+    let b = ir.pop();
+    let a = ir.pop();
+    let result = ir.value(format!("icmp {} i64 {}, {}", condition, a, b));
+    let result = ir.value(format!("zext i1 {} to i64", result));
+    ir.push(result);
+}
+
+/// Like `binary`, with the count masked to 6 bits like on x86, since LLVM
+/// leaves bigger shifts undefined.
+fn shift(ir: &mut Ir, inst: &str) {
+    let b = ir.pop();
+    let a = ir.pop();
+    let b = ir.value(format!("and i64 {}, 63", b));
+    let result = ir.value(format!("{} i64 {}, {}", inst, a, b));
+    ir.push(result);
+}
+
+/// Pop an address, as a pointer to `ty`.
+fn pointer(ir: &mut Ir, ty: &str) -> String {
+    let address = ir.pop();
+    ir.value(format!("inttoptr i64 {} to {}*", address, ty))
+}
+
+pub fn compile(ir: &mut Ir, op: &Op) {
+    ir.comment(format!("-- {} --", name(op)));
+    match op {
+        Op::Add => binary(ir, "add"),
+        Op::Sub => binary(ir, "sub"),
+        Op::Mul => binary(ir, "mul"),
+        Op::Div => binary(ir, "udiv"),
+        Op::Mod => binary(ir, "urem"),
+        Op::DivMod => {
+            let b = ir.pop();
+            let a = ir.pop();
+            let quotient = ir.value(format!("udiv i64 {}, {}", a, b));
+            let remainder = ir.value(format!("urem i64 {}, {}", a, b));
+            ir.push(quotient);
+            ir.push(remainder);
+        }
+        Op::BitwiseAnd => binary(ir, "and"),
+        Op::BitwiseOr => binary(ir, "or"),
+        Op::BitwiseXor => binary(ir, "xor"),
+        Op::BitwiseNot => {
+            let a = ir.pop();
+            let result = ir.value(format!("xor i64 {}, -1", a));
+            ir.push(result);
+        }
+        Op::Shl => shift(ir, "shl"),
+        Op::Shr => shift(ir, "lshr"),
+        Op::Eq => compare(ir, "eq"),
+        Op::Neq => compare(ir, "ne"),
+        Op::Lt => compare(ir, "slt"),
+        Op::Gt => compare(ir, "sgt"),
+        Op::Lte => compare(ir, "sle"),
+        Op::Gte => compare(ir, "sge"),
+        Op::Load => {
+            let ptr = pointer(ir, "i8");
+            let byte = ir.value(format!("load i8, i8* {}", ptr));
+            let result = ir.value(format!("zext i8 {} to i64", byte));
+            ir.push(result);
+        }
+        Op::Load64 => {
+            let ptr = pointer(ir, "i64");
+            let result = ir.value(format!("load i64, i64* {}, align 1", ptr));
+            ir.push(result);
+        }
+        Op::Store => {
+            let value = ir.pop();
+            let ptr = pointer(ir, "i8");
+            let byte = ir.value(format!("trunc i64 {} to i8", value));
+            ir.line(format!("store i8 {}, i8* {}", byte, ptr));
+        }
+        Op::Store64 => {
+            let value = ir.pop();
+            let ptr = pointer(ir, "i64");
+            ir.line(format!("store i64 {}, i64* {}, align 1", value, ptr));
+        }
+    }
+}
+
+fn name(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "add",
+        Op::Sub => "sub",
+        Op::Mul => "mul",
+        Op::Div => "div",
+        Op::Mod => "mod",
+        Op::DivMod => "divmod",
+        Op::BitwiseAnd => "and",
+        Op::BitwiseOr => "or",
+        Op::BitwiseXor => "xor",
+        Op::BitwiseNot => "not",
+        Op::Shl => "shl",
+        Op::Shr => "shr",
+        Op::Eq => "eq",
+        Op::Neq => "neq",
+        Op::Lt => "lt",
+        Op::Gt => "gt",
+        Op::Lte => "lte",
+        Op::Gte => "gte",
+        Op::Load => "load",
+        Op::Store => "store",
+        Op::Load64 => "load64",
+        Op::Store64 => "store64",
+    }
+}
+
+/// Pop the syscall number and `args` arguments, and push the result.
+pub fn syscall(ir: &mut Ir, args: usize) {
+    ir.comment(format!("-- syscall{} --", args));
+    let mut values = vec![ir.pop()];
+    for arg in 0..6 {
+        if arg < args {
+            values.push(ir.pop());
+        } else {
+            values.push("0".to_string());
+        }
+    }
+    let args: Vec<String> = values
+        .iter()
+        .map(|value| format!("i64 {}", value))
+        .collect();
+    let result = ir.value(format!("call i64 @syscall({})", args.join(", ")));
+    ir.push(result);
+}
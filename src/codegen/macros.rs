@@ -1,5 +1,3 @@
-pub use casey::lower;
-
 #[macro_export]
 macro_rules! comment {
     ($asm:ident, $s:expr) => {
@@ -17,6 +15,7 @@ macro_rules! segment {
         $asm.set_insert_segment(match $s {
             "text" => crate::codegen::builder::SegmentKind::Text,
             "data" => crate::codegen::builder::SegmentKind::Data,
+            "rodata" => crate::codegen::builder::SegmentKind::Rodata,
             "bss" => crate::codegen::builder::SegmentKind::Bss,
             s => panic!("Invalid segment {}", s),
         });
@@ -102,6 +101,7 @@ macro_rules! intrinsics {
                 }
             }
 
+            #[allow(clippy::should_implement_trait)]
             pub fn from_str(s: &str) -> Result<Self, String> {
                 use Intrinsic::*;
                 match s {
@@ -0,0 +1,145 @@
+//! The map `--emit-map` writes for an executable, so profilers can tell where
+//! its code came from without debug info.
+//!
+//! It's a JSON array with an object for each symbol in the executable's symbol
+//! table, in address order, covering everything up to the next symbol or the
+//! end of its section. The labels control flow jumps to also have the source
+//! location of their keyword, and the macro that was expanded into it if there
+//! was one, which is `null` for everything else.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Context, Result};
+
+use super::labels::Labels;
+use crate::{
+    error::{Error::IOError, IOError::InvalidElf},
+    instruction::Program,
+    json::Json,
+};
+
+const SHT_SYMTAB: u32 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+struct Symbol {
+    name: String,
+    address: u64,
+    /// Where the symbol's section ends
+    end: u64,
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// The symbols of a little endian ELF64 file defined in one of its sections,
+/// or `None` if it isn't one.
+fn symbols(elf: &[u8]) -> Option<Vec<Symbol>> {
+    if !elf.starts_with(b"\x7fELF\x02\x01") {
+        return None;
+    }
+    let headers = u64_at(elf, 0x28)? as usize;
+    let header_size = u16_at(elf, 0x3a)? as usize;
+    let count = u16_at(elf, 0x3c)? as usize;
+    // The address, offset, size and link of each section
+    let sections = (0..count)
+        .map(|i| {
+            let at = headers + i * header_size;
+            Some((
+                u32_at(elf, at + 4)?,
+                u64_at(elf, at + 16)?,
+                u64_at(elf, at + 24)? as usize,
+                u64_at(elf, at + 32)? as usize,
+                u32_at(elf, at + 40)? as usize,
+            ))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let mut symbols = Vec::new();
+    for &(kind, _, offset, size, link) in &sections {
+        if kind != SHT_SYMTAB {
+            continue;
+        }
+        let (_, _, strings, _, _) = *sections.get(link)?;
+        for at in (offset..offset + size).step_by(24).skip(1) {
+            let info = *elf.get(at + 4)?;
+            let section = u16_at(elf, at + 6)? as usize;
+            let Some(&(_, start, _, section_size, _)) = sections.get(section) else {
+                // Undefined, absolute or common
+                continue;
+            };
+            if section == 0 || matches!(info & 0xf, STT_SECTION | STT_FILE) {
+                continue;
+            }
+            let name = strings + u32_at(elf, at)? as usize;
+            let name = elf.get(name..)?.split(|&b| b == 0).next()?;
+            symbols.push(Symbol {
+                name: String::from_utf8_lossy(name).to_string(),
+                address: u64_at(elf, at + 8)?,
+                end: start + section_size as u64,
+            });
+        }
+    }
+    symbols.sort_by_key(|symbol| symbol.address);
+    Some(symbols)
+}
+
+/// Write the map of `exe`, which was built from `program`, to `path`.
+pub fn write(program: &Program, exe: &Path, path: &Path) -> Result<()> {
+    let elf =
+        std::fs::read(exe).with_context(|| format!("Could not read {}", exe.to_string_lossy()))?;
+    let symbols = symbols(&elf)
+        .ok_or(IOError(InvalidElf))
+        .with_context(|| format!("Could not read the symbols of {}", exe.to_string_lossy()))?;
+
+    // Code expanded from a macro keeps the locations of its body
+    let mut macros = HashMap::new();
+    for macro_ in program.macros.values() {
+        for inst in &macro_.body {
+            macros.insert(&inst.loc, macro_.name.as_str());
+        }
+    }
+    let labels = Labels::new(program);
+    let labels: HashMap<&str, usize> = labels.iter().map(|(ip, name)| (name, ip)).collect();
+
+    let mut entries = Vec::new();
+    for (i, symbol) in symbols.iter().enumerate() {
+        let end = match symbols.get(i + 1) {
+            Some(next) if next.address < symbol.end => next.address,
+            _ => symbol.end,
+        };
+        let (source, macro_) = match labels.get(symbol.name.as_str()) {
+            Some(&ip) => {
+                let loc = &program.instructions[ip].loc;
+                let source = Json::object([
+                    ("file", Json::String(loc.0.clone())),
+                    ("line", Json::Number(loc.1 as f64)),
+                    ("col", Json::Number(loc.2 as f64)),
+                ]);
+                let macro_ = macros.get(loc).map(|name| Json::String(name.to_string()));
+                (source, macro_.unwrap_or(Json::Null))
+            }
+            None => (Json::Null, Json::Null),
+        };
+        let entry = Json::object([
+            ("label", Json::String(symbol.name.clone())),
+            ("start", Json::Number(symbol.address as f64)),
+            ("end", Json::Number(end as f64)),
+            ("source", source),
+            ("macro", macro_),
+        ]);
+        entries.push(entry.to_string());
+    }
+    // A label a line, so the map diffs well too
+    let map = format!("[\n{}\n]\n", entries.join(",\n"));
+    std::fs::write(path, map)
+        .with_context(|| format!("Could not write the map to {}", path.to_string_lossy()))
+}
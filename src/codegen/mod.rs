@@ -1,9 +1,27 @@
+mod aarch64;
+mod assembler;
+mod att;
 mod builder;
+mod c;
+mod cache;
+mod checked;
 mod compile;
+mod darwin;
+mod instrument;
 pub mod intrinsics;
+mod labels;
+mod llvm;
 mod macros;
+mod map;
 mod ops;
+mod peephole;
+mod riscv64;
+mod source;
 mod syscalls;
+mod target;
+mod wasm32;
+mod windows;
 
-pub use compile::compile;
 pub use compile::BSS_CAPACITY;
+pub use compile::{compile, size, Size};
+pub use target::{Arch, Os};
@@ -1,7 +1,10 @@
-use crate::{asm, asm_line, comment};
+use crate::{asm, asm_line, comment, instruction::Extern};
 
 use super::builder::Builder;
 
+/// Where the SysV ABI takes integer arguments, first to last
+const ARGUMENT_REGISTERS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+
 pub fn add(asm: &mut Builder) {
     comment!(asm, "-- add --");
     asm!(
@@ -76,7 +79,7 @@ pub fn divmod(asm: &mut Builder) {
 
 pub fn not(asm: &mut Builder) {
     comment!(asm, "-- not --");
-    asm!(asm, ("pop", "rax"), ("neg", "rax"), ("push", "rax"));
+    asm!(asm, ("pop", "rax"), ("not", "rax"), ("push", "rax"));
 }
 
 pub fn band(asm: &mut Builder) {
@@ -370,3 +373,24 @@ pub fn syscall6(asm: &mut Builder) {
         ("push", "rax")
     );
 }
+
+/// Call a C function, popping its arguments like a syscall's.
+pub fn call_extern(asm: &mut Builder, declaration: &Extern) {
+    comment!(asm, &format!("-- extern: {} --", declaration.name));
+    for reg in &ARGUMENT_REGISTERS[..declaration.args] {
+        asm!(asm, ("pop", "{}", reg));
+    }
+    asm!(
+        asm,
+        /// rbx is callee saved, so it keeps the stack pointer to go back to
+        ("mov", "rbx, rsp"),
+        ("and", "rsp, -16"),
+        /// No vector registers are used, for variadic functions
+        ("xor", "eax, eax"),
+        ("call", "{}", declaration.name),
+        ("mov", "rsp, rbx")
+    );
+    if declaration.returns {
+        asm!(asm, ("push", "rax"));
+    }
+}
@@ -0,0 +1,614 @@
+//! Cleanups on the generated text segment, run before it's written out.
+//!
+//! Every op pops its inputs and pushes its result, so most of what this does is
+//! turning a push and the pop that takes it back off into a `mov`. It relies on
+//! the codegen never leaving a value in a register across a label or jump, so
+//! anything but `rsp` and `rbp` is dead there. Calls and syscalls read
+//! registers, so they stop everything else. Jumps that land on another jump
+//! are sent straight to where it goes, and ones that go nowhere are removed.
+
+use std::collections::HashMap;
+
+const SCRATCH: [&str; 14] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+
+/// Instructions that only touch the registers and memory they name.
+const PLAIN: [&str; 14] = [
+    "mov", "movzx", "lea", "add", "sub", "and", "or", "xor", "cmp", "test", "neg", "not", "shl",
+    "shr",
+];
+
+/// Each condition the comparisons use and its opposite.
+const CONDITIONS: [(&str, &str); 12] = [
+    ("e", "ne"),
+    ("ne", "e"),
+    ("z", "nz"),
+    ("nz", "z"),
+    ("l", "ge"),
+    ("ge", "l"),
+    ("g", "le"),
+    ("le", "g"),
+    ("b", "ae"),
+    ("ae", "b"),
+    ("a", "be"),
+    ("be", "a"),
+];
+
+#[derive(Debug, Clone)]
+pub(super) enum Line {
+    /// Comments and blank lines
+    Comment(String),
+    Inst {
+        op: String,
+        args: Vec<String>,
+        comment: Option<String>,
+        text: String,
+    },
+    /// Labels and directives, which code can't be moved across
+    Other(String),
+    /// Where the source of the lines after it is, for debug info. Code moves
+    /// across it like a comment.
+    Loc(String),
+}
+
+impl Line {
+    pub(super) fn parse(text: String) -> Line {
+        let trimmed = text.trim();
+        if trimmed.is_empty() || trimmed.starts_with(";;") {
+            return Line::Comment(text);
+        }
+        if trimmed.starts_with("%line ") {
+            return Line::Loc(text);
+        }
+        if !text.starts_with(' ') {
+            return Line::Other(text);
+        }
+        let (code, comment) = match trimmed.split_once(";;") {
+            Some((code, comment)) => (code.trim(), Some(comment.trim().to_string())),
+            None => (trimmed, None),
+        };
+        let (op, args) = code.split_once(' ').unwrap_or((code, ""));
+        let args = args
+            .split(',')
+            .map(str::trim)
+            .filter(|arg| !arg.is_empty())
+            .map(str::to_string)
+            .collect();
+        Line::Inst {
+            op: op.to_string(),
+            args,
+            comment,
+            text,
+        }
+    }
+
+    pub(super) fn inst(op: &str, args: &[&str], comment: Option<String>) -> Line {
+        let args = args.join(", ");
+        let text = match &comment {
+            Some(comment) => format!("{0:4}{1:8}{2:28};; {3}", " ", op, args, comment),
+            None => format!("{0:4}{1:8}{2}", " ", op, args),
+        };
+        Line::parse(text)
+    }
+
+    /// The op and its arguments, or `None` for anything that isn't an instruction.
+    pub(super) fn op(&self) -> Option<(&str, Vec<&str>)> {
+        match self {
+            Line::Inst { op, args, .. } => Some((op, args.iter().map(String::as_str).collect())),
+            _ => None,
+        }
+    }
+
+    /// The file and line a `Loc` points to.
+    pub(super) fn loc(&self) -> Option<(&str, usize)> {
+        let Line::Loc(text) = self else { return None };
+        let (line, file) = text.trim().strip_prefix("%line ")?.split_once(' ')?;
+        let (line, _) = line.split_once('+').unwrap_or((line, ""));
+        Some((file, line.parse().ok()?))
+    }
+
+    pub(super) fn comment(&self) -> Option<String> {
+        match self {
+            Line::Inst { comment, .. } => comment.clone(),
+            _ => None,
+        }
+    }
+
+    /// The name of the label this defines, if it's a label.
+    fn label(&self) -> Option<&str> {
+        let Line::Other(text) = self else { return None };
+        let name = text.trim().strip_suffix(':')?;
+        (!name.contains(char::is_whitespace)).then_some(name)
+    }
+
+    /// The op and label of a direct jump, conditional or not.
+    fn jump(&self) -> Option<(&str, &str)> {
+        match self.op()? {
+            (op, args) if args.len() == 1 && (op == "jmp" || negate(op).is_some()) => {
+                Some((op, args[0]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether this is an instruction that can be moved past.
+    fn is_plain(&self) -> bool {
+        match self.op() {
+            Some((op, args)) => {
+                (PLAIN.contains(&op) || op.starts_with("cmov"))
+                    && !args.iter().any(|arg| mentions(arg, "rsp"))
+            }
+            None => false,
+        }
+    }
+
+    pub(super) fn text(self) -> String {
+        match self {
+            Line::Comment(text) | Line::Other(text) | Line::Loc(text) | Line::Inst { text, .. } => {
+                text
+            }
+        }
+    }
+}
+
+/// The 64 bit register that `name` is part of.
+pub(super) fn family(name: &str) -> Option<&'static str> {
+    let base = match name {
+        "rax" | "eax" | "ax" | "al" | "ah" => "rax",
+        "rbx" | "ebx" | "bx" | "bl" | "bh" => "rbx",
+        "rcx" | "ecx" | "cx" | "cl" | "ch" => "rcx",
+        "rdx" | "edx" | "dx" | "dl" | "dh" => "rdx",
+        "rsi" | "esi" | "si" | "sil" => "rsi",
+        "rdi" | "edi" | "di" | "dil" => "rdi",
+        "rbp" | "ebp" | "bp" | "bpl" => "rbp",
+        "rsp" | "esp" | "sp" | "spl" => "rsp",
+        // r8 to r15, and their r8d, r8w and r8b parts
+        _ => {
+            let name = name.trim_end_matches(['d', 'w', 'b']);
+            return SCRATCH[6..].iter().copied().find(|&r| r == name);
+        }
+    };
+    Some(base)
+}
+
+/// Whether `arg` uses any part of the register `reg`.
+fn mentions(arg: &str, reg: &str) -> bool {
+    arg.split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| family(word) == Some(reg))
+}
+
+/// The registers `arg` uses.
+fn registers(arg: &str) -> Vec<&'static str> {
+    arg.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter_map(family)
+        .collect()
+}
+
+/// Whether `arg` is a whole register, so writing it replaces the old value.
+fn is_full(arg: &str) -> bool {
+    SCRATCH.contains(&arg) || (arg.starts_with('e') && family(arg).is_some() && arg.len() == 3)
+}
+
+/// Whether `line` reads `reg`.
+fn reads(line: &Line, reg: &str) -> bool {
+    match line.op() {
+        Some(("mov" | "movzx" | "lea", args)) if args.len() == 2 && is_full(args[0]) => {
+            mentions(args[1], reg)
+        }
+        Some((_, args)) => args.iter().any(|arg| mentions(arg, reg)),
+        None => false,
+    }
+}
+
+/// Whether `line` replaces the whole of `reg` without reading it.
+fn overwrites(line: &Line, reg: &str) -> bool {
+    match line.op() {
+        Some(("mov" | "movzx" | "lea", args)) if args.len() == 2 => {
+            is_full(args[0]) && family(args[0]) == Some(reg) && !mentions(args[1], reg)
+        }
+        Some(("pop", args)) => args.len() == 1 && is_full(args[0]) && family(args[0]) == Some(reg),
+        _ => false,
+    }
+}
+
+/// Whether the value in `reg` no longer matters from `lines[start]` on.
+fn dead_from(lines: &[Line], start: usize, reg: &str) -> bool {
+    if !SCRATCH.contains(&reg) {
+        return false;
+    }
+    for line in &lines[start..] {
+        match line {
+            Line::Comment(_) | Line::Loc(_) => continue,
+            Line::Other(_) => return true,
+            Line::Inst { .. } => {}
+        }
+        let Some((op, _)) = line.op() else { continue };
+        if op.starts_with('j') {
+            return true;
+        }
+        if !line.is_plain() && !matches!(op, "push" | "pop") {
+            return false;
+        }
+        if overwrites(line, reg) {
+            return true;
+        }
+        if reads(line, reg) {
+            return false;
+        }
+    }
+    false
+}
+
+/// The instructions before `end`, nearest first, skipping comments.
+fn before(lines: &[Line], end: usize) -> impl Iterator<Item = usize> + '_ {
+    (0..end)
+        .rev()
+        .filter(|&i| !matches!(lines[i], Line::Comment(_) | Line::Loc(_)))
+}
+
+/// The instructions from `start`, skipping comments.
+fn after(lines: &[Line], start: usize) -> impl Iterator<Item = usize> + '_ {
+    (start..lines.len()).filter(|&i| !matches!(lines[i], Line::Comment(_) | Line::Loc(_)))
+}
+
+/// The conditional jump taken when `jump` isn't.
+fn negate(jump: &str) -> Option<String> {
+    let cc = jump.strip_prefix('j')?;
+    let (_, negated) = CONDITIONS.iter().find(|(c, _)| *c == cc)?;
+    Some(format!("j{}", negated))
+}
+
+/// Run every pass over `lines` until none of them find anything else to do.
+pub fn optimize(lines: &mut Vec<String>) {
+    let mut code: Vec<Line> = lines.drain(..).map(Line::parse).collect();
+    loop {
+        let len = code.len();
+        code = pair_push_pop(code);
+        fuse_branches(&mut code);
+        remove_dead_movs(&mut code);
+        merge_movs(&mut code);
+        remove_repeated_tests(&mut code);
+        thread_jumps(&mut code);
+        remove_redundant_jumps(&mut code);
+        if code.len() == len {
+            break;
+        }
+    }
+    lines.extend(code.into_iter().map(Line::text));
+}
+
+/// `push x ... pop r` becomes `mov r, x` where the pop is, as long as nothing in
+/// between touches the stack, `r`, or the registers in `x`.
+fn pair_push_pop(lines: Vec<Line>) -> Vec<Line> {
+    let mut out: Vec<Line> = Vec::with_capacity(lines.len());
+    for line in lines {
+        let target = match line.op() {
+            Some(("pop", args)) if args.len() == 1 && SCRATCH.contains(&args[0]) => {
+                args[0].to_string()
+            }
+            _ => {
+                out.push(line);
+                continue;
+            }
+        };
+        let mut between = Vec::new();
+        let mut source = None;
+        for i in before(&out, out.len()) {
+            match out[i].op() {
+                Some(("push", args)) if args.len() == 1 => {
+                    let arg = args[0];
+                    let used = registers(arg);
+                    let clear = !used.contains(&"rsp")
+                        && (between.is_empty() || !arg.contains('['))
+                        && between
+                            .iter()
+                            .all(|&j| used.iter().all(|reg| !mentions_any(&out[j], reg)));
+                    if clear {
+                        source = Some((i, arg.to_string()));
+                    }
+                    break;
+                }
+                _ if out[i].is_plain() && !mentions_any(&out[i], &target) => between.push(i),
+                _ => break,
+            }
+        }
+        match source {
+            Some((i, source)) => {
+                out.remove(i);
+                if source != target {
+                    out.push(Line::inst("mov", &[&target, &source], line.comment()));
+                }
+            }
+            None => out.push(line),
+        }
+    }
+    out
+}
+
+fn mentions_any(line: &Line, reg: &str) -> bool {
+    line.op()
+        .is_some_and(|(_, args)| args.iter().any(|arg| mentions(arg, reg)))
+}
+
+/// A comparison materialized as a bool and then tested by `do` becomes a
+/// conditional jump on the comparison itself:
+///
+/// ```text
+/// mov rcx, 0; mov rdx, 1; cmp a, b; cmovl rcx, rdx; mov rax, rcx; test rax, rax; jz end
+/// ```
+///
+/// becomes `cmp a, b; jge end`.
+fn fuse_branches(lines: &mut Vec<Line>) {
+    let mut i = 0;
+    while i < lines.len() {
+        if let Some(removed) = fuse_branch(lines, i) {
+            i -= removed.min(i);
+        }
+        i += 1;
+    }
+}
+
+fn fuse_branch(lines: &mut Vec<Line>, jump: usize) -> Option<usize> {
+    let Some(("jz", args)) = lines[jump].op() else {
+        return None;
+    };
+    let label = args.first()?.to_string();
+    let prev: Vec<usize> = before(lines, jump).take(3).collect();
+    let mut prev = prev.into_iter();
+    let test = prev.next()?;
+    let tested = match lines[test].op() {
+        Some(("test", args)) if args.len() == 2 && args[0] == args[1] => args[0].to_string(),
+        _ => return None,
+    };
+    // `do` tests a copy of the bool when it was pushed and popped
+    let mut cmov = prev.next()?;
+    let mut copy = None;
+    if let Some(("mov", args)) = lines[cmov].op() {
+        if args.len() == 2 && args[0] == tested {
+            copy = Some((cmov, args[1].to_string()));
+            cmov = prev.next()?;
+        }
+    }
+    let (flag, result, one) = match lines[cmov].op() {
+        Some((op, args)) if args.len() == 2 && op.starts_with("cmov") => (
+            op[4..].to_string(),
+            args[0].to_string(),
+            args[1].to_string(),
+        ),
+        _ => return None,
+    };
+    let copied = copy.as_ref().map_or(&tested, |(_, copied)| copied);
+    let registers = [&tested, &result, &one];
+    if *copied != result || !registers.iter().all(|reg| SCRATCH.contains(&reg.as_str())) {
+        return None;
+    }
+    let negated = CONDITIONS.iter().find(|(cc, _)| *cc == flag)?.1;
+    // The zero and one the bool is picked from, set before the cmp
+    let mut zero = None;
+    let mut set_one = None;
+    for i in before(lines, cmov) {
+        match lines[i].op() {
+            Some(("mov", args)) if args == [result.as_str(), "0"] && zero.is_none() => {
+                zero = Some(i)
+            }
+            Some(("mov", args)) if args == [one.as_str(), "1"] && set_one.is_none() => {
+                set_one = Some(i)
+            }
+            _ if lines[i].is_plain()
+                && !mentions_any(&lines[i], &result)
+                && !mentions_any(&lines[i], &one) => {}
+            _ => break,
+        }
+        if zero.is_some() && set_one.is_some() {
+            break;
+        }
+    }
+    let (zero, set_one) = (zero?, set_one?);
+    if !registers.iter().all(|reg| dead_from(lines, jump + 1, reg)) {
+        return None;
+    }
+    let branch = Line::inst(&format!("j{}", negated), &[&label], lines[jump].comment());
+    lines[jump] = branch;
+    let mut removed = vec![zero, set_one, cmov, test];
+    removed.extend(copy.map(|(copy, _)| copy));
+    removed.sort_unstable();
+    for &i in removed.iter().rev() {
+        lines.remove(i);
+    }
+    Some(removed.len())
+}
+
+/// A `mov` into a register that's overwritten before anything reads it, or
+/// that copies a register onto itself.
+fn remove_dead_movs(lines: &mut Vec<Line>) {
+    let mut i = 0;
+    while i < lines.len() {
+        let dead = match lines[i].op() {
+            Some(("mov", args)) if args.len() == 2 && args[0] == args[1] => {
+                SCRATCH.contains(&args[0])
+            }
+            Some(("mov", args)) if args.len() == 2 && is_full(args[0]) => {
+                let reg = family(args[0]).unwrap_or("rsp");
+                dead_from(lines, i + 1, reg)
+            }
+            _ => false,
+        };
+        if dead {
+            lines.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// `mov r, x` and something later that only copies `r` somewhere else, when
+/// `x` doesn't change in between and `r` isn't needed after that:
+///
+/// - `mov r, x; ...; mov s, r` becomes `...; mov s, x`
+/// - `mov r, x; ...; push r` becomes `...; push x`, if `x` fits in a push
+fn merge_movs(lines: &mut Vec<Line>) {
+    let mut i = 0;
+    while i < lines.len() {
+        match merge_mov(lines, i) {
+            Some((next, merged)) => {
+                lines[next] = merged;
+                lines.remove(i);
+            }
+            None => i += 1,
+        }
+    }
+}
+
+fn merge_mov(lines: &[Line], i: usize) -> Option<(usize, Line)> {
+    let Some(("mov", args)) = lines[i].op() else {
+        return None;
+    };
+    let (reg, source) = match args[..] {
+        [reg, source] if SCRATCH.contains(&reg) => (reg, source),
+        _ => return None,
+    };
+    let used = registers(source);
+    if used.contains(&"rsp") {
+        return None;
+    }
+    let mut next = None;
+    for j in after(lines, i + 1) {
+        if mentions_any(&lines[j], reg) {
+            next = Some(j);
+            break;
+        }
+        let movable = lines[j].is_plain() || matches!(lines[j].op(), Some(("push" | "pop", _)));
+        if !movable || source.contains('[') || used.iter().any(|r| mentions_any(&lines[j], r)) {
+            return None;
+        }
+    }
+    let next = next?;
+    let comment = lines[next].comment().or(lines[i].comment());
+    let merged = match lines[next].op() {
+        Some(("mov", args)) if args.len() == 2 && args[1] == reg && SCRATCH.contains(&args[0]) => {
+            Line::inst("mov", &[args[0], source], comment)
+        }
+        Some(("push", args))
+            if args == [reg]
+                && (SCRATCH.contains(&source)
+                    || source
+                        .parse::<i64>()
+                        .is_ok_and(|n| i32::try_from(n).is_ok())) =>
+        {
+            Line::inst("push", &[source], comment)
+        }
+        _ => return None,
+    };
+    if !dead_from(lines, next + 1, reg) {
+        return None;
+    }
+    Some((next, merged))
+}
+
+/// A `cmp` or `test` straight after the same one, which sets the same flags.
+fn remove_repeated_tests(lines: &mut Vec<Line>) {
+    let mut i = 0;
+    while i < lines.len() {
+        let repeated = match lines[i].op() {
+            Some((op @ ("cmp" | "test"), args)) => before(lines, i)
+                .next()
+                .and_then(|prev| lines[prev].op())
+                .is_some_and(|(prev_op, prev_args)| prev_op == op && prev_args == args),
+            _ => false,
+        };
+        if repeated {
+            lines.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// The label of the `jmp` that's the first thing after `label`, if it is one.
+/// Local labels depend on the label before them, so they're left alone.
+fn jump_after(lines: &[Line], labels: &HashMap<String, usize>, label: &str) -> Option<String> {
+    for i in after(lines, labels.get(label)? + 1) {
+        match lines[i].jump() {
+            Some(("jmp", target)) if labels.contains_key(target) => {
+                return Some(target.to_string())
+            }
+            _ if lines[i].label().is_some() => continue,
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// A jump to a `jmp` goes straight to where that one does, like the jump from
+/// an else block to the end of an if that's the end of another block.
+fn thread_jumps(lines: &mut [Line]) {
+    let labels: HashMap<String, usize> = (0..lines.len())
+        .filter_map(|i| lines[i].label().map(|label| (label.to_string(), i)))
+        .filter(|(label, _)| !label.starts_with('.'))
+        .collect();
+    for i in 0..lines.len() {
+        let Some((op, label)) = lines[i].jump() else {
+            continue;
+        };
+        let mut target = label.to_string();
+        let mut seen = vec![target.clone()];
+        while let Some(next) = jump_after(lines, &labels, &target) {
+            // A loop of jumps never gets anywhere, so it's left as it is
+            if seen.contains(&next) {
+                break;
+            }
+            seen.push(next.clone());
+            target = next;
+        }
+        if target != label {
+            lines[i] = Line::inst(op, &[&target], lines[i].comment());
+        }
+    }
+}
+
+/// Whether `label` is reached from `lines[start]` without running anything.
+fn falls_to(lines: &[Line], start: usize, label: &str) -> bool {
+    for i in after(lines, start) {
+        match lines[i].label() {
+            Some(name) if name == label => return true,
+            // Local labels after it belong to a different one
+            Some(name) if !name.starts_with('.') && label.starts_with('.') => return false,
+            Some(_) => continue,
+            None => return false,
+        }
+    }
+    false
+}
+
+/// A jump to where the code after it is anyway is removed, and a conditional
+/// jump over a `jmp` is turned around to take its place:
+///
+/// - `jmp end; end:` becomes `end:`
+/// - `jz else; jmp end; else:` becomes `jnz end; else:`
+fn remove_redundant_jumps(lines: &mut Vec<Line>) {
+    let mut i = 0;
+    while i < lines.len() {
+        let Some((op, label)) = lines[i].jump() else {
+            i += 1;
+            continue;
+        };
+        if falls_to(lines, i + 1, label) {
+            lines.remove(i);
+            continue;
+        }
+        let next = after(lines, i + 1).next();
+        let over = next.and_then(|next| match lines[next].jump() {
+            Some(("jmp", target)) if falls_to(lines, next + 1, label) => {
+                Some((next, target.to_string()))
+            }
+            _ => None,
+        });
+        if let (Some((next, target)), Some(negated)) = (over, negate(op)) {
+            let comment = lines[next].comment().or(lines[i].comment());
+            lines[i] = Line::inst(&negated, &[&target], comment);
+            lines.remove(next);
+        }
+        i += 1;
+    }
+}
@@ -0,0 +1,115 @@
+use crate::codegen::intrinsics::Intrinsic;
+use crate::{asm, asm_line, comment, label};
+
+use super::{exit, pop, push, Builder};
+
+pub fn compile(asm: &mut Builder, intrinsic: &Intrinsic) {
+    match intrinsic {
+        Intrinsic::Print => {
+            pop(asm, "a0");
+            asm!(asm, ("call", "intrinsic_print"));
+        }
+        Intrinsic::Panic => exit(asm, 1),
+        Intrinsic::Dup => {
+            pop(asm, "t0");
+            push(asm, "t0");
+            push(asm, "t0");
+        }
+        Intrinsic::Dup2 => {
+            pop(asm, "t1");
+            pop(asm, "t0");
+            for reg in ["t0", "t1", "t0", "t1"] {
+                push(asm, reg);
+            }
+        }
+        Intrinsic::Swap => {
+            pop(asm, "t1");
+            pop(asm, "t0");
+            push(asm, "t1");
+            push(asm, "t0");
+        }
+        Intrinsic::Over => {
+            pop(asm, "t1");
+            pop(asm, "t0");
+            for reg in ["t0", "t1", "t0"] {
+                push(asm, reg);
+            }
+        }
+        Intrinsic::Drop => asm!(asm, ("addi", "s11, s11, 8")),
+        Intrinsic::Drop2 => asm!(asm, ("addi", "s11, s11, 16")),
+        Intrinsic::Mem => {
+            asm!(asm, ("la", "t0, mem"));
+            push(asm, "t0");
+        }
+        Intrinsic::Argc => {
+            asm!(
+                asm,
+                ("la", "t0, args_ptr"),
+                ("ld", "t0, 0(t0)"),
+                ("ld", "t0, 0(t0)")
+            );
+            push(asm, "t0");
+        }
+        Intrinsic::Argv => {
+            asm!(
+                asm,
+                ("la", "t0, args_ptr"),
+                ("ld", "t0, 0(t0)"),
+                ("addi", "t0, t0, 8")
+            );
+            push(asm, "t0");
+        }
+        Intrinsic::CastPtr => {
+            comment!(asm, "-- Cast to Pointer --");
+        }
+        Intrinsic::CastInt => {
+            comment!(asm, "-- Cast to Int --");
+        }
+        Intrinsic::Align => {
+            pop(asm, "t1");
+            pop(asm, "t0");
+            asm!(
+                asm,
+                ("add", "t0, t0, t1"),
+                ("addi", "t0, t0, -1"),
+                ("neg", "t1, t1"),
+                ("and", "t0, t0, t1")
+            );
+            push(asm, "t0");
+        }
+        Intrinsic::Here => {
+            comment!(asm, "-- {} --", asm.tmp_here);
+        }
+    }
+}
+
+/// `intrinsic_print`, which prints `a0` as an unsigned number and a newline.
+pub fn gen_print(asm: &mut Builder) {
+    label!(asm, "intrinsic_print");
+    asm!(
+        asm,
+        ("la", "t0, print_buf"),
+        /// Digits are written backwards from the end of the buffer
+        ("addi", "t0, t0, 31"),
+        ("li", "t1, 10"),
+        ("sb", "t1, 0(t0)"),
+        ("li", "a2, 1")
+    );
+    label!(asm, ".Lintrinsic_print_body");
+    asm!(
+        asm,
+        ("remu", "t2, a0, t1"),
+        ("divu", "a0, a0, t1"),
+        ("addi", "t2, t2, 48"),
+        ("addi", "t0, t0, -1"),
+        ("sb", "t2, 0(t0)"),
+        ("addi", "a2, a2, 1"),
+        ("bnez", "a0, .Lintrinsic_print_body"),
+        ("mv", "a1, t0"),
+        ("li", "a0, 1"),
+        /// write
+        ("li", "a7, 64"),
+        ("ecall"),
+        ("ret")
+    );
+}
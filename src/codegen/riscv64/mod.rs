@@ -0,0 +1,307 @@
+//! Code generation for 64 bit RISC-V Linux, written for GNU as.
+//!
+//! Like on arm, the porth stack gets its own region of the bss, here with `s11`
+//! pointing at the top value. Syscalls take their number in `a7` and arguments
+//! in `a0` to `a5`, and go through `do_syscall` to be translated with the same
+//! table, since both use the generic Linux numbers.
+
+mod intrinsics;
+mod ops;
+
+use anyhow::{Context, Result};
+
+use super::aarch64::{syscall_table, AT_FDCWD, FORK, STACK_CAPACITY, UNSUPPORTED};
+use super::builder::Builder;
+use super::labels::Labels;
+use super::source::Source;
+use crate::{
+    asm, asm_line, comment, err,
+    error::{CompileError::*, Error::CompileError},
+    instruction::*,
+    label, segment,
+};
+
+pub fn push(asm: &mut Builder, reg: &str) {
+    asm!(asm, ("addi", "s11, s11, -8"), ("sd", "{}, 0(s11)", reg));
+}
+
+pub fn pop(asm: &mut Builder, reg: &str) {
+    asm!(asm, ("ld", "{}, 0(s11)", reg), ("addi", "s11, s11, 8"));
+}
+
+pub fn exit(asm: &mut Builder, code: i64) {
+    comment!(asm, "-- exit {} --", code);
+    asm!(asm, ("li", "a0, {}", code), ("li", "a7, 93"), ("ecall"));
+}
+
+pub fn generate(
+    program: &Program,
+    mem_capacity: usize,
+    mut source: Option<Source>,
+) -> Result<Builder> {
+    let mut asm = Builder::gas("#");
+    comment!(asm, "-- generated by the worth compiler --");
+
+    segment!(asm, "bss");
+    asm!(asm, (".balign", "{}", MEM_ALIGN));
+    label!(asm, "mem");
+    asm!(asm, (".skip", "{}", mem_capacity));
+    asm!(asm, (".balign", "{}", MEM_ALIGN));
+    for memory in &program.memories {
+        label!(asm, "{}", memory.label());
+        asm!(asm, (".skip", "{}", memory.reserved()));
+    }
+    label!(asm, "args_ptr");
+    asm!(asm, (".skip", "8"));
+    label!(asm, "print_buf");
+    asm!(asm, (".skip", "32"));
+    label!(asm, "stack");
+    asm!(asm, (".skip", "{}", STACK_CAPACITY));
+    label!(asm, "stack_end");
+
+    segment!(asm, "text");
+    asm!(asm, (".globl", "_start"));
+    label!(asm, "_start");
+    asm!(
+        asm,
+        ("la", "t0, args_ptr"),
+        /// Save the stack pointer for argc and argv intrinsics
+        ("sd", "sp, 0(t0)"),
+        ("la", "s11, stack_end")
+    );
+
+    let labels = Labels::new(program);
+    for (ip, inst) in program.instructions.iter().enumerate() {
+        asm.tmp_here =
+            inst.loc.0.clone() + ":" + &inst.loc.1.to_string() + ":" + &inst.loc.2.to_string();
+        if let Some(line) = source.as_mut().and_then(|source| source.line(&inst.loc)) {
+            comment!(asm, line);
+        }
+        match &inst.kind {
+            InstructionKind::Push(val) => {
+                let value = match val {
+                    Value::Int(i) => *i,
+                    Value::Bool(b) => *b as i64,
+                    Value::Char(c) => *c as i64,
+                    Value::Ptr(name) => err!(
+                        program,
+                        CompileError(UnexpectedToken(name.clone())),
+                        format!("Pointer to {} should be resolved before codegen", name),
+                        ip
+                    ),
+                    Value::Str(s) => {
+                        let s_id = asm.new_const_str(s);
+                        asm!(asm, ("li", "t0, {}", s.len()));
+                        push(&mut asm, "t0");
+                        asm!(asm, ("la", "t0, const_str_{}", s_id));
+                        push(&mut asm, "t0");
+                        continue;
+                    }
+                };
+                asm!(asm, ("li", "t0, {}", value));
+                push(&mut asm, "t0");
+            }
+            InstructionKind::Intrinsic(intrinsic) => {
+                comment!(
+                    asm,
+                    &format!("-- intrinsic: {} --", intrinsic.to_string().to_lowercase())
+                );
+                intrinsics::compile(&mut asm, intrinsic);
+                comment!(asm, "-- end intrinsic --");
+            }
+            InstructionKind::Keyword(Keyword::While { self_ip, .. }) => {
+                comment!(asm, "-- while --");
+                label!(asm, labels.get(*self_ip));
+            }
+            InstructionKind::Keyword(Keyword::Do { end_ip }) => {
+                pop(&mut asm, "t0");
+                // Branches only reach 4KiB, so they skip over a jump instead
+                asm!(
+                    asm,
+                    ("bnez", "t0, .Ldo_{}", ip),
+                    /// Jump to the end of the block
+                    ("j", "{}", labels.get(*end_ip))
+                );
+                label!(asm, ".Ldo_{}", ip);
+                comment!(asm, "-- do --");
+            }
+            InstructionKind::Keyword(Keyword::If) => {
+                comment!(asm, "-- if --");
+            }
+            InstructionKind::Keyword(Keyword::Unsafe) => {
+                comment!(asm, "-- unsafe --");
+            }
+            InstructionKind::Keyword(
+                keyword @ (Keyword::Elif { self_ip, end_ip } | Keyword::Else { self_ip, end_ip }),
+            ) => {
+                comment!(asm, "-- {} --", keyword);
+                asm!(
+                    asm,
+                    /// Jump to the end of the if statement
+                    ("j", "{}", labels.get(*end_ip))
+                );
+                label!(asm, labels.get(*self_ip));
+            }
+            InstructionKind::Keyword(Keyword::End { self_ip, while_ip }) => {
+                comment!(asm, "-- end --");
+                if let Some(while_ip) = while_ip {
+                    asm!(
+                        asm,
+                        /// Jump to while statement
+                        ("j", "{}", labels.get(*while_ip))
+                    );
+                }
+                label!(asm, labels.get(*self_ip));
+            }
+            InstructionKind::Op(op) => match op {
+                Op::Add => ops::add(&mut asm),
+                Op::Sub => ops::sub(&mut asm),
+                Op::Mul => ops::mul(&mut asm),
+                Op::Div => ops::div(&mut asm),
+                Op::Mod => ops::mod_(&mut asm),
+                Op::DivMod => ops::divmod(&mut asm),
+                Op::BitwiseAnd => ops::band(&mut asm),
+                Op::BitwiseOr => ops::bor(&mut asm),
+                Op::BitwiseXor => ops::xor(&mut asm),
+                Op::BitwiseNot => ops::not(&mut asm),
+                Op::Shl => ops::shl(&mut asm),
+                Op::Shr => ops::shr(&mut asm),
+                Op::Eq => ops::eq(&mut asm),
+                Op::Neq => ops::neq(&mut asm),
+                Op::Lt => ops::lt(&mut asm),
+                Op::Gt => ops::gt(&mut asm),
+                Op::Lte => ops::lte(&mut asm),
+                Op::Gte => ops::gte(&mut asm),
+                Op::Load => ops::load(&mut asm),
+                Op::Store => ops::store(&mut asm),
+                Op::Load64 => ops::load64(&mut asm),
+                Op::Store64 => ops::store64(&mut asm),
+            },
+            InstructionKind::Syscall(kind) => ops::syscall(&mut asm, syscall_args(kind)),
+            InstructionKind::Keyword(Keyword::Include) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("include".into())),
+                    "Include should be expanded before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Macro) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("macro".into())),
+                    "Macro should be expanded before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Extern) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("extern".into())),
+                    "Extern should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Memory) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("memory".into())),
+                    "Memory should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Memory(memory) => {
+                asm!(asm, ("la", "t0, {}", memory.label()));
+                push(&mut asm, "t0");
+            }
+            InstructionKind::Extern(_) => {
+                unreachable!("compile only lets externs through for native x86_64-linux")
+            }
+            InstructionKind::Name(name) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken(name.clone())),
+                    format!("Name {} should be resolved before codegen", name),
+                    ip
+                )
+            }
+        }
+    }
+
+    exit(&mut asm, 0);
+
+    intrinsics::gen_print(&mut asm);
+    gen_syscall(&mut asm);
+
+    Ok(asm)
+}
+
+fn syscall_args(kind: &SyscallKind) -> usize {
+    match kind {
+        SyscallKind::Syscall0 => 0,
+        SyscallKind::Syscall1 => 1,
+        SyscallKind::Syscall2 => 2,
+        SyscallKind::Syscall3 => 3,
+        SyscallKind::Syscall4 => 4,
+        SyscallKind::Syscall5 => 5,
+        SyscallKind::Syscall6 => 6,
+    }
+}
+
+/// `do_syscall` and the table it translates numbers with.
+fn gen_syscall(asm: &mut Builder) {
+    let table = syscall_table();
+
+    label!(asm, "do_syscall");
+    asm!(
+        asm,
+        ("li", "t0, {}", table.len()),
+        ("bgeu", "a7, t0, .Lsyscall_unsupported"),
+        ("la", "t1, syscall_table"),
+        ("slli", "t0, a7, 1"),
+        ("add", "t1, t1, t0"),
+        ("lhu", "t1, 0(t1)"),
+        ("li", "t0, {}", UNSUPPORTED),
+        ("beq", "t1, t0, .Lsyscall_unsupported"),
+        ("li", "t0, {}", AT_FDCWD),
+        ("and", "t0, t1, t0"),
+        ("beqz", "t0, .Lsyscall_fork"),
+        ("mv", "a3, a2"),
+        ("mv", "a2, a1"),
+        ("mv", "a1, a0"),
+        /// AT_FDCWD
+        ("li", "a0, -100")
+    );
+    label!(asm, ".Lsyscall_fork");
+    asm!(
+        asm,
+        ("li", "t0, {}", FORK),
+        ("and", "t0, t1, t0"),
+        ("beqz", "t0, .Lsyscall_call"),
+        /// SIGCHLD, which is what fork sends the parent
+        ("li", "a0, 17")
+    );
+    label!(asm, ".Lsyscall_call");
+    asm!(
+        asm,
+        ("li", "t0, {}", FORK - 1),
+        ("and", "a7, t1, t0"),
+        ("ecall"),
+        ("ret")
+    );
+    label!(asm, ".Lsyscall_unsupported");
+    asm!(
+        asm,
+        /// -ENOSYS
+        ("li", "a0, -38"),
+        ("ret")
+    );
+
+    segment!(asm, "rodata");
+    asm!(asm, (".balign", "2"));
+    label!(asm, "syscall_table");
+    for chunk in table.chunks(16) {
+        let entries: Vec<String> = chunk.iter().map(u16::to_string).collect();
+        asm!(asm, (".hword", "{}", entries.join(", ")));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{asm, asm_line, comment};
+
+use super::{pop, push, Builder};
+
+/// Pop `b` into `t1` then `a` into `t0`, run `insts` and push `t0`.
+fn binary(asm: &mut Builder, name: &str, insts: &[(&str, &str)]) {
+    comment!(asm, "-- {} --", name);
+    pop(asm, "t1");
+    pop(asm, "t0");
+    for (op, args) in insts {
+        asm!(asm, (op, "{}", args));
+    }
+    push(asm, "t0");
+}
+
+pub fn add(asm: &mut Builder) {
+    binary(asm, "add", &[("add", "t0, t0, t1")]);
+}
+
+pub fn sub(asm: &mut Builder) {
+    binary(asm, "sub", &[("sub", "t0, t0, t1")]);
+}
+
+pub fn mul(asm: &mut Builder) {
+    binary(asm, "mul", &[("mul", "t0, t0, t1")]);
+}
+
+pub fn div(asm: &mut Builder) {
+    binary(asm, "div", &[("divu", "t0, t0, t1")]);
+}
+
+pub fn mod_(asm: &mut Builder) {
+    binary(asm, "mod", &[("remu", "t0, t0, t1")]);
+}
+
+pub fn divmod(asm: &mut Builder) {
+    comment!(asm, "-- divmod --");
+    pop(asm, "t1");
+    pop(asm, "t0");
+    asm!(asm, ("divu", "t2, t0, t1"), ("remu", "t3, t0, t1"));
+    push(asm, "t2");
+    push(asm, "t3");
+}
+
+pub fn not(asm: &mut Builder) {
+    comment!(asm, "-- not --");
+    pop(asm, "t0");
+    asm!(asm, ("not", "t0, t0"));
+    push(asm, "t0");
+}
+
+pub fn band(asm: &mut Builder) {
+    binary(asm, "and", &[("and", "t0, t0, t1")]);
+}
+
+pub fn bor(asm: &mut Builder) {
+    binary(asm, "or", &[("or", "t0, t0, t1")]);
+}
+
+pub fn xor(asm: &mut Builder) {
+    binary(asm, "xor", &[("xor", "t0, t0, t1")]);
+}
+
+pub fn shl(asm: &mut Builder) {
+    binary(asm, "shl", &[("sll", "t0, t0, t1")]);
+}
+
+pub fn shr(asm: &mut Builder) {
+    binary(asm, "shr", &[("srl", "t0, t0, t1")]);
+}
+
+// There are no flags, only set-less-than, so the rest is built out of it
+
+pub fn eq(asm: &mut Builder) {
+    binary(asm, "eq", &[("sub", "t0, t0, t1"), ("seqz", "t0, t0")]);
+}
+
+pub fn neq(asm: &mut Builder) {
+    binary(asm, "neq", &[("sub", "t0, t0, t1"), ("snez", "t0, t0")]);
+}
+
+pub fn lt(asm: &mut Builder) {
+    binary(asm, "lt", &[("slt", "t0, t0, t1")]);
+}
+
+pub fn gt(asm: &mut Builder) {
+    binary(asm, "gt", &[("slt", "t0, t1, t0")]);
+}
+
+pub fn lte(asm: &mut Builder) {
+    binary(asm, "lte", &[("slt", "t0, t1, t0"), ("xori", "t0, t0, 1")]);
+}
+
+pub fn gte(asm: &mut Builder) {
+    binary(asm, "gte", &[("slt", "t0, t0, t1"), ("xori", "t0, t0, 1")]);
+}
+
+pub fn load(asm: &mut Builder) {
+    comment!(asm, "-- load --");
+    pop(asm, "t0");
+    asm!(asm, ("lbu", "t0, 0(t0)"));
+    push(asm, "t0");
+}
+
+pub fn store(asm: &mut Builder) {
+    comment!(asm, "-- store --");
+    pop(asm, "t1");
+    pop(asm, "t0");
+    asm!(asm, ("sb", "t1, 0(t0)"));
+}
+
+pub fn load64(asm: &mut Builder) {
+    comment!(asm, "-- load64 --");
+    pop(asm, "t0");
+    asm!(asm, ("ld", "t0, 0(t0)"));
+    push(asm, "t0");
+}
+
+pub fn store64(asm: &mut Builder) {
+    comment!(asm, "-- store64 --");
+    pop(asm, "t1");
+    pop(asm, "t0");
+    asm!(asm, ("sd", "t1, 0(t0)"));
+}
+
+/// Pop the x86-64 syscall number and `args` arguments, and push the result.
+/// Arguments that weren't given are zeroed, like on arm.
+pub fn syscall(asm: &mut Builder, args: usize) {
+    comment!(asm, "-- syscall{} --", args);
+    pop(asm, "a7");
+    for arg in 0..6 {
+        if arg < args {
+            pop(asm, &format!("a{}", arg));
+        } else {
+            asm!(asm, ("li", "a{}, 0", arg));
+        }
+    }
+    asm!(asm, ("call", "do_syscall"));
+    push(asm, "a0");
+}
@@ -0,0 +1,44 @@
+//! The .porth source of each instruction, written above the code for it in the
+//! assembly kept with `--keep-asm`.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::instruction::Program;
+
+pub struct Source {
+    base_path: PathBuf,
+    /// The lines of each file read so far, by the name locations give it
+    files: HashMap<String, Vec<String>>,
+    /// What the last line was for
+    last: Option<(String, usize)>,
+}
+
+impl Source {
+    pub fn new(program: &Program) -> Self {
+        Self {
+            base_path: program.base_path.clone(),
+            files: HashMap::new(),
+            last: None,
+        }
+    }
+
+    /// The line `loc` is on with where it's from, unless the last line written
+    /// was that one too. Files that can't be read, like includes found outside
+    /// the program's directory, just don't have lines, so the code expanded
+    /// from their macros stays under the line that used it.
+    pub fn line(&mut self, loc: &(String, usize, usize)) -> Option<String> {
+        let (file, line, _) = loc;
+        if self.last.as_ref() == Some(&(file.clone(), *line)) {
+            return None;
+        }
+        let base_path = &self.base_path;
+        let lines = self.files.entry(file.clone()).or_insert_with(|| {
+            std::fs::read_to_string(base_path.join(file))
+                .map(|source| source.lines().map(str::to_string).collect())
+                .unwrap_or_default()
+        });
+        let text = lines.get(line.checked_sub(1)?)?.trim();
+        self.last = Some((file.clone(), *line));
+        Some(format!("{}:{}: {}", file, line, text))
+    }
+}
@@ -0,0 +1,311 @@
+use std::process::Command;
+
+use crate::cli::{Assembler, Linker, Target};
+
+/// The instruction set a target runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+    Riscv64,
+    Wasm32,
+}
+
+/// What a target's programs make their syscalls to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Os {
+    Linux,
+    Macos,
+    Windows,
+    Wasi,
+}
+
+impl Target {
+    pub fn arch(&self) -> Arch {
+        match self {
+            Target::X86_64Linux | Target::X86_64Macos | Target::X86_64Windows => Arch::X86_64,
+            Target::Aarch64Linux => Arch::Aarch64,
+            Target::Riscv64Linux => Arch::Riscv64,
+            Target::Wasm32 => Arch::Wasm32,
+        }
+    }
+
+    pub fn os(&self) -> Os {
+        match self {
+            Target::X86_64Linux | Target::Aarch64Linux | Target::Riscv64Linux => Os::Linux,
+            Target::X86_64Macos => Os::Macos,
+            Target::X86_64Windows => Os::Windows,
+            Target::Wasm32 => Os::Wasi,
+        }
+    }
+
+    /// The target's triple, which `--target` takes as well as its name.
+    pub fn triple(&self) -> &'static str {
+        match self {
+            Target::X86_64Linux => "x86_64-unknown-linux-gnu",
+            Target::Aarch64Linux => "aarch64-unknown-linux-gnu",
+            Target::Riscv64Linux => "riscv64-unknown-linux-gnu",
+            Target::X86_64Macos => "x86_64-apple-darwin",
+            Target::X86_64Windows => "x86_64-pc-windows-gnu",
+            Target::Wasm32 => "wasm32-wasi",
+        }
+    }
+
+    /// Whether the compiler itself runs on this target, so its tools aren't
+    /// cross tools.
+    fn is_host(&self) -> bool {
+        match self {
+            Target::X86_64Linux => cfg!(all(target_arch = "x86_64", target_os = "linux")),
+            Target::Aarch64Linux => cfg!(all(target_arch = "aarch64", target_os = "linux")),
+            Target::Riscv64Linux => cfg!(all(target_arch = "riscv64", target_os = "linux")),
+            // ld64 links for either arch
+            Target::X86_64Macos => cfg!(target_os = "macos"),
+            Target::X86_64Windows => cfg!(windows),
+            // Runs anywhere there's a runtime
+            Target::Wasm32 => true,
+        }
+    }
+
+    /// The binutils `tool` for this target, like `aarch64-linux-gnu-as`.
+    fn binutil(&self, tool: &str) -> String {
+        match self {
+            _ if self.is_host() => tool.to_string(),
+            Target::X86_64Linux => format!("x86_64-linux-gnu-{}", tool),
+            Target::Aarch64Linux => format!("aarch64-linux-gnu-{}", tool),
+            Target::Riscv64Linux => format!("riscv64-linux-gnu-{}", tool),
+            Target::X86_64Macos => format!("x86_64-apple-darwin-{}", tool),
+            Target::X86_64Windows => format!("x86_64-w64-mingw32-{}", tool),
+            Target::Wasm32 => unreachable!("wasm isn't built with binutils"),
+        }
+    }
+
+    /// What builds x86 assembly for this target unless `--assembler` says
+    /// otherwise. The built-in assembler only writes ELF.
+    pub fn default_assembler(&self) -> Assembler {
+        match self {
+            Target::X86_64Linux => Assembler::Builtin,
+            _ => Assembler::Nasm,
+        }
+    }
+
+    /// The command that assembles `asm` into the object file `obj`, with
+    /// `assembler` for x86.
+    pub fn assembler(
+        &self,
+        asm: &str,
+        obj: &str,
+        assembler: Assembler,
+        debug_info: bool,
+    ) -> Command {
+        // GNU as writes debug info for the `.loc`s in the assembly by itself
+        let nasm_debug_info: &[&str] = match (debug_info, self) {
+            (false, _) => &[],
+            (true, Target::X86_64Windows) => &["-g"],
+            (true, _) => &["-g", "-F", "dwarf"],
+        };
+        match self {
+            Target::X86_64Linux | Target::X86_64Windows if assembler == Assembler::Gas => {
+                let mut cmd = Command::new(self.binutil("as"));
+                cmd.args(["--64", asm, "-o", obj]);
+                cmd
+            }
+            Target::X86_64Macos if assembler == Assembler::Gas => {
+                let mut cmd = Command::new(self.binutil("as"));
+                cmd.args(["-arch", "x86_64", asm, "-o", obj]);
+                cmd
+            }
+            Target::X86_64Linux => {
+                let mut cmd = Command::new("nasm");
+                cmd.args([asm, "-f", "elf64", "-o", obj]);
+                cmd.args(nasm_debug_info);
+                cmd
+            }
+            Target::X86_64Macos => {
+                let mut cmd = Command::new("nasm");
+                cmd.args([asm, "-f", "macho64", "-o", obj]);
+                cmd.args(nasm_debug_info);
+                cmd
+            }
+            Target::X86_64Windows => {
+                let mut cmd = Command::new("nasm");
+                cmd.args([asm, "-f", "win64", "-o", obj]);
+                cmd.args(nasm_debug_info);
+                cmd
+            }
+            Target::Wasm32 => {
+                let mut cmd = Command::new("wat2wasm");
+                cmd.args([asm, "-o", obj]);
+                cmd
+            }
+            Target::Aarch64Linux | Target::Riscv64Linux => {
+                let mut cmd = Command::new(self.binutil("as"));
+                cmd.args([asm, "-o", obj]);
+                cmd
+            }
+        }
+    }
+
+    /// The triple LLVM IR for this target is written for, if the LLVM backend
+    /// can build for it.
+    pub fn llvm_triple(&self) -> Option<&'static str> {
+        (self.os() == Os::Linux).then(|| self.triple())
+    }
+
+    /// The command that optimizes the LLVM IR `ll` and builds it into the
+    /// object file `obj`.
+    pub fn clang(&self, ll: &str, obj: &str, opt_level: u8, pie: bool) -> Command {
+        let triple = self.llvm_triple().expect("the IR was written for a triple");
+        let mut cmd = Command::new("clang");
+        cmd.arg(format!("--target={}", triple));
+        cmd.arg(if opt_level == 0 { "-O0" } else { "-O2" });
+        if pie {
+            cmd.arg("-fPIE");
+        }
+        cmd.args(["-c", ll, "-o", obj]);
+        cmd
+    }
+
+    /// The C compiler for this target, which also links what it builds.
+    fn c_compiler(&self) -> String {
+        match self {
+            _ if self.is_host() => "cc".to_string(),
+            _ => self.binutil("gcc"),
+        }
+    }
+
+    /// The command that optimizes the C source `c` and builds it into the
+    /// object file `obj`.
+    pub fn cc(&self, c: &str, obj: &str, opt_level: u8, pie: bool) -> Command {
+        let mut cmd = Command::new(self.c_compiler());
+        cmd.arg(if opt_level == 0 { "-O0" } else { "-O2" });
+        if pie {
+            cmd.arg("-fPIE");
+        }
+        cmd.args(["-c", c, "-o", obj]);
+        cmd
+    }
+
+    /// cc, linking with `linker`.
+    fn cc_linking(&self, linker: Linker) -> Command {
+        let mut cmd = Command::new(self.c_compiler());
+        match linker {
+            Linker::Builtin | Linker::Ld => {}
+            Linker::Lld => {
+                cmd.arg("-fuse-ld=lld");
+            }
+            Linker::Mold => {
+                cmd.arg("-fuse-ld=mold");
+            }
+        }
+        cmd
+    }
+
+    /// The command that links `obj`, built from C, with the C library.
+    pub fn cc_linker(&self, obj: &str, exe: &str, pie: bool, linker: Linker) -> Command {
+        let mut cmd = self.cc_linking(linker);
+        if pie {
+            cmd.arg("-pie");
+        }
+        cmd.args([obj, "-o", exe]);
+        cmd
+    }
+
+    /// The command that links `obj`, which starts itself at `_start`, with the
+    /// C library.
+    pub fn libc_linker(&self, obj: &str, exe: &str, pie: bool, linker: Linker) -> Command {
+        let mut cmd = self.cc_linking(linker);
+        cmd.args(["-nostartfiles", if pie { "-pie" } else { "-no-pie" }]);
+        cmd.args([obj, "-o", exe]);
+        cmd
+    }
+
+    /// Whether `linker` can link executables for this target.
+    pub fn links_with(&self, linker: Linker) -> bool {
+        match linker {
+            Linker::Mold => self.os() == Os::Linux,
+            _ => true,
+        }
+    }
+
+    /// The command that links `obj` into the executable `exe` with `linker`,
+    /// if the object isn't already one.
+    pub fn linker(&self, obj: &str, exe: &str, pie: bool, linker: Linker) -> Option<Command> {
+        // lld and mold link for every arch they know, so there are no cross versions
+        let ld = match (linker, self) {
+            (Linker::Lld, Target::X86_64Macos) => "ld64.lld".to_string(),
+            (Linker::Lld, Target::X86_64Windows) if self.is_host() => "lld-link".to_string(),
+            (Linker::Lld, _) => "ld.lld".to_string(),
+            (Linker::Mold, _) => "mold".to_string(),
+            // nasm output links with any x86-64 ld, like it always has
+            (_, Target::X86_64Linux) => "ld".to_string(),
+            (_, Target::X86_64Windows) if self.is_host() => "link".to_string(),
+            _ => self.binutil("ld"),
+        };
+        let mut cmd = match self {
+            Target::X86_64Linux | Target::Aarch64Linux | Target::Riscv64Linux => Command::new(ld),
+            _ if pie => unreachable!("only Linux executables are built position independent"),
+            Target::X86_64Macos => {
+                let mut cmd = Command::new(ld);
+                // Static, so the kernel starts it with argc on the stack like Linux does
+                cmd.args(["-arch", "x86_64", "-static", "-e", "_start"]);
+                cmd
+            }
+            Target::X86_64Windows if self.is_host() => {
+                let mut cmd = Command::new(ld);
+                cmd.args(["/nologo", "/subsystem:console", "/entry:_start", obj]);
+                cmd.args(["kernel32.lib", &format!("/out:{}", exe)]);
+                return Some(cmd);
+            }
+            Target::X86_64Windows => {
+                let mut cmd = Command::new(ld);
+                if linker == Linker::Lld {
+                    // The MinGW flavor, which takes the same arguments as GNU ld
+                    cmd.args(["-m", "i386pep"]);
+                }
+                cmd.args(["-e", "_start", "--subsystem", "console"]);
+                cmd.args([obj, "-o", exe, "-lkernel32"]);
+                return Some(cmd);
+            }
+            Target::Wasm32 => return None,
+        };
+        if pie {
+            // Static, like gcc's -static-pie, with nothing to relocate at startup
+            cmd.args(["-static", "-pie", "--no-dynamic-linker", "-z", "text"]);
+        }
+        cmd.args([obj, "-o", exe]);
+        Some(cmd)
+    }
+
+    /// The extension of the assembly written for this target.
+    pub fn asm_extension(&self) -> &'static str {
+        match self {
+            Target::Wasm32 => "wat",
+            _ => "asm",
+        }
+    }
+
+    /// The extension of the object file the assembler makes.
+    pub fn obj_extension(&self) -> &'static str {
+        match self {
+            Target::Wasm32 => "wasm",
+            _ => "o",
+        }
+    }
+
+    /// The extension executables for this target need.
+    pub fn exe_extension(&self) -> &'static str {
+        match self {
+            Target::X86_64Windows => "exe",
+            Target::Wasm32 => "wasm",
+            _ => "",
+        }
+    }
+
+    /// What runs executables for this target when they can't run on their own.
+    pub fn runtime(&self) -> Option<&'static str> {
+        match self {
+            Target::Wasm32 => Some("wasmtime"),
+            _ => None,
+        }
+    }
+}
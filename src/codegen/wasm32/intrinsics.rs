@@ -0,0 +1,116 @@
+use crate::codegen::intrinsics::Intrinsic;
+
+use super::{Layout, Wat, ARGC, IOV, PRINT_END, TRANSFERRED};
+
+pub fn compile(wat: &mut Wat, intrinsic: &Intrinsic, layout: &Layout) {
+    match intrinsic {
+        Intrinsic::Print => wat.lines(&["call $pop", "call $print"]),
+        Intrinsic::Panic => wat.lines(&["i32.const 1", "call $proc_exit"]),
+        Intrinsic::Dup => {
+            wat.lines(&[
+                "call $pop",
+                "local.tee $a",
+                "call $push",
+                "local.get $a",
+                "call $push",
+            ]);
+        }
+        Intrinsic::Dup2 => {
+            wat.lines(&["call $pop", "local.set $b", "call $pop", "local.set $a"]);
+            for local in ["$a", "$b", "$a", "$b"] {
+                wat.lines(&[&format!("local.get {}", local), "call $push"]);
+            }
+        }
+        Intrinsic::Swap => {
+            wat.lines(&["call $pop", "local.set $b", "call $pop", "local.set $a"]);
+            for local in ["$b", "$a"] {
+                wat.lines(&[&format!("local.get {}", local), "call $push"]);
+            }
+        }
+        Intrinsic::Over => {
+            wat.lines(&["call $pop", "local.set $b", "call $pop", "local.set $a"]);
+            for local in ["$a", "$b", "$a"] {
+                wat.lines(&[&format!("local.get {}", local), "call $push"]);
+            }
+        }
+        Intrinsic::Drop => wat.lines(&["call $pop", "drop"]),
+        Intrinsic::Drop2 => wat.lines(&["call $pop", "drop", "call $pop", "drop"]),
+        Intrinsic::Mem => wat.push(layout.mem),
+        Intrinsic::Argc => {
+            wat.lines(&[&format!("i32.const {}", ARGC), "i64.load", "call $push"]);
+        }
+        Intrinsic::Argv => wat.push(layout.argv),
+        Intrinsic::CastPtr => wat.comment("-- Cast to Pointer --"),
+        Intrinsic::CastInt => wat.comment("-- Cast to Int --"),
+        Intrinsic::Align => {
+            wat.lines(&[
+                "call $pop",
+                "local.set $b",
+                "call $pop",
+                "local.get $b",
+                "i64.add",
+                "i64.const 1",
+                "i64.sub",
+                "i64.const 0",
+                "local.get $b",
+                "i64.sub",
+                "i64.and",
+                "call $push",
+            ]);
+        }
+        Intrinsic::Here => {
+            let here = wat.asm.tmp_here.clone();
+            wat.comment(format!("-- {} --", here));
+        }
+    }
+}
+
+/// `$print`, which prints a number as unsigned and a newline.
+pub fn gen_print(wat: &mut Wat) {
+    wat.open("(func $print (param $value i64) (local $p i32)");
+    // Digits are written backwards from the end of the buffer
+    wat.lines(&[
+        &format!("i32.const {}", PRINT_END - 1),
+        "local.tee $p",
+        "i32.const 10",
+        "i32.store8",
+    ]);
+    wat.open("loop $digits");
+    wat.lines(&[
+        "local.get $p",
+        "i32.const 1",
+        "i32.sub",
+        "local.tee $p",
+        "local.get $value",
+        "i64.const 10",
+        "i64.rem_u",
+        "i64.const 48",
+        "i64.add",
+        "i64.store8",
+        "local.get $value",
+        "i64.const 10",
+        "i64.div_u",
+        "local.tee $value",
+        "i64.eqz",
+        "i32.eqz",
+        "br_if $digits",
+    ]);
+    wat.close("end");
+    wat.lines(&[
+        &format!("i32.const {}", IOV),
+        "local.get $p",
+        "i32.store",
+        &format!("i32.const {}", IOV + 4),
+        &format!("i32.const {}", PRINT_END),
+        "local.get $p",
+        "i32.sub",
+        "i32.store",
+        "i32.const 1",
+        &format!("i32.const {}", IOV),
+        "i32.const 1",
+        &format!("i32.const {}", TRANSFERRED),
+        "call $fd_write",
+        "drop",
+    ]);
+    wat.close(")");
+}
@@ -0,0 +1,519 @@
+//! Code generation for WebAssembly with WASI, written as a text module.
+//!
+//! Wasm has no registers or native stack to point at, so the porth stack lives
+//! in linear memory below `$sp`, next to the porth memory region and the
+//! program's strings. Porth's blocks map onto wasm's structured control flow:
+//! a while loop is a `loop` inside a `block` it breaks out of, and an if chain
+//! is nested `if`s, one for each `elif`. WASI only covers `read`, `write`,
+//! `close` and `exit` of the Linux syscalls programs use, and the rest return
+//! `-ENOSYS`. Errors are negated WASI errno values, which mostly differ from
+//! Linux ones.
+
+mod intrinsics;
+mod ops;
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use super::aarch64::STACK_CAPACITY;
+use super::builder::Builder;
+use super::source::Source;
+use crate::{
+    err,
+    error::{CompileError::*, Error::CompileError},
+    instruction::*,
+};
+
+/// An iovec for WASI calls, and where they write how much they transferred
+const IOV: u32 = 16;
+const TRANSFERRED: u32 = IOV + 8;
+/// What `args_sizes_get` writes, argc and then the size of the strings
+const ARG_SIZES: u32 = 32;
+const ARGC: u32 = 40;
+const PRINT_END: u32 = 96;
+const DATA: u32 = 1024;
+/// Room for argv, as WASI writes it and then widened to 64 bits
+const MAX_ARGS: u32 = 1024;
+const ARGS_SIZE: u32 = 64 * 1024;
+const PAGE_SIZE: u32 = 64 * 1024;
+
+/// Where everything goes in linear memory.
+struct Layout {
+    mem: u32,
+    /// Where the declared memories start, each at its offset from here
+    memories: u32,
+    argv32: u32,
+    argv: u32,
+    arg_strings: u32,
+    stack_end: u32,
+}
+
+impl Layout {
+    /// Unless it doesn't fit in the 4GiB of a 32 bit address space.
+    fn new(data_len: u32, mem_capacity: usize, memories: &[Memory]) -> Option<Self> {
+        let mem = (DATA + data_len).next_multiple_of(MEM_ALIGN as u32);
+        let memories_start = mem.checked_add(u32::try_from(mem_capacity).ok()?)?;
+        let memories_start = memories_start.checked_next_multiple_of(MEM_ALIGN as u32)?;
+        let memories_len = memories
+            .last()
+            .map_or(0, |memory| memory.offset + memory.reserved());
+        let argv32 = memories_start.checked_add(u32::try_from(memories_len).ok()?)?;
+        let argv = argv32 + MAX_ARGS * 4;
+        let arg_strings = argv + (MAX_ARGS + 1) * 8;
+        let stack_end = argv32.checked_add(ARGS_SIZE + STACK_CAPACITY as u32)?;
+        Some(Self {
+            mem,
+            memories: memories_start,
+            argv32,
+            argv,
+            arg_strings,
+            stack_end,
+        })
+    }
+}
+
+/// The module being written, and how deeply the next line is nested.
+pub struct Wat {
+    asm: Builder,
+    depth: usize,
+}
+
+impl Wat {
+    fn line(&mut self, text: impl AsRef<str>) {
+        let line = format!("{:1$}{2}", "", self.depth * 2, text.as_ref());
+        self.asm.insert(line);
+    }
+
+    fn lines(&mut self, lines: &[&str]) {
+        for line in lines {
+            self.line(line);
+        }
+    }
+
+    fn comment(&mut self, text: impl AsRef<str>) {
+        self.line(format!(";; {}", text.as_ref()))
+    }
+
+    /// A line that starts a block, with the ones after it indented.
+    fn open(&mut self, text: impl AsRef<str>) {
+        self.line(text);
+        self.depth += 1;
+    }
+
+    /// A line that ends a block.
+    fn close(&mut self, text: impl AsRef<str>) {
+        self.depth -= 1;
+        self.line(text);
+    }
+
+    fn push(&mut self, value: impl std::fmt::Display) {
+        self.line(format!("i64.const {}", value));
+        self.line("call $push");
+    }
+}
+
+/// A block that's still open in the porth program.
+enum Open {
+    While(usize),
+    /// With how many `if`s its `elif`s opened
+    If(usize),
+    Unsafe,
+}
+
+pub fn generate(
+    program: &Program,
+    mem_capacity: usize,
+    mut source: Option<Source>,
+) -> Result<Builder> {
+    let (strings, data) = strings(program);
+    let layout = Layout::new(data.len() as u32, mem_capacity, &program.memories)
+        .ok_or(CompileError(UnsupportedTarget("wasm32".into())))
+        .with_context(|| {
+            format!(
+                "{} bytes of mem and the memories declared don't fit in wasm32 memory",
+                mem_capacity
+            )
+        })?;
+    let pages = layout.stack_end.div_ceil(PAGE_SIZE);
+
+    let mut wat = Wat {
+        asm: Builder::wat(),
+        depth: 0,
+    };
+    wat.comment("-- generated by the worth compiler --");
+    wat.open("(module");
+    for (name, params, result) in [
+        ("fd_write", "i32 i32 i32 i32", "i32"),
+        ("fd_read", "i32 i32 i32 i32", "i32"),
+        ("fd_close", "i32", "i32"),
+        ("args_sizes_get", "i32 i32", "i32"),
+        ("args_get", "i32 i32", "i32"),
+    ] {
+        wat.line(format!(
+            "(import \"wasi_snapshot_preview1\" \"{0}\" (func ${0} (param {1}) (result {2})))",
+            name, params, result
+        ));
+    }
+    wat.line("(import \"wasi_snapshot_preview1\" \"proc_exit\" (func $proc_exit (param i32)))");
+    wat.line(format!("(memory (export \"memory\") {})", pages));
+    wat.line(format!(
+        "(global $sp (mut i32) (i32.const {}))",
+        layout.stack_end
+    ));
+    if !data.is_empty() {
+        wat.line(format!("(data (i32.const {}) \"{}\")", DATA, escape(&data)));
+    }
+
+    gen_stack(&mut wat);
+    intrinsics::gen_print(&mut wat);
+    gen_syscall(&mut wat);
+    gen_args(&mut wat, &layout);
+
+    wat.open("(func $main (export \"_start\")");
+    wat.line("(local $a i64) (local $b i64) (local $c i64) (local $n i64)");
+    wat.line("call $init_args");
+    let mut open = Vec::new();
+    for (ip, inst) in program.instructions.iter().enumerate() {
+        wat.asm.tmp_here =
+            inst.loc.0.clone() + ":" + &inst.loc.1.to_string() + ":" + &inst.loc.2.to_string();
+        if let Some(line) = source.as_mut().and_then(|source| source.line(&inst.loc)) {
+            wat.comment(line);
+        }
+        match &inst.kind {
+            InstructionKind::Push(val) => match val {
+                Value::Int(i) => wat.push(i),
+                Value::Bool(b) => wat.push(*b as i64),
+                Value::Char(c) => wat.push(*c as i64),
+                Value::Ptr(name) => err!(
+                    program,
+                    CompileError(UnexpectedToken(name.clone())),
+                    format!("Pointer to {} should be resolved before codegen", name),
+                    ip
+                ),
+                Value::Str(s) => {
+                    wat.push(s.len());
+                    wat.push(DATA + strings[s]);
+                }
+            },
+            InstructionKind::Intrinsic(intrinsic) => {
+                wat.comment(format!(
+                    "-- intrinsic: {} --",
+                    intrinsic.to_string().to_lowercase()
+                ));
+                intrinsics::compile(&mut wat, intrinsic, &layout);
+            }
+            InstructionKind::Keyword(Keyword::While { self_ip, .. }) => {
+                wat.comment("-- while --");
+                wat.open(format!("block $end_{}", self_ip));
+                wat.open(format!("loop $while_{}", self_ip));
+                open.push(Open::While(*self_ip));
+            }
+            InstructionKind::Keyword(Keyword::Do { .. }) => {
+                wat.comment("-- do --");
+                wat.line("call $pop");
+                wat.line("i64.eqz");
+                match open.last() {
+                    Some(Open::While(while_ip)) => wat.line(format!("br_if $end_{}", while_ip)),
+                    _ => {
+                        wat.line("i32.eqz");
+                        wat.open("if");
+                    }
+                }
+            }
+            InstructionKind::Keyword(Keyword::If) => {
+                wat.comment("-- if --");
+                open.push(Open::If(0));
+            }
+            InstructionKind::Keyword(Keyword::Unsafe) => {
+                wat.comment("-- unsafe --");
+                open.push(Open::Unsafe);
+            }
+            InstructionKind::Keyword(keyword @ (Keyword::Elif { .. } | Keyword::Else { .. })) => {
+                wat.comment(format!("-- {} --", keyword));
+                wat.close("else");
+                wat.depth += 1;
+                if let (Keyword::Elif { .. }, Some(Open::If(ifs))) = (keyword, open.last_mut()) {
+                    *ifs += 1;
+                }
+            }
+            InstructionKind::Keyword(Keyword::End { .. }) => {
+                wat.comment("-- end --");
+                match open.pop() {
+                    Some(Open::While(while_ip)) => {
+                        wat.line(format!("br $while_{}", while_ip));
+                        wat.close("end");
+                        wat.close("end");
+                    }
+                    Some(Open::If(ifs)) => {
+                        for _ in 0..=ifs {
+                            wat.close("end");
+                        }
+                    }
+                    Some(Open::Unsafe) => {}
+                    None => {
+                        err!(
+                            program,
+                            CompileError(UnexpectedToken("end".into())),
+                            "End without a block to close",
+                            ip
+                        )
+                    }
+                }
+            }
+            InstructionKind::Op(op) => ops::compile(&mut wat, op),
+            InstructionKind::Syscall(kind) => ops::syscall(&mut wat, syscall_args(kind)),
+            InstructionKind::Keyword(Keyword::Include) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("include".into())),
+                    "Include should be expanded before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Macro) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("macro".into())),
+                    "Macro should be expanded before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Extern) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("extern".into())),
+                    "Extern should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Keyword(Keyword::Memory) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken("memory".into())),
+                    "Memory should be declared before codegen",
+                    ip
+                )
+            }
+            InstructionKind::Memory(memory) => {
+                wat.push(layout.memories as usize + memory.offset);
+            }
+            InstructionKind::Extern(_) => {
+                unreachable!("compile only lets externs through for native x86_64-linux")
+            }
+            InstructionKind::Name(name) => {
+                err!(
+                    program,
+                    CompileError(UnexpectedToken(name.clone())),
+                    format!("Name {} should be resolved before codegen", name),
+                    ip
+                )
+            }
+        }
+    }
+    wat.close(")");
+    wat.close(")");
+
+    Ok(wat.asm)
+}
+
+/// Every string literal's offset into the data segment, and its contents.
+fn strings(program: &Program) -> (HashMap<String, u32>, Vec<u8>) {
+    let mut offsets = HashMap::new();
+    let mut data = Vec::new();
+    for inst in &program.instructions {
+        if let InstructionKind::Push(Value::Str(s)) = &inst.kind {
+            offsets.entry(s.clone()).or_insert_with(|| {
+                let offset = data.len() as u32;
+                data.extend_from_slice(s.as_bytes());
+                offset
+            });
+        }
+    }
+    (offsets, data)
+}
+
+/// `bytes` as the inside of a wat string.
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'"' | b'\\' => format!("\\{:02x}", b),
+            b' '..=b'~' => (b as char).to_string(),
+            _ => format!("\\{:02x}", b),
+        })
+        .collect()
+}
+
+fn syscall_args(kind: &SyscallKind) -> usize {
+    match kind {
+        SyscallKind::Syscall0 => 0,
+        SyscallKind::Syscall1 => 1,
+        SyscallKind::Syscall2 => 2,
+        SyscallKind::Syscall3 => 3,
+        SyscallKind::Syscall4 => 4,
+        SyscallKind::Syscall5 => 5,
+        SyscallKind::Syscall6 => 6,
+    }
+}
+
+/// `$push` and `$pop`, which move a value between the wasm and porth stacks.
+fn gen_stack(wat: &mut Wat) {
+    wat.open("(func $push (param $value i64)");
+    wat.lines(&[
+        "global.get $sp",
+        "i32.const 8",
+        "i32.sub",
+        "global.set $sp",
+        "global.get $sp",
+        "local.get $value",
+        "i64.store",
+    ]);
+    wat.close(")");
+    wat.open("(func $pop (result i64)");
+    wat.lines(&[
+        "global.get $sp",
+        "i64.load",
+        "global.get $sp",
+        "i32.const 8",
+        "i32.add",
+        "global.set $sp",
+    ]);
+    wat.close(")");
+}
+
+/// `$syscall`, which takes a Linux syscall number and its first three
+/// arguments, and returns the result.
+fn gen_syscall(wat: &mut Wat) {
+    wat.open(
+        "(func $syscall (param $n i64) (param $a i64) (param $b i64) (param $c i64) (result i64)",
+    );
+    wat.line("(local $errno i32)");
+    for (number, function) in [(0, "fd_read"), (1, "fd_write")] {
+        wat.lines(&["local.get $n", &format!("i64.const {}", number), "i64.eq"]);
+        wat.open("if");
+        wat.lines(&[
+            &format!("i32.const {}", IOV),
+            "local.get $b",
+            "i64.store32",
+            &format!("i32.const {}", IOV + 4),
+            "local.get $c",
+            "i64.store32",
+            "local.get $a",
+            "i32.wrap_i64",
+            &format!("i32.const {}", IOV),
+            "i32.const 1",
+            &format!("i32.const {}", TRANSFERRED),
+            &format!("call ${}", function),
+        ]);
+        errno(wat);
+        wat.lines(&[
+            &format!("i32.const {}", TRANSFERRED),
+            "i64.load32_u",
+            "return",
+        ]);
+        wat.close("end");
+    }
+    wat.lines(&["local.get $n", "i64.const 3", "i64.eq"]);
+    wat.open("if");
+    wat.lines(&["local.get $a", "i32.wrap_i64", "call $fd_close"]);
+    errno(wat);
+    wat.lines(&["i64.const 0", "return"]);
+    wat.close("end");
+    // exit and exit_group
+    wat.lines(&[
+        "local.get $n",
+        "i64.const 60",
+        "i64.eq",
+        "local.get $n",
+        "i64.const 231",
+        "i64.eq",
+        "i32.or",
+    ]);
+    wat.open("if");
+    wat.lines(&["local.get $a", "i32.wrap_i64", "call $proc_exit"]);
+    wat.close("end");
+    // -ENOSYS
+    wat.line("i64.const -38");
+    wat.close(")");
+}
+
+/// Return the negated errno on top of the wasm stack, if it isn't 0.
+fn errno(wat: &mut Wat) {
+    wat.line("local.tee $errno");
+    wat.open("if");
+    wat.lines(&[
+        "i64.const 0",
+        "local.get $errno",
+        "i64.extend_i32_u",
+        "i64.sub",
+        "return",
+    ]);
+    wat.close("end");
+}
+
+/// `$init_args`, which fills in argc and a 64 bit argv. Programs with more
+/// arguments than there's room for get none.
+fn gen_args(wat: &mut Wat, layout: &Layout) {
+    wat.open("(func $init_args (local $i i32)");
+    wat.lines(&[
+        &format!("i32.const {}", ARG_SIZES),
+        &format!("i32.const {}", ARG_SIZES + 4),
+        "call $args_sizes_get",
+        "drop",
+        &format!("i32.const {}", ARG_SIZES),
+        "i32.load",
+        &format!("i32.const {}", MAX_ARGS),
+        "i32.gt_u",
+        &format!("i32.const {}", ARG_SIZES + 4),
+        "i32.load",
+        &format!(
+            "i32.const {}",
+            layout.argv32 + ARGS_SIZE - layout.arg_strings
+        ),
+        "i32.gt_u",
+        "i32.or",
+    ]);
+    wat.open("if");
+    wat.line("return");
+    wat.close("end");
+    wat.lines(&[
+        &format!("i32.const {}", layout.argv32),
+        &format!("i32.const {}", layout.arg_strings),
+        "call $args_get",
+        "drop",
+        &format!("i32.const {}", ARGC),
+        &format!("i32.const {}", ARG_SIZES),
+        "i64.load32_u",
+        "i64.store",
+    ]);
+    wat.open("block $done");
+    wat.open("loop $copy");
+    wat.lines(&[
+        "local.get $i",
+        &format!("i32.const {}", ARG_SIZES),
+        "i32.load",
+        "i32.ge_u",
+        "br_if $done",
+        "local.get $i",
+        "i32.const 8",
+        "i32.mul",
+        &format!("i32.const {}", layout.argv),
+        "i32.add",
+        "local.get $i",
+        "i32.const 4",
+        "i32.mul",
+        &format!("i32.const {}", layout.argv32),
+        "i32.add",
+        "i64.load32_u",
+        "i64.store",
+        "local.get $i",
+        "i32.const 1",
+        "i32.add",
+        "local.set $i",
+        "br $copy",
+    ]);
+    wat.close("end");
+    wat.close("end");
+    wat.close(")");
+}
@@ -0,0 +1,129 @@
+use crate::instruction::Op;
+
+use super::Wat;
+
+/// Pop `b` then `a`, and push the result of `insts` on `a` and `b`.
+fn binary(wat: &mut Wat, insts: &[&str]) {
+    wat.lines(&["call $pop", "local.set $b", "call $pop", "local.get $b"]);
+    wat.lines(insts);
+    wat.line("call $push");
+}
+
+/// Like `binary`, for the comparisons, which give an i32.
+fn compare(wat: &mut Wat, inst: &str) {
+    binary(wat, &[inst, "i64.extend_i32_u"]);
+}
+
+pub fn compile(wat: &mut Wat, op: &Op) {
+    wat.comment(format!("-- {} --", name(op)));
+    match op {
+        Op::Add => binary(wat, &["i64.add"]),
+        Op::Sub => binary(wat, &["i64.sub"]),
+        Op::Mul => binary(wat, &["i64.mul"]),
+        Op::Div => binary(wat, &["i64.div_u"]),
+        Op::Mod => binary(wat, &["i64.rem_u"]),
+        Op::DivMod => {
+            wat.lines(&[
+                "call $pop",
+                "local.set $b",
+                "call $pop",
+                "local.tee $a",
+                "local.get $b",
+                "i64.div_u",
+                "call $push",
+                "local.get $a",
+                "local.get $b",
+                "i64.rem_u",
+                "call $push",
+            ]);
+        }
+        Op::BitwiseAnd => binary(wat, &["i64.and"]),
+        Op::BitwiseOr => binary(wat, &["i64.or"]),
+        Op::BitwiseXor => binary(wat, &["i64.xor"]),
+        Op::BitwiseNot => {
+            wat.lines(&["call $pop", "i64.const -1", "i64.xor", "call $push"]);
+        }
+        // The count is masked to 6 bits, like on x86
+        Op::Shl => binary(wat, &["i64.shl"]),
+        Op::Shr => binary(wat, &["i64.shr_u"]),
+        Op::Eq => compare(wat, "i64.eq"),
+        Op::Neq => compare(wat, "i64.ne"),
+        Op::Lt => compare(wat, "i64.lt_s"),
+        Op::Gt => compare(wat, "i64.gt_s"),
+        Op::Lte => compare(wat, "i64.le_s"),
+        Op::Gte => compare(wat, "i64.ge_s"),
+        Op::Load => {
+            wat.lines(&["call $pop", "i32.wrap_i64", "i64.load8_u", "call $push"]);
+        }
+        Op::Load64 => {
+            wat.lines(&["call $pop", "i32.wrap_i64", "i64.load", "call $push"]);
+        }
+        Op::Store | Op::Store64 => {
+            let store = match op {
+                Op::Store => "i64.store8",
+                _ => "i64.store",
+            };
+            wat.lines(&[
+                "call $pop",
+                "local.set $b",
+                "call $pop",
+                "i32.wrap_i64",
+                "local.get $b",
+                store,
+            ]);
+        }
+    }
+}
+
+fn name(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "add",
+        Op::Sub => "sub",
+        Op::Mul => "mul",
+        Op::Div => "div",
+        Op::Mod => "mod",
+        Op::DivMod => "divmod",
+        Op::BitwiseAnd => "and",
+        Op::BitwiseOr => "or",
+        Op::BitwiseXor => "xor",
+        Op::BitwiseNot => "not",
+        Op::Shl => "shl",
+        Op::Shr => "shr",
+        Op::Eq => "eq",
+        Op::Neq => "neq",
+        Op::Lt => "lt",
+        Op::Gt => "gt",
+        Op::Lte => "lte",
+        Op::Gte => "gte",
+        Op::Load => "load",
+        Op::Store => "store",
+        Op::Load64 => "load64",
+        Op::Store64 => "store64",
+    }
+}
+
+/// Pop the syscall number and `args` arguments, and push the result. Only the
+/// first three arguments are passed on, since no WASI call needs more.
+pub fn syscall(wat: &mut Wat, args: usize) {
+    wat.comment(format!("-- syscall{} --", args));
+    wat.lines(&["call $pop", "local.set $n"]);
+    for (arg, local) in ["$a", "$b", "$c"].iter().enumerate() {
+        if arg < args {
+            wat.line("call $pop");
+        } else {
+            wat.line("i64.const 0");
+        }
+        wat.line(format!("local.set {}", local));
+    }
+    for _ in 3..args {
+        wat.lines(&["call $pop", "drop"]);
+    }
+    wat.lines(&[
+        "local.get $n",
+        "local.get $a",
+        "local.get $b",
+        "local.get $c",
+        "call $syscall",
+        "call $push",
+    ]);
+}
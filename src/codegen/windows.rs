@@ -0,0 +1,108 @@
+//! Running the x86-64 codegen on Windows.
+//!
+//! Windows has no stable syscalls, so `do_syscall` calls into kernel32 instead,
+//! for the Linux syscalls with an obvious equivalent: `read` and `write` become
+//! `ReadFile` and `WriteFile`, with descriptors 0 to 2 standing for the
+//! standard handles and anything else taken as a handle, and `exit` becomes
+//! `ExitProcess`. Every other syscall returns `-ENOSYS`, and failed reads and
+//! writes `-EIO`. There's no argc and argv on the stack, so argv holds the
+//! whole command line as its only argument.
+
+use super::builder::Builder;
+use crate::{asm, asm_line, label, segment};
+
+const ENOSYS: i64 = 38;
+const EIO: i64 = 5;
+
+/// What `do_syscall` calls.
+const IMPORTS: [&str; 5] = [
+    "GetCommandLineA",
+    "GetStdHandle",
+    "ReadFile",
+    "WriteFile",
+    "ExitProcess",
+];
+
+/// Point `args_ptr` at an argc and argv made from the command line. Goes right
+/// after `_start`, while the stack still has nothing on it.
+pub fn gen_args(asm: &mut Builder) {
+    segment!(asm, "bss");
+    label!(asm, "windows_args");
+    asm!(asm, ("resq", "3"));
+    segment!(asm, "text");
+    for function in IMPORTS {
+        asm.insert(format!("extern {}", function));
+    }
+    asm!(
+        asm,
+        /// Shadow space, and realigned from the return address
+        ("sub", "rsp, 40"),
+        ("call", "GetCommandLineA"),
+        ("add", "rsp, 40"),
+        ("lea", "rbx, [rel windows_args]"),
+        ("mov", "qword [rbx], 1"),
+        ("mov", "[rbx + 8], rax"),
+        ("mov", "[args_ptr], rbx")
+    );
+}
+
+/// `do_syscall`, which takes the Linux syscall number in rax and arguments in
+/// rdi, rsi and rdx, and returns the result in rax.
+pub fn gen_syscall(asm: &mut Builder) {
+    label!(asm, "do_syscall");
+    asm!(
+        asm,
+        ("push", "rbp"),
+        ("mov", "rbp, rsp"),
+        /// Calls need rsp 16 byte aligned, with 32 bytes of shadow space
+        ("and", "rsp, -16"),
+        ("sub", "rsp, 48"),
+        ("cmp", "rax, 0"),
+        ("je", ".read"),
+        ("cmp", "rax, 1"),
+        ("je", ".write"),
+        ("cmp", "rax, 60"),
+        ("je", ".exit"),
+        /// exit_group
+        ("cmp", "rax, 231"),
+        ("je", ".exit"),
+        ("mov", "rax, {}", -ENOSYS),
+        ("jmp", ".done")
+    );
+    label!(asm, ".exit");
+    asm!(asm, ("mov", "ecx, edi"), ("call", "ExitProcess"));
+    for (name, function) in [("read", "ReadFile"), ("write", "WriteFile")] {
+        label!(asm, ".{}", name);
+        asm!(
+            asm,
+            /// The count, later overwritten with how much was transferred
+            ("mov", "[rsp + 40], rdx"),
+            ("mov", "rcx, rdi"),
+            ("cmp", "rdi, 2"),
+            ("ja", ".{}_handle", name),
+            /// STD_INPUT_HANDLE, STD_OUTPUT_HANDLE or STD_ERROR_HANDLE
+            ("mov", "ecx, -10"),
+            ("sub", "ecx, edi"),
+            ("call", "GetStdHandle"),
+            ("mov", "rcx, rax")
+        );
+        label!(asm, ".{}_handle", name);
+        asm!(
+            asm,
+            ("mov", "rdx, rsi"),
+            ("mov", "r8d, [rsp + 40]"),
+            ("lea", "r9, [rsp + 40]"),
+            /// No OVERLAPPED
+            ("mov", "qword [rsp + 32], 0"),
+            ("call", "{}", function),
+            ("test", "eax, eax"),
+            ("jz", ".failed"),
+            ("mov", "eax, [rsp + 40]"),
+            ("jmp", ".done")
+        );
+    }
+    label!(asm, ".failed");
+    asm!(asm, ("mov", "rax, {}", -EIO));
+    label!(asm, ".done");
+    asm!(asm, ("mov", "rsp, rbp"), ("pop", "rbp"), ("ret"));
+}
@@ -0,0 +1,139 @@
+//! Defaults for the options of `worthc build` and `run`, so a project's
+//! needn't be given every time. They're the `[defaults]` of the
+//! `.worth.toml` or `worth.toml` in the directory worthc runs in or the
+//! closest one above it, which can be the project's manifest:
+//!
+//! ```toml
+//! [defaults]
+//! keep-asm = true
+//! out-dir = "build"
+//! include = ["lib"]
+//! target = "aarch64-linux"
+//! strict = true
+//! ```
+//!
+//! Paths are relative to the file. What's given on the command line is used
+//! over them. `include` is where every command looks for includes; the rest
+//! are only for `build` and `run`, since only they build anything.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::{parser::ValueSource, ArgMatches, ValueEnum};
+
+use crate::{
+    cli::{Cli, Command, Target},
+    error::{Error::ManifestError, ManifestError::*},
+    manifest::{self, MANIFEST},
+};
+
+/// Read before `MANIFEST` in the same directory, for defaults that aren't the
+/// project's to share
+pub const CONFIG: &str = ".worth.toml";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// The directory the file is in, which its paths are relative to
+    pub dir: PathBuf,
+    pub keep_asm: bool,
+    pub out_dir: Option<PathBuf>,
+    /// Looked in for includes, after the manifest's
+    pub include: Vec<PathBuf>,
+    pub target: Option<Target>,
+    pub strict: bool,
+}
+
+impl Config {
+    /// The defaults in `text`, which is in `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Result<Config> {
+        let invalid = |why: String| ManifestError(InvalidManifest(why));
+        let mut config = Config {
+            dir: dir.to_path_buf(),
+            ..Config::default()
+        };
+        let tables = manifest::parse_tables(text)?;
+        let defaults = tables
+            .iter()
+            .filter(|(table, _)| table == "defaults")
+            .flat_map(|(_, fields)| fields);
+        for (key, value) in defaults {
+            let bool = || {
+                value
+                    .as_bool()
+                    .ok_or_else(|| invalid(format!("defaults.{} isn't a boolean", key)))
+            };
+            let string = || {
+                value
+                    .as_str()
+                    .ok_or_else(|| invalid(format!("defaults.{} isn't a string", key)))
+            };
+            match key.as_str() {
+                "keep-asm" => config.keep_asm = bool()?,
+                "strict" => config.strict = bool()?,
+                "out-dir" => config.out_dir = Some(PathBuf::from(string()?)),
+                "include" => {
+                    config.include = value.as_paths().ok_or_else(|| {
+                        invalid("defaults.include isn't a list of strings".to_string())
+                    })?
+                }
+                "target" => {
+                    let target = string()?;
+                    config.target = Some(Target::from_str(target, false).map_err(|_| {
+                        invalid(format!("defaults.target {} isn't a target", target))
+                    })?)
+                }
+                _ => return Err(invalid(format!("unknown default {}", key)).into()),
+            }
+        }
+        Ok(config)
+    }
+
+    /// The defaults in the file at `path`.
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.to_string_lossy()))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        Config::parse(&text, dir).with_context(|| format!("In {}", path.to_string_lossy()))
+    }
+
+    /// The closest file with defaults to `dir`, in it or a directory above it.
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .flat_map(|dir| [dir.join(CONFIG), dir.join(MANIFEST)])
+            .find(|path| path.is_file())
+    }
+
+    /// Where includes are looked for.
+    pub fn search_path(&self) -> Vec<PathBuf> {
+        self.include.iter().map(|dir| self.dir.join(dir)).collect()
+    }
+
+    /// Give `args` the defaults for the options `matches`, which they were
+    /// parsed from, didn't give.
+    pub fn apply(&self, args: &mut Cli, matches: &ArgMatches) {
+        let Some((_, matches)) = matches.subcommand() else {
+            return;
+        };
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let out_dir = self.out_dir.as_ref().map(|dir| self.dir.join(dir));
+        match &mut args.command {
+            Some(Command::Build(opt)) => {
+                opt.keep_asm |= self.keep_asm;
+                opt.strict |= self.strict;
+                opt.out_dir = opt.out_dir.take().or(out_dir);
+                if let Some(target) = self.target.filter(|_| !given("target")) {
+                    opt.target = target;
+                }
+            }
+            Some(Command::Run(opt)) => {
+                opt.keep_asm |= self.keep_asm;
+                opt.strict |= self.strict;
+                opt.out_dir = opt.out_dir.take().or(out_dir);
+                if let Some(target) = self.target.filter(|_| !given("target")) {
+                    opt.target = target;
+                }
+            }
+            _ => {}
+        }
+    }
+}
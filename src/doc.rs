@@ -0,0 +1,177 @@
+//! `worthc doc`, which documents the macros a program defines as Markdown or
+//! HTML: each one's name, the comments on the lines right before it, the stack
+//! effect it checks with and where it's defined.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    cli::{DocFormat, DocOptions},
+    error::{err_loc, Error::IOError, IOError::*},
+    log::{self, LogLevel},
+    parser::{self, Token, TokenType},
+    program::load_program,
+    typecheck,
+};
+
+/// A macro and what to say about it.
+#[derive(Debug, Clone)]
+pub struct Word {
+    pub name: String,
+    /// Its comment lines, without the slashes
+    pub doc: Vec<String>,
+    pub effect: Option<String>,
+    pub loc: (String, usize, usize),
+}
+
+fn is_keyword(token: &Token, keyword: &str) -> bool {
+    matches!(token.ty, TokenType::Keyword) && token.value == keyword
+}
+
+/// Whether `tokens[at]` is the first thing on its line.
+fn starts_line(tokens: &[Token], at: usize) -> bool {
+    at == 0 || tokens[at - 1].location.1 != tokens[at].location.1
+}
+
+/// The comment lines right before `tokens[at]`, which have nothing else on
+/// them.
+fn doc_comment(tokens: &[Token], at: usize) -> Vec<String> {
+    let mut doc = Vec::new();
+    if !starts_line(tokens, at) {
+        return doc;
+    }
+    let mut line = tokens[at].location.1;
+    for before in (0..at).rev() {
+        let token = &tokens[before];
+        if !matches!(token.ty, TokenType::Comment)
+            || token.location.1 + 1 != line
+            || !starts_line(tokens, before)
+        {
+            break;
+        }
+        line = token.location.1;
+        let text = token.value.trim_start_matches('/');
+        doc.push(text.strip_prefix(' ').unwrap_or(text).to_string());
+    }
+    doc.reverse();
+    doc
+}
+
+/// The macros defined in `file` itself, not what it includes, in order.
+pub fn words(file: &PathBuf) -> Result<Vec<Word>> {
+    let program = load_program(file).with_context(|| format!("Failed to load {:?}.", file))?;
+    let source = std::fs::read_to_string(file)
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not read {}", file.to_string_lossy()))?;
+    let name = format!("{}.porth", program.name);
+    let tokens = parser::parse_program(parser::Span::new_extra(&source, &name))?;
+
+    let mut words = Vec::new();
+    for (at, pair) in tokens.windows(2).enumerate() {
+        if !is_keyword(&pair[0], "macro") || !matches!(pair[1].ty, TokenType::Name) {
+            continue;
+        }
+        let effect = program
+            .macros
+            .get(&pair[1].value)
+            .and_then(|macro_| typecheck::macro_effect(&program, &macro_.body));
+        words.push(Word {
+            name: pair[1].value.clone(),
+            doc: doc_comment(&tokens, at),
+            effect,
+            loc: pair[0].location.clone(),
+        });
+    }
+    Ok(words)
+}
+
+pub fn markdown(title: &str, words: &[Word]) -> String {
+    let mut out = format!("# {}\n", title);
+    for word in words {
+        out += &format!("\n## `{}`\n\n", word.name);
+        if !word.doc.is_empty() {
+            out += &format!("{}\n\n", word.doc.join("\n"));
+        }
+        if let Some(effect) = &word.effect {
+            out += &format!("Stack effect: `{}`\n\n", effect);
+        }
+        out += &format!("Defined at `{}`\n", err_loc(&word.loc));
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn html(title: &str, words: &[Word]) -> String {
+    let title = escape_html(title);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n",
+        title, title
+    );
+    for word in words {
+        let name = escape_html(&word.name);
+        out += &format!(
+            "<section id=\"{}\">\n<h2><code>{}</code></h2>\n",
+            name, name
+        );
+        if !word.doc.is_empty() {
+            out += &format!("<p>{}</p>\n", escape_html(&word.doc.join("\n")));
+        }
+        if let Some(effect) = &word.effect {
+            out += &format!(
+                "<p>Stack effect: <code>{}</code></p>\n",
+                escape_html(effect)
+            );
+        }
+        out += &format!(
+            "<p>Defined at <code>{}</code></p>\n</section>\n",
+            escape_html(&err_loc(&word.loc))
+        );
+    }
+    out + "</body>\n</html>\n"
+}
+
+/// Document `file`, to `opt.output` or stdout.
+pub fn run(file: &PathBuf, opt: &DocOptions) -> Result<()> {
+    let words = words(file)?;
+    let title = file
+        .file_stem()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let is_html = |path: &Path| {
+        path.extension()
+            .is_some_and(|ext| ext == "html" || ext == "htm")
+    };
+    let format = opt.format.unwrap_or(match &opt.output {
+        Some(path) if is_html(path) => DocFormat::Html,
+        _ => DocFormat::Markdown,
+    });
+    let doc = match format {
+        DocFormat::Markdown => markdown(&title, &words),
+        DocFormat::Html => html(&title, &words),
+    };
+    match &opt.output {
+        Some(path) => {
+            std::fs::write(path, doc)
+                .map_err(|e| IOError(Inherited(e)))
+                .with_context(|| format!("Could not write {}", path.to_string_lossy()))?;
+            log::log(
+                LogLevel::Info,
+                format!(
+                    "Documented {} macros in {}",
+                    words.len(),
+                    path.to_string_lossy()
+                ),
+                false,
+            );
+        }
+        None => print!("{}", doc),
+    }
+    Ok(())
+}
@@ -0,0 +1,157 @@
+//! The `dump-*` commands, which print what a stage of the compiler made of a
+//! program, for debugging the compiler and for tools that want to know.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    cli::DumpOptions,
+    error::{err_loc, Error::IOError, IOError::*},
+    instruction::{InstructionKind, Keyword, Value},
+    json::Json,
+    parser::{self, TokenType},
+    program::load_program,
+};
+
+/// What kind of token `ty` is, in a word.
+fn token_kind(ty: &TokenType) -> &'static str {
+    match ty {
+        TokenType::Intrinsic(_) => "intrinsic",
+        TokenType::Name => "name",
+        TokenType::Comment => "comment",
+        TokenType::Op => "op",
+        TokenType::Keyword => "keyword",
+        TokenType::Value(Value::Int(_)) => "int",
+        TokenType::Value(Value::Str(_)) => "string",
+        TokenType::Value(Value::Char(_)) => "char",
+        TokenType::Value(Value::Ptr(_)) => "ptr",
+        TokenType::Value(Value::Bool(_)) => "bool",
+        TokenType::Syscall(_) => "syscall",
+    }
+}
+
+/// Print the tokens of the program in `file`, without preprocessing it, so
+/// it's only what that file has.
+pub fn tokens(file: &Path, opt: &DumpOptions) -> Result<()> {
+    let source = std::fs::read_to_string(file)
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not read {}", file.to_string_lossy()))?;
+    let name = file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tokens = parser::parse_program(parser::Span::new_extra(&source, &name))
+        .with_context(|| format!("Could not lex {}", file.to_string_lossy()))?;
+
+    if opt.json {
+        for token in &tokens {
+            let (file, line, col) = &token.location;
+            let token = Json::object([
+                ("kind", token_kind(&token.ty).into()),
+                ("value", token.value.as_str().into()),
+                ("file", file.as_str().into()),
+                ("line", (*line).into()),
+                ("col", (*col).into()),
+            ]);
+            println!("{}", token);
+        }
+        return Ok(());
+    }
+    let rows: Vec<_> = tokens
+        .iter()
+        .map(|token| {
+            (
+                err_loc(&token.location),
+                token_kind(&token.ty),
+                &token.value,
+            )
+        })
+        .collect();
+    let width = rows.iter().map(|(loc, ..)| loc.len()).max().unwrap_or(0);
+    for (loc, kind, value) in rows {
+        println!("{:width$}  {:9}  {:?}", loc, kind, value, width = width);
+    }
+    Ok(())
+}
+
+/// Where `kind` jumps to, if it's control flow that can.
+fn jump(kind: &InstructionKind) -> Option<usize> {
+    match kind {
+        InstructionKind::Keyword(Keyword::Do { end_ip })
+        | InstructionKind::Keyword(Keyword::Elif { end_ip, .. })
+        | InstructionKind::Keyword(Keyword::Else { end_ip, .. }) => Some(*end_ip),
+        InstructionKind::Keyword(Keyword::End { while_ip, .. }) => *while_ip,
+        _ => None,
+    }
+}
+
+/// Print the instructions of the program in `file` once it's preprocessed, with
+/// where control flow jumps and the macro each came from.
+pub fn ir(file: &Path, opt: &DumpOptions) -> Result<()> {
+    let program = load_program(&PathBuf::from(file))
+        .with_context(|| format!("Failed to load {:?}.", file))?;
+    // Code expanded from a macro keeps the locations of its body
+    let mut macros = HashMap::new();
+    for macro_ in program.macros.values() {
+        for inst in &macro_.body {
+            macros.insert(&inst.loc, macro_.name.as_str());
+        }
+    }
+
+    if opt.json {
+        for inst in &program.instructions {
+            let (file, line, col) = &inst.loc;
+            let inst = Json::object([
+                ("ip", inst.ip.into()),
+                ("instruction", inst.kind.to_string().into()),
+                ("jump", jump(&inst.kind).map_or(Json::Null, Json::from)),
+                ("file", file.as_str().into()),
+                ("line", (*line).into()),
+                ("col", (*col).into()),
+                (
+                    "macro",
+                    macros
+                        .get(&inst.loc)
+                        .map_or(Json::Null, |&name| name.into()),
+                ),
+            ]);
+            println!("{}", inst);
+        }
+        return Ok(());
+    }
+    let rows: Vec<[String; 5]> = program
+        .instructions
+        .iter()
+        .map(|inst| {
+            let jump = jump(&inst.kind).map(|ip| format!("-> {}", ip));
+            let from = macros.get(&inst.loc).map(|name| format!("in {}", name));
+            [
+                inst.ip.to_string(),
+                inst.kind.to_string(),
+                jump.unwrap_or_default(),
+                err_loc(&inst.loc),
+                from.unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let width = |column: usize| rows.iter().map(|row| row[column].len()).max().unwrap_or(0);
+    let widths = [width(0), width(1), width(2), width(3)];
+    for [ip, inst, jump, loc, from] in &rows {
+        let line = format!(
+            "{:>w0$}  {:w1$}  {:w2$}  {:w3$}  {}",
+            ip,
+            inst,
+            jump,
+            loc,
+            from,
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3]
+        );
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
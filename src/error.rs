@@ -1,12 +1,16 @@
-use std::{collections::HashMap, mem::ManuallyDrop};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use thiserror::Error;
 
 use crate::{
+    cli::FmtConfig,
     instruction::{Instruction, InstructionKind, Value},
-    parser::{Token, TokenType},
+    json::Json,
+    parser::{escape, Token, TokenType},
 };
 
 pub trait BoolError {
+    #[allow(clippy::result_unit_err)]
     fn to_err(self) -> anyhow::Result<(), ()>;
 }
 
@@ -22,20 +26,56 @@ impl BoolError for bool {
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("[Compile Error] {0}")]
     CompileError(CompileError),
-    #[error("[Parse Error] {0}")]
     ParseError(ParseError),
-    #[error("[Preprocessor Error] {0}")]
     PreprocessorError(PreprocessorError),
-    #[error("[Runtime Error] {0}")]
     RuntimeError(RuntimeError),
-    #[error("[Runner Error] {0}")]
     RunnerError(RunnerError),
-    #[error("[Typecheck Error] {0}")]
     TypecheckError(TypecheckError),
-    #[error("[IO Error] {0}")]
     IOError(IOError),
+    ManifestError(ManifestError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (category, e): (_, &dyn std::fmt::Display) = match self {
+            Error::CompileError(e) => ("Compile", e),
+            Error::ParseError(e) => ("Parse", e),
+            Error::PreprocessorError(e) => ("Preprocessor", e),
+            Error::RuntimeError(e) => ("Runtime", e),
+            Error::RunnerError(e) => ("Runner", e),
+            Error::TypecheckError(e) => ("Typecheck", e),
+            Error::IOError(e) => ("IO", e),
+            Error::ManifestError(e) => ("Manifest", e),
+        };
+        match self.id() {
+            Some(id) => write!(f, "[{} Error {}] {}", category, id, e),
+            None => write!(f, "[{} Error] {}", category, e),
+        }
+    }
+}
+
+impl Error {
+    /// Stable identifier for the error kind, e.g. `TypecheckError::StackUnderflow`.
+    pub fn code(&self) -> String {
+        let (category, kind) = match self {
+            Error::CompileError(e) => ("CompileError", format!("{:?}", e)),
+            Error::ParseError(e) => ("ParseError", format!("{:?}", e)),
+            Error::PreprocessorError(e) => ("PreprocessorError", format!("{:?}", e)),
+            Error::RuntimeError(e) => ("RuntimeError", format!("{:?}", e)),
+            Error::RunnerError(e) => ("RunnerError", format!("{:?}", e)),
+            Error::TypecheckError(e) => ("TypecheckError", format!("{:?}", e)),
+            Error::IOError(e) => ("IOError", format!("{:?}", e)),
+            Error::ManifestError(e) => ("ManifestError", format!("{:?}", e)),
+        };
+        let variant = kind.split('(').next().unwrap_or_default();
+        format!("{}::{}", category, variant)
+    }
+
+    /// The code `worthc explain` knows the error kind by, e.g. `W0015`.
+    pub fn id(&self) -> Option<&'static str> {
+        crate::explain::code(&self.code())
+    }
 }
 
 #[derive(Error, Debug)]
@@ -48,22 +88,30 @@ pub enum TypecheckError {
     InvalidStack,
     #[error("Include found in program")]
     IncludeInCode,
+    #[error("Extern found in program")]
+    ExternInCode,
     #[error("Macro found in program")]
     MacroInCode,
+    #[error("Memory found in program")]
+    MemoryInCode,
     #[error("Invalid end encountered")]
     InvalidEnd,
     #[error("Invalid else encountered")]
     InvalidElse,
     #[error("Invalid loop encountered")]
     InvalidLoop,
+    #[error("Stack depth limit exceeded")]
+    StackDepthExceeded,
+    #[error("Unknown name {0}")]
+    UnknownName(String),
 }
 
 #[derive(Error, Debug)]
 pub enum RunnerError {
     #[error("Failed to invoke program: {0}")]
     InvokeError(std::io::Error),
-    #[error("Program exited with non-zero status: {0}")]
-    NonZeroStatus(usize),
+    #[error("Program timed out")]
+    TimeoutExceeded,
 }
 
 #[derive(Error, Debug)]
@@ -76,6 +124,24 @@ pub enum IOError {
     InvalidPath,
     #[error("No file extension")]
     NoFileExtension,
+    #[error("Invalid JSON")]
+    InvalidJson,
+    #[error("Invalid ELF")]
+    InvalidElf,
+    #[error("Invalid test case: {0}")]
+    InvalidTestCase(String),
+    #[error("Invalid IR: {0}")]
+    InvalidIr(String),
+}
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+    #[error("Missing {0}")]
+    MissingField(&'static str),
+    #[error("Could not fetch dependency {0}")]
+    FetchFailed(String),
 }
 
 #[derive(Error, Debug)]
@@ -90,6 +156,16 @@ pub enum CompileError {
     LdLinkError,
     #[error("Unexpected token: {0}")]
     UnexpectedToken(String),
+    #[error("Unsupported target: {0}")]
+    UnsupportedTarget(String),
+    #[error("Unknown stack depth")]
+    UnknownStackDepth,
+    #[error("Could not assemble `{0}`")]
+    AssembleError(String),
+    #[error("Built-in linker error")]
+    LinkError,
+    #[error("C library not linked, needed for {0}")]
+    LibcNotLinked(String),
 }
 
 #[derive(Error, Debug)]
@@ -102,6 +178,8 @@ pub enum ParseError {
     UnknownKeyword,
     #[error("Unexpected token: {0}")]
     UnexpectedToken(String),
+    #[error("Integer too large: {0}")]
+    IntegerTooLarge(String),
 }
 
 #[derive(Error, Debug)]
@@ -122,6 +200,10 @@ pub enum PreprocessorError {
     UnexpectedMacroEnd,
     #[error("Unclosed {0} block")]
     UnclosedBlock(String),
+    #[error("Invalid extern {0}")]
+    InvalidExtern(String),
+    #[error("Invalid memory {0}")]
+    InvalidMemory(String),
 }
 
 #[derive(Error, Debug)]
@@ -138,19 +220,142 @@ pub enum RuntimeError {
     MacroNotExpanded,
     #[error("Name not resolved")]
     NameNotResolved,
+    #[error("C functions can't be simulated")]
+    ExternNotSimulated,
     #[error("Buffer overflow")]
     BufferOverflow,
+    #[error("Invalid breakpoint")]
+    InvalidBreakpoint,
+    #[error("Step limit exceeded")]
+    StepLimitExceeded,
+    #[error("Timeout exceeded")]
+    TimeoutExceeded,
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Invalid alignment")]
+    InvalidAlignment,
+}
+
+/// Error context tied to a source location. Displays exactly like the message it wraps,
+/// but lets tooling recover the location with `anyhow::Error::downcast_ref`.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub loc: (String, usize, usize),
+    rendered: String,
+}
+
+impl Diagnostic {
+    pub fn new(loc: &(String, usize, usize), message: String, rendered: String) -> Self {
+        Self {
+            message,
+            loc: loc.clone(),
+            rendered,
+        }
+    }
+
+    /// Uses the first line of `rendered` as the message.
+    pub fn at(loc: &(String, usize, usize), rendered: String) -> Self {
+        let message = rendered.lines().next().unwrap_or_default().to_owned();
+        Self::new(loc, message, rendered)
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.rendered)
+    }
+}
+
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// What a diagnostic says about an error.
+pub struct Summary<'a> {
+    pub code: Option<String>,
+    /// Its code for `worthc explain`
+    pub id: Option<&'static str>,
+    /// Without color
+    pub message: String,
+    /// Where it happened, if that's known
+    pub loc: Option<&'a (String, usize, usize)>,
+}
+
+pub fn summary(err: &anyhow::Error) -> Summary<'_> {
+    let code = err.downcast_ref::<Error>().map(Error::code);
+    let id = err.downcast_ref::<Error>().and_then(Error::id);
+    let (message, loc) = match err.downcast_ref::<Diagnostic>() {
+        Some(diag) => (diag.message.clone(), Some(&diag.loc)),
+        None => (
+            err.to_string()
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_owned(),
+            None,
+        ),
+    };
+    Summary {
+        code,
+        id,
+        message: strip_ansi(message.trim()),
+        loc,
+    }
+}
+
+/// Renders an error as a single-line JSON diagnostic for `--message-format=json`,
+/// with the line and column counted from 1.
+pub fn diagnostic_json(err: &anyhow::Error) -> String {
+    let Summary {
+        code,
+        id,
+        message,
+        loc,
+    } = summary(err);
+    let (file, line, column) = match loc {
+        Some((file, line, column)) => (
+            Json::from(file.as_str()),
+            Json::from(*line),
+            Json::from(column + 1),
+        ),
+        None => (Json::Null, Json::Null, Json::Null),
+    };
+    Json::object([
+        ("severity", "error".into()),
+        ("message", message.into()),
+        ("file", file),
+        ("line", line),
+        ("column", column),
+        ("code", code.into()),
+        ("id", id.into()),
+    ])
+    .to_string()
 }
 
 pub struct FmtToken<'a> {
-    pub prefix: String,
     pub color: String,
     pub value: String,
     pub postcolor: String,
-    pub postfix: String,
     pub loc: &'a (String, usize, usize),
     pub kind: FmtTokenKind,
-    pub indent_level: usize,
+    /// Columns the line it starts is indented by
+    pub indent: usize,
+    /// Starts a line of its own, continuing one that got too long
+    pub wrap: bool,
 }
 
 pub enum FmtTokenKind {
@@ -159,17 +364,21 @@ pub enum FmtTokenKind {
 }
 
 pub trait RenderFmt {
-    fn render(&self, start_line: usize, line_numbers: bool, err: bool) -> String;
-    fn format(&mut self) -> &mut Self;
+    fn render(&self, line_numbers: bool, err: bool) -> String;
+    fn format(&mut self, config: &FmtConfig) -> &mut Self;
 }
 
 impl<'a> RenderFmt for Vec<FmtToken<'a>> {
-    fn render(&self, start_line: usize, line_numbers: bool, err: bool) -> String {
+    fn render(&self, line_numbers: bool, err: bool) -> String {
         let mut curr_line_no = 0;
         let mut lines = Vec::new();
         let mut line = String::new();
 
         for inst in self {
+            if inst.wrap {
+                lines.push(line.trim_end_matches(' ').to_owned());
+                line = String::new();
+            }
             if inst.loc.1 != curr_line_no {
                 lines.push(line.trim_end_matches(' ').to_owned());
                 line = String::new();
@@ -190,6 +399,10 @@ impl<'a> RenderFmt for Vec<FmtToken<'a>> {
                     };
                     lines.push(format!("{:.>len$}↓| ...", ""))
                 }
+                // Blank lines between code are kept, a run of them as one
+                if !err && curr_line_no != 0 && inst.loc.1 > curr_line_no + 1 {
+                    lines.push(String::new());
+                }
                 curr_line_no = inst.loc.1;
             }
             if line.is_empty() {
@@ -197,166 +410,82 @@ impl<'a> RenderFmt for Vec<FmtToken<'a>> {
                     let len = curr_line_no.to_string().len();
                     line += &format!("{:.<len$}| ", curr_line_no);
                 }
-            }
-            if err {
-                line.push_str(&inst.prefix.replace("\n", ""));
-            } else {
-                line.push_str(&inst.prefix.replacen("\n", "", 1));
-            }
-            if line.trim_matches('\n').len() == 0
-                && !matches!(
-                    inst.kind,
-                    FmtTokenKind::Instruction(InstructionKind::Keyword(_))
-                        | FmtTokenKind::Token(TokenType::Keyword)
-                )
-            {
-                line.push_str(&" ".repeat(inst.indent_level * 4));
+                line.push_str(&" ".repeat(inst.indent));
             }
             line.push_str(&inst.color);
             line.push_str(&inst.value);
             line.push_str(&inst.postcolor);
-            if err {
-                line.push_str(&inst.postfix.replace("\n", ""));
-            } else {
-                line.push_str(&inst.postfix.replacen("\n", "", 1));
-            }
+            line.push(' ');
         }
         lines.push(line.trim_end_matches(' ').to_owned());
         lines.join("\n").trim_start().to_owned()
     }
 
-    fn format(&mut self) -> &mut Self {
-        let program = self;
-
-        let mut indent = 0;
-        let mut prev_newline = false;
-
-        let mut ip = 0;
-        let program_len = program.len();
-        while ip < program_len {
-            let mut tok = &mut program[ip];
-            let curr_prev_newline = prev_newline;
-            let curr_indent = indent;
+    fn format(&mut self, config: &FmtConfig) -> &mut Self {
+        // How deep in blocks the next token is
+        let mut depth: usize = 0;
+        // How wide the line is so far, and which one it is
+        let mut width = 0;
+        let mut line_no = 0;
+        let mut prev_push = false;
 
+        for tok in self.iter_mut() {
             use FmtTokenKind::*;
-            match &tok.kind {
+            let keyword = match &tok.kind {
                 Token(TokenType::Keyword) | Instruction(InstructionKind::Keyword(_)) => {
-                    match tok.value.as_str() {
-                        "while" => {
-                            if !curr_prev_newline {
-                                tok.prefix = "\n\n".to_owned();
-                            }
-                            tok.postfix = " ".to_owned();
-                            indent += 1;
-                            prev_newline = false;
-                        }
-                        "if" => {
-                            if !curr_prev_newline {
-                                tok.prefix = "\n".to_owned();
-                            }
-                            tok.prefix.push_str(&" ".repeat(curr_indent * 4));
-                            tok.postfix = " ".to_owned();
-                            indent += 1;
-                            prev_newline = false;
-                        }
-                        "else" => {
-                            if !curr_prev_newline {
-                                tok.prefix = "\n".to_owned();
-                                tok.prefix.push_str(&" ".repeat((curr_indent - 1) * 4));
-                            } else {
-                                tok.prefix.push_str(&" ".repeat((curr_indent - 1) * 4));
-                            }
-                            tok.postfix = "\n".to_owned();
-                            prev_newline = true;
-                        }
-                        "elif" => {
-                            if !curr_prev_newline {
-                                tok.prefix = "\n".to_owned();
-                                tok.prefix.push_str(&" ".repeat((curr_indent - 1) * 4));
-                            } else {
-                                tok.prefix.push_str(&" ".repeat((curr_indent - 1) * 4));
-                            }
-                            tok.postfix = " ".to_owned();
-                            prev_newline = false;
-                        }
-                        "else if" => {
-                            if !curr_prev_newline {
-                                tok.prefix = "\n".to_owned();
-                                tok.prefix.push_str(&" ".repeat((curr_indent - 1) * 4));
-                            }
-                            tok.postfix = " ".to_owned();
-                            prev_newline = false;
-                        }
-                        "end" => {
-                            if !curr_prev_newline {
-                                tok.prefix = "\n".to_owned();
-                                tok.prefix.push_str(&" ".repeat((curr_indent - 1) * 4));
-                            } else {
-                                tok.prefix.push_str(&" ".repeat((curr_indent - 1) * 4));
-                            }
-                            tok.postfix = "\n".to_owned();
-                            prev_newline = true;
-                            if indent > 0 {
-                                indent -= 1;
-                            }
-                            if ip + 1 < program_len {
-                                tok = &mut program[ip + 1];
-                                if !matches!(tok.kind, FmtTokenKind::Token(TokenType::Keyword)) {
-                                    tok = &mut program[ip];
-                                    tok.postfix.push_str("\n");
-                                }
-                            }
-                        }
-                        "do" => {
-                            tok.postfix = "\n".to_owned();
-                            prev_newline = true;
-                        }
-                        "macro" => {
-                            tok.prefix = "\n".to_owned();
-                            tok.postfix = " ".to_owned();
-                            ip += 1;
-                            tok = &mut program[ip];
-                            if let FmtTokenKind::Token(TokenType::Name) = tok.kind {
-                                tok.postfix = "\n".to_owned();
-                                prev_newline = true;
-                                indent += 1;
-                            } else {
-                                panic!()
-                            }
-                        }
-                        "include" => {
-                            if !curr_prev_newline {
-                                tok.prefix = "\n".to_owned();
-                                tok.prefix.push_str(&" ".repeat(curr_indent * 4));
-                            }
-                            tok.postfix = " ".to_owned();
-                            ip += 1;
-                            tok = &mut program[ip];
-                            if let FmtTokenKind::Token(TokenType::Value(Value::Str(_))) = tok.kind {
-                                tok.postfix = "\n\n".to_owned();
-                                prev_newline = true;
-                            } else {
-                                panic!()
-                            }
-                        }
-                        igl => {
-                            unreachable!("Unexpected keyword {}", igl);
-                        }
-                    }
+                    tok.value.as_str()
                 }
-                _ => {
-                    tok.postfix = " ".to_owned();
-                    prev_newline = false;
-                    if curr_prev_newline {
-                        tok.prefix.push_str(&" ".repeat(curr_indent * 4));
-                    }
+                _ => "",
+            };
+            // Keywords between the parts of a block line up with its start
+            let level = match keyword {
+                "while" | "if" | "macro" | "unsafe" | "memory" => {
+                    depth += 1;
+                    depth - 1
+                }
+                "do" | "elif" | "else if" | "else" => depth.saturating_sub(1),
+                "end" => {
+                    depth = depth.saturating_sub(1);
+                    depth
                 }
+                _ => depth,
+            };
+            tok.indent = level * config.indent;
+
+            let push = matches!(
+                tok.kind,
+                Token(TokenType::Value(_)) | Instruction(InstructionKind::Push(_))
+            );
+            if tok.loc.1 != line_no {
+                line_no = tok.loc.1;
+                width = tok.indent;
+            } else if push
+                && prev_push
+                && config.max_width > 0
+                && width + tok.value.len() > config.max_width
+            {
+                // Long runs of values carry on below, at the same indent so
+                // formatting them again leaves them as they are
+                tok.wrap = true;
+                width = tok.indent;
             }
-            tok.indent_level = curr_indent;
-            ip += 1;
+            width += tok.value.len() + 1;
+            prev_push = push;
         }
 
-        program
+        self
+    }
+}
+
+/// `value` as it would be written in a program.
+fn literal(value: &Value) -> String {
+    match value {
+        Value::Str(s) => format!(
+            "\"{}\"",
+            s.chars().map(|c| escape(c, '"')).collect::<String>()
+        ),
+        Value::Char(c) => format!("'{}'", escape(*c as char, '\'')),
+        other => other.to_string(),
     }
 }
 
@@ -369,24 +498,22 @@ impl<'a> AsFmt<'a> for &'a [Instruction] {
         let mut fmt_tokens = Vec::new();
         for token in self.iter() {
             let token_str = match &token.kind {
-                InstructionKind::Push(val) => match val {
-                    Value::Str(s) => format!("\"{}\"", s),
-                    other => other.to_string(),
-                },
+                InstructionKind::Push(val) => literal(val),
                 InstructionKind::Intrinsic(i) => i.to_string(),
                 InstructionKind::Op(op) => op.to_string(),
                 InstructionKind::Keyword(kw) => kw.to_string(),
                 InstructionKind::Name(name) => name.to_string(),
                 InstructionKind::Syscall(syscall) => syscall.to_string(),
+                InstructionKind::Extern(declaration) => declaration.name.clone(),
+                InstructionKind::Memory(memory) => memory.name.clone(),
             };
 
             fmt_tokens.push(FmtToken {
-                indent_level: 0,
-                prefix: String::new(),
+                indent: 0,
+                wrap: false,
                 color: String::new(),
                 value: token_str.clone(),
                 postcolor: String::new(),
-                postfix: String::new(),
                 loc: &token.loc,
                 kind: FmtTokenKind::Instruction(token.kind.clone()),
             });
@@ -405,20 +532,16 @@ impl<'a> AsFmt<'a> for Vec<Token> {
                 TokenType::Comment => token.value.clone(),
                 TokenType::Op => token.value.clone(),
                 TokenType::Keyword => token.value.clone(),
-                TokenType::Value(v) => match v {
-                    Value::Str(s) => format!("\"{}\"", s),
-                    v => v.to_string(),
-                },
-                TokenType::Syscall(_) => todo!(),
+                TokenType::Value(v) => literal(v),
+                TokenType::Syscall(_) => token.value.clone(),
             };
 
             fmt_tokens.push(FmtToken {
-                indent_level: 0,
-                prefix: String::new(),
+                indent: 0,
+                wrap: false,
                 color: String::new(),
                 value: token_str.clone(),
                 postcolor: String::new(),
-                postfix: String::new(),
                 loc: &token.location,
                 kind: FmtTokenKind::Token(token.ty.clone()),
             });
@@ -427,6 +550,14 @@ impl<'a> AsFmt<'a> for Vec<Token> {
     }
 }
 
+/// Whether diagnostics are colored, which they are unless `--color` says
+/// otherwise.
+static COLOR: AtomicBool = AtomicBool::new(true);
+
+pub fn set_color(color: bool) {
+    COLOR.store(color, Ordering::Relaxed);
+}
+
 pub enum Highlight {
     Warning,
     Error,
@@ -436,6 +567,9 @@ pub fn highlight_program<'a>(
     program: &mut Vec<FmtToken<'a>>,
     highlights: HashMap<usize, Highlight>,
 ) {
+    if !COLOR.load(Ordering::Relaxed) {
+        return;
+    }
     program.iter_mut().enumerate().for_each(|(ip, tok)| {
         if let Some(highlight) = highlights.get(&ip) {
             match highlight {
@@ -462,8 +596,6 @@ pub fn err_spread(program: &Vec<Instruction>, ip: usize, secondary: Option<usize
     let end = (ip + spread_len).min(program.len());
     let spread = &program[start..end];
 
-    let first_line = program[start].loc.1 - 1;
-
     let mut tokens = spread.as_fmt();
     let mut highlights = HashMap::new();
     highlights.insert(ip - start, Highlight::Error);
@@ -471,7 +603,13 @@ pub fn err_spread(program: &Vec<Instruction>, ip: usize, secondary: Option<usize
         highlights.insert(secondary - start, Highlight::Warning);
     }
     highlight_program(&mut tokens, highlights);
-    tokens.format().render(first_line, true, true)
+    tokens
+        .format(&FmtConfig {
+            // The lines as they are in the source
+            max_width: 0,
+            ..Default::default()
+        })
+        .render(true, true)
 }
 
 pub fn err_loc(loc: &(String, usize, usize)) -> String {
@@ -491,23 +629,31 @@ pub fn kw_str(kw: &str) -> &str {
 macro_rules! err {
     ($program:ident, $kind:expr, $msg:expr, $ip:expr) => {
         return Err($kind).with_context(|| {
-            use crate::error::{err_loc, err_spread};
-            format!(
-                "[{}] {}\n{}\n",
-                err_loc(&$program.instructions[$ip].loc),
-                $msg,
-                err_spread(&$program.instructions, $ip, None)
+            use $crate::error::{err_loc, err_spread, Diagnostic};
+            Diagnostic::new(
+                &$program.instructions[$ip].loc,
+                $msg.to_string(),
+                format!(
+                    "[{}] {}\n{}\n",
+                    err_loc(&$program.instructions[$ip].loc),
+                    $msg,
+                    err_spread(&$program.instructions, $ip, None)
+                ),
             )
         })
     };
     ($program:ident, $kind:expr, $msg:expr, $ip:expr, $last_ip:expr) => {
         return Err($kind).with_context(|| {
-            use crate::error::{err_loc, err_spread};
-            format!(
-                "[{}] {}\n{}\n",
-                err_loc(&$program.instructions[$ip].loc),
-                $msg,
-                err_spread(&$program.instructions, $ip, $last_ip)
+            use $crate::error::{err_loc, err_spread, Diagnostic};
+            Diagnostic::new(
+                &$program.instructions[$ip].loc,
+                $msg.to_string(),
+                format!(
+                    "[{}] {}\n{}\n",
+                    err_loc(&$program.instructions[$ip].loc),
+                    $msg,
+                    err_spread(&$program.instructions, $ip, $last_ip)
+                ),
             )
         })
     };
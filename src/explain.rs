@@ -0,0 +1,771 @@
+//! `worthc explain`, and the codes it knows errors by. Every kind of error has
+//! one, shown in its diagnostic as `[Typecheck Error W0015]`, and only ever
+//! given to it: a new kind gets the next one at the end.
+
+/// A code, the error it's for as `Error::code` calls it, and what to say
+/// about it: a line on what it is, then why it happens, an example and how to
+/// fix it.
+pub const EXPLANATIONS: &[(&str, &str, &str)] = &[
+    (
+        "W0001",
+        "ParseError::Incomplete",
+        "Part of the program couldn't be lexed into tokens.
+
+Lexing stops at the first thing that isn't a token, and everything from there
+on is left over. It's usually an unterminated string or character literal.
+
+For example:
+
+    \"hello puts
+
+Fix it by closing the literal, or removing what isn't meant to be there.
+",
+    ),
+    (
+        "W0002",
+        "ParseError::UnknownOperator",
+        "An operator that doesn't exist.
+
+Something was lexed as an operator but isn't one of +, -, *, /, %, divmod,
+&, |, ^, ~, <<, >>, =, !=, <, >, <=, >=, ., ,, .64 or ,64, or mod, band and
+the others they're written as in words.
+
+Fix it by using one of those, or a macro with a name that isn't an operator.
+",
+    ),
+    (
+        "W0003",
+        "ParseError::UnknownKeyword",
+        "A keyword that doesn't exist.
+
+Something was lexed as a keyword but isn't one of while, do, if, elif, else,
+end, macro, include, unsafe, extern or memory.
+
+Fix it by using one of those.
+",
+    ),
+    (
+        "W0004",
+        "ParseError::UnexpectedToken",
+        "A token where it can't go.
+
+For example, a comment that was lexed where only an instruction can be.
+
+Fix it by moving or removing the token.
+",
+    ),
+    (
+        "W0005",
+        "PreprocessorError::InvalidInclude",
+        "include isn't followed by a string.
+
+The path to include has to be a string literal, written right after it.
+
+For example:
+
+    include std
+
+Fix it by quoting the path:
+
+    include \"std.porth\"
+",
+    ),
+    (
+        "W0006",
+        "PreprocessorError::InvalidFilename",
+        "An included path doesn't name a file.
+
+The path has to end in a file's name, which the included program is called
+by.
+
+Fix it by giving the file's whole path, like \"lib/strings.porth\".
+",
+    ),
+    (
+        "W0007",
+        "PreprocessorError::IncludeNotFound",
+        "An included file couldn't be found.
+
+Includes are looked for next to the file including them, then in the
+directories worth.toml adds and its dependencies.
+
+For example:
+
+    include \"missing.porth\"
+
+Fix it by correcting the path, or adding the directory it's in to include in
+worth.toml.
+",
+    ),
+    (
+        "W0008",
+        "PreprocessorError::TooManyMacroExpansions",
+        "Macros kept expanding into more macros.
+
+Macros are expanded until none are left, up to 100 times, so a macro that uses
+itself, directly or through another, never finishes.
+
+For example:
+
+    macro forever forever end
+    forever
+
+Fix it by using a while loop for the repetition instead.
+",
+    ),
+    (
+        "W0009",
+        "PreprocessorError::RecursiveInclude",
+        "Files include each other without end.
+
+A file that includes itself, directly or through another file, is included
+over and over, up to 100 deep.
+
+Fix it by moving what both files need into a third that neither of them is
+included by.
+",
+    ),
+    (
+        "W0010",
+        "PreprocessorError::UnexpectedKeyword",
+        "A keyword where it can't go.
+
+elif, else and end have to close the block before them, and do has to follow
+the condition of an if, elif or while.
+
+For example:
+
+    1 print else 2 print end
+
+Fix it by opening the block first:
+
+    if true do 1 print else 2 print end
+",
+    ),
+    (
+        "W0011",
+        "PreprocessorError::UnexpectedMacroEnd",
+        "An end that closes a macro that isn't open.
+
+Fix it by removing the extra end, or adding the macro it was meant to close.
+",
+    ),
+    (
+        "W0012",
+        "PreprocessorError::UnclosedBlock",
+        "A block that's never closed.
+
+Every if, while, macro, memory and unsafe needs an end.
+
+For example:
+
+    if true do 1 print
+
+Fix it by adding the end:
+
+    if true do 1 print end
+",
+    ),
+    (
+        "W0013",
+        "PreprocessorError::InvalidExtern",
+        "An extern isn't declared right.
+
+extern takes the C function's name, how many arguments it takes, up to 6, and
+how many results it returns, 0 or 1.
+
+For example:
+
+    extern puts 1
+
+Fix it by giving both counts:
+
+    extern puts 1 1
+",
+    ),
+    (
+        "W0014",
+        "PreprocessorError::InvalidMemory",
+        "A memory isn't declared right.
+
+memory takes a name that isn't declared already and a size in bytes that's
+worked out from constants, then end.
+
+For example:
+
+    memory buf argc end
+
+Fix it by making the size a constant:
+
+    memory buf 1024 end
+",
+    ),
+    (
+        "W0015",
+        "TypecheckError::StackUnderflow",
+        "An instruction needs more values than the stack has.
+
+Every instruction takes its arguments off the top of the stack, so they have
+to be pushed first.
+
+For example:
+
+    1 +
+
+Fix it by pushing what's missing:
+
+    1 2 +
+",
+    ),
+    (
+        "W0016",
+        "TypecheckError::InvalidTypeForOp",
+        "An instruction was given a value of the wrong type.
+
+Values are ints, bools or pointers, and each instruction only takes some of
+them. + can't add two pointers, if needs a bool and so on.
+
+For example:
+
+    if 1 do 2 print end
+
+Fix it by giving it the type it takes, comparing or casting it first:
+
+    if 1 0 != do 2 print end
+",
+    ),
+    (
+        "W0017",
+        "TypecheckError::InvalidStack",
+        "The program ends with more values on the stack than it should.
+
+At the end a program can leave nothing, or an int it exits with.
+
+For example:
+
+    1 2
+
+Fix it by dropping what's left over:
+
+    1 2 drop
+",
+    ),
+    (
+        "W0018",
+        "TypecheckError::IncludeInCode",
+        "An include was left after preprocessing.
+
+Includes are replaced by the files they include before typechecking, so this
+is a bug in the compiler. Please report it, with the program.
+",
+    ),
+    (
+        "W0019",
+        "TypecheckError::ExternInCode",
+        "An extern declaration was left after preprocessing.
+
+Extern declarations are removed before typechecking, so this is a bug in the
+compiler. Please report it, with the program.
+",
+    ),
+    (
+        "W0020",
+        "TypecheckError::MacroInCode",
+        "A macro definition was left after preprocessing.
+
+Macros are expanded and their definitions removed before typechecking, so
+this is a bug in the compiler. Please report it, with the program.
+",
+    ),
+    (
+        "W0021",
+        "TypecheckError::MemoryInCode",
+        "A memory declaration was left after preprocessing.
+
+Memory declarations are removed before typechecking, so this is a bug in the
+compiler. Please report it, with the program.
+",
+    ),
+    (
+        "W0022",
+        "TypecheckError::InvalidEnd",
+        "A block ends with the stack different from how it started.
+
+The stack has to be the same after a while loop as before it, and after an if
+without an else whether it ran or not, since both can be skipped.
+
+For example:
+
+    if true do 1 end
+
+Fix it by giving the if an else that leaves the same, or dropping what it
+pushed:
+
+    if true do 1 else 2 end
+",
+    ),
+    (
+        "W0023",
+        "TypecheckError::InvalidElse",
+        "The branches of an if leave different stacks.
+
+Whichever branch runs, the stack after has to be the same.
+
+For example:
+
+    if true do 1 else end
+
+Fix it by making every branch leave the same types:
+
+    if true do 1 else 0 end
+",
+    ),
+    (
+        "W0024",
+        "TypecheckError::InvalidLoop",
+        "A while loop's condition changes the stack.
+
+The condition runs once more than the body, so it can only push the bool do
+takes.
+
+For example:
+
+    while 1 dup 10 < do end
+
+Fix it by keeping what the loop counts in memory, or on the stack before the
+loop, so the condition leaves it as it found it:
+
+    0 while dup 10 < do 1 + end drop
+",
+    ),
+    (
+        "W0025",
+        "TypecheckError::StackDepthExceeded",
+        "The stack can grow deeper than --max-stack-depth allows.
+
+Fix it by keeping fewer values on the stack at once, putting some in memory,
+or raising the limit.
+",
+    ),
+    (
+        "W0026",
+        "TypecheckError::UnknownName",
+        "A name that isn't a macro, memory or extern.
+
+For example:
+
+    grete
+
+Fix it by correcting the name, or declaring it, or including the file that
+does.
+",
+    ),
+    (
+        "W0027",
+        "CompileError::NasmInvokeError",
+        "nasm couldn't be run.
+
+Builds use nasm to assemble unless --assembler says otherwise.
+
+Fix it by installing nasm, or building with --assembler builtin.
+",
+    ),
+    (
+        "W0028",
+        "CompileError::NasmCompileError",
+        "nasm couldn't assemble the generated assembly.
+
+This is a bug in the compiler, unless nasm is too old. Please report it, with
+the program and what nasm said.
+",
+    ),
+    (
+        "W0029",
+        "CompileError::LdInvokeError",
+        "ld couldn't be run.
+
+Fix it by installing binutils, or building with --linker builtin.
+",
+    ),
+    (
+        "W0030",
+        "CompileError::LdLinkError",
+        "ld couldn't link the program.
+
+With --link-libc or --link-arg, it's usually a library that isn't installed.
+Otherwise this is a bug in the compiler. Please report it, with what ld said.
+",
+    ),
+    (
+        "W0031",
+        "CompileError::UnexpectedToken",
+        "An include or macro was left for codegen.
+
+They're taken care of by the preprocessor, so this is a bug in the compiler.
+Please report it, with the program.
+",
+    ),
+    (
+        "W0032",
+        "CompileError::UnsupportedTarget",
+        "The target, backend or assembler can't build what was asked for.
+
+For example, the C library is only linked for x86_64-linux, and the built-in
+assembler only writes ELF.
+
+Fix it by choosing a target, backend or assembler that can, as the message
+says.
+",
+    ),
+    (
+        "W0033",
+        "CompileError::UnknownStackDepth",
+        "How deep the stack gets couldn't be worked out.
+
+The LLVM backend needs to know, so the program has to typecheck.
+
+Fix it by building without -u/--unsafe, or with the native backend.
+",
+    ),
+    (
+        "W0034",
+        "CompileError::AssembleError",
+        "The built-in assembler didn't understand a line of the generated assembly.
+
+This is a bug in the compiler. Please report it, with the program, and build
+with --assembler nasm until it's fixed.
+",
+    ),
+    (
+        "W0035",
+        "CompileError::LinkError",
+        "The built-in linker couldn't link the program.
+
+Fix it by building with --linker ld, and please report it with the program.
+",
+    ),
+    (
+        "W0036",
+        "CompileError::LibcNotLinked",
+        "An extern is called without the C library linked.
+
+For example:
+
+    extern puts 1 1
+    \"hi\\n\" drop puts drop
+
+Fix it by building with --link-libc.
+",
+    ),
+    (
+        "W0037",
+        "RuntimeError::IOError",
+        "The simulated program read or wrote a file descriptor it can't.
+
+Fix it by only reading what's open for reading and writing what's open for
+writing, checking what open returns first.
+",
+    ),
+    (
+        "W0038",
+        "RuntimeError::StackUnderflow",
+        "The simulated program popped an empty stack.
+
+It only gets past the typechecker in an unsafe block, or with -u/--unsafe.
+
+Fix it by pushing what's popped, as typechecking the program would say.
+",
+    ),
+    (
+        "W0039",
+        "RuntimeError::StringCapacityExceeded",
+        "The simulator ran out of room for string literals.
+
+Fix it by using fewer or shorter strings, or building the program instead.
+",
+    ),
+    (
+        "W0040",
+        "RuntimeError::InvalidMemoryAccess",
+        "The simulated program read or wrote memory it doesn't have.
+
+It's usually a pointer that's gone past the end of mem or a memory, or an int
+used as one.
+
+For example:
+
+    0 , print
+
+Fix it by only loading and storing inside memory the program has.
+",
+    ),
+    (
+        "W0041",
+        "RuntimeError::MacroNotExpanded",
+        "The simulator found a macro left in the program.
+
+Macros are expanded before the program runs, so this is a bug in the
+compiler. Please report it, with the program.
+",
+    ),
+    (
+        "W0042",
+        "RuntimeError::NameNotResolved",
+        "The simulator found a name that isn't anything, or a pointer to a name.
+
+With typechecking off, an unknown name gets as far as running. Pointers to
+names only come from IR written by hand, nothing worthc writes has them.
+
+Fix it by correcting or declaring the name.
+",
+    ),
+    (
+        "W0043",
+        "RuntimeError::ExternNotSimulated",
+        "The simulated program called a C function.
+
+C functions only exist once the program's linked with the C library.
+
+Fix it by building and running the program with --link-libc instead.
+",
+    ),
+    (
+        "W0044",
+        "RuntimeError::BufferOverflow",
+        "More arguments or environment than the simulator has room for.
+
+Fix it by passing fewer or shorter arguments.
+",
+    ),
+    (
+        "W0045",
+        "RuntimeError::InvalidBreakpoint",
+        "A breakpoint that isn't an instruction or a line.
+
+Breakpoints are given as an instruction's number, or FILE:LINE.
+
+For example:
+
+    --break greet.porth
+
+Fix it by adding the line:
+
+    --break greet.porth:3
+",
+    ),
+    (
+        "W0046",
+        "RuntimeError::StepLimitExceeded",
+        "The simulated program ran more instructions than --max-steps allows.
+
+Fix it by raising the limit, or making sure the program's loops finish.
+",
+    ),
+    (
+        "W0047",
+        "RuntimeError::TimeoutExceeded",
+        "The simulated program ran for longer than --timeout allows.
+
+Fix it by raising the limit, or making sure the program's loops finish.
+",
+    ),
+    (
+        "W0048",
+        "RuntimeError::DivisionByZero",
+        "The simulated program divided by zero.
+
+For example:
+
+    1 0 / print
+
+Fix it by checking the divisor isn't zero first.
+",
+    ),
+    (
+        "W0049",
+        "RuntimeError::InvalidAlignment",
+        "align was given something that isn't a power of two.
+
+For example:
+
+    mem 3 align
+
+Fix it by aligning to 1, 2, 4, 8 or another power of two.
+",
+    ),
+    (
+        "W0050",
+        "RunnerError::InvokeError",
+        "The built program couldn't be started.
+
+Fix it by checking it was built for this machine: a program built for another
+target can only be run there.
+",
+    ),
+    (
+        "W0051",
+        "RunnerError::TimeoutExceeded",
+        "The program ran for longer than --timeout allows, and was killed.
+
+Fix it by raising the limit, or making sure the program's loops finish.
+",
+    ),
+    (
+        "W0052",
+        "IOError::Inherited",
+        "A file couldn't be read or written.
+
+Fix it as the message from the operating system says, usually by correcting
+the path or its permissions.
+",
+    ),
+    (
+        "W0053",
+        "IOError::InvalidFilename",
+        "A path doesn't end in a file name worthc can use.
+
+Program names have to be UTF-8, since they name what's built.
+
+Fix it by renaming the file.
+",
+    ),
+    (
+        "W0054",
+        "IOError::InvalidPath",
+        "A path doesn't have a directory it's in.
+
+Fix it by giving the program's path, not a root or empty one.
+",
+    ),
+    (
+        "W0055",
+        "IOError::NoFileExtension",
+        "An output path's extension isn't UTF-8.
+
+The extension says what to build, so it has to be readable.
+
+Fix it by naming the output .asm, .o, .ir or without an extension.
+",
+    ),
+    (
+        "W0056",
+        "IOError::InvalidJson",
+        "A message from a debugger or editor isn't valid JSON.
+
+This is a bug in the debugger or editor. Please report it there.
+",
+    ),
+    (
+        "W0057",
+        "IOError::InvalidElf",
+        "An executable couldn't be read as ELF.
+
+Maps are made from the executable the build wrote, so this is a bug in the
+compiler. Please report it, with the program.
+",
+    ),
+    (
+        "W0058",
+        "IOError::InvalidTestCase",
+        "A test's .txt isn't made of the sections worthc test knows.
+
+Each section starts with a line of :args, :stdin, :stdout or :exit.
+
+For example:
+
+    stdout
+    hi
+
+Fix it by starting the section with a colon:
+
+    :stdout
+    hi
+",
+    ),
+    (
+        "W0059",
+        "IOError::InvalidIr",
+        "A .ir file isn't IR this worthc can read.
+
+It's from another version of worthc, or was changed after it was written.
+
+Fix it by emitting it again with --emit ir.
+",
+    ),
+    (
+        "W0060",
+        "ManifestError::InvalidManifest",
+        "worth.toml or .worth.toml has what they can't.
+
+Only strings, booleans, lists and inline tables are read, and the fields of
+package and defaults have to have the right types.
+
+For example:
+
+    [package]
+    name = hello
+
+Fix it by quoting the string:
+
+    [package]
+    name = \"hello\"
+",
+    ),
+    (
+        "W0061",
+        "ManifestError::MissingField",
+        "worth.toml is missing a field it has to have.
+
+Every manifest has to name its package.
+
+Fix it by adding the field:
+
+    [package]
+    name = \"hello\"
+",
+    ),
+    (
+        "W0062",
+        "ManifestError::FetchFailed",
+        "A dependency couldn't be cloned from git.
+
+Fix it by checking the URL and rev, that git is installed and that the
+repository can be reached.
+",
+    ),
+    (
+        "W0063",
+        "ParseError::IntegerTooLarge",
+        "An integer literal that doesn't fit in 64 bits.
+
+Integers are signed 64 bit, so they go from -9223372036854775808 to
+9223372036854775807.
+
+For example:
+
+    18446744073709551615 print
+
+Fix it by writing the value another way, like 0 1 - for all ones.
+",
+    ),
+];
+
+/// The code for the error `Error::code` calls `error`.
+pub fn code(error: &str) -> Option<&'static str> {
+    EXPLANATIONS
+        .iter()
+        .find(|(_, of, _)| *of == error)
+        .map(|(code, ..)| *code)
+}
+
+/// What `worthc explain` says about `code`, which can be in either case and
+/// without its zeroes, or the name of the error.
+pub fn explain(code: &str) -> Option<String> {
+    let number = code
+        .strip_prefix(['W', 'w'])
+        .and_then(|number| number.parse::<usize>().ok());
+    let (code, error, text) = EXPLANATIONS.iter().find(|(known, error, _)| {
+        *error == code || number.is_some_and(|number| known[1..].parse() == Ok(number))
+    })?;
+    Some(format!("{} ({})\n\n{}", code, error, text))
+}
@@ -1,60 +1,62 @@
-use std::path::PathBuf;
+//! The formatter `worthc fmt` rewrites programs with, or with `--check` only
+//! reports which ones it would.
 
-use anyhow::Context;
-use clap::Parser;
+use std::path::Path;
 
-#[allow(unused)]
-mod cfg;
-#[allow(unused)]
-mod cli;
-#[allow(unused)]
-mod codegen;
-#[allow(unused)]
-mod error;
-#[allow(unused)]
-mod instruction;
-#[allow(unused)]
-mod log;
-#[allow(unused)]
-mod parser;
-#[allow(unused)]
-mod preprocessor;
-#[allow(unused)]
-mod program;
-#[allow(unused)]
-mod runner;
-#[allow(unused)]
-mod sim;
-#[allow(unused)]
-mod typecheck;
+use anyhow::{Context, Result};
 
-use error::{AsFmt, Error::IOError, IOError::*, RenderFmt};
+use crate::{
+    cli::{FmtConfig, FmtOptions, Newline},
+    error::{AsFmt, Error::IOError, IOError::*, RenderFmt},
+    log::{self, LogLevel},
+    parser,
+};
 
-#[derive(Parser, Debug)]
-pub struct Args {
-    files: Vec<PathBuf>,
+/// The program in `source`, formatted.
+pub fn format(source: &str, config: &FmtConfig) -> Result<String> {
+    let program = parser::parse_program(parser::Span::from(source))?;
+    let formatted = program.as_fmt().format(config).render(false, false) + "\n";
+    Ok(match config.newline {
+        Newline::Lf => formatted,
+        Newline::Crlf => formatted.replace('\n', "\r\n"),
+    })
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    for file in &args.files {
-        let path = file
-            .canonicalize()
-            .with_context(|| format!("Failed to canonicalize path {:?}", file))?;
-        let name = path.clone().with_extension("");
-        let name = name
-            .file_name()
-            .ok_or(IOError(InvalidFilename))
-            .with_context(|| format!("Path {:?} does not have a filename", path))?
-            .to_str()
-            .ok_or(IOError(InvalidFilename))
-            .with_context(|| format!("Path {:?} does not have a valid filename", path))?;
-
-        let source = std::fs::read_to_string(&path).map_err(|e| IOError(Inherited(e)))?;
+fn format_file(file: &Path, config: &FmtConfig) -> Result<(String, String)> {
+    let source = std::fs::read_to_string(file)
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not read {}", file.to_string_lossy()))?;
+    let formatted = format(&source, config)
+        .with_context(|| format!("Could not format {}", file.to_string_lossy()))?;
+    Ok((source, formatted))
+}
 
-        let program = parser::parse_program(parser::Span::from(source.as_str()))?;
-        let formatted = program.as_fmt().format().render(0, false, false);
-        std::fs::write(file, formatted)?;
+/// Format every file, or check they already are. Returns whether they all
+/// were.
+pub fn run(opt: &FmtOptions) -> Result<bool> {
+    let mut formatted_already = true;
+    for file in &opt.files {
+        let (source, formatted) = format_file(file, &opt.config)?;
+        if source == formatted {
+            continue;
+        }
+        formatted_already = false;
+        if opt.check {
+            log::log(
+                LogLevel::Warn,
+                format!("{} isn't formatted", file.to_string_lossy()),
+                false,
+            );
+        } else {
+            std::fs::write(file, formatted)
+                .map_err(|e| IOError(Inherited(e)))
+                .with_context(|| format!("Could not write {}", file.to_string_lossy()))?;
+            log::log(
+                LogLevel::Info,
+                format!("Formatted {}", file.to_string_lossy()),
+                false,
+            );
+        }
     }
-    Ok(())
+    Ok(formatted_already)
 }
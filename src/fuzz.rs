@@ -0,0 +1,131 @@
+//! Entry points for fuzzing the front of the compiler, built with the
+//! `fuzzing` feature for the targets in `fuzz/`. Each one takes whatever bytes
+//! the fuzzer made up and only cares that it doesn't panic: errors are what
+//! malformed programs are meant to get. With cargo-fuzz, from the top of the
+//! repository:
+//!
+//! ```text
+//! cargo +nightly fuzz run typecheck
+//! ```
+//!
+//! Arbitrary bytes rarely lex, so `preprocess` and `typecheck` read theirs as
+//! a stream of tokens instead, a byte a token, which gets them past the lexer
+//! into the code that trusts it.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crate::{cli::TypecheckOptions, instruction::Program, parser, preprocessor, typecheck};
+
+/// What the programs are called, so their locations have a file.
+const NAME: &str = "fuzz";
+
+/// What a token stream's bytes pick from. No `include`, so nothing's read from
+/// disk.
+const WORDS: &[&str] = &[
+    "0",
+    "1",
+    "2",
+    "8",
+    "60",
+    "-1",
+    "9223372036854775807",
+    "'a'",
+    "\"s\"",
+    "\"\\n\"",
+    "true",
+    "false",
+    "a",
+    "b",
+    "c",
+    "print",
+    "panic",
+    "dup",
+    "2dup",
+    "swap",
+    "mem",
+    "drop",
+    "2drop",
+    "over",
+    "argc",
+    "argv",
+    "cast(ptr)",
+    "cast(int)",
+    "align",
+    "here",
+    "+",
+    "-",
+    "*",
+    "/",
+    "mod",
+    "divmod",
+    "band",
+    "bor",
+    "bxor",
+    "~",
+    "shl",
+    "shr",
+    "=",
+    "!=",
+    "<",
+    ">",
+    "<=",
+    ">=",
+    ".64",
+    ",64",
+    ".",
+    ",",
+    "syscall0",
+    "syscall1",
+    "syscall3",
+    "syscall6",
+    "while",
+    "do",
+    "if",
+    "elif",
+    "else",
+    "end",
+    "macro",
+    "unsafe",
+    "extern",
+    "memory",
+    "// comment\n",
+];
+
+/// The source of the tokens `data` picks.
+pub fn tokens(data: &[u8]) -> String {
+    data.iter()
+        .map(|&byte| WORDS[byte as usize % WORDS.len()])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_source(source: &str) -> Option<Program> {
+    let path = PathBuf::from(format!("{}.porth", NAME));
+    parser::parse(source.to_string(), NAME, path).ok()
+}
+
+/// Lex and parse `data`, if it's UTF-8.
+pub fn parse(data: &[u8]) {
+    if let Ok(source) = std::str::from_utf8(data) {
+        let _ = parse_source(source);
+    }
+}
+
+/// Preprocess the tokens `data` picks.
+pub fn preprocess(data: &[u8]) {
+    if let Some(program) = parse_source(&tokens(data)) {
+        let _ = preprocessor::process(program);
+    }
+}
+
+/// Preprocess and typecheck the tokens `data` picks.
+pub fn typecheck(data: &[u8]) {
+    let Some(program) = parse_source(&tokens(data)) else {
+        return;
+    };
+    if let Ok(program) = preprocessor::process(program) {
+        let _ = typecheck::typecheck(&program, &TypecheckOptions::parse_from(["worthc"]));
+    }
+}
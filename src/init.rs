@@ -0,0 +1,92 @@
+//! `worthc init`, which makes a new project `worthc build` can build as it is:
+//!
+//! ```text
+//! hello/
+//!   worth.toml
+//!   src/main.porth
+//!   tests/main.porth
+//!   tests/main.txt
+//!   .gitignore
+//! ```
+//!
+//! The test includes the program and says what it should write, so `worthc
+//! test tests` checks it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    cli::InitOptions,
+    error::{Error::IOError, IOError::*},
+    log::{self, LogLevel},
+    manifest::MANIFEST,
+};
+
+const MAIN: &str = "// Writes to stdout, which is descriptor 1, with syscall 1, write
+macro puts 1 1 syscall3 drop end
+
+\"Hello, World!\\n\" puts
+";
+
+const TEST: &str = "include \"../src/main.porth\"
+";
+
+const EXPECTED: &str = ":stdout
+Hello, World!
+";
+
+/// What's built, and where git dependencies are cloned to.
+fn gitignore(name: &str) -> String {
+    format!("/{}\n*.asm\n*.o\n.worth/\n", name)
+}
+
+/// The files of a project called `name`, by where they go in it.
+pub fn files(name: &str) -> Vec<(PathBuf, String)> {
+    let manifest = format!(
+        "[package]\nname = \"{}\"\nentry = \"src/main.porth\"\n",
+        name.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    vec![
+        (PathBuf::from(MANIFEST), manifest),
+        (PathBuf::from("src/main.porth"), MAIN.to_string()),
+        (PathBuf::from("tests/main.porth"), TEST.to_string()),
+        (PathBuf::from("tests/main.txt"), EXPECTED.to_string()),
+        (PathBuf::from(".gitignore"), gitignore(name)),
+    ]
+}
+
+fn write(path: &Path, contents: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| IOError(Inherited(e)))
+            .with_context(|| format!("Could not create {}", dir.to_string_lossy()))?;
+    }
+    std::fs::write(path, contents)
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not write {}", path.to_string_lossy()))?;
+    Ok(())
+}
+
+/// Make the project `opt` says, in a directory that isn't there yet.
+pub fn run(opt: &InitOptions) -> Result<()> {
+    let dir = &opt.dir;
+    let name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(IOError(InvalidFilename))
+        .with_context(|| format!("{} can't be a project's name", dir.to_string_lossy()))?;
+    // Not create_dir_all, so nothing that's there already is written over
+    std::fs::create_dir(dir)
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not create {}", dir.to_string_lossy()))?;
+    for (path, contents) in files(name) {
+        write(&dir.join(path), &contents)?;
+    }
+    log::log(
+        LogLevel::Info,
+        format!("Created {} in {}", name, dir.to_string_lossy()),
+        false,
+    );
+    Ok(())
+}
@@ -13,6 +13,13 @@ pub struct Program {
     pub base_path: PathBuf,
     pub instructions: Vec<Instruction>,
     pub macros: HashMap<String, Macro>,
+    /// In the order they're declared
+    pub memories: Vec<Memory>,
+    /// Every file included, directly or not, in the order they were read
+    pub includes: Vec<PathBuf>,
+    /// Where includes that aren't next to the file including them are looked
+    /// for, in order
+    pub search_path: Vec<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
@@ -155,6 +162,9 @@ pub enum Keyword {
     },
     Macro,
     Include,
+    Unsafe,
+    Extern,
+    Memory,
 }
 
 impl Keyword {
@@ -184,6 +194,9 @@ impl Keyword {
             }),
             "macro" => Ok(Keyword::Macro),
             "include" => Ok(Keyword::Include),
+            "unsafe" => Ok(Keyword::Unsafe),
+            "extern" => Ok(Keyword::Extern),
+            "memory" => Ok(Keyword::Memory),
             kw => {
                 Err(ParseError(UnknownKeyword)).with_context(|| format!("Unknown keyword: {}", kw))
             }
@@ -202,6 +215,9 @@ impl std::fmt::Display for Keyword {
             Keyword::End { .. } => write!(f, "end"),
             Keyword::Macro => write!(f, "macro"),
             Keyword::Include => write!(f, "include"),
+            Keyword::Unsafe => write!(f, "unsafe"),
+            Keyword::Extern => write!(f, "extern"),
+            Keyword::Memory => write!(f, "memory"),
         }
     }
 }
@@ -231,6 +247,55 @@ impl std::fmt::Display for SyscallKind {
     }
 }
 
+/// A C function declared with `extern name args returns`, called with the
+/// SysV calling convention. Its arguments are popped like a syscall's, with
+/// the first one on top.
+#[derive(Debug, Clone)]
+pub struct Extern {
+    pub name: String,
+    pub args: usize,
+    /// Whether it pushes what it returns
+    pub returns: bool,
+}
+
+/// What mem and each memory after it are aligned to, so qword loads and stores
+/// through them are too.
+pub const MEM_ALIGN: usize = 16;
+
+/// A region of its own declared with `memory name size end`, reserved next to
+/// mem. Using its name pushes its address.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    pub name: String,
+    /// Which one it is, counting declarations from 0
+    pub id: usize,
+    pub size: usize,
+    /// From the start of the first region, which are laid out in order
+    pub offset: usize,
+}
+
+impl Memory {
+    /// How much is reserved for it, rounded up so the next one starts aligned
+    /// to `MEM_ALIGN`.
+    pub fn reserved(&self) -> usize {
+        self.size.next_multiple_of(MEM_ALIGN)
+    }
+
+    /// What the region is called in assembly, its name unless that isn't a
+    /// valid identifier.
+    pub fn label(&self) -> String {
+        if self
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            format!("mem_{}", self.name)
+        } else {
+            format!("memory_{}", self.id)
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Instruction {
     pub kind: InstructionKind,
@@ -246,6 +311,8 @@ pub enum InstructionKind {
     Keyword(Keyword),
     Name(String),
     Syscall(SyscallKind),
+    Extern(Extern),
+    Memory(Memory),
 }
 
 impl std::fmt::Display for InstructionKind {
@@ -261,6 +328,8 @@ impl std::fmt::Display for InstructionKind {
             InstructionKind::Keyword(k) => write!(f, "{}", k),
             InstructionKind::Name(n) => write!(f, "{}", n),
             InstructionKind::Syscall(s) => write!(f, "{}", s),
+            InstructionKind::Extern(e) => write!(f, "{}", e.name),
+            InstructionKind::Memory(m) => write!(f, "{}", m.name),
         }
     }
 }
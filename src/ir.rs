@@ -0,0 +1,397 @@
+//! The `.ir` files `--emit ir` writes: a program as codegen gets it, after
+//! preprocessing, as JSON. Anything that loads programs takes them in place of
+//! the source, so a build can start from one, and tools can read them without
+//! parsing worth.
+//!
+//! The file is an object with the `format`, its `version`, and the program:
+//!
+//! - `strings`, every string pushed, once each
+//! - `memories`, by id, each with its `name`, `size` and `offset`
+//! - `instructions`, by ip, each an object with its `kind`, what that kind
+//!   needs and the `file`, `line` and `col` it came from. Strings are pushed by
+//!   `string`, their index in `strings`, and control flow keeps the ips it
+//!   jumps between
+//! - `macros`, each with its `name`, the `line` and `col` it's defined at and
+//!   its `body`, for where code came from
+//! - `includes`, every file the program was read from
+//!
+//! Integers are written as strings, since JSON numbers can't hold every one.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    codegen::intrinsics::Intrinsic,
+    error::{Error::IOError, IOError::*},
+    instruction::*,
+    json::Json,
+};
+
+pub const FORMAT: &str = "worth-ir";
+/// Bumped whenever files written before can't be read the same
+pub const VERSION: i64 = 1;
+
+fn invalid<T>(why: impl Into<String>) -> Result<T> {
+    Err(IOError(InvalidIr(why.into())).into())
+}
+
+struct Writer {
+    strings: Vec<String>,
+    ids: HashMap<String, usize>,
+}
+
+impl Writer {
+    fn string(&mut self, s: &str) -> usize {
+        if let Some(&id) = self.ids.get(s) {
+            return id;
+        }
+        self.strings.push(s.to_string());
+        self.ids.insert(s.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    fn instruction(&mut self, inst: &Instruction) -> Json {
+        let mut fields: Vec<(&str, Json)> = match &inst.kind {
+            InstructionKind::Push(Value::Int(n)) => {
+                vec![("kind", "int".into()), ("value", n.to_string().into())]
+            }
+            InstructionKind::Push(Value::Str(s)) => {
+                vec![("kind", "string".into()), ("string", self.string(s).into())]
+            }
+            InstructionKind::Push(Value::Char(c)) => {
+                vec![("kind", "char".into()), ("value", (*c as usize).into())]
+            }
+            InstructionKind::Push(Value::Bool(b)) => {
+                vec![("kind", "bool".into()), ("value", (*b).into())]
+            }
+            InstructionKind::Push(Value::Ptr(s)) => {
+                vec![("kind", "ptr".into()), ("value", s.as_str().into())]
+            }
+            InstructionKind::Intrinsic(i) => {
+                vec![("kind", "intrinsic".into()), ("name", i.to_string().into())]
+            }
+            InstructionKind::Op(op) => vec![("kind", "op".into()), ("name", op.to_string().into())],
+            InstructionKind::Keyword(keyword) => {
+                let mut fields = vec![
+                    ("kind", "keyword".into()),
+                    ("name", keyword.to_string().into()),
+                ];
+                match *keyword {
+                    Keyword::While { self_ip, do_ip } => {
+                        fields.extend([("self_ip", self_ip.into()), ("do_ip", do_ip.into())])
+                    }
+                    Keyword::Do { end_ip } => fields.push(("end_ip", end_ip.into())),
+                    Keyword::Elif { self_ip, end_ip } | Keyword::Else { self_ip, end_ip } => {
+                        fields.extend([("self_ip", self_ip.into()), ("end_ip", end_ip.into())])
+                    }
+                    Keyword::End { self_ip, while_ip } => {
+                        fields.extend([("self_ip", self_ip.into()), ("while_ip", while_ip.into())])
+                    }
+                    _ => {}
+                }
+                fields
+            }
+            InstructionKind::Name(name) => {
+                vec![("kind", "name".into()), ("name", name.as_str().into())]
+            }
+            InstructionKind::Syscall(kind) => {
+                vec![
+                    ("kind", "syscall".into()),
+                    ("name", kind.to_string().into()),
+                ]
+            }
+            InstructionKind::Extern(ext) => vec![
+                ("kind", "extern".into()),
+                ("name", ext.name.as_str().into()),
+                ("args", ext.args.into()),
+                ("returns", ext.returns.into()),
+            ],
+            InstructionKind::Memory(memory) => {
+                vec![("kind", "memory".into()), ("id", memory.id.into())]
+            }
+        };
+        let (file, line, col) = &inst.loc;
+        fields.extend([
+            ("file", file.as_str().into()),
+            ("line", (*line).into()),
+            ("col", (*col).into()),
+        ]);
+        Json::object(fields)
+    }
+}
+
+/// `program` as an IR file.
+pub fn to_json(program: &Program) -> Json {
+    let mut writer = Writer {
+        strings: Vec::new(),
+        ids: HashMap::new(),
+    };
+    let instructions: Vec<Json> = program
+        .instructions
+        .iter()
+        .map(|inst| writer.instruction(inst))
+        .collect();
+    // Sorted, so the same program is always written the same
+    let mut macros: Vec<_> = program.macros.values().collect();
+    macros.sort_by(|a, b| a.name.cmp(&b.name));
+    let macros: Vec<Json> = macros
+        .into_iter()
+        .map(|macro_| {
+            let body: Vec<Json> = macro_
+                .body
+                .iter()
+                .map(|inst| writer.instruction(inst))
+                .collect();
+            Json::object([
+                ("name", macro_.name.as_str().into()),
+                ("line", macro_.loc.0.into()),
+                ("col", macro_.loc.1.into()),
+                ("body", body.into()),
+            ])
+        })
+        .collect();
+    let memories: Vec<Json> = program
+        .memories
+        .iter()
+        .map(|memory| {
+            Json::object([
+                ("name", memory.name.as_str().into()),
+                ("size", memory.size.into()),
+                ("offset", memory.offset.into()),
+            ])
+        })
+        .collect();
+    let includes: Vec<Json> = program
+        .includes
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned().into())
+        .collect();
+    let strings: Vec<Json> = writer.strings.into_iter().map(Json::from).collect();
+    Json::object([
+        ("format", FORMAT.into()),
+        ("version", VERSION.into()),
+        ("name", program.name.as_str().into()),
+        (
+            "base_path",
+            program.base_path.to_string_lossy().into_owned().into(),
+        ),
+        ("strings", strings.into()),
+        ("memories", memories.into()),
+        ("instructions", instructions.into()),
+        ("macros", macros.into()),
+        ("includes", includes.into()),
+    ])
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> Result<&'a Json> {
+    match json.get(key) {
+        Some(value) => Ok(value),
+        None => invalid(format!("missing {}", key)),
+    }
+}
+
+fn str_field<'a>(json: &'a Json, key: &str) -> Result<&'a str> {
+    match field(json, key)?.as_str() {
+        Some(s) => Ok(s),
+        None => invalid(format!("{} isn't a string", key)),
+    }
+}
+
+fn usize_field(json: &Json, key: &str) -> Result<usize> {
+    match field(json, key)?.as_i64() {
+        Some(n) if n >= 0 => Ok(n as usize),
+        _ => invalid(format!("{} isn't a count", key)),
+    }
+}
+
+fn array_field<'a>(json: &'a Json, key: &str) -> Result<&'a [Json]> {
+    match field(json, key)?.as_array() {
+        Some(items) => Ok(items),
+        None => invalid(format!("{} isn't an array", key)),
+    }
+}
+
+struct Reader<'a> {
+    strings: &'a [Json],
+    memories: &'a [Memory],
+}
+
+impl Reader<'_> {
+    fn instruction(&self, json: &Json, ip: usize) -> Result<Instruction> {
+        let name = || str_field(json, "name");
+        let kind = match str_field(json, "kind")? {
+            "int" => match str_field(json, "value")?.parse() {
+                Ok(n) => InstructionKind::Push(Value::Int(n)),
+                Err(_) => return invalid("value isn't an integer"),
+            },
+            "string" => match self
+                .strings
+                .get(usize_field(json, "string")?)
+                .and_then(Json::as_str)
+            {
+                Some(s) => InstructionKind::Push(Value::Str(s.to_string())),
+                None => return invalid("string isn't in strings"),
+            },
+            "char" => match u8::try_from(usize_field(json, "value")?) {
+                Ok(c) => InstructionKind::Push(Value::Char(c)),
+                Err(_) => return invalid("value isn't a char"),
+            },
+            "bool" => match field(json, "value")?.as_bool() {
+                Some(b) => InstructionKind::Push(Value::Bool(b)),
+                None => return invalid("value isn't a bool"),
+            },
+            "ptr" => InstructionKind::Push(Value::Ptr(str_field(json, "value")?.to_string())),
+            "intrinsic" => match Intrinsic::from_str(name()?) {
+                Ok(i) => InstructionKind::Intrinsic(i),
+                Err(e) => return invalid(e),
+            },
+            "op" => InstructionKind::Op(Op::from_str(name()?)?),
+            "keyword" => {
+                let ip = |key| usize_field(json, key);
+                let keyword = match Keyword::from_str(name()?)? {
+                    Keyword::While { .. } => Keyword::While {
+                        self_ip: ip("self_ip")?,
+                        do_ip: ip("do_ip")?,
+                    },
+                    Keyword::Do { .. } => Keyword::Do {
+                        end_ip: ip("end_ip")?,
+                    },
+                    Keyword::Elif { .. } => Keyword::Elif {
+                        self_ip: ip("self_ip")?,
+                        end_ip: ip("end_ip")?,
+                    },
+                    Keyword::Else { .. } => Keyword::Else {
+                        self_ip: ip("self_ip")?,
+                        end_ip: ip("end_ip")?,
+                    },
+                    Keyword::End { .. } => Keyword::End {
+                        self_ip: ip("self_ip")?,
+                        while_ip: match field(json, "while_ip")? {
+                            Json::Null => None,
+                            _ => Some(ip("while_ip")?),
+                        },
+                    },
+                    keyword => keyword,
+                };
+                InstructionKind::Keyword(keyword)
+            }
+            "name" => InstructionKind::Name(name()?.to_string()),
+            "syscall" => InstructionKind::Syscall(match name()? {
+                "syscall0" => SyscallKind::Syscall0,
+                "syscall1" => SyscallKind::Syscall1,
+                "syscall2" => SyscallKind::Syscall2,
+                "syscall3" => SyscallKind::Syscall3,
+                "syscall4" => SyscallKind::Syscall4,
+                "syscall5" => SyscallKind::Syscall5,
+                "syscall6" => SyscallKind::Syscall6,
+                other => return invalid(format!("unknown syscall {}", other)),
+            }),
+            "extern" => InstructionKind::Extern(Extern {
+                name: name()?.to_string(),
+                args: usize_field(json, "args")?,
+                returns: field(json, "returns")?.as_bool().unwrap_or(false),
+            }),
+            "memory" => match self.memories.get(usize_field(json, "id")?) {
+                Some(memory) => InstructionKind::Memory(memory.clone()),
+                None => return invalid("id isn't in memories"),
+            },
+            other => return invalid(format!("unknown kind {}", other)),
+        };
+        Ok(Instruction {
+            kind,
+            loc: (
+                str_field(json, "file")?.to_string(),
+                usize_field(json, "line")?,
+                usize_field(json, "col")?,
+            ),
+            ip,
+        })
+    }
+}
+
+/// The program in an IR file.
+pub fn from_json(json: &Json) -> Result<Program> {
+    if json.get("format").and_then(Json::as_str) != Some(FORMAT) {
+        return invalid(format!("not a {} file", FORMAT));
+    }
+    match json.get("version").and_then(Json::as_i64) {
+        Some(VERSION) => {}
+        Some(version) => return invalid(format!("version {}, not {}", version, VERSION)),
+        None => return invalid("missing version"),
+    }
+    let memories = array_field(json, "memories")?
+        .iter()
+        .enumerate()
+        .map(|(id, memory)| {
+            Ok(Memory {
+                name: str_field(memory, "name")?.to_string(),
+                id,
+                size: usize_field(memory, "size")?,
+                offset: usize_field(memory, "offset")?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let reader = Reader {
+        strings: array_field(json, "strings")?,
+        memories: &memories,
+    };
+    let instructions = array_field(json, "instructions")?
+        .iter()
+        .enumerate()
+        .map(|(ip, inst)| reader.instruction(inst, ip))
+        .collect::<Result<Vec<_>>>()?;
+    let mut macros = HashMap::new();
+    for macro_ in array_field(json, "macros")? {
+        let name = str_field(macro_, "name")?.to_string();
+        let body = array_field(macro_, "body")?
+            .iter()
+            .enumerate()
+            .map(|(ip, inst)| reader.instruction(inst, ip))
+            .collect::<Result<Vec<_>>>()?;
+        let loc = (usize_field(macro_, "line")?, usize_field(macro_, "col")?);
+        macros.insert(
+            name.clone(),
+            Macro {
+                name,
+                body,
+                loc,
+                uses: Vec::new(),
+            },
+        );
+    }
+    let includes = array_field(json, "includes")?
+        .iter()
+        .map(|path| match path.as_str() {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => invalid("an include isn't a string"),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Program {
+        name: str_field(json, "name")?.to_string(),
+        base_path: PathBuf::from(str_field(json, "base_path")?),
+        instructions,
+        macros,
+        memories,
+        includes,
+        search_path: Vec::new(),
+    })
+}
+
+/// Write `program` to `path` as IR.
+pub fn write(program: &Program, path: &Path) -> Result<()> {
+    std::fs::write(path, to_json(program).to_string() + "\n")
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not write {}", path.to_string_lossy()))
+}
+
+/// The program in the IR file at `path`.
+pub fn read(path: &Path) -> Result<Program> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not read {}", path.to_string_lossy()))?;
+    Json::parse(&text)
+        .and_then(|json| from_json(&json))
+        .with_context(|| format!("Could not load {}", path.to_string_lossy()))
+}
@@ -0,0 +1,330 @@
+//! Just enough JSON for the tools that talk to editors and for
+//! `--message-format=json`, built and read by hand.
+
+use std::fmt::{self, Display};
+
+use anyhow::{Context, Result};
+
+use crate::error::{Error::IOError, IOError::InvalidJson};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Fields in the order they were written
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(s: &str) -> Result<Json> {
+        let mut parser = Parser {
+            chars: s.char_indices().peekable(),
+        };
+        let value = parser.value()?;
+        parser.space();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some((at, c)) => invalid(at, &format!("trailing {:?}", c)),
+        }
+    }
+
+    /// An object with `fields`, in order.
+    pub fn object<K: Into<String>>(fields: impl IntoIterator<Item = (K, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// The field `key` of an object, `None` for anything else.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) if n.fract() == 0.0 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            // Whole numbers without the .0, so ids and lines read as integers
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write!(f, "\"{}\"", escape(s)),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "\"{}\":{}", escape(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// `s` as it goes between the quotes of a JSON string.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(n: i32) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+fn invalid<T>(at: usize, msg: &str) -> Result<T> {
+    Err(IOError(InvalidJson)).with_context(|| format!("Invalid JSON at byte {}: {}", at, msg))
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    fn space(&mut self) {
+        while self
+            .chars
+            .next_if(|(_, c)| c.is_ascii_whitespace())
+            .is_some()
+        {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.space();
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            Some((at, c)) => invalid(at, &format!("expected {:?}, found {:?}", expected, c)),
+            None => invalid(
+                usize::MAX,
+                &format!("expected {:?}, found the end", expected),
+            ),
+        }
+    }
+
+    fn word(&mut self, word: &str, value: Json) -> Result<Json> {
+        for expected in word.chars() {
+            match self.chars.next() {
+                Some((_, c)) if c == expected => {}
+                Some((at, _)) => return invalid(at, &format!("expected {}", word)),
+                None => return invalid(usize::MAX, &format!("expected {}", word)),
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.space();
+        let Some(&(at, c)) = self.chars.peek() else {
+            return invalid(usize::MAX, "expected a value, found the end");
+        };
+        match c {
+            'n' => self.word("null", Json::Null),
+            't' => self.word("true", Json::Bool(true)),
+            'f' => self.word("false", Json::Bool(false)),
+            '"' => Ok(Json::String(self.string()?)),
+            '[' => {
+                self.chars.next();
+                let mut items = Vec::new();
+                self.space();
+                if self.chars.next_if(|(_, c)| *c == ']').is_some() {
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.space();
+                    match self.chars.next() {
+                        Some((_, ',')) => {}
+                        Some((_, ']')) => return Ok(Json::Array(items)),
+                        _ => return invalid(at, "unclosed array"),
+                    }
+                }
+            }
+            '{' => {
+                self.chars.next();
+                let mut fields = Vec::new();
+                self.space();
+                if self.chars.next_if(|(_, c)| *c == '}').is_some() {
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.space();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.space();
+                    match self.chars.next() {
+                        Some((_, ',')) => {}
+                        Some((_, '}')) => return Ok(Json::Object(fields)),
+                        _ => return invalid(at, "unclosed object"),
+                    }
+                }
+            }
+            '-' | '0'..='9' => {
+                let mut number = String::new();
+                while let Some((_, c)) = self
+                    .chars
+                    .next_if(|(_, c)| matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+                {
+                    number.push(c);
+                }
+                match number.parse() {
+                    Ok(n) => Ok(Json::Number(n)),
+                    Err(_) => invalid(at, &format!("invalid number {}", number)),
+                }
+            }
+            c => invalid(at, &format!("unexpected {:?}", c)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let start = match self.chars.next() {
+            Some((at, '"')) => at,
+            Some((at, c)) => return invalid(at, &format!("expected a string, found {:?}", c)),
+            None => return invalid(usize::MAX, "expected a string, found the end"),
+        };
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(s),
+                Some((_, '\\')) => match self.chars.next() {
+                    Some((_, 'n')) => s.push('\n'),
+                    Some((_, 't')) => s.push('\t'),
+                    Some((_, 'r')) => s.push('\r'),
+                    Some((_, 'b')) => s.push('\u{8}'),
+                    Some((_, 'f')) => s.push('\u{c}'),
+                    Some((_, 'u')) => s.push(self.unicode_escape(start)?),
+                    Some((_, c)) => s.push(c),
+                    None => return invalid(start, "unclosed string"),
+                },
+                Some((_, c)) => s.push(c),
+                None => return invalid(start, "unclosed string"),
+            }
+        }
+    }
+
+    /// The rest of a `\u` escape, including the low half of a surrogate pair.
+    fn unicode_escape(&mut self, start: usize) -> Result<char> {
+        let high = self.hex4(start)?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            let backslash = self.chars.next().map(|(_, c)| c);
+            let u = self.chars.next().map(|(_, c)| c);
+            let low = self.hex4(start)?;
+            if backslash != Some('\\') || u != Some('u') || !(0xDC00..0xE000).contains(&low) {
+                return invalid(start, "unpaired surrogate");
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).map_or_else(|| invalid(start, "invalid escape"), Ok)
+    }
+
+    fn hex4(&mut self, start: usize) -> Result<u32> {
+        let digits: String = (0..4)
+            .filter_map(|_| self.chars.next())
+            .map(|(_, c)| c)
+            .collect();
+        u32::from_str_radix(&digits, 16)
+            .or_else(|_| invalid(start, &format!("invalid escape \\u{}", digits)))
+    }
+}
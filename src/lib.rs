@@ -0,0 +1,36 @@
+//! The worth compiler as a library, for tools and tests that want to load,
+//! check or simulate programs from Rust. See `sim::SimulationState` for running
+//! a program step by step with in-memory stdio.
+
+pub mod cfg;
+pub mod cli;
+pub mod codegen;
+pub mod config;
+pub mod doc;
+pub mod dump;
+pub mod error;
+pub mod explain;
+pub mod fmt;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+pub mod init;
+pub mod instruction;
+pub mod ir;
+pub mod json;
+pub mod lint;
+pub mod log;
+pub mod lsp;
+pub mod manifest;
+pub mod optimize;
+pub mod parser;
+pub mod preprocessor;
+pub mod program;
+pub mod prompt;
+pub mod runner;
+pub mod sarif;
+pub mod sim;
+pub mod stats;
+pub mod test;
+pub mod timings;
+pub mod typecheck;
+pub mod watch;
@@ -0,0 +1,296 @@
+//! `worthc lint`, which warns about code that's valid but probably not what
+//! was meant. Each warning is for a lint, and says which, so it can be turned
+//! off with `--allow` or a comment on the line it's for or the one before:
+//!
+//! ```text
+//! // lint: allow(dropped-push)
+//! 1 drop
+//! ```
+//!
+//! Only what's written in the program itself is linted, not what it includes.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    cli::{LintOptions, MessageFormat},
+    codegen::intrinsics::Intrinsic,
+    error::{err_loc, Error::IOError, IOError::*},
+    instruction::{InstructionKind, Keyword, Op, Program},
+    json::Json,
+    log::{self, LogLevel},
+    optimize,
+    parser::{self, TokenType},
+    preprocessor,
+    program::load_program_with,
+    sarif::{self, Finding},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Lint {
+    /// A macro the program defines but never uses
+    UnusedMacro,
+    /// Code after the program exits, which never runs
+    Unreachable,
+    /// A value pushed only to be dropped
+    DroppedPush,
+    /// `=` on two constants, which is always true or always false
+    ConstantComparison,
+    /// A macro or memory with the name of one declared before it
+    ShadowedName,
+}
+
+impl Lint {
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UnusedMacro => "unused-macro",
+            Lint::Unreachable => "unreachable",
+            Lint::DroppedPush => "dropped-push",
+            Lint::ConstantComparison => "constant-comparison",
+            Lint::ShadowedName => "shadowed-name",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub message: String,
+    pub loc: (String, usize, usize),
+}
+
+/// The lints comments turn off, by the line they're for.
+fn allowed(source: &str, file: &str) -> Result<Vec<(usize, Lint)>> {
+    let tokens = parser::parse_program(parser::Span::new_extra(source, file))?;
+    let mut allowed = Vec::new();
+    for token in tokens {
+        if !matches!(token.ty, TokenType::Comment) {
+            continue;
+        }
+        let text = token.value.trim_start_matches('/').trim();
+        let Some(lints) = text
+            .strip_prefix("lint:")
+            .and_then(|rest| rest.trim().strip_prefix("allow("))
+            .and_then(|rest| rest.strip_suffix(')'))
+        else {
+            continue;
+        };
+        for name in lints.split(',') {
+            match Lint::from_str(name.trim(), false) {
+                Ok(lint) => {
+                    let line = token.location.1;
+                    allowed.extend([(line, lint), (line + 1, lint)]);
+                }
+                Err(_) => log::log(
+                    LogLevel::Warn,
+                    format!("{}: Unknown lint {}", err_loc(&token.location), name.trim()),
+                    false,
+                ),
+            }
+        }
+    }
+    Ok(allowed)
+}
+
+/// The macros and memories declared in `program`, in order, with where their
+/// names are.
+fn declarations(program: &Program) -> Vec<(&str, &String, &(String, usize, usize))> {
+    program
+        .instructions
+        .windows(2)
+        .filter_map(|pair| match (&pair[0].kind, &pair[1].kind) {
+            (InstructionKind::Keyword(Keyword::Macro), InstructionKind::Name(name)) => {
+                Some(("Macro", name, &pair[1].loc))
+            }
+            (InstructionKind::Keyword(Keyword::Memory), InstructionKind::Name(name)) => {
+                Some(("Memory", name, &pair[1].loc))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Warnings about what's written in `file`. `program` has its includes put in
+/// but its macros not yet expanded, so a constant is one written as it is, not
+/// a macro that's one.
+fn check_source(program: &Program, file: &str, warnings: &mut Vec<Warning>) {
+    let declared = declarations(program);
+    for (at, (kind, name, loc)) in declared.iter().enumerate() {
+        if loc.0 != file {
+            continue;
+        }
+        if let Some((before, _, at)) = declared[..at].iter().find(|(_, other, _)| other == name) {
+            warnings.push(Warning {
+                lint: Lint::ShadowedName,
+                message: format!(
+                    "{} {} has the name of the {} at {}",
+                    kind,
+                    name,
+                    before.to_lowercase(),
+                    err_loc(at)
+                ),
+                loc: (*loc).clone(),
+            });
+        }
+        let used = program.instructions.iter().any(|inst| {
+            matches!(&inst.kind, InstructionKind::Name(used) if used == *name)
+                && !declared.iter().any(|(_, _, at)| *at == &inst.loc)
+        });
+        if *kind == "Macro" && !used {
+            warnings.push(Warning {
+                lint: Lint::UnusedMacro,
+                message: format!("Macro {} is never used", name),
+                loc: (*loc).clone(),
+            });
+        }
+    }
+
+    let instructions = &program.instructions;
+    for (ip, inst) in instructions.iter().enumerate() {
+        if inst.loc.0 != file {
+            continue;
+        }
+        match (
+            &inst.kind,
+            &instructions[ip + 1..instructions.len().min(ip + 3)],
+        ) {
+            (InstructionKind::Push(value), [drop, ..])
+                if matches!(drop.kind, InstructionKind::Intrinsic(Intrinsic::Drop)) =>
+            {
+                warnings.push(Warning {
+                    lint: Lint::DroppedPush,
+                    message: format!("{} is pushed only to be dropped", value),
+                    loc: inst.loc.clone(),
+                });
+            }
+            (_, [other, eq]) if matches!(eq.kind, InstructionKind::Op(Op::Eq)) => {
+                if let (Some(a), Some(b)) = (optimize::constant(inst), optimize::constant(other)) {
+                    warnings.push(Warning {
+                        lint: Lint::ConstantComparison,
+                        message: format!("= compares two constants, so it's always {}", a == b),
+                        loc: eq.loc.clone(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Warnings about code that's written in `file` that never runs, once
+/// `program` is preprocessed.
+fn check_unreachable(program: &Program, file: &str, warnings: &mut Vec<Warning>) {
+    let instructions = &program.instructions;
+    for (ip, inst) in instructions.iter().enumerate() {
+        if !optimize::exits(ip.checked_sub(1).map(|prev| &instructions[prev]), inst) {
+            continue;
+        }
+        let end = optimize::block_end(instructions, ip + 1);
+        if let Some(dead) = instructions[ip + 1..end]
+            .iter()
+            .find(|inst| inst.loc.0 == file)
+        {
+            warnings.push(Warning {
+                lint: Lint::Unreachable,
+                message: "This never runs, it's after the program exits".to_string(),
+                loc: dead.loc.clone(),
+            });
+        }
+    }
+}
+
+/// The warnings about `file` that aren't turned off by `allow` or its
+/// comments, in the order they're in the file.
+pub fn lint(file: &PathBuf, allow: &[Lint]) -> Result<Vec<Warning>> {
+    lint_with(file, Vec::new(), allow)
+}
+
+/// Like `lint`, looking for includes in `search_path` too.
+pub fn lint_with(
+    file: &PathBuf,
+    search_path: Vec<PathBuf>,
+    allow: &[Lint],
+) -> Result<Vec<Warning>> {
+    let program = load_program_with(file, search_path.clone())
+        .with_context(|| format!("Failed to load {:?}.", file))?;
+    let source = std::fs::read_to_string(file)
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not read {}", file.to_string_lossy()))?;
+    let name = format!("{}.porth", program.name);
+    let mut parsed = parser::parse(source.clone(), &program.name, program.base_path.join(&name))?;
+    parsed.search_path = search_path;
+    let included = preprocessor::included(parsed)?;
+
+    let mut warnings = Vec::new();
+    check_source(&included, &name, &mut warnings);
+    check_unreachable(&program, &name, &mut warnings);
+    let allowed = allowed(&source, &name)?;
+    warnings.retain(|warning| {
+        !allow.contains(&warning.lint) && !allowed.contains(&(warning.loc.1, warning.lint))
+    });
+    // A macro's code is checked everywhere it's expanded, but said once
+    warnings.sort_by_key(|warning| (warning.loc.1, warning.loc.2, warning.lint));
+    warnings.dedup();
+    Ok(warnings)
+}
+
+/// A SARIF log of `warnings` about `file`.
+pub fn sarif(warnings: &[Warning], file: &Path) -> Json {
+    let findings: Vec<_> = warnings
+        .iter()
+        .map(|warning| Finding {
+            rule: warning.lint.name().to_string(),
+            level: "warning",
+            message: warning.message.clone(),
+            loc: Some(warning.loc.clone()),
+        })
+        .collect();
+    let rules: Vec<_> = Lint::value_variants()
+        .iter()
+        .map(|lint| {
+            let help = lint
+                .to_possible_value()
+                .and_then(|value| value.get_help().cloned());
+            (
+                lint.name().to_string(),
+                help.map(|help| help.to_string()).unwrap_or_default(),
+            )
+        })
+        .collect();
+    sarif::log(&findings, &rules, Some(file))
+}
+
+/// Lint `file`, logging each warning, or printing them as a SARIF log with
+/// `format`. Returns whether there were none.
+pub fn run(file: &PathBuf, opt: &LintOptions, format: MessageFormat) -> Result<bool> {
+    let warnings = lint(file, &opt.allow)?;
+    if format == MessageFormat::Sarif {
+        println!("{}", sarif(&warnings, file));
+        return Ok(warnings.is_empty());
+    }
+    report(&warnings);
+    Ok(warnings.is_empty())
+}
+
+/// Log each of `warnings`, then how many there were.
+pub fn report(warnings: &[Warning]) {
+    for warning in warnings {
+        log::log(
+            LogLevel::Warn,
+            format!(
+                "{}: {} [{}]",
+                err_loc(&warning.loc),
+                warning.message,
+                warning.lint.name()
+            ),
+            false,
+        );
+    }
+    log::log(
+        LogLevel::Info,
+        format!("{} warnings", warnings.len()),
+        false,
+    );
+}
@@ -1,16 +1,173 @@
+//! Messages to stderr, at a level `--log-level`, `-q` and `-v` set the least of.
+//!
+//! Each is logged in the phases it's logged during, which are the ones
+//! `--timings` reports, like `preprocess` and an `include` inside it. How much
+//! is logged in one can be set on its own with `WORTHC_LOG`, a comma separated
+//! list of a level for everything and `PHASE=LEVEL`s, so
+//!
+//! ```text
+//! WORTHC_LOG=warn,typecheck=debug
+//! ```
+//!
+//! only logs warnings, except while typechecking. A phase is named by its every
+//! word too, so `include` is every include and `assemble` whichever assembler.
+//! `--log-format json` writes each message as a line of JSON for tools, with
+//! the phases it's in.
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+
+use crate::json::Json;
+
+/// Where the levels of each phase are read from.
+pub const ENV: &str = "WORTHC_LOG";
+
+/// From the most to log to the least. Each level shows itself and the ones
+/// after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum LogLevel {
     Debug,
+    /// The commands run to build and run programs
+    Cmd,
     Info,
     Warn,
-    Cmd,
 }
 
+impl LogLevel {
+    fn from_u8(level: u8) -> LogLevel {
+        match level {
+            0 => LogLevel::Debug,
+            1 => LogLevel::Cmd,
+            2 => LogLevel::Info,
+            _ => LogLevel::Warn,
+        }
+    }
+}
+
+/// What `--log-format` writes messages as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// `[INFO] message`
+    Human,
+    /// `{"level":"info","message":"message","phases":["preprocess"]}`
+    Json,
+}
+
+/// The least a message needs to be logged, set by `--log-level`, `-q` and `-v`.
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Cmd as u8);
+/// Set by `--log-format json`
+static JSON: AtomicBool = AtomicBool::new(false);
+/// The level `WORTHC_LOG` gives everything, if it does
+static DEFAULT: Mutex<Option<LogLevel>> = Mutex::new(None);
+/// The least a message needs to be logged in a phase, from `WORTHC_LOG`
+static PHASE_LEVELS: Mutex<Vec<(String, LogLevel)>> = Mutex::new(Vec::new());
+/// The phases being logged in, innermost last
+static PHASES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// Read the levels in `filter`, written like `WORTHC_LOG` is, warning about
+/// any that aren't.
+pub fn set_filter(filter: &str) {
+    let mut levels = Vec::new();
+    for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let (phase, level) = match directive.rsplit_once('=') {
+            Some((phase, level)) => (Some(phase.trim()), level.trim()),
+            None => (None, directive),
+        };
+        let Ok(level) = LogLevel::from_str(level, true) else {
+            log(
+                LogLevel::Warn,
+                format!("{} has {:?}, which isn't a level", ENV, directive),
+                false,
+            );
+            continue;
+        };
+        match phase {
+            Some(phase) => levels.push((phase.to_string(), level)),
+            None => *DEFAULT.lock().unwrap() = Some(level),
+        }
+    }
+    *PHASE_LEVELS.lock().unwrap() = levels;
+}
+
+/// The level `WORTHC_LOG` gives everything, or cmd.
+pub fn default_level() -> LogLevel {
+    DEFAULT.lock().unwrap().unwrap_or(LogLevel::Cmd)
+}
+
+/// In a phase until it's dropped, so it's left however the code in it ends,
+/// even by panicking.
+pub struct Phase(());
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        // Poisoned by a panic while logging, which is past caring about phases
+        if let Ok(mut phases) = PHASES.lock() {
+            phases.pop();
+        }
+    }
+}
+
+/// Enter the phase `name` until the returned `Phase` is dropped.
+pub fn enter(name: &str) -> Phase {
+    PHASES.lock().unwrap().push(name.to_string());
+    Phase(())
+}
+
+/// Call `f` in the phase `name`.
+pub fn phase<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    let _phase = enter(name);
+    f()
+}
+
+/// The least a message needs to be logged in `phases`: the level of the
+/// innermost one `WORTHC_LOG` has one for.
+fn least(phases: &[String]) -> LogLevel {
+    let levels = PHASE_LEVELS.lock().unwrap();
+    phases
+        .iter()
+        .rev()
+        .find_map(|phase| {
+            levels
+                .iter()
+                .rev()
+                .find(|(name, _)| name == phase || phase.split(' ').any(|word| word == name))
+                .map(|(_, level)| *level)
+        })
+        .unwrap_or(LogLevel::from_u8(LEVEL.load(Ordering::Relaxed)))
+}
+
+/// Debug messages are logged when `debug_enabled` is, as well as at the debug
+/// level.
 pub fn log(level: LogLevel, message: String, debug_enabled: bool) {
+    let phases = PHASES.lock().unwrap().clone();
+    let shown = level >= least(&phases) || (level == LogLevel::Debug && debug_enabled);
+    if !shown {
+        return;
+    }
+    if JSON.load(Ordering::Relaxed) {
+        let phases: Vec<Json> = phases.into_iter().map(Json::from).collect();
+        let level = level.to_possible_value().expect("no level is skipped");
+        let json = Json::object([
+            ("level", level.get_name().into()),
+            ("message", crate::error::strip_ansi(&message).into()),
+            ("phases", phases.into()),
+        ]);
+        eprintln!("{}", json);
+        return;
+    }
     match level {
         LogLevel::Debug => {
-            if debug_enabled {
-                eprintln!("[DEBUG] {}", message);
-            }
+            eprintln!("[DEBUG] {}", message);
         }
         LogLevel::Cmd => {
             eprintln!("[CMD] {}", message);
@@ -0,0 +1,507 @@
+//! `worthc lsp`, a Language Server Protocol server over stdio.
+//!
+//! Documents are synced whole. Each time one is opened or changed it's parsed,
+//! preprocessed with its includes read from disk, and typechecked, and the first
+//! error is published as its diagnostic. Macros and includes can be followed to
+//! where they're defined, hovering a macro shows its body and the stack effect it
+//! checks with, and documents are formatted like `worthc fmt` does.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+
+use crate::cli::{FmtConfig, TypecheckOptions};
+use crate::error::{err_loc, strip_ansi, AsFmt, Diagnostic, Error, RenderFmt};
+use crate::instruction::{Program, Value};
+use crate::json::Json;
+use crate::parser::{self, Token, TokenType};
+use crate::sim::dap::read_message;
+use crate::{fmt, preprocessor, typecheck};
+
+/// JSON-RPC's code for requests the server doesn't know
+const METHOD_NOT_FOUND: i64 = -32601;
+/// And for ones it couldn't answer
+const REQUEST_FAILED: i64 = -32803;
+
+/// Serve one client on stdin and stdout, until it says to exit.
+pub fn serve() -> Result<()> {
+    let mut input = BufReader::new(io::stdin().lock());
+    let mut server = Server::new(io::stdout().lock());
+    while let Some(message) = read_message(&mut input)? {
+        if !server.handle(&Json::parse(&message)?)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+struct Document {
+    text: String,
+    /// As of the last change it could be preprocessed after, for stack effects
+    program: Option<Program>,
+}
+
+struct Server<W: Write> {
+    out: W,
+    /// By URI
+    documents: HashMap<String, Document>,
+}
+
+impl<W: Write> Server<W> {
+    fn new(out: W) -> Self {
+        Self {
+            out,
+            documents: HashMap::new(),
+        }
+    }
+
+    fn send(&mut self, mut message: Vec<(&str, Json)>) -> Result<()> {
+        message.insert(0, ("jsonrpc", "2.0".into()));
+        let body = Json::object(message).to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.out.flush().context("Failed to write LSP message")
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> Result<()> {
+        self.send(vec![("method", method.into()), ("params", params)])
+    }
+
+    fn respond(&mut self, id: Json, result: Result<Json, String>) -> Result<()> {
+        match result {
+            Ok(result) => self.send(vec![("id", id), ("result", result)]),
+            Err(message) => self.error(id, REQUEST_FAILED, message),
+        }
+    }
+
+    fn error(&mut self, id: Json, code: i64, message: String) -> Result<()> {
+        let error = Json::object([("code", code.into()), ("message", message.into())]);
+        self.send(vec![("id", id), ("error", error)])
+    }
+
+    /// Handle a request or notification, returning false once it's time to exit.
+    fn handle(&mut self, message: &Json) -> Result<bool> {
+        let method = message
+            .get("method")
+            .and_then(Json::as_str)
+            .unwrap_or_default();
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let Some(id) = message.get("id").cloned() else {
+            match method {
+                "exit" => return Ok(false),
+                "textDocument/didOpen" => {
+                    let text = params
+                        .get("textDocument")
+                        .and_then(|document| document.get("text"))
+                        .and_then(Json::as_str)
+                        .unwrap_or_default();
+                    self.update(document_uri(params), text.to_owned())?;
+                }
+                "textDocument/didChange" => {
+                    let text = params
+                        .get("contentChanges")
+                        .and_then(Json::as_array)
+                        .and_then(|changes| changes.last())
+                        .and_then(|change| change.get("text"))
+                        .and_then(Json::as_str)
+                        .unwrap_or_default();
+                    self.update(document_uri(params), text.to_owned())?;
+                }
+                "textDocument/didClose" => {
+                    let uri = document_uri(params);
+                    self.documents.remove(uri);
+                    self.publish(uri, Vec::new())?;
+                }
+                _ => {}
+            }
+            return Ok(true);
+        };
+        let result = match method {
+            "initialize" => Ok(Json::object([
+                (
+                    "capabilities",
+                    Json::object([
+                        // Whole documents
+                        ("textDocumentSync", 1.into()),
+                        ("definitionProvider", true.into()),
+                        ("hoverProvider", true.into()),
+                        ("documentFormattingProvider", true.into()),
+                    ]),
+                ),
+                (
+                    "serverInfo",
+                    Json::object([
+                        ("name", "worthc".into()),
+                        ("version", env!("CARGO_PKG_VERSION").into()),
+                    ]),
+                ),
+            ])),
+            "shutdown" => Ok(Json::Null),
+            "textDocument/definition" => Ok(self.definition(params).unwrap_or(Json::Null)),
+            "textDocument/hover" => Ok(self.hover(params).unwrap_or(Json::Null)),
+            "textDocument/formatting" => self.formatting(params),
+            method => {
+                self.error(
+                    id,
+                    METHOD_NOT_FOUND,
+                    format!("Unsupported request {}", method),
+                )?;
+                return Ok(true);
+            }
+        };
+        self.respond(id, result)?;
+        Ok(true)
+    }
+
+    fn update(&mut self, uri: &str, text: String) -> Result<()> {
+        let (program, diagnostics) = check(uri, &text);
+        let document = self.documents.entry(uri.to_owned()).or_insert(Document {
+            text: String::new(),
+            program: None,
+        });
+        document.text = text;
+        if program.is_some() {
+            document.program = program;
+        }
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Json>) -> Result<()> {
+        self.notify(
+            "textDocument/publishDiagnostics",
+            Json::object([("uri", uri.into()), ("diagnostics", diagnostics.into())]),
+        )
+    }
+
+    /// Where the macro or include at the position in `params` is.
+    fn definition(&self, params: &Json) -> Option<Json> {
+        let (uri, line, character) = position(params)?;
+        let text = &self.documents.get(uri)?.text;
+        let path = uri_path(uri);
+        let files = files(&path, text);
+        let tokens = &files.first()?.1;
+        let at = token_at(tokens, text, line, character)?;
+        match &tokens[at].ty {
+            TokenType::Value(Value::Str(included))
+                if at > 0 && is_keyword(&tokens[at - 1], "include") =>
+            {
+                let dir = path.parent().unwrap_or(Path::new(""));
+                Some(location(&dir.join(included), 0, 0, 0))
+            }
+            TokenType::Name => {
+                let (path, tokens, at) = find_macro(&files, &tokens[at].value)?;
+                let (_, line, column) = &tokens[at + 1].location;
+                let name = &tokens[at + 1].value;
+                Some(location(path, line - 1, *column, column + name.len()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The body and stack effect of the macro at the position in `params`.
+    fn hover(&self, params: &Json) -> Option<Json> {
+        let (uri, line, character) = position(params)?;
+        let document = self.documents.get(uri)?;
+        let files = files(&uri_path(uri), &document.text);
+        let tokens = &files.first()?.1;
+        let at = token_at(tokens, &document.text, line, character)?;
+        let token = &tokens[at];
+        if !matches!(token.ty, TokenType::Name) {
+            return None;
+        }
+        let (_, definition, start) = find_macro(&files, &token.value)?;
+        let body = definition[start..=block_end(definition, start)?].to_vec();
+        let mut value = format!(
+            "```porth\n{}\n```",
+            body.as_fmt()
+                .format(&FmtConfig::default())
+                .render(false, false)
+        );
+        let effect = document.program.as_ref().and_then(|program| {
+            typecheck::macro_effect(program, &program.macros.get(&token.value)?.body)
+        });
+        if let Some(effect) = effect {
+            value.push_str(&format!("\n\nStack effect: `{}`", effect));
+        }
+        let (_, line, column) = &token.location;
+        Some(Json::object([
+            (
+                "contents",
+                Json::object([("kind", "markdown".into()), ("value", value.into())]),
+            ),
+            (
+                "range",
+                range(line - 1, *column, column + token.value.len()),
+            ),
+        ]))
+    }
+
+    /// The document in `params` formatted, as one edit replacing all of it.
+    fn formatting(&self, params: &Json) -> Result<Json, String> {
+        let uri = document_uri(params);
+        let text = &self
+            .documents
+            .get(uri)
+            .ok_or_else(|| format!("{} isn't open", uri))?
+            .text;
+        let mut config = FmtConfig::default();
+        if let Some(tab_size) = params
+            .get("options")
+            .and_then(|options| options.get("tabSize"))
+            .and_then(Json::as_i64)
+        {
+            config.indent = tab_size.max(0) as usize;
+        }
+        let formatted = fmt::format(text, &config).map_err(|e| strip_ansi(&format!("{:?}", e)))?;
+        if formatted == *text {
+            return Ok(Json::Array(Vec::new()));
+        }
+        let last_line = text.rsplit('\n').next().unwrap_or_default();
+        let end = Json::object([
+            ("line", text.matches('\n').count().into()),
+            ("character", last_line.len().into()),
+        ]);
+        let start = Json::object([("line", 0.into()), ("character", 0.into())]);
+        Ok(vec![Json::object([
+            ("range", Json::object([("start", start), ("end", end)])),
+            ("newText", formatted.into()),
+        ])]
+        .into())
+    }
+}
+
+/// Load the document at `uri` from `text` and typecheck it, with the program if
+/// it got as far as preprocessing and the diagnostics for what went wrong.
+fn check(uri: &str, text: &str) -> (Option<Program>, Vec<Json>) {
+    let path = uri_path(uri);
+    let name = path
+        .file_stem()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "main".to_owned());
+    let program =
+        parser::parse(text.to_owned(), &name, path.clone()).and_then(preprocessor::process);
+    let (program, result) = match program {
+        Ok(program) => {
+            let options = TypecheckOptions::parse_from(["worthc"]);
+            let result = typecheck::typecheck(&program, &options);
+            (Some(program), result)
+        }
+        Err(e) => (None, Err(e)),
+    };
+    let diagnostics = match result {
+        Ok(()) => Vec::new(),
+        Err(e) => vec![diagnostic(&e, &format!("{}.porth", name), text)],
+    };
+    (program, diagnostics)
+}
+
+/// `err` as a diagnostic for `text`, which locations call `file`. Ones from
+/// included files are shown at the start of the document.
+fn diagnostic(err: &anyhow::Error, file: &str, text: &str) -> Json {
+    let (message, range) = match err.downcast_ref::<Diagnostic>() {
+        Some(diag) if diag.loc.0 == file => {
+            let (_, line, column) = &diag.loc;
+            let written = text
+                .lines()
+                .nth(line - 1)
+                .and_then(|line| line.get(*column..))
+                .map_or(0, written_len);
+            (
+                diag.message.clone(),
+                range(line - 1, *column, column + written),
+            )
+        }
+        Some(diag) => (
+            format!("{}: {}", err_loc(&diag.loc), diag.message),
+            range(0, 0, 0),
+        ),
+        None => (
+            err.to_string()
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_owned(),
+            range(0, 0, 0),
+        ),
+    };
+    let mut fields = vec![
+        ("range", range),
+        // Error
+        ("severity", 1.into()),
+        ("source", "worthc".into()),
+        ("message", strip_ansi(message.trim()).into()),
+    ];
+    if let Some(error) = err.downcast_ref::<Error>() {
+        fields.push(("code", error.code().into()));
+    }
+    Json::object(fields)
+}
+
+/// The file at `path`, with `text`, and everything it includes from disk, each
+/// with its tokens. Files that can't be read or parsed are left out.
+fn files(path: &Path, text: &str) -> Vec<(PathBuf, Vec<Token>)> {
+    let mut files = Vec::new();
+    let mut seen = HashSet::new();
+    let mut todo = vec![(path.to_path_buf(), Some(text.to_owned()))];
+    while let Some((path, text)) = todo.pop() {
+        if !seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone())) {
+            continue;
+        }
+        let Some(text) = text.or_else(|| std::fs::read_to_string(&path).ok()) else {
+            continue;
+        };
+        let file = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let Ok(tokens) = parser::parse_program(parser::Span::new_extra(&text, &file)) else {
+            continue;
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for pair in tokens.windows(2) {
+            if let (true, TokenType::Value(Value::Str(included))) =
+                (is_keyword(&pair[0], "include"), &pair[1].ty)
+            {
+                todo.push((dir.join(included), None));
+            }
+        }
+        files.push((path, tokens));
+    }
+    files
+}
+
+/// Where `name` is defined: the file, its tokens and where `macro` is in them.
+fn find_macro<'a>(
+    files: &'a [(PathBuf, Vec<Token>)],
+    name: &str,
+) -> Option<(&'a Path, &'a [Token], usize)> {
+    files.iter().find_map(|(path, tokens)| {
+        let at = tokens.windows(2).position(|pair| {
+            is_keyword(&pair[0], "macro")
+                && matches!(pair[1].ty, TokenType::Name)
+                && pair[1].value == name
+        })?;
+        Some((path.as_path(), tokens.as_slice(), at))
+    })
+}
+
+/// The `end` of the block starting at `start`.
+fn block_end(tokens: &[Token], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (at, token) in tokens.iter().enumerate().skip(start) {
+        if !matches!(token.ty, TokenType::Keyword) {
+            continue;
+        }
+        match token.value.as_str() {
+            "while" | "if" | "macro" | "unsafe" | "memory" => depth += 1,
+            "end" => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(at);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn is_keyword(token: &Token, keyword: &str) -> bool {
+    matches!(token.ty, TokenType::Keyword) && token.value == keyword
+}
+
+/// The token written at 0 based `line` and `character`, or just before it.
+fn token_at(tokens: &[Token], text: &str, line: usize, character: usize) -> Option<usize> {
+    let source = text.lines().nth(line)?;
+    tokens.iter().position(|token| {
+        let (_, token_line, column) = token.location;
+        let written = source.get(column..).map_or(0, written_len);
+        token_line == line + 1 && (column..=column + written).contains(&character)
+    })
+}
+
+/// How long the token at the start of `source` is as written.
+fn written_len(source: &str) -> usize {
+    let mut chars = source.char_indices();
+    match chars.next() {
+        Some((_, quote @ ('"' | '\''))) => {
+            let mut escaped = false;
+            for (at, c) in chars {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    c if c == quote => return at + 1,
+                    _ => {}
+                }
+            }
+            source.len()
+        }
+        _ => source.find(char::is_whitespace).unwrap_or(source.len()),
+    }
+}
+
+fn document_uri(params: &Json) -> &str {
+    params
+        .get("textDocument")
+        .and_then(|document| document.get("uri"))
+        .and_then(Json::as_str)
+        .unwrap_or_default()
+}
+
+/// The document and 0 based line and character of a position request.
+fn position(params: &Json) -> Option<(&str, usize, usize)> {
+    let position = params.get("position")?;
+    let line = position.get("line")?.as_i64()?;
+    let character = position.get("character")?.as_i64()?;
+    Some((document_uri(params), line as usize, character as usize))
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    let position =
+        |character: usize| Json::object([("line", line.into()), ("character", character.into())]);
+    Json::object([("start", position(start)), ("end", position(end))])
+}
+
+fn location(path: &Path, line: usize, start: usize, end: usize) -> Json {
+    Json::object([
+        ("uri", path_uri(path).into()),
+        ("range", range(line, start, end)),
+    ])
+}
+
+fn uri_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri).as_bytes();
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut at = 0;
+    while at < path.len() {
+        let escaped = path
+            .get(at + 1..at + 3)
+            .filter(|_| path[at] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                at += 3;
+            }
+            None => {
+                bytes.push(path[at]);
+                at += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn path_uri(path: &Path) -> String {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut uri = "file://".to_owned();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '%' | '#' | '?' => uri.push_str(&format!("%{:02X}", c as u32)),
+            c => uri.push(c),
+        }
+    }
+    uri
+}
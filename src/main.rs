@@ -1,58 +1,295 @@
-use clap::Parser;
-
-use cli::{Cli, Command};
-
-mod cfg;
-mod cli;
-mod codegen;
-mod error;
-mod instruction;
-mod log;
-mod parser;
-mod preprocessor;
-mod program;
-mod runner;
-mod sim;
-mod typecheck;
+use clap::{error::ErrorKind, ArgMatches, CommandFactory, FromArgMatches};
+
+use worthc::cli::{Cli, Command, FmtOptions, MessageFormat};
+use worthc::{
+    cfg, codegen, config::Config, doc, dump, error, explain, fmt, init, lint, log, lsp, optimize,
+    prompt, runner, sarif, sim, stats, test, timings, typecheck, watch,
+};
+
+use std::path::PathBuf;
 
 use anyhow::{Context, Result};
 
-use self::program::load_program;
+use worthc::manifest::Manifest;
+use worthc::program::{self, load_program_with, load_stdin, STDIN_NAME};
 
 fn main() -> Result<()> {
-    let args = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    log::set_format(args.log_format);
+    if let Ok(filter) = std::env::var(log::ENV) {
+        log::set_filter(&filter);
+    }
+    log::set_level(args.log_level());
+    error::set_color(args.color.enabled());
+    prompt::set_yes(args.yes);
+    if args.timings {
+        timings::enable();
+    }
+    let message_format = args.message_format;
+    let file = args.take_file();
+    match run(args, &matches, file.clone()) {
+        Err(e) if message_format == MessageFormat::Json => {
+            eprintln!("{}", error::diagnostic_json(&e));
+            std::process::exit(1);
+        }
+        Err(e) if message_format == MessageFormat::Sarif => {
+            println!("{}", sarif::error_log(&e, file.as_deref()));
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            if let Some(id) = e.downcast_ref::<error::Error>().and_then(error::Error::id) {
+                eprintln!(
+                    "\nFor more information about this error, try `worthc explain {}`.",
+                    id
+                );
+            }
+            std::process::exit(1);
+        }
+        ok => ok,
+    }
+}
 
-    let program =
-        load_program(&args.file).with_context(|| format!("Failed to load {:?}.", args.file))?;
+fn run(mut args: Cli, matches: &ArgMatches, file: Option<PathBuf>) -> Result<()> {
+    match &args.command {
+        Some(Command::Dap) => return sim::dap::serve(),
+        Some(Command::Lsp) => return lsp::serve(),
+        Some(Command::Fmt(opt)) => return format(opt),
+        Some(Command::Test(opt)) => {
+            if !test::run(opt)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Init(opt)) => return init::run(opt),
+        Some(Command::Explain(opt)) => match explain::explain(&opt.code) {
+            Some(text) => {
+                print!("{}", text);
+                return Ok(());
+            }
+            None => Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("{} isn't the code of an error", opt.code),
+                )
+                .exit(),
+        },
+        _ => {}
+    }
+    let cwd = std::env::current_dir()?;
+    let config = match Config::find(&cwd) {
+        Some(path) => Config::load(&path)?,
+        None => Config::default(),
+    };
+    config.apply(&mut args, matches);
+    // A build without a file is of the project it's in
+    let mut search_path = Vec::new();
+    let file = match (file, &mut args.command) {
+        (None, Some(Command::Build(opt))) => match Manifest::find(&cwd) {
+            Some(path) => {
+                let manifest = Manifest::load(&path)?;
+                search_path = manifest.search_path()?;
+                opt.output
+                    .get_or_insert_with(|| manifest.dir.join(&manifest.name));
+                Some(manifest.entry())
+            }
+            None => None,
+        },
+        (file, _) => file,
+    };
+    search_path.extend(config.search_path());
+    if let Some(Command::Repl(_)) = &args.command {
+        return repl(&args, search_path, file.as_ref());
+    }
+    let Some(file) = file else {
+        Cli::command()
+            .error(ErrorKind::MissingRequiredArgument, "FILE is required")
+            .exit();
+    };
+    let stdin = file.as_os_str() == "-";
+    match &args.command {
+        Some(
+            Command::Doc(_)
+            | Command::Lint(_)
+            | Command::Stats(_)
+            | Command::Difftest(_)
+            | Command::DumpTokens(_)
+            | Command::DumpIr(_),
+        ) if stdin => {
+            Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    "Only build, run, simulate and cfg read the program from stdin",
+                )
+                .exit();
+        }
+        Some(Command::Doc(opt)) => return doc::run(&file, opt),
+        Some(Command::Lint(opt)) => {
+            if !lint::run(&file, opt, args.message_format)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Stats(opt)) => return stats::run(&file, opt),
+        Some(Command::Difftest(opt)) => {
+            if !test::difftest(&file, opt)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::DumpTokens(opt)) => return dump::tokens(&file, opt),
+        Some(Command::DumpIr(opt)) => return dump::ir(&file, opt),
+        _ => {}
+    }
+    if let Some(Command::Simulate(opt)) = &args.command {
+        args.typecheck.debugger = opt.tc_debug;
+    }
+    let watching = match &args.command {
+        Some(Command::Build(opt)) => opt.watch,
+        Some(Command::Run(opt)) => opt.watch,
+        Some(Command::Simulate(opt)) => opt.watch,
+        _ => false,
+    };
+    if watching && stdin {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "A program read from stdin can't be watched",
+            )
+            .exit();
+    }
+    if watching {
+        return watch::watch(&file, args.message_format, |includes| {
+            execute(&file, &search_path, &args, includes)
+        });
+    }
+    let code = execute(&file, &search_path, &args, &mut Vec::new())?;
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
 
-    let tc_debugger = if let Some(Command::Simulate(opt)) = &args.command {
-        opt.tc_debug
+/// Load the program in `file`, or stdin if it's -, with includes from
+/// `search_path`, link in the ones `args` say to and do what they say with it,
+/// leaving the files it includes in `includes`. Returns the exit code of a
+/// program run or simulated.
+fn execute(
+    file: &PathBuf,
+    search_path: &[PathBuf],
+    args: &Cli,
+    includes: &mut Vec<PathBuf>,
+) -> Result<i32> {
+    let strict = match &args.command {
+        Some(Command::Build(opt)) => opt.strict,
+        Some(Command::Run(opt)) => opt.strict,
+        _ => false,
+    };
+    if strict && file.as_os_str() != "-" {
+        let warnings = lint::lint_with(file, search_path.to_vec(), &[])?;
+        if !warnings.is_empty() {
+            lint::report(&warnings);
+            return Err(anyhow::anyhow!(
+                "Not building {}, worthc lint warns about it and --strict is on",
+                file.to_string_lossy()
+            ));
+        }
+    }
+    let program = if file.as_os_str() == "-" {
+        let name = args.name.as_deref().unwrap_or(STDIN_NAME);
+        load_stdin(name, search_path.to_vec())
+            .with_context(|| format!("Failed to load {} from stdin.", name))?
     } else {
-        false
+        load_program_with(file, search_path.to_vec())
+            .with_context(|| format!("Failed to load {:?}.", file))?
+    };
+    let link = match &args.command {
+        Some(Command::Build(opt)) => &opt.link,
+        Some(Command::Run(opt)) => &opt.link,
+        Some(Command::Simulate(opt)) => &opt.link,
+        _ => &Vec::new(),
     };
+    let mut programs = vec![program];
+    for file in link {
+        let program = load_program_with(file, search_path.to_vec())
+            .with_context(|| format!("Failed to load {:?}.", file))?;
+        programs.push(program);
+    }
+    let program = program::link(programs)?;
+    includes.clone_from(&program.includes);
+    includes.extend(link.iter().cloned());
+
     if !args.unsafe_ {
-        typecheck::typecheck(&program, tc_debugger)?;
+        timings::time("typecheck", || {
+            typecheck::typecheck(&program, &args.typecheck)
+        })?;
     }
 
-    match args.command {
+    match &args.command {
         Some(Command::Build(opt)) => {
-            let compiled = codegen::compile(&program, opt)?;
+            let program = timings::time("optimize", || {
+                optimize::optimize(&program, opt.opt_level, args.verbose)
+            })?;
+            let compiled = codegen::compile(&program, opt.clone())?;
+            timings::report();
             log::log(log::LogLevel::Info, format!("Built {:?}", compiled), false);
         }
         Some(Command::Run(opt)) => {
+            let program = timings::time("optimize", || {
+                optimize::optimize(&program, opt.opt_level, args.verbose)
+            })?;
             let compiled = codegen::compile(&program, opt.clone().into())?
                 .canonicalize()
                 .with_context(|| format!("Could not find compiled file for {:?}", &program.name))?;
-            runner::run(&compiled, opt)?;
+            timings::report();
+            return runner::run(&compiled, opt.clone());
+        }
+        Some(Command::Simulate(opt)) => {
+            timings::report();
+            return sim::simulate(&program, opt.clone());
         }
-        Some(Command::Simulate(opt)) => sim::simulate(&program, opt)?,
         Some(Command::Cfg(opt)) => {
-            cfg::dump(&program, opt)?;
+            timings::report();
+            cfg::dump(&program, opt.clone())?;
         }
-        None => {
-            todo!("Implement repl")
+        Some(
+            Command::Dap
+            | Command::Lsp
+            | Command::Repl(_)
+            | Command::Fmt(_)
+            | Command::Test(_)
+            | Command::Init(_)
+            | Command::Explain(_)
+            | Command::Doc(_)
+            | Command::Lint(_)
+            | Command::Stats(_)
+            | Command::Difftest(_)
+            | Command::DumpTokens(_)
+            | Command::DumpIr(_),
+        ) => {
+            unreachable!("dap, lsp, repl, fmt, test, init, explain, doc and dumps are done by now")
         }
+        None => repl(args, search_path.to_vec(), Some(file))?,
     };
 
+    Ok(0)
+}
+
+fn format(opt: &FmtOptions) -> Result<()> {
+    if opt.files.is_empty() {
+        Cli::command()
+            .error(ErrorKind::MissingRequiredArgument, "FILE is required")
+            .exit();
+    }
+    if !fmt::run(opt)? && opt.check {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+/// Simulate a line at a time, after `file` if there is one.
+fn repl(args: &Cli, search_path: Vec<PathBuf>, file: Option<&PathBuf>) -> Result<()> {
+    let typecheck = (!args.unsafe_).then(|| args.typecheck.clone());
+    sim::repl::run(typecheck, search_path, file.map(PathBuf::as_path))
+}
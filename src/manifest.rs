@@ -0,0 +1,414 @@
+//! `worth.toml`, which makes a directory a project `worthc build` can build
+//! without being given a file:
+//!
+//! ```toml
+//! [package]
+//! name = "hello"
+//! entry = "src/main.porth"
+//! include = ["lib"]
+//!
+//! [dependencies]
+//! strings = { path = "../strings" }
+//! rule110 = { git = "https://example.com/rule110.git", rev = "v1" }
+//! ```
+//!
+//! Only as much TOML as that is read, by hand like `json` is. Includes are
+//! looked for in the project's include directories, then in each dependency's
+//! directory and the include directories of its own manifest if it has one.
+//! Dependencies from git are cloned into `.worth/deps` next to the manifest
+//! the first time they're needed, and used from there after, until their url
+//! or rev changes.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+use crate::error::{Error::ManifestError, ManifestError::*};
+use crate::log::{self, LogLevel};
+
+pub const MANIFEST: &str = "worth.toml";
+/// Where dependencies from git are cloned to, in the project
+pub const DEPS_DIR: &str = ".worth/deps";
+/// The url and rev a dependency was cloned from, in its clone's .git. It's
+/// written last, so a clone that was cut short doesn't have one.
+const FETCHED: &str = ".git/worth-fetched";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// The directory the manifest is in, which its paths are relative to
+    pub dir: PathBuf,
+    pub name: String,
+    pub entry: PathBuf,
+    pub include: Vec<PathBuf>,
+    pub dependencies: Vec<Dependency>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    pub source: Source,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Path(PathBuf),
+    Git { url: String, rev: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    String(String),
+    Bool(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// A list of paths, if it's a list of strings.
+    pub(crate) fn as_paths(&self) -> Option<Vec<PathBuf>> {
+        match self {
+            Value::Array(values) => values
+                .iter()
+                .map(|value| value.as_str().map(PathBuf::from))
+                .collect(),
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Table(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+/// A table's keys and their values, in order.
+pub(crate) type Table = Vec<(String, Value)>;
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn invalid<T>(&self, why: impl Into<String>) -> Result<T> {
+        let why = format!("line {}: {}", self.line, why.into());
+        Err(ManifestError(InvalidManifest(why)).into())
+    }
+
+    fn space(&mut self) {
+        while self.chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.space();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => self.invalid(format!("expected {:?}, found {:?}", expected, c)),
+            None => self.invalid(format!("expected {:?}", expected)),
+        }
+    }
+
+    fn key(&mut self) -> Result<String> {
+        self.space();
+        let mut key = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        {
+            key.push(c);
+        }
+        if key.is_empty() {
+            return self.invalid("expected a key");
+        }
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.space();
+        match self.chars.next() {
+            Some('"') => {
+                let mut s = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some('"') => return Ok(Value::String(s)),
+                        Some('\\') => match self.chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(c @ ('"' | '\\')) => s.push(c),
+                            _ => return self.invalid("invalid escape"),
+                        },
+                        Some(c) => s.push(c),
+                        None => return self.invalid("unterminated string"),
+                    }
+                }
+            }
+            Some('[') => {
+                let mut values = Vec::new();
+                loop {
+                    self.space();
+                    if self.chars.next_if_eq(&']').is_some() {
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.space();
+                    if self.chars.next_if_eq(&',').is_none() {
+                        self.expect(']')?;
+                        return Ok(Value::Array(values));
+                    }
+                }
+            }
+            Some('{') => {
+                let mut fields = Vec::new();
+                self.space();
+                if self.chars.next_if_eq(&'}').is_some() {
+                    return Ok(Value::Table(fields));
+                }
+                loop {
+                    let key = self.key()?;
+                    self.expect('=')?;
+                    fields.push((key, self.value()?));
+                    self.space();
+                    if self.chars.next_if_eq(&',').is_none() {
+                        self.expect('}')?;
+                        return Ok(Value::Table(fields));
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut word = c.to_string();
+                while let Some(c) = self.chars.next_if(char::is_ascii_alphabetic) {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => self.invalid(format!("unexpected {:?}", word)),
+                }
+            }
+            Some(c) => self.invalid(format!("unexpected {:?}", c)),
+            None => self.invalid("expected a value"),
+        }
+    }
+
+    /// The rest of the line, which can only be a comment.
+    fn end(&mut self) -> Result<()> {
+        self.space();
+        match self.chars.next() {
+            None | Some('#') => Ok(()),
+            Some(c) => self.invalid(format!("unexpected {:?}", c)),
+        }
+    }
+}
+
+/// The tables in `text` by name, the keys before any in one without.
+pub(crate) fn parse_tables(text: &str) -> Result<Vec<(String, Table)>> {
+    let mut tables = vec![(String::new(), Table::new())];
+    for (line_no, line) in text.lines().enumerate() {
+        let mut parser = Parser {
+            chars: line.chars().peekable(),
+            line: line_no + 1,
+        };
+        parser.space();
+        match parser.chars.peek() {
+            None | Some('#') => continue,
+            Some('[') => {
+                parser.chars.next();
+                let name = parser.key()?;
+                parser.expect(']')?;
+                parser.end()?;
+                tables.push((name, Vec::new()));
+            }
+            Some(_) => {
+                let key = parser.key()?;
+                parser.expect('=')?;
+                let value = parser.value()?;
+                parser.end()?;
+                tables.last_mut().unwrap().1.push((key, value));
+            }
+        }
+    }
+    Ok(tables)
+}
+
+impl Manifest {
+    /// The manifest in `text`, which is in `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Result<Manifest> {
+        let tables = parse_tables(text)?;
+        let table = |name: &str| -> Table {
+            tables
+                .iter()
+                .filter(|(table, _)| table == name)
+                .flat_map(|(_, fields)| fields.clone())
+                .collect()
+        };
+        let invalid = |why: String| ManifestError(InvalidManifest(why));
+        let package = Value::Table(table("package"));
+        let string = |key: &str| match package.get(key) {
+            Some(value) => value
+                .as_str()
+                .map(|s| Some(s.to_string()))
+                .ok_or_else(|| invalid(format!("package.{} isn't a string", key))),
+            None => Ok(None),
+        };
+        let name = string("name")?.ok_or(ManifestError(MissingField("package.name")))?;
+        let entry = string("entry")?.unwrap_or_else(|| "main.porth".to_string());
+        let include = match package.get("include") {
+            Some(dirs) => dirs.as_paths(),
+            None => Some(Vec::new()),
+        }
+        .ok_or_else(|| invalid("package.include isn't a list of strings".to_string()))?;
+
+        let mut dependencies = Vec::new();
+        for (name, dep) in table("dependencies") {
+            let field = |key| dep.get(key).and_then(Value::as_str).map(str::to_string);
+            let source = match (field("path"), field("git")) {
+                (Some(path), None) => Source::Path(PathBuf::from(path)),
+                // git would take it for an option
+                (None, Some(_)) if field("rev").is_some_and(|rev| rev.starts_with('-')) => {
+                    let why = format!("dependency {}'s rev can't start with -", name);
+                    return Err(invalid(why).into());
+                }
+                (None, Some(url)) => Source::Git {
+                    url,
+                    rev: field("rev"),
+                },
+                _ => {
+                    let why = format!("dependency {} needs either a path or a git URL", name);
+                    return Err(invalid(why).into());
+                }
+            };
+            dependencies.push(Dependency { name, source });
+        }
+
+        Ok(Manifest {
+            dir: dir.to_path_buf(),
+            name,
+            entry: PathBuf::from(entry),
+            include,
+            dependencies,
+        })
+    }
+
+    /// The manifest at `path`.
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.to_string_lossy()))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        Manifest::parse(&text, dir).with_context(|| format!("In {}", path.to_string_lossy()))
+    }
+
+    /// The closest manifest to `dir`, in it or a directory above it.
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|dir| dir.join(MANIFEST))
+            .find(|path| path.is_file())
+    }
+
+    /// The program `worthc build` builds.
+    pub fn entry(&self) -> PathBuf {
+        self.dir.join(&self.entry)
+    }
+
+    /// Where includes are looked for, fetching dependencies that aren't yet.
+    pub fn search_path(&self) -> Result<Vec<PathBuf>> {
+        let mut search_path = Vec::new();
+        self.add_search_path(&self.dir, &mut search_path, &mut Vec::new())?;
+        Ok(search_path)
+    }
+
+    /// Add this manifest's directories to `search_path`, then its
+    /// dependencies', fetching any from git into `project`. `seen` has the
+    /// dependencies already added, so each is added once.
+    fn add_search_path(
+        &self,
+        project: &Path,
+        search_path: &mut Vec<PathBuf>,
+        seen: &mut Vec<String>,
+    ) -> Result<()> {
+        search_path.extend(self.include.iter().map(|dir| self.dir.join(dir)));
+        for dep in &self.dependencies {
+            if seen.contains(&dep.name) {
+                continue;
+            }
+            seen.push(dep.name.clone());
+            let dir = match &dep.source {
+                Source::Path(path) => self.dir.join(path),
+                Source::Git { url, rev } => fetch(project, &dep.name, url, rev.as_deref())?,
+            };
+            search_path.push(dir.clone());
+            let manifest = dir.join(MANIFEST);
+            if manifest.is_file() {
+                Manifest::load(&manifest)?.add_search_path(project, search_path, seen)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Clone `url` at `rev` into the cache of `project` as `name`, unless it's
+/// there already from the same url and rev, returning where it is.
+fn fetch(project: &Path, name: &str, url: &str, rev: Option<&str>) -> Result<PathBuf> {
+    let dir = project.join(DEPS_DIR).join(name);
+    let fetched = format!("{}\n{}\n", url, rev.unwrap_or_default());
+    if dir.exists() {
+        if std::fs::read_to_string(dir.join(FETCHED)).is_ok_and(|was| was == fetched) {
+            return Ok(dir);
+        }
+        log::log(
+            LogLevel::Info,
+            format!(
+                "Fetching {} again, it's from another url or rev or wasn't finished",
+                name
+            ),
+            false,
+        );
+        std::fs::remove_dir_all(&dir)
+            .with_context(|| format!("Could not remove {}", dir.to_string_lossy()))?;
+    }
+    let mut clone = Command::new("git");
+    clone.args(["clone", "--quiet", "--", url]).arg(&dir);
+    let mut commands = vec![clone];
+    if let Some(rev) = rev {
+        let mut checkout = Command::new("git");
+        checkout
+            .arg("-C")
+            .arg(&dir)
+            .args(["checkout", "--quiet", rev]);
+        commands.push(checkout);
+    }
+    for mut command in commands {
+        log::log(
+            LogLevel::Cmd,
+            format!("{:?}", command).replace('"', ""),
+            false,
+        );
+        let status = command
+            .status()
+            .with_context(|| format!("Could not run git to fetch {}", name))?;
+        if !status.success() {
+            // Not left half cloned, so it's tried again next time
+            let _ = std::fs::remove_dir_all(&dir);
+            return Err(ManifestError(FetchFailed(name.to_string())).into());
+        }
+    }
+    std::fs::write(dir.join(FETCHED), fetched)
+        .with_context(|| format!("Could not write {}", dir.join(FETCHED).to_string_lossy()))?;
+    Ok(dir)
+}
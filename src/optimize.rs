@@ -0,0 +1,283 @@
+//! Optimizations on the preprocessed program, run after typechecking and
+//! before codegen so errors still point at the code as written.
+//!
+//! Constant folding evaluates arithmetic, comparisons and stack shuffles whose
+//! inputs are all pushed constants, like the ones macros expand to. Keywords are
+//! never folded over, and every jump target is a keyword, so only straight-line
+//! code is affected. Results match the generated code, not the simulator, which
+//! differ for division and shifts of negative numbers.
+//!
+//! Dead code elimination then drops the branches a constant condition never
+//! takes, loops that never run, and code after the program has exited or
+//! started looping forever, up to the end of the enclosing block.
+
+use anyhow::Result;
+
+use crate::codegen::intrinsics::Intrinsic;
+use crate::instruction::{Instruction, InstructionKind, Keyword, Op, Program, SyscallKind, Value};
+use crate::log::{self, LogLevel};
+use crate::preprocessor;
+
+/// Optimize a copy of `program` for codegen. Level 0 leaves it as it is.
+/// With `verbose`, every piece of code that was removed is reported.
+pub fn optimize(program: &Program, level: u8, verbose: bool) -> Result<Program> {
+    let mut program = program.clone();
+    if level == 0 {
+        return Ok(program);
+    }
+    program.instructions = fold_constants(&program.instructions);
+    preprocessor::relink(&mut program)?;
+    // An elif left first in its chain is only an if on the next round
+    let mut removed = Vec::new();
+    while eliminate_dead_code(&mut program, &mut removed) {
+        preprocessor::relink(&mut program)?;
+    }
+    if verbose {
+        for message in removed {
+            log::log(LogLevel::Info, message, true);
+        }
+    }
+    Ok(program)
+}
+
+pub(crate) fn constant(inst: &Instruction) -> Option<i64> {
+    match inst.kind {
+        InstructionKind::Push(Value::Int(i)) => Some(i),
+        InstructionKind::Push(Value::Bool(b)) => Some(b as i64),
+        InstructionKind::Push(Value::Char(c)) => Some(c as i64),
+        _ => None,
+    }
+}
+
+/// What `kind` leaves on the stack given constant inputs, deepest first.
+fn evaluate(kind: &InstructionKind, args: &[i64]) -> Option<Vec<Value>> {
+    let int = |i: i64| Some(vec![Value::Int(i)]);
+    let bool = |b: bool| Some(vec![Value::Bool(b)]);
+    match (kind, args) {
+        (InstructionKind::Op(op), &[a, b]) => {
+            let (ua, ub) = (a as u64, b as u64);
+            match op {
+                Op::Add => int(a.wrapping_add(b)),
+                Op::Sub => int(a.wrapping_sub(b)),
+                Op::Mul => int(a.wrapping_mul(b)),
+                // Unsigned, and left to fault at runtime when dividing by zero
+                Op::Div => int(ua.checked_div(ub)? as i64),
+                Op::Mod => int(ua.checked_rem(ub)? as i64),
+                Op::DivMod => Some(vec![
+                    Value::Int(ua.checked_div(ub)? as i64),
+                    Value::Int((ua % ub) as i64),
+                ]),
+                Op::BitwiseAnd => int(a & b),
+                Op::BitwiseOr => int(a | b),
+                Op::BitwiseXor => int(a ^ b),
+                // The shift count is masked like x86 does
+                Op::Shl => int((ua << (ub & 63)) as i64),
+                Op::Shr => int((ua >> (ub & 63)) as i64),
+                Op::Eq => bool(a == b),
+                Op::Neq => bool(a != b),
+                Op::Lt => bool(a < b),
+                Op::Gt => bool(a > b),
+                Op::Lte => bool(a <= b),
+                Op::Gte => bool(a >= b),
+                _ => None,
+            }
+        }
+        (InstructionKind::Intrinsic(intrinsic), args) => {
+            let ints = |ints: &[i64]| Some(ints.iter().map(|&i| Value::Int(i)).collect());
+            match (intrinsic, args) {
+                (Intrinsic::Drop, [_]) | (Intrinsic::Drop2, [_, _]) => Some(Vec::new()),
+                (Intrinsic::CastInt | Intrinsic::CastPtr, &[a]) => int(a),
+                (Intrinsic::Dup, &[a]) => ints(&[a, a]),
+                (Intrinsic::Swap, &[a, b]) => ints(&[b, a]),
+                (Intrinsic::Over, &[a, b]) => ints(&[a, b, a]),
+                (Intrinsic::Dup2, &[a, b]) => ints(&[a, b, a, b]),
+                // Left to the simulator to report when it isn't a power of two
+                (Intrinsic::Align, &[a, n]) if n > 0 && n & (n - 1) == 0 => {
+                    int(a.wrapping_add(n - 1) & -n)
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn arity(kind: &InstructionKind) -> Option<usize> {
+    match kind {
+        InstructionKind::Op(Op::Load | Op::Load64 | Op::Store | Op::Store64 | Op::BitwiseNot) => {
+            None
+        }
+        InstructionKind::Op(_) => Some(2),
+        InstructionKind::Intrinsic(
+            Intrinsic::Drop | Intrinsic::Dup | Intrinsic::CastInt | Intrinsic::CastPtr,
+        ) => Some(1),
+        InstructionKind::Intrinsic(
+            Intrinsic::Drop2
+            | Intrinsic::Swap
+            | Intrinsic::Over
+            | Intrinsic::Dup2
+            | Intrinsic::Align,
+        ) => Some(2),
+        _ => None,
+    }
+}
+
+fn fold_constants(instructions: &[Instruction]) -> Vec<Instruction> {
+    let mut folded: Vec<Instruction> = Vec::with_capacity(instructions.len());
+    for inst in instructions {
+        let Some(n) = arity(&inst.kind) else {
+            folded.push(inst.clone());
+            continue;
+        };
+        let Some(start) = folded.len().checked_sub(n) else {
+            folded.push(inst.clone());
+            continue;
+        };
+        let args: Option<Vec<i64>> = folded[start..].iter().map(constant).collect();
+        let Some(results) = args.and_then(|args| evaluate(&inst.kind, &args)) else {
+            folded.push(inst.clone());
+            continue;
+        };
+        // The result stands for the whole expression, so it takes the location it starts at
+        let loc = folded[start].loc.clone();
+        folded.truncate(start);
+        folded.extend(results.into_iter().map(|value| Instruction {
+            kind: InstructionKind::Push(value),
+            loc: loc.clone(),
+            ip: 0,
+        }));
+    }
+    folded
+}
+
+/// Whether nothing runs after `inst`, because it exits the program.
+pub(crate) fn exits(prev: Option<&Instruction>, inst: &Instruction) -> bool {
+    let exit = matches!(prev.and_then(constant), Some(60 | 231));
+    match inst.kind {
+        InstructionKind::Syscall(SyscallKind::Syscall1) => exit,
+        InstructionKind::Intrinsic(Intrinsic::Panic) => true,
+        _ => false,
+    }
+}
+
+/// The `end`, `elif`, `else` or `do` that closes the block `start` is in.
+pub(crate) fn block_end(instructions: &[Instruction], start: usize) -> usize {
+    let mut depth = 0;
+    for (ip, inst) in instructions.iter().enumerate().skip(start) {
+        match inst.kind {
+            InstructionKind::Keyword(Keyword::If | Keyword::While { .. } | Keyword::Unsafe) => {
+                depth += 1
+            }
+            InstructionKind::Keyword(Keyword::End { .. }) if depth > 0 => depth -= 1,
+            InstructionKind::Keyword(
+                Keyword::End { .. }
+                | Keyword::Elif { .. }
+                | Keyword::Else { .. }
+                | Keyword::Do { .. },
+            ) if depth == 0 => return ip,
+            _ => {}
+        }
+    }
+    instructions.len()
+}
+
+/// Remove code that can never run, adding a message to `removed` for each piece
+/// of it. Returns whether anything changed, in which case jumps need relinking.
+fn eliminate_dead_code(program: &mut Program, removed: &mut Vec<String>) -> bool {
+    let instructions = &program.instructions;
+    let mut dead = vec![false; instructions.len()];
+    let mut elifs = Vec::new();
+    let mut remove = |dead: &mut [bool], range: std::ops::Range<usize>, why: Option<&str>| {
+        if let (Some(why), Some(first)) = (why, instructions.get(range.start)) {
+            if !range.is_empty() {
+                removed.push(format!(
+                    "Removed {} instructions at {}:{}:{} that never run, {}",
+                    range.len(),
+                    first.loc.0,
+                    first.loc.1,
+                    first.loc.2,
+                    why
+                ));
+            }
+        }
+        dead[range].fill(true);
+    };
+    for (ip, inst) in instructions.iter().enumerate() {
+        if dead[ip] {
+            continue;
+        }
+        if exits(ip.checked_sub(1).map(|prev| &instructions[prev]), inst) {
+            let end = block_end(instructions, ip + 1);
+            remove(&mut dead, ip + 1..end, Some("after the program exits"));
+            continue;
+        }
+        let condition = instructions.get(ip + 1).and_then(constant);
+        let body_end = match instructions.get(ip + 2).map(|inst| &inst.kind) {
+            Some(InstructionKind::Keyword(Keyword::Do { end_ip })) => *end_ip,
+            _ => continue,
+        };
+        match (&inst.kind, condition) {
+            (InstructionKind::Keyword(Keyword::If), Some(0)) => {
+                remove(&mut dead, ip..ip + 3, None);
+                remove(
+                    &mut dead,
+                    ip + 3..body_end,
+                    Some("the condition is always false"),
+                );
+                match instructions[body_end].kind {
+                    InstructionKind::Keyword(Keyword::End { .. }) => {
+                        remove(&mut dead, body_end..body_end + 1, None)
+                    }
+                    InstructionKind::Keyword(Keyword::Else { end_ip, .. }) => {
+                        remove(&mut dead, body_end..body_end + 1, None);
+                        remove(&mut dead, end_ip..end_ip + 1, None);
+                    }
+                    _ => elifs.push(body_end),
+                }
+            }
+            (InstructionKind::Keyword(Keyword::If), Some(_)) => {
+                remove(&mut dead, ip..ip + 3, None);
+                match instructions[body_end].kind {
+                    InstructionKind::Keyword(
+                        Keyword::Elif { end_ip, .. } | Keyword::Else { end_ip, .. },
+                    ) => {
+                        remove(
+                            &mut dead,
+                            body_end..end_ip,
+                            Some("the condition is always true"),
+                        );
+                        remove(&mut dead, end_ip..end_ip + 1, None);
+                    }
+                    _ => remove(&mut dead, body_end..body_end + 1, None),
+                }
+            }
+            (InstructionKind::Keyword(Keyword::While { .. }), Some(0)) => {
+                remove(
+                    &mut dead,
+                    ip..body_end + 1,
+                    Some("the loop condition is always false"),
+                );
+            }
+            (InstructionKind::Keyword(Keyword::While { .. }), Some(_)) => {
+                let end = block_end(instructions, body_end + 1);
+                remove(
+                    &mut dead,
+                    body_end + 1..end,
+                    Some("after a loop that never ends"),
+                );
+            }
+            _ => {}
+        }
+    }
+    if !dead.contains(&true) && elifs.is_empty() {
+        return false;
+    }
+    for elif in elifs {
+        program.instructions[elif].kind = InstructionKind::Keyword(Keyword::If);
+    }
+    let mut dead = dead.into_iter();
+    program
+        .instructions
+        .retain(|_| !dead.next().unwrap_or(false));
+    true
+}
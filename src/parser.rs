@@ -3,6 +3,7 @@ use std::{collections::HashMap, path::PathBuf};
 use crate::{
     codegen::intrinsics::Intrinsic,
     error::{
+        Diagnostic,
         Error::{IOError, ParseError},
         IOError::InvalidPath,
         ParseError::*,
@@ -15,9 +16,10 @@ use nom::{
     bytes::complete::tag,
     character::complete::{char, digit1, hex_digit1, multispace0, multispace1, satisfy},
     combinator::{eof, opt},
+    error::ErrorKind,
     multi::{many0, many1},
     sequence::{delimited, preceded, tuple},
-    FindSubstring, FindToken, IResult,
+    FindSubstring, IResult,
 };
 use nom_locate::LocatedSpan;
 
@@ -90,33 +92,64 @@ pub fn parse(source: String, name: &str, path: PathBuf) -> Result<Program> {
             })
             .collect::<Result<Vec<_>>>()?,
         macros: HashMap::new(),
+        memories: Vec::new(),
+        includes: Vec::new(),
+        search_path: Vec::new(),
     })
 }
 
 pub fn parse_program<'a>(input: Span<'a>) -> Result<Vec<Token>> {
     let mut input = input;
     let mut tokens = Vec::new();
-    while let Ok((rem, token)) = delimited(
-        multispace0,
-        alt((
-            parse_comment,
-            parse_keyword,
-            parse_syscalls,
-            parse_intrinsic,
-            parse_value,
-            parse_op,
-            parse_name,
-        )),
-        alt((multispace1, eof)),
-    )(input)
-    {
+    loop {
+        let (rem, token) = match delimited(
+            multispace0,
+            alt((
+                parse_comment,
+                parse_keyword,
+                parse_syscalls,
+                parse_intrinsic,
+                parse_value,
+                parse_op,
+                parse_name,
+            )),
+            alt((multispace1, eof)),
+        )(input)
+        {
+            Ok(parsed) => parsed,
+            // An integer literal that's too big, see `int_too_large`
+            Err(nom::Err::Failure(e)) if e.code == ErrorKind::TooLarge => {
+                let literal = e
+                    .input
+                    .fragment()
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default();
+                // Columns from 0, like the tokens'
+                let loc = (
+                    e.input.extra.to_string(),
+                    e.input.location_line() as usize,
+                    e.input.get_utf8_column() - 1,
+                );
+                return Err(ParseError(IntegerTooLarge(literal.to_string()))).with_context(|| {
+                    Diagnostic::at(&loc, format!("{} doesn't fit in 64 bits", literal))
+                });
+            }
+            Err(_) => break,
+        };
         tokens.push(token);
         input = rem;
     }
 
     if !input.fragment().is_empty() {
-        return Err(ParseError(Incomplete))
-            .with_context(|| format!("Remaining input: {}", input.fragment()));
+        let loc = (
+            input.extra.to_string(),
+            input.location_line() as usize,
+            input.get_utf8_column(),
+        );
+        return Err(ParseError(Incomplete)).with_context(|| {
+            Diagnostic::at(&loc, format!("Remaining input: {}", input.fragment()))
+        });
     }
 
     Ok(tokens)
@@ -124,6 +157,12 @@ pub fn parse_program<'a>(input: Span<'a>) -> Result<Vec<Token>> {
 
 pub fn parse_syscalls<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
     let (input, syscall) = preceded(tag("syscall"), digit1)(base_input)?;
+    let Ok(n) = syscall.fragment().parse::<usize>() else {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            base_input,
+            ErrorKind::Digit,
+        )));
+    };
 
     let loc = (
         base_input.extra.to_string(),
@@ -137,7 +176,7 @@ pub fn parse_syscalls<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
     let token = Token {
         value: "syscall".to_owned() + syscall.fragment(),
         location: loc,
-        ty: TokenType::Syscall(syscall.fragment().parse::<usize>().unwrap()),
+        ty: TokenType::Syscall(n),
     };
     Ok((input, token))
 }
@@ -182,13 +221,15 @@ pub fn parse_string<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
         char('"'),
     )(base_input)?;
     let value = value.into_iter().collect::<String>();
+    // Search for the literal as written, since escapes make `value` differ from the source
+    let raw = &base_input.fragment()[..base_input.fragment().len() - input.fragment().len()];
 
     let loc = (
         base_input.extra.to_string(),
         base_input.location_line() as usize,
         base_input
             .get_line_beginning()
-            .find_substring(value.as_bytes())
+            .find_substring(raw.lines().next().unwrap_or_default().as_bytes())
             .unwrap(),
     );
 
@@ -247,6 +288,20 @@ pub fn parse_char<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
     Ok((input, token))
 }
 
+/// How `c` is written in a literal quoted with `quote`, the way `special_char`
+/// reads it back.
+pub fn escape(c: char, quote: char) -> String {
+    match c {
+        '\n' => "\\n".to_owned(),
+        '\t' => "\\t".to_owned(),
+        '\r' => "\\r".to_owned(),
+        '\\' => "\\\\".to_owned(),
+        '\0' => "\\0".to_owned(),
+        c if c == quote => format!("\\{}", c),
+        c => c.to_string(),
+    }
+}
+
 pub fn special_char<'a>(input: Span<'a>) -> IResult<Span<'a>, char> {
     let (input, c) = preceded(
         char('\\'),
@@ -287,14 +342,23 @@ pub fn parse_int<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
             .unwrap(),
     );
 
+    let value_num = fragment
+        .parse::<i64>()
+        .map_err(|_| int_too_large(base_input))?;
     let token = Token {
         value: fragment.clone(),
         location: loc,
-        ty: TokenType::Value(Value::Int(fragment.parse::<i64>().unwrap())),
+        ty: TokenType::Value(Value::Int(value_num)),
     };
     Ok((input, token))
 }
 
+/// Stop lexing at an integer literal that doesn't fit in an i64, rather than
+/// trying it as a name.
+fn int_too_large(input: Span) -> nom::Err<nom::error::Error<Span>> {
+    nom::Err::Failure(nom::error::Error::new(input, ErrorKind::TooLarge))
+}
+
 pub fn parse_hex_int<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
     let (input, value) = preceded(alt((tag("0x"), tag("0X"))), hex_digit1)(base_input)?;
     let loc = (
@@ -305,7 +369,8 @@ pub fn parse_hex_int<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
             .find_substring(value.fragment().as_bytes())
             .unwrap(),
     );
-    let value_num = i64::from_str_radix(value.fragment(), 16).unwrap();
+    let value_num =
+        i64::from_str_radix(value.fragment(), 16).map_err(|_| int_too_large(base_input))?;
     let token = Token {
         value: value_num.to_string(),
         location: loc,
@@ -373,6 +438,9 @@ pub fn parse_keyword<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
         tag("macro"),
         tag("end"),
         tag("include"),
+        tag("unsafe"),
+        tag("extern"),
+        tag("memory"),
     ))(base_input)?;
     let loc = (
         base_input.extra.to_string(),
@@ -460,7 +528,8 @@ pub fn parse_op<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
 
 pub fn parse_comment<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
     let (input, _) = nom::bytes::complete::tag("//")(base_input)?;
-    let (input, spaces) = multispace0(input)?;
+    // Not past the end of the line, or an empty comment would take the next one
+    let (input, spaces) = nom::character::complete::space0(input)?;
     let (input, comment) = nom::bytes::complete::take_while(|c: char| c != '\n')(input)?;
     let loc = (
         base_input.extra.to_string(),
@@ -473,7 +542,8 @@ pub fn parse_comment<'a>(base_input: Span<'a>) -> IResult<Span<'a>, Token> {
     Ok((
         input,
         Token {
-            value: "".to_string(),
+            // As it's written, for the formatter
+            value: format!("//{}{}", spaces.fragment(), comment.fragment().trim_end()),
             location: loc,
             ty: TokenType::Comment,
         },
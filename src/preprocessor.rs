@@ -1,10 +1,15 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use crate::codegen::intrinsics::Intrinsic;
 use crate::err;
 use crate::error::kw_str;
 use crate::error::{Error::PreprocessorError, PreprocessorError::*};
-use crate::instruction::{Instruction, InstructionKind, Keyword, Macro, Program, Value};
+use crate::instruction::{
+    Extern, Instruction, InstructionKind, Keyword, Macro, Memory, Program, Value,
+};
+use crate::log::{self, LogLevel};
+use crate::timings;
 use anyhow::{Context, Result};
 
 pub fn process(mut program: Program) -> Result<Program> {
@@ -16,6 +21,10 @@ pub fn process(mut program: Program) -> Result<Program> {
         "Failed to process includes for {}.porth",
         program.name
     ))?;
+    let externs = collect_externs(&mut program).context(format!(
+        "Failed to process externs for {}.porth",
+        program.name
+    ))?;
     collect_macros(&mut program).context(format!(
         "Failed to process macros for {}.porth",
         program.name
@@ -36,6 +45,22 @@ pub fn process(mut program: Program) -> Result<Program> {
         }
         depth += 1;
     }
+    log::log(
+        LogLevel::Debug,
+        format!(
+            "{} macros expanded in {} passes, {} instructions",
+            program.macros.len(),
+            depth,
+            program.instructions.len()
+        ),
+        false,
+    );
+    collect_memories(&mut program).context(format!(
+        "Failed to process memories for {}.porth",
+        program.name
+    ))?;
+    resolve_externs(&mut program, &externs);
+    resolve_memories(&mut program);
     ips(&mut program);
     jumps(&mut program).context(format!(
         "Failed to validate control flow for {}.porth",
@@ -44,12 +69,32 @@ pub fn process(mut program: Program) -> Result<Program> {
     Ok(program)
 }
 
+/// Number the instructions and resolve jumps again, after a pass changed them.
+pub fn relink(program: &mut Program) -> Result<()> {
+    ips(program);
+    jumps(program).context(format!(
+        "Failed to validate control flow for {}.porth",
+        program.name
+    ))
+}
+
 fn ips(program: &mut Program) {
     for (ip, instruction) in program.instructions.iter_mut().enumerate() {
         instruction.ip = ip;
     }
 }
 
+/// `program` with its includes put in and nothing else done to it, so macro
+/// and memory declarations are still where they were written.
+pub fn included(mut program: Program) -> Result<Program> {
+    here(&mut program)?;
+    includes(&mut program, 0).context(format!(
+        "Failed to process includes for {}.porth",
+        program.name
+    ))?;
+    Ok(program)
+}
+
 fn here(program: &mut Program) -> Result<()> {
     for instruction in &mut program.instructions {
         match instruction.kind {
@@ -70,7 +115,6 @@ fn here(program: &mut Program) -> Result<()> {
 }
 
 fn includes(program: &mut Program, depth: usize) -> Result<()> {
-    // TODO(#3): Search path for includes
     let mut include_paths = Vec::new();
     let mut inst_to_remove = Vec::new();
 
@@ -115,7 +159,11 @@ fn includes(program: &mut Program, depth: usize) -> Result<()> {
     // Process includes
     let base_path = program.base_path.clone();
     for (include, include_ip) in &mut include_paths {
-        let include_path = base_path.join(&include);
+        let include_path = std::iter::once(&base_path)
+            .chain(&program.search_path)
+            .map(|dir| dir.join(&include))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| base_path.join(&include));
         if !include_path.exists() {
             err!(
                 program,
@@ -171,16 +219,152 @@ fn includes(program: &mut Program, depth: usize) -> Result<()> {
             )
         };
         let name = name.to_string_lossy().to_string();
-        let mut include_program = crate::parser::parse(include_file, &name, include_path.clone())?;
-        here(&mut include_program)?;
-        includes(&mut include_program, depth + 1)?;
+        let include_name = format!("include {}.porth", name);
+        let mut include_program = timings::time(include_name, || -> Result<Program> {
+            let mut include_program =
+                crate::parser::parse(include_file, &name, include_path.clone())?;
+            include_program.search_path = program.search_path.clone();
+            here(&mut include_program)?;
+            includes(&mut include_program, depth + 1)?;
+            log::log(
+                LogLevel::Debug,
+                format!(
+                    "Included {} from {}.porth, {} instructions",
+                    include_path.to_string_lossy(),
+                    program.name,
+                    include_program.instructions.len()
+                ),
+                false,
+            );
+            Ok(include_program)
+        })?;
         program
             .instructions
             .append(&mut include_program.instructions);
+        program.includes.push(include_path);
+        program.includes.append(&mut include_program.includes);
     }
     Ok(())
 }
 
+/// Take out every `extern name args returns`, where `returns` is 0 or 1.
+fn collect_externs(program: &mut Program) -> Result<HashMap<String, Extern>> {
+    let mut externs = HashMap::new();
+    let mut ip = 0;
+    while ip < program.instructions.len() {
+        let InstructionKind::Keyword(Keyword::Extern) = program.instructions[ip].kind else {
+            ip += 1;
+            continue;
+        };
+        let declaration = program.instructions.get(ip + 1..ip + 4).map(|parts| {
+            let kinds: Vec<&InstructionKind> = parts.iter().map(|part| &part.kind).collect();
+            match kinds[..] {
+                [InstructionKind::Name(name), InstructionKind::Push(Value::Int(args)), InstructionKind::Push(Value::Int(returns))]
+                    if (0..=6).contains(args) && (0..=1).contains(returns) =>
+                {
+                    Some(Extern {
+                        name: name.clone(),
+                        args: *args as usize,
+                        returns: *returns == 1,
+                    })
+                }
+                _ => None,
+            }
+        });
+        let Some(Some(declaration)) = declaration else {
+            let name = program
+                .instructions
+                .get(ip + 1)
+                .map(|name| name.kind.to_string());
+            err!(
+                program,
+                PreprocessorError(InvalidExtern(name.unwrap_or_default())),
+                "Expected a name, up to 6 arguments and 0 or 1 results after extern",
+                ip
+            );
+        };
+        externs.insert(declaration.name.clone(), declaration);
+        program.instructions.drain(ip..ip + 4);
+    }
+    Ok(externs)
+}
+
+/// Make the names left after macros have been expanded calls to the externs
+/// they name, so a macro hides an extern of the same name, like std's `puts`.
+fn resolve_externs(program: &mut Program, externs: &HashMap<String, Extern>) {
+    for instruction in &mut program.instructions {
+        if let InstructionKind::Name(name) = &instruction.kind {
+            if let Some(declaration) = externs.get(name) {
+                instruction.kind = InstructionKind::Extern(declaration.clone());
+            }
+        }
+    }
+}
+
+/// Take out every `memory name size end`, once macros have been expanded so
+/// the size can be one, laying the regions out one after the other.
+fn collect_memories(program: &mut Program) -> Result<()> {
+    let mut offset = 0;
+    let mut ip = 0;
+    while ip < program.instructions.len() {
+        let InstructionKind::Keyword(Keyword::Memory) = program.instructions[ip].kind else {
+            ip += 1;
+            continue;
+        };
+        let declaration = program.instructions.get(ip + 1..ip + 4).and_then(|parts| {
+            match [&parts[0].kind, &parts[1].kind, &parts[2].kind] {
+                [InstructionKind::Name(name), InstructionKind::Push(Value::Int(size)), InstructionKind::Keyword(Keyword::End { .. })]
+                    if *size >= 0 =>
+                {
+                    Some((name.clone(), *size as usize))
+                }
+                _ => None,
+            }
+        });
+        let Some((name, size)) = declaration else {
+            let name = program
+                .instructions
+                .get(ip + 1)
+                .map(|name| name.kind.to_string());
+            err!(
+                program,
+                PreprocessorError(InvalidMemory(name.unwrap_or_default())),
+                "Expected a name and a size in bytes between memory and end",
+                ip
+            );
+        };
+        if program.memories.iter().any(|memory| memory.name == name) {
+            err!(
+                program,
+                PreprocessorError(InvalidMemory(name.clone())),
+                format!("Memory {} is already declared", name),
+                ip
+            );
+        }
+        let memory = Memory {
+            name,
+            id: program.memories.len(),
+            size,
+            offset,
+        };
+        offset += memory.reserved();
+        program.memories.push(memory);
+        program.instructions.drain(ip..ip + 4);
+    }
+    Ok(())
+}
+
+/// Make the names of memories push their addresses.
+fn resolve_memories(program: &mut Program) {
+    for instruction in &mut program.instructions {
+        if let InstructionKind::Name(name) = &instruction.kind {
+            if let Some(memory) = program.memories.iter().find(|memory| &memory.name == name) {
+                instruction.kind = InstructionKind::Memory(memory.clone());
+            }
+        }
+    }
+}
+
 fn collect_macros(program: &mut Program) -> Result<()> {
     let mut macro_body = Vec::new();
     let mut macro_name = String::new();
@@ -202,7 +386,14 @@ fn collect_macros(program: &mut Program) -> Result<()> {
                 }
             }
             InstructionKind::Keyword(Keyword::End { .. }) => {
-                let (kind, start_ip) = macro_stack.pop().unwrap();
+                let Some((kind, start_ip)) = macro_stack.pop() else {
+                    err!(
+                        program,
+                        PreprocessorError(UnexpectedKeyword("end".to_string())),
+                        "End without a block to close.",
+                        ip
+                    );
+                };
                 match kind {
                     "macro" => {
                         if in_macro {
@@ -244,8 +435,21 @@ fn collect_macros(program: &mut Program) -> Result<()> {
             InstructionKind::Keyword(Keyword::While { .. }) => {
                 macro_stack.push(("while", ip));
             }
+            InstructionKind::Keyword(Keyword::Unsafe) => {
+                macro_stack.push(("unsafe", ip));
+            }
+            InstructionKind::Keyword(Keyword::Memory) => {
+                macro_stack.push(("memory", ip));
+            }
             InstructionKind::Keyword(Keyword::Do { .. }) => {
-                let _ = macro_stack.pop().unwrap().0;
+                if macro_stack.pop().is_none() {
+                    err!(
+                        program,
+                        PreprocessorError(UnexpectedKeyword("do".to_string())),
+                        "Do can only follow if, elif and while.",
+                        ip
+                    );
+                }
                 macro_stack.push(("do", ip));
             }
             _ => {}
@@ -272,7 +476,16 @@ fn expand_macros(program: &mut Program) -> Result<bool> {
                 in_macro = true;
                 continue;
             }
-            InstructionKind::Name(name) => {
+            // The name a memory is declared with isn't expanded
+            InstructionKind::Name(name)
+                if !matches!(
+                    new_instructions.last(),
+                    Some(Instruction {
+                        kind: InstructionKind::Keyword(Keyword::Memory),
+                        ..
+                    })
+                ) =>
+            {
                 if !in_macro {
                     if let Some(macro_) = program.macros.get(name) {
                         new_instructions.extend(macro_.body.clone());
@@ -284,23 +497,30 @@ fn expand_macros(program: &mut Program) -> Result<bool> {
             InstructionKind::Keyword(Keyword::While { .. }) => {
                 macro_stack.push("while");
             }
+            InstructionKind::Keyword(Keyword::Unsafe) => {
+                macro_stack.push("unsafe");
+            }
+            InstructionKind::Keyword(Keyword::Memory) => {
+                macro_stack.push("memory");
+            }
             InstructionKind::Keyword(Keyword::Do { .. }) => {
-                let _ = macro_stack.pop().unwrap();
+                macro_stack.pop();
                 macro_stack.push("do");
             }
             InstructionKind::Keyword(Keyword::If { .. }) => {
                 macro_stack.push("if");
             }
             InstructionKind::Keyword(Keyword::Elif { .. }) => {
-                let _ = macro_stack.pop().unwrap();
+                macro_stack.pop();
                 macro_stack.push("elif");
             }
             InstructionKind::Keyword(Keyword::Else { .. }) => {
-                let _ = macro_stack.pop().unwrap();
+                macro_stack.pop();
                 macro_stack.push("else");
             }
             InstructionKind::Keyword(Keyword::End { .. }) => {
-                let kind = macro_stack.pop().unwrap();
+                // What doesn't match up is reported by `jumps`
+                let kind = macro_stack.pop().unwrap_or_default();
 
                 match kind {
                     "macro" => {
@@ -343,7 +563,14 @@ fn jumps(program: &mut Program) -> Result<()> {
                 self_ip,
                 end_ip: else_ip,
             }) => {
-                let (t, if_do_end_ip, _, last_ip, last_last_ip) = jump_stack.pop().unwrap();
+                let Some((t, if_do_end_ip, _, last_ip, last_last_ip)) = jump_stack.pop() else {
+                    err!(
+                        program,
+                        PreprocessorError(UnexpectedKeyword("elif".to_string())),
+                        "Elif can only close if/do and elif/do blocks.",
+                        ip
+                    );
+                };
                 *self_ip = ip;
                 match t {
                     "ifdo" | "elifdo" => {}
@@ -365,7 +592,14 @@ fn jumps(program: &mut Program) -> Result<()> {
                 elifs.push(else_ip);
             }
             InstructionKind::Keyword(Keyword::Else { self_ip, end_ip }) => {
-                let (t, if_end_ip, _, last_ip, last_last_ip) = jump_stack.pop().unwrap();
+                let Some((t, if_end_ip, _, last_ip, last_last_ip)) = jump_stack.pop() else {
+                    err!(
+                        program,
+                        PreprocessorError(UnexpectedKeyword("else".to_string())),
+                        "Else can only close if/do and elif/do blocks.",
+                        ip
+                    );
+                };
                 *self_ip = ip;
                 match t {
                     "ifdo" | "elifdo" => {}
@@ -389,7 +623,14 @@ fn jumps(program: &mut Program) -> Result<()> {
                 self_ip,
                 while_ip: return_ip,
             }) => {
-                let (t, end_ip, while_ip, _, last_last_ip) = jump_stack.pop().unwrap();
+                let Some((t, end_ip, while_ip, _, last_last_ip)) = jump_stack.pop() else {
+                    err!(
+                        program,
+                        PreprocessorError(UnexpectedKeyword("end".to_string())),
+                        "End without a block to close.",
+                        ip
+                    );
+                };
                 *self_ip = ip;
                 match t {
                     "else" => {
@@ -413,11 +654,12 @@ fn jumps(program: &mut Program) -> Result<()> {
                         }
                         elifs.clear();
                     }
+                    "unsafe" => {}
                     _ => {
                         err!(
                             program,
                             PreprocessorError(UnexpectedKeyword(format!("end following {t}"))),
-                            "End can only close if/do, elif/do, else and while/do blocks.",
+                            "End can only close if/do, elif/do, else, while/do and unsafe blocks.",
                             ip,
                             last_last_ip
                         );
@@ -428,8 +670,18 @@ fn jumps(program: &mut Program) -> Result<()> {
                 *self_ip = ip;
                 jump_stack.push(("while", Some(self_ip), None, ip, None));
             }
+            InstructionKind::Keyword(Keyword::Unsafe) => {
+                jump_stack.push(("unsafe", None, None, ip, None));
+            }
             InstructionKind::Keyword(Keyword::Do { end_ip }) => {
-                let (t, while_ip, _, last_ip, _) = jump_stack.pop().unwrap();
+                let Some((t, while_ip, _, last_ip, _)) = jump_stack.pop() else {
+                    err!(
+                        program,
+                        PreprocessorError(UnexpectedKeyword("do".to_string())),
+                        "Do can only follow if, elif and while.",
+                        ip
+                    );
+                };
                 match t {
                     "if" => {
                         jump_stack.push(("ifdo", Some(end_ip), None, ip, Some(last_ip)));
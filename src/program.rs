@@ -1,11 +1,29 @@
-use crate::error::IOError::*;
-use crate::instruction::Program;
+use crate::error::{IOError::*, PreprocessorError::InvalidMemory};
+use crate::instruction::{InstructionKind, Keyword, Program};
+use crate::ir;
 use crate::preprocessor;
-use crate::{error::Error::IOError, parser};
+use crate::timings;
+use crate::{
+    error::Error::{IOError, PreprocessorError},
+    parser,
+};
 use anyhow::{Context, Result};
+use std::io::Read;
 use std::path::PathBuf;
 
+/// What a program read from stdin is called unless it's given a name.
+pub const STDIN_NAME: &str = "stdin";
+
 pub fn load_program(path: &PathBuf) -> Result<Program> {
+    load_program_with(path, Vec::new())
+}
+
+/// Like `load_program`, looking for includes in `search_path` too. An `.ir`
+/// file is read as the program it has, already preprocessed.
+pub fn load_program_with(path: &PathBuf, search_path: Vec<PathBuf>) -> Result<Program> {
+    if path.extension().is_some_and(|ext| ext == "ir") {
+        return ir::read(path);
+    }
     let path = path
         .canonicalize()
         .with_context(|| format!("Failed to canonicalize path {:?}", path))?;
@@ -20,7 +38,106 @@ pub fn load_program(path: &PathBuf) -> Result<Program> {
 
     let source = std::fs::read_to_string(&path).map_err(|e| IOError(Inherited(e)))?;
 
-    let program = parser::parse(source, name, path.clone())?;
-    let program = preprocessor::process(program)?;
-    Ok(program)
+    load_source(source, name, path.clone(), search_path)
+}
+
+/// The program read from stdin, called `name`. It includes files from the
+/// current directory, as if it were saved there.
+pub fn load_stdin(name: &str, search_path: Vec<PathBuf>) -> Result<Program> {
+    let mut source = String::new();
+    std::io::stdin()
+        .read_to_string(&mut source)
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| "Could not read the program from stdin")?;
+    let path = std::env::current_dir()
+        .map_err(|e| IOError(Inherited(e)))?
+        .join(format!("{}.porth", name));
+    load_source(source, name, path, search_path)
+}
+
+/// `source` parsed and preprocessed as if it were read from `path`.
+pub fn load_source(
+    source: String,
+    name: &str,
+    path: PathBuf,
+    search_path: Vec<PathBuf>,
+) -> Result<Program> {
+    let mut program = timings::time("parse", || parser::parse(source, name, path))?;
+    program.search_path = search_path;
+    timings::time("preprocess", || preprocessor::process(program))
+}
+
+/// `programs` as one, for building separately loaded files together. Each
+/// keeps its own includes and macros, runs after the one before it, and has
+/// its memories laid out after the ones before it. The first is what the
+/// program is called and where it's built.
+pub fn link(programs: Vec<Program>) -> Result<Program> {
+    let mut programs = programs.into_iter();
+    let mut linked = programs.next().expect("there's a program to link");
+    for mut program in programs {
+        let base = linked.instructions.len();
+        let (base_id, base_offset) = match linked.memories.last() {
+            Some(last) => (last.id + 1, last.offset + last.reserved()),
+            None => (0, 0),
+        };
+        for memory in &mut program.memories {
+            if linked.memories.iter().any(|m| m.name == memory.name) {
+                return Err(PreprocessorError(InvalidMemory(memory.name.clone()))).with_context(
+                    || {
+                        format!(
+                            "Memory {} is declared by {} and a program linked before it",
+                            memory.name, program.name
+                        )
+                    },
+                );
+            }
+            memory.id += base_id;
+            memory.offset += base_offset;
+        }
+        for inst in &mut program.instructions {
+            inst.ip += base;
+            match &mut inst.kind {
+                InstructionKind::Keyword(
+                    Keyword::While {
+                        self_ip,
+                        do_ip: jump,
+                    }
+                    | Keyword::Elif {
+                        self_ip,
+                        end_ip: jump,
+                    }
+                    | Keyword::Else {
+                        self_ip,
+                        end_ip: jump,
+                    },
+                ) => {
+                    *self_ip += base;
+                    *jump += base;
+                }
+                InstructionKind::Keyword(Keyword::Do { end_ip }) => *end_ip += base,
+                InstructionKind::Keyword(Keyword::End { self_ip, while_ip }) => {
+                    *self_ip += base;
+                    if let Some(while_ip) = while_ip {
+                        *while_ip += base;
+                    }
+                }
+                InstructionKind::Memory(memory) => {
+                    memory.id += base_id;
+                    memory.offset += base_offset;
+                }
+                _ => {}
+            }
+        }
+        for (name, macro_) in program.macros {
+            linked.macros.entry(name).or_insert(macro_);
+        }
+        linked.instructions.extend(program.instructions);
+        linked.memories.extend(program.memories);
+        for include in program.includes {
+            if !linked.includes.contains(&include) {
+                linked.includes.push(include);
+            }
+        }
+    }
+    Ok(linked)
 }
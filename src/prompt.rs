@@ -0,0 +1,35 @@
+//! Questions worthc asks before doing what can't be undone, like writing over
+//! a file. `--yes` answers every one of them yes. Without a terminal there's
+//! nobody to answer, so rather than wait for one each gets its default.
+
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+
+/// Set by `--yes`.
+static YES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_yes(yes: bool) {
+    YES.store(yes, Ordering::Relaxed);
+}
+
+/// Ask `question`, which is answered `default` when it can't be asked.
+pub fn confirm(question: &str, default: bool) -> Result<bool> {
+    if YES.load(Ordering::Relaxed) {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return Ok(default);
+    }
+    dialoguer::console::set_colors_enabled(true);
+    let answer = dialoguer::Select::new()
+        .item("Yes")
+        .item("No")
+        .default(if default { 0 } else { 1 })
+        .with_prompt(question)
+        .report(true)
+        .clear(true)
+        .interact()?;
+    Ok(answer == 0)
+}
@@ -1,4 +1,7 @@
+use std::fs::File;
 use std::path::PathBuf;
+use std::process::{Child, ExitStatus};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 
@@ -6,32 +9,79 @@ use crate::cli::RunOptions;
 use crate::error::{Error::RunnerError, RunnerError::*};
 use crate::{log, log::LogLevel};
 
-pub fn run(compiled: &PathBuf, opt: RunOptions) -> Result<()> {
+/// How often a program with a timeout is checked on.
+const POLL: Duration = Duration::from_millis(10);
+
+/// Run `compiled` with the program's arguments, returning its exit code. One
+/// killed by a signal exits with 128 plus its number, as shells report it.
+pub fn run(compiled: &PathBuf, opt: RunOptions) -> Result<i32> {
     log::log(
         log::LogLevel::Info,
         format!("Running {:?}", compiled).replace("\"", ""),
         false,
     );
-    let mut run_cmd = std::process::Command::new(compiled);
-    run_cmd.args(&opt.run_args);
+    let mut run_cmd = match opt.target.runtime() {
+        Some(runtime) => {
+            let mut cmd = std::process::Command::new(runtime);
+            cmd.arg(compiled);
+            cmd
+        }
+        None => std::process::Command::new(compiled),
+    };
+    run_cmd.args(&opt.run_args).envs(opt.env.iter().cloned());
+    if let Some(dir) = &opt.cwd {
+        run_cmd.current_dir(dir);
+    }
+    if let Some(path) = &opt.stdin {
+        let file = File::open(path)
+            .map_err(|e| RunnerError(InvokeError(e)))
+            .with_context(|| format!("Could not open {} for stdin", path.to_string_lossy()))?;
+        run_cmd.stdin(file);
+    }
     log::log(
         LogLevel::Cmd,
         format!("{:?}\n", run_cmd).replace("\"", ""),
         false,
     );
-    let run = run_cmd
+    let mut child = run_cmd
         .spawn()
         .map_err(|e| RunnerError(InvokeError(e)))
-        .with_context(|| format!("Failed to spawn run process for {:?}", compiled))?
-        .wait_with_output()
-        .map_err(|e| RunnerError(InvokeError(e)))
-        .with_context(|| format!("Failed to wait for {:?} process to complete", compiled))?;
+        .with_context(|| format!("Failed to spawn run process for {:?}", compiled))?;
+    let status = wait(&mut child, opt.timeout);
+    remove(compiled, &opt);
+    let status = status?;
+    Ok(exit_code(status))
+}
 
-    if run.status.code().unwrap_or(0) != 0 {
-        return Err(RunnerError(NonZeroStatus(run.status.code().unwrap_or(0) as usize)).into());
+/// Wait for `child` to exit, killing it once `timeout` has passed.
+fn wait(child: &mut Child, timeout: Option<Duration>) -> Result<ExitStatus> {
+    let start = Instant::now();
+    loop {
+        let status = match timeout {
+            Some(_) => child.try_wait(),
+            None => child.wait().map(Some),
+        }
+        .map_err(|e| RunnerError(InvokeError(e)))
+        .with_context(|| "Failed to wait for the program to complete")?;
+        if let Some(status) = status {
+            return Ok(status);
+        }
+        if let Some(timeout) = timeout.filter(|&timeout| start.elapsed() > timeout) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(RunnerError(TimeoutExceeded))
+                .with_context(|| format!("Program timed out after {:?}", timeout));
+        }
+        std::thread::sleep(POLL);
     }
+}
 
-    // Delete executable
+/// Delete the executable once it's run, unless it's to be kept. Built where -o
+/// says, it's kept like a build would be.
+fn remove(compiled: &PathBuf, opt: &RunOptions) {
+    if opt.keep_exe || opt.output.is_some() {
+        return;
+    }
     if let Err(e) = std::fs::remove_file(compiled) {
         log::log(
             LogLevel::Warn,
@@ -39,5 +89,17 @@ pub fn run(compiled: &PathBuf, opt: RunOptions) -> Result<()> {
             false,
         );
     }
-    Ok(())
+}
+
+#[cfg(unix)]
+fn exit_code(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+#[cfg(not(unix))]
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
 }
@@ -0,0 +1,123 @@
+//! SARIF 2.1.0 logs for `--message-format=sarif`, which GitHub code scanning
+//! and other tools can read: the warnings `worthc lint` found, or the error
+//! worthc failed with.
+
+use std::path::Path;
+
+use crate::{
+    error::{summary, Summary},
+    json::Json,
+};
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+/// Something a log reports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub rule: String,
+    /// `error` or `warning`
+    pub level: &'static str,
+    pub message: String,
+    pub loc: Option<(String, usize, usize)>,
+}
+
+impl Finding {
+    /// `err` as an error, its code the rule it broke.
+    pub fn error(err: &anyhow::Error) -> Finding {
+        let Summary {
+            code, message, loc, ..
+        } = summary(err);
+        Finding {
+            rule: code.unwrap_or_else(|| "error".to_string()),
+            level: "error",
+            message,
+            loc: loc.cloned(),
+        }
+    }
+}
+
+/// Where a location in `file` is, for a log about `program`. Locations only
+/// have a file's name, so one that isn't `program`'s is taken to be next to
+/// it, which is where most includes are.
+fn uri(file: &str, program: Option<&Path>) -> String {
+    let path = match program {
+        Some(program) if program.file_name().is_some_and(|name| name == file) => {
+            program.to_path_buf()
+        }
+        Some(program) => program.with_file_name(file),
+        None => file.into(),
+    };
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// A log of `findings` about `program`, with each of `rules` described.
+pub fn log(findings: &[Finding], rules: &[(String, String)], program: Option<&Path>) -> Json {
+    let rules = rules
+        .iter()
+        .map(|(id, description)| {
+            Json::object([
+                ("id", id.as_str().into()),
+                (
+                    "shortDescription",
+                    Json::object([("text", description.as_str().into())]),
+                ),
+            ])
+        })
+        .collect::<Vec<_>>();
+    let results = findings
+        .iter()
+        .map(|finding| {
+            let mut result = vec![
+                ("ruleId", finding.rule.as_str().into()),
+                ("level", finding.level.into()),
+                (
+                    "message",
+                    Json::object([("text", finding.message.as_str().into())]),
+                ),
+            ];
+            if let Some((file, line, col)) = &finding.loc {
+                let location = Json::object([(
+                    "physicalLocation",
+                    Json::object([
+                        (
+                            "artifactLocation",
+                            Json::object([("uri", uri(file, program).into())]),
+                        ),
+                        (
+                            "region",
+                            // Columns count from 1 here
+                            Json::object([
+                                ("startLine", (*line).into()),
+                                ("startColumn", (col + 1).into()),
+                            ]),
+                        ),
+                    ]),
+                )]);
+                result.push(("locations", vec![location].into()));
+            }
+            Json::object(result)
+        })
+        .collect::<Vec<_>>();
+    let driver = Json::object([
+        ("name", "worthc".into()),
+        ("version", env!("CARGO_PKG_VERSION").into()),
+        ("rules", rules.into()),
+    ]);
+    Json::object([
+        ("$schema", SCHEMA.into()),
+        ("version", "2.1.0".into()),
+        (
+            "runs",
+            vec![Json::object([
+                ("tool", Json::object([("driver", driver)])),
+                ("results", results.into()),
+            ])]
+            .into(),
+        ),
+    ])
+}
+
+/// A log of the error `err`, which happened to `program`.
+pub fn error_log(err: &anyhow::Error, program: Option<&Path>) -> Json {
+    log(&[Finding::error(err)], &[], program)
+}
@@ -1,18 +1,80 @@
-use std::io::{self, BufRead, BufReader, Write};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
 
-use crate::error::{Error::RuntimeError, RuntimeError::*};
+use crate::error::{err_loc, Diagnostic, Error::RuntimeError, RuntimeError::*};
 use crate::log::{self, LogLevel::*};
-use crate::{cli::SimulatorOptions, codegen::intrinsics::Intrinsic, instruction::*};
+use crate::{
+    cli::SimulatorOptions,
+    codegen::{intrinsics::Intrinsic, BSS_CAPACITY},
+    instruction::*,
+};
 use anyhow::{Context, Result};
 
+mod bytecode;
+pub mod dap;
+mod debugger;
+mod fds;
+mod heap;
+mod history;
+mod limits;
+mod net;
+mod process;
+mod profile;
+pub mod repl;
+mod sandbox;
+mod syscalls;
+mod trace;
+mod uninit;
+
+use bytecode::Bytecode;
+use debugger::{Breakpoint, Debugger};
+use fds::{Fd, FdTable};
+use heap::Heap;
+use history::History;
+use limits::Limits;
+use process::Processes;
+use profile::Profile;
+pub use sandbox::Sandbox;
+pub use syscalls::Deterministic;
+use trace::Trace;
+use uninit::Uninit;
+
 pub struct BinaryIO {
     pub reader: Option<Box<dyn BufRead>>,
     pub writer: Option<Box<dyn Write>>,
+    /// Set for files opened by the program, which are read and written unbuffered
+    pub file: Option<File>,
+    /// The status flags reported by fcntl(F_GETFL)
+    pub flags: i64,
+    pub socket: Option<net::Socket>,
 }
 
 impl BinaryIO {
     pub fn new(reader: Option<Box<dyn BufRead>>, writer: Option<Box<dyn Write>>) -> Self {
-        Self { reader, writer }
+        // O_RDONLY, O_WRONLY or O_RDWR
+        let flags = match (&reader, &writer) {
+            (Some(_), Some(_)) => 2,
+            (None, Some(_)) => 1,
+            _ => 0,
+        };
+        Self {
+            reader,
+            writer,
+            file: None,
+            flags,
+            socket: None,
+        }
+    }
+
+    pub fn file(file: File, flags: i64) -> Self {
+        Self {
+            reader: None,
+            writer: None,
+            file: Some(file),
+            flags,
+            socket: None,
+        }
     }
 
     pub fn stdio() -> Vec<Self> {
@@ -22,27 +84,370 @@ impl BinaryIO {
             Self::new(None, Some(Box::new(io::stderr()))),
         ]
     }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match (&mut self.file, &mut self.reader) {
+            (Some(file), _) => Ok(file.read(buf)?),
+            (None, Some(reader)) => Ok(reader.read(buf)?),
+            (None, None) => Err(RuntimeError(IOError)).context("Not opened for reading"),
+        }
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        match (&mut self.file, &mut self.writer) {
+            (Some(file), _) => Ok(file.write_all(buf)?),
+            (None, Some(writer)) => {
+                writer.write_all(buf)?;
+                Ok(writer.flush()?)
+            }
+            (None, None) => Err(RuntimeError(IOError)).context("Not opened for writing"),
+        }
+    }
 }
 
 const STR_CAPACITY: usize = 640_000;
 const ARGV_CAPACITY: usize = 640_000;
-const BSS_CAPACITY: usize = 640_000;
-const NULL_PTR_PADDING: usize = 1;
-const STR_BUF_PTR: usize = NULL_PTR_PADDING;
-const ARGV_BUF_PTR: usize = NULL_PTR_PADDING + STR_CAPACITY;
-const MEM_BUF_PTR: usize = NULL_PTR_PADDING + STR_CAPACITY + ARGV_CAPACITY;
-const MEM_LIMIT: usize = NULL_PTR_PADDING + STR_CAPACITY + ARGV_CAPACITY + BSS_CAPACITY;
+/// Keeps the regions after it aligned like mem is in a compiled program
+const NULL_PTR_PADDING: usize = MEM_ALIGN;
+pub const STR_BUF_PTR: usize = NULL_PTR_PADDING;
+pub const ARGV_BUF_PTR: usize = NULL_PTR_PADDING + STR_CAPACITY;
+/// mem is last, so `--mem-capacity` only moves where the heap starts
+pub const MEM_BUF_PTR: usize = NULL_PTR_PADDING + STR_CAPACITY + ARGV_CAPACITY;
+
+/// Which layout region `addr` is in, ordered like the regions are, with the
+/// heap starting at `mem_end`.
+fn region_index(addr: usize, mem_end: usize) -> usize {
+    [STR_BUF_PTR, ARGV_BUF_PTR, MEM_BUF_PTR, mem_end]
+        .iter()
+        .take_while(|&&start| addr >= start)
+        .count()
+}
+
+/// The name of the memory region `addr` falls in, for error messages.
+fn region_name(addr: usize, mem_end: usize) -> &'static str {
+    ["null", "str", "argv", "mem", "heap"][region_index(addr, mem_end)]
+}
+
+/// Check that all `width` bytes at `addr` are in memory and inside a single region,
+/// which they would have to be in a compiled program, and return it as an index.
+#[inline]
+fn check_access(
+    memory: &[u8],
+    mem_end: usize,
+    addr: i64,
+    width: usize,
+    access: &str,
+    ip: usize,
+) -> Result<usize> {
+    match usize::try_from(addr) {
+        Ok(start)
+            if start >= STR_BUF_PTR
+                && start
+                    .checked_add(width)
+                    .is_some_and(|end| end <= memory.len())
+                && (width <= 1
+                    || region_index(start, mem_end)
+                        == region_index(start + width - 1, mem_end)) =>
+        {
+            Ok(start)
+        }
+        _ => invalid_access(memory, mem_end, addr, width, access, ip),
+    }
+}
+
+/// The error for `inst` dividing by zero, where it is in the source.
+#[cold]
+fn division_by_zero(inst: &Instruction) -> Result<()> {
+    Err(RuntimeError(DivisionByZero)).with_context(|| {
+        Diagnostic::at(
+            &inst.loc,
+            format!(
+                "Division by zero: {} divides by 0\n\nat {}",
+                inst.kind,
+                err_loc(&inst.loc)
+            ),
+        )
+    })
+}
+
+#[cold]
+fn invalid_access(
+    memory: &[u8],
+    mem_end: usize,
+    addr: i64,
+    width: usize,
+    access: &str,
+    ip: usize,
+) -> Result<usize> {
+    let msg = match usize::try_from(addr) {
+        Err(_) => format!("negative address {}", addr),
+        Ok(start) if start < STR_BUF_PTR => format!("null pointer ({:x})", start),
+        Ok(start)
+            if start
+                .checked_add(width)
+                .is_none_or(|end| end > memory.len()) =>
+        {
+            format!(
+                "{} bytes at {:x} go past the end of the {} region ({:x})",
+                width,
+                start,
+                region_name(start, mem_end),
+                memory.len()
+            )
+        }
+        Ok(start) => format!(
+            "{} bytes at {:x} cross from the {} region into the {} region",
+            width,
+            start,
+            region_name(start, mem_end),
+            region_name(start + width - 1, mem_end)
+        ),
+    };
+    Err(RuntimeError(InvalidMemoryAccess))
+        .with_context(|| format!("Invalid memory {} at {}: {}", access, ip, msg))
+}
 
 pub struct SimulationState {
     pub stack: Vec<i64>,
     pub memory: Vec<u8>,
-    pub fds: Vec<BinaryIO>,
+    /// Where the declared memories start, right after mem
+    pub memories: usize,
+    /// Where mem and the memories end and the heap starts
+    pub mem_end: usize,
+    pub fds: FdTable,
     pub argc: usize,
     pub str_allocated: usize,
+    /// Where each string literal lives in the str region, by its contents, so
+    /// identical ones share an address like they do built
+    pub strings: HashMap<String, usize>,
     pub ip: usize,
+    pub heap: Heap,
+    /// Set by `--deterministic`
+    pub deterministic: Option<Deterministic>,
+    /// Set by `--sandbox`
+    pub sandbox: Option<Sandbox>,
+    pub processes: Processes,
+    /// Set when the program exits, sim_instruction doesn't advance past that
+    pub exit_code: Option<i32>,
 }
 
-pub fn simulate(program: &Program, mut opt: SimulatorOptions) -> Result<()> {
+impl SimulationState {
+    /// A simulation of `program` about to start, with `argv` (including argv\[0\])
+    /// as its arguments and the host's stdio.
+    pub fn new(program: &[Instruction], argv: &[String]) -> Result<Self> {
+        let memories = MEM_BUF_PTR + BSS_CAPACITY.next_multiple_of(MEM_ALIGN);
+        let memories_len = program
+            .iter()
+            .filter_map(|inst| match &inst.kind {
+                InstructionKind::Memory(memory) => Some(memory.offset + memory.reserved()),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let mem_end = memories + memories_len;
+        let mut state = SimulationState {
+            stack: Vec::new(),
+            memory: vec![0; mem_end],
+            memories,
+            mem_end,
+            fds: FdTable::stdio(),
+            argc: 0,
+            str_allocated: 0,
+            strings: HashMap::new(),
+            ip: 0,
+            heap: Heap::new(mem_end),
+            deterministic: None,
+            sandbox: None,
+            processes: Processes::default(),
+            exit_code: None,
+        };
+
+        // Allocate strings and push arguments (char** argv) onto the stack
+        for arg in argv {
+            if (state.argc + 1) * 8 > ARGV_CAPACITY {
+                return Err(RuntimeError(BufferOverflow)).with_context(|| {
+                    format!(
+                        "Argv buffer overflow: {} > {}",
+                        (state.argc + 1) * 8,
+                        ARGV_CAPACITY
+                    )
+                });
+            }
+            let arg_ptr = state.alloc_str(arg)?;
+            let argv_ptr = ARGV_BUF_PTR + (state.argc * 8);
+            // copy argv_ptr to bss[argv_ptr..argv_ptr + 8], little-endian like x86_64
+            state.memory[argv_ptr..argv_ptr + 8].copy_from_slice(&(arg_ptr as u64).to_le_bytes());
+            state.argc += 1;
+        }
+
+        state.intern_strings(program)?;
+        Ok(state)
+    }
+
+    /// Give mem `mem_capacity` bytes instead, before anything runs.
+    pub fn with_mem_capacity(mut self, mem_capacity: usize) -> Self {
+        let memories_len = self.mem_end - self.memories;
+        self.memories = MEM_BUF_PTR + mem_capacity.next_multiple_of(MEM_ALIGN);
+        self.mem_end = self.memories + memories_len;
+        self.memory.resize(self.mem_end, 0);
+        self.heap = Heap::new(self.mem_end);
+        self
+    }
+
+    /// Leave room for `len` bytes of memories, before anything runs, for
+    /// programs that declare more as they go.
+    pub fn with_memories_capacity(mut self, len: usize) -> Self {
+        self.mem_end = self.memories + len.max(self.mem_end - self.memories);
+        self.memory.resize(self.mem_end, 0);
+        self.heap = Heap::new(self.mem_end);
+        self
+    }
+
+    /// Replace stdin, stdout and stderr, for example with in-memory buffers.
+    pub fn with_stdio(
+        mut self,
+        stdin: Box<dyn BufRead>,
+        stdout: Box<dyn Write>,
+        stderr: Box<dyn Write>,
+    ) -> Self {
+        self.fds
+            .insert_at(0, Fd::new(BinaryIO::new(Some(stdin), None)));
+        self.fds
+            .insert_at(1, Fd::new(BinaryIO::new(None, Some(stdout))));
+        self.fds
+            .insert_at(2, Fd::new(BinaryIO::new(None, Some(stderr))));
+        self
+    }
+
+    /// Give the program `env` as its environment, as `KEY=VAL` strings pointed
+    /// to after argv's null like on the stack of a real process.
+    pub fn with_env(mut self, env: &[(String, String)]) -> Result<Self> {
+        for (i, (key, value)) in env.iter().enumerate() {
+            let slot = ARGV_BUF_PTR + (self.argc + 1 + i) * 8;
+            // The one after it stays null, ending envp
+            if slot + 16 > ARGV_BUF_PTR + ARGV_CAPACITY {
+                return Err(RuntimeError(BufferOverflow)).with_context(|| {
+                    format!("Argv buffer overflow: {} > {}", slot, ARGV_CAPACITY)
+                });
+            }
+            let ptr = self.alloc_str(&format!("{}={}", key, value))?;
+            self.memory[slot..slot + 8].copy_from_slice(&(ptr as u64).to_le_bytes());
+        }
+        Ok(self)
+    }
+
+    /// Run up to `n` more instructions. Returns the exit code once the program has
+    /// exited or run off its end, and `None` while it's still going.
+    pub fn step(&mut self, program: &[Instruction], n: usize) -> Result<Option<i32>> {
+        for _ in 0..n {
+            if self.exit_code.is_some() || self.ip >= program.len() {
+                break;
+            }
+            sim_instruction(&program[self.ip], self)?;
+        }
+        Ok(self.exit_status(program))
+    }
+
+    /// The exit code if the program is done, `None` while it's still running.
+    pub fn exit_status(&self, program: &[Instruction]) -> Option<i32> {
+        match self.exit_code {
+            Some(code) => Some(code),
+            None if self.ip >= program.len() => Some(0),
+            None => None,
+        }
+    }
+
+    /// A copy of the state, without the process table. Open files are shared.
+    fn snapshot(&self) -> Self {
+        Self {
+            stack: self.stack.clone(),
+            memory: self.memory.clone(),
+            memories: self.memories,
+            mem_end: self.mem_end,
+            fds: self.fds.clone(),
+            argc: self.argc,
+            str_allocated: self.str_allocated,
+            strings: self.strings.clone(),
+            ip: self.ip,
+            heap: self.heap.clone(),
+            deterministic: self.deterministic.clone(),
+            sandbox: self.sandbox,
+            processes: Processes::default(),
+            exit_code: None,
+        }
+    }
+
+    /// Copy a string literal into the str region, NUL-terminated, returning its address.
+    fn alloc_str(&mut self, s: &str) -> Result<usize> {
+        let ptr = STR_BUF_PTR + self.str_allocated;
+        if ptr + s.len() + 1 > ARGV_BUF_PTR {
+            return Err(RuntimeError(StringCapacityExceeded))
+                .with_context(|| format!("String capacity exceeded: {} > {}", ptr, STR_CAPACITY));
+        }
+        self.memory[ptr..ptr + s.len()].copy_from_slice(s.as_bytes());
+        self.str_allocated += s.len() + 1;
+        Ok(ptr)
+    }
+
+    /// The address of the string literal `s`, copying it in the first time.
+    fn intern_str(&mut self, s: &str) -> Result<usize> {
+        if let Some(&ptr) = self.strings.get(s) {
+            return Ok(ptr);
+        }
+        let ptr = self.alloc_str(s)?;
+        self.strings.insert(s.to_string(), ptr);
+        Ok(ptr)
+    }
+
+    /// Give every string literal in the program its one copy up front, like the
+    /// data segment of a compiled program.
+    fn intern_strings(&mut self, program: &[Instruction]) -> Result<()> {
+        for inst in program {
+            if let InstructionKind::Push(Value::Str(s)) = &inst.kind {
+                self.intern_str(s)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Go back to a snapshot, keeping the current process table.
+    fn restore(&mut self, snapshot: &Self) {
+        let processes = std::mem::take(&mut self.processes);
+        *self = snapshot.snapshot();
+        self.processes = processes;
+    }
+}
+
+/// Write `val` unsigned and a newline to fd 1, like the built programs do.
+/// Failures are ignored, like the generated code does.
+fn print(fds: &FdTable, val: i64) {
+    if let Some(mut io) = fds.get(1) {
+        let _ = io.write_all(format!("{}\n", val as u64).as_bytes());
+    }
+}
+
+/// Run the program to completion, returning its exit code.
+pub fn simulate(program: &Program, mut opt: SimulatorOptions) -> Result<i32> {
+    let Some(dir) = opt.cwd.take() else {
+        return simulate_here(program, opt);
+    };
+    // Opened from where worthc was started, like the rest of its paths
+    if let Some(stdin) = &mut opt.stdin {
+        *stdin = stdin
+            .canonicalize()
+            .with_context(|| format!("Could not find {}", stdin.to_string_lossy()))?;
+    }
+    let previous = std::env::current_dir().context("Could not find the current directory")?;
+    std::env::set_current_dir(&dir)
+        .with_context(|| format!("Could not run the program in {}", dir.to_string_lossy()))?;
+    let code = simulate_here(program, opt);
+    std::env::set_current_dir(&previous)
+        .with_context(|| format!("Could not go back to {}", previous.to_string_lossy()))?;
+    code
+}
+
+/// Simulate `program` in the current directory.
+fn simulate_here(program: &Program, opt: SimulatorOptions) -> Result<i32> {
     let mut debug = opt.debug;
     let Program {
         instructions: program,
@@ -51,105 +456,153 @@ pub fn simulate(program: &Program, mut opt: SimulatorOptions) -> Result<()> {
         ..
     } = program;
 
-    let mut state = SimulationState {
-        stack: Vec::new(),
-        memory: vec![0; MEM_LIMIT],
-        fds: BinaryIO::stdio(),
-        argc: 0,
-        str_allocated: 0,
-        ip: 0,
-    };
-
     let mut argv = opt.sim_args;
     argv.insert(
         0,
         base_path.join(program_name).to_str().unwrap().to_string(),
     );
+    // What a program run natively would get
+    let mut env: Vec<(String, String)> = std::env::vars_os()
+        .map(|(key, value)| {
+            let lossy = |s: std::ffi::OsString| s.to_string_lossy().into_owned();
+            (lossy(key), lossy(value))
+        })
+        .filter(|(key, _)| !opt.env.iter().any(|(set, _)| set == key))
+        .collect();
+    env.extend(opt.env.iter().cloned());
+    let mut state = SimulationState::new(program, &argv)?
+        .with_mem_capacity(opt.mem_capacity)
+        .with_env(&env)?;
+    if let Some(path) = &opt.stdin {
+        let file = File::open(path)
+            .with_context(|| format!("Could not open {} for stdin", path.to_string_lossy()))?;
+        // O_RDONLY
+        state.fds.insert_at(0, Fd::new(BinaryIO::file(file, 0)));
+    }
+    state.deterministic = opt.deterministic.map(Deterministic::new);
+    state.sandbox = opt.sandbox.then(|| Sandbox::new(&opt.allow));
 
-    // Allocate strings and push arguments (char** argv) onto the stack
-    for arg in argv.iter() {
-        let mut arg_bytes = arg.as_bytes().to_vec();
-        arg_bytes.push(0); // null-terminate
-        let len = arg_bytes.len();
-        let arg_ptr = STR_BUF_PTR + state.str_allocated;
-        state.memory[arg_ptr..arg_ptr + len].copy_from_slice(&arg_bytes);
-        state.str_allocated += len;
-
-        if arg_ptr > STR_CAPACITY {
-            return Err(RuntimeError(StringCapacityExceeded)).with_context(|| {
-                format!("String capacity exceeded: {} > {}", arg_ptr, STR_CAPACITY)
-            });
-        }
-
-        let argv_ptr = ARGV_BUF_PTR + (state.argc * 8);
-        // copy argv_ptr to bss[argv_ptr..argv_ptr + 8]
-        state.memory[argv_ptr] = (arg_ptr >> 56) as u8;
-        state.memory[argv_ptr + 1] = (arg_ptr >> 48) as u8;
-        state.memory[argv_ptr + 2] = (arg_ptr >> 40) as u8;
-        state.memory[argv_ptr + 3] = (arg_ptr >> 32) as u8;
-        state.memory[argv_ptr + 4] = (arg_ptr >> 24) as u8;
-        state.memory[argv_ptr + 5] = (arg_ptr >> 16) as u8;
-        state.memory[argv_ptr + 6] = (arg_ptr >> 8) as u8;
-        state.memory[argv_ptr + 7] = arg_ptr as u8;
-
-        state.argc += 1;
-
-        if state.argc * 8 > ARGV_CAPACITY {
-            return Err(RuntimeError(BufferOverflow)).with_context(|| {
+    let mut breakpoints = Vec::new();
+    for target in &opt.breakpoints {
+        let breakpoint = Breakpoint::parse(target)
+            .ok_or(RuntimeError(InvalidBreakpoint))
+            .with_context(|| {
                 format!(
-                    "Argv buffer overflow: {} > {}",
-                    state.argc * 8,
-                    ARGV_CAPACITY
+                    "Invalid breakpoint {}, expected <ip> or <file>:<line>",
+                    target
                 )
-            });
-        }
+            })?;
+        log::log(Info, format!("Breakpoint at {}", breakpoint), debug);
+        breakpoints.push(breakpoint);
     }
+    let debugging = opt.step || !breakpoints.is_empty();
+    let mut debugger = Debugger::new(breakpoints, opt.step, debug);
+    let every = if debugging { opt.snapshot_every } else { 0 };
+    let mut history = History::new(every, &state);
+    let mut trace = opt.trace.as_deref().map(Trace::create).transpose()?;
+    let mut profile = opt.profile.map(|top| Profile::new(program, top));
+    let mut limits = Limits::new(opt.max_steps, opt.timeout);
+    let mut uninit = opt.uninit.then(|| Uninit::new(&state.memory));
 
-    if let Some(breakpoint) = opt.breakpoint {
-        log::log(
-            Info,
-            format!("Breakpoint at instruction {}", breakpoint),
-            debug,
-        );
+    // Nothing needs to see each step, so take the fast path
+    if !debugging && !debug && trace.is_none() && profile.is_none() && uninit.is_none() {
+        Bytecode::lower(program, &state.strings).run(program, &mut state, &mut limits)?;
     }
 
-    while state.ip < program.len() {
-        if let Some(breakpoint) = opt.breakpoint {
-            if breakpoint == state.ip {
-                log::log(Info, format!("Breakpoint reached"), debug);
-                opt.step = true;
-            }
+    while state.ip < program.len() && state.exit_code.is_none() {
+        limits.step(state.ip)?;
+        if debugger.at_breakpoint(program, state.ip) {
+            log::log(Info, "Breakpoint reached".to_string(), debug);
+            debugger.stepping = true;
         }
         let inst = &program[state.ip];
-        sim_instruction(inst, &mut state)?;
+        if let Some(trace) = &mut trace {
+            trace.record(inst, state.stack.len())?;
+        }
+        if let Some(profile) = &mut profile {
+            profile.record(state.ip);
+        }
+        if let Some(uninit) = &mut uninit {
+            uninit.before(inst, &state);
+        }
+        history.step(inst, &mut state)?;
+        if let Some(uninit) = &mut uninit {
+            uninit.after(&state.memory);
+        }
+        if state.exit_code.is_some() {
+            break;
+        }
 
-        if opt.step || opt.debug {
-            println!("{}: {:?}", state.ip, inst);
+        if debugger.check_watchpoints(&state.memory) {
+            debugger.stepping = true;
+        }
+        if debugger.stepping || debugger.trace {
+            println!("{}: {:?}", inst.ip, inst);
             println!("Stack: {:?}", state.stack);
         }
-        if opt.step {
-            let mut cmd = String::new();
-            std::io::stdin().read_line(&mut cmd).unwrap();
-            match cmd.trim() {
-                "c" => opt.step = false,
-                "d" => debug = !debug,
-                "q" => break,
-                _ => {}
+        while debugger.stepping {
+            debugger.prompt(program, &state);
+            let Some(n) = debugger.back.take() else {
+                break;
+            };
+            if history.back(program, &mut state, n)? {
+                debugger.reset_watchpoints(&state.memory);
+                println!("Back at step {}, next {}", history.steps, state.ip);
+                println!("Stack: {:?}", state.stack);
+            } else if every == 0 {
+                println!("Snapshots are disabled, see --snapshot-every");
+            } else {
+                println!("Can't step back {}, there's no snapshot that far back", n);
             }
         }
+        debug = debugger.trace;
+        if debugger.quit {
+            break;
+        }
+    }
+    if let Some(profile) = &profile {
+        profile.report(program);
+    }
+    match state.exit_code {
+        Some(code) => {
+            log::log(Debug, format!("Sim exited with code {}", code), debug);
+            Ok(code)
+        }
+        None => {
+            log::log(Debug, "Sim exited successfully".into(), debug);
+            Ok(0)
+        }
     }
-    log::log(Debug, "Sim exited successfully".into(), debug);
-    Ok(())
 }
 
 pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Result<()> {
+    if let InstructionKind::Syscall(kind) = &inst.kind {
+        if sandbox::syscall(state, kind) || process::syscall(state, kind)? {
+            return Ok(());
+        }
+    }
+    if let InstructionKind::Push(Value::Str(s)) = &inst.kind {
+        let ptr = state.intern_str(s)?;
+        state.stack.push(s.len() as i64);
+        state.stack.push(ptr as i64);
+        state.ip += 1;
+        return Ok(());
+    }
     let SimulationState {
         stack,
         memory: bss,
+        memories,
+        mem_end,
         fds,
         argc,
-        str_allocated,
+        str_allocated: _,
+        strings: _,
         ip,
+        heap,
+        deterministic,
+        sandbox: _,
+        processes: _,
+        exit_code,
     } = state;
     macro_rules! pop {
         () => {
@@ -165,52 +618,119 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
             Value::Int(i) => stack.push(*i),
             Value::Char(c) => stack.push((*c) as i64),
             Value::Bool(b) => stack.push(*b as i64),
-            Value::Str(s) => {
-                let len = s.as_bytes().len();
-                stack.push(len as i64);
-                let str_buf_end = STR_BUF_PTR + *str_allocated;
-                stack.push(str_buf_end as i64);
-                bss[str_buf_end..str_buf_end + len].copy_from_slice(s.as_bytes());
-                *str_allocated += len + 1;
-                if str_buf_end > STR_CAPACITY {
-                    return Err(RuntimeError(StringCapacityExceeded)).with_context(|| {
-                        format!(
-                            "String capacity exceeded: {} > {}",
-                            str_buf_end, STR_CAPACITY
-                        )
-                    });
-                }
+            Value::Str(_) => unreachable!("String literals are pushed above"),
+            Value::Ptr(name) => {
+                return Err(RuntimeError(NameNotResolved)).with_context(|| {
+                    format!(
+                        "Encountered a pointer to {} at {}, which isn't resolved",
+                        name, ip
+                    )
+                });
             }
-            Value::Ptr(_name) => todo!(),
         },
         InstructionKind::Syscall(SyscallKind::Syscall0) => {
             let syscall = pop!();
             match syscall {
-                number => todo!("Implement syscall0 {}", number),
+                // Getpid, and gettid since the simulator is single threaded
+                39 | 186 => stack.push(syscalls::getpid()),
+                102 => stack.push(syscalls::id("Uid:", 0)),
+                104 => stack.push(syscalls::id("Gid:", 0)),
+                107 => stack.push(syscalls::id("Uid:", 1)),
+                108 => stack.push(syscalls::id("Gid:", 1)),
+                110 => stack.push(syscalls::getppid()),
+                number => stack.push(syscalls::unimplemented(0, number)),
             }
         }
         InstructionKind::Syscall(SyscallKind::Syscall1) => {
             let syscall = pop!();
             let arg1 = pop!();
             match syscall {
-                60 => {
-                    // Exit
-                    std::process::exit(arg1 as i32);
+                60 | 231 => {
+                    // Exit, and exit_group since the simulator is single threaded
+                    *exit_code = Some(arg1 as i32);
+                    return Ok(());
                 }
                 3 => {
                     // Close
-                    fds.remove(arg1 as usize);
+                    stack.push(syscalls::close(fds, arg1));
+                }
+                22 => {
+                    // Pipe
+                    stack.push(syscalls::pipe(fds, bss, arg1, 0)?);
+                }
+                32 => {
+                    // Dup
+                    stack.push(syscalls::dup(fds, arg1));
                 }
-                number => todo!("Implement syscall1 {}", number),
+                12 => {
+                    // Brk
+                    stack.push(heap.brk(bss, arg1));
+                }
+                87 => {
+                    // Unlink
+                    stack.push(syscalls::unlink(bss, arg1)?);
+                }
+                number => stack.push(syscalls::unimplemented(1, number)),
             }
         }
-        #[allow(unused_variables)]
         InstructionKind::Syscall(SyscallKind::Syscall2) => {
             let syscall = pop!();
             let arg1 = pop!();
             let arg2 = pop!();
             match syscall {
-                number => todo!("Implement syscall2 {}", number),
+                11 => {
+                    // Munmap
+                    stack.push(heap.munmap(bss, arg1, arg2));
+                }
+                2 => {
+                    // Open without a mode
+                    stack.push(syscalls::open(fds, bss, arg1, arg2, 0)?);
+                }
+                4 => {
+                    // Stat
+                    stack.push(syscalls::stat(bss, arg1, arg2)?);
+                }
+                5 => {
+                    // Fstat
+                    stack.push(syscalls::fstat(fds, bss, arg1, arg2)?);
+                }
+                6 => {
+                    // Lstat
+                    stack.push(syscalls::lstat(bss, arg1, arg2)?);
+                }
+                35 => {
+                    // Nanosleep
+                    stack.push(syscalls::nanosleep(bss, deterministic, arg1, arg2)?);
+                }
+                96 => {
+                    // Gettimeofday
+                    stack.push(syscalls::gettimeofday(bss, deterministic, arg1, arg2)?);
+                }
+                228 => {
+                    // Clock_gettime
+                    stack.push(syscalls::clock_gettime(bss, deterministic, arg1, arg2)?);
+                }
+                33 => {
+                    // Dup2
+                    stack.push(syscalls::dup3(fds, arg1, arg2, None));
+                }
+                72 => {
+                    // Fcntl without an argument
+                    stack.push(syscalls::fcntl(fds, arg1, arg2, 0));
+                }
+                48 => {
+                    // Shutdown
+                    stack.push(net::shutdown(fds, arg1, arg2));
+                }
+                50 => {
+                    // Listen, the backlog is up to the host
+                    stack.push(net::listen(fds, arg1));
+                }
+                293 => {
+                    // Pipe2
+                    stack.push(syscalls::pipe(fds, bss, arg1, arg2)?);
+                }
+                number => stack.push(syscalls::unimplemented(2, number)),
             }
         }
         InstructionKind::Syscall(SyscallKind::Syscall3) => {
@@ -222,16 +742,13 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
                 0 => {
                     // Read
                     let fd = arg1 as usize;
-                    let buf = arg2 as usize;
                     let count = arg3 as usize;
-                    //let mut tmp_buf = String::new();
+                    let buf = check_access(bss, *mem_end, arg2, count, "write", *ip)?;
                     let buf = &mut bss[buf..buf + count];
-                    let bytes_read = fds[fd]
-                        .reader
-                        .as_mut()
-                        .with_context(|| {
-                            format!("File descriptor {} is not opened for reading", fd)
-                        })?
+                    let bytes_read = fds
+                        .get(arg1)
+                        .ok_or(RuntimeError(IOError))
+                        .with_context(|| format!("File descriptor {} is not open", fd))?
                         .read(buf)
                         .with_context(|| format!("Failed to read from file descriptor {}", fd))?;
                     stack.push(bytes_read as i64);
@@ -239,34 +756,63 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
                 1 => {
                     // Write
                     let fd = arg1 as usize;
-                    let buf = arg2 as usize;
                     let count = arg3 as usize;
+                    let buf = check_access(bss, *mem_end, arg2, count, "read", *ip)?;
                     let buf = &bss[buf..buf + count];
-                    fds[fd]
-                        .writer
-                        .as_mut()
+                    fds.get(arg1)
                         .ok_or(RuntimeError(IOError))
-                        .with_context(|| {
-                            format!("File descriptor {} is not opened for writing", fd)
-                        })?
+                        .with_context(|| format!("File descriptor {} is not open", fd))?
                         .write_all(buf)
                         .with_context(|| format!("Failed to write to file descriptor {}", fd))?;
-                    fds[fd]
-                        .writer
-                        .as_mut()
-                        .with_context(|| {
-                            format!("File descriptor {} is not opened for writing", fd)
-                        })?
-                        .flush()
-                        .with_context(|| {
-                            format!("Failed to flush writer for file descriptor {}", fd)
-                        })?;
                     stack.push(count as i64);
                 }
-                number => todo!("Implement syscall3 {}", number),
+                2 => {
+                    // Open
+                    stack.push(syscalls::open(fds, bss, arg1, arg2, arg3)?);
+                }
+                8 => {
+                    // Lseek
+                    stack.push(syscalls::lseek(fds, arg1, arg2, arg3));
+                }
+                72 => {
+                    // Fcntl
+                    stack.push(syscalls::fcntl(fds, arg1, arg2, arg3));
+                }
+                41 => {
+                    // Socket
+                    stack.push(net::socket(fds, arg1, arg2, arg3));
+                }
+                42 => {
+                    // Connect
+                    stack.push(net::connect(fds, bss, arg1, arg2, arg3)?);
+                }
+                43 => {
+                    // Accept
+                    stack.push(net::accept(fds, bss, arg1, arg2, arg3)?);
+                }
+                49 => {
+                    // Bind
+                    stack.push(net::bind(fds, bss, arg1, arg2, arg3)?);
+                }
+                51 => {
+                    // Getsockname
+                    stack.push(net::getsockname(fds, bss, arg1, arg2, arg3)?);
+                }
+                292 => {
+                    // Dup3
+                    stack.push(syscalls::dup3(fds, arg1, arg2, Some(arg3)));
+                }
+                257 => {
+                    // Openat without a mode
+                    stack.push(syscalls::openat(fds, bss, arg1, arg2, arg3, 0)?);
+                }
+                318 => {
+                    // Getrandom, always from the non-blocking pool
+                    stack.push(syscalls::getrandom(bss, deterministic, arg1, arg2)?);
+                }
+                number => stack.push(syscalls::unimplemented(3, number)),
             }
         }
-        #[allow(unused_variables)]
         InstructionKind::Syscall(SyscallKind::Syscall4) => {
             let syscall = pop!();
             let arg1 = pop!();
@@ -274,7 +820,34 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
             let arg3 = pop!();
             let arg4 = pop!();
             match syscall {
-                number => todo!("Implement syscall4 {}", number),
+                44 => {
+                    // Send
+                    stack.push(net::transfer(fds, bss, arg1, arg2, arg3, true)?);
+                }
+                45 => {
+                    // Recv
+                    stack.push(net::transfer(fds, bss, arg1, arg2, arg3, false)?);
+                }
+                288 => {
+                    // Accept4, the flags only matter for blocking and exec
+                    stack.push(net::accept(fds, bss, arg1, arg2, arg3)?);
+                }
+                230 => {
+                    // Clock_nanosleep
+                    stack.push(syscalls::clock_nanosleep(
+                        bss,
+                        deterministic,
+                        arg1,
+                        arg2,
+                        arg3,
+                        arg4,
+                    )?);
+                }
+                257 => {
+                    // Openat
+                    stack.push(syscalls::openat(fds, bss, arg1, arg2, arg3, arg4)?);
+                }
+                number => stack.push(syscalls::unimplemented(4, number)),
             }
         }
         #[allow(unused_variables)]
@@ -286,7 +859,11 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
             let arg4 = pop!();
             let arg5 = pop!();
             match syscall {
-                number => todo!("Implement syscall5 {}", number),
+                54 => {
+                    // Setsockopt
+                    stack.push(net::setsockopt(fds, arg1));
+                }
+                number => stack.push(syscalls::unimplemented(5, number)),
             }
         }
         #[allow(unused_variables)]
@@ -299,7 +876,19 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
             let arg5 = pop!();
             let arg6 = pop!();
             match syscall {
-                number => todo!("Implement syscall6 {}", number),
+                9 => {
+                    // Mmap, the address hint and protection are ignored
+                    stack.push(heap.mmap(bss, fds, arg2, arg4, arg5, arg6));
+                }
+                44 => {
+                    // Sendto, the address is ignored on connected sockets
+                    stack.push(net::transfer(fds, bss, arg1, arg2, arg3, true)?);
+                }
+                45 => {
+                    // Recvfrom, without reporting the peer address
+                    stack.push(net::transfer(fds, bss, arg1, arg2, arg3, false)?);
+                }
+                number => stack.push(syscalls::unimplemented(6, number)),
             }
         }
         InstructionKind::Keyword(Keyword::While { .. }) => {}
@@ -311,6 +900,7 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
             }
         }
         InstructionKind::Keyword(Keyword::If { .. }) => {}
+        InstructionKind::Keyword(Keyword::Unsafe) => {}
         InstructionKind::Keyword(Keyword::Elif {
             end_ip: else_ip, ..
         }) => {
@@ -328,10 +918,13 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
             }
         }
         InstructionKind::Intrinsic(intrinsic) => match intrinsic {
-            Intrinsic::Panic => std::process::exit(1),
+            Intrinsic::Panic => {
+                *exit_code = Some(1);
+                return Ok(());
+            }
             Intrinsic::Print => {
                 let a = pop!();
-                println!("{}", a);
+                print(fds, a);
             }
             Intrinsic::Dup => {
                 let a = pop!();
@@ -375,39 +968,65 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
             }
             Intrinsic::CastPtr => {}
             Intrinsic::CastInt => {}
+            Intrinsic::Align => {
+                let n = pop!();
+                let ptr = pop!();
+                if n <= 0 || n & (n - 1) != 0 {
+                    return Err(RuntimeError(InvalidAlignment)).with_context(|| {
+                        Diagnostic::at(
+                            &inst.loc,
+                            format!(
+                                "Invalid alignment: align needs a power of two, got {}\n\nat {}",
+                                n,
+                                err_loc(&inst.loc)
+                            ),
+                        )
+                    });
+                }
+                stack.push(ptr.wrapping_add(n - 1) & n.wrapping_neg());
+            }
             #[allow(unreachable_patterns)]
             intrinsic => todo!("Implement intrinsic {}", intrinsic),
         },
         InstructionKind::Op(Op::Add) => {
             let a = pop!();
             let b = pop!();
-            stack.push(a + b);
+            stack.push(a.wrapping_add(b));
         }
         InstructionKind::Op(Op::Sub) => {
             let a = pop!();
             let b = pop!();
-            stack.push(b - a);
+            stack.push(b.wrapping_sub(a));
         }
         InstructionKind::Op(Op::Mul) => {
             let a = pop!();
             let b = pop!();
-            stack.push(a * b);
+            stack.push(a.wrapping_mul(b));
         }
         InstructionKind::Op(Op::Div) => {
             let a = pop!();
             let b = pop!();
-            stack.push(b / a);
+            if a == 0 {
+                return division_by_zero(inst);
+            }
+            stack.push(b.wrapping_div(a));
         }
         InstructionKind::Op(Op::Mod) => {
             let a = pop!();
             let b = pop!();
-            stack.push(b % a);
+            if a == 0 {
+                return division_by_zero(inst);
+            }
+            stack.push(b.wrapping_rem(a));
         }
         InstructionKind::Op(Op::DivMod) => {
             let a = pop!();
             let b = pop!();
-            stack.push(b / a);
-            stack.push(b % a);
+            if a == 0 {
+                return division_by_zero(inst);
+            }
+            stack.push(b.wrapping_div(a));
+            stack.push(b.wrapping_rem(a));
         }
         InstructionKind::Op(Op::BitwiseAnd) => {
             let a = pop!();
@@ -431,12 +1050,12 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
         InstructionKind::Op(Op::Shl) => {
             let a = pop!();
             let b = pop!();
-            stack.push(b << a);
+            stack.push(b.wrapping_shl(a as u32));
         }
         InstructionKind::Op(Op::Shr) => {
             let a = pop!();
             let b = pop!();
-            stack.push(b >> a);
+            stack.push(b.wrapping_shr(a as u32));
         }
         InstructionKind::Op(Op::Eq) => {
             let a = pop!();
@@ -469,64 +1088,26 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
             stack.push((b >= a) as i64);
         }
         InstructionKind::Op(Op::Store) => {
-            let val = pop!() % 0xFF;
-            let addr = pop!();
-            if addr > MEM_LIMIT as i64 {
-                return Err(RuntimeError(InvalidMemoryAccess)).with_context(|| {
-                    format!("Invalid memory write: {:x} > {:x}", addr, MEM_LIMIT)
-                });
-            }
-            bss[addr as usize] = val as u8; // Take lower byte only
+            let val = pop!() & 0xFF;
+            let addr = check_access(bss, *mem_end, pop!(), 1, "write", *ip)?;
+            bss[addr] = val as u8; // Take lower byte only
         }
         InstructionKind::Op(Op::Load) => {
-            let addr = pop!();
-            if addr > MEM_LIMIT as i64 {
-                return Err(RuntimeError(InvalidMemoryAccess)).with_context(|| {
-                    format!(
-                        "Invalid memory read at {}: {:x} > {:x}",
-                        ip, addr, MEM_LIMIT
-                    )
-                });
-            }
-            stack.push(bss[addr as usize] as i64);
+            let addr = check_access(bss, *mem_end, pop!(), 1, "read", *ip)?;
+            stack.push(bss[addr] as i64);
         }
         InstructionKind::Op(Op::Store64) => {
             let val = pop!();
-            let addr = pop!();
-            if addr > MEM_LIMIT as i64 {
-                return Err(RuntimeError(InvalidMemoryAccess)).with_context(|| {
-                    format!("Invalid memory write: {:x} > {:x}", addr, MEM_LIMIT)
-                });
-            }
-            // Store 8 bytes of value to the address
-            bss[addr as usize] = (val >> 56) as u8;
-            bss[addr as usize + 1] = (val >> 48) as u8;
-            bss[addr as usize + 2] = (val >> 40) as u8;
-            bss[addr as usize + 3] = (val >> 32) as u8;
-            bss[addr as usize + 4] = (val >> 24) as u8;
-            bss[addr as usize + 5] = (val >> 16) as u8;
-            bss[addr as usize + 6] = (val >> 8) as u8;
-            bss[addr as usize + 7] = val as u8;
+            let addr = check_access(bss, *mem_end, pop!(), 8, "write", *ip)?;
+            // Store 8 bytes of value to the address, little-endian like x86_64
+            bss[addr..addr + 8].copy_from_slice(&val.to_le_bytes());
         }
         InstructionKind::Op(Op::Load64) => {
-            let addr = pop!();
-            if addr > MEM_LIMIT as i64 {
-                return Err(RuntimeError(InvalidMemoryAccess)).with_context(|| {
-                    format!(
-                        "Invalid memory read at {}: {:x} > {:x}",
-                        ip, addr, MEM_LIMIT
-                    )
-                });
-            }
+            let addr = check_access(bss, *mem_end, pop!(), 8, "read", *ip)?;
             // Read 8 bytes of value from the address
-            let val = (bss[addr as usize] as i64) << 56
-                | (bss[addr as usize + 1] as i64) << 48
-                | (bss[addr as usize + 2] as i64) << 40
-                | (bss[addr as usize + 3] as i64) << 32
-                | (bss[addr as usize + 4] as i64) << 24
-                | (bss[addr as usize + 5] as i64) << 16
-                | (bss[addr as usize + 6] as i64) << 8
-                | bss[addr as usize + 7] as i64;
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&bss[addr..addr + 8]);
+            let val = i64::from_le_bytes(bytes);
             stack.push(val);
         }
         InstructionKind::Keyword(Keyword::Macro) => {
@@ -537,6 +1118,17 @@ pub fn sim_instruction(inst: &Instruction, state: &mut SimulationState) -> Resul
             return Err(RuntimeError(NameNotResolved))
                 .with_context(|| format!("Encountered unresolved name at {}: {}", ip, name));
         }
+        InstructionKind::Memory(memory) => {
+            stack.push((*memories + memory.offset) as i64);
+        }
+        InstructionKind::Extern(declaration) => {
+            return Err(RuntimeError(ExternNotSimulated)).with_context(|| {
+                format!(
+                    "Encountered a call to {} at {}, compile with --link-libc to call it",
+                    declaration.name, ip
+                )
+            });
+        }
 
         #[allow(unreachable_patterns)]
         instruction => todo!("Implement instruction {:?}", instruction),
@@ -0,0 +1,293 @@
+//! A faster way to run programs when nothing needs to watch each step.
+//!
+//! Instructions are lowered to small `Copy` ops with jump targets resolved, one
+//! per instruction so ips stay the same. Anything that isn't a plain stack,
+//! arithmetic, memory or control flow op, like syscalls, is left to
+//! `sim_instruction`.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use super::limits::Limits;
+use super::{
+    check_access, division_by_zero, print, sim_instruction, SimulationState, ARGV_BUF_PTR,
+    MEM_BUF_PTR,
+};
+use crate::codegen::intrinsics::Intrinsic;
+use crate::error::{Error::RuntimeError, RuntimeError::*};
+use crate::instruction::{Instruction, InstructionKind, Keyword, Op as InstOp, Value};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Push(i64),
+    /// A string literal at its interned address
+    PushStr {
+        len: usize,
+        ptr: usize,
+    },
+    Nop,
+    Jump(usize),
+    /// `do`, which pops the condition
+    JumpIfZero(usize),
+    Mem,
+    Argc,
+    Argv,
+    Print,
+    Dup,
+    Swap,
+    Drop,
+    Over,
+    Drop2,
+    Dup2,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    DivMod,
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,
+    Shr,
+    Eq,
+    Neq,
+    Lt,
+    Gt,
+    Lte,
+    Gte,
+    Store,
+    Load,
+    Store64,
+    Load64,
+    /// Run the original instruction with `sim_instruction`
+    Slow,
+}
+
+pub struct Bytecode {
+    ops: Vec<Op>,
+}
+
+impl Bytecode {
+    pub fn lower(program: &[Instruction], strings: &HashMap<String, usize>) -> Self {
+        let mut ops: Vec<Op> = program.iter().map(|inst| lower(inst, strings)).collect();
+        // Jump straight past any no-ops at the target
+        for i in 0..ops.len() {
+            let target = match &mut ops[i] {
+                Op::Jump(target) | Op::JumpIfZero(target) => *target,
+                _ => continue,
+            };
+            let mut resolved = target;
+            while ops.get(resolved) == Some(&Op::Nop) {
+                resolved += 1;
+            }
+            if let Op::Jump(target) | Op::JumpIfZero(target) = &mut ops[i] {
+                *target = resolved;
+            }
+        }
+        Self { ops }
+    }
+
+    /// Run until the program finishes or exits.
+    pub fn run(
+        &self,
+        program: &[Instruction],
+        state: &mut SimulationState,
+        limits: &mut Limits,
+    ) -> Result<()> {
+        macro_rules! pop {
+            () => {
+                match state.stack.pop() {
+                    Some(val) => val,
+                    None => return underflow(state.ip),
+                }
+            };
+        }
+        macro_rules! binop {
+            (|$a:ident, $b:ident| $result:expr) => {{
+                let $a = pop!();
+                let $b = pop!();
+                let result = $result;
+                state.stack.push(result);
+            }};
+        }
+        while let Some(&op) = self.ops.get(state.ip) {
+            limits.step(state.ip)?;
+            match op {
+                Op::Push(val) => state.stack.push(val),
+                Op::PushStr { len, ptr } => state.stack.extend([len as i64, ptr as i64]),
+                Op::Nop => {}
+                Op::Jump(target) => {
+                    state.ip = target;
+                    continue;
+                }
+                Op::JumpIfZero(target) => {
+                    if pop!() == 0 {
+                        state.ip = target;
+                        continue;
+                    }
+                }
+                Op::Mem => state.stack.push(MEM_BUF_PTR as i64),
+                Op::Argc => state.stack.push(state.argc as i64),
+                Op::Argv => state.stack.push(ARGV_BUF_PTR as i64),
+                Op::Print => {
+                    let a = pop!();
+                    print(&state.fds, a);
+                }
+                Op::Dup => {
+                    let a = pop!();
+                    state.stack.extend([a, a]);
+                }
+                Op::Swap => binop!(|a, b| {
+                    state.stack.push(a);
+                    b
+                }),
+                Op::Drop => {
+                    state.stack.pop();
+                }
+                Op::Over => binop!(|a, b| {
+                    state.stack.extend([b, a]);
+                    b
+                }),
+                Op::Drop2 => {
+                    state.stack.pop();
+                    state.stack.pop();
+                }
+                Op::Dup2 => binop!(|a, b| {
+                    state.stack.extend([b, a, b]);
+                    a
+                }),
+                Op::Add => binop!(|a, b| a.wrapping_add(b)),
+                Op::Sub => binop!(|a, b| b.wrapping_sub(a)),
+                Op::Mul => binop!(|a, b| a.wrapping_mul(b)),
+                Op::Div | Op::Mod | Op::DivMod if state.stack.last() == Some(&0) => {
+                    return division_by_zero(&program[state.ip]);
+                }
+                Op::Div => binop!(|a, b| b.wrapping_div(a)),
+                Op::Mod => binop!(|a, b| b.wrapping_rem(a)),
+                Op::DivMod => binop!(|a, b| {
+                    state.stack.push(b.wrapping_div(a));
+                    b.wrapping_rem(a)
+                }),
+                Op::And => binop!(|a, b| a & b),
+                Op::Or => binop!(|a, b| a | b),
+                Op::Xor => binop!(|a, b| a ^ b),
+                Op::Not => {
+                    let a = pop!();
+                    state.stack.push(!a);
+                }
+                Op::Shl => binop!(|a, b| b.wrapping_shl(a as u32)),
+                Op::Shr => binop!(|a, b| b.wrapping_shr(a as u32)),
+                Op::Eq => binop!(|a, b| (a == b) as i64),
+                Op::Neq => binop!(|a, b| (a != b) as i64),
+                Op::Lt => binop!(|a, b| (b < a) as i64),
+                Op::Gt => binop!(|a, b| (b > a) as i64),
+                Op::Lte => binop!(|a, b| (b <= a) as i64),
+                Op::Gte => binop!(|a, b| (b >= a) as i64),
+                Op::Store => {
+                    let val = pop!() & 0xFF;
+                    let addr =
+                        check_access(&state.memory, state.mem_end, pop!(), 1, "write", state.ip)?;
+                    state.memory[addr] = val as u8;
+                }
+                Op::Load => {
+                    let addr =
+                        check_access(&state.memory, state.mem_end, pop!(), 1, "read", state.ip)?;
+                    state.stack.push(state.memory[addr] as i64);
+                }
+                Op::Store64 => {
+                    let val = pop!();
+                    let addr =
+                        check_access(&state.memory, state.mem_end, pop!(), 8, "write", state.ip)?;
+                    state.memory[addr..addr + 8].copy_from_slice(&val.to_le_bytes());
+                }
+                Op::Load64 => {
+                    let addr =
+                        check_access(&state.memory, state.mem_end, pop!(), 8, "read", state.ip)?;
+                    let mut bytes = [0; 8];
+                    bytes.copy_from_slice(&state.memory[addr..addr + 8]);
+                    state.stack.push(i64::from_le_bytes(bytes));
+                }
+                Op::Slow => {
+                    sim_instruction(&program[state.ip], state)?;
+                    if state.exit_code.is_some() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            }
+            state.ip += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cold]
+fn underflow(ip: usize) -> Result<()> {
+    Err(RuntimeError(StackUnderflow))
+        .with_context(|| format!("Stack underflow at instruction {}", ip))
+}
+
+fn lower(inst: &Instruction, strings: &HashMap<String, usize>) -> Op {
+    match &inst.kind {
+        InstructionKind::Push(Value::Str(s)) => match strings.get(s) {
+            Some(&ptr) => Op::PushStr { len: s.len(), ptr },
+            None => Op::Slow,
+        },
+        InstructionKind::Push(Value::Int(i)) => Op::Push(*i),
+        InstructionKind::Push(Value::Char(c)) => Op::Push(*c as i64),
+        InstructionKind::Push(Value::Bool(b)) => Op::Push(*b as i64),
+        InstructionKind::Keyword(Keyword::While { .. } | Keyword::If | Keyword::Unsafe) => Op::Nop,
+        InstructionKind::Keyword(Keyword::Do { end_ip }) => Op::JumpIfZero(end_ip + 1),
+        InstructionKind::Keyword(Keyword::Elif { end_ip, .. } | Keyword::Else { end_ip, .. }) => {
+            Op::Jump(*end_ip)
+        }
+        InstructionKind::Keyword(Keyword::End { while_ip, .. }) => match while_ip {
+            Some(while_ip) => Op::Jump(*while_ip),
+            None => Op::Nop,
+        },
+        InstructionKind::Intrinsic(intrinsic) => match intrinsic {
+            Intrinsic::Print => Op::Print,
+            Intrinsic::Dup => Op::Dup,
+            Intrinsic::Mem => Op::Mem,
+            Intrinsic::Swap => Op::Swap,
+            Intrinsic::Drop => Op::Drop,
+            Intrinsic::Over => Op::Over,
+            Intrinsic::Drop2 => Op::Drop2,
+            Intrinsic::Dup2 => Op::Dup2,
+            Intrinsic::Argc => Op::Argc,
+            Intrinsic::Argv => Op::Argv,
+            Intrinsic::CastPtr | Intrinsic::CastInt => Op::Nop,
+            _ => Op::Slow,
+        },
+        InstructionKind::Op(op) => match op {
+            InstOp::Add => Op::Add,
+            InstOp::Sub => Op::Sub,
+            InstOp::Mul => Op::Mul,
+            InstOp::Div => Op::Div,
+            InstOp::Mod => Op::Mod,
+            InstOp::DivMod => Op::DivMod,
+            InstOp::BitwiseAnd => Op::And,
+            InstOp::BitwiseOr => Op::Or,
+            InstOp::BitwiseXor => Op::Xor,
+            InstOp::BitwiseNot => Op::Not,
+            InstOp::Shl => Op::Shl,
+            InstOp::Shr => Op::Shr,
+            InstOp::Eq => Op::Eq,
+            InstOp::Neq => Op::Neq,
+            InstOp::Lt => Op::Lt,
+            InstOp::Gt => Op::Gt,
+            InstOp::Lte => Op::Lte,
+            InstOp::Gte => Op::Gte,
+            InstOp::Store => Op::Store,
+            InstOp::Load => Op::Load,
+            InstOp::Store64 => Op::Store64,
+            InstOp::Load64 => Op::Load64,
+            #[allow(unreachable_patterns)]
+            _ => Op::Slow,
+        },
+        _ => Op::Slow,
+    }
+}
@@ -0,0 +1,652 @@
+//! `worthc dap`, a Debug Adapter Protocol server for the simulator over stdio.
+//!
+//! The program is a single thread with a single stack frame, at the instruction
+//! about to run. Its scopes are the data stack, top first, and mem as 8 byte
+//! words, and any memory can be read with readMemory. Since stdin and stdout carry
+//! the protocol, the program gets an empty stdin and its output is sent as output
+//! events. Steps go a source line at a time unless an instruction granularity is
+//! asked for.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+
+use super::{region_name, SimulationState, ARGV_BUF_PTR, MEM_BUF_PTR};
+use crate::cli::TypecheckOptions;
+use crate::codegen::BSS_CAPACITY;
+use crate::error::{strip_ansi, Error::IOError, IOError::InvalidJson};
+use crate::instruction::Instruction;
+use crate::json::Json;
+use crate::program::load_program;
+use crate::typecheck;
+
+/// Instructions to run between checks for requests like pause.
+const BATCH: usize = 10_000;
+const THREAD_ID: i64 = 1;
+const STACK_REF: i64 = 1;
+const MEM_REF: i64 = 2;
+
+/// Serve one debug session on stdin and stdout.
+pub fn serve() -> Result<()> {
+    let requests = read_messages(io::stdin());
+    Server::new(io::stdout().lock()).serve(requests)
+}
+
+/// Parse messages on a thread of their own, so a running program can be paused.
+fn read_messages(input: impl Read + Send + 'static) -> Receiver<Result<Json>> {
+    let (send, recv) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        loop {
+            let message = match read_message(&mut input) {
+                Ok(Some(message)) => Json::parse(&message),
+                Ok(None) => return,
+                Err(e) => Err(e),
+            };
+            if send.send(message).is_err() {
+                return;
+            }
+        }
+    });
+    recv
+}
+
+/// One `Content-Length` framed message, or `None` at the end of the input.
+pub(crate) fn read_message(input: &mut impl BufRead) -> Result<Option<String>> {
+    let mut len = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                len = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let len = len
+        .ok_or(IOError(InvalidJson))
+        .context("Message without a Content-Length")?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|_| IOError(InvalidJson))
+        .context("Message isn't UTF-8")
+}
+
+/// Everything the program writes to one of its stdio files.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(PartialEq)]
+enum Mode {
+    Stopped,
+    Running,
+    StepInstruction,
+    /// Until the program leaves this file and line
+    StepLine(String, usize),
+}
+
+struct Session {
+    program: Vec<Instruction>,
+    state: SimulationState,
+    stdout: Output,
+    stderr: Output,
+    /// Paths of the program and everything it includes, by file name as in
+    /// instruction locations
+    sources: HashMap<String, PathBuf>,
+    stop_on_entry: bool,
+    no_debug: bool,
+}
+
+struct Server<W: Write> {
+    out: W,
+    seq: i64,
+    session: Option<Session>,
+    /// Breakpoint lines by file name
+    breakpoints: HashMap<String, Vec<usize>>,
+    mode: Mode,
+    /// Don't stop at a breakpoint on the instruction being resumed from
+    resuming: bool,
+}
+
+impl<W: Write> Server<W> {
+    fn new(out: W) -> Self {
+        Self {
+            out,
+            seq: 0,
+            session: None,
+            breakpoints: HashMap::new(),
+            mode: Mode::Stopped,
+            resuming: false,
+        }
+    }
+
+    fn serve(mut self, requests: Receiver<Result<Json>>) -> Result<()> {
+        loop {
+            let request = if self.mode == Mode::Stopped {
+                match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => return Ok(()),
+                }
+            } else {
+                match requests.try_recv() {
+                    Ok(request) => Some(request),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            };
+            match request {
+                Some(request) => {
+                    if !self.handle(&request?)? {
+                        return Ok(());
+                    }
+                }
+                None => self.run()?,
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Vec<(&str, Json)>) -> Result<()> {
+        self.seq += 1;
+        message.insert(0, ("seq", self.seq.into()));
+        let body = Json::object(message).to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.out.flush().context("Failed to write DAP message")
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Result<()> {
+        self.send(vec![
+            ("type", "event".into()),
+            ("event", event.into()),
+            ("body", body),
+        ])
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> Result<()> {
+        let mut response = vec![
+            ("type", "response".into()),
+            (
+                "request_seq",
+                request.get("seq").cloned().unwrap_or(Json::Null),
+            ),
+            ("success", result.is_ok().into()),
+            (
+                "command",
+                request.get("command").cloned().unwrap_or(Json::Null),
+            ),
+        ];
+        match result {
+            Ok(body) => response.push(("body", body)),
+            Err(message) => response.push(("message", message.into())),
+        }
+        self.send(response)
+    }
+
+    /// Handle a request, returning false once the session is over.
+    fn handle(&mut self, request: &Json) -> Result<bool> {
+        let command = request
+            .get("command")
+            .and_then(Json::as_str)
+            .unwrap_or_default();
+        let args = request.get("arguments").unwrap_or(&Json::Null);
+        let result = match command {
+            "initialize" => Ok(Json::object([
+                ("supportsConfigurationDoneRequest", true.into()),
+                ("supportsReadMemoryRequest", true.into()),
+                ("supportsSteppingGranularity", true.into()),
+                ("supportsTerminateRequest", true.into()),
+            ])),
+            "launch" => {
+                let result = self.launch(args);
+                let launched = result.is_ok();
+                self.respond(request, result.map(|_| Json::Null))?;
+                // Breakpoints come after this, once there's a program to check them against
+                if launched {
+                    self.event("initialized", Json::Null)?;
+                }
+                return Ok(true);
+            }
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "configurationDone" => {
+                self.respond(request, Ok(Json::Null))?;
+                match &self.session {
+                    Some(session) if session.stop_on_entry && !session.no_debug => {
+                        self.stopped("entry")?
+                    }
+                    Some(_) => self.resume(Mode::Running),
+                    None => {}
+                }
+                return Ok(true);
+            }
+            "threads" => Ok(Json::object([(
+                "threads",
+                vec![Json::object([
+                    ("id", THREAD_ID.into()),
+                    ("name", "main".into()),
+                ])]
+                .into(),
+            )])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Json::object([(
+                "scopes",
+                vec![
+                    Json::object([
+                        ("name", "Stack".into()),
+                        ("variablesReference", STACK_REF.into()),
+                        ("expensive", false.into()),
+                    ]),
+                    Json::object([
+                        ("name", "mem".into()),
+                        ("variablesReference", MEM_REF.into()),
+                        ("indexedVariables", (BSS_CAPACITY / 8).into()),
+                        ("expensive", true.into()),
+                    ]),
+                ]
+                .into(),
+            )])),
+            "variables" => self.variables(args),
+            "readMemory" => self.read_memory(args),
+            "continue" => {
+                self.respond(
+                    request,
+                    Ok(Json::object([("allThreadsContinued", true.into())])),
+                )?;
+                self.resume(Mode::Running);
+                return Ok(true);
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.respond(request, Ok(Json::Null))?;
+                let granularity = args.get("granularity").and_then(Json::as_str);
+                let mode = match (&self.session, granularity) {
+                    (Some(_), Some("instruction")) => Mode::StepInstruction,
+                    (Some(session), _) => match session.program.get(session.state.ip) {
+                        Some(inst) => Mode::StepLine(inst.loc.0.clone(), inst.loc.1),
+                        None => Mode::StepInstruction,
+                    },
+                    (None, _) => Mode::Stopped,
+                };
+                self.resume(mode);
+                return Ok(true);
+            }
+            "pause" => {
+                self.respond(request, Ok(Json::Null))?;
+                if self.mode != Mode::Stopped {
+                    self.stopped("pause")?;
+                }
+                return Ok(true);
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::Null))?;
+                self.event("terminated", Json::Null)?;
+                return Ok(false);
+            }
+            command => Err(format!("Unsupported request {}", command)),
+        };
+        self.respond(request, result)?;
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<(), String> {
+        let path = args
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or("launch needs the path of the program")?;
+        let path = PathBuf::from(path);
+        let program = load_program(&path).map_err(|e| error_message(&e))?;
+        if !args.get("unsafe").and_then(Json::as_bool).unwrap_or(false) {
+            let options = TypecheckOptions::parse_from(["worthc"]);
+            typecheck::typecheck(&program, &options).map_err(|e| error_message(&e))?;
+        }
+
+        let mut argv = vec![program.base_path.join(&program.name).display().to_string()];
+        for arg in args
+            .get("args")
+            .and_then(Json::as_array)
+            .unwrap_or_default()
+        {
+            argv.extend(arg.as_str().map(str::to_string));
+        }
+        let (stdout, stderr) = (Output::default(), Output::default());
+        let state = SimulationState::new(&program.instructions, &argv)
+            .map_err(|e| error_message(&e))?
+            .with_stdio(
+                Box::new(io::empty()),
+                Box::new(stdout.clone()),
+                Box::new(stderr.clone()),
+            );
+        self.session = Some(Session {
+            program: program.instructions,
+            state,
+            stdout,
+            stderr,
+            sources: sources(&path),
+            stop_on_entry: args
+                .get("stopOnEntry")
+                .and_then(Json::as_bool)
+                .unwrap_or(false),
+            no_debug: args.get("noDebug").and_then(Json::as_bool).unwrap_or(false),
+        });
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Json {
+        let path = args
+            .get("source")
+            .and_then(|source| source.get("path"))
+            .and_then(Json::as_str)
+            .unwrap_or_default();
+        let file = file_name(Path::new(path));
+        let lines: Vec<usize> = args
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|bp| bp.get("line")?.as_i64())
+            .map(|line| line as usize)
+            .collect();
+        let breakpoints = lines
+            .iter()
+            .map(|&line| {
+                // Only lines with code on them can be stopped at
+                let verified = self.session.as_ref().is_some_and(|session| {
+                    session
+                        .program
+                        .iter()
+                        .any(|inst| inst.loc.0 == file && inst.loc.1 == line)
+                });
+                Json::object([("verified", verified.into()), ("line", line.into())])
+            })
+            .collect::<Vec<_>>();
+        self.breakpoints.insert(file, lines);
+        Json::object([("breakpoints", breakpoints.into())])
+    }
+
+    fn stack_trace(&self) -> Result<Json, String> {
+        let session = self.session.as_ref().ok_or("Nothing is running")?;
+        let frames = match session.program.get(session.state.ip) {
+            Some(inst) => {
+                let (file, line, column) = &inst.loc;
+                let mut source = vec![("name", file.as_str().into())];
+                if let Some(path) = session.sources.get(file) {
+                    source.push(("path", path.display().to_string().into()));
+                }
+                vec![Json::object([
+                    ("id", 0.into()),
+                    ("name", format!("{}: {}", inst.ip, inst.kind).into()),
+                    ("source", Json::object(source)),
+                    ("line", (*line).into()),
+                    ("column", (*column).into()),
+                    ("instructionPointerReference", inst.ip.to_string().into()),
+                ])]
+            }
+            None => Vec::new(),
+        };
+        Ok(Json::object([
+            ("totalFrames", frames.len().into()),
+            ("stackFrames", frames.into()),
+        ]))
+    }
+
+    fn variables(&self, args: &Json) -> Result<Json, String> {
+        let session = self.session.as_ref().ok_or("Nothing is running")?;
+        let memory = &session.state.memory;
+        let variable = |name: String, value: i64| {
+            let mut fields = vec![
+                ("name", name.into()),
+                ("value", value.to_string().into()),
+                ("variablesReference", 0.into()),
+            ];
+            // Values past str are likely pointers, small ints would all look like strs
+            if let Ok(addr) = usize::try_from(value) {
+                if (ARGV_BUF_PTR..memory.len()).contains(&addr) {
+                    fields.push(("type", region_name(addr, session.state.mem_end).into()));
+                    fields.push(("memoryReference", format!("{:#x}", addr).into()));
+                }
+            }
+            Json::object(fields)
+        };
+        let variables: Vec<Json> = match args.get("variablesReference").and_then(Json::as_i64) {
+            Some(STACK_REF) => {
+                let stack = &session.state.stack;
+                stack
+                    .iter()
+                    .rev()
+                    .enumerate()
+                    .map(|(depth, &value)| variable(depth.to_string(), value))
+                    .collect()
+            }
+            Some(MEM_REF) => {
+                let start = args.get("start").and_then(Json::as_i64).unwrap_or(0).max(0) as usize;
+                let count = args
+                    .get("count")
+                    .and_then(Json::as_i64)
+                    .unwrap_or(100)
+                    .max(0) as usize;
+                (start..(start + count).min(BSS_CAPACITY / 8))
+                    .map(|word| {
+                        let addr = MEM_BUF_PTR + word * 8;
+                        let mut bytes = [0; 8];
+                        bytes.copy_from_slice(&memory[addr..addr + 8]);
+                        variable(format!("mem+{}", word * 8), i64::from_le_bytes(bytes))
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        Ok(Json::object([("variables", variables.into())]))
+    }
+
+    fn read_memory(&self, args: &Json) -> Result<Json, String> {
+        let session = self.session.as_ref().ok_or("Nothing is running")?;
+        let reference = args
+            .get("memoryReference")
+            .and_then(Json::as_str)
+            .unwrap_or_default();
+        let base = match reference.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => reference.parse(),
+        }
+        .map_err(|_| format!("Invalid memory reference {}", reference))?;
+        let addr = base + args.get("offset").and_then(Json::as_i64).unwrap_or(0);
+        let count = args.get("count").and_then(Json::as_i64).unwrap_or(0).max(0) as usize;
+        let memory = &session.state.memory;
+        let start = usize::try_from(addr)
+            .unwrap_or(usize::MAX)
+            .min(memory.len());
+        let end = start.saturating_add(count).min(memory.len());
+        Ok(Json::object([
+            ("address", format!("{:#x}", addr).into()),
+            ("data", base64(&memory[start..end]).into()),
+            ("unreadableBytes", (count - (end - start)).into()),
+        ]))
+    }
+
+    fn resume(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.resuming = true;
+    }
+
+    fn stopped(&mut self, reason: &str) -> Result<()> {
+        self.mode = Mode::Stopped;
+        self.flush_output()?;
+        self.event(
+            "stopped",
+            Json::object([
+                ("reason", reason.into()),
+                ("threadId", THREAD_ID.into()),
+                ("allThreadsStopped", true.into()),
+            ]),
+        )
+    }
+
+    /// Send what the program wrote since last time as output events.
+    fn flush_output(&mut self) -> Result<()> {
+        let Some(session) = &self.session else {
+            return Ok(());
+        };
+        let outputs = [("stdout", &session.stdout), ("stderr", &session.stderr)];
+        let outputs: Vec<(&str, Vec<u8>)> = outputs
+            .into_iter()
+            .map(|(category, output)| (category, output.0.take()))
+            .filter(|(_, bytes)| !bytes.is_empty())
+            .collect();
+        for (category, bytes) in outputs {
+            let output = String::from_utf8_lossy(&bytes).into_owned();
+            self.event(
+                "output",
+                Json::object([("category", category.into()), ("output", output.into())]),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn exited(&mut self, code: i32, error: Option<anyhow::Error>) -> Result<()> {
+        self.mode = Mode::Stopped;
+        self.flush_output()?;
+        if let Some(error) = error {
+            let message = error_message(&error) + "\n";
+            self.event(
+                "output",
+                Json::object([("category", "stderr".into()), ("output", message.into())]),
+            )?;
+        }
+        self.session = None;
+        self.event("exited", Json::object([("exitCode", code.into())]))?;
+        self.event("terminated", Json::Null)
+    }
+
+    /// Run the program for a while, until it stops or it's time to check for requests.
+    fn run(&mut self) -> Result<()> {
+        let Some(session) = &mut self.session else {
+            self.mode = Mode::Stopped;
+            return Ok(());
+        };
+        let mut stop = None;
+        for _ in 0..BATCH {
+            if let Some(code) = session.state.exit_status(&session.program) {
+                return self.exited(code, None);
+            }
+            let ip = session.state.ip;
+            if !self.resuming && !session.no_debug && at_breakpoint(&self.breakpoints, session, ip)
+            {
+                stop = Some("breakpoint");
+                break;
+            }
+            self.resuming = false;
+            if let Err(e) = session.state.step(&session.program, 1) {
+                return self.exited(1, Some(e));
+            }
+            let next = session.program.get(session.state.ip).map(|inst| &inst.loc);
+            let done = match &self.mode {
+                Mode::StepInstruction => true,
+                Mode::StepLine(file, line) => next.is_some_and(|(f, l, _)| f != file || l != line),
+                Mode::Running | Mode::Stopped => false,
+            };
+            if done && session.state.exit_status(&session.program).is_none() {
+                stop = Some("step");
+                break;
+            }
+        }
+        match stop {
+            Some(reason) => self.stopped(reason),
+            None => self.flush_output(),
+        }
+    }
+}
+
+/// Whether there's a breakpoint on the line starting at `ip`. Lines that use
+/// macros from other files don't start again after them, so the instruction
+/// before in the same file decides.
+fn at_breakpoint(breakpoints: &HashMap<String, Vec<usize>>, session: &Session, ip: usize) -> bool {
+    let (file, line, _) = &session.program[ip].loc;
+    breakpoints
+        .get(file)
+        .is_some_and(|lines| lines.contains(line))
+        && session.program[..ip]
+            .iter()
+            .rev()
+            .find(|inst| inst.loc.0 == *file)
+            .is_none_or(|inst| inst.loc.1 != *line)
+}
+
+/// Errors as they'd be printed, without the colors.
+fn error_message(error: &anyhow::Error) -> String {
+    strip_ansi(&format!("{:?}", error))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The program at `path` and everything it includes, found by following the
+/// include lines of each file.
+fn sources(path: &Path) -> HashMap<String, PathBuf> {
+    let mut sources = HashMap::new();
+    let mut todo = vec![path.to_path_buf()];
+    while let Some(path) = todo.pop() {
+        let Ok(path) = path.canonicalize() else {
+            continue;
+        };
+        if sources.insert(file_name(&path), path.clone()).is_some() {
+            continue;
+        }
+        let Ok(source) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for rest in source.split("include").skip(1) {
+            let Some(included) = rest.trim_start().strip_prefix('"') else {
+                continue;
+            };
+            if let Some((included, _)) = included.split_once('"') {
+                todo.push(dir.join(included));
+            }
+        }
+    }
+    sources
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const DIGITS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(DIGITS[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,283 @@
+//! Interactive debugger for the simulator, entered with `--step` or a breakpoint.
+
+use std::io::Write;
+use std::path::Path;
+
+use super::{region_name, SimulationState, ARGV_BUF_PTR, MEM_BUF_PTR, STR_BUF_PTR};
+use crate::instruction::Instruction;
+
+pub enum Breakpoint {
+    Ip(usize),
+    Line(String, usize),
+}
+
+impl Breakpoint {
+    pub fn parse(target: &str) -> Option<Self> {
+        if let Ok(ip) = target.parse::<usize>() {
+            return Some(Breakpoint::Ip(ip));
+        }
+        let (file, line) = target.rsplit_once(':')?;
+        Some(Breakpoint::Line(file.to_string(), line.parse().ok()?))
+    }
+}
+
+impl std::fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Breakpoint::Ip(ip) => write!(f, "ip {}", ip),
+            Breakpoint::Line(file, line) => write!(f, "{}:{}", file, line),
+        }
+    }
+}
+
+struct Watchpoint {
+    addr: usize,
+    /// The bytes as of the last instruction
+    value: Vec<u8>,
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    pub stepping: bool,
+    run_to: Option<usize>,
+    /// Print every instruction and the stack while running, toggled with `d`
+    pub trace: bool,
+    pub quit: bool,
+    /// Set when the user asked to step back this many instructions
+    pub back: Option<u64>,
+}
+
+impl Debugger {
+    pub fn new(breakpoints: Vec<Breakpoint>, stepping: bool, trace: bool) -> Self {
+        Self {
+            breakpoints,
+            watchpoints: Vec::new(),
+            stepping,
+            run_to: None,
+            trace,
+            quit: false,
+            back: None,
+        }
+    }
+
+    /// Whether to stop before running the instruction at `ip`.
+    pub fn at_breakpoint(&mut self, program: &[Instruction], ip: usize) -> bool {
+        let loc = &program[ip].loc;
+        if self.run_to == Some(ip) {
+            self.run_to = None;
+            return true;
+        }
+        self.breakpoints.iter().any(|bp| match bp {
+            Breakpoint::Ip(bp) => *bp == ip,
+            Breakpoint::Line(file, line) => {
+                *line == loc.1
+                    && Path::new(&loc.0).ends_with(file)
+                    // Only stop on the first instruction of the line
+                    && (ip == 0 || program[ip - 1].loc.1 != *line)
+            }
+        })
+    }
+
+    /// Check the watchpoints after an instruction ran, reporting and updating
+    /// any that changed. Returns true if one did.
+    pub fn check_watchpoints(&mut self, memory: &[u8]) -> bool {
+        let mut hit = false;
+        for watch in &mut self.watchpoints {
+            let now = &memory[watch.addr..watch.addr + watch.value.len()];
+            if now != watch.value {
+                println!(
+                    "Watchpoint {:#x}: {:02x?} -> {:02x?}",
+                    watch.addr, watch.value, now
+                );
+                watch.value = now.to_vec();
+                hit = true;
+            }
+        }
+        hit
+    }
+
+    /// Take the current memory as the watched values, after stepping back.
+    pub fn reset_watchpoints(&mut self, memory: &[u8]) {
+        for watch in &mut self.watchpoints {
+            let len = watch.value.len();
+            watch.value = memory[watch.addr..watch.addr + len].to_vec();
+        }
+    }
+
+    /// Read commands until one of them resumes the simulation or steps back.
+    pub fn prompt(&mut self, program: &[Instruction], state: &SimulationState) {
+        loop {
+            print!("(sim) ");
+            let _ = std::io::stdout().flush();
+            let mut cmd = String::new();
+            if std::io::stdin().read_line(&mut cmd).unwrap_or(0) == 0 {
+                // stdin closed, run to completion
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                self.run_to = None;
+                self.stepping = false;
+                return;
+            }
+            let mut args = cmd.split_whitespace();
+            match (args.next(), args.next(), args.next()) {
+                (None, ..) | (Some("s" | "step"), ..) => {
+                    self.stepping = true;
+                    return;
+                }
+                (Some("c" | "continue"), ..) => {
+                    self.stepping = false;
+                    return;
+                }
+                (Some("r" | "back"), n, _) => match n.map_or(Ok(1), str::parse) {
+                    Ok(n) => {
+                        self.back = Some(n);
+                        return;
+                    }
+                    Err(_) => println!("Invalid count {}", n.unwrap_or_default()),
+                },
+                (Some("u" | "until"), Some(target), _) => match target.parse::<usize>() {
+                    Ok(target) if target < program.len() => {
+                        self.run_to = Some(target);
+                        self.stepping = false;
+                        return;
+                    }
+                    _ => println!("Invalid instruction {}", target),
+                },
+                (Some("b" | "break"), Some(target), _) => match Breakpoint::parse(target) {
+                    Some(bp) => self.breakpoints.push(bp),
+                    None => println!(
+                        "Invalid breakpoint {}, expected <ip> or <file>:<line>",
+                        target
+                    ),
+                },
+                (Some("w" | "watch"), Some(addr), len) => {
+                    let len = len.map_or(Some(8), parse_addr);
+                    match (parse_addr(addr), len) {
+                        (Some(addr), Some(len)) => match bytes(&state.memory, addr, len) {
+                            Some(value) => self.watchpoints.push(Watchpoint {
+                                addr,
+                                value: value.to_vec(),
+                            }),
+                            None => println!("{} bytes at {:#x} are out of bounds", len, addr),
+                        },
+                        _ => println!("Invalid watchpoint {}", addr),
+                    }
+                }
+                (Some("breakpoints"), ..) => {
+                    let watchpoints = self.watchpoints.iter().map(|watch| {
+                        format!("{:#x}, {} bytes", watch.addr, watch.value.len())
+                    });
+                    for (i, bp) in self
+                        .breakpoints
+                        .iter()
+                        .map(ToString::to_string)
+                        .chain(watchpoints)
+                        .enumerate()
+                    {
+                        println!("{}: {}", i, bp);
+                    }
+                }
+                (Some("delete"), Some(n), _) => match n.parse::<usize>() {
+                    Ok(n) if n < self.breakpoints.len() => {
+                        self.breakpoints.remove(n);
+                    }
+                    Ok(n) if n - self.breakpoints.len() < self.watchpoints.len() => {
+                        self.watchpoints.remove(n - self.breakpoints.len());
+                    }
+                    _ => println!("No breakpoint {}", n),
+                },
+                (Some("m" | "mem"), Some(addr), Some(len)) => {
+                    match (parse_addr(addr), parse_addr(len)) {
+                        (Some(addr), Some(len)) => match bytes(&state.memory, addr, len) {
+                            Some(bytes) => hexdump(addr, bytes),
+                            None => println!("{} bytes at {:#x} are out of bounds", len, addr),
+                        },
+                        _ => println!("Invalid address {} or length {}", addr, len),
+                    }
+                }
+                (Some("p" | "stack"), ..) => print_stack(&state.stack, state.mem_end),
+                (Some("d" | "debug"), ..) => {
+                    self.trace = !self.trace;
+                    println!("Tracing {}", if self.trace { "on" } else { "off" });
+                }
+                (Some("q" | "quit"), ..) => {
+                    self.quit = true;
+                    return;
+                }
+                (Some("h" | "help"), ..) => println!(
+                    "Commands:\n  \
+                     <enter>, s, step         run the next instruction\n  \
+                     r, back [n]              step back n (default 1) instructions\n  \
+                     c, continue              run until the next breakpoint or watchpoint\n  \
+                     u, until <ip>            run until instruction ip\n  \
+                     b, break <ip|file:line>  add a breakpoint\n  \
+                     w, watch <addr> [len]    stop when any of the len (default 8) bytes at addr change\n  \
+                     breakpoints              list breakpoints and watchpoints\n  \
+                     delete <n>               delete breakpoint or watchpoint n\n  \
+                     m, mem <addr> <len>      hexdump memory\n  \
+                     p, stack                 print the stack, top first\n  \
+                     d, debug                 toggle printing every instruction\n  \
+                     q, quit                  stop the simulation\n\
+                     Addresses are decimal, 0x hex, or str, argv or mem with an optional +offset."
+                ),
+                (Some(cmd), ..) => println!("Unknown command {}, try help", cmd),
+            }
+        }
+    }
+}
+
+pub fn parse_addr(s: &str) -> Option<usize> {
+    let number = |s: &str| match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    };
+    let (base, offset) = match s.split_once('+') {
+        Some((base, offset)) => (base, number(offset)?),
+        None => (s, 0),
+    };
+    let base = match base {
+        "str" => STR_BUF_PTR,
+        "argv" => ARGV_BUF_PTR,
+        "mem" => MEM_BUF_PTR,
+        _ => number(base)?,
+    };
+    Some(base + offset)
+}
+
+pub fn bytes(memory: &[u8], addr: usize, len: usize) -> Option<&[u8]> {
+    memory.get(addr..addr.checked_add(len)?)
+}
+
+pub fn hexdump(addr: usize, bytes: &[u8]) {
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = row
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!("{:08x}  {:<47}  |{}|", addr + i * 16, hex.join(" "), text);
+    }
+}
+
+pub fn print_stack(stack: &[i64], mem_end: usize) {
+    if stack.is_empty() {
+        println!("Stack is empty");
+    }
+    for (depth, &value) in stack.iter().rev().enumerate() {
+        // Values in argv or mem are likely pointers, small ints would all look like strs
+        let region = match usize::try_from(value) {
+            Ok(addr) if (ARGV_BUF_PTR..mem_end).contains(&addr) => {
+                format!(" ({})", region_name(addr, mem_end))
+            }
+            _ => String::new(),
+        };
+        println!("{:>4}: {:<20} {:#x}{}", depth, value, value, region);
+    }
+}
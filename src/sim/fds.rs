@@ -0,0 +1,165 @@
+//! The simulated file descriptor table.
+//!
+//! Descriptors made by dup, dup2 or fcntl share one `BinaryIO`, the way they
+//! would share an open file description in the kernel, so they also share
+//! file offsets and buffered input.
+
+use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
+use std::io::{self, BufReader, Read, Write};
+use std::rc::Rc;
+
+use super::BinaryIO;
+
+/// Same as the default RLIMIT_NOFILE soft limit on Linux.
+pub const MAX_FDS: usize = 1024;
+
+#[derive(Clone)]
+pub struct Fd {
+    pub io: Rc<RefCell<BinaryIO>>,
+    /// FD_CLOEXEC, which belongs to the descriptor rather than the description
+    pub cloexec: bool,
+}
+
+impl Fd {
+    pub fn new(io: BinaryIO) -> Self {
+        Self {
+            io: Rc::new(RefCell::new(io)),
+            cloexec: false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FdTable {
+    fds: Vec<Option<Fd>>,
+}
+
+impl FdTable {
+    pub fn stdio() -> Self {
+        Self {
+            fds: BinaryIO::stdio()
+                .into_iter()
+                .map(|io| Some(Fd::new(io)))
+                .collect(),
+        }
+    }
+
+    pub fn fd(&self, fd: i64) -> Option<&Fd> {
+        usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.fds.get(fd))
+            .and_then(Option::as_ref)
+    }
+
+    pub fn fd_mut(&mut self, fd: i64) -> Option<&mut Fd> {
+        usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.fds.get_mut(fd))
+            .and_then(Option::as_mut)
+    }
+
+    pub fn get(&self, fd: i64) -> Option<RefMut<'_, BinaryIO>> {
+        self.fd(fd).map(|fd| fd.io.borrow_mut())
+    }
+
+    /// Put `fd` in the lowest free slot at or above `min`, like the kernel does.
+    /// Returns `None` if the table is full.
+    pub fn insert_from(&mut self, min: usize, fd: Fd) -> Option<i64> {
+        let slot = (min..MAX_FDS).find(|&i| self.fds.get(i).is_none_or(Option::is_none))?;
+        self.insert_at(slot, fd);
+        Some(slot as i64)
+    }
+
+    pub fn insert(&mut self, io: BinaryIO) -> Option<i64> {
+        self.insert_from(0, Fd::new(io))
+    }
+
+    /// Put `fd` at `slot`, closing whatever was there.
+    pub fn insert_at(&mut self, slot: usize, fd: Fd) {
+        if slot >= self.fds.len() {
+            self.fds.resize(slot + 1, None);
+        }
+        self.fds[slot] = Some(fd);
+    }
+
+    /// Returns false if `fd` wasn't open.
+    pub fn close(&mut self, fd: i64) -> bool {
+        usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.fds.get_mut(fd))
+            .and_then(Option::take)
+            .is_some()
+    }
+}
+
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+struct PipeReader(Rc<RefCell<PipeBuffer>>);
+
+struct PipeWriter(Rc<RefCell<PipeBuffer>>);
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.0.borrow_mut();
+        if pipe.data.is_empty() && pipe.writers > 0 {
+            // Nothing else runs while the simulator waits, so this would never return
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Reading from an empty pipe would block forever",
+            ));
+        }
+        let len = buf.len().min(pipe.data.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.data.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.0.borrow_mut();
+        if pipe.readers == 0 {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        pipe.data.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.borrow_mut().readers -= 1;
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.borrow_mut().writers -= 1;
+    }
+}
+
+/// An in-memory pipe, as its (read, write) ends.
+pub fn pipe() -> (BinaryIO, BinaryIO) {
+    let buffer = Rc::new(RefCell::new(PipeBuffer {
+        data: VecDeque::new(),
+        readers: 1,
+        writers: 1,
+    }));
+    (
+        BinaryIO::new(
+            Some(Box::new(BufReader::new(PipeReader(buffer.clone())))),
+            None,
+        ),
+        BinaryIO::new(None, Some(Box::new(PipeWriter(buffer)))),
+    )
+}
@@ -0,0 +1,155 @@
+//! Dynamic memory for simulated programs.
+//!
+//! The program break starts right after the static buffers and grows the
+//! simulator memory in place. Anonymous mappings are carved out above it, so
+//! once something is mapped the break can no longer move past that mapping,
+//! the same way the kernel refuses a brk that would collide with a mapping.
+
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+use super::FdTable;
+
+const PAGE_SIZE: usize = 4096;
+
+const EINVAL: i64 = 22;
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+
+const MAP_FIXED: i64 = 0x10;
+const MAP_ANONYMOUS: i64 = 0x20;
+
+/// Mappings larger than this are refused rather than allocated on the host.
+const MAX_MAPPING: usize = 1 << 32;
+
+fn page_align(n: usize) -> usize {
+    (n + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[derive(Clone)]
+pub struct Heap {
+    brk_start: usize,
+    brk: usize,
+    /// Live mappings as (start, len), in the order they were made
+    mappings: Vec<(usize, usize)>,
+    /// Unmapped ranges that can be handed out again
+    free: Vec<(usize, usize)>,
+}
+
+impl Heap {
+    pub fn new(brk_start: usize) -> Self {
+        Self {
+            brk_start,
+            brk: brk_start,
+            mappings: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// Move the program break to `addr`, returning the new break, or the
+    /// current one if `addr` is 0 or the break can't be moved there.
+    pub fn brk(&mut self, memory: &mut Vec<u8>, addr: i64) -> i64 {
+        let Ok(addr) = usize::try_from(addr) else {
+            return self.brk as i64;
+        };
+        let limit = self
+            .mappings
+            .iter()
+            .chain(self.free.iter())
+            .map(|(start, _)| *start)
+            .min()
+            .unwrap_or(usize::MAX);
+        if addr < self.brk_start || addr > limit || addr - self.brk_start > MAX_MAPPING {
+            return self.brk as i64;
+        }
+        if addr > memory.len() {
+            memory.resize(addr, 0);
+        } else if addr < self.brk {
+            // Released memory reads as zero if the break grows back over it
+            memory[addr..self.brk].fill(0);
+        }
+        self.brk = addr;
+        self.brk as i64
+    }
+
+    /// Map `len` bytes of zeroed memory, or of `fd` starting at `offset`.
+    /// File mappings are private copies and never written back.
+    pub fn mmap(
+        &mut self,
+        memory: &mut Vec<u8>,
+        fds: &FdTable,
+        len: i64,
+        flags: i64,
+        fd: i64,
+        offset: i64,
+    ) -> i64 {
+        let len = match usize::try_from(len) {
+            Ok(len) if len > 0 && len <= MAX_MAPPING => page_align(len),
+            Ok(len) if len > MAX_MAPPING => return -ENOMEM,
+            _ => return -EINVAL,
+        };
+        if flags & MAP_FIXED != 0 || offset < 0 || offset as usize & (PAGE_SIZE - 1) != 0 {
+            return -EINVAL;
+        }
+        let start = match self.free.iter().position(|(_, free)| *free >= len) {
+            Some(i) => {
+                let (start, free) = self.free.remove(i);
+                if free > len {
+                    self.free.push((start + len, free - len));
+                }
+                start
+            }
+            None => {
+                let start = page_align(memory.len().max(self.brk));
+                memory.resize(start + len, 0);
+                start
+            }
+        };
+        if flags & MAP_ANONYMOUS == 0 {
+            let Some(io) = fds.get(fd).filter(|io| io.file.is_some()) else {
+                self.free.push((start, len));
+                return -EBADF;
+            };
+            let file = io.file.as_ref().unwrap();
+            // Mapping past the end of the file leaves the rest zeroed, and like
+            // a real mapping it doesn't move the file's offset
+            let region = &mut memory[start..start + len];
+            let copied = read_at(file, region, offset as u64);
+            if copied.is_err() {
+                region.fill(0);
+                self.free.push((start, len));
+                return -EINVAL;
+            }
+        }
+        self.mappings.push((start, len));
+        start as i64
+    }
+
+    /// Unmap a mapping previously returned by `mmap`. Partial unmaps are not supported.
+    pub fn munmap(&mut self, memory: &mut Vec<u8>, addr: i64, len: i64) -> i64 {
+        let Some(i) = self.mappings.iter().position(|(start, mapped)| {
+            *start as i64 == addr && page_align(len.max(0) as usize) == *mapped
+        }) else {
+            return -EINVAL;
+        };
+        let (start, len) = self.mappings.remove(i);
+        if start + len == memory.len() {
+            memory.truncate(start);
+        } else {
+            memory[start..start + len].fill(0);
+            self.free.push((start, len));
+        }
+        0
+    }
+}
+
+/// Fill `buf` from `file` at `offset`, up to its end, without moving its offset.
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64)? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+//! Snapshots of the simulation, so the debugger can step backwards.
+//!
+//! A snapshot is taken every few instructions and after every syscall. Stepping
+//! back restores the closest snapshot and replays from there, which never has to
+//! run a syscall again since there's a snapshot after each one. Stepping forward
+//! over a syscall that already ran restores its snapshot rather than repeating
+//! it, so output isn't written twice and input isn't read twice. Child processes
+//! and open files are shared with the snapshots rather than rewound.
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+
+use super::{sim_instruction, SimulationState};
+use crate::instruction::{Instruction, InstructionKind};
+
+/// Snapshots copy all of memory, so only keep this many around.
+const MAX_SNAPSHOTS: usize = 64;
+
+pub struct History {
+    /// Take a snapshot every `every` instructions, or none at all if 0
+    every: u64,
+    /// Oldest first, with the number of instructions run when they were taken
+    snapshots: VecDeque<(u64, SimulationState)>,
+    /// Instructions run so far, including ones that were stepped back over
+    pub steps: u64,
+}
+
+impl History {
+    pub fn new(every: u64, state: &SimulationState) -> Self {
+        let mut snapshots = VecDeque::new();
+        if every != 0 {
+            snapshots.push_back((0, state.snapshot()));
+        }
+        Self {
+            every,
+            snapshots,
+            steps: 0,
+        }
+    }
+
+    /// Run `inst`, the next instruction.
+    pub fn step(&mut self, inst: &Instruction, state: &mut SimulationState) -> Result<()> {
+        self.steps += 1;
+        let syscall = matches!(inst.kind, InstructionKind::Syscall(_));
+        if syscall {
+            if let Some((_, snapshot)) = self.snapshots.iter().find(|(s, _)| *s == self.steps) {
+                state.restore(snapshot);
+                return Ok(());
+            }
+        }
+        sim_instruction(inst, state)?;
+        let recorded = self.snapshots.back().is_some_and(|(s, _)| *s >= self.steps);
+        if self.every != 0 && !recorded && (syscall || self.steps.is_multiple_of(self.every)) {
+            if self.snapshots.len() == MAX_SNAPSHOTS {
+                self.snapshots.pop_front();
+            }
+            self.snapshots.push_back((self.steps, state.snapshot()));
+        }
+        Ok(())
+    }
+
+    /// Go back `n` instructions. Returns false if there's no snapshot that far back.
+    pub fn back(
+        &mut self,
+        program: &[Instruction],
+        state: &mut SimulationState,
+        n: u64,
+    ) -> Result<bool> {
+        let Some(target) = self.steps.checked_sub(n) else {
+            return Ok(false);
+        };
+        let Some((at, snapshot)) = self.snapshots.iter().rev().find(|(s, _)| *s <= target) else {
+            return Ok(false);
+        };
+        state.restore(snapshot);
+        // Only plain instructions run between a snapshot and the next one
+        for _ in *at..target {
+            sim_instruction(&program[state.ip], state)?;
+        }
+        self.steps = target;
+        Ok(true)
+    }
+}
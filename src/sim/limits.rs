@@ -0,0 +1,64 @@
+//! `--max-steps` and `--timeout`, so runaway programs can't hang the simulator.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::error::{Error::RuntimeError, RuntimeError::*};
+
+/// How many steps run between looks at the clock.
+const CLOCK_INTERVAL: u64 = 1 << 16;
+
+pub struct Limits {
+    max_steps: Option<u64>,
+    timeout: Option<(Duration, Instant)>,
+    steps: u64,
+}
+
+impl Limits {
+    pub fn new(max_steps: Option<u64>, timeout: Option<Duration>) -> Self {
+        Self {
+            max_steps,
+            timeout: timeout.map(|timeout| (timeout, Instant::now())),
+            steps: 0,
+        }
+    }
+
+    /// Count a step, failing once a limit is passed.
+    #[inline]
+    pub fn step(&mut self, ip: usize) -> Result<()> {
+        self.steps += 1;
+        if self.max_steps.is_some_and(|max| self.steps > max) {
+            return self.exceeded(ip);
+        }
+        if self.steps.is_multiple_of(CLOCK_INTERVAL) {
+            if let Some((timeout, start)) = self.timeout {
+                if start.elapsed() > timeout {
+                    return self.exceeded(ip);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cold]
+    fn exceeded(&self, ip: usize) -> Result<()> {
+        match self.timeout {
+            Some((timeout, start)) if start.elapsed() > timeout => {
+                Err(RuntimeError(TimeoutExceeded)).with_context(|| {
+                    format!(
+                        "Simulation timed out after {:?} at instruction {}",
+                        timeout, ip
+                    )
+                })
+            }
+            _ => Err(RuntimeError(StepLimitExceeded)).with_context(|| {
+                format!(
+                    "Simulation stopped after {} steps at instruction {}",
+                    self.steps - 1,
+                    ip
+                )
+            }),
+        }
+    }
+}
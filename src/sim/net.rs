@@ -0,0 +1,277 @@
+//! TCP sockets for simulated programs, backed by std::net.
+//!
+//! std::net can't bind a socket without listening on it, so bind only records
+//! the address and listen does the real work. Only IPv4 stream sockets are
+//! supported.
+
+use std::io::{BufReader, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+
+use anyhow::Result;
+
+use super::syscalls::{errno, load, region, store};
+use super::{BinaryIO, FdTable};
+
+const EBADF: i64 = 9;
+const EINVAL: i64 = 22;
+const ENOTSOCK: i64 = 88;
+const EPROTONOSUPPORT: i64 = 93;
+const EAFNOSUPPORT: i64 = 97;
+const EISCONN: i64 = 106;
+const ENOTCONN: i64 = 107;
+const EMFILE: i64 = 24;
+
+const AF_INET: u16 = 2;
+const SOCK_STREAM: i64 = 1;
+/// SOCK_NONBLOCK | SOCK_CLOEXEC, which may be or'd into the socket type
+const SOCK_FLAGS: i64 = 0o4000 | 0o2000000;
+const SOCKADDR_IN_SIZE: usize = 16;
+
+pub enum Socket {
+    Unbound,
+    Bound(SocketAddrV4),
+    Listener(TcpListener),
+    Stream(TcpStream),
+}
+
+impl BinaryIO {
+    fn socket(socket: Socket) -> Self {
+        let mut io = Self::new(None, None);
+        io.flags = 2; // O_RDWR
+        io.socket = Some(socket);
+        io
+    }
+
+    /// A connected stream, which can also be used with read and write.
+    fn stream(stream: TcpStream) -> Self {
+        let mut io = Self::socket(Socket::Unbound);
+        io.reader = stream
+            .try_clone()
+            .ok()
+            .map(|s| Box::new(BufReader::new(s)) as _);
+        io.writer = stream.try_clone().ok().map(|s| Box::new(s) as _);
+        io.socket = Some(Socket::Stream(stream));
+        io
+    }
+}
+
+/// Read a `struct sockaddr_in`. Returns the errno to fail with if it isn't one.
+fn read_addr(memory: &[u8], ptr: i64, len: i64) -> Result<Result<SocketAddrV4, i64>> {
+    if len < SOCKADDR_IN_SIZE as i64 {
+        return Ok(Err(-EINVAL));
+    }
+    let addr = region(memory, ptr, SOCKADDR_IN_SIZE, "sockaddr")?;
+    // sa_family is in the machine's byte order, the port and address in network order
+    let family = load(memory, addr, 2) as u16;
+    if family != AF_INET {
+        return Ok(Err(-EAFNOSUPPORT));
+    }
+    let port = u16::from_be_bytes([memory[addr + 2], memory[addr + 3]]);
+    let ip = Ipv4Addr::new(
+        memory[addr + 4],
+        memory[addr + 5],
+        memory[addr + 6],
+        memory[addr + 7],
+    );
+    Ok(Ok(SocketAddrV4::new(ip, port)))
+}
+
+/// Write `addr` to a sockaddr buffer whose size is at `len_ptr`, like accept and getsockname.
+fn write_addr(memory: &mut [u8], ptr: i64, len_ptr: i64, addr: SocketAddr) -> Result<()> {
+    if ptr == 0 {
+        return Ok(());
+    }
+    let SocketAddr::V4(addr) = addr else {
+        return Ok(());
+    };
+    let len_addr = region(memory, len_ptr, 4, "socklen")?;
+    let len = (load(memory, len_addr, 4) as usize).min(SOCKADDR_IN_SIZE);
+    let mut bytes = [0; SOCKADDR_IN_SIZE];
+    bytes[..2].copy_from_slice(&AF_INET.to_le_bytes());
+    bytes[2..4].copy_from_slice(&addr.port().to_be_bytes());
+    bytes[4..8].copy_from_slice(&addr.ip().octets());
+    let buf = region(memory, ptr, len, "sockaddr")?;
+    memory[buf..buf + len].copy_from_slice(&bytes[..len]);
+    store(memory, len_addr, SOCKADDR_IN_SIZE as u64, 4);
+    Ok(())
+}
+
+fn socket_of(fds: &FdTable, fd: i64) -> Result<std::cell::RefMut<'_, BinaryIO>, i64> {
+    let io = fds.get(fd).ok_or(-EBADF)?;
+    if io.socket.is_none() {
+        return Err(-ENOTSOCK);
+    }
+    Ok(io)
+}
+
+pub fn socket(fds: &mut FdTable, domain: i64, kind: i64, protocol: i64) -> i64 {
+    if domain != AF_INET as i64 {
+        return -EAFNOSUPPORT;
+    }
+    // 6 is IPPROTO_TCP
+    if kind & !SOCK_FLAGS != SOCK_STREAM || (protocol != 0 && protocol != 6) {
+        return -EPROTONOSUPPORT;
+    }
+    fds.insert(BinaryIO::socket(Socket::Unbound))
+        .unwrap_or(-EMFILE)
+}
+
+pub fn bind(fds: &FdTable, memory: &[u8], fd: i64, addr: i64, len: i64) -> Result<i64> {
+    let mut io = match socket_of(fds, fd) {
+        Ok(io) => io,
+        Err(e) => return Ok(e),
+    };
+    let addr = match read_addr(memory, addr, len)? {
+        Ok(addr) => addr,
+        Err(e) => return Ok(e),
+    };
+    match io.socket {
+        Some(Socket::Unbound) => {
+            io.socket = Some(Socket::Bound(addr));
+            Ok(0)
+        }
+        _ => Ok(-EINVAL),
+    }
+}
+
+pub fn listen(fds: &FdTable, fd: i64) -> i64 {
+    let mut io = match socket_of(fds, fd) {
+        Ok(io) => io,
+        Err(e) => return e,
+    };
+    // Listening without binding picks any port, like the kernel does
+    let addr = match &io.socket {
+        Some(Socket::Bound(addr)) => *addr,
+        Some(Socket::Unbound) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        Some(Socket::Listener(_)) => return 0,
+        _ => return -EINVAL,
+    };
+    match TcpListener::bind(addr) {
+        Ok(listener) => {
+            io.socket = Some(Socket::Listener(listener));
+            0
+        }
+        Err(e) => errno(e),
+    }
+}
+
+pub fn accept(fds: &mut FdTable, memory: &mut [u8], fd: i64, addr: i64, len: i64) -> Result<i64> {
+    let accepted = match socket_of(fds, fd).as_deref() {
+        Ok(BinaryIO {
+            socket: Some(Socket::Listener(listener)),
+            ..
+        }) => listener.accept(),
+        Ok(_) => return Ok(-EINVAL),
+        Err(e) => return Ok(*e),
+    };
+    let (stream, peer) = match accepted {
+        Ok(accepted) => accepted,
+        Err(e) => return Ok(errno(e)),
+    };
+    write_addr(memory, addr, len, peer)?;
+    Ok(fds.insert(BinaryIO::stream(stream)).unwrap_or(-EMFILE))
+}
+
+pub fn connect(fds: &FdTable, memory: &[u8], fd: i64, addr: i64, len: i64) -> Result<i64> {
+    let mut io = match socket_of(fds, fd) {
+        Ok(io) => io,
+        Err(e) => return Ok(e),
+    };
+    let addr = match read_addr(memory, addr, len)? {
+        Ok(addr) => addr,
+        Err(e) => return Ok(e),
+    };
+    match io.socket {
+        // A bound address is dropped, std::net can't connect from a chosen port
+        Some(Socket::Unbound | Socket::Bound(_)) => {}
+        Some(Socket::Stream(_)) => return Ok(-EISCONN),
+        _ => return Ok(-EINVAL),
+    }
+    match TcpStream::connect(addr) {
+        Ok(stream) => {
+            *io = BinaryIO {
+                flags: io.flags,
+                ..BinaryIO::stream(stream)
+            };
+            Ok(0)
+        }
+        Err(e) => Ok(errno(e)),
+    }
+}
+
+/// sendto and recvfrom on connected streams, where the address is ignored like the kernel does.
+pub fn transfer(
+    fds: &FdTable,
+    memory: &mut [u8],
+    fd: i64,
+    buf: i64,
+    len: i64,
+    send: bool,
+) -> Result<i64> {
+    let mut io = match socket_of(fds, fd) {
+        Ok(io) => io,
+        Err(e) => return Ok(e),
+    };
+    if !matches!(io.socket, Some(Socket::Stream(_))) {
+        return Ok(-ENOTCONN);
+    }
+    let Ok(len) = usize::try_from(len) else {
+        return Ok(-EINVAL);
+    };
+    let addr = region(memory, buf, len, "socket")?;
+    let buf = &mut memory[addr..addr + len];
+    let result = if send {
+        io.writer.as_mut().map(|w| w.write_all(buf).map(|_| len))
+    } else {
+        io.reader.as_mut().map(|r| r.read(buf))
+    };
+    match result {
+        Some(Ok(n)) => Ok(n as i64),
+        Some(Err(e)) => Ok(errno(e)),
+        None => Ok(-ENOTCONN),
+    }
+}
+
+pub fn shutdown(fds: &FdTable, fd: i64, how: i64) -> i64 {
+    let io = match socket_of(fds, fd) {
+        Ok(io) => io,
+        Err(e) => return e,
+    };
+    let how = match how {
+        0 => Shutdown::Read,
+        1 => Shutdown::Write,
+        2 => Shutdown::Both,
+        _ => return -EINVAL,
+    };
+    match &io.socket {
+        Some(Socket::Stream(stream)) => stream.shutdown(how).map_or_else(errno, |_| 0),
+        _ => -ENOTCONN,
+    }
+}
+
+pub fn getsockname(fds: &FdTable, memory: &mut [u8], fd: i64, addr: i64, len: i64) -> Result<i64> {
+    let io = match socket_of(fds, fd) {
+        Ok(io) => io,
+        Err(e) => return Ok(e),
+    };
+    let local = match &io.socket {
+        Some(Socket::Unbound) => Ok(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into()),
+        Some(Socket::Bound(addr)) => Ok((*addr).into()),
+        Some(Socket::Listener(listener)) => listener.local_addr(),
+        Some(Socket::Stream(stream)) => stream.local_addr(),
+        None => unreachable!(),
+    };
+    match local {
+        Ok(local) => write_addr(memory, addr, len, local).map(|_| 0),
+        Err(e) => Ok(errno(e)),
+    }
+}
+
+/// Options are accepted and ignored. std::net already sets SO_REUSEADDR on listeners,
+/// which is what programs almost always ask for.
+pub fn setsockopt(fds: &FdTable, fd: i64) -> i64 {
+    match socket_of(fds, fd) {
+        Ok(_) => 0,
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,232 @@
+//! Best-effort process control for simulated programs.
+//!
+//! There is no way to fork the simulator itself, so fork snapshots the
+//! simulation and runs the child branch first. When the child calls execve
+//! the command is spawned on the host and the parent resumes with its pid;
+//! when the child exits the parent resumes with a made-up pid that wait4
+//! reports the exit status for. Children that do anything else long-lived,
+//! like serving requests, can't be emulated this way.
+
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, Stdio};
+
+use anyhow::Result;
+
+use super::syscalls::{errno, load, read_cstr, region, store};
+use super::{FdTable, SimulationState};
+use crate::instruction::SyscallKind;
+
+const ECHILD: i64 = 10;
+const EINVAL: i64 = 22;
+
+const WNOHANG: i64 = 1;
+
+/// One past the largest pid Linux hands out, so emulated pids never collide with real ones.
+const EMULATED_PID_BASE: i64 = 1 << 22;
+
+enum Exec {
+    /// The call failed and returns this to the program
+    Failed(i64),
+    /// The forked branch was replaced and its parent is running again
+    Resumed,
+    /// The whole simulation was replaced by a program that exited with this code
+    Exited(i32),
+}
+
+enum Child {
+    Spawned(std::process::Child),
+    /// A forked branch that exited without exec'ing, with its wait status
+    Exited {
+        pid: i64,
+        status: i32,
+    },
+}
+
+impl Child {
+    fn pid(&self) -> i64 {
+        match self {
+            Child::Spawned(child) => child.id() as i64,
+            Child::Exited { pid, .. } => *pid,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Processes {
+    /// Parents waiting for their forked branch to exec or exit, innermost last
+    forks: Vec<SimulationState>,
+    children: Vec<Child>,
+    emulated: i64,
+}
+
+/// Handle fork, vfork, execve and wait4, plus exit inside a forked branch. These
+/// replace or copy the whole state, so they can't go through the field borrows
+/// `sim_instruction` works with. Returns false for any other instruction.
+pub fn syscall(state: &mut SimulationState, kind: &SyscallKind) -> Result<bool> {
+    let Some(&number) = state.stack.last() else {
+        return Ok(false);
+    };
+    let argc = match (kind, number) {
+        (SyscallKind::Syscall0, 57 | 58) => 0,
+        (SyscallKind::Syscall1, 60 | 231) if !state.processes.forks.is_empty() => 1,
+        (SyscallKind::Syscall3, 59) => 3,
+        (SyscallKind::Syscall4, 61) => 4,
+        _ => return Ok(false),
+    };
+    if state.stack.len() <= argc {
+        // Leave it to the usual stack underflow error
+        return Ok(false);
+    }
+    let at = state.stack.len() - argc - 1;
+    let mut args = state.stack.split_off(at);
+    args.pop();
+    args.reverse();
+    match number {
+        57 | 58 => fork(state),
+        60 | 231 => exit(state, args[0]),
+        59 => match execve(state, args[0], args[1], args[2])? {
+            Exec::Failed(e) => state.stack.push(e),
+            Exec::Resumed => {}
+            Exec::Exited(code) => {
+                state.exit_code = Some(code);
+                return Ok(true);
+            }
+        },
+        _ => {
+            let result = wait4(
+                &mut state.processes,
+                &mut state.memory,
+                args[0],
+                args[1],
+                args[2],
+            )?;
+            state.stack.push(result);
+        }
+    }
+    state.ip += 1;
+    Ok(true)
+}
+
+/// Start running the child branch of a fork, keeping a copy of the parent to return to.
+fn fork(state: &mut SimulationState) {
+    let parent = state.snapshot();
+    state.processes.forks.push(parent);
+    state.stack.push(0);
+}
+
+/// Return to the innermost parent, which sees `child` as the result of its fork.
+fn resume_parent(state: &mut SimulationState, child: Child) {
+    let Some(parent) = state.processes.forks.pop() else {
+        return;
+    };
+    let processes = std::mem::take(&mut state.processes);
+    *state = parent;
+    state.processes = processes;
+    state.stack.push(child.pid());
+    state.processes.children.push(child);
+}
+
+/// Exit the current forked branch.
+fn exit(state: &mut SimulationState, code: i64) {
+    state.processes.emulated += 1;
+    let child = Child::Exited {
+        pid: EMULATED_PID_BASE + state.processes.emulated,
+        status: ((code & 0xff) << 8) as i32,
+    };
+    resume_parent(state, child)
+}
+
+/// Read a null-terminated array of string pointers.
+fn read_strings(memory: &[u8], ptr: i64) -> Result<Vec<OsString>> {
+    let mut strings = Vec::new();
+    if ptr == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let addr = region(memory, ptr + strings.len() as i64 * 8, 8, "argv")?;
+        match load(memory, addr, 8) as i64 {
+            0 => return Ok(strings),
+            str_ptr => strings.push(read_cstr(memory, str_ptr)?.into_os_string()),
+        }
+    }
+}
+
+/// Pass a standard stream through to the child if the program redirected it to a file.
+fn stdio(fds: &FdTable, fd: i64) -> Stdio {
+    fds.get(fd)
+        .and_then(|io| io.file.as_ref().and_then(|file| file.try_clone().ok()))
+        .map_or(Stdio::inherit(), Stdio::from)
+}
+
+/// Spawn the program, then either resume the parent of a fork or, like a real
+/// exec, finish the simulation with the program's status.
+fn execve(state: &mut SimulationState, path: i64, argv: i64, envp: i64) -> Result<Exec> {
+    let path = read_cstr(&state.memory, path)?;
+    let argv = read_strings(&state.memory, argv)?;
+    let envp = read_strings(&state.memory, envp)?;
+    let mut command = Command::new(&path);
+    if let Some((arg0, args)) = argv.split_first() {
+        command.arg0(arg0).args(args);
+    }
+    command.env_clear();
+    for var in envp {
+        let var = var.into_vec();
+        let Some(eq) = var.iter().position(|&b| b == b'=') else {
+            return Ok(Exec::Failed(-EINVAL));
+        };
+        command.env(
+            OsString::from_vec(var[..eq].to_vec()),
+            OsString::from_vec(var[eq + 1..].to_vec()),
+        );
+    }
+    command
+        .stdin(stdio(&state.fds, 0))
+        .stdout(stdio(&state.fds, 1))
+        .stderr(stdio(&state.fds, 2));
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => return Ok(Exec::Failed(errno(e))),
+    };
+    if state.processes.forks.is_empty() {
+        let status = child.wait()?;
+        return Ok(Exec::Exited(
+            status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+        ));
+    }
+    resume_parent(state, Child::Spawned(child));
+    Ok(Exec::Resumed)
+}
+
+fn wait4(
+    processes: &mut Processes,
+    memory: &mut [u8],
+    pid: i64,
+    wstatus: i64,
+    options: i64,
+) -> Result<i64> {
+    let Some(i) = processes
+        .children
+        .iter()
+        .position(|child| pid == -1 || child.pid() == pid)
+    else {
+        return Ok(-ECHILD);
+    };
+    let status = match &mut processes.children[i] {
+        Child::Spawned(child) if options & WNOHANG != 0 => match child.try_wait()? {
+            Some(status) => status.into_raw(),
+            None => return Ok(0),
+        },
+        Child::Spawned(child) => child.wait()?.into_raw(),
+        Child::Exited { status, .. } => *status,
+    };
+    let child = processes.children.remove(i);
+    if wstatus != 0 {
+        let addr = region(memory, wstatus, 4, "wstatus")?;
+        store(memory, addr, status as u32 as u64, 4);
+    }
+    Ok(child.pid())
+}
@@ -0,0 +1,49 @@
+//! `--profile`, how often each source line ran.
+
+use std::collections::HashMap;
+
+use crate::instruction::Instruction;
+
+pub struct Profile {
+    /// Executions of each instruction, by ip
+    counts: Vec<u64>,
+    top: usize,
+}
+
+impl Profile {
+    pub fn new(program: &[Instruction], top: usize) -> Self {
+        Self {
+            counts: vec![0; program.len()],
+            top,
+        }
+    }
+
+    pub fn record(&mut self, ip: usize) {
+        self.counts[ip] += 1;
+    }
+
+    /// Print the `top` lines that ran the most instructions.
+    pub fn report(&self, program: &[Instruction]) {
+        let mut lines: HashMap<(&str, usize), u64> = HashMap::new();
+        for (inst, &count) in program.iter().zip(&self.counts) {
+            if count > 0 {
+                *lines.entry((&inst.loc.0, inst.loc.1)).or_default() += count;
+            }
+        }
+        let total: u64 = self.counts.iter().sum();
+        let mut lines: Vec<_> = lines.into_iter().collect();
+        // Ties sorted by location so reports of the same program can be compared
+        lines.sort_by(|(a_loc, a), (b_loc, b)| b.cmp(a).then(a_loc.cmp(b_loc)));
+        eprintln!("{:>12} {:>6}  line", "instructions", "%");
+        for ((file, line), count) in lines.into_iter().take(self.top) {
+            eprintln!(
+                "{:>12} {:>5.1}%  {}:{}",
+                count,
+                count as f64 * 100.0 / total.max(1) as f64,
+                file,
+                line
+            );
+        }
+        eprintln!("{:>12} total", total);
+    }
+}
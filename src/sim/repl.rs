@@ -0,0 +1,268 @@
+//! `worthc repl`, which simulates a line at a time on top of the ones before
+//! it. The lines are kept as the session's source, so what they declare stays
+//! declared, and the stack and memory carry over from one to the next. A line
+//! that fails to check or run is left out, and undoes what it did to them.
+//!
+//! Lines starting with `:` are commands, see `HELP`. One that opens a block
+//! without closing it is continued on the next.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+use super::debugger::{bytes, hexdump, parse_addr, print_stack};
+use super::SimulationState;
+use crate::{
+    cli::TypecheckOptions,
+    error::{Error::IOError, Error::PreprocessorError, IOError::*, PreprocessorError::*},
+    instruction::{Instruction, Memory},
+    program::load_source,
+    typecheck::{self, ValType},
+};
+
+/// What the session's program is called, and where it's taken to be
+const NAME: &str = "repl";
+/// Room for the memories a session declares, which can't move once it runs
+const MEMORIES_CAPACITY: usize = 1 << 20;
+/// Where the lines entered are remembered between sessions, in the home directory
+const HISTORY: &str = ".worthc_history";
+
+const HELP: &str = "Commands:
+  :stack              print the stack, top first
+  :type               print the types on the stack, top last
+  :mem <addr> <len>   hexdump memory, at an address or a memory's name
+  :reset              forget everything and start over
+  :load <file>        run a file as if it were entered
+  :save <file>        write the lines entered so far to a file
+  :help               print this
+  :quit               leave, like ctrl-d
+Addresses are decimal, 0x hex, or str, argv, mem or a memory's name with an
+optional +offset.";
+
+pub struct Session {
+    /// Every line that ran, in order
+    pub source: String,
+    instructions: Vec<Instruction>,
+    memories: Vec<Memory>,
+    pub state: SimulationState,
+    /// What's on the stack, unless typechecking is off
+    pub types: Vec<ValType>,
+    typecheck: Option<TypecheckOptions>,
+    search_path: Vec<PathBuf>,
+    dir: PathBuf,
+}
+
+impl Session {
+    /// A session with nothing entered yet, typechecked with `typecheck` unless
+    /// it's `None`, including files from the current directory and `search_path`.
+    pub fn new(typecheck: Option<TypecheckOptions>, search_path: Vec<PathBuf>) -> Result<Self> {
+        Ok(Self {
+            source: String::new(),
+            instructions: Vec::new(),
+            memories: Vec::new(),
+            state: Self::start()?,
+            types: Vec::new(),
+            typecheck,
+            search_path,
+            dir: std::env::current_dir().map_err(|e| IOError(Inherited(e)))?,
+        })
+    }
+
+    fn start() -> Result<SimulationState> {
+        Ok(SimulationState::new(&[], &[NAME.to_string()])?
+            .with_memories_capacity(MEMORIES_CAPACITY))
+    }
+
+    /// Forget everything entered.
+    pub fn reset(&mut self) -> Result<()> {
+        self.source.clear();
+        self.instructions.clear();
+        self.memories.clear();
+        self.types.clear();
+        self.state = Self::start()?;
+        Ok(())
+    }
+
+    /// Run `source` after everything before it. Returns the exit code if it
+    /// exited, which starts the session over.
+    pub fn run(&mut self, source: &str) -> Result<Option<i32>> {
+        let candidate = format!("{}{}\n", self.source, source);
+        let path = self.dir.join(format!("{}.porth", NAME));
+        let program = load_source(candidate.clone(), NAME, path, self.search_path.clone())?;
+        let types = match &self.typecheck {
+            Some(opt) => typecheck::leaves(&program, opt)?.unwrap_or_default(),
+            None => Vec::new(),
+        };
+        let ran = &self.instructions;
+        let unchanged = program.instructions.len() >= ran.len()
+            && ran
+                .iter()
+                .zip(&program.instructions)
+                .all(|(a, b)| a.loc == b.loc && a.kind.to_string() == b.kind.to_string());
+        if !unchanged {
+            anyhow::bail!("That changes code that already ran, :reset to start over");
+        }
+        let memories = program
+            .memories
+            .iter()
+            .map(|memory| memory.offset + memory.reserved())
+            .max()
+            .unwrap_or(0);
+        if memories > MEMORIES_CAPACITY {
+            anyhow::bail!(
+                "The session's memories need {} bytes, but only {} fit",
+                memories,
+                MEMORIES_CAPACITY
+            );
+        }
+
+        let before = self.state.snapshot();
+        if let Err(e) = self.state.step(&program.instructions, usize::MAX) {
+            self.state.restore(&before);
+            return Err(e);
+        }
+        if let Some(code) = self.state.exit_code {
+            self.reset()?;
+            return Ok(Some(code));
+        }
+        self.source = candidate;
+        self.instructions = program.instructions;
+        self.memories = program.memories;
+        self.types = types;
+        Ok(None)
+    }
+
+    /// `addr` as `parse_addr` reads it, or a declared memory's name with an
+    /// optional `+offset`.
+    fn addr(&self, addr: &str) -> Option<usize> {
+        let (name, offset) = addr.split_once('+').unwrap_or((addr, "0"));
+        match self.memories.iter().find(|memory| memory.name == name) {
+            Some(memory) => {
+                parse_addr(offset).map(|offset| self.state.memories + memory.offset + offset)
+            }
+            None => parse_addr(addr),
+        }
+    }
+
+    /// Do the command in `line`, after its `:`. Returns false for `:quit`.
+    pub fn command(&mut self, line: &str) -> Result<bool> {
+        let mut args = line.split_whitespace();
+        match (args.next(), args.next(), args.next()) {
+            (Some("stack"), ..) => print_stack(&self.state.stack, self.state.mem_end),
+            (Some("type"), ..) if self.typecheck.is_none() => {
+                println!("Typechecking is off, so there are no types")
+            }
+            (Some("type"), ..) if self.types.is_empty() => println!("Stack is empty"),
+            (Some("type"), ..) => {
+                let types: Vec<_> = self.types.iter().map(ToString::to_string).collect();
+                println!("{}", types.join(" "));
+            }
+            (Some("mem"), Some(addr), Some(len)) => match (self.addr(addr), parse_addr(len)) {
+                (Some(addr), Some(len)) => match bytes(&self.state.memory, addr, len) {
+                    Some(bytes) => hexdump(addr, bytes),
+                    None => println!("{} bytes at {:#x} are out of bounds", len, addr),
+                },
+                _ => println!("Invalid address {} or length {}", addr, len),
+            },
+            (Some("reset"), ..) => self.reset()?,
+            (Some("load"), Some(file), _) => {
+                let source = std::fs::read_to_string(file)
+                    .map_err(|e| IOError(Inherited(e)))
+                    .with_context(|| format!("Could not read {}", file))?;
+                if let Some(code) = self.run(source.trim_end())? {
+                    println!("Exited with {}, starting over", code);
+                }
+            }
+            (Some("save"), Some(file), _) => std::fs::write(file, &self.source)
+                .map_err(|e| IOError(Inherited(e)))
+                .with_context(|| format!("Could not write {}", file))?,
+            (Some("q" | "quit"), ..) => return Ok(false),
+            (Some("h" | "help"), ..) => println!("{}", HELP),
+            (Some(cmd @ ("mem" | "load" | "save")), ..) => {
+                println!("Missing arguments to :{}, try :help", cmd)
+            }
+            (cmd, ..) => println!("Unknown command :{}, try :help", cmd.unwrap_or_default()),
+        }
+        Ok(true)
+    }
+}
+
+/// Whether `err` is only that a block isn't closed yet.
+fn unclosed(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref(),
+        Some(PreprocessorError(UnclosedBlock(_)))
+    )
+}
+
+fn history() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY))
+}
+
+/// Read lines and run them until stdin ends or `:quit`, after running `file`
+/// if there is one.
+pub fn run(
+    typecheck: Option<TypecheckOptions>,
+    search_path: Vec<PathBuf>,
+    file: Option<&Path>,
+) -> Result<()> {
+    let mut session = Session::new(typecheck, search_path)?;
+    if let Some(file) = file {
+        session.command(&format!("load {}", file.to_string_lossy()))?;
+    }
+    let mut editor = DefaultEditor::new().context("Could not start reading lines")?;
+    let history = history();
+    if let Some(history) = &history {
+        // There's none the first time
+        let _ = editor.load_history(history);
+    }
+    let mut pending = String::new();
+    loop {
+        let prompt = if pending.is_empty() {
+            "worth> "
+        } else {
+            "...> "
+        };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                pending.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e).context("Could not read a line"),
+        };
+        if line.trim().is_empty() && pending.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        if pending.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                match session.command(command) {
+                    Ok(true) => continue,
+                    Ok(false) => break,
+                    Err(e) => {
+                        eprintln!("Error: {:?}", e);
+                        continue;
+                    }
+                }
+            }
+        }
+        pending.push_str(&line);
+        match session.run(&pending) {
+            Err(e) if unclosed(&e) => {
+                pending.push('\n');
+                continue;
+            }
+            Err(e) => eprintln!("Error: {:?}", e),
+            Ok(Some(code)) => println!("Exited with {}, starting over", code),
+            Ok(None) => {}
+        }
+        pending.clear();
+    }
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
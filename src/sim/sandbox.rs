@@ -0,0 +1,87 @@
+//! `--sandbox`, syscalls an untrusted program isn't allowed to make.
+//!
+//! Denied syscalls return -EPERM without doing anything, like a seccomp filter
+//! would. Reading files, stdio, memory, time and randomness are always allowed.
+
+use super::SimulationState;
+use crate::cli::Permission;
+use crate::instruction::SyscallKind;
+use crate::log::{self, LogLevel::Warn};
+
+const EPERM: i64 = 1;
+
+const O_ACCMODE: i64 = 0o3;
+const O_CREAT: i64 = 0o100;
+const O_TRUNC: i64 = 0o1000;
+const O_APPEND: i64 = 0o2000;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sandbox {
+    write: bool,
+    net: bool,
+    process: bool,
+}
+
+impl Sandbox {
+    pub fn new(allow: &[Permission]) -> Self {
+        Self {
+            write: allow.contains(&Permission::Write),
+            net: allow.contains(&Permission::Net),
+            process: allow.contains(&Permission::Process),
+        }
+    }
+
+    fn allows(&self, number: i64, args: &[i64]) -> bool {
+        let writes = |flags: i64| flags & (O_ACCMODE | O_CREAT | O_TRUNC | O_APPEND) != 0;
+        match (number, args) {
+            // Open and openat
+            (2, [_, flags, ..]) | (257, [_, _, flags, ..]) => self.write || !writes(*flags),
+            // Unlink
+            (87, _) => self.write,
+            // Socket, connect, accept, send, recv, shutdown, bind, listen,
+            // getsockname, setsockopt and accept4
+            (41..=51 | 54 | 288, _) => self.net,
+            // Fork, vfork, execve and wait4
+            (57..=59 | 61, _) => self.process,
+            _ => true,
+        }
+    }
+}
+
+/// Fail the syscall about to run with -EPERM if the sandbox denies it. Returns
+/// false if it's allowed, or anything other than a syscall.
+pub fn syscall(state: &mut SimulationState, kind: &SyscallKind) -> bool {
+    let Some(sandbox) = state.sandbox else {
+        return false;
+    };
+    let argc = match kind {
+        SyscallKind::Syscall0 => 0,
+        SyscallKind::Syscall1 => 1,
+        SyscallKind::Syscall2 => 2,
+        SyscallKind::Syscall3 => 3,
+        SyscallKind::Syscall4 => 4,
+        SyscallKind::Syscall5 => 5,
+        SyscallKind::Syscall6 => 6,
+    };
+    if state.stack.len() <= argc {
+        // Leave it to the usual stack underflow error
+        return false;
+    }
+    // The number is on top, then the first argument
+    let args: Vec<i64> = state.stack.iter().rev().take(argc + 1).copied().collect();
+    if sandbox.allows(args[0], &args[1..]) {
+        return false;
+    }
+    log::log(
+        Warn,
+        format!(
+            "Sandbox denied syscall {} at instruction {}",
+            args[0], state.ip
+        ),
+        false,
+    );
+    state.stack.truncate(state.stack.len() - argc - 1);
+    state.stack.push(-EPERM);
+    state.ip += 1;
+    true
+}
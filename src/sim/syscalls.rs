@@ -0,0 +1,528 @@
+//! Host implementations of the syscalls available to simulated programs.
+//!
+//! Like the kernel, these report failure by returning `-errno` to the program
+//! instead of aborting the simulation.
+
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::{Error::RuntimeError, RuntimeError::*};
+use crate::log::{self, LogLevel};
+use anyhow::{Context, Result};
+
+use super::fds::{self, FdTable, MAX_FDS};
+use super::BinaryIO;
+
+const EBADF: i64 = 9;
+const EIO: i64 = 5;
+const ESPIPE: i64 = 29;
+const EINVAL: i64 = 22;
+const EMFILE: i64 = 24;
+const ENOSYS: i64 = 38;
+
+const O_CLOEXEC: i64 = 0o2000000;
+const O_APPEND: i64 = 0o2000;
+const O_NONBLOCK: i64 = 0o4000;
+
+const F_DUPFD: i64 = 0;
+const F_GETFD: i64 = 1;
+const F_SETFD: i64 = 2;
+const F_GETFL: i64 = 3;
+const F_SETFL: i64 = 4;
+const F_DUPFD_CLOEXEC: i64 = 1030;
+const FD_CLOEXEC: i64 = 1;
+
+const O_ACCMODE: i64 = 0o3;
+const O_WRONLY: i64 = 0o1;
+const O_RDWR: i64 = 0o2;
+const AT_FDCWD: i64 = -100;
+
+const CLOCK_REALTIME: i64 = 0;
+const CLOCK_MONOTONIC: i64 = 1;
+const CLOCK_MONOTONIC_RAW: i64 = 4;
+const CLOCK_REALTIME_COARSE: i64 = 5;
+const CLOCK_MONOTONIC_COARSE: i64 = 6;
+const CLOCK_BOOTTIME: i64 = 7;
+const TIMER_ABSTIME: i64 = 1;
+
+const STAT_SIZE: usize = 144;
+const TIMESPEC_SIZE: usize = 16;
+
+pub(super) fn errno(e: io::Error) -> i64 {
+    -(e.raw_os_error().map(i64::from).unwrap_or(EIO))
+}
+
+/// Read the null-terminated path starting at `ptr` in simulator memory.
+pub fn read_cstr(memory: &[u8], ptr: i64) -> Result<PathBuf> {
+    let start = usize::try_from(ptr)
+        .ok()
+        .filter(|&start| start < memory.len())
+        .ok_or(RuntimeError(InvalidMemoryAccess))
+        .with_context(|| format!("Invalid string pointer: {:x}", ptr))?;
+    let len = memory[start..]
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(RuntimeError(InvalidMemoryAccess))
+        .with_context(|| format!("Unterminated string at {:x}", ptr))?;
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(
+        &memory[start..start + len],
+    )))
+}
+
+/// Check that the `len` byte struct at `ptr` lies inside simulator memory.
+pub(super) fn region(memory: &[u8], ptr: i64, len: usize, what: &str) -> Result<usize> {
+    usize::try_from(ptr)
+        .ok()
+        .filter(|&addr| addr + len <= memory.len())
+        .ok_or(RuntimeError(InvalidMemoryAccess))
+        .with_context(|| format!("Invalid {} buffer: {:x}", what, ptr))
+}
+
+/// Store `size` bytes of `val` at `addr`, little-endian like x86_64.
+pub(super) fn store(memory: &mut [u8], addr: usize, val: u64, size: usize) {
+    for i in 0..size {
+        memory[addr + i] = (val >> (i * 8)) as u8;
+    }
+}
+
+/// Load `size` bytes at `addr`, little-endian like x86_64.
+pub(super) fn load(memory: &[u8], addr: usize, size: usize) -> u64 {
+    (0..size)
+        .rev()
+        .fold(0, |val, i| val << 8 | memory[addr + i] as u64)
+}
+
+/// Fill in a `struct stat` as laid out by x86_64 Linux.
+fn write_stat(memory: &mut [u8], ptr: i64, meta: &Metadata) -> Result<()> {
+    let addr = region(memory, ptr, STAT_SIZE, "stat")?;
+    memory[addr..addr + STAT_SIZE].fill(0);
+    store(memory, addr, meta.dev(), 8);
+    store(memory, addr + 8, meta.ino(), 8);
+    store(memory, addr + 16, meta.nlink(), 8);
+    store(memory, addr + 24, meta.mode() as u64, 4);
+    store(memory, addr + 28, meta.uid() as u64, 4);
+    store(memory, addr + 32, meta.gid() as u64, 4);
+    store(memory, addr + 40, meta.rdev(), 8);
+    store(memory, addr + 48, meta.size(), 8);
+    store(memory, addr + 56, meta.blksize(), 8);
+    store(memory, addr + 64, meta.blocks(), 8);
+    store(memory, addr + 72, meta.atime() as u64, 8);
+    store(memory, addr + 80, meta.atime_nsec() as u64, 8);
+    store(memory, addr + 88, meta.mtime() as u64, 8);
+    store(memory, addr + 96, meta.mtime_nsec() as u64, 8);
+    store(memory, addr + 104, meta.ctime() as u64, 8);
+    store(memory, addr + 112, meta.ctime_nsec() as u64, 8);
+    Ok(())
+}
+
+pub fn open(fds: &mut FdTable, memory: &[u8], path: i64, flags: i64, mode: i64) -> Result<i64> {
+    let path = read_cstr(memory, path)?;
+    let mut options = OpenOptions::new();
+    match flags & O_ACCMODE {
+        O_WRONLY => options.write(true),
+        O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+    options
+        .custom_flags((flags & !O_ACCMODE) as i32)
+        .mode(mode as u32);
+    let file = match options.open(path) {
+        Ok(file) => file,
+        Err(e) => return Ok(errno(e)),
+    };
+    let cloexec = flags & O_CLOEXEC != 0;
+    let io = BinaryIO::file(file, flags & !O_CLOEXEC);
+    let Some(fd) = fds.insert(io) else {
+        return Ok(-EMFILE);
+    };
+    fds.fd_mut(fd).unwrap().cloexec = cloexec;
+    Ok(fd)
+}
+
+pub fn openat(
+    fds: &mut FdTable,
+    memory: &[u8],
+    dirfd: i64,
+    path: i64,
+    flags: i64,
+    mode: i64,
+) -> Result<i64> {
+    if dirfd != AT_FDCWD && !read_cstr(memory, path)?.is_absolute() {
+        // Descriptors don't remember their paths, so only the working directory is supported
+        return Ok(-EINVAL);
+    }
+    open(fds, memory, path, flags, mode)
+}
+
+pub fn close(fds: &mut FdTable, fd: i64) -> i64 {
+    if fds.close(fd) {
+        0
+    } else {
+        -EBADF
+    }
+}
+
+pub fn lseek(fds: &FdTable, fd: i64, offset: i64, whence: i64) -> i64 {
+    let Some(mut io) = fds.get(fd) else {
+        return -EBADF;
+    };
+    // Only files opened by the program itself can be seeked
+    let Some(file) = io.file.as_mut() else {
+        return -ESPIPE;
+    };
+    let pos = match whence {
+        0 if offset >= 0 => SeekFrom::Start(offset as u64),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return -EINVAL,
+    };
+    match file.seek(pos) {
+        Ok(pos) => pos as i64,
+        Err(e) => errno(e),
+    }
+}
+
+pub fn stat(memory: &mut [u8], path: i64, buf: i64) -> Result<i64> {
+    match std::fs::metadata(read_cstr(memory, path)?) {
+        Ok(meta) => write_stat(memory, buf, &meta).map(|_| 0),
+        Err(e) => Ok(errno(e)),
+    }
+}
+
+pub fn lstat(memory: &mut [u8], path: i64, buf: i64) -> Result<i64> {
+    match std::fs::symlink_metadata(read_cstr(memory, path)?) {
+        Ok(meta) => write_stat(memory, buf, &meta).map(|_| 0),
+        Err(e) => Ok(errno(e)),
+    }
+}
+
+pub fn fstat(fds: &FdTable, memory: &mut [u8], fd: i64, buf: i64) -> Result<i64> {
+    let Some(io) = fds.get(fd) else {
+        return Ok(-EBADF);
+    };
+    let meta = match &io.file {
+        Some(file) => file.metadata(),
+        // The standard streams are the simulator's own
+        None if fd <= 2 => std::fs::metadata(format!("/proc/self/fd/{}", fd)),
+        None => return Ok(-EINVAL),
+    };
+    match meta {
+        Ok(meta) => write_stat(memory, buf, &meta).map(|_| 0),
+        Err(e) => Ok(errno(e)),
+    }
+}
+
+pub fn dup(fds: &mut FdTable, fd: i64) -> i64 {
+    let Some(old) = fds.fd(fd) else {
+        return -EBADF;
+    };
+    let new = fds::Fd {
+        cloexec: false,
+        ..old.clone()
+    };
+    fds.insert_from(0, new).unwrap_or(-EMFILE)
+}
+
+/// Dup2 when `flags` is `None`, otherwise dup3.
+pub fn dup3(fds: &mut FdTable, fd: i64, new_fd: i64, flags: Option<i64>) -> i64 {
+    let (Some(old), Ok(slot)) = (fds.fd(fd), usize::try_from(new_fd)) else {
+        return -EBADF;
+    };
+    if slot >= MAX_FDS {
+        return -EBADF;
+    }
+    match flags {
+        Some(flags) if flags & !O_CLOEXEC != 0 || fd == new_fd => return -EINVAL,
+        None if fd == new_fd => return new_fd,
+        _ => {}
+    }
+    let new = fds::Fd {
+        cloexec: flags.unwrap_or(0) & O_CLOEXEC != 0,
+        ..old.clone()
+    };
+    fds.insert_at(slot, new);
+    new_fd
+}
+
+/// Write the (read, write) descriptors of a new pipe as two ints at `buf`.
+pub fn pipe(fds: &mut FdTable, memory: &mut [u8], buf: i64, flags: i64) -> Result<i64> {
+    let addr = region(memory, buf, 8, "pipe")?;
+    if flags & !(O_CLOEXEC | O_NONBLOCK) != 0 {
+        return Ok(-EINVAL);
+    }
+    let (reader, writer) = fds::pipe();
+    let Some(read_fd) = fds.insert(reader) else {
+        return Ok(-EMFILE);
+    };
+    let Some(write_fd) = fds.insert(writer) else {
+        fds.close(read_fd);
+        return Ok(-EMFILE);
+    };
+    for fd in [read_fd, write_fd] {
+        let fd = fds.fd_mut(fd).unwrap();
+        fd.cloexec = flags & O_CLOEXEC != 0;
+        fd.io.borrow_mut().flags |= flags & O_NONBLOCK;
+    }
+    store(memory, addr, read_fd as u64, 4);
+    store(memory, addr + 4, write_fd as u64, 4);
+    Ok(0)
+}
+
+pub fn fcntl(fds: &mut FdTable, fd: i64, cmd: i64, arg: i64) -> i64 {
+    let Some(entry) = fds.fd_mut(fd) else {
+        return -EBADF;
+    };
+    match cmd {
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            let Ok(min) = usize::try_from(arg) else {
+                return -EINVAL;
+            };
+            let new = fds::Fd {
+                cloexec: cmd == F_DUPFD_CLOEXEC,
+                ..entry.clone()
+            };
+            fds.insert_from(min, new).unwrap_or(-EMFILE)
+        }
+        F_GETFD => entry.cloexec as i64 * FD_CLOEXEC,
+        F_SETFD => {
+            entry.cloexec = arg & FD_CLOEXEC != 0;
+            0
+        }
+        F_GETFL => entry.io.borrow().flags,
+        F_SETFL => {
+            // Only recorded, the simulator never blocks and appends aren't forwarded to the host
+            let mut io = entry.io.borrow_mut();
+            io.flags = io.flags & !(O_APPEND | O_NONBLOCK) | arg & (O_APPEND | O_NONBLOCK);
+            0
+        }
+        _ => -EINVAL,
+    }
+}
+
+pub fn unlink(memory: &[u8], path: i64) -> Result<i64> {
+    match std::fs::remove_file(read_cstr(memory, path)?) {
+        Ok(()) => Ok(0),
+        Err(e) => Ok(errno(e)),
+    }
+}
+
+/// `--deterministic`: seeded random bytes, and a clock that starts at a fixed
+/// time and moves a millisecond every time it's read, or as far as a sleep asks.
+#[derive(Clone)]
+pub struct Deterministic {
+    rng: u64,
+    /// Time since the fixed start
+    elapsed: Duration,
+}
+
+impl Deterministic {
+    /// 2000-01-01T00:00:00Z, the realtime clock's start
+    const EPOCH: Duration = Duration::from_secs(946_684_800);
+    const TICK: Duration = Duration::from_millis(1);
+
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: seed,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// splitmix64
+    fn next(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// The current time on `clock`, or `None` for clocks the host can't provide.
+fn now(det: &mut Option<Deterministic>, clock: i64) -> Option<Duration> {
+    // Monotonic clocks count from the first time any of them is read
+    static START: OnceLock<Instant> = OnceLock::new();
+    let realtime = matches!(clock, CLOCK_REALTIME | CLOCK_REALTIME_COARSE);
+    let monotonic = matches!(
+        clock,
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME
+    );
+    match det {
+        Some(det) if realtime || monotonic => {
+            det.elapsed += Deterministic::TICK;
+            Some(
+                det.elapsed
+                    + if realtime {
+                        Deterministic::EPOCH
+                    } else {
+                        Duration::ZERO
+                    },
+            )
+        }
+        _ if realtime => SystemTime::now().duration_since(UNIX_EPOCH).ok(),
+        _ if monotonic => Some(START.get_or_init(Instant::now).elapsed()),
+        _ => None,
+    }
+}
+
+/// Sleep on the host, or just move the deterministic clock forward.
+fn sleep(det: &mut Option<Deterministic>, time: Duration) {
+    match det {
+        Some(det) => det.elapsed += time,
+        None => std::thread::sleep(time),
+    }
+}
+
+fn read_timespec(memory: &[u8], ptr: i64) -> Result<Option<Duration>> {
+    let addr = region(memory, ptr, TIMESPEC_SIZE, "timespec")?;
+    let (sec, nsec) = (
+        load(memory, addr, 8) as i64,
+        load(memory, addr + 8, 8) as i64,
+    );
+    if sec < 0 || !(0..1_000_000_000).contains(&nsec) {
+        return Ok(None);
+    }
+    Ok(Some(Duration::new(sec as u64, nsec as u32)))
+}
+
+/// Write `time` as a seconds/sub-seconds pair, with `unit` nanoseconds per sub-second.
+fn write_time(memory: &mut [u8], ptr: i64, time: Duration, unit: u32) -> Result<()> {
+    let addr = region(memory, ptr, TIMESPEC_SIZE, "time")?;
+    store(memory, addr, time.as_secs(), 8);
+    store(memory, addr + 8, (time.subsec_nanos() / unit) as u64, 8);
+    Ok(())
+}
+
+pub fn clock_gettime(
+    memory: &mut [u8],
+    det: &mut Option<Deterministic>,
+    clock: i64,
+    buf: i64,
+) -> Result<i64> {
+    match now(det, clock) {
+        Some(time) => write_time(memory, buf, time, 1).map(|_| 0),
+        None => Ok(-EINVAL),
+    }
+}
+
+pub fn gettimeofday(
+    memory: &mut [u8],
+    det: &mut Option<Deterministic>,
+    tv: i64,
+    tz: i64,
+) -> Result<i64> {
+    if tv != 0 {
+        let time = now(det, CLOCK_REALTIME).unwrap_or_default();
+        write_time(memory, tv, time, 1000)?;
+    }
+    if tz != 0 {
+        // The timezone is always UTC
+        let addr = region(memory, tz, 8, "timezone")?;
+        memory[addr..addr + 8].fill(0);
+    }
+    Ok(0)
+}
+
+pub fn nanosleep(
+    memory: &mut [u8],
+    det: &mut Option<Deterministic>,
+    req: i64,
+    rem: i64,
+) -> Result<i64> {
+    let Some(time) = read_timespec(memory, req)? else {
+        return Ok(-EINVAL);
+    };
+    sleep(det, time);
+    if rem != 0 {
+        write_time(memory, rem, Duration::ZERO, 1)?;
+    }
+    Ok(0)
+}
+
+pub fn clock_nanosleep(
+    memory: &mut [u8],
+    det: &mut Option<Deterministic>,
+    clock: i64,
+    flags: i64,
+    req: i64,
+    rem: i64,
+) -> Result<i64> {
+    let (Some(time), Some(now)) = (read_timespec(memory, req)?, now(det, clock)) else {
+        return Ok(-EINVAL);
+    };
+    if flags & TIMER_ABSTIME != 0 {
+        sleep(det, time.saturating_sub(now));
+        return Ok(0);
+    }
+    sleep(det, time);
+    if rem != 0 {
+        write_time(memory, rem, Duration::ZERO, 1)?;
+    }
+    Ok(0)
+}
+
+/// What a syscall the simulator doesn't have returns, like it would from a
+/// kernel without it.
+pub fn unimplemented(args: usize, number: i64) -> i64 {
+    log::log(
+        LogLevel::Debug,
+        format!(
+            "syscall{} {} isn't simulated, it returns -ENOSYS",
+            args, number
+        ),
+        false,
+    );
+    -ENOSYS
+}
+
+pub fn getpid() -> i64 {
+    std::process::id() as i64
+}
+
+pub fn getppid() -> i64 {
+    std::os::unix::process::parent_id() as i64
+}
+
+/// Read a real (0) or effective (1) id from the `Uid:` or `Gid:` line of /proc/self/status.
+pub fn id(field: &str, which: usize) -> i64 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(field))
+        .and_then(|ids| ids.split_whitespace().nth(which))
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(-EIO)
+}
+
+pub fn getrandom(
+    memory: &mut [u8],
+    det: &mut Option<Deterministic>,
+    buf: i64,
+    len: i64,
+) -> Result<i64> {
+    let Ok(len) = usize::try_from(len) else {
+        return Ok(-EINVAL);
+    };
+    let addr = region(memory, buf, len, "getrandom")?;
+    if let Some(det) = det {
+        det.fill(&mut memory[addr..addr + len]);
+        return Ok(len as i64);
+    }
+    let filled =
+        File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut memory[addr..addr + len]));
+    match filled {
+        Ok(()) => Ok(len as i64),
+        Err(e) => Ok(errno(e)),
+    }
+}
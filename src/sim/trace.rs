@@ -0,0 +1,72 @@
+//! `--trace`, a line for every instruction the simulator runs.
+//!
+//! Lines have no step count or timestamp, so the traces of two runs can be
+//! diffed directly. A path ending in .gz is compressed by piping through gzip.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use anyhow::{Context, Result};
+
+use crate::error::err_loc;
+use crate::instruction::Instruction;
+
+pub struct Trace {
+    writer: Option<BufWriter<Box<dyn Write>>>,
+    gzip: Option<Child>,
+}
+
+impl Trace {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create simulation trace {:?}", path))?;
+        if path.extension().is_some_and(|ext| ext == "gz") {
+            let mut gzip = Command::new("gzip")
+                .arg("-c")
+                .stdin(Stdio::piped())
+                .stdout(file)
+                .spawn()
+                .context("Failed to start gzip for the simulation trace")?;
+            let stdin = gzip.stdin.take().map(|s| Box::new(s) as Box<dyn Write>);
+            Ok(Self {
+                writer: stdin.map(BufWriter::new),
+                gzip: Some(gzip),
+            })
+        } else {
+            Ok(Self {
+                writer: Some(BufWriter::new(Box::new(file))),
+                gzip: None,
+            })
+        }
+    }
+
+    /// Written before the instruction runs, so on error the last line is the one that failed.
+    pub fn record(&mut self, inst: &Instruction, depth: usize) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writeln!(
+                writer,
+                "{}: {} at {} depth {}",
+                inst.ip,
+                inst.kind,
+                err_loc(&inst.loc),
+                depth
+            )
+            .context("Failed to write simulation trace")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            let _ = writer.flush();
+        }
+        // gzip only finishes the file once its stdin is closed above
+        if let Some(gzip) = &mut self.gzip {
+            let _ = gzip.wait();
+        }
+    }
+}
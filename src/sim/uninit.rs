@@ -0,0 +1,133 @@
+//! `--uninit`, warnings for loads of memory that was never written.
+//!
+//! Memory starts out zeroed, so these reads don't fail, they just quietly get
+//! zeros. Strings and argv count as written; mem and the heap start out
+//! unwritten. Stores mark the bytes they write. Syscalls write memory in too many
+//! ways to list, so the memory they changed is found by comparing before and
+//! after, rounded out to whole words so zeros written next to other bytes still
+//! count. The fixed size structs a few syscalls fill in, where whole fields are
+//! often zero, are marked outright.
+
+use std::collections::HashSet;
+
+use super::{region_name, SimulationState, MEM_BUF_PTR};
+use crate::error::err_loc;
+use crate::instruction::{Instruction, InstructionKind, Op};
+use crate::log::{self, LogLevel::Warn};
+
+pub struct Uninit {
+    /// Whether each byte of memory was written
+    written: Vec<bool>,
+    /// Memory from `MEM_BUF_PTR` on, as of before the syscall being run
+    before: Option<Vec<u8>>,
+    /// Instructions already warned about, so loops only warn once
+    warned: HashSet<usize>,
+}
+
+impl Uninit {
+    pub fn new(memory: &[u8]) -> Self {
+        let mut written = vec![false; memory.len()];
+        written[..MEM_BUF_PTR].fill(true);
+        Self {
+            written,
+            before: None,
+            warned: HashSet::new(),
+        }
+    }
+
+    /// Check `inst` before it runs.
+    pub fn before(&mut self, inst: &Instruction, state: &SimulationState) {
+        self.written.resize(state.memory.len(), false);
+        let width = match inst.kind {
+            InstructionKind::Op(Op::Load | Op::Store) => 1,
+            InstructionKind::Op(Op::Load64 | Op::Store64) => 8,
+            InstructionKind::Syscall(_) => {
+                if let Some((addr, len)) = output(&state.stack) {
+                    let end = addr.saturating_add(len).min(self.written.len());
+                    if addr < end {
+                        self.written[addr..end].fill(true);
+                    }
+                }
+                self.before = Some(state.memory[MEM_BUF_PTR..].to_vec());
+                return;
+            }
+            _ => return,
+        };
+        let load = matches!(inst.kind, InstructionKind::Op(Op::Load | Op::Load64));
+        // Loads take the address from the top of the stack, stores from below the value
+        let depth = if load { 1 } else { 2 };
+        let Some(addr) = state
+            .stack
+            .len()
+            .checked_sub(depth)
+            .and_then(|i| usize::try_from(state.stack[i]).ok())
+        else {
+            return;
+        };
+        // Out of bounds accesses are reported when the instruction runs
+        let Some(bytes) = addr
+            .checked_add(width)
+            .and_then(|end| self.written.get_mut(addr..end))
+        else {
+            return;
+        };
+        if !load {
+            bytes.fill(true);
+        } else if bytes.contains(&false) && self.warned.insert(inst.ip) {
+            log::log(
+                Warn,
+                format!(
+                    "{} at {} read uninitialized memory at {:#x} in the {} region",
+                    inst.kind,
+                    err_loc(&inst.loc),
+                    addr,
+                    region_name(addr, state.mem_end)
+                ),
+                false,
+            );
+        }
+    }
+
+    /// Mark what the syscall that just ran wrote, if that's what it was.
+    pub fn after(&mut self, memory: &[u8]) {
+        let Some(before) = self.before.take() else {
+            return;
+        };
+        let after = &memory[MEM_BUF_PTR..];
+        let changed = |i: &usize| before.get(*i).is_some_and(|b| *b != after[*i]);
+        let mut i = 0;
+        while let Some(start) = (i..after.len()).find(changed) {
+            let mut end = start + 1;
+            while end < after.len() && (changed(&end) || !end.is_multiple_of(8)) {
+                end += 1;
+            }
+            let start = start - start % 8;
+            self.written[MEM_BUF_PTR + start..MEM_BUF_PTR + end].fill(true);
+            i = end;
+        }
+    }
+}
+
+/// The struct the syscall about to run fills in, if it's one that has a fixed size.
+fn output(stack: &[i64]) -> Option<(usize, usize)> {
+    // The number is on top, then the first argument
+    let arg = |n: usize| {
+        let i = stack.len().checked_sub(n + 1)?;
+        usize::try_from(stack[i]).ok()
+    };
+    let (ptr, len) = match stack.last()? {
+        // stat, fstat and lstat
+        4..=6 => (arg(2)?, 144),
+        // pipe and pipe2
+        22 | 293 => (arg(1)?, 8),
+        // nanosleep's remaining time
+        35 => (arg(2)?, 16),
+        // gettimeofday, ignoring the timezone
+        96 => (arg(1)?, 16),
+        228 => (arg(2)?, 16),
+        // clock_nanosleep's remaining time
+        230 => (arg(4)?, 16),
+        _ => return None,
+    };
+    (ptr != 0).then_some((ptr, len))
+}
@@ -0,0 +1,295 @@
+//! `worthc stats`, which says what a program is made of: how many of each
+//! kind of instruction it has, how much each macro adds once it's expanded,
+//! the strings it keeps and how big its executable is, to find what's making
+//! it big.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+
+use crate::{
+    cli::{CompilerOptions, StatsOptions},
+    codegen::{self, Size},
+    error::{Error::IOError, IOError::*},
+    instruction::{InstructionKind, Keyword, Program, Value},
+    json::Json,
+    optimize, parser, preprocessor,
+    program::load_program,
+};
+
+/// How much a macro adds to the program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacroStats {
+    pub name: String,
+    /// How many times it's expanded, counting inside other macros
+    pub uses: usize,
+    /// Instructions in its body as written
+    pub written: usize,
+    /// Instructions in its body once the macros in it are expanded too
+    pub expanded: usize,
+}
+
+impl MacroStats {
+    /// The instructions it adds to the program, all its uses together.
+    pub fn total(&self) -> usize {
+        self.uses * self.expanded
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub instructions: usize,
+    /// How many of each kind of instruction there are, most first
+    pub kinds: Vec<(String, usize)>,
+    /// The macros it uses, adding the most first
+    pub macros: Vec<MacroStats>,
+    /// How many different strings there are, and their bytes together
+    pub strings: (usize, usize),
+    /// `None` if it couldn't be built to find out
+    pub size: Option<Size>,
+}
+
+/// What kind of instruction `kind` is, in a word or two.
+fn kind(kind: &InstructionKind) -> String {
+    match kind {
+        InstructionKind::Push(Value::Int(_)) => "push int".to_string(),
+        InstructionKind::Push(Value::Str(_)) => "push string".to_string(),
+        InstructionKind::Push(Value::Char(_)) => "push char".to_string(),
+        InstructionKind::Push(Value::Ptr(_)) => "push ptr".to_string(),
+        InstructionKind::Push(Value::Bool(_)) => "push bool".to_string(),
+        InstructionKind::Name(_) => "name".to_string(),
+        InstructionKind::Extern(_) => "extern".to_string(),
+        InstructionKind::Memory(_) => "memory".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// How many times each macro is expanded, given `included`, the program
+/// before any of them were.
+fn uses(program: &Program, included: &Program) -> HashMap<String, usize> {
+    // Where each macro's name and body were written, which isn't a use
+    let defined: HashSet<_> = program
+        .macros
+        .values()
+        .flat_map(|macro_| &macro_.body)
+        .map(|inst| &inst.loc)
+        .collect();
+    let mut direct: HashMap<&str, usize> = HashMap::new();
+    for pair in included.instructions.windows(2) {
+        match (&pair[0].kind, &pair[1].kind) {
+            (InstructionKind::Keyword(Keyword::Macro | Keyword::Memory), _) => {}
+            (_, InstructionKind::Name(name))
+                if program.macros.contains_key(name) && !defined.contains(&pair[1].loc) =>
+            {
+                *direct.entry(name).or_default() += 1
+            }
+            _ => {}
+        }
+    }
+
+    // Each use of a macro expands the ones in its body as well
+    fn count(
+        program: &Program,
+        direct: &HashMap<&str, usize>,
+        name: &str,
+        seen: &mut HashMap<String, usize>,
+    ) -> usize {
+        if let Some(&uses) = seen.get(name) {
+            return uses;
+        }
+        // Not expanded any more than this if it's in its own body
+        seen.insert(name.to_string(), 0);
+        let mut uses = direct.get(name).copied().unwrap_or(0);
+        for other in program.macros.values() {
+            let inside = other
+                .body
+                .iter()
+                .filter(|inst| matches!(&inst.kind, InstructionKind::Name(n) if n == name))
+                .count();
+            if inside > 0 && other.name != name {
+                uses += inside * count(program, direct, &other.name, seen);
+            }
+        }
+        seen.insert(name.to_string(), uses);
+        uses
+    }
+    let mut seen = HashMap::new();
+    for name in program.macros.keys() {
+        count(program, &direct, name, &mut seen);
+    }
+    seen
+}
+
+/// How many instructions the macro `name` expands to.
+fn expanded(program: &Program, name: &str, depth: usize) -> usize {
+    let Some(macro_) = program.macros.get(name) else {
+        return 1;
+    };
+    macro_
+        .body
+        .iter()
+        .map(|inst| match &inst.kind {
+            InstructionKind::Name(inner) if depth < 100 && program.macros.contains_key(inner) => {
+                expanded(program, inner, depth + 1)
+            }
+            _ => 1,
+        })
+        .sum()
+}
+
+/// The stats of the program in `file`, optimized at `opt_level`.
+pub fn stats(file: &PathBuf, opt_level: u8) -> Result<Stats> {
+    let program = load_program(file).with_context(|| format!("Failed to load {:?}.", file))?;
+    let source = std::fs::read_to_string(file)
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not read {}", file.to_string_lossy()))?;
+    let name = format!("{}.porth", program.name);
+    let included = preprocessor::included(parser::parse(
+        source,
+        &program.name,
+        program.base_path.join(&name),
+    )?)?;
+    let uses = uses(&program, &included);
+    let mut macros: Vec<_> = program
+        .macros
+        .values()
+        .filter(|macro_| uses.get(&macro_.name).is_some_and(|&uses| uses > 0))
+        .map(|macro_| MacroStats {
+            name: macro_.name.clone(),
+            uses: uses[&macro_.name],
+            written: macro_.body.len(),
+            expanded: expanded(&program, &macro_.name, 0),
+        })
+        .collect();
+    macros.sort_by(|a, b| b.total().cmp(&a.total()).then(a.name.cmp(&b.name)));
+
+    let program = optimize::optimize(&program, opt_level, false)?;
+    let mut kinds: HashMap<String, usize> = HashMap::new();
+    let mut strings = HashSet::new();
+    for inst in &program.instructions {
+        *kinds.entry(kind(&inst.kind)).or_default() += 1;
+        if let InstructionKind::Push(Value::Str(s)) = &inst.kind {
+            strings.insert(s.as_str());
+        }
+    }
+    let mut kinds: Vec<_> = kinds.into_iter().collect();
+    kinds.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    // Built as `worthc build` would with no options
+    let build = CompilerOptions::parse_from(["build"]);
+    Ok(Stats {
+        instructions: program.instructions.len(),
+        kinds,
+        macros,
+        strings: (strings.len(), strings.iter().map(|s| s.len()).sum()),
+        size: codegen::size(&program, &build).ok(),
+    })
+}
+
+impl Stats {
+    pub fn json(&self) -> Json {
+        Json::object([
+            ("instructions", self.instructions.into()),
+            (
+                "kinds",
+                Json::object(
+                    self.kinds
+                        .iter()
+                        .map(|(kind, count)| (kind.as_str(), (*count).into())),
+                ),
+            ),
+            (
+                "macros",
+                self.macros
+                    .iter()
+                    .map(|macro_| {
+                        Json::object([
+                            ("name", macro_.name.as_str().into()),
+                            ("uses", macro_.uses.into()),
+                            ("written", macro_.written.into()),
+                            ("expanded", macro_.expanded.into()),
+                            ("total", macro_.total().into()),
+                        ])
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+            (
+                "strings",
+                Json::object([
+                    ("count", self.strings.0.into()),
+                    ("bytes", self.strings.1.into()),
+                ]),
+            ),
+            (
+                "size",
+                self.size
+                    .map(|size| {
+                        Json::object([
+                            ("text", size.text.into()),
+                            ("data", size.data.into()),
+                            ("rodata", size.rodata.into()),
+                            ("bss", size.bss.into()),
+                            ("file", size.file.into()),
+                        ])
+                    })
+                    .into(),
+            ),
+        ])
+    }
+
+    pub fn text(&self) -> String {
+        let mut out = format!("{} instructions\n", self.instructions);
+        let width = self.kinds.iter().map(|(kind, _)| kind.len()).max();
+        for (kind, count) in &self.kinds {
+            out += &format!("  {:width$}  {}\n", kind, count, width = width.unwrap_or(0));
+        }
+
+        if !self.macros.is_empty() {
+            let width = self.macros.iter().map(|m| m.name.len()).max().unwrap_or(0);
+            out += &format!(
+                "\nMacros\n  {:width$}  {:>6}  {:>7}  {:>8}  {:>6}\n",
+                "name",
+                "uses",
+                "written",
+                "expanded",
+                "total",
+                width = width
+            );
+            for m in &self.macros {
+                out += &format!(
+                    "  {:width$}  {:>6}  {:>7}  {:>8}  {:>6}\n",
+                    m.name,
+                    m.uses,
+                    m.written,
+                    m.expanded,
+                    m.total(),
+                    width = width
+                );
+            }
+        }
+
+        out += &format!("\n{} strings, {} bytes\n", self.strings.0, self.strings.1);
+        match self.size {
+            Some(size) => out += &format!(
+                "\nExecutable, about {} bytes\n  text    {}\n  data    {}\n  rodata  {}\n  bss     {} (not in the file)\n",
+                size.file, size.text, size.data, size.rodata, size.bss
+            ),
+            None => out += "\nExecutable size unknown, it doesn't build for x86_64-linux\n",
+        }
+        out
+    }
+}
+
+/// Print the stats of `file`.
+pub fn run(file: &PathBuf, opt: &StatsOptions) -> Result<()> {
+    let stats = stats(file, opt.opt_level)?;
+    if opt.json {
+        println!("{}", stats.json());
+    } else {
+        print!("{}", stats.text());
+    }
+    Ok(())
+}
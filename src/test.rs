@@ -0,0 +1,364 @@
+//! `worthc test`, which runs every program in a directory that has a `.txt`
+//! next to it both simulated and built, and checks each does what the `.txt`
+//! expects.
+//!
+//! The `.txt` is made of sections, each starting with a line beginning with
+//! `:`, and any of them can be left out:
+//!
+//! ```text
+//! :args first second
+//! :stdin
+//! What the program reads
+//! :stdout
+//! What it should write
+//! :exit 0
+//! ```
+//!
+//! Without `:stdout` the simulator and the build only have to write the same
+//! thing, and without `:exit` they both have to exit with 0.
+//!
+//! `worthc difftest` does that last part for a program on its own, given its
+//! arguments and stdin there instead, and says where the two first differ.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    cli::{DifftestOptions, TestOptions},
+    error::{Error::IOError, IOError::*},
+    log::{self, LogLevel},
+};
+
+/// A program and what it should do.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Case {
+    pub args: Vec<String>,
+    pub stdin: String,
+    pub stdout: Option<String>,
+    pub exit: i32,
+}
+
+impl Case {
+    pub fn parse(text: &str) -> Result<Case> {
+        let mut case = Case::default();
+        // The section taking lines, and the ones it's taken so far
+        let mut section: Option<(&str, String)> = None;
+        for line in text.split_inclusive('\n') {
+            let Some(header) = line.strip_prefix(':') else {
+                match &mut section {
+                    Some((_, lines)) => *lines += line,
+                    None => {
+                        return Err(IOError(InvalidTestCase(format!(
+                            "{:?} isn't in a section",
+                            line.trim_end()
+                        )))
+                        .into())
+                    }
+                }
+                continue;
+            };
+            case.finish(section.take());
+            let header = header.trim_end();
+            let (name, rest) = header.split_once(' ').unwrap_or((header, ""));
+            match name {
+                "args" => case.args = rest.split_whitespace().map(str::to_string).collect(),
+                "exit" => {
+                    case.exit = rest.trim().parse().map_err(|_| {
+                        IOError(InvalidTestCase(format!("{:?} isn't an exit code", rest)))
+                    })?
+                }
+                "stdin" | "stdout" if rest.is_empty() => section = Some((name, String::new())),
+                _ => {
+                    return Err(
+                        IOError(InvalidTestCase(format!("Unknown section :{}", header))).into(),
+                    )
+                }
+            }
+        }
+        case.finish(section);
+        Ok(case)
+    }
+
+    /// Put the lines of the section just ended where they go.
+    fn finish(&mut self, section: Option<(&str, String)>) {
+        match section {
+            Some(("stdin", lines)) => self.stdin = lines,
+            Some((_, lines)) => self.stdout = Some(lines),
+            None => {}
+        }
+    }
+
+    /// The case for `program`, if it has a `.txt`.
+    pub fn load(program: &Path) -> Result<Option<Case>> {
+        let file = program.with_extension("txt");
+        if !file.exists() {
+            return Ok(None);
+        }
+        let text = std::fs::read_to_string(&file)
+            .map_err(|e| IOError(Inherited(e)))
+            .with_context(|| format!("Could not read {}", file.to_string_lossy()))?;
+        let case = Case::parse(&text).with_context(|| format!("In {}", file.to_string_lossy()))?;
+        Ok(Some(case))
+    }
+}
+
+/// What a program did.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    /// `None` if it was killed
+    exit: Option<i32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+fn spawn(command: &mut Command, stdin: &str) -> Result<Outcome> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not start {:?}", command.get_program()))?;
+    let mut pipe = child.stdin.take().unwrap();
+    let stdin = stdin.to_string();
+    // Written while the output's read, so neither pipe fills up waiting on the other
+    let writer = std::thread::spawn(move || pipe.write_all(stdin.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not wait for {:?}", command.get_program()))?;
+    // The program doesn't have to read all of it
+    let _ = writer.join();
+    Ok(Outcome {
+        exit: output.status.code(),
+        stdout: output.stdout,
+        stderr: output.stderr,
+    })
+}
+
+/// What `program` did simulated and built, given `args` and `stdin`, or why it
+/// didn't build.
+fn outcomes(
+    program: &Path,
+    args: &[String],
+    stdin: &str,
+) -> Result<std::result::Result<(Outcome, Outcome), String>> {
+    let worthc = std::env::current_exe().context("Could not find worthc to run")?;
+    // Where the simulator says the program is, so both get the same argv[0]
+    let program = program
+        .canonicalize()
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not find {}", program.to_string_lossy()))?;
+    let exe = program.with_extension("");
+    let built = spawn(
+        Command::new(&worthc)
+            .arg("build")
+            .arg(&program)
+            .arg("-o")
+            .arg(&exe),
+        "",
+    )?;
+    if built.exit != Some(0) {
+        return Ok(Err(String::from_utf8_lossy(&built.stderr).to_string()));
+    }
+    let native = spawn(Command::new(&exe).args(args), stdin)?;
+    let _ = std::fs::remove_file(&exe);
+    let simulated = spawn(
+        Command::new(&worthc)
+            .arg("simulate")
+            .arg(&program)
+            .arg("--")
+            .args(args),
+        stdin,
+    )?;
+    Ok(Ok((simulated, native)))
+}
+
+/// Why `program` doesn't do what `case` expects, if it doesn't.
+fn check(program: &Path, case: &Case) -> Result<Option<String>> {
+    let (simulated, native) = match outcomes(program, &case.args, &case.stdin)? {
+        Ok(outcomes) => outcomes,
+        Err(why) => return Ok(Some(format!("it didn't build:\n{}", why))),
+    };
+
+    for (how, outcome) in [("simulated", &simulated), ("built", &native)] {
+        if outcome.exit != Some(case.exit) {
+            return Ok(Some(format!(
+                "{} it exited with {}, not {}:\n{}",
+                how,
+                outcome
+                    .exit
+                    .map_or("a signal".to_string(), |code| code.to_string()),
+                case.exit,
+                String::from_utf8_lossy(&outcome.stderr)
+            )));
+        }
+        match &case.stdout {
+            Some(stdout) if outcome.stdout != stdout.as_bytes() => {
+                return Ok(Some(format!(
+                    "{} it wrote\n{}\ninstead of\n{}",
+                    how,
+                    String::from_utf8_lossy(&outcome.stdout),
+                    stdout
+                )))
+            }
+            _ => {}
+        }
+    }
+    if simulated != native {
+        return Ok(Some(format!(
+            "simulated it wrote\n{}{}\nbut built\n{}{}",
+            String::from_utf8_lossy(&simulated.stdout),
+            String::from_utf8_lossy(&simulated.stderr),
+            String::from_utf8_lossy(&native.stdout),
+            String::from_utf8_lossy(&native.stderr)
+        )));
+    }
+    Ok(None)
+}
+
+/// Every program in `dir` and the directories in it, in order.
+fn programs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = std::fs::read_dir(dir)
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not read {}", dir.to_string_lossy()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| IOError(Inherited(e)))
+        .with_context(|| format!("Could not read {}", dir.to_string_lossy()))?;
+    entries.sort();
+    let mut found = Vec::new();
+    for entry in entries {
+        if entry.is_dir() {
+            found.extend(programs(&entry)?);
+        } else if entry.extension().is_some_and(|ext| ext == "porth") {
+            found.push(entry);
+        }
+    }
+    Ok(found)
+}
+
+/// Run every case in `opt.dir`. Returns whether they all passed.
+pub fn run(opt: &TestOptions) -> Result<bool> {
+    let (mut passed, mut failed) = (0, 0);
+    for program in programs(&opt.dir)? {
+        let Some(case) = Case::load(&program)? else {
+            continue;
+        };
+        match check(&program, &case)? {
+            None => {
+                passed += 1;
+                log::log(
+                    LogLevel::Info,
+                    format!("Passed {}", program.to_string_lossy()),
+                    false,
+                );
+            }
+            Some(why) => {
+                failed += 1;
+                log::log(
+                    LogLevel::Warn,
+                    format!("Failed {}: {}", program.to_string_lossy(), why.trim_end()),
+                    false,
+                );
+            }
+        }
+    }
+    log::log(
+        LogLevel::Info,
+        format!("{} passed, {} failed", passed, failed),
+        false,
+    );
+    Ok(failed == 0)
+}
+
+/// Where `a` and `b` first differ, if they do.
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(a, b)| a != b)
+        .or((a.len() != b.len()).then(|| a.len().min(b.len())))
+}
+
+/// How the stream `name` differs from simulated to built, if it does.
+fn difference(name: &str, simulated: &[u8], built: &[u8]) -> Option<String> {
+    let at = first_difference(simulated, built)?;
+    let line = simulated[..at].iter().filter(|&&b| b == b'\n').count() + 1;
+    let from = |bytes: &[u8]| {
+        let rest = &bytes[at..];
+        String::from_utf8_lossy(&rest[..rest.len().min(32)]).to_string()
+    };
+    Some(format!(
+        "{} differs at byte {}, on line {}: simulated wrote {:?} but built {:?}",
+        name,
+        at,
+        line,
+        from(simulated),
+        from(built)
+    ))
+}
+
+/// Run `file` simulated and built with the same arguments and stdin, and say
+/// how what they did differs. Returns whether it didn't.
+pub fn difftest(file: &Path, opt: &DifftestOptions) -> Result<bool> {
+    let stdin = match &opt.stdin {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| IOError(Inherited(e)))
+            .with_context(|| format!("Could not read {}", path.to_string_lossy()))?,
+        None => String::new(),
+    };
+    let (simulated, native) = match outcomes(file, &opt.args, &stdin)? {
+        Ok(outcomes) => outcomes,
+        Err(why) => {
+            log::log(
+                LogLevel::Warn,
+                format!(
+                    "{} didn't build:\n{}",
+                    file.to_string_lossy(),
+                    why.trim_end()
+                ),
+                false,
+            );
+            return Ok(false);
+        }
+    };
+    let exit = |outcome: &Outcome| {
+        outcome
+            .exit
+            .map_or("a signal".to_string(), |code| code.to_string())
+    };
+    let mut differences: Vec<String> = [
+        difference("stdout", &simulated.stdout, &native.stdout),
+        difference("stderr", &simulated.stderr, &native.stderr),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if simulated.exit != native.exit {
+        differences.push(format!(
+            "Simulated it exited with {} but built with {}",
+            exit(&simulated),
+            exit(&native)
+        ));
+    }
+    for difference in &differences {
+        log::log(LogLevel::Warn, difference.clone(), false);
+    }
+    if differences.is_empty() {
+        log::log(
+            LogLevel::Info,
+            format!(
+                "{} does the same simulated and built: {} bytes of stdout, {} of stderr and exit {}",
+                file.to_string_lossy(),
+                simulated.stdout.len(),
+                simulated.stderr.len(),
+                exit(&simulated)
+            ),
+            false,
+        );
+    }
+    Ok(differences.is_empty())
+}
@@ -0,0 +1,73 @@
+//! `--timings`, which reports how long each phase of loading and building a
+//! program took: parsing, preprocessing each include, typechecking, codegen,
+//! and the assembler and linker.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::log;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// How many phases the one starting now is inside of
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+/// In the order they started, so a phase comes before the ones inside it
+static PHASES: Mutex<Vec<Phase>> = Mutex::new(Vec::new());
+
+struct Phase {
+    name: String,
+    depth: usize,
+    took: Duration,
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Call `f` in the phase `name`, timing it if timings are on.
+pub fn time<T>(name: impl Into<String>, f: impl FnOnce() -> T) -> T {
+    let name = name.into();
+    if !ENABLED.load(Ordering::Relaxed) {
+        return log::phase(&name, f);
+    }
+    let depth = DEPTH.fetch_add(1, Ordering::Relaxed);
+    let index = {
+        let mut phases = PHASES.lock().unwrap();
+        phases.push(Phase {
+            name: name.clone(),
+            depth,
+            took: Duration::ZERO,
+        });
+        phases.len() - 1
+    };
+    let start = Instant::now();
+    let result = log::phase(&name, f);
+    PHASES.lock().unwrap()[index].took = start.elapsed();
+    DEPTH.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+/// Write the phases timed since the last report to stderr, if timings are on.
+pub fn report() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let phases = std::mem::take(&mut *PHASES.lock().unwrap());
+    let width = phases
+        .iter()
+        .map(|phase| phase.depth * 2 + phase.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("total".len());
+    eprintln!("Timings:");
+    for phase in &phases {
+        let name = format!("{}{}", "  ".repeat(phase.depth), phase.name);
+        eprintln!("  {:<width$}  {:>10.2?}", name, phase.took, width = width);
+    }
+    let total: Duration = phases
+        .iter()
+        .filter(|phase| phase.depth == 0)
+        .map(|phase| phase.took)
+        .sum();
+    eprintln!("  {:<width$}  {:>10.2?}", "total", total, width = width);
+}
@@ -1,10 +1,15 @@
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::io::Write;
 
 use anyhow::{Context, Result};
+use clap::Parser;
 
+use crate::cli::TypecheckOptions;
 use crate::codegen::intrinsics::Intrinsic;
-use crate::error::{err_loc, err_spread, Error::TypecheckError, TypecheckError::*};
-use crate::instruction::{InstructionKind, Keyword, Op, Program, SyscallKind, Value};
+use crate::error::{err_loc, err_spread, Diagnostic, Error::TypecheckError, TypecheckError::*};
+use crate::instruction::{Instruction, InstructionKind, Keyword, Op, Program, SyscallKind, Value};
+use crate::log::{self, LogLevel};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ValType {
@@ -12,6 +17,57 @@ pub enum ValType {
     Char,
     Ptr,
     Bool,
+    /// A pointer whose pointee type is known, e.g. a string literal's `ptr<char>`.
+    PtrTo(Pointee),
+    /// Produced inside `unsafe` blocks, unifies with every other type.
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pointee {
+    Char,
+    Ptr,
+}
+
+impl ValType {
+    /// Whether a value of this type can be used where `expected` is required.
+    /// Typed pointers can be used anywhere an untyped `ptr` is accepted.
+    fn is(self, expected: ValType) -> bool {
+        self == expected
+            || self == ValType::Any
+            || expected == ValType::Any
+            || (expected == ValType::Ptr && matches!(self, ValType::PtrTo(_)))
+    }
+
+    /// The type pushed by a byte load (`,`) through this pointer.
+    fn load(self) -> ValType {
+        match self {
+            ValType::PtrTo(Pointee::Char) => ValType::Char,
+            ValType::Any => ValType::Any,
+            _ => ValType::Int,
+        }
+    }
+
+    /// The type pushed by a 64-bit load (`,64`) through this pointer.
+    fn load64(self) -> ValType {
+        match self {
+            ValType::PtrTo(Pointee::Ptr) => ValType::Ptr,
+            ValType::Any => ValType::Any,
+            _ => ValType::Int,
+        }
+    }
+
+    /// Whether `value` may be stored through this pointer.
+    fn accepts(self, value: ValType) -> bool {
+        use ValType::*;
+        match self {
+            _ if value == Any => true,
+            Any => true,
+            PtrTo(Pointee::Char) => matches!(value, Char | Int),
+            PtrTo(Pointee::Ptr) => value.is(Ptr),
+            _ => matches!(value, Int | Char | Bool),
+        }
+    }
 }
 
 impl Display for ValType {
@@ -21,32 +77,394 @@ impl Display for ValType {
             ValType::Char => write!(f, "char"),
             ValType::Ptr => write!(f, "ptr"),
             ValType::Bool => write!(f, "bool"),
+            ValType::PtrTo(pointee) => write!(f, "ptr<{}>", pointee),
+            ValType::Any => write!(f, "any"),
+        }
+    }
+}
+
+/// The stack either of two branches may leave, if they can be joined: `any`
+/// joins with every type, and pointers with different pointees widen to `ptr`.
+fn join(a: &[ValType], b: &[ValType]) -> Option<Vec<ValType>> {
+    use ValType::*;
+    if a.len() != b.len() {
+        return None;
+    }
+    a.iter()
+        .zip(b)
+        .map(|(&a, &b)| match (a, b) {
+            _ if a == b => Some(a),
+            (Any, _) | (_, Any) => Some(Any),
+            (Ptr | PtrTo(_), Ptr | PtrTo(_)) => Some(Ptr),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Display for Pointee {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pointee::Char => write!(f, "char"),
+            Pointee::Ptr => write!(f, "ptr"),
+        }
+    }
+}
+
+enum Breakpoint {
+    Ip(usize),
+    Line(String, usize),
+}
+
+/// Interactive debugger for the typechecker, paused after an instruction is checked.
+struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    stepping: bool,
+    run_to: Option<usize>,
+    last_stack: Vec<ValType>,
+}
+
+impl Debugger {
+    fn new() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            stepping: true,
+            run_to: None,
+            last_stack: Vec::new(),
+        }
+    }
+
+    fn should_stop(&self, program: &Program, ip: usize) -> bool {
+        let loc = &program.instructions[ip].loc;
+        self.stepping
+            || self.run_to == Some(ip)
+            || self.breakpoints.iter().any(|bp| match bp {
+                Breakpoint::Ip(bp) => *bp == ip,
+                Breakpoint::Line(file, line) => {
+                    *line == loc.1
+                        && (loc.0 == *file || loc.0.trim_end_matches(".porth") == file)
+                        // Only stop on the first instruction of the line
+                        && (ip == 0 || program.instructions[ip - 1].loc.1 != *line)
+                }
+            })
+    }
+
+    fn step(
+        &mut self,
+        program: &Program,
+        ip: usize,
+        stack: &[ValType],
+        snapshots: &[(Vec<ValType>, Keyword)],
+    ) {
+        if self.should_stop(program, ip) {
+            self.run_to = None;
+            let inst = &program.instructions[ip];
+            println!("{}: {}", ip, inst.kind);
+            println!("Location: {}", err_loc(&inst.loc));
+            println!("Stack: {}", fmt_stack(stack));
+            self.prompt(program, stack, snapshots);
+        }
+        self.last_stack = stack.to_vec();
+    }
+
+    fn prompt(
+        &mut self,
+        program: &Program,
+        stack: &[ValType],
+        snapshots: &[(Vec<ValType>, Keyword)],
+    ) {
+        loop {
+            print!("(tc) ");
+            let _ = std::io::stdout().flush();
+            let mut cmd = String::new();
+            if std::io::stdin().read_line(&mut cmd).unwrap_or(0) == 0 {
+                // stdin closed, run to completion
+                self.breakpoints.clear();
+                self.run_to = None;
+                self.stepping = false;
+                return;
+            }
+            let mut args = cmd.split_whitespace();
+            match (args.next(), args.next()) {
+                (None, _) | (Some("s" | "step"), _) => {
+                    self.stepping = true;
+                    return;
+                }
+                (Some("c" | "continue"), _) => {
+                    self.stepping = false;
+                    return;
+                }
+                (Some("j" | "jump"), Some(target)) => match target.parse::<usize>() {
+                    Ok(target) if target < program.instructions.len() => {
+                        // Run to the target so the stack snapshots stay consistent
+                        self.run_to = Some(target);
+                        self.stepping = false;
+                        return;
+                    }
+                    _ => println!("Invalid instruction {}", target),
+                },
+                (Some("b" | "break"), Some(target)) => match parse_breakpoint(target) {
+                    Some(bp) => self.breakpoints.push(bp),
+                    None => println!(
+                        "Invalid breakpoint {}, expected <ip> or <file>:<line>",
+                        target
+                    ),
+                },
+                (Some("breakpoints"), _) => {
+                    for (i, bp) in self.breakpoints.iter().enumerate() {
+                        match bp {
+                            Breakpoint::Ip(ip) => println!("{}: ip {}", i, ip),
+                            Breakpoint::Line(file, line) => println!("{}: {}:{}", i, file, line),
+                        }
+                    }
+                }
+                (Some("delete"), Some(n)) => match n.parse::<usize>() {
+                    Ok(n) if n < self.breakpoints.len() => {
+                        self.breakpoints.remove(n);
+                    }
+                    _ => println!("No breakpoint {}", n),
+                },
+                (Some("p" | "stack"), _) => println!("Stack: {}", fmt_stack(stack)),
+                (Some("snapshots"), _) => {
+                    if snapshots.is_empty() {
+                        println!("No snapshots");
+                    }
+                    for (i, (snapshot, kw)) in snapshots.iter().enumerate() {
+                        println!("{}: {} {}", i, kw, fmt_stack(snapshot));
+                    }
+                }
+                (Some("diff"), _) => print_stack_diff(&self.last_stack, stack),
+                (Some("q" | "quit"), _) => {
+                    self.breakpoints.clear();
+                    self.run_to = None;
+                    self.stepping = false;
+                    return;
+                }
+                (Some("h" | "help"), _) => println!(
+                    "Commands:\n  \
+                     <enter>, s, step         check the next instruction\n  \
+                     c, continue              run until the next breakpoint\n  \
+                     b, break <ip|file:line>  add a breakpoint\n  \
+                     breakpoints              list breakpoints\n  \
+                     delete <n>               delete breakpoint n\n  \
+                     j, jump <ip>             run until instruction ip\n  \
+                     p, stack                 print the stack\n  \
+                     snapshots                print the block stack snapshots\n  \
+                     diff                     show the stack changes made by the last step\n  \
+                     q, quit                  stop debugging and finish typechecking"
+                ),
+                (Some(cmd), _) => println!("Unknown command {}, try help", cmd),
+            }
         }
     }
 }
 
-pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
+fn parse_breakpoint(target: &str) -> Option<Breakpoint> {
+    if let Ok(ip) = target.parse::<usize>() {
+        return Some(Breakpoint::Ip(ip));
+    }
+    let (file, line) = target.rsplit_once(':')?;
+    Some(Breakpoint::Line(file.to_string(), line.parse().ok()?))
+}
+
+fn print_stack_diff(before: &[ValType], after: &[ValType]) {
+    let common = before.iter().zip(after).take_while(|(a, b)| a == b).count();
+    println!("Unchanged: {}", fmt_stack(&before[..common]));
+    for t in before[common..].iter().rev() {
+        println!("- {}", t);
+    }
+    for t in &after[common..] {
+        println!("+ {}", t);
+    }
+}
+
+fn fmt_stack(stack: &[ValType]) -> String {
+    let types = stack.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+    format!("[{}]", types.join(", "))
+}
+
+/// Whether a syscall whose number was pushed by `inst` never returns (exit, exit_group)
+fn is_exit(inst: &InstructionKind) -> bool {
+    matches!(inst, InstructionKind::Push(Value::Int(60 | 231)))
+}
+
+/// Close the current branch of an if chain, returning to the divergence state before the block
+fn end_branch(divergence: &mut [(bool, bool)], diverged: &mut bool) {
+    if let Some((entry, branches)) = divergence.last_mut() {
+        *branches &= *diverged;
+        *diverged = *entry;
+    }
+}
+
+pub fn typecheck(program: &Program, opt: &TypecheckOptions) -> Result<()> {
+    let Some(stack) = check(program, opt, Vec::new())? else {
+        log::log(
+            LogLevel::Debug,
+            "The program always exits".to_string(),
+            false,
+        );
+        return Ok(());
+    };
+    log::log(
+        LogLevel::Debug,
+        format!("Stack at the end of the program: {:?}", stack),
+        false,
+    );
+    if stack.len() > 1 {
+        return Err(TypecheckError(InvalidStack)).with_context(|| {
+            format!(
+                "Invalid stack at end of program: Expected argc and/or return code, stack was {:?}.",
+                stack
+            )
+        });
+    } else if stack.len() == 1 && !stack[0].is(ValType::Int) {
+        return Err(TypecheckError(InvalidStack)).with_context(|| {
+            format!(
+                "Invalid stack at end of program: Expected argc and/or return code as int, got {}.",
+                &stack[0]
+            )
+        });
+    }
+    Ok(())
+}
+
+/// What `program` leaves on the stack, checked like `typecheck` does but with
+/// anything left allowed, for code that's run a piece at a time. `None` if it
+/// always exits.
+pub fn leaves(program: &Program, opt: &TypecheckOptions) -> Result<Option<Vec<ValType>>> {
+    check(program, opt, Vec::new())
+}
+
+/// Most inputs `stack_effect` tries before giving up.
+const MAX_INPUTS: usize = 16;
+/// How deep macros using macros are expanded for a stack effect
+const MAX_EXPANSION: usize = 64;
+
+/// How many values `program` takes, as anything, and what it leaves in their
+/// place: the fewest it checks with, or `None` if it doesn't check with any.
+/// Code that always exits leaves nothing.
+pub fn stack_effect(program: &Program) -> Option<(usize, Vec<ValType>)> {
+    let opt = TypecheckOptions::parse_from(["worthc"]);
+    for inputs in 0..=MAX_INPUTS {
+        match check(program, &opt, vec![ValType::Any; inputs]) {
+            Ok(stack) => return Some((inputs, stack.unwrap_or_default())),
+            Err(e) if matches!(e.downcast_ref(), Some(TypecheckError(StackUnderflow))) => {}
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+/// The stack effect of `body`, a macro of `program`, like `any any -> int`.
+pub fn macro_effect(program: &Program, body: &[Instruction]) -> Option<String> {
+    let checked = Program {
+        name: program.name.clone(),
+        base_path: program.base_path.clone(),
+        instructions: expand(program, body, 0)?,
+        macros: HashMap::new(),
+        memories: program.memories.clone(),
+        includes: program.includes.clone(),
+        search_path: program.search_path.clone(),
+    };
+    let (inputs, outputs) = stack_effect(&checked)?;
+    let side = |types: Vec<String>| match types.is_empty() {
+        true => "nothing".to_owned(),
+        false => types.join(" "),
+    };
+    Some(format!(
+        "{} -> {}",
+        side(vec!["any".to_owned(); inputs]),
+        side(outputs.iter().map(ToString::to_string).collect())
+    ))
+}
+
+/// `body` with the macros it uses expanded and its memories resolved, like the
+/// preprocessor leaves programs.
+fn expand(program: &Program, body: &[Instruction], depth: usize) -> Option<Vec<Instruction>> {
+    if depth > MAX_EXPANSION {
+        return None;
+    }
+    let mut expanded = Vec::new();
+    for inst in body {
+        let InstructionKind::Name(name) = &inst.kind else {
+            expanded.push(inst.clone());
+            continue;
+        };
+        if let Some(macro_) = program.macros.get(name) {
+            expanded.extend(expand(program, &macro_.body, depth + 1)?);
+        } else {
+            let memory = program
+                .memories
+                .iter()
+                .find(|memory| &memory.name == name)?;
+            expanded.push(Instruction {
+                kind: InstructionKind::Memory(memory.clone()),
+                ..inst.clone()
+            });
+        }
+    }
+    Some(expanded)
+}
+
+/// Check `program` starting with `stack`, returning what's left on it unless
+/// the program always exits first.
+fn check(
+    program: &Program,
+    opt: &TypecheckOptions,
+    mut stack: Vec<ValType>,
+) -> Result<Option<Vec<ValType>>> {
     use ValType::*;
     let Program { instructions, .. } = program;
+    let mut debugger = opt.debugger.then(Debugger::new);
+
+    let mut trace = match &opt.trace {
+        Some(path) => Some(std::io::BufWriter::new(
+            std::fs::File::create(path)
+                .with_context(|| format!("Failed to create typecheck trace {:?}", path))?,
+        )),
+        None => None,
+    };
 
-    let mut stack = vec![]; // Start with int for argc and a ptr for argv
     let mut snapshots = Vec::new();
+    // For each open unsafe block, how deep the stack below what it's touched is
+    let mut unsafe_floors: Vec<usize> = Vec::new();
+    // Set once the current path has called exit, so whatever it leaves behind never matters
+    let mut diverged = false;
+    // For each open block: (diverged on entry, every finished branch diverged)
+    let mut divergence = Vec::new();
+    // Deepest stack seen and the instruction that reached it
+    let mut max_depth = (0, None);
 
     let mut ip = 0;
     while ip < instructions.len() {
         let inst = &instructions[ip];
+        let before = (!unsafe_floors.is_empty()).then(|| stack.clone());
+        // Written before checking, so the last entry is the failing instruction on error
+        if let Some(trace) = &mut trace {
+            writeln!(
+                trace,
+                "{}: {} at {}\n    stack: {}",
+                ip,
+                inst.kind,
+                err_loc(&inst.loc),
+                fmt_stack(&stack)
+            )
+            .context("Failed to write typecheck trace")?;
+        }
         macro_rules! pop {
             () => {
                 stack
                     .pop()
                     .ok_or(TypecheckError(StackUnderflow))
                     .with_context(|| {
-                        format!(
-                            "Stack underflow at instruction {}: {}\n\n{}\n\nat {}",
-                            ip,
-                            inst.kind,
-                            err_spread(&program.instructions, ip, None),
-                            err_loc(&inst.loc)
+                        Diagnostic::at(
+                            &inst.loc,
+                            format!(
+                                "Stack underflow at instruction {}: {}\n\n{}\n\nat {}",
+                                ip,
+                                inst.kind,
+                                err_spread(&program.instructions, ip, None),
+                                err_loc(&inst.loc)
+                            ),
                         )
                     })?
             };
@@ -54,39 +472,38 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
         macro_rules! expect {
             ($expect:ident) => {{
                 let v = pop!();
-                if !matches!(v, $expect) {
+                if !v.is($expect) {
                     return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string()))).with_context(
                         || {
-                            format!(
+                            Diagnostic::at(&inst.loc, format!(
                                 "Invalid type for {}: Expected {}, got {}.\n\n{}\n\nat {}",
                                 inst.kind,
                                 casey::lower!(stringify!($expect)),
                                 v,
                                 err_spread(&program.instructions, ip, None),
                                 err_loc(&inst.loc)
-                            )
+                            ))
                         },
                     );
                 } else {
-                    $expect
+                    v
                 }
             }};
             (($($expect:ident),+)) => {{
                 let v = pop!();
-                #[allow(unreachable_patterns)]
                 match v {
-                    $($expect => $expect,)+
+                    v if $(v.is($expect))||+ => v,
                     _ => {
                         return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string()))).with_context(
                             || {
-                                format!(
+                                Diagnostic::at(&inst.loc, format!(
                                     "Invalid type for {}: Expected {}, got {}.\n\n{}\n\nat {}",
                                     inst.kind,
                                     casey::lower!(stringify!($($expect)or+)),
                                     v,
                                     err_spread(&program.instructions, ip, None),
                                     err_loc(&inst.loc)
-                                )
+                                ))
                             },
                         );
                     }
@@ -126,14 +543,14 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                 #[allow(unused_comparisons)]
                 if stack.len() < $num {
                     return Err(TypecheckError(StackUnderflow)).with_context(|| {
-                        format!(
+                        Diagnostic::at(&inst.loc, format!(
                             "Not enough arguments for {}: Expected {} items, got {}.\n\n{}\n\nat {}",
                             inst.kind,
                             $num,
                             stack.len(),
                             err_spread(&program.instructions, ip, None),
                             err_loc(&inst.loc)
-                        )
+                        ))
                     });
                 }
                 for _ in 0..$num {
@@ -151,7 +568,8 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     tc!(push: Char);
                 }
                 Value::Str(_) => {
-                    tc!(push: Int, Ptr);
+                    tc!(push: Int);
+                    stack.push(PtrTo(Pointee::Char));
                 }
                 Value::Ptr(_) => {
                     tc!(push: Ptr);
@@ -165,19 +583,20 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     let (a, b) = tc!(expect: (Int, Ptr, Char, Bool), (Int, Ptr, Char, Bool));
                     match (a, b) {
                         (Int, Int) => stack.push(Int),
-                        (Int, Ptr) => stack.push(Ptr),
-                        (Ptr, Int) => stack.push(Ptr),
+                        (Int, p @ (Ptr | PtrTo(_))) => stack.push(p),
+                        (p @ (Ptr | PtrTo(_)), Int) => stack.push(p),
                         (Char, Int) => stack.push(Char),
                         (Int, Char) => stack.push(Int),
                         (Int, Bool) => stack.push(Int),
                         (Bool, Int) => stack.push(Int),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or ptr, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -186,18 +605,23 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     let (a, b) = tc!(expect: (Int, Ptr, Char, Bool), (Int, Ptr, Char, Bool));
                     match (a, b) {
                         (Int, Int) => stack.push(Int),
-                        (Ptr, Int) => stack.push(Ptr),
+                        // ptr int - -> ptr
+                        (Int, p @ (Ptr | PtrTo(_))) => stack.push(p),
+                        (p @ (Ptr | PtrTo(_)), Int) => stack.push(p),
+                        // ptr ptr - -> int
+                        (Ptr | PtrTo(_), Ptr | PtrTo(_)) => stack.push(Int),
                         (Char, Int) => stack.push(Char),
                         (Int, Char) => stack.push(Int),
                         (Int, Bool) => stack.push(Int),
                         (Bool, Int) => stack.push(Int),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or ptr, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -223,13 +647,14 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                         (Char, Bool) => stack.push(Char),
                         (Bool, Char) => stack.push(Char),
                         (Int, Int) => stack.push(Int),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or bool, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -246,13 +671,14 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                         (Char, Bool) => stack.push(Char),
                         (Bool, Char) => stack.push(Char),
                         (Int, Int) => stack.push(Int),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or bool, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -269,13 +695,14 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                         (Char, Bool) => stack.push(Char),
                         (Bool, Char) => stack.push(Char),
                         (Int, Int) => stack.push(Int),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or bool, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -286,7 +713,8 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                         Int => stack.push(Int),
                         Char => stack.push(Char),
                         Bool => stack.push(Bool),
-                        Ptr => unreachable!(),
+                        Any => stack.push(Any),
+                        Ptr | PtrTo(_) => unreachable!(),
                     }
                 }
                 Op::Shl => {
@@ -300,23 +728,24 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     match (a, b) {
                         (Int, Int) => stack.push(Bool),
                         (Int, Char) => stack.push(Bool),
-                        (Int, Ptr) => stack.push(Bool),
+                        (Int, Ptr | PtrTo(_)) => stack.push(Bool),
                         (Int, Bool) => stack.push(Bool),
                         (Char, Char) => stack.push(Bool),
                         (Char, Int) => stack.push(Bool),
                         (Char, Bool) => stack.push(Bool),
-                        (Ptr, Ptr) => stack.push(Bool),
-                        (Ptr, Int) => stack.push(Bool),
+                        (Ptr | PtrTo(_), Ptr | PtrTo(_)) => stack.push(Bool),
+                        (Ptr | PtrTo(_), Int) => stack.push(Bool),
                         (Bool, Bool) => stack.push(Bool),
                         (Bool, Int) => stack.push(Bool),
                         (Bool, Char) => stack.push(Bool),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or ptr, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -326,23 +755,24 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     match (a, b) {
                         (Int, Int) => stack.push(Bool),
                         (Int, Char) => stack.push(Bool),
-                        (Int, Ptr) => stack.push(Bool),
+                        (Int, Ptr | PtrTo(_)) => stack.push(Bool),
                         (Int, Bool) => stack.push(Bool),
                         (Char, Char) => stack.push(Bool),
                         (Char, Int) => stack.push(Bool),
                         (Char, Bool) => stack.push(Bool),
-                        (Ptr, Ptr) => stack.push(Bool),
-                        (Ptr, Int) => stack.push(Bool),
+                        (Ptr | PtrTo(_), Ptr | PtrTo(_)) => stack.push(Bool),
+                        (Ptr | PtrTo(_), Int) => stack.push(Bool),
                         (Bool, Bool) => stack.push(Bool),
                         (Bool, Int) => stack.push(Bool),
                         (Bool, Char) => stack.push(Bool),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or ptr, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -351,17 +781,18 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     let (a, b) = tc!(expect: (Int, Ptr, Char), (Int, Ptr, Char));
                     match (a, b) {
                         (Int, Int) => stack.push(Bool),
-                        (Ptr, Ptr) => stack.push(Bool),
+                        (Ptr | PtrTo(_), Ptr | PtrTo(_)) => stack.push(Bool),
                         (Char, Char) => stack.push(Bool),
                         (Char, Int) => stack.push(Bool),
                         (Int, Char) => stack.push(Bool),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or ptr, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -370,17 +801,18 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     let (a, b) = tc!(expect: (Int, Ptr, Char), (Int, Ptr, Char));
                     match (a, b) {
                         (Int, Int) => stack.push(Bool),
-                        (Ptr, Ptr) => stack.push(Bool),
+                        (Ptr | PtrTo(_), Ptr | PtrTo(_)) => stack.push(Bool),
                         (Char, Char) => stack.push(Bool),
                         (Char, Int) => stack.push(Bool),
                         (Int, Char) => stack.push(Bool),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or ptr, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -389,17 +821,18 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     let (a, b) = tc!(expect: (Int, Ptr, Char), (Int, Ptr, Char));
                     match (a, b) {
                         (Int, Int) => stack.push(Bool),
-                        (Ptr, Ptr) => stack.push(Bool),
+                        (Ptr | PtrTo(_), Ptr | PtrTo(_)) => stack.push(Bool),
                         (Char, Char) => stack.push(Bool),
                         (Char, Int) => stack.push(Bool),
                         (Int, Char) => stack.push(Bool),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or ptr, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -408,34 +841,41 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     let (a, b) = tc!(expect: (Int, Ptr, Char), (Int, Ptr, Char));
                     match (a, b) {
                         (Int, Int) => stack.push(Bool),
-                        (Ptr, Ptr) => stack.push(Bool),
+                        (Ptr | PtrTo(_), Ptr | PtrTo(_)) => stack.push(Bool),
                         (Char, Char) => stack.push(Bool),
                         (Char, Int) => stack.push(Bool),
                         (Int, Char) => stack.push(Bool),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_b) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected int or ptr, got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_b, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
                 }
-                Op::Store => {
-                    tc!(expect: (Int, Char, Bool), Ptr);
-                }
-                Op::Store64 => {
-                    tc!(expect: (Int, Char, Bool), Ptr);
+                Op::Store | Op::Store64 => {
+                    let (value, ptr) = tc!(expect: (Int, Char, Bool, Ptr), Ptr);
+                    if !ptr.accepts(value) {
+                        return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
+                            .with_context(|| {
+                                Diagnostic::at(&inst.loc, format!(
+                                    "Invalid type for {}: Cannot store {} through {}.\n\n{}\n\nat {}",
+                                    inst.kind, value, ptr, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
+                                ))
+                            });
+                    }
                 }
                 Op::Load => {
-                    tc!(expect: Ptr);
-                    stack.push(Int);
+                    let ptr = tc!(expect: Ptr);
+                    stack.push(ptr.load());
                 }
                 Op::Load64 => {
-                    tc!(expect: Ptr);
-                    stack.push(Int);
+                    let ptr = tc!(expect: Ptr);
+                    stack.push(ptr.load64());
                 }
                 Op::Mod => {
                     let (a, b) = tc!(expect: (Int, Char, Ptr), (Int, Char));
@@ -444,15 +884,15 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                         (Char, Char) => stack.push(Char),
                         (Char, Int) => stack.push(Char),
                         (Int, Char) => stack.push(Int),
-                        (Ptr, Int) => stack.push(Ptr),
-                        (Ptr, Char) => stack.push(Ptr),
+                        (p @ (Ptr | PtrTo(_)), Int | Char) => stack.push(p),
+                        (Any, _) | (_, Any) => stack.push(Any),
                         (illegal_a, illegal_n) => {
                             return Err(TypecheckError(InvalidTypeForOp(inst.kind.to_string())))
                                 .with_context(|| {
-                                    format!(
+                                    Diagnostic::at(&inst.loc, format!(
                                         "Invalid type for {}: Expected (int | char | ptr) and (int | char), got {} and {}.\n\n{}\n\nat {}",
                                         inst.kind, illegal_a, illegal_n, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                    )
+                                    ))
                                 });
                         }
                     }
@@ -460,7 +900,7 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
             },
             InstructionKind::Intrinsic(i) => match i {
                 Intrinsic::Argc => tc!(push: Int),
-                Intrinsic::Argv => tc!(push: Ptr),
+                Intrinsic::Argv => stack.push(PtrTo(Pointee::Ptr)),
                 Intrinsic::Print => require!(1),
                 Intrinsic::Panic => require!(0),
                 Intrinsic::Dup => {
@@ -495,17 +935,24 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     stack.push(b);
                 }
                 Intrinsic::CastPtr => {
-                    tc!(expect: Int => push: Ptr);
+                    tc!(expect: (Int, Ptr) => push: Ptr);
                 }
                 Intrinsic::CastInt => {
                     tc!(expect: (Char, Ptr, Bool) => push: Int);
                 }
+                Intrinsic::Align => {
+                    // Still points to the same kind of thing
+                    let (_, ptr) = tc!(expect: Int, Ptr);
+                    stack.push(ptr);
+                }
                 Intrinsic::Here => {
-                    tc!(push: Int, Ptr);
+                    tc!(push: Int);
+                    stack.push(PtrTo(Pointee::Char));
                 }
             },
             InstructionKind::Keyword(kw) => match kw {
                 Keyword::While { .. } => {
+                    divergence.push((diverged, true));
                     snapshots.push((
                         stack.clone(),
                         Keyword::While {
@@ -519,33 +966,48 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     let (stack_snapshot, op_type) = snapshots
                         .pop()
                         .ok_or(TypecheckError(InvalidLoop))
-                        .with_context(|| format!("Invalid do: No stack snapshot available"))?;
+                        .with_context(|| {
+                            Diagnostic::at(
+                                &inst.loc,
+                                "Invalid do: No stack snapshot available".to_string(),
+                            )
+                        })?;
                     if let Keyword::While { .. } = op_type {
-                        if stack != stack_snapshot {
+                        let Some(joined) = join(&stack, &stack_snapshot) else {
                             return Err(TypecheckError(InvalidLoop)).with_context(|| {
-                                format!(
+                                Diagnostic::at(&inst.loc, format!(
                                     "Expected types {:?}, got {:?}. A while loop cannot modify the stack.\n\n{}\n\nat {}",
                                     stack_snapshot, stack, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                )
+                                ))
                             });
-                        }
+                        };
+                        stack = joined;
                         snapshots.push((stack.clone(), Keyword::Do { end_ip: 0 }));
                     } else if let Keyword::If { .. } | Keyword::Elif { .. } = op_type {
                         snapshots.push((stack.clone(), Keyword::Do { end_ip: 0 }));
                     } else {
                         return Err(TypecheckError(InvalidLoop)).with_context(|| {
-                            format!(
-                                "Invalid do: Expected while, got {:?}\n\n{}\n\nat {}",
-                                op_type,
-                                err_spread(&program.instructions, ip, None),
-                                err_loc(&inst.loc)
+                            Diagnostic::at(
+                                &inst.loc,
+                                format!(
+                                    "Invalid do: Expected while, got {:?}\n\n{}\n\nat {}",
+                                    op_type,
+                                    err_spread(&program.instructions, ip, None),
+                                    err_loc(&inst.loc)
+                                ),
                             )
                         });
                     }
                 }
                 Keyword::If => {
+                    divergence.push((diverged, true));
                     snapshots.push((stack.clone(), Keyword::If));
                 }
+                Keyword::Unsafe => {
+                    divergence.push((diverged, true));
+                    snapshots.push((stack.clone(), Keyword::Unsafe));
+                    unsafe_floors.push(stack.len());
+                }
                 Keyword::Elif {
                     self_ip,
                     end_ip: else_ip,
@@ -554,25 +1016,36 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                         .pop()
                         .ok_or(TypecheckError(InvalidElse))
                         .with_context(|| {
-                            format!(
-                                "Invalid else: No stack snapshot available: \n\n{}\n\nat {}",
-                                err_spread(&program.instructions, ip, None),
-                                err_loc(&inst.loc)
+                            Diagnostic::at(
+                                &inst.loc,
+                                format!(
+                                    "Invalid else: No stack snapshot available: \n\n{}\n\nat {}",
+                                    err_spread(&program.instructions, ip, None),
+                                    err_loc(&inst.loc)
+                                ),
                             )
                         })?;
                     if !matches!(op_type, Keyword::Do { .. }) {
                         return Err(TypecheckError(InvalidElse)).with_context(|| {
-                            format!("Invalid else: Expected do, got {:?}\n", op_type)
+                            Diagnostic::at(
+                                &inst.loc,
+                                format!("Invalid else: Expected do, got {:?}\n", op_type),
+                            )
                         });
                     }
-                    if stack != expected_stack {
+                    if diverged {
+                        stack = expected_stack;
+                    } else if let Some(joined) = join(&stack, &expected_stack) {
+                        stack = joined;
+                    } else {
                         return Err(TypecheckError(InvalidElse)).with_context(|| {
-                            format!(
+                            Diagnostic::at(&inst.loc, format!(
                                 "Expected types {:?}, got {:?}. An elseless if statement cannot modify the stack.\n\n{}\n\nat {}",
                                 expected_stack, stack, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                            )
+                            ))
                         });
                     }
+                    end_branch(&mut divergence, &mut diverged);
                     snapshots.push((
                         stack.clone(),
                         Keyword::Elif {
@@ -586,15 +1059,26 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                         .pop()
                         .ok_or(TypecheckError(InvalidElse))
                         .with_context(|| {
-                            format!(
-                                "Invalid else: No stack snapshot available: \n\n{}\n\nat {}",
-                                err_spread(&program.instructions, ip, None),
-                                err_loc(&inst.loc)
+                            Diagnostic::at(
+                                &inst.loc,
+                                format!(
+                                    "Invalid else: No stack snapshot available: \n\n{}\n\nat {}",
+                                    err_spread(&program.instructions, ip, None),
+                                    err_loc(&inst.loc)
+                                ),
                             )
                         })?;
                     if let Keyword::Do { .. } = op_type {
+                        // A diverging branch leaves nothing for the else branch to match
+                        let branch_stack = if diverged {
+                            stack_snapshot.clone()
+                        } else {
+                            std::mem::take(&mut stack)
+                        };
+                        stack = stack_snapshot;
+                        end_branch(&mut divergence, &mut diverged);
                         snapshots.push((
-                            std::mem::replace(&mut stack, stack_snapshot),
+                            branch_stack,
                             Keyword::Else {
                                 self_ip: 0,
                                 end_ip: 0,
@@ -602,11 +1086,14 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                         ));
                     } else {
                         return Err(TypecheckError(InvalidElse)).with_context(|| {
-                            format!(
-                                "Invalid else: Expected if, got {:?}\n\n{}\n\nat {}",
-                                op_type,
-                                err_spread(&program.instructions, ip, None),
-                                err_loc(&inst.loc)
+                            Diagnostic::at(
+                                &inst.loc,
+                                format!(
+                                    "Invalid else: Expected if, got {:?}\n\n{}\n\nat {}",
+                                    op_type,
+                                    err_spread(&program.instructions, ip, None),
+                                    err_loc(&inst.loc)
+                                ),
                             )
                         });
                     }
@@ -615,59 +1102,126 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     let (expected_stack, op_type) = snapshots
                         .pop()
                         .ok_or(TypecheckError(InvalidEnd))
-                        .with_context(|| format!("Invalid end: No stack snapshot available"))?;
+                        .with_context(|| {
+                            Diagnostic::at(
+                                &inst.loc,
+                                "Invalid end: No stack snapshot available".to_string(),
+                            )
+                        })?;
+                    let (entry_diverged, branches_diverged) =
+                        divergence.pop().unwrap_or((false, false));
                     if let Keyword::Do { .. } = op_type {
-                        if stack != expected_stack {
+                        // The loop or elseless if can always be skipped, so it never diverges
+                        if diverged {
+                            stack = expected_stack;
+                        } else if let Some(joined) = join(&stack, &expected_stack) {
+                            stack = joined;
+                        } else {
                             return Err(TypecheckError(InvalidEnd)).with_context(|| {
-                                format!(
+                                Diagnostic::at(&inst.loc, format!(
                                     "Expected types {:?}, got {:?}. A while loop cannot modify the stack.\n\n{}\n\nat {}",
                                     expected_stack, stack, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                )
+                                ))
                             });
                         }
+                        diverged = entry_diverged;
                     } else if let Keyword::Do { .. } = op_type {
-                        if stack != expected_stack {
+                        if let Some(joined) = join(&stack, &expected_stack) {
+                            stack = joined;
+                        } else {
                             return Err(TypecheckError(InvalidEnd)).with_context(|| {
-                                format!(
+                                Diagnostic::at(&inst.loc, format!(
                                     "Expected types {:?}, got {:?}. An elseless if statement cannot modify the stack.\n\n{}\n\nat {}",
                                     expected_stack, stack, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                )
+                                ))
                             });
                         }
                     } else if let Keyword::Else { .. } = op_type {
-                        if stack != expected_stack {
+                        if branches_diverged {
+                            // Only the else branch falls through, keep its stack
+                        } else if diverged {
+                            stack = expected_stack;
+                        } else if let Some(joined) = join(&stack, &expected_stack) {
+                            stack = joined;
+                        } else {
                             return Err(TypecheckError(InvalidEnd)).with_context(|| {
-                                format!(
+                                Diagnostic::at(&inst.loc, format!(
                                     "Expected types {:?}, got {:?}. Both branches of an if statement must push the same types to the stack\n\n{}\n\nat {}",
                                     expected_stack, stack, err_spread(&program.instructions, ip, None), err_loc(&inst.loc)
-                                )
+                                ))
                             });
                         }
+                        diverged = entry_diverged || (branches_diverged && diverged);
+                    } else if let Keyword::Unsafe = op_type {
+                        unsafe_floors.pop();
+                        diverged = entry_diverged || diverged;
                     } else {
                         unreachable!()
                     }
                 }
                 Keyword::Macro => {
                     return Err(TypecheckError(MacroInCode)).with_context(|| {
-                        format!(
-                            "Unexpected macro in code at instruction {}\n\n{}\n\nat {}",
-                            ip,
-                            err_spread(&program.instructions, ip, None),
-                            err_loc(&inst.loc)
+                        Diagnostic::at(
+                            &inst.loc,
+                            format!(
+                                "Unexpected macro in code at instruction {}\n\n{}\n\nat {}",
+                                ip,
+                                err_spread(&program.instructions, ip, None),
+                                err_loc(&inst.loc)
+                            ),
                         )
                     })
                 }
                 Keyword::Include => {
                     return Err(TypecheckError(IncludeInCode)).with_context(|| {
-                        format!(
-                            "Unexpected include in code at instruction {}\n\n{}\n\nat {}",
-                            ip,
-                            err_spread(&program.instructions, ip, None),
-                            err_loc(&inst.loc)
+                        Diagnostic::at(
+                            &inst.loc,
+                            format!(
+                                "Unexpected include in code at instruction {}\n\n{}\n\nat {}",
+                                ip,
+                                err_spread(&program.instructions, ip, None),
+                                err_loc(&inst.loc)
+                            ),
+                        )
+                    })
+                }
+                Keyword::Extern => {
+                    return Err(TypecheckError(ExternInCode)).with_context(|| {
+                        Diagnostic::at(
+                            &inst.loc,
+                            format!(
+                                "Unexpected extern in code at instruction {}\n\n{}\n\nat {}",
+                                ip,
+                                err_spread(&program.instructions, ip, None),
+                                err_loc(&inst.loc)
+                            ),
+                        )
+                    })
+                }
+                Keyword::Memory => {
+                    return Err(TypecheckError(MemoryInCode)).with_context(|| {
+                        Diagnostic::at(
+                            &inst.loc,
+                            format!(
+                                "Unexpected memory in code at instruction {}\n\n{}\n\nat {}",
+                                ip,
+                                err_spread(&program.instructions, ip, None),
+                                err_loc(&inst.loc)
+                            ),
                         )
                     })
                 }
             },
+            InstructionKind::Memory(_) => {
+                tc!(push: Ptr);
+            }
+            // C doesn't say what its arguments are, so like syscalls they're anything
+            InstructionKind::Extern(declaration) => {
+                require!(declaration.args);
+                if declaration.returns {
+                    tc!(push: Int);
+                }
+            }
             // TODO(#4): Figure out how to typecheck syscall args and return types
             InstructionKind::Syscall(s) => {
                 require!(match s {
@@ -679,39 +1233,85 @@ pub fn typecheck(program: &Program, debugger: bool) -> Result<()> {
                     SyscallKind::Syscall5 => 6,
                     SyscallKind::Syscall6 => 7,
                 });
-                tc!(push: Int)
+                tc!(push: Int);
+                if ip > 0 && is_exit(&instructions[ip - 1].kind) {
+                    diverged = true;
+                }
+            }
+            // Left over when no macro or memory has the name
+            InstructionKind::Name(name) => {
+                return Err(TypecheckError(UnknownName(name.clone()))).with_context(|| {
+                    Diagnostic::at(
+                        &inst.loc,
+                        format!(
+                            "Unknown name {} at instruction {}\n\n{}\n\nat {}",
+                            name,
+                            ip,
+                            err_spread(&program.instructions, ip, None),
+                            err_loc(&inst.loc)
+                        ),
+                    )
+                })
             }
-            unim => todo!(
-                "Implement typechecking for instruction {} at {}",
-                unim,
-                err_loc(&inst.loc)
-            ),
         };
-        if debugger {
-            println!("{}: {:?}", ip, inst);
-            println!("Location: {}", err_loc(&inst.loc));
-            println!("Stack: {:?}", stack);
-            println!("Snapshots: {}\n", snapshots.len());
-            std::io::stdin().read_line(&mut String::new()).unwrap();
+        // What an unsafe block consumes or produces loses its type, and what's
+        // below that keeps it
+        if let Some(before) = before {
+            let kept = before
+                .iter()
+                .zip(&stack)
+                .take_while(|(before, after)| before == after)
+                .count();
+            for floor in &mut unsafe_floors {
+                *floor = (*floor).min(kept);
+            }
+        }
+        if let Some(&floor) = unsafe_floors.iter().min() {
+            stack.iter_mut().skip(floor).for_each(|t| *t = Any);
+        }
+        if stack.len() > max_depth.0 {
+            max_depth = (stack.len(), Some(ip));
+            if let Some(limit) = opt.max_stack_depth {
+                if stack.len() > limit {
+                    return Err(TypecheckError(StackDepthExceeded)).with_context(|| {
+                        Diagnostic::at(
+                            &inst.loc,
+                            format!(
+                                "Stack depth {} exceeds the limit of {}.\n\n{}\n\nat {}",
+                                stack.len(),
+                                limit,
+                                err_spread(&program.instructions, ip, None),
+                                err_loc(&inst.loc)
+                            ),
+                        )
+                    });
+                }
+            }
+        }
+        if let Some(debugger) = &mut debugger {
+            debugger.step(program, ip, &stack, &snapshots);
         }
 
         ip += 1;
     }
 
-    if stack.len() > 1 {
-        return Err(TypecheckError(InvalidStack)).with_context(|| {
-            format!(
-                "Invalid stack at end of program: Expected argc and/or return code, stack was {:?}.",
-                stack
-            )
-        });
-    } else if stack.len() == 1 && !matches!(&stack[0], ValType::Int) {
-        return Err(TypecheckError(InvalidStack)).with_context(|| {
-            format!(
-                "Invalid stack at end of program: Expected argc and/or return code as int, got {}.",
-                &stack[0]
-            )
-        });
+    if opt.stack_depth {
+        let at = match max_depth.1 {
+            Some(ip) => format!(" at {}", err_loc(&instructions[ip].loc)),
+            None => String::new(),
+        };
+        log::log(
+            LogLevel::Info,
+            format!("Maximum stack depth: {}{}", max_depth.0, at),
+            false,
+        );
     }
-    Ok(())
+
+    if let Some(trace) = &mut trace {
+        writeln!(trace, "end of program\n    stack: {}", fmt_stack(&stack))
+            .and_then(|_| trace.flush())
+            .context("Failed to write typecheck trace")?;
+    }
+
+    Ok((!diverged).then_some(stack))
 }
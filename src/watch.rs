@@ -0,0 +1,70 @@
+//! `--watch`, which builds, runs or simulates a program again each time it or
+//! anything it includes is saved. The files are polled for their modification
+//! times, so it works the same wherever they are.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+
+use crate::cli::MessageFormat;
+use crate::error::{diagnostic_json, err_loc, strip_ansi, Diagnostic};
+use crate::log::{self, LogLevel};
+use crate::sarif;
+
+/// How long between looks at the files.
+const POLL: Duration = Duration::from_millis(200);
+
+/// Call `once` with `file` and every time it changes, until interrupted. It
+/// returns the exit code of the program, and leaves what `file` included in
+/// its argument whenever it gets that far, so those are watched too.
+pub fn watch(
+    file: &Path,
+    message_format: MessageFormat,
+    mut once: impl FnMut(&mut Vec<PathBuf>) -> Result<i32>,
+) -> Result<()> {
+    let mut includes = Vec::new();
+    loop {
+        match once(&mut includes) {
+            Ok(0) => {}
+            Ok(code) => log::log(LogLevel::Warn, format!("Exited with {}", code), false),
+            Err(e) if message_format == MessageFormat::Json => eprintln!("{}", diagnostic_json(&e)),
+            Err(e) if message_format == MessageFormat::Sarif => {
+                println!("{}", sarif::error_log(&e, Some(file)))
+            }
+            Err(e) => eprintln!("Error: {}", concise(&e)),
+        }
+        let mut files = vec![file.to_path_buf()];
+        files.extend(includes.iter().cloned());
+        log::log(
+            LogLevel::Info,
+            format!("Watching {} files for changes", files.len()),
+            false,
+        );
+        let before = modified(&files);
+        while modified(&files) == before {
+            std::thread::sleep(POLL);
+        }
+        // Editors can take more than one write to save
+        std::thread::sleep(POLL);
+    }
+}
+
+fn modified(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// `err` on a line, at where it happened if it knows.
+fn concise(err: &anyhow::Error) -> String {
+    match err.downcast_ref::<Diagnostic>() {
+        Some(diag) => format!(
+            "{}: {}",
+            err_loc(&diag.loc),
+            strip_ansi(diag.message.trim())
+        ),
+        None => strip_ansi(&format!("{:#}", err)),
+    }
+}
@@ -5,46 +5,63 @@ use std::{
 
 use std::io::Write;
 
-struct TestData {
-    stdin: String,
-    args: Vec<String>,
+/// A target the tests can only run through another program.
+#[derive(Clone, Copy)]
+struct Cross {
+    /// Told apart from other builds of the same program by
+    name: &'static str,
+    /// Passed to `worthc build` to build for it
+    flags: &'static [&'static str],
+    /// What runs the built program, like qemu-user, unless it runs on its own
+    runtime: Option<&'static str>,
+    /// What building for the target needs besides worthc
+    tool: &'static str,
+    /// Of the built program
+    extension: &'static str,
 }
 
-fn parse_in_file(file: &PathBuf) -> Option<TestData> {
-    let Some(contents) = std::fs::read_to_string(file).ok() else {
-        return None;
+/// Check a program like `worthc test` does, but building with `cross.flags` and
+/// running the program with `cross.runtime`. Skipped unless the runtime and tool
+/// are installed.
+fn cross_runner(category: &str, name: &str, cross: Cross) {
+    let installed = |tool: &str| {
+        Command::new(tool)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok()
     };
-    let args: Vec<String> = snailquote::escape(contents.lines().nth(0).unwrap())
-        .split_whitespace()
-        .map(|x| x.to_string())
-        .collect();
-    let stdin: String = contents
-        .lines()
-        .skip(1)
-        .map(|x| x.to_string())
-        .collect::<Vec<String>>()
-        .join("\n");
-    Some(TestData { args, stdin })
+    if cross.runtime.is_some_and(|runtime| !installed(runtime)) || !installed(cross.tool) {
+        eprintln!(
+            "Skipping {} for {}: {} or {} not found",
+            name,
+            cross.name,
+            cross.runtime.unwrap_or("it"),
+            cross.tool
+        );
+        return;
+    }
+    run_test(category, name, cross);
 }
 
-fn runner(category: &str, name: &str) {
+fn run_test(category: &str, name: &str, cross: Cross) {
     let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
-    let file = dir.join(&category).join(&name).with_extension("porth");
-    let args_file = dir.join(&category).join(&name).with_extension("txt");
-    let test_data = parse_in_file(&args_file);
-    let (args, stdin) = if let Some(test_data) = test_data {
-        (Some(test_data.args), Some(test_data.stdin))
-    } else {
-        (None, None)
-    };
+    let file = dir.join(category).join(name).with_extension("porth");
+    let case = worthc::test::Case::load(&file)
+        .expect("invalid test case")
+        .unwrap_or_default();
+    // Distinct from the native test's binary, which may be running at the same time
     let out_file = dir
-        .join("".to_string() + category + "/" + name)
-        .with_extension("");
+        .join(category)
+        .join(format!("{}-{}", name, cross.name))
+        .with_extension(cross.extension);
 
     let output = test_bin::get_test_bin("worthc")
         .arg(&file)
         .args(["build", "-o"])
         .arg(&out_file)
+        .args(cross.flags)
         .output()
         .expect("failed to execute process");
     assert_eq!(
@@ -54,138 +71,3518 @@ fn runner(category: &str, name: &str) {
         &name,
         unsafe { String::from_utf8_unchecked(output.stderr) }
     );
-    let mut output = Command::new(&out_file);
-    if let Some(args) = &args {
-        //output.arg("--");
-        output.args(args);
-    }
+    let mut output = match cross.runtime {
+        Some(runtime) => {
+            let mut runtime = Command::new(runtime);
+            runtime.arg(&out_file);
+            runtime
+        }
+        None => Command::new(&out_file),
+    };
     let mut handle = output
+        .args(&case.args)
         .stdout(Stdio::piped())
         .stdin(Stdio::piped())
         .spawn()
         .expect("failed to execute process");
-    if let Some(stdin) = &stdin {
-        handle
-            .stdin
-            .as_mut()
-            .unwrap()
-            .write_all(stdin.as_bytes())
-            .unwrap();
-    }
+    handle
+        .stdin
+        .as_mut()
+        .unwrap()
+        .write_all(case.stdin.as_bytes())
+        .unwrap();
     let output = handle
         .wait_with_output()
         .expect("failed to execute process");
 
     assert_eq!(
-        output.status.success(),
-        true,
-        "\n\n------ Test Error ------\nProgram {} exited with non-zero status:\n\n{}\n---- End Test Error ----\n------- Test Out -------\nProgram {} exited with non-zero status:\n\n{}\n----- End Test Out -----\n",
+        output.status.code(),
+        Some(case.exit),
+        "\n\n------ Test Error ------\nProgram {} exited with the wrong status:\n\n{}\n---- End Test Error ----\n------- Test Out -------\n{}\n----- End Test Out -----\n",
         &name,
         unsafe { String::from_utf8_unchecked(output.stderr) },
-        &name,
         unsafe { String::from_utf8_unchecked(output.stdout) }
     );
-    let mut sim = test_bin::get_test_bin("worthc");
-    sim.arg(file).arg("S");
-    if let Some(args) = &args {
-        sim.arg("--");
-        sim.args(args);
+    if let Some(stdout) = &case.stdout {
+        assert_eq!(String::from_utf8_lossy(&output.stdout), *stdout);
     }
-    let mut handle = sim
-        .stdout(Stdio::piped())
-        .stdin(Stdio::piped())
-        .spawn()
-        .expect("failed to execute process");
-    if let Some(stdin) = &stdin {
-        handle
-            .stdin
-            .as_mut()
-            .unwrap()
-            .write_all(stdin.as_bytes())
-            .unwrap();
+
+    // Remove the tmp_test file
+    std::fs::remove_file(&out_file).expect("Could not remove tmp_test file");
+}
+
+/// Every program in `tests/<category>` with a `.txt`, through `worthc test`.
+fn test_dir(category: &str) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(category);
+    let output = test_bin::get_test_bin("worthc")
+        .arg("test")
+        .arg(&dir)
+        .output()
+        .expect("failed to run worthc test");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn programs() {
+    test_dir("programs");
+}
+
+#[test]
+fn euler() {
+    test_dir("euler");
+}
+
+#[test]
+fn test_case() {
+    use worthc::test::Case;
+
+    let case = Case::parse(":args a  b\n:exit 3\n:stdin\nline\n:stdout\n\none\n").unwrap();
+    assert_eq!(case.args, ["a", "b"]);
+    assert_eq!(case.exit, 3);
+    assert_eq!(case.stdin, "line\n");
+    assert_eq!(case.stdout.as_deref(), Some("\none\n"));
+    assert_eq!(
+        Case::parse(":stdout\n").unwrap().stdout.as_deref(),
+        Some("")
+    );
+    assert_eq!(Case::parse("").unwrap(), Case::default());
+    assert!(Case::parse("outside\n").is_err());
+    assert!(Case::parse(":stderr\n").is_err());
+    assert!(Case::parse(":exit zero\n").is_err());
+}
+
+/// Typecheck `source` as a program at the top of the repository, so it can
+/// include std.porth. Fails with the code of the error it got, like
+/// `TypecheckError::InvalidTypeForOp`.
+fn typecheck(source: &str) -> Result<(), String> {
+    use clap::Parser;
+
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("typecheck.porth");
+    let code = |err: anyhow::Error| match err.downcast_ref::<worthc::error::Error>() {
+        Some(err) => err.code(),
+        None => format!("{:?}", err),
+    };
+    let program = worthc::parser::parse(source.to_string(), "typecheck", path).map_err(code)?;
+    let program = worthc::preprocessor::process(program).map_err(code)?;
+    let opt = worthc::cli::TypecheckOptions::parse_from(["worthc"]);
+    worthc::typecheck::typecheck(&program, &opt).map_err(code)
+}
+
+#[test]
+fn unsafe_blocks() {
+    // What a block consumes or produces is any
+    for source in [
+        "argv unsafe drop mem end true .64",
+        "\"abc\" unsafe swap drop end 1 + drop",
+        "mem unsafe true end .",
+        "argv unsafe unsafe drop 1 end end true .64",
+    ] {
+        assert_eq!(typecheck(source), Ok(()), "{}", source);
     }
-    let sim_output = handle
-        .wait_with_output()
-        .expect("failed to wait for process");
+    // and what's below it is still checked
+    for source in [
+        "argv unsafe 1 end drop true .64",
+        "argv unsafe 1 unsafe drop end end true .64",
+        "true mem unsafe drop end 'x' .",
+    ] {
+        assert_eq!(
+            typecheck(source),
+            Err("TypecheckError::InvalidTypeForOp".to_string()),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn stray_keywords() {
+    // Keywords with no block for them are errors, not panics
+    for source in [
+        "end",
+        "do",
+        "else",
+        "elif",
+        "1 print else 2 print end",
+        "if else end end",
+        "macro x end end",
+    ] {
+        assert_eq!(
+            typecheck(source),
+            Err("PreprocessorError::UnexpectedKeyword".to_string()),
+            "{}",
+            source
+        );
+    }
+}
 
+#[test]
+fn integers_too_large() {
+    assert_eq!(typecheck("-9223372036854775808 drop"), Ok(()));
+    assert_eq!(typecheck("9223372036854775807 drop"), Ok(()));
+    for source in [
+        "9223372036854775808 drop",
+        "-9223372036854775809 drop",
+        "1 99999999999999999999 + drop",
+    ] {
+        assert_eq!(
+            typecheck(source),
+            Err("ParseError::IntegerTooLarge".to_string()),
+            "{}",
+            source
+        );
+    }
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("big.porth");
+    let err = worthc::parser::parse("1 print\n  99999999999999999999\n".to_string(), "big", path)
+        .unwrap_err();
+    let diagnostic = err.downcast_ref::<worthc::error::Diagnostic>().unwrap();
+    assert_eq!(diagnostic.loc, ("big.porth".to_string(), 2, 2));
     assert_eq!(
-        sim_output.status.success(),
-        true,
-        "\n\n------- Sim Error ------\nSim for {} exited with non-zero status:\n\n{}\n----- End Sim Error ----\n",
-        &name,
-        unsafe { String::from_utf8_unchecked(sim_output.stderr) }
+        diagnostic.message,
+        "99999999999999999999 doesn't fit in 64 bits"
     );
+}
 
-    assert!(
-        sim_output.stdout == output.stdout,
-        "\nSim:\nStdout:\n{}\n\nStderr:\n{}\n\nTest:\nStdout:\n{}\n\nStderr:\n{}\n",
-        unsafe { String::from_utf8_unchecked(sim_output.stdout) },
-        unsafe { String::from_utf8_unchecked(sim_output.stderr) },
-        unsafe { String::from_utf8_unchecked(output.stdout) },
-        unsafe { String::from_utf8_unchecked(output.stderr) }
+#[test]
+fn typed_pointers() {
+    let ok = [
+        // A char through a string's ptr<char>
+        "\"abc\" swap drop 'x' .",
+        // argv is a ptr<ptr>, so what it points to can be loaded from
+        "argv ,64 , drop",
+        "argv mem .64",
+        // Pointers with different pointees join as ptr
+        "if 1 1 = do \"abc\" swap drop else mem end drop",
+        "if 1 1 = do \"abc\" swap drop else mem end 'x' .",
+        "\"abc\" swap drop while dup mem != do drop mem end drop",
+    ];
+    for source in ok {
+        assert_eq!(typecheck(source), Ok(()), "{}", source);
+    }
+    let invalid = [
+        // Only chars and ints go through a ptr<char>
+        "\"abc\" swap drop mem .",
+        // And only pointers through a ptr<ptr>
+        "argv true .64",
+        "argv argv , .64",
+        // Joined as ptr, so it isn't a ptr<ptr> anymore
+        "if 1 1 = do mem else argv end mem .64",
+        // A pointer and an int don't join
+        "if 1 1 = do \"abc\" swap drop else 1 end drop",
+    ];
+    for source in invalid {
+        assert!(typecheck(source).is_err(), "{}", source);
+    }
+    assert_eq!(
+        typecheck("\"abc\" swap drop mem .").unwrap_err(),
+        "TypecheckError::InvalidTypeForOp"
     );
-    assert!(sim_output.stderr == output.stderr);
+}
 
-    // Remove the tmp_test file
-    std::fs::remove_file(&out_file).expect("Could not remove tmp_test file");
+const RISCV64: Cross = Cross {
+    name: "riscv64-linux",
+    flags: &["--target", "riscv64-linux"],
+    runtime: Some("qemu-riscv64"),
+    tool: "riscv64-linux-gnu-as",
+    extension: "",
+};
+const AARCH64: Cross = Cross {
+    name: "aarch64-linux",
+    flags: &["--target", "aarch64-linux"],
+    runtime: Some("qemu-aarch64"),
+    tool: "aarch64-linux-gnu-as",
+    extension: "",
+};
+const WASM32: Cross = Cross {
+    name: "wasm32",
+    flags: &["--target", "wasm32"],
+    runtime: Some("wasmtime"),
+    tool: "wat2wasm",
+    extension: "wasm",
+};
+
+const LLVM: Cross = Cross {
+    name: "x86_64-linux-llvm",
+    flags: &["--target", "x86_64-linux", "--backend", "llvm"],
+    runtime: None,
+    tool: "clang",
+    extension: "",
+};
+const C: Cross = Cross {
+    name: "x86_64-linux-c",
+    flags: &["--target", "x86_64-linux", "--backend", "c"],
+    runtime: None,
+    tool: "cc",
+    extension: "",
+};
+const NASM: Cross = Cross {
+    name: "x86_64-linux-nasm",
+    flags: &["--assembler", "nasm"],
+    runtime: None,
+    tool: "nasm",
+    extension: "",
+};
+const GAS: Cross = Cross {
+    name: "x86_64-linux-gas",
+    flags: &["--assembler", "gas"],
+    runtime: None,
+    tool: "as",
+    extension: "",
+};
+
+const LD: Cross = Cross {
+    name: "x86_64-linux-ld",
+    flags: &["--linker", "ld"],
+    runtime: None,
+    tool: "ld",
+    extension: "",
+};
+
+const LLD: Cross = Cross {
+    name: "x86_64-linux-lld",
+    flags: &["--linker", "lld"],
+    runtime: None,
+    tool: "ld.lld",
+    extension: "",
+};
+
+const MOLD: Cross = Cross {
+    name: "x86_64-linux-mold",
+    flags: &["--linker", "mold"],
+    runtime: None,
+    tool: "mold",
+    extension: "",
+};
+
+const PIE: Cross = Cross {
+    name: "x86_64-linux-pie",
+    flags: &["--pie", "--linker", "ld"],
+    runtime: None,
+    tool: "ld",
+    extension: "",
+};
+
+#[test]
+fn riscv64_hello_world() {
+    cross_runner("programs", "hello", RISCV64);
+}
+
+#[test]
+fn riscv64_math() {
+    cross_runner("programs", "math", RISCV64);
+}
+
+#[test]
+fn riscv64_rule110() {
+    cross_runner("programs", "rule110", RISCV64);
+}
+
+#[test]
+fn riscv64_files() {
+    cross_runner("programs", "files", RISCV64);
+}
+
+#[test]
+fn aarch64_hello_world() {
+    cross_runner("programs", "hello", AARCH64);
+}
+
+#[test]
+fn aarch64_math() {
+    cross_runner("programs", "math", AARCH64);
+}
+
+#[test]
+fn aarch64_rule110() {
+    cross_runner("programs", "rule110", AARCH64);
+}
+
+#[test]
+fn aarch64_files() {
+    cross_runner("programs", "files", AARCH64);
+}
+
+#[test]
+fn wasm32_hello_world() {
+    cross_runner("programs", "hello", WASM32);
+}
+
+#[test]
+fn wasm32_math() {
+    cross_runner("programs", "math", WASM32);
+}
+
+#[test]
+fn wasm32_rule110() {
+    cross_runner("programs", "rule110", WASM32);
+}
+
+#[test]
+fn wasm32_name() {
+    cross_runner("programs", "name", WASM32);
+}
+
+#[test]
+fn llvm_hello_world() {
+    cross_runner("programs", "hello", LLVM);
+}
+
+#[test]
+fn llvm_math() {
+    cross_runner("programs", "math", LLVM);
 }
 
 #[test]
-fn hello_world() {
-    runner("programs", "hello");
+fn llvm_rule110() {
+    cross_runner("programs", "rule110", LLVM);
 }
 
 #[test]
-fn memory() {
-    runner("programs", "memory");
+fn llvm_unsafe() {
+    cross_runner("programs", "unsafe", LLVM);
 }
 
 #[test]
-fn bitwise() {
-    runner("programs", "bitwise");
+fn c_hello_world() {
+    cross_runner("programs", "hello", C);
 }
 
 #[test]
-fn rule110() {
-    runner("programs", "rule110");
+fn c_math() {
+    cross_runner("programs", "math", C);
 }
 
 #[test]
-fn string() {
-    runner("programs", "string");
+fn c_rule110() {
+    cross_runner("programs", "rule110", C);
 }
 
 #[test]
-fn char() {
-    runner("programs", "char");
+fn c_pipes() {
+    cross_runner("programs", "pipes", C);
 }
 
 #[test]
-fn include() {
-    runner("programs", "include");
+fn nasm_hello_world() {
+    cross_runner("programs", "hello", NASM);
 }
 
 #[test]
-fn math() {
-    runner("programs", "math");
+fn nasm_rule110() {
+    cross_runner("programs", "rule110", NASM);
 }
 
 #[test]
-fn args() {
-    runner("programs", "args");
+fn gas_hello_world() {
+    cross_runner("programs", "hello", GAS);
 }
 
 #[test]
-fn name() {
-    runner("programs", "name");
+fn gas_math() {
+    cross_runner("programs", "math", GAS);
 }
 
 #[test]
-fn euler1() {
-    runner("euler", "problem01");
+fn gas_rule110() {
+    cross_runner("programs", "rule110", GAS);
+}
+
+#[test]
+fn gas_endian() {
+    cross_runner("programs", "endian", GAS);
+}
+
+#[test]
+fn ld_hello_world() {
+    cross_runner("programs", "hello", LD);
+}
+
+#[test]
+fn ld_rule110() {
+    cross_runner("programs", "rule110", LD);
+}
+
+#[test]
+fn lld_hello_world() {
+    cross_runner("programs", "hello", LLD);
+}
+
+#[test]
+fn mold_hello_world() {
+    cross_runner("programs", "hello", MOLD);
+}
+
+#[test]
+fn link_arg() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let out_file = dir.join("hello-link-arg");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("hello.porth"))
+        .args(["build", "--link-arg", "--strip-all", "-o"])
+        .arg(&out_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let exe = std::fs::read(&out_file).unwrap();
+    // ld was used instead of the built-in linker, and took the argument
+    assert!(!exe.windows(8).any(|window| window == b".symtab\0"));
+    let status = Command::new(&out_file)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    std::fs::remove_file(&out_file).unwrap();
+}
+
+#[test]
+fn pie_hello_world() {
+    cross_runner("programs", "hello", PIE);
+}
+
+#[test]
+fn pie_memory() {
+    cross_runner("programs", "memory", PIE);
+}
+
+#[test]
+fn debug_info() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let file = dir.join("hello.porth").canonicalize().unwrap();
+    let out_file = dir.join("hello-debug-info");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(&file)
+        .args(["build", "-g", "-o"])
+        .arg(&out_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let exe = std::fs::read(&out_file).unwrap();
+    let contains = |needle: &[u8]| exe.windows(needle.len()).any(|window| window == needle);
+    assert!(contains(b".debug_line\0"));
+    // The line table names the source by its full path
+    assert!(contains(file.to_str().unwrap().as_bytes()));
+    let status = Command::new(&out_file)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    std::fs::remove_file(&out_file).unwrap();
+}
+
+/// A writer the test can read back after handing it to the simulator.
+#[derive(Clone, Default)]
+struct SharedBuf(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn sim_api() {
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/name.porth");
+    let program = worthc::program::load_program(&file).unwrap();
+    let stdout = SharedBuf::default();
+    let mut state = worthc::sim::SimulationState::new(&program.instructions, &["name".into()])
+        .unwrap()
+        .with_stdio(
+            Box::new(&b"worth\n"[..]),
+            Box::new(stdout.clone()),
+            Box::new(std::io::sink()),
+        );
+    // The prompt's length and pointer
+    assert_eq!(state.step(&program.instructions, 1).unwrap(), None);
+    assert_eq!(state.stack.len(), 2);
+    assert_eq!(state.stack[0], "What is your name? ".len() as i64);
+    let status = loop {
+        if let Some(status) = state.step(&program.instructions, 100).unwrap() {
+            break status;
+        }
+    };
+    assert_eq!(status, 0);
+    assert_eq!(
+        String::from_utf8(stdout.0.borrow().clone()).unwrap(),
+        "What is your name? Hello, worth! ( ^-^)/\n"
+    );
+    let name = worthc::sim::MEM_BUF_PTR;
+    assert_eq!(&state.memory[name..name + 5], b"worth");
+}
+
+#[test]
+fn dap() {
+    use std::io::{BufRead, BufReader, Read};
+    use worthc::json::Json;
+
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/memory.porth");
+    let mut child = test_bin::get_test_bin("worthc")
+        .arg("dap")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start the dap server");
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut seq = 0;
+    let mut request = |command: &str, arguments: Json| {
+        seq += 1;
+        let body = Json::object([
+            ("seq", seq.into()),
+            ("type", "request".into()),
+            ("command", command.into()),
+            ("arguments", arguments),
+        ])
+        .to_string();
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    };
+    // Messages up to and including the first that `done` accepts
+    let mut until = |done: &dyn Fn(&Json) -> bool| {
+        let mut messages = Vec::new();
+        loop {
+            let mut len = 0;
+            loop {
+                let mut header = String::new();
+                stdout.read_line(&mut header).unwrap();
+                match header.trim().strip_prefix("Content-Length:") {
+                    Some(n) => len = n.trim().parse().unwrap(),
+                    None if header.trim().is_empty() => break,
+                    None => {}
+                }
+            }
+            let mut body = vec![0; len];
+            stdout.read_exact(&mut body).unwrap();
+            let message = Json::parse(std::str::from_utf8(&body).unwrap()).unwrap();
+            let finished = done(&message);
+            messages.push(message);
+            if finished {
+                return messages;
+            }
+        }
+    };
+    let event = |name: &'static str| move |m: &Json| m.get("event") == Some(&name.into());
+
+    request("initialize", Json::object::<&str>([]));
+    request(
+        "launch",
+        Json::object([("program", file.to_str().unwrap().into())]),
+    );
+    until(&event("initialized"));
+    request(
+        "setBreakpoints",
+        Json::object([
+            (
+                "source",
+                Json::object([("path", file.to_str().unwrap().into())]),
+            ),
+            (
+                "breakpoints",
+                vec![Json::object([("line", 19.into())])].into(),
+            ),
+        ]),
+    );
+    request("configurationDone", Json::Null);
+    until(&event("stopped"));
+
+    request("stackTrace", Json::object([("threadId", 1.into())]));
+    let response = until(&|m| m.get("command") == Some(&"stackTrace".into()));
+    let frame = &response
+        .last()
+        .unwrap()
+        .get("body")
+        .unwrap()
+        .get("stackFrames")
+        .unwrap()
+        .as_array()
+        .unwrap()[0];
+    assert_eq!(frame.get("line").and_then(Json::as_i64), Some(19));
+
+    request("continue", Json::Null);
+    let messages = until(&event("terminated"));
+    let output: String = messages
+        .iter()
+        .filter(|m| m.get("event") == Some(&"output".into()))
+        .filter_map(|m| m.get("body")?.get("output")?.as_str())
+        .collect();
+    assert_eq!(output, "abcbcd\0\n");
+    let exited = messages
+        .iter()
+        .find(|m| m.get("event") == Some(&"exited".into()));
+    let code = exited.and_then(|m| m.get("body")?.get("exitCode")?.as_i64());
+    assert_eq!(code, Some(0));
+
+    request("disconnect", Json::Null);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn lsp() {
+    use std::io::{BufRead, BufReader, Read};
+    use worthc::json::Json;
+
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    // Only open in the editor, so never written
+    let uri = format!("file://{}/lsp.porth", dir.to_str().unwrap());
+    let text = "include \"../../std.porth\"\nmacro twice dup  + end\n\n2 twice print\n";
+    let mut child = test_bin::get_test_bin("worthc")
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start the lsp server");
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut send = |id: Option<i64>, method: &str, params: Json| {
+        let mut message = vec![("jsonrpc", "2.0".into()), ("method", method.into())];
+        message.extend(id.map(|id| ("id", id.into())));
+        message.push(("params", params));
+        let body = Json::object(message).to_string();
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    };
+    let mut next = || {
+        let mut len = 0;
+        loop {
+            let mut header = String::new();
+            stdout.read_line(&mut header).unwrap();
+            match header.trim().strip_prefix("Content-Length:") {
+                Some(n) => len = n.trim().parse().unwrap(),
+                None if header.trim().is_empty() => break,
+                None => {}
+            }
+        }
+        let mut body = vec![0; len];
+        stdout.read_exact(&mut body).unwrap();
+        Json::parse(std::str::from_utf8(&body).unwrap()).unwrap()
+    };
+    let document = || Json::object([("uri", uri.as_str().into())]);
+    let at = |line: i64, character: i64| {
+        Json::object([
+            ("textDocument", document()),
+            (
+                "position",
+                Json::object([("line", line.into()), ("character", character.into())]),
+            ),
+        ])
+    };
+    let diagnostics = |message: &Json| {
+        message
+            .get("params")
+            .and_then(|params| params.get("diagnostics"))
+            .and_then(Json::as_array)
+            .unwrap()
+            .to_vec()
+    };
+
+    send(Some(1), "initialize", Json::object::<&str>([]));
+    let capabilities = next().get("result").unwrap().get("capabilities").cloned();
+    assert_eq!(
+        capabilities.and_then(|c| c.get("hoverProvider").cloned()),
+        Some(true.into())
+    );
+    send(
+        None,
+        "textDocument/didOpen",
+        Json::object([(
+            "textDocument",
+            Json::object([("uri", uri.as_str().into()), ("text", text.into())]),
+        )]),
+    );
+    assert!(diagnostics(&next()).is_empty());
+
+    send(Some(2), "textDocument/definition", at(3, 3));
+    let definition = next().get("result").cloned().unwrap();
+    let start = definition.get("range").unwrap().get("start").unwrap();
+    assert_eq!(definition.get("uri"), Some(&uri.as_str().into()));
+    assert_eq!(start.get("line").and_then(Json::as_i64), Some(1));
+    assert_eq!(start.get("character").and_then(Json::as_i64), Some(6));
+
+    send(Some(3), "textDocument/definition", at(0, 12));
+    let definition = next().get("result").cloned().unwrap();
+    let std = definition.get("uri").and_then(Json::as_str).unwrap();
+    assert!(std.ends_with("/std.porth"), "{}", std);
+
+    send(Some(4), "textDocument/hover", at(3, 4));
+    let hover = next();
+    let value = hover
+        .get("result")
+        .and_then(|result| result.get("contents")?.get("value")?.as_str())
+        .unwrap();
+    assert!(value.contains("macro twice dup + end"), "{}", value);
+    assert!(value.contains("Stack effect: `any -> any`"), "{}", value);
+
+    send(
+        Some(5),
+        "textDocument/formatting",
+        Json::object([
+            ("textDocument", document()),
+            ("options", Json::object([("tabSize", 2.into())])),
+        ]),
+    );
+    let edits = next().get("result").cloned().unwrap();
+    let edits = edits.as_array().unwrap();
+    assert_eq!(edits.len(), 1);
+    assert_eq!(
+        edits[0].get("newText").and_then(Json::as_str),
+        Some(text.replace("dup  +", "dup +").as_str())
+    );
+
+    send(
+        None,
+        "textDocument/didChange",
+        Json::object([
+            ("textDocument", document()),
+            (
+                "contentChanges",
+                vec![Json::object([("text", (text.to_owned() + "+\n").into())])].into(),
+            ),
+        ]),
+    );
+    let diagnostics = diagnostics(&next());
+    assert_eq!(diagnostics.len(), 1);
+    let start = diagnostics[0].get("range").unwrap().get("start").unwrap();
+    assert_eq!(start.get("line").and_then(Json::as_i64), Some(4));
+    assert_eq!(
+        diagnostics[0].get("code"),
+        Some(&"TypecheckError::StackUnderflow".into())
+    );
+
+    send(Some(6), "shutdown", Json::Null);
+    assert_eq!(next().get("result"), Some(&Json::Null));
+    send(None, "exit", Json::Null);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn stdin() {
+    use std::io::Write;
+
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let piped = |source: &str, args: &[&str]| {
+        let mut child = test_bin::get_test_bin("worthc")
+            .arg("-")
+            .args(args)
+            .current_dir(&dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start worthc");
+        // Commands that don't read stdin can exit before it's written
+        let _ = child.stdin.take().unwrap().write_all(source.as_bytes());
+        child.wait_with_output().unwrap()
+    };
+
+    // Includes are found from the current directory
+    let source = std::fs::read_to_string(dir.join("include.porth")).unwrap();
+    let output = piped(&source, &["simulate"]);
+    assert!(output.status.success());
+    let expected = test_bin::get_test_bin("worthc")
+        .arg("simulate")
+        .arg(dir.join("include.porth"))
+        .output()
+        .expect("failed to run worthc simulate");
+    assert_eq!(output.stdout, expected.stdout);
+
+    let output = piped("unknown", &["simulate", "--name", "piped"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("at piped.porth:1:0"));
+
+    let output = piped("1 print", &["dump-ir"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn watch() {
+    use std::io::{BufRead, BufReader};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let dir = std::env::temp_dir().join(format!("worthc-watch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let main = dir.join("main.porth");
+    let included = dir.join("included.porth");
+    std::fs::write(&main, "include \"included.porth\"\nanswer print\n").unwrap();
+    std::fs::write(&included, "macro answer 1 end\n").unwrap();
+
+    let mut child = test_bin::get_test_bin("worthc")
+        .args(["simulate", "--watch"])
+        .arg(&main)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start watching");
+    let (send, lines) = mpsc::channel();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    let out = send.clone();
+    std::thread::spawn(move || {
+        stdout
+            .lines()
+            .map_while(Result::ok)
+            .for_each(|l| out.send(l).unwrap_or(()))
+    });
+    std::thread::spawn(move || {
+        stderr
+            .lines()
+            .map_while(Result::ok)
+            .for_each(|l| send.send(l).unwrap_or(()))
+    });
+    // Everything up to the next time it starts watching
+    let next = || {
+        let mut output = Vec::new();
+        loop {
+            let line = lines
+                .recv_timeout(Duration::from_secs(20))
+                .expect("nothing was watched");
+            if line.contains("Watching 2 files for changes") {
+                return output;
+            }
+            output.push(line);
+        }
+    };
+
+    assert_eq!(next(), ["1"]);
+    // Modification times can be too coarse to see a change in the same second
+    std::thread::sleep(Duration::from_millis(1100));
+    std::fs::write(&included, "macro answer 2 end\n").unwrap();
+    assert_eq!(next(), ["2"]);
+    std::thread::sleep(Duration::from_millis(1100));
+    std::fs::write(&included, "macro answer end\n").unwrap();
+    let output = next();
+    assert!(
+        output
+            .iter()
+            .any(|l| l.starts_with("Error: ") && l.contains("main.porth:2:")),
+        "{:?}",
+        output
+    );
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn libc() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let out_file = dir.join("libc");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("libc.porth"))
+        .args(["build", "--link-libc", "-o"])
+        .arg(&out_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // Exiting through the C library flushes what printf buffered
+    let output = Command::new(&out_file).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "42 is the answer\n1234\n"
+    );
+    std::fs::remove_file(&out_file).unwrap();
+
+    // Every other backend and target says where the first call is
+    let reject = |flags: &[&str]| {
+        let output = test_bin::get_test_bin("worthc")
+            .arg(dir.join("libc.porth"))
+            .args(["build", "--emit", "asm", "-o"])
+            .arg(dir.join("libc-rejected"))
+            .args(flags)
+            .output()
+            .expect("failed to execute process");
+        assert!(!output.status.success(), "{:?}", flags);
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+    for flags in [
+        &["--target", "aarch64-linux"][..],
+        &["--target", "riscv64-linux"],
+        &["--target", "wasm32"],
+        &["--target", "x86_64-macos"],
+        &["--backend", "llvm"],
+        &["--backend", "c"],
+    ] {
+        let stderr = reject(flags);
+        assert!(
+            stderr.contains(
+                "[libc.porth:8:37] C functions like printf are only called from native x86_64-linux code"
+            ),
+            "{:?}: {}",
+            flags,
+            stderr
+        );
+    }
+    assert!(reject(&[]).contains("Extern printf is a C function, compile with --link-libc"));
 }
 
 #[test]
-fn euler2() {
-    runner("euler", "problem02");
+fn mem_capacity() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let file = dir.join("big_mem.porth");
+    let out_file = dir.join("big_mem");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(&file)
+        .args(["build", "--mem-capacity", "1048576", "-o"])
+        .arg(&out_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let output = Command::new(&out_file).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "42\n");
+    std::fs::remove_file(&out_file).unwrap();
+    // The simulator is sized the same way, and out of bounds without it
+    let sim = |args: &[&str]| {
+        test_bin::get_test_bin("worthc")
+            .arg(&file)
+            .arg("S")
+            .args(args)
+            .output()
+            .expect("failed to execute process")
+    };
+    let output = sim(&["--mem-capacity", "1048576"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "42\n");
+    assert!(!sim(&[]).status.success());
+}
+
+#[test]
+fn checked() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    for (name, stdout, stderr) in [
+        (
+            "underflow",
+            "1\n",
+            "underflow.porth:5:0: Stack underflow, drop pops 1 but there are fewer on the stack\n",
+        ),
+        (
+            "out_of_bounds",
+            "",
+            "out_of_bounds.porth:4:15: . wrote outside of mem\n",
+        ),
+        (
+            "div_zero",
+            "",
+            "div_zero.porth:4:12: Division by zero, / divides by 0\n",
+        ),
+    ] {
+        let out_file = dir.join(format!("{}_checked", name));
+        let output = test_bin::get_test_bin("worthc")
+            .arg(dir.join(name).with_extension("porth"))
+            .args(["-u", "build", "--checked", "-o"])
+            .arg(&out_file)
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        let output = Command::new(&out_file).output().unwrap();
+        std::fs::remove_file(&out_file).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), stdout);
+        assert_eq!(String::from_utf8_lossy(&output.stderr), stderr);
+    }
+}
+
+#[test]
+fn check_bounds() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let out_file = dir.join("out_of_bounds_check_bounds");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("out_of_bounds.porth"))
+        .args(["build", "--check-bounds", "-o"])
+        .arg(&out_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let output = Command::new(&out_file).output().unwrap();
+    std::fs::remove_file(&out_file).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    // Where mem ends depends on how the executable was laid out
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let address = stderr
+        .strip_prefix("out_of_bounds.porth:4:15: Invalid memory write of 1 bytes at 0x")
+        .and_then(|rest| rest.strip_suffix('\n'))
+        .unwrap_or_else(|| panic!("unexpected error: {}", stderr));
+    assert!(u64::from_str_radix(address, 16).is_ok());
+}
+
+#[test]
+fn source_asm() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let out_file = dir.join("regions_source");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("regions.porth"))
+        .args(["build", "--keep-asm", "-o"])
+        .arg(&out_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let asm_file = out_file.with_extension("asm");
+    let asm = std::fs::read_to_string(&asm_file).unwrap();
+    std::fs::remove_file(&asm_file).unwrap();
+    std::fs::remove_file(&out_file).unwrap();
+    assert!(asm.contains(";; regions.porth:10: a 42 .64\n"));
+    // Once for all the instructions on it
+    assert_eq!(asm.matches("regions.porth:11: a 8 + 7 .64").count(), 1);
+}
+
+#[test]
+fn labels() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let out_file = dir.join("rule110_labels");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("rule110.porth"))
+        .args(["build", "--keep-asm", "-o"])
+        .arg(&out_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let asm_file = out_file.with_extension("asm");
+    let asm = std::fs::read_to_string(&asm_file).unwrap();
+    std::fs::remove_file(&asm_file).unwrap();
+    std::fs::remove_file(&out_file).unwrap();
+    // Named for the keyword and where it is
+    assert!(asm.contains("\nwhile_5_2:\n"));
+    assert!(asm.contains("while_end_38_0"));
+    assert!(!asm.contains("addr_"));
+}
+
+#[test]
+fn emit_map() {
+    use worthc::json::Json;
+
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let out_file = dir.join("countdown");
+    let map_file = dir.join("countdown.map");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("countdown.porth"))
+        .args(["build", "-o"])
+        .arg(&out_file)
+        .arg("--emit-map")
+        .arg(&map_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let map = std::fs::read_to_string(&map_file).unwrap();
+    std::fs::remove_file(&map_file).unwrap();
+    std::fs::remove_file(&out_file).unwrap();
+    let Json::Array(labels) = Json::parse(&map).unwrap() else {
+        panic!("the map isn't an array: {}", map);
+    };
+    let label = |name: &str| {
+        labels
+            .iter()
+            .find(|label| label.get("label").and_then(Json::as_str) == Some(name))
+            .unwrap_or_else(|| panic!("{} isn't in the map: {}", name, map))
+    };
+    // Both loops come from the one in the macro
+    for name in ["while_5_2", "while_5_2_2"] {
+        let label = label(name);
+        assert_eq!(label.get("macro"), Some(&Json::String("countdown".into())));
+        let source = label.get("source").unwrap();
+        assert_eq!(
+            source.get("file").and_then(Json::as_str),
+            Some("countdown.porth")
+        );
+        assert_eq!(source.get("line"), Some(&Json::Number(5.0)));
+    }
+    assert_eq!(label("_start").get("source"), Some(&Json::Null));
+    // In order, each up to where the next starts
+    let addresses: Vec<_> = labels
+        .iter()
+        .map(|label| match (label.get("start"), label.get("end")) {
+            (Some(Json::Number(start)), Some(Json::Number(end))) => (*start, *end),
+            _ => panic!("no address range: {}", label),
+        })
+        .collect();
+    for pair in addresses.windows(2) {
+        assert!(pair[0].0 <= pair[0].1 && pair[0].1 <= pair[1].0);
+    }
+}
+
+#[test]
+fn emit() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let out_file = dir.join("hello_emitted");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("hello.porth"))
+        .args(["build", "--emit", "obj,asm", "--emit", "exe", "-o"])
+        .arg(&out_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let asm = std::fs::read_to_string(out_file.with_extension("asm")).unwrap();
+    let object = std::fs::read(out_file.with_extension("o")).unwrap();
+    let run = Command::new(&out_file)
+        .output()
+        .expect("failed to execute process");
+    for extension in ["asm", "o", ""] {
+        std::fs::remove_file(out_file.with_extension(extension)).unwrap();
+    }
+    // The assembly is kept like with --keep-asm, source and all
+    assert!(asm.contains(";; hello.porth:3:"));
+    assert!(object.starts_with(b"\x7fELF"));
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "Hello, World\n");
+
+    // Only what's asked for is left, where -o says, whatever its extension
+    for (emit, extension) in [("obj", "exe"), ("exe", "weird"), ("asm", "o")] {
+        let output = test_bin::get_test_bin("worthc")
+            .arg(dir.join("hello.porth"))
+            .args(["build", "--emit", emit, "-o"])
+            .arg(out_file.with_extension(extension))
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success(), "{}", emit);
+        let written = std::fs::read(out_file.with_extension(extension)).unwrap();
+        std::fs::remove_file(out_file.with_extension(extension)).unwrap();
+        for other in ["asm", "o", ""] {
+            assert!(!out_file.with_extension(other).exists(), "{}", emit);
+        }
+        match emit {
+            "asm" => assert!(written.starts_with(b"segment .bss")),
+            _ => assert!(written.starts_with(b"\x7fELF")),
+        }
+    }
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("hello.porth"))
+        .args(["build", "--emit", "exe", "-o"])
+        .arg(out_file.with_extension("weird"))
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let run = Command::new(out_file.with_extension("weird"))
+        .output()
+        .expect("failed to execute process");
+    std::fs::remove_file(out_file.with_extension("weird")).unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "Hello, World\n");
+}
+
+#[test]
+fn out_dir() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let out_dir = dir.join("out_dir");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("hello.porth"))
+        .args(["run", "-k", "--out-dir"])
+        .arg(&out_dir)
+        .current_dir(&dir)
+        .output()
+        .expect("failed to execute process");
+    let asm = out_dir.join("hello.asm").exists();
+    std::fs::remove_dir_all(&out_dir).unwrap();
+    assert!(output.status.success());
+    // Run found the executable where it was built
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello, World\n");
+    assert!(asm);
+    assert!(!dir.join("hello").exists());
+}
+
+#[test]
+fn run_exit_code() {
+    let dir = std::env::temp_dir().join(format!("worthc-exit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("exit.porth");
+    std::fs::write(&file, "42 60 syscall1 drop\n").unwrap();
+    let output = test_bin::get_test_bin("worthc")
+        .arg(&file)
+        .arg("run")
+        .current_dir(&dir)
+        .output()
+        .expect("failed to execute process");
+    assert_eq!(output.status.code(), Some(42));
+    assert!(!dir.join("exit").exists());
+
+    // Killed by SIGFPE
+    let output = test_bin::get_test_bin("worthc")
+        .arg(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/div_zero.porth"))
+        .arg("run")
+        .current_dir(&dir)
+        .output()
+        .expect("failed to execute process");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(128 + 8));
+}
+
+#[test]
+fn run_env() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let dir = std::env::temp_dir().join(format!("worthc-run-env-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let name = dir.join("name.in");
+    std::fs::write(&name, "Bob\n").unwrap();
+    for command in ["run", "simulate"] {
+        let run = |file: &str, args: &[&std::ffi::OsStr]| {
+            let output = test_bin::get_test_bin("worthc")
+                .arg(root.join(file))
+                .arg(command)
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .expect("failed to execute process");
+            assert!(output.status.success(), "{} {}", command, file);
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        };
+        let env = run(
+            "tests/env/env.porth",
+            &["--env".as_ref(), "WORTH_ENV=yes".as_ref()],
+        );
+        assert!(env.lines().any(|var| var == "WORTH_ENV=yes"), "{}", command);
+        let cwd = run(
+            "tests/env/cwd.porth",
+            &["--cwd".as_ref(), root.join("tests/env").as_os_str()],
+        );
+        assert_eq!(cwd, "from in\n");
+        let greeting = run(
+            "tests/programs/name.porth",
+            &["--stdin".as_ref(), name.as_os_str()],
+        );
+        assert_eq!(greeting, "What is your name? Hello, Bob! ( ^-^)/\n");
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn timeout() {
+    let dir = std::env::temp_dir().join(format!("worthc-timeout-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("forever.porth");
+    std::fs::write(&file, "while true do end\n").unwrap();
+    for (command, code) in [
+        ("run", "RunnerError::TimeoutExceeded"),
+        ("simulate", "RuntimeError::TimeoutExceeded"),
+    ] {
+        let output = test_bin::get_test_bin("worthc")
+            .args(["--message-format", "json"])
+            .arg(&file)
+            .args([command, "--timeout", "0.2"])
+            .current_dir(&dir)
+            .output()
+            .expect("failed to execute process");
+        assert!(!output.status.success());
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains(code),
+            "{}",
+            command
+        );
+    }
+    for timeout in ["-1", "nan"] {
+        let output = test_bin::get_test_bin("worthc")
+            .arg(&file)
+            .args(["simulate", &format!("--timeout={}", timeout)])
+            .current_dir(&dir)
+            .output()
+            .expect("failed to execute process");
+        assert_eq!(output.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains(&format!("{} isn't a number of seconds", timeout)));
+    }
+    let built = dir.join("forever").exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(!built);
+}
+
+#[test]
+fn max_steps() {
+    let dir = std::env::temp_dir().join(format!("worthc-max-steps-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("forever.porth"), "while true do end\n").unwrap();
+    std::fs::write(dir.join("add.porth"), "1 2 + print\n").unwrap();
+    let simulate = |file: &str, args: &[&str]| {
+        test_bin::get_test_bin("worthc")
+            .args(["--message-format", "json", file, "simulate"])
+            .args(args)
+            .current_dir(&dir)
+            .output()
+            .expect("failed to execute process")
+    };
+    // Four steps are enough for this, but not three
+    let output = simulate("add.porth", &["--max-steps", "4"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+    let output = simulate("add.porth", &["--max-steps", "3"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("RuntimeError::StepLimitExceeded"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Simulation stopped after 3 steps"),
+        "{}",
+        stderr
+    );
+
+    // On either path, a loop is stopped instead of running on
+    for args in [
+        &["--max-steps", "100"][..],
+        &["--max-steps", "100", "--profile", "1"],
+    ] {
+        let output = simulate("forever.porth", args);
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("RuntimeError::StepLimitExceeded"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("Simulation stopped after 100 steps"),
+            "{}",
+            stderr
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn log_level() {
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/hello.porth");
+    let dir = std::env::temp_dir().join(format!("worthc-log-level-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let stderr = |args: &[&str]| {
+        let output = test_bin::get_test_bin("worthc")
+            .arg(&file)
+            .arg("run")
+            .args(args)
+            .current_dir(&dir)
+            .output()
+            .expect("failed to execute process");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello, World\n");
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+    let all = stderr(&[]);
+    let info = stderr(&["--log-level", "info"]);
+    let quiet = stderr(&["-q"]);
+    let verbose = stderr(&["-v"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(all.contains("[CMD]") && all.contains("[INFO] Running"));
+    assert!(!all.contains("[DEBUG]"));
+    assert!(!info.contains("[CMD]") && info.contains("[INFO] Running"));
+    assert_eq!(quiet, "");
+    assert!(verbose.contains("[DEBUG]") && verbose.contains("[CMD]"));
+
+    // -v is before the command too, and can't be quiet as well
+    let output = test_bin::get_test_bin("worthc")
+        .args(["-v", "-q"])
+        .arg(&file)
+        .arg("simulate")
+        .output()
+        .expect("failed to execute process");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot be used with"));
+
+    // and reports what the optimizer removed
+    let out = std::env::temp_dir().join(format!("worthc-verbose-{}", std::process::id()));
+    let output = test_bin::get_test_bin("worthc")
+        .arg(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/dead.porth"))
+        .args(["build", "-v", "-o"])
+        .arg(&out)
+        .output()
+        .expect("failed to execute process");
+    let _ = std::fs::remove_file(&out);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Removed 5 instructions at dead.porth:7:12 that never run"));
+}
+
+#[test]
+fn timings() {
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/hello.porth");
+    let out = std::env::temp_dir().join(format!("worthc-timings-{}", std::process::id()));
+    let output = test_bin::get_test_bin("worthc")
+        .arg(&file)
+        .args(["build", "-q", "--timings", "-o"])
+        .arg(&out)
+        .output()
+        .expect("failed to execute process");
+    let _ = std::fs::remove_file(&out);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let phases: Vec<_> = stderr
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.trim_start().split("  ").next())
+        .collect();
+    assert_eq!(stderr.lines().next(), Some("Timings:"));
+    for phase in [
+        "parse",
+        "preprocess",
+        "include std.porth",
+        "typecheck",
+        "codegen",
+        "total",
+    ] {
+        assert!(phases.contains(&phase), "{} isn't in {:?}", phase, phases);
+    }
+    // Includes are inside preprocessing
+    assert!(stderr.contains("\n    include std.porth"));
+}
+
+#[test]
+fn keep_exe() {
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/hello.porth");
+    let dir = std::env::temp_dir().join(format!("worthc-keep-exe-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let run = |args: &[&str]| {
+        test_bin::get_test_bin("worthc")
+            .arg(&file)
+            .arg("run")
+            .args(args)
+            .current_dir(&dir)
+            .output()
+            .expect("failed to execute process")
+    };
+    let output = run(&["--keep-exe"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello, World\n");
+    let kept = dir.join("hello").exists();
+    let output = run(&["-o", "greeting"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello, World\n");
+    let kept_at = dir.join("greeting").exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(kept);
+    assert!(kept_at);
+}
+
+#[test]
+fn instrument() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let out_file = dir.join("countdown_instrumented");
+    let profile_file = dir.join("countdown_instrumented.profile");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("countdown.porth"))
+        .args(["build", "--instrument", "-o"])
+        .arg(&out_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    // The profile goes next to the executable wherever it's run from
+    let output = Command::new(&out_file)
+        .current_dir(std::env::temp_dir())
+        .output()
+        .expect("failed to execute process");
+    std::fs::remove_file(&out_file).unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n2\n1\n2\n1\n");
+    let profile = std::fs::read_to_string(&profile_file).unwrap();
+    std::fs::remove_file(&profile_file).unwrap();
+    // Both loops are counted for the macro they're written in
+    assert_eq!(
+        profile,
+        "1 countdown.porth:8:0\n\
+         7 countdown.porth:5:2\n\
+         5 countdown.porth:5:16\n\
+         2 countdown.porth:5:33\n"
+    );
+}
+
+#[test]
+fn jump_threading() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let out_file = dir.join("jumps_threaded");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(dir.join("jumps.porth"))
+        .args(["build", "--keep-asm", "-o"])
+        .arg(&out_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let asm_file = out_file.with_extension("asm");
+    let asm = std::fs::read_to_string(&asm_file).unwrap();
+    std::fs::remove_file(&asm_file).unwrap();
+    std::fs::remove_file(&out_file).unwrap();
+    let jumps: Vec<_> = asm
+        .lines()
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [op, target, ..] if op.starts_with('j') => Some((op, target)),
+                _ => None,
+            },
+        )
+        .collect();
+    let jumps_to = |label| jumps.iter().any(|&(_, target)| target == label);
+    // The inner else goes past the end it used to jump to
+    assert!(jumps.contains(&("jmp", "if_end_8_0")));
+    assert!(!jumps_to("if_end_5_38"));
+    // Empty blocks don't jump over nothing
+    assert!(!jumps_to("if_end_11_28"));
+    assert!(jumps.contains(&("jnz", "if_end_12_28")));
+}
+
+#[test]
+fn file_after_command() {
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/args.porth");
+    let simulate = |args: &[&str]| {
+        let output = test_bin::get_test_bin("worthc")
+            .args(args.iter().map(|arg| match *arg {
+                "FILE" => file.as_os_str(),
+                arg => arg.as_ref(),
+            }))
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .skip(1)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    assert_eq!(simulate(&["simulate", "FILE", "--", "a", "b"]), "a b");
+    // The file still goes first too, where what follows the command is all
+    // for the program
+    assert_eq!(simulate(&["FILE", "simulate", "--", "a", "b"]), "a b");
+    assert_eq!(simulate(&["FILE", "simulate", "a", "b"]), "a b");
+
+    // repl is the one that doesn't need a file
+    let output = test_bin::get_test_bin("worthc")
+        .arg("repl")
+        .env("HOME", std::env::temp_dir())
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn fmt_check() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let file = dir.join("fmt_check.porth");
+    let source = "macro  twice dup +   end\n1 twice print";
+    std::fs::write(&file, source).unwrap();
+    let fmt = |check: bool| {
+        let mut fmt = test_bin::get_test_bin("worthc");
+        fmt.arg("fmt").arg(&file);
+        if check {
+            fmt.arg("--check");
+        }
+        fmt.output().expect("failed to execute process")
+    };
+    let check = fmt(true);
+    let unchanged = std::fs::read_to_string(&file).unwrap();
+    let formatted = fmt(false);
+    let result = std::fs::read_to_string(&file).unwrap();
+    std::fs::remove_file(&file).unwrap();
+    assert_eq!(check.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&check.stderr).contains("fmt_check.porth isn't formatted"));
+    assert_eq!(unchanged, source);
+    assert!(formatted.status.success());
+    assert_eq!(
+        result,
+        worthc::fmt::format(source, &Default::default()).unwrap()
+    );
+    assert_ne!(result, source);
+}
+
+/// Formats `tests/fmt/{name}.porth`, which has to come out as `{name}.fmt`, and
+/// that again, which has to leave it as it is.
+fn fmt_runner(name: &str) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fmt");
+    let source = std::fs::read_to_string(dir.join(name).with_extension("porth")).unwrap();
+    let expected = std::fs::read_to_string(dir.join(name).with_extension("fmt")).unwrap();
+    let formatted = worthc::fmt::format(&source, &Default::default()).unwrap();
+    assert_eq!(formatted, expected, "\n{} formatted differently", name);
+    let again = worthc::fmt::format(&formatted, &Default::default()).unwrap();
+    assert_eq!(again, formatted, "\n{} changed formatting it again", name);
+}
+
+#[test]
+fn fmt_blank_lines() {
+    fmt_runner("blank_lines");
+}
+
+#[test]
+fn fmt_blocks() {
+    fmt_runner("blocks");
+}
+
+#[test]
+fn fmt_comment_lines() {
+    fmt_runner("comments");
+}
+
+#[test]
+fn fmt_literals() {
+    fmt_runner("literals");
+}
+
+#[test]
+fn fmt_idempotent() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let programs = std::fs::read_dir(root.join("tests/programs"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "porth"));
+    for file in programs.chain([root.join("std.porth")]) {
+        let source = std::fs::read_to_string(&file).unwrap();
+        let formatted = worthc::fmt::format(&source, &Default::default()).unwrap();
+        let again = worthc::fmt::format(&formatted, &Default::default()).unwrap();
+        assert_eq!(again, formatted, "\n{:?} changed formatting it again", file);
+    }
+}
+
+#[test]
+fn fmt_comments() {
+    let source = "// Counts to three\n0 while dup 3 < do // each\n  // one a line\n  dup print 1 +\nend drop\n//\n1 print\n";
+    let formatted = worthc::fmt::format(source, &Default::default()).unwrap();
+    let lines: Vec<&str> = formatted.lines().collect();
+    assert_eq!(lines[0], "// Counts to three");
+    assert!(lines.contains(&"0 while dup 3 < do // each"));
+    assert!(lines.contains(&"    // one a line"));
+    // An empty comment doesn't take the line after it
+    let i = lines.iter().position(|line| *line == "//").unwrap();
+    assert_eq!(lines[i + 1], "1 print");
+}
+
+#[test]
+fn fmt_config() {
+    use worthc::cli::{FmtConfig, Newline};
+
+    let values: Vec<String> = (1..=20).map(|n| n.to_string()).collect();
+    let source = format!("0 while dup 3 < do\n{}\nend drop", values.join(" "));
+    let config = FmtConfig {
+        indent: 2,
+        max_width: 24,
+        newline: Newline::Crlf,
+    };
+    let formatted = worthc::fmt::format(&source, &config).unwrap();
+    let lines: Vec<&str> = formatted.split("\r\n").collect();
+    assert!(!formatted.replace("\r\n", "").contains('\n'));
+    assert!(lines.contains(&"  1 2 3 4 5 6 7 8 9 10"));
+    assert!(lines.contains(&"  11 12 13 14 15 16 17"));
+    assert!(lines.contains(&"  18 19 20"));
+
+    let unwrapped = worthc::fmt::format(
+        &source,
+        &FmtConfig {
+            max_width: 0,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert!(unwrapped.contains(&format!("\n    {}\n", values.join(" "))));
+}
+
+#[test]
+fn doc() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/doc");
+    let output = test_bin::get_test_bin("worthc")
+        .arg("doc")
+        .arg(dir.join("words.porth"))
+        .output()
+        .expect("failed to run worthc doc");
+    assert!(output.status.success());
+    let expected = std::fs::read_to_string(dir.join("words.md")).unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+
+    let html = std::env::temp_dir().join(format!("worthc-doc-{}.html", std::process::id()));
+    let output = test_bin::get_test_bin("worthc")
+        .arg("doc")
+        .arg(dir.join("words.porth"))
+        .arg("-o")
+        .arg(&html)
+        .output()
+        .expect("failed to run worthc doc");
+    assert!(output.status.success());
+    let written = std::fs::read_to_string(&html).unwrap();
+    std::fs::remove_file(&html).unwrap();
+    assert!(written.starts_with("<!DOCTYPE html>"));
+    assert!(written.contains("<h2><code>zero?</code></h2>\n<p>Whether it's zero</p>"));
+    assert!(written.contains("<code>any -&gt; any any</code>"));
+}
+
+#[test]
+fn manifest() {
+    use worthc::manifest::{Dependency, Manifest, Source};
+
+    let dir = std::env::temp_dir().join(format!("worthc-manifest-{}", std::process::id()));
+    let project = dir.join("project");
+    for sub in ["src", "lib"] {
+        std::fs::create_dir_all(project.join(sub)).unwrap();
+    }
+    std::fs::create_dir_all(dir.join("dep/lib")).unwrap();
+    let std = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("std.porth");
+    std::fs::write(
+        project.join("worth.toml"),
+        "[package]\nname = \"sum\" # what's built\nentry = \"src/main.porth\"\ninclude = [\"lib\"]\n\n[dependencies]\ndep = { path = \"../dep\" }\n",
+    )
+    .unwrap();
+    std::fs::write(
+        project.join("src/main.porth"),
+        format!("include {:?}\ninclude \"one.porth\"\ninclude \"two.porth\"\ninclude \"three.porth\"\none two + three + print\n", std),
+    )
+    .unwrap();
+    std::fs::write(project.join("lib/one.porth"), "macro one 1 end\n").unwrap();
+    // Found through the dependency's own manifest
+    std::fs::write(
+        dir.join("dep/worth.toml"),
+        "[package]\nname = \"dep\"\ninclude = [\"lib\"]\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("dep/two.porth"), "macro two 2 end\n").unwrap();
+    std::fs::write(dir.join("dep/lib/three.porth"), "macro three 3 end\n").unwrap();
+
+    let output = test_bin::get_test_bin("worthc")
+        .arg("build")
+        .current_dir(project.join("src"))
+        .output()
+        .expect("failed to build the project");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let output = Command::new(project.join("sum")).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "6\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let manifest = Manifest::parse(
+        "[package]\nname = \"x\"\n[dependencies]\ny = { git = \"https://example.com/y.git\", rev = \"v1\" }\n",
+        &PathBuf::from("/x"),
+    )
+    .unwrap();
+    assert_eq!(manifest.entry(), PathBuf::from("/x/main.porth"));
+    assert_eq!(
+        manifest.dependencies,
+        [Dependency {
+            name: "y".to_string(),
+            source: Source::Git {
+                url: "https://example.com/y.git".to_string(),
+                rev: Some("v1".to_string())
+            }
+        }]
+    );
+    for invalid in [
+        "[package]\n",
+        "[package]\nname = [\"x\"]\n",
+        "[package]\nname = \"x\" y\n",
+        "[package]\nname = \"x\"\n[dependencies]\ny = {}\n",
+        "[package]\nname = \"x\"\n[dependencies]\ny = { git = \"y\", rev = \"--orphan=z\" }\n",
+    ] {
+        assert!(
+            Manifest::parse(invalid, &PathBuf::from(".")).is_err(),
+            "{:?}",
+            invalid
+        );
+    }
+}
+
+#[test]
+fn git_dependencies() {
+    use worthc::manifest::Manifest;
+
+    let dir = std::env::temp_dir().join(format!("worthc-git-deps-{}", std::process::id()));
+    let repo = dir.join("repo");
+    std::fs::create_dir_all(&repo).unwrap();
+    let git = |args: &[&str]| {
+        let status = Command::new("git")
+            .args([
+                "-c",
+                "user.name=worth",
+                "-c",
+                "user.email=worth@example.com",
+            ])
+            .args(args)
+            .current_dir(&repo)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "{:?}", args);
+    };
+    git(&["init", "--quiet"]);
+    for answer in ["1", "2"] {
+        std::fs::write(
+            repo.join("lib.porth"),
+            format!("macro answer {} end\n", answer),
+        )
+        .unwrap();
+        git(&["add", "lib.porth"]);
+        git(&["commit", "--quiet", "-m", answer]);
+        git(&["tag", &format!("v{}", answer)]);
+    }
+    let project = dir.join("project");
+    std::fs::create_dir_all(&project).unwrap();
+    let fetch = |url: &str, rev: &str| {
+        let manifest = format!(
+            "[package]\nname = \"x\"\n[dependencies]\ny = {{ git = {:?}, rev = {:?} }}\n",
+            url, rev
+        );
+        Manifest::parse(&manifest, &project)?.search_path()
+    };
+    let url = repo.to_string_lossy().into_owned();
+    let lib = project.join(".worth/deps/y/lib.porth");
+    let answer = || std::fs::read_to_string(&lib).unwrap();
+
+    assert_eq!(fetch(&url, "v1").unwrap(), [project.join(".worth/deps/y")]);
+    assert_eq!(answer(), "macro answer 1 end\n");
+    // Kept while the rev's the same, and fetched again when it isn't
+    std::fs::write(&lib, "changed\n").unwrap();
+    fetch(&url, "v1").unwrap();
+    assert_eq!(answer(), "changed\n");
+    fetch(&url, "v2").unwrap();
+    assert_eq!(answer(), "macro answer 2 end\n");
+    // Or when it was cut short before it was finished
+    std::fs::remove_file(project.join(".worth/deps/y/.git/worth-fetched")).unwrap();
+    std::fs::write(&lib, "half\n").unwrap();
+    fetch(&url, "v2").unwrap();
+    assert_eq!(answer(), "macro answer 2 end\n");
+
+    // A url that looks like an option is still taken as a url
+    let pwned = dir.join("pwned");
+    let err = fetch(
+        &format!("--upload-pack=touch {}", pwned.to_string_lossy()),
+        "v1",
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<worthc::error::Error>().map(|e| e.code()),
+        Some("ManifestError::FetchFailed".to_string())
+    );
+    assert!(!pwned.exists());
+    assert!(!project.join(".worth/deps/y").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dump_tokens() {
+    use worthc::json::Json;
+
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fmt/comments.porth");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(&file)
+        .arg("dump-tokens")
+        .output()
+        .expect("failed to run worthc dump-tokens");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(
+        lines[0],
+        "comments.porth:1:0   comment    \"// Counts to three\""
+    );
+    assert_eq!(lines[1], "comments.porth:2:0   int        \"0\"");
+    assert_eq!(lines[2], "comments.porth:2:2   keyword    \"while\"");
+
+    let output = test_bin::get_test_bin("worthc")
+        .arg("dump-tokens")
+        .arg(&file)
+        .arg("--json")
+        .output()
+        .expect("failed to run worthc dump-tokens");
+    let tokens: Vec<_> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| Json::parse(line).unwrap())
+        .collect();
+    assert_eq!(tokens.len(), lines.len());
+    assert_eq!(tokens[1].get("kind").and_then(Json::as_str), Some("int"));
+    assert_eq!(tokens[2].get("value").and_then(Json::as_str), Some("while"));
+    assert_eq!(
+        tokens[0].get("file").and_then(Json::as_str),
+        Some("comments.porth")
+    );
+    assert_eq!(tokens[2].get("line").and_then(Json::as_i64), Some(2));
+    assert_eq!(tokens[2].get("col").and_then(Json::as_i64), Some(2));
+}
+
+#[test]
+fn dump_ir() {
+    use worthc::json::Json;
+
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fmt/blocks.porth");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(&file)
+        .arg("dump-ir")
+        .output()
+        .expect("failed to run worthc dump-ir");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines[1], " 1  while           blocks.porth:5:2");
+    assert_eq!(lines[5], " 5  do       -> 43  blocks.porth:5:16");
+    assert_eq!(lines[11], "11  counter         blocks.porth:3:0    in bump");
+    assert_eq!(lines[43], "43  end      -> 1   blocks.porth:16:0");
+
+    let output = test_bin::get_test_bin("worthc")
+        .arg("dump-ir")
+        .arg(&file)
+        .arg("--json")
+        .output()
+        .expect("failed to run worthc dump-ir");
+    let insts: Vec<_> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| Json::parse(line).unwrap())
+        .collect();
+    assert_eq!(insts.len(), lines.len());
+    assert_eq!(insts[5].get("jump").and_then(Json::as_i64), Some(43));
+    assert_eq!(insts[6].get("jump"), Some(&Json::Null));
+    assert_eq!(insts[11].get("macro").and_then(Json::as_str), Some("bump"));
+    assert_eq!(insts[11].get("line").and_then(Json::as_i64), Some(3));
+}
+
+#[test]
+fn emit_ir() {
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/include.porth");
+    let ir = std::env::temp_dir().join(format!("worthc-ir-{}.ir", std::process::id()));
+    let output = test_bin::get_test_bin("worthc")
+        .arg("build")
+        .arg(&file)
+        .arg("--emit")
+        .arg("ir")
+        .arg("-o")
+        .arg(&ir)
+        .output()
+        .expect("failed to run worthc build");
+    assert!(output.status.success());
+    assert!(ir.exists());
+
+    let original = worthc::program::load_program(&file).unwrap();
+    let loaded = worthc::program::load_program(&ir).unwrap();
+    assert_eq!(
+        worthc::ir::to_json(&loaded).to_string(),
+        worthc::ir::to_json(&original).to_string()
+    );
+
+    let simulate = |file: &PathBuf| {
+        test_bin::get_test_bin("worthc")
+            .arg("simulate")
+            .arg(file)
+            .output()
+            .expect("failed to run worthc simulate")
+    };
+    let from_ir = simulate(&ir);
+    assert!(from_ir.status.success());
+    assert_eq!(from_ir.stdout, simulate(&file).stdout);
+
+    std::fs::write(&ir, "{\"format\":\"worth-ir\",\"version\":0}").unwrap();
+    let output = simulate(&ir);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("version 0, not 1"));
+    std::fs::remove_file(&ir).unwrap();
+}
+
+#[test]
+fn link() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/link");
+    let expected = "0\n1\n2\n5\n100\n2\n";
+    let output = test_bin::get_test_bin("worthc")
+        .arg("simulate")
+        .arg(dir.join("first.porth"))
+        .arg("--link")
+        .arg(dir.join("second.porth"))
+        .output()
+        .expect("failed to run worthc simulate");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+
+    let exe = std::env::temp_dir().join(format!("worthc-link-{}", std::process::id()));
+    let output = test_bin::get_test_bin("worthc")
+        .arg("build")
+        .arg(dir.join("first.porth"))
+        .arg("--link")
+        .arg(dir.join("second.porth"))
+        .arg("-o")
+        .arg(&exe)
+        .output()
+        .expect("failed to run worthc build");
+    assert!(output.status.success());
+    let output = std::process::Command::new(&exe).output().unwrap();
+    let _ = std::fs::remove_file(&exe);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+
+    let output = test_bin::get_test_bin("worthc")
+        .arg("simulate")
+        .arg(dir.join("first.porth"))
+        .arg("--link")
+        .arg(dir.join("clash.porth"))
+        .output()
+        .expect("failed to run worthc simulate");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Memory counter is declared by clash"));
+}
+
+#[test]
+fn sim_div_zero() {
+    use worthc::error::Diagnostic;
+
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/div_zero.porth");
+    let output = test_bin::get_test_bin("worthc")
+        .arg(&file)
+        .arg("simulate")
+        .output()
+        .expect("failed to execute process");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Division by zero: / divides by 0\n\nat div_zero.porth:4:12"));
+
+    // Stepping doesn't take the same path as running it all at once
+    let program = worthc::program::load_program(&file).unwrap();
+    let mut state =
+        worthc::sim::SimulationState::new(&program.instructions, &["div_zero".into()]).unwrap();
+    let err = state.step(&program.instructions, 100).unwrap_err();
+    let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+    assert_eq!(diagnostic.loc, ("div_zero.porth".to_string(), 4, 12));
+}
+
+#[test]
+fn sim_wrapping() {
+    let file = std::env::temp_dir().join(format!("worthc-wrapping-{}.porth", std::process::id()));
+    std::fs::write(
+        &file,
+        "9223372036854775807 1 + print\n1 65 shl print\n0 1 - 9223372036854775807 * print\n\
+         9223372036854775807 cast(ptr) 8 align cast(int) print\n",
+    )
+    .unwrap();
+    // Overflowing wraps around like it does built, stepped through or not
+    for flags in [&[][..], &["--profile", "1"]] {
+        let output = test_bin::get_test_bin("worthc")
+            .arg("simulate")
+            .args(flags)
+            .arg(&file)
+            .output()
+            .expect("failed to run worthc simulate");
+        assert!(output.status.success(), "{:?}", flags);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "9223372036854775808\n2\n9223372036854775809\n9223372036854775808\n"
+        );
+    }
+    std::fs::remove_file(&file).unwrap();
+}
+
+/// `buf drop`, with `buf` a pointer to a name nothing resolved. Only IR can
+/// have one.
+const PTR_IR: &str = concat!(
+    "{\"format\":\"worth-ir\",\"version\":1,\"name\":\"ptr\",\"base_path\":\".\",",
+    "\"strings\":[],\"memories\":[],\"macros\":[],\"includes\":[],\"instructions\":[",
+    "{\"kind\":\"ptr\",\"value\":\"buf\",\"file\":\"ptr.porth\",\"line\":1,\"col\":0},",
+    "{\"kind\":\"intrinsic\",\"name\":\"drop\",\"file\":\"ptr.porth\",\"line\":1,\"col\":4}]}"
+);
+
+#[test]
+fn sim_unknown_syscall() {
+    let dir = std::env::temp_dir().join(format!("worthc-enosys-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let simulate = |file: &str| {
+        test_bin::get_test_bin("worthc")
+            .arg("simulate")
+            .arg(dir.join(file))
+            .output()
+            .expect("failed to run worthc simulate")
+    };
+    // Like the kernel, syscalls it doesn't have fail with -ENOSYS
+    std::fs::write(
+        dir.join("enosys.porth"),
+        "500 syscall0 print\n0 0 0 501 syscall3 print\n",
+    )
+    .unwrap();
+    let output = simulate("enosys.porth");
+    assert!(output.status.success());
+    let enosys = format!("{}\n", -38i64 as u64);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), enosys.repeat(2));
+
+    // A pointer to a name can't be simulated
+    std::fs::write(dir.join("ptr.ir"), PTR_IR).unwrap();
+    let output = simulate("ptr.ir");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("pointer to buf at 0, which isn't resolved"),
+        "{}",
+        stderr
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn color() {
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/underflow.porth");
+    let stderr = |args: &[&str], no_color: bool| {
+        let mut command = test_bin::get_test_bin("worthc");
+        command.arg(&file).arg("simulate").args(args);
+        if no_color {
+            command.env("NO_COLOR", "1");
+        } else {
+            command.env_remove("NO_COLOR");
+        }
+        let output = command.output().expect("failed to execute process");
+        assert!(!output.status.success());
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+    // stderr isn't a terminal here
+    assert!(!stderr(&[], false).contains('\x1b'));
+    assert!(stderr(&["--color", "always"], false).contains("\x1b[91m"));
+    assert!(stderr(&["--color", "always"], true).contains("\x1b[91m"));
+    assert!(!stderr(&["--color", "never"], false).contains('\x1b'));
+}
+
+#[test]
+fn lint() {
+    use worthc::lint::{lint, Lint};
+
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/lint/lint.porth");
+    let found = |allow: &[Lint]| -> Vec<(Lint, usize, usize)> {
+        lint(&file, allow)
+            .unwrap()
+            .into_iter()
+            .map(|warning| (warning.lint, warning.loc.1, warning.loc.2))
+            .collect()
+    };
+    // The dropped push on line 12 is allowed by the comment before it
+    assert_eq!(
+        found(&[]),
+        [
+            (Lint::UnusedMacro, 3, 6),
+            (Lint::ShadowedName, 6, 6),
+            (Lint::DroppedPush, 10, 0),
+            (Lint::ConstantComparison, 13, 4),
+            (Lint::Unreachable, 18, 0),
+        ]
+    );
+    assert_eq!(
+        found(&[Lint::UnusedMacro, Lint::Unreachable]),
+        [
+            (Lint::ShadowedName, 6, 6),
+            (Lint::DroppedPush, 10, 0),
+            (Lint::ConstantComparison, 13, 4),
+        ]
+    );
+
+    let status = |args: &[&str]| {
+        test_bin::get_test_bin("worthc")
+            .arg("lint")
+            .arg(&file)
+            .args(args)
+            .output()
+            .expect("failed to run worthc lint")
+            .status
+    };
+    assert!(!status(&[]).success());
+    assert!(status(&[
+        "-A",
+        "unused-macro",
+        "-A",
+        "shadowed-name",
+        "--allow",
+        "dropped-push",
+        "--allow",
+        "constant-comparison",
+        "--allow",
+        "unreachable"
+    ])
+    .success());
+}
+
+#[test]
+fn stats() {
+    use worthc::stats::{stats, MacroStats};
+
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/stats/stats.porth");
+    let stats = stats(&file, 1).unwrap();
+    assert_eq!(stats.instructions, 20);
+    assert!(stats.kinds.contains(&("push string".to_string(), 4)));
+    let macro_ = |name: &str| stats.macros.iter().find(|m| m.name == name).cloned();
+    // greet is used once itself and twice by twice
+    assert_eq!(
+        macro_("greet"),
+        Some(MacroStats {
+            name: "greet".to_string(),
+            uses: 3,
+            written: 2,
+            expanded: 5,
+        })
+    );
+    assert_eq!(macro_("twice").map(|m| (m.uses, m.expanded)), Some((1, 10)));
+    // std's macros are only there if they're used
+    assert_eq!(macro_("exit"), None);
+    assert_eq!(stats.macros[0].name, "puts");
+    assert_eq!(stats.strings, (1, 3));
+    let size = stats.size.unwrap();
+    assert_eq!(size.rodata, 3);
+    assert!(size.text > 0 && size.file > size.text);
+
+    let output = test_bin::get_test_bin("worthc")
+        .arg("stats")
+        .arg(&file)
+        .arg("--json")
+        .output()
+        .expect("failed to run worthc stats");
+    assert!(output.status.success());
+    let json = worthc::json::Json::parse(String::from_utf8_lossy(&output.stdout).trim()).unwrap();
+    assert_eq!(json.get("instructions").and_then(|n| n.as_i64()), Some(20));
+}
+
+#[test]
+fn sarif() {
+    use worthc::json::Json;
+
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let sarif = |args: &[&str]| {
+        let output = test_bin::get_test_bin("worthc")
+            .current_dir(&root)
+            .args(args)
+            .args(["--message-format", "sarif"])
+            .output()
+            .expect("failed to execute process");
+        assert!(!output.status.success());
+        Json::parse(String::from_utf8_lossy(&output.stdout).trim()).unwrap()
+    };
+    let results = |log: &Json| {
+        assert_eq!(log.get("version").and_then(|v| v.as_str()), Some("2.1.0"));
+        let run = &log.get("runs").and_then(|runs| runs.as_array()).unwrap()[0];
+        run.get("results")
+            .and_then(|results| results.as_array())
+            .unwrap()
+            .iter()
+            .map(|result| {
+                let location = &result.get("locations").and_then(|l| l.as_array()).unwrap()[0]
+                    .get("physicalLocation")
+                    .unwrap()
+                    .clone();
+                let field = |path: &[&str]| {
+                    let mut json = Some(location);
+                    for key in path {
+                        json = json.and_then(|json| json.get(key));
+                    }
+                    json.cloned().unwrap()
+                };
+                (
+                    result
+                        .get("ruleId")
+                        .and_then(|r| r.as_str())
+                        .unwrap()
+                        .to_string(),
+                    result
+                        .get("level")
+                        .and_then(|l| l.as_str())
+                        .unwrap()
+                        .to_string(),
+                    field(&["artifactLocation", "uri"])
+                        .as_str()
+                        .unwrap()
+                        .to_string(),
+                    field(&["region", "startLine"]).as_i64().unwrap(),
+                    field(&["region", "startColumn"]).as_i64().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let lint = sarif(&["lint", "tests/lint/lint.porth"]);
+    let found = results(&lint);
+    assert_eq!(found.len(), 5);
+    assert_eq!(
+        found[0],
+        (
+            "unused-macro".to_string(),
+            "warning".to_string(),
+            "tests/lint/lint.porth".to_string(),
+            3,
+            7
+        )
+    );
+    let run = &lint.get("runs").and_then(|runs| runs.as_array()).unwrap()[0];
+    let rules = run
+        .get("tool")
+        .and_then(|tool| tool.get("driver"))
+        .and_then(|driver| driver.get("rules"))
+        .and_then(|rules| rules.as_array())
+        .unwrap();
+    assert_eq!(rules.len(), 5);
+
+    let error = sarif(&["simulate", "tests/programs/underflow.porth"]);
+    assert_eq!(
+        results(&error),
+        [(
+            "TypecheckError::StackUnderflow".to_string(),
+            "error".to_string(),
+            "tests/programs/underflow.porth".to_string(),
+            5,
+            1
+        )]
+    );
+}
+
+#[test]
+fn message_format_json() {
+    use worthc::json::Json;
+
+    let output = test_bin::get_test_bin("worthc")
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["simulate", "tests/programs/underflow.porth"])
+        .args(["--message-format", "json"])
+        .output()
+        .expect("failed to execute process");
+    assert!(!output.status.success());
+    let diagnostic = Json::parse(String::from_utf8_lossy(&output.stderr).trim()).unwrap();
+    assert_eq!(
+        diagnostic.get("code").and_then(Json::as_str),
+        Some("TypecheckError::StackUnderflow")
+    );
+    assert_eq!(diagnostic.get("id").and_then(Json::as_str), Some("W0015"));
+    assert_eq!(
+        diagnostic.get("file").and_then(Json::as_str),
+        Some("underflow.porth")
+    );
+    // Counted from 1 like SARIF does, where the text says :5:0
+    assert_eq!(diagnostic.get("line").and_then(Json::as_i64), Some(5));
+    assert_eq!(diagnostic.get("column").and_then(Json::as_i64), Some(1));
+
+    // Quotes are escaped, so it's still one line that parses
+    let dir = std::env::temp_dir().join(format!("worthc-json-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a\"b.porth"), "x\"y\n").unwrap();
+    let output = test_bin::get_test_bin("worthc")
+        .current_dir(&dir)
+        .args(["simulate", "a\"b.porth", "--message-format", "json"])
+        .output()
+        .expect("failed to execute process");
+    std::fs::remove_dir_all(&dir).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.lines().count(), 1, "{}", stderr);
+    let diagnostic = Json::parse(stderr.trim()).unwrap();
+    assert_eq!(
+        diagnostic.get("file").and_then(Json::as_str),
+        Some("a\"b.porth")
+    );
+    assert!(diagnostic
+        .get("message")
+        .and_then(Json::as_str)
+        .is_some_and(|message| message.starts_with("Unknown name x\"y")));
+}
+
+#[test]
+fn explain() {
+    use worthc::explain::{self, EXPLANATIONS};
+
+    for (at, (code, error, text)) in EXPLANATIONS.iter().enumerate() {
+        assert_eq!(*code, format!("W{:04}", at + 1));
+        assert_eq!(explain::code(error), Some(*code));
+        assert!(text.ends_with('\n'), "{} doesn't end in a newline", code);
+    }
+    assert_eq!(
+        explain::explain("w15"),
+        explain::explain("TypecheckError::StackUnderflow")
+    );
+
+    let worthc = |args: &[&str]| {
+        test_bin::get_test_bin("worthc")
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .args(args)
+            .output()
+            .expect("failed to execute process")
+    };
+    let output = worthc(&["explain", "W0015"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("W0015 (TypecheckError::StackUnderflow)\n\n"));
+    assert!(stdout.contains("    1 2 +\n"));
+    assert!(!worthc(&["explain", "W9999"]).status.success());
+
+    let output = worthc(&["simulate", "tests/programs/underflow.porth"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("[Typecheck Error W0015] Stack Underflow"));
+    assert!(stderr.contains("try `worthc explain W0015`"));
+}
+
+#[test]
+fn config() {
+    use clap::{CommandFactory, FromArgMatches};
+    use worthc::cli::{Cli, Command as Subcommand, Target};
+    use worthc::config::Config;
+
+    let dir = std::env::temp_dir().join(format!("worthc-config-{}", std::process::id()));
+    for sub in ["lib", "src"] {
+        std::fs::create_dir_all(dir.join(sub)).unwrap();
+    }
+    std::fs::write(
+        dir.join(".worth.toml"),
+        "[defaults]\nkeep-asm = true\nout-dir = \"build\"\ninclude = [\"lib\"]\ntarget = \"wasm32\"\nstrict = true\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("main.porth"), "include \"one.porth\"\none print\n").unwrap();
+    std::fs::write(dir.join("lib/one.porth"), "macro one 1 end\n").unwrap();
+    std::fs::write(dir.join("bad.porth"), "1 drop\n").unwrap();
+
+    let build = |file: &str| {
+        test_bin::get_test_bin("worthc")
+            .args(["build", file, "--target", "x86_64-linux", "-o", "main"])
+            .current_dir(dir.join("src"))
+            .output()
+            .expect("failed to build")
+    };
+    let output = build("../main.porth");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(dir.join("build/main.asm").is_file());
+    let output = Command::new(dir.join("build/main")).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    // Strict, so the warning stops the build
+    let output = build("../bad.porth");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("[dropped-push]"));
+
+    let config = Config::load(&Config::find(&dir.join("src")).unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(config.search_path(), [dir.join("lib")]);
+    let target = |args: &[&str]| {
+        let matches = Cli::command().get_matches_from(args);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        config.apply(&mut cli, &matches);
+        match cli.command {
+            Some(Subcommand::Build(opt)) => (opt.target, opt.out_dir, opt.keep_asm),
+            _ => unreachable!(),
+        }
+    };
+    assert_eq!(
+        target(&["worthc", "build", "x.porth"]),
+        (Target::Wasm32, Some(dir.join("build")), true)
+    );
+    assert_eq!(
+        target(&[
+            "worthc",
+            "build",
+            "x.porth",
+            "--target",
+            "riscv64-linux",
+            "--out-dir",
+            "out"
+        ]),
+        (Target::Riscv64Linux, Some(PathBuf::from("out")), true)
+    );
+
+    for invalid in [
+        "[defaults]\nkeep-asm = \"yes\"\n",
+        "[defaults]\ntarget = \"z80\"\n",
+        "[defaults]\nopt = 2\n",
+        "[defaults]\nstrict = maybe\n",
+    ] {
+        assert!(
+            Config::parse(invalid, &PathBuf::from(".")).is_err(),
+            "{:?}",
+            invalid
+        );
+    }
+}
+
+#[test]
+fn target_triples() {
+    use clap::ValueEnum;
+    use worthc::cli::Target;
+    use worthc::codegen::{Arch, Os};
+
+    for target in Target::value_variants() {
+        assert_eq!(Target::from_str(target.triple(), false), Ok(*target));
+        assert!(target.triple().starts_with(match target.arch() {
+            Arch::X86_64 => "x86_64-",
+            Arch::Aarch64 => "aarch64-",
+            Arch::Riscv64 => "riscv64-",
+            Arch::Wasm32 => "wasm32-",
+        }));
+    }
+    assert_eq!(Target::X86_64Macos.os(), Os::Macos);
+
+    let asm = std::env::temp_dir().join(format!("worthc-triple-{}.asm", std::process::id()));
+    let output = test_bin::get_test_bin("worthc")
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["build", "tests/programs/align.porth"])
+        .args(["--target", "aarch64-unknown-linux-gnu", "-o"])
+        .arg(&asm)
+        .output()
+        .expect("failed to build");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // GNU as syntax, not nasm's
+    assert!(std::fs::read_to_string(&asm).unwrap().contains(".balign"));
+    std::fs::remove_file(&asm).unwrap();
+}
+
+#[test]
+fn init() {
+    let dir = std::env::temp_dir().join(format!("worthc-init-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let worthc = |args: &[&str], cwd: &PathBuf| {
+        test_bin::get_test_bin("worthc")
+            .args(args)
+            .current_dir(cwd)
+            .output()
+            .expect("failed to run worthc")
+    };
+    let output = worthc(&["init", "hello"], &dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let project = dir.join("hello");
+    for file in [
+        "worth.toml",
+        "src/main.porth",
+        "tests/main.txt",
+        ".gitignore",
+    ] {
+        assert!(project.join(file).is_file(), "{} wasn't made", file);
+    }
+    // Not made again over what's there
+    assert!(!worthc(&["init", "hello"], &dir).status.success());
+
+    assert!(worthc(&["build"], &project).status.success());
+    let output = Command::new(project.join("hello")).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello, World!\n");
+    let output = worthc(&["test", "tests"], &project);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn non_interactive() {
+    let dot = std::env::temp_dir().join(format!("worthc-cfg-{}.dot", std::process::id()));
+    let cfg = |flags: &[&str]| {
+        std::fs::write(&dot, "old").unwrap();
+        let output = test_bin::get_test_bin("worthc")
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .args(["tests/programs/align.porth", "cfg", "-o"])
+            .arg(&dot)
+            .args(flags)
+            .stdin(Stdio::null())
+            .output()
+            .expect("failed to run worthc cfg");
+        (
+            String::from_utf8_lossy(&output.stderr).to_string(),
+            std::fs::read_to_string(&dot).unwrap(),
+        )
+    };
+    // Nobody to ask, so it isn't written over
+    let (stderr, written) = cfg(&[]);
+    assert!(stderr.contains("--force writes over it"), "{}", stderr);
+    assert_eq!(written, "old");
+    // Rendering it needs graphviz, but the graph's written before that
+    for flags in [&["--force"][..], &["--yes"], &["--non-interactive"]] {
+        let (stderr, written) = cfg(flags);
+        assert!(written.starts_with("digraph {"), "{:?}: {}", flags, stderr);
+    }
+    std::fs::remove_file(&dot).unwrap();
+    let _ = std::fs::remove_file(dot.with_extension("svg"));
+}
+
+#[test]
+fn log_phases() {
+    use worthc::json::Json;
+
+    let exe = std::env::temp_dir().join(format!("worthc-log-{}", std::process::id()));
+    let build = |filter: &str, flags: &[&str]| {
+        let output = test_bin::get_test_bin("worthc")
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .env("WORTHC_LOG", filter)
+            .args(flags)
+            .args(["tests/programs/hello.porth", "build", "-o"])
+            .arg(&exe)
+            .output()
+            .expect("failed to build");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stderr).to_string()
+    };
+    let stderr = build("warn,include=debug", &[]);
+    assert!(stderr.contains("[DEBUG] Included "), "{}", stderr);
+    assert!(!stderr.contains("[INFO]"), "{}", stderr);
+    assert!(!stderr.contains("macros expanded"), "{}", stderr);
+    // --log-level is over what WORTHC_LOG gives everything
+    let stderr = build("warn", &["--log-level", "info"]);
+    assert!(stderr.contains("[INFO] Built"), "{}", stderr);
+
+    let stderr = build("preprocess=debug", &["--log-format", "json"]);
+    let messages: Vec<_> = stderr
+        .lines()
+        .map(|line| Json::parse(line).unwrap())
+        .collect();
+    let expanded = messages
+        .iter()
+        .find(|message| {
+            message
+                .get("message")
+                .and_then(|m| m.as_str())
+                .is_some_and(|m| m.contains("macros expanded"))
+        })
+        .expect("the preprocessor's debug message is logged");
+    assert_eq!(
+        expanded.get("level").and_then(|l| l.as_str()),
+        Some("debug")
+    );
+    assert_eq!(
+        expanded
+            .get("phases")
+            .and_then(|phases| phases.as_array())
+            .map(|phases| phases.iter().map(|p| p.as_str()).collect::<Vec<_>>()),
+        Some(vec![Some("preprocess")])
+    );
+    let _ = std::fs::remove_file(&exe);
+    let _ = std::fs::remove_file(exe.with_extension("asm"));
+}
+
+#[test]
+fn difftest() {
+    let dir = std::env::temp_dir().join(format!("worthc-difftest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy(
+        concat!(env!("CARGO_MANIFEST_DIR"), "/std.porth"),
+        dir.join("std.porth"),
+    )
+    .unwrap();
+    std::fs::write(dir.join("input.txt"), "from stdin\n").unwrap();
+    let programs = [
+        // Reads stdin and echoes it, then its first argument
+        (
+            "echo",
+            "include \"std.porth\"\nmemory buf 64 end\n64 buf stdin read buf puts\nargv 8 + ,64 dup cast(ptr) strlen swap puts\n",
+        ),
+        // Each is its own process, so their ids differ
+        ("pid", "include \"std.porth\"\n\"same\\n\" puts\n39 syscall0 print\n"),
+        // Flips every bit, of values only known at runtime
+        ("not", "argc ~ print\nargc 5 + ~ print\nargc 2 - ~ print\n"),
+        // Identical literals are one string, distinct ones aren't
+        (
+            "same",
+            "\"a\" swap drop cast(int) \"a\" swap drop cast(int) = print\n\
+             \"a\" swap drop cast(int) \"b\" swap drop cast(int) = print\n",
+        ),
+    ];
+    for (name, source) in programs {
+        std::fs::write(dir.join(format!("{}.porth", name)), source).unwrap();
+    }
+    let difftest = |name: &str, flags: &[&str]| {
+        let output = test_bin::get_test_bin("worthc")
+            .current_dir(&dir)
+            .arg(format!("{}.porth", name))
+            .arg("difftest")
+            .args(flags)
+            .output()
+            .expect("failed to run worthc difftest");
+        (
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        )
+    };
+
+    let (code, stderr) = difftest("echo", &["--stdin", "input.txt", "--", "arg"]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert!(
+        stderr.contains("does the same simulated and built: 14 bytes of stdout"),
+        "{}",
+        stderr
+    );
+
+    let (code, stderr) = difftest("not", &[]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert!(
+        stderr.contains("does the same simulated and built: 44 bytes of stdout"),
+        "{}",
+        stderr
+    );
+
+    let (code, stderr) = difftest("same", &[]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert!(
+        stderr.contains("does the same simulated and built: 4 bytes of stdout"),
+        "{}",
+        stderr
+    );
+
+    let (code, stderr) = difftest("pid", &[]);
+    assert_eq!(code, Some(1), "{}", stderr);
+    let at = stderr
+        .split("stdout differs at byte ")
+        .nth(1)
+        .and_then(|rest| rest.split(',').next())
+        .and_then(|at| at.parse::<usize>().ok());
+    // "same\n" is the same, so it's the id that isn't
+    assert!(at.is_some_and(|at| at >= 5), "{}", stderr);
+    assert!(stderr.contains("on line 2"), "{}", stderr);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unresolved_pointer() {
+    let dir = std::env::temp_dir().join(format!("worthc-ptr-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("ptr.ir"), PTR_IR).unwrap();
+    // Every backend says where it is instead of panicking
+    for flags in [
+        &[][..],
+        &["--target", "aarch64-linux"],
+        &["--target", "riscv64-linux"],
+        &["--target", "wasm32"],
+        &["--backend", "llvm"],
+        &["--backend", "c"],
+    ] {
+        let output = test_bin::get_test_bin("worthc")
+            .current_dir(&dir)
+            .args(["build", "ptr.ir", "--emit", "asm", "-o", "ptr"])
+            .args(flags)
+            .output()
+            .expect("failed to run worthc build");
+        assert_eq!(output.status.code(), Some(1), "{:?}", flags);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("[ptr.porth:1:0] Pointer to buf should be resolved before codegen"),
+            "{:?}: {}",
+            flags,
+            stderr
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn repl() {
+    let dir = std::env::temp_dir().join(format!("worthc-repl-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("start.porth"), "macro twice 2 * end\n").unwrap();
+    let repl = |args: &[&str], input: &str| {
+        let mut child = test_bin::get_test_bin("worthc")
+            .args(args)
+            .current_dir(&dir)
+            // So the history is kept out of the real home
+            .env("HOME", &dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start worthc repl");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        (
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        )
+    };
+
+    // The stack, memories and what's declared carry over from line to line,
+    // and a block can span lines
+    let input = "1 2 + print\n\
+                 memory m 8 end\n\
+                 m 65 .\n\
+                 :mem m 1\n\
+                 if 1 1 = do\n  7\nelse\n  8\nend\n\
+                 :stack\n\
+                 :type\n\
+                 twice print\n\
+                 :save saved.porth\n";
+    let (stdout, stderr) = repl(&["repl"], input);
+    assert!(stdout.contains("3\n"), "{}", stdout);
+    assert!(
+        stdout.contains(" 41 ") && stdout.contains("|A|"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("0: 7 "), "{}", stdout);
+    assert!(stdout.contains("int\n"), "{}", stdout);
+    // twice isn't declared without the file
+    assert!(stderr.contains("twice"), "{}", stderr);
+    assert_eq!(
+        std::fs::read_to_string(dir.join("saved.porth")).unwrap(),
+        "1 2 + print\nmemory m 8 end\nm 65 .\nif 1 1 = do\n  7\nelse\n  8\nend\n"
+    );
+
+    // A line that fails is left out, so the next runs on what was there before
+    let (stdout, stderr) = repl(&["repl", "start.porth"], "4\ndrop drop\ntwice print\n");
+    assert!(stderr.contains("Stack Underflow"), "{}", stderr);
+    assert_eq!(stdout, "8\n");
+
+    // A file without a command is loaded before reading lines, and exiting
+    // starts over
+    let (stdout, _) = repl(
+        &["start.porth"],
+        "3 twice print\n0 60 syscall1\n:type\n:quit\n1 print\n",
+    );
+    assert_eq!(stdout, "6\nExited with 0, starting over\nStack is empty\n");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sandbox() {
+    let dir = std::env::temp_dir().join(format!("worthc-sandbox-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("r"), "").unwrap();
+    // Each syscall's result, negated after the first so -EPERM prints as 1
+    std::fs::write(
+        dir.join("sandbox.porth"),
+        "mem 'r' . mem 8 + 'w' .\n\
+         0 0 mem 2 syscall3 print\n\
+         420 65 mem 8 + 2 syscall3 0 swap - print\n\
+         420 65 mem 8 + 0 100 - 257 syscall4 0 swap - print\n\
+         mem 8 + 87 syscall1 0 swap - print\n\
+         0 1 2 41 syscall3 0 swap - print\n\
+         0 0 0 0 61 syscall4 0 swap - print\n\
+         0 0 mem 59 syscall3 0 swap - print\n",
+    )
+    .unwrap();
+    let simulate = |allow: &[&str]| {
+        let output = test_bin::get_test_bin("worthc")
+            .current_dir(&dir)
+            .args(["simulate", "sandbox.porth", "--sandbox"])
+            .args(allow.iter().flat_map(|allow| ["--allow", allow]))
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        let _ = std::fs::remove_file(dir.join("w"));
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let denied = stderr.matches("Sandbox denied syscall").count();
+        (
+            stdout.lines().map(str::to_owned).collect::<Vec<_>>(),
+            denied,
+        )
+    };
+    let eperm = "1";
+
+    // Reading is allowed, writing files, sockets and processes aren't
+    let (results, denied) = simulate(&[]);
+    assert_eq!(results, ["3", eperm, eperm, eperm, eperm, eperm, eperm]);
+    assert_eq!(denied, 6);
+
+    // Open and openat for writing return fds, and unlink 0
+    let (results, denied) = simulate(&["write"]);
+    assert_ne!(results[1], eperm);
+    assert_ne!(results[2], eperm);
+    assert_eq!(results[3], "0");
+    assert_eq!(results[4..], [eperm, eperm, eperm]);
+    assert_eq!(denied, 3);
+
+    // Socket returns an fd
+    let (results, denied) = simulate(&["net"]);
+    assert_eq!(results[1..4], [eperm, eperm, eperm]);
+    assert_ne!(results[4], eperm);
+    assert_eq!(results[5..], [eperm, eperm]);
+    assert_eq!(denied, 5);
+
+    // Wait4 returns -ECHILD, with no children, and execve fails on r
+    let (results, denied) = simulate(&["process"]);
+    assert_eq!(results[1..5], [eperm, eperm, eperm, eperm]);
+    assert_eq!(results[5], "10");
+    assert_ne!(results[6], eperm);
+    assert_eq!(denied, 4);
+
+    let (results, denied) = simulate(&["write", "net", "process"]);
+    assert!(!results.contains(&eperm.to_string()));
+    assert_eq!(denied, 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn debugger() {
+    let dir = std::env::temp_dir().join(format!("worthc-debugger-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("dbg.porth"), "1 print\n2 3 +\nprint\n4 print\n").unwrap();
+    let debug = |args: &[&str], commands: &str| {
+        let mut child = test_bin::get_test_bin("worthc")
+            .current_dir(&dir)
+            .args(["simulate", "dbg.porth"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start worthc simulate");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(commands.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    // Each of `expected` in order
+    let in_order = |stdout: &str, expected: &[&str]| {
+        let mut rest = stdout;
+        for expected in expected {
+            let at = rest
+                .find(expected)
+                .unwrap_or_else(|| panic!("{:?} isn't after this in:\n{}", expected, stdout));
+            rest = &rest[at + expected.len()..];
+        }
+    };
+
+    // Stop on a line, print the stack, step, add a breakpoint and continue to it
+    let stdout = debug(
+        &["--break", "dbg.porth:2"],
+        "p\ns\np\nbreak 7\nbreakpoints\nc\np\nc\n",
+    );
+    in_order(
+        &stdout,
+        &[
+            "1\n",
+            "2: Instruction { kind: Push(Int(2))",
+            "(sim)    0: 2 ",
+            "(sim) 3: Instruction { kind: Push(Int(3))",
+            "   0: 3 ",
+            "   1: 2 ",
+            "0: dbg.porth:2\n1: ip 7\n",
+            "5\n",
+            "4\n",
+            "7: Instruction { kind: Intrinsic(Print)",
+            "(sim) Stack is empty\n",
+        ],
+    );
+
+    // --step stops before the first, and continue runs to the end
+    let stdout = debug(&["--step"], "help\nbogus\ncontinue\n");
+    in_order(
+        &stdout,
+        &[
+            "0: Instruction { kind: Push(Int(1))",
+            "(sim) Commands:",
+            "Unknown command bogus, try help\n",
+            "1\n5\n4\n",
+        ],
+    );
+
+    // quit stops there, and a closed stdin runs to the end
+    assert!(!debug(&["--step"], "quit\n").contains("1\n"));
+    assert!(debug(&["--step"], "").ends_with("1\n5\n4\n"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The instructions, without comments or indentation, of what `worthc build
+/// --emit asm` makes of hello.porth for `target`.
+fn hello_asm(target: &str) -> Vec<String> {
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/hello.porth");
+    let out = std::env::temp_dir().join(format!("worthc-{}-{}.asm", target, std::process::id()));
+    let output = test_bin::get_test_bin("worthc")
+        .arg(&file)
+        .args(["build", "--target", target, "--emit", "asm", "-o"])
+        .arg(&out)
+        .output()
+        .expect("failed to execute process");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let asm = std::fs::read_to_string(&out).unwrap();
+    std::fs::remove_file(&out).unwrap();
+    asm.lines()
+        .map(|line| line.split(";;").next().unwrap().split_whitespace())
+        .map(|words| words.collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+#[test]
+fn macos_asm() {
+    let asm = hello_asm("x86_64-macos");
+    // Every syscall goes through do_syscall, which has the only syscall in it
+    let start = asm.iter().position(|line| line == "do_syscall:").unwrap();
+    let syscalls: Vec<_> = asm
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| *line == "syscall")
+        .map(|(i, _)| i)
+        .collect();
+    assert_eq!(syscalls.len(), 1);
+    assert!(syscalls[0] > start);
+    assert!(asm.iter().filter(|line| *line == "call do_syscall").count() >= 2);
+    // It jumps to the BSD number, and errors set the carry flag with a positive errno
+    for expected in ["add rax, 0x2000000", "jnc .pipe", "neg rax", "mov rax, -78"] {
+        assert!(
+            asm[start..].iter().any(|line| line == expected),
+            "{}",
+            expected
+        );
+    }
+    // write is 4 and exit is 1, from a table indexed by the Linux number
+    let table = asm
+        .iter()
+        .position(|line| line == "syscall_table:")
+        .unwrap();
+    let numbers: Vec<u16> = asm[table + 1..]
+        .iter()
+        .take_while(|line| line.starts_with("dw "))
+        .flat_map(|line| line[3..].split(", ").map(|n| n.parse().unwrap()))
+        .collect();
+    assert_eq!((numbers[1], numbers[60]), (4, 1));
+    assert_eq!(numbers[12], 0xFFFF);
+}
+
+#[test]
+fn windows_asm() {
+    let asm = hello_asm("x86_64-windows");
+    // There are no syscalls, kernel32 is called instead
+    assert!(!asm.iter().any(|line| line == "syscall"));
+    for function in [
+        "GetCommandLineA",
+        "GetStdHandle",
+        "ReadFile",
+        "WriteFile",
+        "ExitProcess",
+    ] {
+        assert!(
+            asm.contains(&format!("extern {}", function)),
+            "{}",
+            function
+        );
+        assert!(asm.contains(&format!("call {}", function)), "{}", function);
+    }
+    assert!(asm.iter().filter(|line| *line == "call do_syscall").count() >= 2);
+    let start = asm.iter().position(|line| line == "do_syscall:").unwrap();
+    let syscall = &asm[start..];
+    // With the stack aligned and shadow space for the calls, and the first
+    // argument in rcx after the Linux one in rdi
+    for expected in [
+        "and rsp, -16",
+        "sub rsp, 48",
+        "mov ecx, edi",
+        "mov rcx, rdi",
+        "mov ecx, -10",
+        "mov qword [rsp + 32], 0",
+        "mov rax, -38",
+    ] {
+        assert!(syscall.iter().any(|line| line == expected), "{}", expected);
+    }
+    // The command line is argv's only argument, from before anything runs
+    let entry = asm.iter().position(|line| line == "_start:").unwrap();
+    assert_eq!(
+        asm[entry + 1..]
+            .iter()
+            .find(|line| line.starts_with("call "))
+            .unwrap(),
+        "call GetCommandLineA"
+    );
+}
+
+#[test]
+fn tc_trace() {
+    let dir = std::env::temp_dir().join(format!("worthc-tc-trace-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("ok.porth"), "1 2 +\nprint\n").unwrap();
+    std::fs::write(dir.join("underflow.porth"), "1 +\n").unwrap();
+    let trace = |file: &str, trace: &str| {
+        test_bin::get_test_bin("worthc")
+            .current_dir(&dir)
+            .args(["--tc-trace", trace, file, "simulate"])
+            .output()
+            .expect("failed to execute process")
+    };
+
+    // Every step, then what's left at the end
+    assert!(trace("ok.porth", "ok.trace").status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("ok.trace")).unwrap(),
+        "0: 1 at ok.porth:1:0\n    stack: []\n\
+         1: 2 at ok.porth:1:2\n    stack: [int]\n\
+         2: + at ok.porth:1:4\n    stack: [int, int]\n\
+         3: print at ok.porth:2:0\n    stack: [int]\n\
+         end of program\n    stack: []\n"
+    );
+
+    // A failure's last step is the instruction that failed
+    assert!(!trace("underflow.porth", "underflow.trace").status.success());
+    let written = std::fs::read_to_string(dir.join("underflow.trace")).unwrap();
+    assert!(
+        written.ends_with("1: + at underflow.porth:1:2\n    stack: [int]\n"),
+        "{}",
+        written
+    );
+
+    let output = trace("ok.porth", "missing/ok.trace");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Failed to create typecheck trace \"missing/ok.trace\""));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tc_debugger() {
+    let dir = std::env::temp_dir().join(format!("worthc-tc-debugger-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("tc.porth"),
+        "if 1 1 = do\n  1 2 +\nelse\n  3\nend\nprint\n",
+    )
+    .unwrap();
+    let debug = |commands: &str| {
+        let mut child = test_bin::get_test_bin("worthc")
+            .current_dir(&dir)
+            .args(["tc.porth", "simulate", "--tc-debugger"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start worthc simulate");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(commands.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    // Break on a line, continue to it, look around, step and jump
+    let stdout = debug("break tc:4\nbreakpoints\nc\nsnapshots\ns\ndiff\nj 11\np\nq\n");
+    let expected = "0: if\nLocation: tc.porth:1:0\nStack: []\n\
+                    (tc) (tc) 0: tc:4\n\
+                    (tc) 9: 3\nLocation: tc.porth:4:2\nStack: [int]\n\
+                    (tc) 0: else [int]\n\
+                    (tc) 10: end\nLocation: tc.porth:5:0\nStack: [int]\n\
+                    (tc) Unchanged: [int]\n\
+                    (tc) 11: print\nLocation: tc.porth:6:0\nStack: []\n\
+                    (tc) Stack: []\n\
+                    (tc) 3\n";
+    assert_eq!(stdout, expected);
+
+    // What isn't understood is said so, and typechecking goes on
+    let stdout = debug("break x\nj 99\ndelete 3\nbogus\nq\n");
+    assert!(stdout.contains("(tc) Invalid breakpoint x, expected <ip> or <file>:<line>\n"));
+    assert!(stdout.contains("(tc) Invalid instruction 99\n"));
+    assert!(stdout.contains("(tc) No breakpoint 3\n"));
+    assert!(stdout.contains("(tc) Unknown command bogus, try help\n"));
+    assert!(stdout.ends_with("(tc) 3\n"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn stack_depth() {
+    let dir = std::env::temp_dir().join(format!("worthc-stack-depth-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Deepest inside the if, though it might not run
+    std::fs::write(
+        dir.join("depth.porth"),
+        "1 2 3 drop drop drop\nif 1 1 = do 4 5 6 7 drop drop drop drop end\n",
+    )
+    .unwrap();
+    let simulate = |args: &[&str]| {
+        test_bin::get_test_bin("worthc")
+            .current_dir(&dir)
+            .args(args)
+            .args(["depth.porth", "simulate"])
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = simulate(&["--stack-depth"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Maximum stack depth: 4 at depth.porth:2:18"));
+    assert!(simulate(&["--max-stack-depth", "4"]).status.success());
+
+    let output = simulate(&["--max-stack-depth", "3"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Stack depth 4 exceeds the limit of 3."),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("at depth.porth:2:18"), "{}", stderr);
+    assert!(stderr.contains("W0025"), "{}", stderr);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn simulate_returns() {
+    use clap::Parser;
+
+    let simulate = |source: &str| {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("returns.porth");
+        let program =
+            worthc::program::load_source(source.to_string(), "returns", path, Vec::new()).unwrap();
+        let opt = worthc::cli::SimulatorOptions::parse_from(["simulate"]);
+        worthc::sim::simulate(&program, opt)
+    };
+    // Exiting ends the simulation, not this process
+    assert_eq!(simulate("42 60 syscall1 drop 1 print").unwrap(), 42);
+    assert_eq!(simulate("3 231 syscall1 drop").unwrap(), 3);
+    assert_eq!(simulate("panic").unwrap(), 1);
+    assert_eq!(simulate("").unwrap(), 0);
+    // And errors are returned too
+    let err = simulate("1 0 /").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<worthc::error::Error>().map(|e| e.code()),
+        Some("RuntimeError::DivisionByZero".to_string())
+    );
+    // Twice in the same process, each from the start
+    assert_eq!(simulate("7 60 syscall1 drop").unwrap(), 7);
+}
+
+#[test]
+fn sim_memory_access() {
+    let dir = std::env::temp_dir().join(format!("worthc-sim-access-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let simulate = |source: &str, args: &[&str]| {
+        std::fs::write(dir.join("access.porth"), source).unwrap();
+        test_bin::get_test_bin("worthc")
+            .current_dir(&dir)
+            .args(["-u", "access.porth", "simulate"])
+            .args(args)
+            .output()
+            .expect("failed to execute process")
+    };
+    // The last byte before mem is argv's, so it can be read
+    let output = simulate("mem 1 - , print\n", &[]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "0\n");
+
+    // Both the bytecode and the instructions say what the access missed
+    for args in [&[][..], &["--profile", "1"]] {
+        for (source, error) in [
+            ("0 , print", "Invalid memory read at 1: null pointer (0)"),
+            ("0 1 - , print", "Invalid memory read at 3: negative address -1"),
+            ("0 cast(ptr) 1 .64", "Invalid memory write at 3: null pointer (0)"),
+            (
+                "mem 4 - ,64 print",
+                "Invalid memory read at 3: 8 bytes at 13880c cross from the argv region into the mem region",
+            ),
+            (
+                "mem 4 - 1 .64",
+                "Invalid memory write at 4: 8 bytes at 13880c cross from the argv region into the mem region",
+            ),
+            (
+                "1 40 shl , print",
+                "Invalid memory read at 3: 1 bytes at 10000000000 go past the end of the heap region",
+            ),
+        ] {
+            let output = simulate(source, args);
+            assert_eq!(output.status.code(), Some(1), "{}", source);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(stderr.starts_with(&format!("Error: {}", error)), "{}: {}", source, stderr);
+            assert!(stderr.contains("W0"), "{}", stderr);
+        }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn deterministic() {
+    let dir = std::env::temp_dir().join(format!("worthc-deterministic-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let std = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("std.porth");
+    let source = r#"0 8 mem SYS_getrandom syscall3 print
+mem ,64 print
+mem 16 + 0 SYS_clock_gettime syscall2 drop
+mem 16 + ,64 print mem 24 + ,64 print
+mem 16 + CLOCK_MONOTONIC SYS_clock_gettime syscall2 drop
+mem 16 + ,64 print mem 24 + ,64 print
+mem 32 + 5 .64
+0 mem 32 + SYS_nanosleep syscall2 print
+mem 16 + CLOCK_MONOTONIC SYS_clock_gettime syscall2 drop
+mem 16 + ,64 print mem 24 + ,64 print
+0 0 1 - mem SYS_getrandom syscall3 0 swap - print
+"#;
+    std::fs::write(
+        dir.join("det.porth"),
+        format!("include {:?}\n{}", std, source),
+    )
+    .unwrap();
+    let simulate = |args: &[&str]| {
+        test_bin::get_test_bin("worthc")
+            .current_dir(&dir)
+            .args(["det.porth", "simulate"])
+            .args(args)
+            .output()
+            .expect("failed to execute process")
+    };
+    let stdout = |args: &[&str]| {
+        let output = simulate(args);
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let seeded = stdout(&["--deterministic", "42"]);
+    let lines: Vec<_> = seeded.lines().collect();
+    // The random bytes; then the clock starts in 2000 and ticks a millisecond
+    // per read, and sleeping moves it along instead of waiting
+    assert_eq!(lines[0], "8");
+    assert_eq!(
+        lines[2..],
+        [
+            "946684800",
+            "1000000",
+            "0",
+            "2000000",
+            "0",
+            "5",
+            "3000000",
+            "22"
+        ]
+    );
+    // The same seed gives the same run, on either path
+    assert_eq!(stdout(&["--deterministic", "42"]), seeded);
+    assert_eq!(stdout(&["--deterministic", "42", "--profile", "1"]), seeded);
+    // And another seed other bytes
+    let other = stdout(&["--deterministic", "7"]);
+    assert_ne!(other.lines().nth(1), Some(lines[1]));
+    assert_eq!(other.lines().skip(2).collect::<Vec<_>>(), lines[2..]);
+
+    let output = simulate(&["--deterministic", "x"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Invalid value 'x' for '--deterministic <SEED>'"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn uninit() {
+    let dir = std::env::temp_dir().join(format!("worthc-uninit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("uninit.porth"),
+        r#"mem 1 + 1 .
+mem 1 + ,64 print
+0 while dup 3 < do mem 64 + , drop 1 + end drop
+"hi" , print drop
+mem 32 + 1 228 syscall2 drop mem 32 + ,64 drop mem 40 + ,64 drop
+0 8 mem 96 + 318 syscall3 drop mem 96 + ,64 drop
+mem 1 + 1 .64 mem 1 + ,64 print
+"#,
+    )
+    .unwrap();
+    let simulate = |args: &[&str]| {
+        let output = test_bin::get_test_bin("worthc")
+            .args(["uninit.porth", "simulate"])
+            .args(args)
+            .current_dir(&dir)
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n104\n1\n");
+        String::from_utf8(output.stderr).unwrap()
+    };
+    assert!(!simulate(&[]).contains("[WARN]"));
+
+    // Only the load that's partly unwritten and the one in the loop warn, once
+    // each; strings, what syscalls fill in and what's since been written don't
+    for args in [&["--uninit"][..], &["--uninit", "--profile", "1"]] {
+        let warnings: Vec<_> = simulate(args)
+            .lines()
+            .filter(|line| line.starts_with("[WARN]"))
+            .map(str::to_string)
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "[WARN] ,64 at uninit.porth:2:8 read uninitialized memory at 0x138811 in the mem region",
+                "[WARN] , at uninit.porth:3:28 read uninitialized memory at 0x138850 in the mem region",
+            ]
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn string_dedup() {
+    let dir = std::env::temp_dir().join(format!("worthc-string-dedup-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("strings.porth"),
+        "macro s \"hey\" end\n\
+         s 1 1 syscall3 drop s 1 1 syscall3 drop\n\
+         \"other\" 1 1 syscall3 drop \"hey\" 1 1 syscall3 drop\n",
+    )
+    .unwrap();
+    let output = test_bin::get_test_bin("worthc")
+        .args([
+            "strings.porth",
+            "build",
+            "--emit",
+            "asm",
+            "-o",
+            "strings.asm",
+        ])
+        .current_dir(&dir)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let asm = std::fs::read_to_string(dir.join("strings.asm")).unwrap();
+    let lines: Vec<_> = asm
+        .lines()
+        .map(|line| line.split(";;").next().unwrap().split_whitespace())
+        .map(|words| words.collect::<Vec<_>>().join(" "))
+        .collect();
+    let count = |line: &str| lines.iter().filter(|l| *l == line).count();
+    // Three pushes of "hey", from the macro and not, share its label
+    assert_eq!(count("mov r13, const_str_0"), 3);
+    assert_eq!(count("mov r13, const_str_1"), 1);
+    assert_eq!(count("const_str_0:"), 1);
+    assert_eq!(count("const_str_1:"), 1);
+    assert_eq!(count("const_str_2:"), 0);
+    assert_eq!(count("db 104, 101, 121"), 1);
+
+    let output = test_bin::get_test_bin("worthc")
+        .args(["strings.porth", "run"])
+        .current_dir(&dir)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "heyheyotherhey");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rodata_strings() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let dir = std::env::temp_dir().join(format!("worthc-rodata-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("write.porth"), "\"hey\" 1 . drop\n").unwrap();
+    let output = test_bin::get_test_bin("worthc")
+        .args(["write.porth", "build", "--emit", "asm", "-o", "write.asm"])
+        .current_dir(&dir)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    // The string's bytes are after .rodata starts, and not in .data
+    let asm = std::fs::read_to_string(dir.join("write.asm")).unwrap();
+    let rodata = asm.find("segment .rodata").unwrap();
+    let label = asm.find("const_str_0:").unwrap();
+    assert!(rodata < label);
+    assert!(!asm[asm.find("segment .data").unwrap()..rodata].contains("const_str"));
+
+    // So writing to it faults
+    let output = test_bin::get_test_bin("worthc")
+        .args(["write.porth", "build", "-o", "write"])
+        .current_dir(&dir)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let status = std::process::Command::new(dir.join("write"))
+        .status()
+        .expect("failed to execute process");
+    assert_eq!(status.signal(), Some(11));
+    std::fs::remove_dir_all(&dir).unwrap();
 }